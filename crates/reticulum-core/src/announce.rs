@@ -0,0 +1,174 @@
+//! Destination announcements
+//!
+//! An [`Announce`] tells a network segment that a destination is reachable:
+//! it carries the destination's public key, a timestamp and opaque
+//! application data, signed by the destination's identity. Receivers verify
+//! the signature and derive the destination hash from the public key; the
+//! packet's own destination is not trusted, since interfaces may rewrite it
+//! to a return address.
+//!
+//! Nodes that want to find a destination send a path request
+//! ([`path_request`]); the destination answers with an announce.
+//!
+//! Payload format:
+//! ```text
+//! [ 32 bytes: public key ]
+//! [ 8 bytes: timestamp (Unix seconds, big-endian) ]
+//! [ 2 bytes: app data length (big-endian) ]
+//! [ N bytes: app data ]
+//! [ 64 bytes: signature over everything above ]
+//! ```
+
+use crate::{DestinationHash, Identity, NetworkError, Packet, PacketType, Result, Signer};
+use bytes::{Buf, BufMut, BytesMut};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// A signed destination announcement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    /// Public key of the announced destination
+    pub public_key: Vec<u8>,
+
+    /// Creation time (Unix seconds)
+    pub timestamp: u64,
+
+    /// Application data (names, capabilities, ...)
+    pub app_data: Vec<u8>,
+
+    /// Signature by the announced identity
+    pub signature: Vec<u8>,
+}
+
+impl Announce {
+    /// Create and sign an announcement for `identity`
+    pub fn new(identity: &Identity, app_data: Vec<u8>) -> Self {
+        Self::signed_by(identity, app_data).expect("an identity in memory always signs")
+    }
+
+    /// Create an announcement for the identity of `signer`, signed by it
+    pub fn signed_by(signer: &dyn Signer, app_data: Vec<u8>) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut announce = Self {
+            public_key: signer.public_key(),
+            timestamp,
+            app_data,
+            signature: vec![],
+        };
+        announce.signature = signer.sign(&announce.signed_part())?;
+        Ok(announce)
+    }
+
+    /// Get the announced destination hash
+    pub fn destination(&self) -> DestinationHash {
+        Identity::hash_from_public_key(&self.public_key)
+    }
+
+    /// Build the announce packet
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = self.signed_part();
+        payload.extend_from_slice(&self.signature);
+        Packet::announce(self.destination(), payload)
+    }
+
+    /// Parse an announce packet and verify its signature
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        if packet.packet_type != PacketType::Announce {
+            return Err(NetworkError::Packet("Not an announce packet".to_string()));
+        }
+
+        let mut buf = packet.data.as_ref();
+        if buf.len() < 32 + 8 + 2 {
+            return Err(NetworkError::Packet("Announce too short".to_string()));
+        }
+
+        let public_key = buf[..32].to_vec();
+        buf.advance(32);
+        let timestamp = buf.get_u64();
+        let len = buf.get_u16() as usize;
+        if buf.len() != len + 64 {
+            return Err(NetworkError::Packet("Invalid announce length".to_string()));
+        }
+        let app_data = buf[..len].to_vec();
+        let signature = buf[len..].to_vec();
+
+        let announce = Self {
+            public_key,
+            timestamp,
+            app_data,
+            signature,
+        };
+        Identity::verify_external(
+            &announce.public_key,
+            &announce.signed_part(),
+            &announce.signature,
+        )?;
+        Ok(announce)
+    }
+
+    fn signed_part(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(42 + self.app_data.len());
+        buf.put_slice(&self.public_key);
+        buf.put_u64(self.timestamp);
+        buf.put_u16(self.app_data.len() as u16);
+        buf.put_slice(&self.app_data);
+        buf.to_vec()
+    }
+}
+
+/// Build a path request for `destination`
+///
+/// The wanted hash travels in the payload, since interfaces may rewrite the
+/// packet destination.
+pub fn path_request(destination: DestinationHash) -> Packet {
+    Packet::new(PacketType::PathRequest, destination, destination.to_vec())
+}
+
+/// Get the destination a path request asks for
+pub fn requested_destination(packet: &Packet) -> Option<DestinationHash> {
+    if packet.packet_type != PacketType::PathRequest {
+        return None;
+    }
+    packet.data.as_ref().try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_round_trip() {
+        let identity = Identity::generate();
+        let announce = Announce::new(&identity, b"shell".to_vec());
+
+        let packet = Packet::decode(&announce.to_packet().encode()).unwrap();
+        assert_eq!(packet.destination, identity.destination_hash());
+
+        let decoded = Announce::from_packet(&packet).unwrap();
+        assert_eq!(decoded, announce);
+        assert_eq!(decoded.destination(), identity.destination_hash());
+    }
+
+    #[test]
+    fn test_forged_announce() {
+        let identity = Identity::generate();
+        let mut announce = Announce::new(&identity, b"shell".to_vec());
+        announce.app_data = b"evil".to_vec();
+        assert!(Announce::from_packet(&announce.to_packet()).is_err());
+
+        // Someone else's key with our signature
+        let mut announce = Announce::new(&identity, vec![]);
+        announce.public_key = Identity::generate().public_key();
+        assert!(Announce::from_packet(&announce.to_packet()).is_err());
+    }
+
+    #[test]
+    fn test_path_request() {
+        let packet = path_request([5; 32]);
+        assert_eq!(requested_destination(&packet), Some([5; 32]));
+        assert_eq!(requested_destination(&Packet::data([5; 32], vec![])), None);
+    }
+}
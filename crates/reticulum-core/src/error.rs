@@ -13,6 +13,10 @@ pub enum NetworkError {
     #[error("Packet error: {0}")]
     Packet(String),
 
+    /// Bytes that are not a packet
+    #[error("Packet error: {0}")]
+    Decode(#[from] DecodeError),
+
     /// I2P transport error
     #[error("I2P error: {0}")]
     I2p(String),
@@ -42,5 +46,29 @@ pub enum NetworkError {
     InvalidDestination(String),
 }
 
+/// Why bytes received are not a packet
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ends inside a field
+    #[error("Truncated {field}: {needed} bytes needed, {available} left")]
+    Truncated {
+        field: &'static str,
+        needed: usize,
+        available: usize,
+    },
+
+    /// Unknown packet type
+    #[error("Invalid packet type: {0:#04x}")]
+    InvalidPacketType(u8),
+
+    /// The signature flag is neither 0x00 nor 0x01
+    #[error("Invalid signature flag: {0:#04x}")]
+    InvalidSignatureFlag(u8),
+
+    /// Bytes follow the end of the packet
+    #[error("{0} bytes after the end of the packet")]
+    TrailingBytes(usize),
+}
+
 /// Result type for network operations
 pub type Result<T> = std::result::Result<T, NetworkError>;
@@ -1,13 +1,39 @@
 //! Reticulum identity management
+//!
+//! An identity file holds the 32-byte private key, or the key encrypted with
+//! a passphrase: `RNSIDENC`, the PBKDF2-HMAC-SHA256 round count (u32, big
+//! endian), a 16-byte salt and a 16-byte IV, the key encrypted with
+//! AES-256-CBC, and an HMAC-SHA256 over all of that. PBKDF2 stretches the
+//! passphrase into the AES and the HMAC key.
 
 use crate::{DestinationHash, NetworkError, Result};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
 use rand::RngCore;
 use rand::rngs::OsRng;
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::path::Path;
 
+/// Start of an encrypted identity file
+const ENCRYPTED_MAGIC: &[u8; 8] = b"RNSIDENC";
+
+/// PBKDF2 rounds for newly encrypted identities
+pub const KDF_ROUNDS: u32 = 600_000;
+
+/// Most PBKDF2 rounds an identity file may ask for, so a crafted one can't
+/// keep the loader busy for hours
+pub const MAX_KDF_ROUNDS: u32 = 10 * KDF_ROUNDS;
+
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
 /// A Reticulum identity (Ed25519 keypair)
 #[derive(Clone)]
 pub struct Identity {
@@ -61,6 +87,23 @@ impl Identity {
         self.signing_key.to_bytes().to_vec()
     }
 
+    /// The X25519 private key of the identity, for key agreement (session
+    /// encryption); its public key is
+    /// [`x25519_public_key`](Self::x25519_public_key) of ours
+    pub fn x25519_private_key(&self) -> [u8; 32] {
+        self.signing_key.to_scalar_bytes()
+    }
+
+    /// The X25519 public key matching the Ed25519 `public_key`
+    pub fn x25519_public_key(public_key: &[u8]) -> Result<[u8; 32]> {
+        let bytes: [u8; 32] = public_key
+            .try_into()
+            .map_err(|_| NetworkError::Crypto("Public key must be 32 bytes".to_string()))?;
+        let verifying_key = VerifyingKey::from_bytes(&bytes)
+            .map_err(|e| NetworkError::Crypto(format!("Invalid public key: {}", e)))?;
+        Ok(verifying_key.to_montgomery().to_bytes())
+    }
+
     /// Get the destination hash (SHA-256 of public key)
     pub fn destination_hash(&self) -> DestinationHash {
         let mut hasher = Sha256::new();
@@ -107,9 +150,117 @@ impl Identity {
         Ok(())
     }
 
+    /// Save identity to file, encrypted with `passphrase`; a new file is
+    /// only readable by its owner
+    pub fn save_encrypted<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
+        use std::io::Write;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)?
+            .write_all(&self.to_encrypted_bytes(passphrase, KDF_ROUNDS))?;
+        Ok(())
+    }
+
     /// Load identity from file
+    ///
+    /// Fails for encrypted files; see
+    /// [`from_encrypted_bytes`](Self::from_encrypted_bytes).
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let private_key = fs::read(path)?;
+        if is_encrypted(&private_key) {
+            return Err(NetworkError::Identity(
+                "The identity file is encrypted; a passphrase is needed".to_string(),
+            ));
+        }
+        Self::from_bytes(&private_key)
+    }
+
+    /// Check whether the identity file at `path` is encrypted
+    pub fn is_encrypted_file<P: AsRef<Path>>(path: P) -> Result<bool> {
+        Ok(is_encrypted(&fs::read(path)?))
+    }
+
+    /// Check that `path` holds an identity without asking for a passphrase
+    /// (of an encrypted identity, only the format is checked)
+    pub fn check_file<P: AsRef<Path>>(path: P) -> Result<()> {
+        let data = fs::read(path)?;
+        if !is_encrypted(&data) {
+            return Self::from_bytes(&data).map(|_| ());
+        }
+        // The key is 32 bytes, two AES blocks plus one of padding
+        let expected = ENCRYPTED_MAGIC.len() + 4 + SALT_LEN + IV_LEN + 48 + MAC_LEN;
+        if data.len() != expected {
+            return Err(NetworkError::Identity("Damaged identity file".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The private key encrypted with `passphrase`, stretched with `rounds`
+    /// of PBKDF2, in the identity file format
+    pub fn to_encrypted_bytes(&self, passphrase: &str, rounds: u32) -> Vec<u8> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut iv = [0u8; IV_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut iv);
+        let (encryption_key, mac_key) = derive_keys(passphrase, &salt, rounds);
+
+        let ciphertext = Aes256CbcEnc::new_from_slices(&encryption_key, &iv)
+            .expect("key and IV have fixed lengths")
+            .encrypt_padded_vec_mut::<Pkcs7>(&self.signing_key.to_bytes());
+
+        let mut data = ENCRYPTED_MAGIC.to_vec();
+        data.extend_from_slice(&rounds.to_be_bytes());
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&iv);
+        data.extend_from_slice(&ciphertext);
+        let mut mac = HmacSha256::new_from_slice(&mac_key).expect("HMAC takes any key length");
+        mac.update(&data);
+        data.extend_from_slice(&mac.finalize().into_bytes());
+        data
+    }
+
+    /// Decrypt an identity encrypted with
+    /// [`to_encrypted_bytes`](Self::to_encrypted_bytes)
+    ///
+    /// A wrong passphrase fails with [`NetworkError::Crypto`].
+    pub fn from_encrypted_bytes(data: &[u8], passphrase: &str) -> Result<Self> {
+        let header = ENCRYPTED_MAGIC.len() + 4 + SALT_LEN + IV_LEN;
+        if !is_encrypted(data) || data.len() < header + MAC_LEN {
+            return Err(NetworkError::Identity(
+                "Not an encrypted identity".to_string(),
+            ));
+        }
+        let (body, tag) = data.split_at(data.len() - MAC_LEN);
+        let rounds = u32::from_be_bytes(body[8..12].try_into().unwrap());
+        if rounds == 0 {
+            return Err(NetworkError::Identity(
+                "Damaged identity file: no PBKDF2 rounds".to_string(),
+            ));
+        }
+        if rounds > MAX_KDF_ROUNDS {
+            return Err(NetworkError::Identity(format!(
+                "Damaged identity file: {} PBKDF2 rounds, more than {}",
+                rounds, MAX_KDF_ROUNDS
+            )));
+        }
+        let salt = &body[12..12 + SALT_LEN];
+        let iv = &body[12 + SALT_LEN..header];
+        let (encryption_key, mac_key) = derive_keys(passphrase, salt, rounds);
+
+        let mut mac = HmacSha256::new_from_slice(&mac_key).expect("HMAC takes any key length");
+        mac.update(body);
+        mac.verify_slice(tag).map_err(|_| {
+            NetworkError::Crypto("Wrong passphrase, or the identity file is damaged".to_string())
+        })?;
+
+        let private_key = Aes256CbcDec::new_from_slices(&encryption_key, iv)
+            .expect("key and IV have fixed lengths")
+            .decrypt_padded_vec_mut::<Pkcs7>(&body[header..])
+            .map_err(|_| NetworkError::Identity("Damaged identity file".to_string()))?;
         Self::from_bytes(&private_key)
     }
 
@@ -157,6 +308,22 @@ impl Identity {
     }
 }
 
+/// Check whether identity file contents are encrypted
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Stretch `passphrase` into an AES and an HMAC key
+fn derive_keys(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 32], [u8; 32]) {
+    let mut keys = [0u8; 64];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut keys);
+    let mut encryption_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    encryption_key.copy_from_slice(&keys[..32]);
+    mac_key.copy_from_slice(&keys[32..]);
+    (encryption_key, mac_key)
+}
+
 impl std::fmt::Debug for Identity {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Identity")
@@ -211,6 +378,63 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_encrypted_identity() {
+        let identity = Identity::generate();
+        let data = identity.to_encrypted_bytes("correct horse", 1000);
+        assert!(is_encrypted(&data));
+        assert!(!data.windows(32).any(|w| w == identity.private_key()));
+
+        let loaded = Identity::from_encrypted_bytes(&data, "correct horse").unwrap();
+        assert_eq!(loaded.public_key(), identity.public_key());
+
+        assert!(matches!(
+            Identity::from_encrypted_bytes(&data, "battery staple"),
+            Err(NetworkError::Crypto(_))
+        ));
+        let mut damaged = data.clone();
+        damaged[30] ^= 1;
+        assert!(Identity::from_encrypted_bytes(&damaged, "correct horse").is_err());
+        assert!(Identity::from_encrypted_bytes(&data[..40], "correct horse").is_err());
+
+        // A round count beyond the bound is refused before any is run
+        let mut costly = data.clone();
+        costly[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            Identity::from_encrypted_bytes(&costly, "correct horse"),
+            Err(NetworkError::Identity(_))
+        ));
+
+        // Plain loading refuses encrypted files
+        let path = std::env::temp_dir().join(format!("identity-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, &data).unwrap();
+        assert!(Identity::is_encrypted_file(&path).unwrap());
+        assert!(Identity::check_file(&path).is_ok());
+        assert!(Identity::load_from_file(&path).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::remove_file(&path).unwrap();
+            identity.save_encrypted(&path, "correct horse").unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::write(&path, &data[..100]).unwrap();
+        assert!(Identity::check_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_x25519_keys() {
+        let identity = Identity::generate();
+        let secret = x25519_dalek::StaticSecret::from(identity.x25519_private_key());
+        assert_eq!(
+            x25519_dalek::PublicKey::from(&secret).to_bytes(),
+            Identity::x25519_public_key(&identity.public_key()).unwrap()
+        );
+        assert!(Identity::x25519_public_key(&[1; 16]).is_err());
+    }
+
     #[test]
     fn test_destination_hash() {
         let identity = Identity::generate();
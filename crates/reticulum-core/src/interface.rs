@@ -3,7 +3,7 @@
 //! This module provides an abstraction layer for different transport mechanisms
 //! (I2P, TCP, UDP, etc.)
 
-use crate::{Packet, Result};
+use crate::{rns::WireFormat, Packet, Result};
 use async_trait::async_trait;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -16,6 +16,13 @@ pub trait NetworkInterface: Send + Sync {
     /// Send a packet through this interface
     async fn send(&self, packet: &Packet) -> Result<()>;
 
+    /// Send a packet to every reachable peer (used for announces)
+    ///
+    /// Point-to-point interfaces just send it.
+    async fn broadcast(&self, packet: &Packet) -> Result<()> {
+        self.send(packet).await
+    }
+
     /// Receive a packet from this interface
     async fn receive(&self) -> Result<Packet>;
 
@@ -25,6 +32,27 @@ pub trait NetworkInterface: Send + Sync {
     /// Check if interface is ready
     async fn is_ready(&self) -> bool;
 
+    /// How packets are put on the wire
+    ///
+    /// Announces sent on an RNS interface must be built by
+    /// [`rns::announce`](crate::rns::announce).
+    fn wire_format(&self) -> WireFormat {
+        WireFormat::Native
+    }
+
+    /// Destination hash peers address this end by, if the interface has its
+    /// own (an I2P destination) rather than the identity's
+    fn destination_hash(&self) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Re-establish the underlying connection after it failed
+    ///
+    /// Interfaces that recover on their own have nothing to do.
+    async fn reopen(&self) -> Result<()> {
+        Ok(())
+    }
+
     /// Close the interface
     async fn close(&self) -> Result<()>;
 }
@@ -90,6 +118,7 @@ impl NetworkInterface for MockInterface {
 }
 
 /// I2P network interface using SAM protocol
+#[cfg(not(target_arch = "wasm32"))]
 pub struct I2pInterface {
     name: String,
     sam_conn: Arc<Mutex<crate::sam::SamConnection>>,
@@ -99,6 +128,7 @@ pub struct I2pInterface {
     destination_map: Arc<Mutex<std::collections::HashMap<[u8; 32], String>>>,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl I2pInterface {
     /// Create a new I2P interface
     pub async fn new(sam_addr: &str) -> Result<Self> {
@@ -179,6 +209,7 @@ impl I2pInterface {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait]
 impl NetworkInterface for I2pInterface {
     async fn send(&self, packet: &Packet) -> Result<()> {
@@ -203,6 +234,26 @@ impl NetworkInterface for I2pInterface {
         Ok(())
     }
 
+    async fn broadcast(&self, packet: &Packet) -> Result<()> {
+        // Every destination we have heard from
+        let destinations: Vec<String> = {
+            let dest_map = self.destination_map.lock().await;
+            dest_map
+                .values()
+                .filter(|dest| **dest != self.local_destination)
+                .cloned()
+                .collect()
+        };
+
+        let encoded = packet.encode();
+        let mut sam = self.sam_conn.lock().await;
+        for i2p_dest in destinations {
+            sam.datagram_send(&self.session_id, &i2p_dest, &encoded).await?;
+        }
+
+        Ok(())
+    }
+
     async fn receive(&self) -> Result<Packet> {
         use sha2::{Digest, Sha256};
         use tracing::debug;
@@ -226,8 +277,11 @@ impl NetworkInterface for I2pInterface {
             dest_map.insert(source_hash, source_dest);
         }
 
-        // Decode the packet
-        Packet::decode(&data)
+        // Decode the packet, addressed from the source for replies
+        let mut packet = Packet::decode(&data)?;
+        packet.final_destination = Some(packet.destination);
+        packet.destination = source_hash;
+        Ok(packet)
     }
 
     fn name(&self) -> &str {
@@ -238,6 +292,10 @@ impl NetworkInterface for I2pInterface {
         true // If we constructed successfully, we're ready
     }
 
+    fn destination_hash(&self) -> Option<[u8; 32]> {
+        Some(self.local_destination_hash())
+    }
+
     async fn close(&self) -> Result<()> {
         tracing::info!("Closing I2P interface");
         // SAM connection will be dropped automatically
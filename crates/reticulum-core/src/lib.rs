@@ -2,21 +2,57 @@
 //!
 //! This crate provides the core networking functionality for the Reticulum protocol,
 //! including identity management, packet handling, and I2P transport.
+//!
+//! Built for `wasm32` (a browser), the socket transports (TCP, rnsd, I2P)
+//! are left out and a [`WebSocketInterface`](websocket::WebSocketInterface)
+//! to a gateway takes their place.
 
+pub mod announce;
 pub mod error;
 pub mod identity;
 pub mod interface;
+pub mod manager;
 pub mod packet;
+pub mod rns;
+pub mod routing;
+pub mod share;
+pub mod signer;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod rnsd;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod sam;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp;
+
+#[cfg(target_arch = "wasm32")]
+pub mod websocket;
 
 #[cfg(feature = "embedded-router")]
 pub mod embedded_router;
 
-pub use error::{NetworkError, Result};
+pub use announce::Announce;
+pub use error::{DecodeError, NetworkError, Result};
 pub use identity::Identity;
-pub use interface::{I2pInterface, MockInterface, NetworkInterface};
+pub use interface::{MockInterface, NetworkInterface};
+pub use manager::InterfaceManager;
 pub use packet::{Packet, PacketType};
+pub use rns::WireFormat;
+pub use routing::{Route, RoutingTable};
+pub use share::ShareString;
+pub use signer::Signer;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use interface::I2pInterface;
+#[cfg(not(target_arch = "wasm32"))]
+pub use rnsd::RnsdInterface;
+#[cfg(not(target_arch = "wasm32"))]
 pub use sam::SamConnection;
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::TcpInterface;
+
+#[cfg(target_arch = "wasm32")]
+pub use websocket::WebSocketInterface;
 
 #[cfg(feature = "embedded-router")]
 pub use embedded_router::{EmbeddedRouter, EmbeddedRouterConfig, RouterStats};
@@ -0,0 +1,81 @@
+//! Interface manager
+//!
+//! Holds every interface a node is attached to, so a server can bind several
+//! transports at once (e.g. I2P and TCP) and run one receive loop per
+//! interface.
+
+use crate::NetworkInterface;
+use std::sync::Arc;
+use tracing::warn;
+
+/// The set of interfaces a node is attached to
+#[derive(Default, Clone)]
+pub struct InterfaceManager {
+    interfaces: Vec<Arc<dyn NetworkInterface>>,
+}
+
+impl InterfaceManager {
+    /// Create an empty manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach an interface
+    pub fn add(&mut self, interface: Arc<dyn NetworkInterface>) {
+        self.interfaces.push(interface);
+    }
+
+    /// Attach an interface (builder form)
+    pub fn with(mut self, interface: Arc<dyn NetworkInterface>) -> Self {
+        self.add(interface);
+        self
+    }
+
+    /// Get all attached interfaces
+    pub fn interfaces(&self) -> &[Arc<dyn NetworkInterface>] {
+        &self.interfaces
+    }
+
+    /// Get an interface by name
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn NetworkInterface>> {
+        self.interfaces.iter().find(|interface| interface.name() == name)
+    }
+
+    /// Get the number of attached interfaces
+    pub fn len(&self) -> usize {
+        self.interfaces.len()
+    }
+
+    /// Check whether no interface is attached
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty()
+    }
+
+    /// Close every interface, logging (not returning) failures
+    pub async fn close_all(&self) {
+        for interface in &self.interfaces {
+            if let Err(e) = interface.close().await {
+                warn!("Failed to close interface {}: {}", interface.name(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockInterface;
+
+    #[tokio::test]
+    async fn test_manager() {
+        let (client, server) = MockInterface::create_pair();
+        let manager = InterfaceManager::new()
+            .with(Arc::new(client))
+            .with(Arc::new(server));
+
+        assert_eq!(manager.len(), 2);
+        assert!(manager.get("mock-server").is_some());
+        assert!(manager.get("tcp").is_none());
+        manager.close_all().await;
+    }
+}
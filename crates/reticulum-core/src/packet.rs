@@ -1,7 +1,7 @@
 //! Reticulum packet structures
 
-use crate::{DestinationHash, NetworkError, Result};
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::{DecodeError, DestinationHash, Result};
+use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 
 /// Packet type identifier
@@ -22,6 +22,9 @@ pub enum PacketType {
 
     /// Proof packet
     Proof = 0x04,
+
+    /// Path request (asks a destination to announce itself)
+    PathRequest = 0x05,
 }
 
 impl PacketType {
@@ -33,7 +36,8 @@ impl PacketType {
             0x02 => Ok(PacketType::LinkRequest),
             0x03 => Ok(PacketType::LinkResponse),
             0x04 => Ok(PacketType::Proof),
-            _ => Err(NetworkError::Packet(format!("Invalid packet type: {}", value))),
+            0x05 => Ok(PacketType::PathRequest),
+            _ => Err(DecodeError::InvalidPacketType(value).into()),
         }
     }
 }
@@ -47,11 +51,20 @@ pub struct Packet {
     /// Destination hash
     pub destination: DestinationHash,
 
+    /// Destination the packet is finally for, when `destination` is just the
+    /// next hop: encoded in its place, and set on received packets whose
+    /// `destination` the interface replaced with the sender's address
+    pub final_destination: Option<DestinationHash>,
+
     /// Payload data
     pub data: Bytes,
 
     /// Optional signature
     pub signature: Option<Vec<u8>>,
+
+    /// Sequence number sent with the signature, for senders that number
+    /// their signed packets
+    pub sequence: Option<u64>,
 }
 
 impl Packet {
@@ -60,8 +73,10 @@ impl Packet {
         Self {
             packet_type,
             destination,
+            final_destination: None,
             data: Bytes::from(data),
             signature: None,
+            sequence: None,
         }
     }
 
@@ -81,6 +96,12 @@ impl Packet {
         self
     }
 
+    /// Number the packet; sent only with a signature
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
     /// Encode packet to bytes
     ///
     /// Format:
@@ -89,9 +110,12 @@ impl Packet {
     /// [ 32 bytes: destination hash ]
     /// [ 2 bytes: data length (u16, big-endian) ]
     /// [ N bytes: data ]
-    /// [ 1 byte: signature flag (0x00 or 0x01) ]
-    /// [ 64 bytes: signature (if flag is 0x01) ]
+    /// [ 1 byte: signature flag (0x00, 0x01 or 0x02) ]
+    /// [ 8 bytes: sequence number (u64, big-endian; if flag is 0x02) ]
+    /// [ 64 bytes: signature (if flag is 0x01 or 0x02) ]
     /// ```
+    ///
+    /// The destination hash is the final destination if there is one.
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = BytesMut::new();
 
@@ -99,68 +123,64 @@ impl Packet {
         buf.put_u8(self.packet_type as u8);
 
         // Destination
-        buf.put_slice(&self.destination);
+        buf.put_slice(self.final_destination.as_ref().unwrap_or(&self.destination));
 
         // Data length and data
         buf.put_u16(self.data.len() as u16);
         buf.put_slice(&self.data);
 
         // Signature
-        if let Some(sig) = &self.signature {
-            buf.put_u8(0x01); // Signature present
-            buf.put_slice(sig);
-        } else {
-            buf.put_u8(0x00); // No signature
+        match (&self.signature, self.sequence) {
+            (Some(sig), None) => {
+                buf.put_u8(0x01); // Signature present
+                buf.put_slice(sig);
+            }
+            (Some(sig), Some(sequence)) => {
+                buf.put_u8(0x02); // Numbered signature
+                buf.put_u64(sequence);
+                buf.put_slice(sig);
+            }
+            (None, _) => buf.put_u8(0x00), // No signature
         }
 
         buf.to_vec()
     }
 
     /// Decode packet from bytes
+    ///
+    /// Any input is either a packet, encoding back to the same bytes, or a
+    /// [`DecodeError`] saying where it went wrong; none makes it panic.
     pub fn decode(data: &[u8]) -> Result<Self> {
-        if data.len() < 35 {
-            // Minimum: type(1) + dest(32) + len(2)
-            return Err(NetworkError::Packet("Packet too short".to_string()));
-        }
-
-        let mut buf = &data[..];
-
-        // Read packet type
-        let packet_type = PacketType::from_u8(buf.get_u8())?;
-
-        // Read destination
-        let mut destination = [0u8; 32];
-        buf.copy_to_slice(&mut destination);
-
-        // Read data length
-        let data_len = buf.get_u16() as usize;
-
-        if buf.len() < data_len + 1 {
-            return Err(NetworkError::Packet("Invalid data length".to_string()));
-        }
-
-        // Read data
-        let payload = buf.copy_to_bytes(data_len);
-
-        // Read signature flag
-        let sig_flag = buf.get_u8();
-
-        let signature = if sig_flag == 0x01 {
-            if buf.len() < 64 {
-                return Err(NetworkError::Packet("Invalid signature length".to_string()));
+        let mut reader = Reader(data);
+
+        let packet_type = PacketType::from_u8(reader.u8("packet type")?)?;
+        let destination = reader.array("destination")?;
+        let data_len = u16::from_be_bytes(reader.array("data length")?) as usize;
+        let payload = Bytes::copy_from_slice(reader.take("data", data_len)?);
+        let (signature, sequence) = match reader.u8("signature flag")? {
+            0x00 => (None, None),
+            0x01 => {
+                let signature = reader.take("signature", SIGNATURE_LEN)?.to_vec();
+                (Some(signature), None)
             }
-            let mut sig = vec![0u8; 64];
-            buf.copy_to_slice(&mut sig);
-            Some(sig)
-        } else {
-            None
+            0x02 => {
+                let sequence = u64::from_be_bytes(reader.array("sequence number")?);
+                let signature = reader.take("signature", SIGNATURE_LEN)?.to_vec();
+                (Some(signature), Some(sequence))
+            }
+            flag => return Err(DecodeError::InvalidSignatureFlag(flag).into()),
         };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes(reader.0.len()).into());
+        }
 
         Ok(Self {
             packet_type,
             destination,
+            final_destination: None,
             data: payload,
             signature,
+            sequence,
         })
     }
 
@@ -175,9 +195,49 @@ impl Packet {
     }
 }
 
+/// Bytes of a packet signature
+const SIGNATURE_LEN: usize = 64;
+
+/// Takes fields off the front of an encoded packet, failing where it runs
+/// short
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(
+        &mut self,
+        field: &'static str,
+        len: usize,
+    ) -> std::result::Result<&'a [u8], DecodeError> {
+        if self.0.len() < len {
+            return Err(DecodeError::Truncated {
+                field,
+                needed: len,
+                available: self.0.len(),
+            });
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self, field: &'static str) -> std::result::Result<u8, DecodeError> {
+        Ok(self.take(field, 1)?[0])
+    }
+
+    fn array<const N: usize>(
+        &mut self,
+        field: &'static str,
+    ) -> std::result::Result<[u8; N], DecodeError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(field, N)?);
+        Ok(array)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::NetworkError;
 
     #[test]
     fn test_packet_encode_decode() {
@@ -205,6 +265,94 @@ mod tests {
         let decoded = Packet::decode(&encoded).unwrap();
 
         assert_eq!(decoded.signature, Some(signature));
+        assert!(decoded.sequence.is_none());
+    }
+
+    #[test]
+    fn test_packet_with_sequence() {
+        let packet = Packet::data([42u8; 32], b"Test data".to_vec())
+            .with_signature(vec![0xAB; 64])
+            .with_sequence(7);
+        let decoded = Packet::decode(&packet.encode()).unwrap();
+        assert_eq!(decoded.signature, Some(vec![0xAB; 64]));
+        assert_eq!(decoded.sequence, Some(7));
+
+        // Not sent without a signature
+        let unsigned = Packet::data([42u8; 32], b"Test data".to_vec()).with_sequence(7);
+        let decoded = Packet::decode(&unsigned.encode()).unwrap();
+        assert!(decoded.sequence.is_none());
+    }
+
+    #[test]
+    fn test_final_destination() {
+        let mut packet = Packet::data([1u8; 32], b"via".to_vec());
+        packet.final_destination = Some([2u8; 32]);
+
+        let decoded = Packet::decode(&packet.encode()).unwrap();
+        assert_eq!(decoded.destination, [2u8; 32]);
+        assert!(decoded.final_destination.is_none());
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let encoded = Packet::data([7u8; 32], b"payload".to_vec())
+            .with_signature(vec![1; 64])
+            .encode();
+
+        // Every truncation says where it ran short
+        for len in 0..encoded.len() {
+            assert!(
+                matches!(
+                    Packet::decode(&encoded[..len]),
+                    Err(NetworkError::Decode(DecodeError::Truncated { .. }))
+                ),
+                "{} bytes",
+                len
+            );
+        }
+        assert!(matches!(
+            Packet::decode(&encoded[..20]),
+            Err(NetworkError::Decode(DecodeError::Truncated {
+                field: "destination",
+                needed: 32,
+                available: 19,
+            }))
+        ));
+
+        let mut oversize = encoded.clone();
+        oversize[33..35].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(matches!(
+            Packet::decode(&oversize),
+            Err(NetworkError::Decode(DecodeError::Truncated {
+                field: "data",
+                ..
+            }))
+        ));
+
+        let mut bad_type = encoded.clone();
+        bad_type[0] = 0x80;
+        assert!(matches!(
+            Packet::decode(&bad_type),
+            Err(NetworkError::Decode(DecodeError::InvalidPacketType(0x80)))
+        ));
+
+        let mut bad_flag = encoded.clone();
+        bad_flag[35 + 7] = 0x03;
+        assert!(matches!(
+            Packet::decode(&bad_flag),
+            Err(NetworkError::Decode(DecodeError::InvalidSignatureFlag(
+                0x03
+            )))
+        ));
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(matches!(
+            Packet::decode(&trailing),
+            Err(NetworkError::Decode(DecodeError::TrailingBytes(1)))
+        ));
+
+        assert_eq!(Packet::decode(&encoded).unwrap().encode(), encoded);
     }
 
     #[test]
@@ -0,0 +1,751 @@
+//! Reference Reticulum (RNS) wire format
+//!
+//! A compatibility mode for sharing a network with the Python reference
+//! implementation: packets carry the RNS header, announces use the RNS
+//! announce format and streams the RNS HDLC framing, so RNS transport nodes
+//! relay sneakyshell announces and forward its packets like any others.
+//!
+//! Header:
+//! ```text
+//! [ 1 byte: flags ]        IFAC | header type | context flag | transport
+//!                          type | destination type (2 bits) | packet type (2 bits)
+//! [ 1 byte: hops ]
+//! [ 16 bytes: transport ID ]   (header type 2 only)
+//! [ 16 bytes: destination hash ]
+//! [ 1 byte: context ]
+//! [ N bytes: data ]
+//! ```
+//!
+//! RNS destination hashes are 16 bytes, derived from the identity's keys and
+//! an application name ([`destination_hash`]). The [`Codec`] maps those of
+//! the destinations it has seen announced to and from their
+//! [`DestinationHash`], so the rest of the stack keeps using the latter;
+//! others go on the wire truncated and come back padded with zeros
+//! ([`expand`]).
+//!
+//! Packets to the RNS path request destination are
+//! [`PacketType::PathRequest`] packets, and packets to a destination a
+//! transport node announced are sent through that node (header type 2), as
+//! RNS does. Data packet payloads are sneakyshell's own, after a return
+//! address and the packet signature, for which RNS has no fields:
+//! ```text
+//! [ 16 bytes: return address (zeros if none) ]
+//! [ 1 byte: signature flag (0x00, 0x01 or 0x02) ]
+//! [ 8 bytes: sequence number (u64, big-endian; if flag is 0x02) ]
+//! [ 64 bytes: signature (if flag is 0x01 or 0x02) ]
+//! [ N bytes: data ]
+//! ```
+//!
+//! The return address is the RNS hash of a destination the sender announced
+//! ([`Codec::with_return_address`]); received data packets are addressed
+//! from it, like the packets of the other interfaces are from their source,
+//! so replies find their way back.
+//!
+//! RNS nodes keep to an [`MTU`] of 500 bytes and may drop larger packets on
+//! the way. Interface access codes and Links are not supported.
+
+use crate::{
+    Announce, DestinationHash, Identity, NetworkError, Packet, PacketType, Result, Signer,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Name sneakyshell destinations are announced under on RNS
+pub const APP_NAME: &str = "sneakyshell.server";
+
+/// Largest packet RNS nodes pass on
+pub const MTU: usize = 500;
+
+/// Length of an RNS destination hash
+pub const TRUNCATED_HASH_LEN: usize = 16;
+
+/// Length of an RNS name hash
+pub const NAME_HASH_LEN: usize = 10;
+
+/// An RNS destination hash
+pub type RnsHash = [u8; TRUNCATED_HASH_LEN];
+
+const FLAG_IFAC: u8 = 0x80;
+const HEADER_2: u8 = 1;
+const TRANSPORT: u8 = 1;
+
+const DEST_SINGLE: u8 = 0;
+const DEST_PLAIN: u8 = 2;
+const DEST_LINK: u8 = 3;
+
+const DATA: u8 = 0;
+const ANNOUNCE: u8 = 1;
+const LINK_REQUEST: u8 = 2;
+const PROOF: u8 = 3;
+
+const CONTEXT_NONE: u8 = 0x00;
+const CONTEXT_LINK_PROOF: u8 = 0xFF;
+
+/// Smallest header: flags, hops, destination and context
+const HEADER_MIN: usize = 2 + TRUNCATED_HASH_LEN + 1;
+
+const PUBLIC_KEY_LEN: usize = 64;
+const RANDOM_HASH_LEN: usize = 10;
+const RATCHET_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// How packets are put on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// sneakyshell's own ([`Packet::encode`])
+    Native,
+
+    /// The reference Reticulum one (this module)
+    Rns,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        WireFormat::Native
+    }
+}
+
+/// Name hash of `name`, as RNS derives it
+pub fn name_hash(name: &str) -> [u8; NAME_HASH_LEN] {
+    truncate(&Sha256::digest(name.as_bytes()))
+}
+
+/// RNS destination hash of the identity with Ed25519 `public_key`, announced
+/// under [`APP_NAME`]
+pub fn destination_hash(public_key: &[u8]) -> Result<RnsHash> {
+    Ok(single_destination(
+        &rns_public_key(public_key)?,
+        &name_hash(APP_NAME),
+    ))
+}
+
+/// Hash RNS path requests are sent to
+pub fn path_request_destination() -> RnsHash {
+    truncate(&Sha256::digest(name_hash("rnstransport.path.request")))
+}
+
+/// Pad an RNS destination hash into a [`DestinationHash`]
+pub fn expand(hash: &RnsHash) -> DestinationHash {
+    let mut expanded = [0u8; 32];
+    expanded[..TRUNCATED_HASH_LEN].copy_from_slice(hash);
+    expanded
+}
+
+/// Build an RNS announce of `identity` under [`APP_NAME`], carrying
+/// `app_data`
+///
+/// Only interfaces in the RNS format can send it.
+pub fn announce(identity: &Identity, app_data: Vec<u8>) -> Packet {
+    announce_signed_by(identity, app_data).expect("an identity in memory always signs")
+}
+
+/// Build an RNS announce like [`announce`] of the identity of `signer`,
+/// signed by it
+pub fn announce_signed_by(signer: &dyn Signer, app_data: Vec<u8>) -> Result<Packet> {
+    let public_key = rns_public_key(&signer.public_key())?;
+    let name_hash = name_hash(APP_NAME);
+    let destination = single_destination(&public_key, &name_hash);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut random_hash = [0u8; RANDOM_HASH_LEN];
+    random_hash[..5].copy_from_slice(&rand::random::<[u8; 5]>());
+    random_hash[5..].copy_from_slice(&timestamp.to_be_bytes()[3..]);
+
+    let mut signed = Vec::new();
+    signed.extend_from_slice(&destination);
+    signed.extend_from_slice(&public_key);
+    signed.extend_from_slice(&name_hash);
+    signed.extend_from_slice(&random_hash);
+    signed.extend_from_slice(&app_data);
+    let signature = signer.sign(&signed)?;
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&public_key);
+    payload.extend_from_slice(&name_hash);
+    payload.extend_from_slice(&random_hash);
+    payload.extend_from_slice(&signature);
+    payload.extend_from_slice(&app_data);
+    Ok(Packet::announce(signer.destination_hash(), payload))
+}
+
+/// Parse an RNS announce packet and verify its signature
+///
+/// The [`Announce`] returned has the announced Ed25519 key, the timestamp
+/// from the announce's random hash and the app data; its signature is the
+/// RNS one, which [`Announce::from_packet`] would not accept.
+pub fn parse_announce(packet: &Packet) -> Result<Announce> {
+    if packet.packet_type != PacketType::Announce {
+        return Err(NetworkError::Packet("Not an announce packet".to_string()));
+    }
+    Ok(RnsAnnounce::parse(&packet.data, false)?.announce)
+}
+
+/// A verified RNS announce
+struct RnsAnnounce {
+    /// RNS destination hash it announces
+    destination: RnsHash,
+
+    announce: Announce,
+}
+
+impl RnsAnnounce {
+    /// Parse and verify an announce payload; `ratchet` says whether it
+    /// carries a ratchet key (the header's context flag)
+    fn parse(data: &[u8], ratchet: bool) -> Result<Self> {
+        let ratchet_len = if ratchet { RATCHET_LEN } else { 0 };
+        let fixed = PUBLIC_KEY_LEN + NAME_HASH_LEN + RANDOM_HASH_LEN + ratchet_len;
+        if data.len() < fixed + SIGNATURE_LEN {
+            return Err(NetworkError::Packet("RNS announce too short".to_string()));
+        }
+        let public_key = &data[..PUBLIC_KEY_LEN];
+        let name_hash: [u8; NAME_HASH_LEN] = truncate(&data[PUBLIC_KEY_LEN..]);
+        let random_hash = &data[PUBLIC_KEY_LEN + NAME_HASH_LEN..][..RANDOM_HASH_LEN];
+        let signature = &data[fixed..fixed + SIGNATURE_LEN];
+        let app_data = &data[fixed + SIGNATURE_LEN..];
+
+        let destination = single_destination(public_key, &name_hash);
+        let mut signed = Vec::with_capacity(TRUNCATED_HASH_LEN + fixed + app_data.len());
+        signed.extend_from_slice(&destination);
+        signed.extend_from_slice(&data[..fixed]);
+        signed.extend_from_slice(app_data);
+        let signing_key = &public_key[32..];
+        Identity::verify_external(signing_key, &signed, signature)?;
+
+        let mut timestamp = [0u8; 8];
+        timestamp[3..].copy_from_slice(&random_hash[5..]);
+        Ok(Self {
+            destination,
+            announce: Announce {
+                public_key: signing_key.to_vec(),
+                timestamp: u64::from_be_bytes(timestamp),
+                app_data: app_data.to_vec(),
+                signature: signature.to_vec(),
+            },
+        })
+    }
+}
+
+/// Encoder and decoder of RNS packets
+///
+/// Learns from the announces passing through which RNS hash each
+/// destination has, and which transport node leads to it.
+#[derive(Default)]
+pub struct Codec {
+    routes: Mutex<Routes>,
+
+    /// Put in the data packets sent, for replies
+    return_address: Option<RnsHash>,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// RNS hashes of destinations seen announced
+    rns: HashMap<DestinationHash, RnsHash>,
+
+    /// Destinations by RNS hash
+    native: HashMap<RnsHash, DestinationHash>,
+
+    /// Transport node that announced a destination, the next hop to it
+    next_hop: HashMap<RnsHash, RnsHash>,
+}
+
+impl Routes {
+    fn learn(&mut self, destination: DestinationHash, rns: RnsHash) {
+        self.rns.insert(destination, rns);
+        self.native.insert(rns, destination);
+    }
+
+    fn rns_hash(&self, destination: &DestinationHash) -> RnsHash {
+        self.rns
+            .get(destination)
+            .copied()
+            .unwrap_or_else(|| truncate(destination))
+    }
+
+    fn destination(&self, rns: &RnsHash) -> DestinationHash {
+        self.native.get(rns).copied().unwrap_or_else(|| expand(rns))
+    }
+}
+
+impl Codec {
+    /// Create a codec that has seen nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a codec that asks for replies to `return_address`, the RNS
+    /// hash of a destination announced on the network
+    pub fn with_return_address(return_address: RnsHash) -> Self {
+        Self {
+            return_address: Some(return_address),
+            ..Self::default()
+        }
+    }
+
+    /// Encode `packet` in the RNS format
+    pub fn encode(&self, packet: &Packet) -> Result<Vec<u8>> {
+        let mut routes = self.routes.lock().unwrap();
+        let (packet_type, destination_type, context, destination, data) = match packet.packet_type {
+            PacketType::Data => {
+                let mut data =
+                    Vec::with_capacity(TRUNCATED_HASH_LEN + 1 + SIGNATURE_LEN + packet.data.len());
+                data.extend_from_slice(&self.return_address.unwrap_or_default());
+                match (&packet.signature, packet.sequence) {
+                    (Some(signature), None) => {
+                        data.push(0x01);
+                        data.extend_from_slice(signature);
+                    }
+                    (Some(signature), Some(sequence)) => {
+                        data.push(0x02);
+                        data.extend_from_slice(&sequence.to_be_bytes());
+                        data.extend_from_slice(signature);
+                    }
+                    (None, _) => data.push(0x00),
+                }
+                data.extend_from_slice(&packet.data);
+                let destination = routes.rns_hash(&packet.destination);
+                (DATA, DEST_SINGLE, CONTEXT_NONE, destination, data)
+            }
+            PacketType::Announce => {
+                let announce = RnsAnnounce::parse(&packet.data, false).map_err(|_| {
+                    NetworkError::Packet("Not an RNS announce (see rns::announce)".to_string())
+                })?;
+                let destination = Identity::hash_from_public_key(&announce.announce.public_key);
+                routes.learn(destination, announce.destination);
+                let data = packet.data.to_vec();
+                (
+                    ANNOUNCE,
+                    DEST_SINGLE,
+                    CONTEXT_NONE,
+                    announce.destination,
+                    data,
+                )
+            }
+            PacketType::PathRequest => {
+                let requested: DestinationHash = packet
+                    .data
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| NetworkError::Packet("Invalid path request".to_string()))?;
+                let mut data = routes.rns_hash(&requested).to_vec();
+                data.extend_from_slice(&rand::random::<RnsHash>());
+                (
+                    DATA,
+                    DEST_PLAIN,
+                    CONTEXT_NONE,
+                    path_request_destination(),
+                    data,
+                )
+            }
+            PacketType::LinkRequest => {
+                let destination = routes.rns_hash(&packet.destination);
+                (
+                    LINK_REQUEST,
+                    DEST_SINGLE,
+                    CONTEXT_NONE,
+                    destination,
+                    packet.data.to_vec(),
+                )
+            }
+            PacketType::LinkResponse => {
+                let destination = routes.rns_hash(&packet.destination);
+                (
+                    PROOF,
+                    DEST_LINK,
+                    CONTEXT_LINK_PROOF,
+                    destination,
+                    packet.data.to_vec(),
+                )
+            }
+            PacketType::Proof => {
+                let destination = routes.rns_hash(&packet.destination);
+                (
+                    PROOF,
+                    DEST_SINGLE,
+                    CONTEXT_NONE,
+                    destination,
+                    packet.data.to_vec(),
+                )
+            }
+        };
+
+        // Through the transport node that announced the destination, if any
+        let next_hop = match packet_type {
+            ANNOUNCE => None,
+            _ => routes.next_hop.get(&destination).copied(),
+        };
+
+        let mut buf = BytesMut::with_capacity(HEADER_MIN + TRUNCATED_HASH_LEN + data.len());
+        let header_type = u8::from(next_hop.is_some());
+        buf.put_u8((header_type << 6) | (header_type << 4) | (destination_type << 2) | packet_type);
+        buf.put_u8(0); // hops
+        if let Some(transport_id) = next_hop {
+            buf.put_slice(&transport_id);
+        }
+        buf.put_slice(&destination);
+        buf.put_u8(context);
+        buf.put_slice(&data);
+        Ok(buf.to_vec())
+    }
+
+    /// Decode an RNS packet
+    ///
+    /// Announces are verified, and are then addressed from the
+    /// [`DestinationHash`] of the identity they announce.
+    pub fn decode(&self, raw: &[u8]) -> Result<Packet> {
+        if raw.len() < HEADER_MIN {
+            return Err(NetworkError::Packet("RNS packet too short".to_string()));
+        }
+        let mut buf = raw;
+        let flags = buf.get_u8();
+        let _hops = buf.get_u8();
+        if flags & FLAG_IFAC != 0 {
+            return Err(NetworkError::Packet(
+                "RNS interface access codes are not supported".to_string(),
+            ));
+        }
+        let header_type = (flags >> 6) & 0x01;
+        let context_flag = (flags >> 5) & 0x01 == 1;
+        let destination_type = (flags >> 2) & 0x03;
+        let packet_type = flags & 0x03;
+
+        let transport_id = if header_type == HEADER_2 {
+            if buf.len() < HEADER_MIN - 2 + TRUNCATED_HASH_LEN {
+                return Err(NetworkError::Packet("RNS packet too short".to_string()));
+            }
+            let mut id = [0u8; TRUNCATED_HASH_LEN];
+            buf.copy_to_slice(&mut id);
+            Some(id)
+        } else {
+            None
+        };
+        let mut destination = [0u8; TRUNCATED_HASH_LEN];
+        buf.copy_to_slice(&mut destination);
+        let context = buf.get_u8();
+        let data = buf;
+
+        let mut routes = self.routes.lock().unwrap();
+        let packet = match packet_type {
+            DATA if destination_type == DEST_PLAIN && destination == path_request_destination() => {
+                if data.len() < TRUNCATED_HASH_LEN {
+                    return Err(NetworkError::Packet("Invalid path request".to_string()));
+                }
+                let requested = routes.destination(&truncate(data));
+                Packet::new(PacketType::PathRequest, requested, requested.to_vec())
+            }
+            DATA => {
+                if data.len() < TRUNCATED_HASH_LEN {
+                    return Err(NetworkError::Packet(
+                        "Not a sneakyshell data packet".to_string(),
+                    ));
+                }
+                let (return_address, data) = data.split_at(TRUNCATED_HASH_LEN);
+                let (signature, sequence, payload) = match data.split_first() {
+                    Some((0x00, payload)) => (None, None, payload),
+                    Some((0x01, rest)) if rest.len() >= SIGNATURE_LEN => {
+                        let (signature, payload) = rest.split_at(SIGNATURE_LEN);
+                        (Some(signature.to_vec()), None, payload)
+                    }
+                    Some((0x02, rest)) if rest.len() >= 8 + SIGNATURE_LEN => {
+                        let (sequence, rest) = rest.split_at(8);
+                        let (signature, payload) = rest.split_at(SIGNATURE_LEN);
+                        let sequence = u64::from_be_bytes(truncate(sequence));
+                        (Some(signature.to_vec()), Some(sequence), payload)
+                    }
+                    _ => {
+                        return Err(NetworkError::Packet(
+                            "Not a sneakyshell data packet".to_string(),
+                        ))
+                    }
+                };
+                let source = match truncate::<TRUNCATED_HASH_LEN>(return_address) {
+                    address if address == RnsHash::default() => destination,
+                    address => address,
+                };
+                let mut packet = Packet::data(routes.destination(&source), payload.to_vec());
+                if source != destination {
+                    packet.final_destination = Some(routes.destination(&destination));
+                }
+                packet.signature = signature;
+                packet.sequence = sequence;
+                packet
+            }
+            ANNOUNCE => {
+                let announce = RnsAnnounce::parse(data, context_flag)?;
+                if announce.destination != destination {
+                    return Err(NetworkError::Packet(
+                        "RNS announce for another destination".to_string(),
+                    ));
+                }
+                let native = Identity::hash_from_public_key(&announce.announce.public_key);
+                routes.learn(native, destination);
+                if let Some(transport_id) = transport_id {
+                    routes.next_hop.insert(destination, transport_id);
+                }
+                Packet::announce(native, data.to_vec())
+            }
+            LINK_REQUEST => Packet::new(
+                PacketType::LinkRequest,
+                routes.destination(&destination),
+                data.to_vec(),
+            ),
+            PROOF if context == CONTEXT_LINK_PROOF => Packet::new(
+                PacketType::LinkResponse,
+                routes.destination(&destination),
+                data.to_vec(),
+            ),
+            _ => Packet::new(
+                PacketType::Proof,
+                routes.destination(&destination),
+                data.to_vec(),
+            ),
+        };
+        Ok(packet)
+    }
+}
+
+/// HDLC-like framing of RNS stream interfaces
+pub mod hdlc {
+    const FLAG: u8 = 0x7E;
+    const ESC: u8 = 0x7D;
+    const ESC_MASK: u8 = 0x20;
+
+    /// Frame `data` for a stream
+    pub fn frame(data: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(data.len() + 2);
+        framed.push(FLAG);
+        for &byte in data {
+            if byte == FLAG || byte == ESC {
+                framed.extend_from_slice(&[ESC, byte ^ ESC_MASK]);
+            } else {
+                framed.push(byte);
+            }
+        }
+        framed.push(FLAG);
+        framed
+    }
+
+    /// Splits a stream back into frames
+    #[derive(Debug, Default)]
+    pub struct Deframer {
+        /// Frame read so far (None until a flag starts one)
+        frame: Option<Vec<u8>>,
+
+        /// The last byte was an escape
+        escaped: bool,
+    }
+
+    impl Deframer {
+        /// Create a deframer waiting for the first flag
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Feed `bytes` from the stream, returning the frames they complete
+        pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+            let mut frames = Vec::new();
+            for &byte in bytes {
+                match byte {
+                    FLAG => {
+                        if let Some(frame) = self.frame.take().filter(|f| !f.is_empty()) {
+                            frames.push(frame);
+                        }
+                        self.frame = Some(Vec::new());
+                        self.escaped = false;
+                    }
+                    ESC => self.escaped = true,
+                    _ => {
+                        if let Some(frame) = &mut self.frame {
+                            frame.push(if self.escaped { byte ^ ESC_MASK } else { byte });
+                        }
+                        self.escaped = false;
+                    }
+                }
+            }
+            frames
+        }
+
+        /// Length of the frame being read
+        pub fn pending(&self) -> usize {
+            self.frame.as_ref().map_or(0, Vec::len)
+        }
+    }
+}
+
+/// The 64-byte RNS public key (X25519, then Ed25519) of the identity with
+/// Ed25519 `public_key`
+fn rns_public_key(public_key: &[u8]) -> Result<[u8; PUBLIC_KEY_LEN]> {
+    let mut key = [0u8; PUBLIC_KEY_LEN];
+    key[..32].copy_from_slice(&Identity::x25519_public_key(public_key)?);
+    key[32..].copy_from_slice(public_key);
+    Ok(key)
+}
+
+/// Hash of a SINGLE destination with the RNS `public_key` and `name_hash`
+fn single_destination(public_key: &[u8], name_hash: &[u8]) -> RnsHash {
+    let identity_hash: RnsHash = truncate(&Sha256::digest(public_key));
+    let mut material = name_hash.to_vec();
+    material.extend_from_slice(&identity_hash);
+    truncate(&Sha256::digest(&material))
+}
+
+fn truncate<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    bytes[..N].try_into().expect("long enough")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_round_trip() {
+        let codec = Codec::new();
+        let destination = Identity::generate().destination_hash();
+        let packet = Packet::data(destination, b"hello".to_vec()).with_signature(vec![7; 64]);
+
+        let raw = codec.encode(&packet).unwrap();
+        // Header 1, SINGLE, DATA; no hops; truncated destination; no context
+        assert_eq!(raw[0], 0x00);
+        assert_eq!(raw[1], 0);
+        assert_eq!(&raw[2..18], &destination[..16]);
+        assert_eq!(raw[18], CONTEXT_NONE);
+
+        let decoded = codec.decode(&raw).unwrap();
+        assert_eq!(decoded.packet_type, PacketType::Data);
+        assert_eq!(decoded.destination, expand(&truncate(&destination)));
+        assert_eq!(decoded.data.as_ref(), b"hello");
+        assert_eq!(decoded.signature, Some(vec![7; 64]));
+        assert!(decoded.sequence.is_none());
+
+        let numbered = packet.with_sequence(9);
+        let decoded = codec.decode(&codec.encode(&numbered).unwrap()).unwrap();
+        assert_eq!(decoded.data.as_ref(), b"hello");
+        assert_eq!(decoded.signature, Some(vec![7; 64]));
+        assert_eq!(decoded.sequence, Some(9));
+    }
+
+    #[test]
+    fn test_return_address() {
+        let client = Identity::generate();
+        let client_hash = destination_hash(&client.public_key()).unwrap();
+        let sender = Codec::with_return_address(client_hash);
+        let raw = sender
+            .encode(&Packet::data([1; 32], b"hi".to_vec()))
+            .unwrap();
+        assert_eq!(&raw[19..35], &client_hash);
+
+        // Addressed from the client, by its own hash once it announced
+        let receiver = Codec::new();
+        let request = receiver.decode(&raw).unwrap();
+        assert_eq!(request.destination, expand(&client_hash));
+        receiver
+            .decode(&Codec::new().encode(&announce(&client, vec![])).unwrap())
+            .unwrap();
+        let request = receiver.decode(&raw).unwrap();
+        assert_eq!(request.destination, client.destination_hash());
+
+        let reply = receiver
+            .encode(&Packet::data(request.destination, b"ho".to_vec()))
+            .unwrap();
+        assert_eq!(&reply[2..18], &client_hash);
+    }
+
+    #[test]
+    fn test_announce() {
+        let identity = Identity::generate();
+        let packet = announce(&identity, b"shell".to_vec());
+        let parsed = parse_announce(&packet).unwrap();
+        assert_eq!(parsed.public_key, identity.public_key());
+        assert_eq!(parsed.app_data, b"shell");
+        assert_eq!(parsed.destination(), identity.destination_hash());
+
+        // On the wire under the RNS hash, mapped back when received
+        let sender = Codec::new();
+        let raw = sender.encode(&packet).unwrap();
+        let rns_hash = destination_hash(&identity.public_key()).unwrap();
+        assert_eq!(raw[0] & 0x03, ANNOUNCE);
+        assert_eq!(&raw[2..18], &rns_hash);
+
+        let receiver = Codec::new();
+        let received = receiver.decode(&raw).unwrap();
+        assert_eq!(received.destination, identity.destination_hash());
+        let reply = receiver
+            .encode(&Packet::data(identity.destination_hash(), vec![]))
+            .unwrap();
+        assert_eq!(&reply[2..18], &rns_hash);
+
+        // Tampered announces are refused
+        let mut tampered = raw.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_route_through_transport_node() {
+        let identity = Identity::generate();
+        let raw = Codec::new().encode(&announce(&identity, vec![])).unwrap();
+
+        // Rebroadcast by a transport node: header 2, its ID before the
+        // destination
+        let transport_id = [9u8; 16];
+        let mut relayed = vec![raw[0] | (HEADER_2 << 6) | (TRANSPORT << 4), 1];
+        relayed.extend_from_slice(&transport_id);
+        relayed.extend_from_slice(&raw[2..]);
+
+        let codec = Codec::new();
+        codec.decode(&relayed).unwrap();
+        let raw = codec
+            .encode(&Packet::data(identity.destination_hash(), b"hi".to_vec()))
+            .unwrap();
+        assert_eq!(raw[0], (HEADER_2 << 6) | (TRANSPORT << 4));
+        assert_eq!(&raw[2..18], &transport_id);
+        assert_eq!(
+            &raw[18..34],
+            &destination_hash(&identity.public_key()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_path_request() {
+        let codec = Codec::new();
+        let wanted = Identity::generate().destination_hash();
+        let raw = codec
+            .encode(&crate::announce::path_request(wanted))
+            .unwrap();
+        assert_eq!(raw[0], DEST_PLAIN << 2);
+        assert_eq!(&raw[2..18], &path_request_destination());
+        assert_eq!(&raw[19..35], &wanted[..16]);
+
+        let decoded = codec.decode(&raw).unwrap();
+        assert_eq!(decoded.packet_type, PacketType::PathRequest);
+        assert_eq!(
+            crate::announce::requested_destination(&decoded),
+            Some(expand(&truncate(&wanted)))
+        );
+    }
+
+    #[test]
+    fn test_hdlc() {
+        let data = [0x01, 0x7E, 0x02, 0x7D, 0x03];
+        let framed = hdlc::frame(&data);
+        assert_eq!(
+            framed,
+            vec![0x7E, 0x01, 0x7D, 0x5E, 0x02, 0x7D, 0x5D, 0x03, 0x7E]
+        );
+
+        // Split anywhere, with a second frame sharing the closing flag
+        let mut stream = framed.clone();
+        stream.extend_from_slice(&hdlc::frame(b"next")[1..]);
+        let mut deframer = hdlc::Deframer::new();
+        let mut frames = deframer.push(&stream[..4]);
+        frames.extend(deframer.push(&stream[4..]));
+        assert_eq!(frames, vec![data.to_vec(), b"next".to_vec()]);
+    }
+}
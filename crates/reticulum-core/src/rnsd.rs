@@ -0,0 +1,307 @@
+//! Bridge to a Python Reticulum daemon
+//!
+//! Connects to the TCPServerInterface of an `rnsd` and exchanges packets in
+//! the RNS wire format ([`crate::rns`]) with it, HDLC-framed as RNS stream
+//! interfaces are. The daemon's transport then carries sneakyshell packets
+//! across the rest of the Reticulum network, without I2P.
+//!
+//! RNS packets have no source address, so the interface announces an
+//! identity of its own when it connects and asks for replies to it: transport
+//! nodes learn the way back from that announce, and the far end sees the
+//! packets coming from the identity's destination.
+
+use crate::rns::{self, hdlc, WireFormat};
+use crate::{Identity, NetworkError, NetworkInterface, Packet, Result};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Port `rnsd` TCPServerInterfaces listen on by default
+pub const DEFAULT_PORT: u16 = 4242;
+
+/// Largest frame accepted (RNS TCP interfaces stay far below)
+const MAX_FRAME: usize = 64 * 1024;
+
+type Outgoing = Arc<StdMutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// Packet transport through an `rnsd` TCPServerInterface
+pub struct RnsdInterface {
+    name: String,
+    remote: SocketAddr,
+    codec: Arc<rns::Codec>,
+
+    /// Identity replies are sent to, announced on every connection
+    identity: Identity,
+
+    incoming: Mutex<mpsc::UnboundedReceiver<Packet>>,
+
+    /// Frames for the writer task, None once the daemon went away
+    outgoing: Outgoing,
+
+    /// Reader and writer tasks, aborted on close
+    tasks: StdMutex<Vec<JoinHandle<()>>>,
+}
+
+impl RnsdInterface {
+    /// Connect to the `rnsd` TCPServerInterface at `addr`
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let identity = Identity::generate();
+        let return_address = rns::destination_hash(&identity.public_key())?;
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let interface = Self {
+            name: format!("rnsd:{}", addr),
+            remote: addr,
+            codec: Arc::new(rns::Codec::with_return_address(return_address)),
+            identity,
+            incoming: Mutex::new(incoming_rx),
+            outgoing: Arc::new(StdMutex::new(None)),
+            tasks: StdMutex::new(Vec::new()),
+        };
+        interface.open(incoming_tx).await?;
+        info!(
+            "RNS interface connected to rnsd at {}, replies to {}",
+            addr,
+            hex::encode(return_address)
+        );
+        Ok(interface)
+    }
+
+    /// Dial the daemon, start the reader and writer tasks and announce the
+    /// return identity
+    async fn open(&self, incoming: mpsc::UnboundedSender<Packet>) -> Result<()> {
+        let stream = TcpStream::connect(self.remote).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let announce = self
+            .codec
+            .encode(&rns::announce(&self.identity, Vec::new()))?;
+        let _ = outgoing_tx.send(hdlc::frame(&announce));
+        *self.outgoing.lock().unwrap() = Some(outgoing_tx);
+
+        let writer = tokio::spawn(write_frames(writer, outgoing_rx));
+        let reader = {
+            let codec = Arc::clone(&self.codec);
+            let outgoing = Arc::clone(&self.outgoing);
+            let name = self.name.clone();
+            tokio::spawn(async move {
+                if let Err(e) = read_frames(reader, &codec, &incoming).await {
+                    debug!("{} disconnected: {}", name, e);
+                }
+                outgoing.lock().unwrap().take();
+            })
+        };
+
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.extend([reader, writer]);
+        Ok(())
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    codec: &rns::Codec,
+    incoming: &mpsc::UnboundedSender<Packet>,
+) -> Result<()> {
+    let mut deframer = hdlc::Deframer::new();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(NetworkError::Connection(
+                "rnsd closed the connection".to_string(),
+            ));
+        }
+
+        for frame in deframer.push(&buf[..n]) {
+            match codec.decode(&frame) {
+                Ok(packet) => {
+                    if incoming.send(packet).is_err() {
+                        return Ok(());
+                    }
+                }
+                // Most of what an RNS network carries is not for us
+                Err(e) => debug!("Dropping RNS frame: {}", e),
+            }
+        }
+        if deframer.pending() > MAX_FRAME {
+            return Err(NetworkError::Packet(format!(
+                "Frame too large: over {} bytes",
+                MAX_FRAME
+            )));
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    while let Some(frame) = outgoing.recv().await {
+        if writer.write_all(&frame).await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+#[async_trait]
+impl NetworkInterface for RnsdInterface {
+    async fn send(&self, packet: &Packet) -> Result<()> {
+        let raw = self.codec.encode(packet)?;
+        if raw.len() > rns::MTU {
+            warn!(
+                "{} byte packet exceeds the RNS MTU and may be dropped",
+                raw.len()
+            );
+        }
+        let outgoing = self.outgoing.lock().unwrap();
+        let sender = outgoing
+            .as_ref()
+            .ok_or_else(|| NetworkError::Connection("rnsd not connected".to_string()))?;
+        sender
+            .send(hdlc::frame(&raw))
+            .map_err(|_| NetworkError::Connection("rnsd disconnected".to_string()))
+    }
+
+    async fn receive(&self) -> Result<Packet> {
+        let mut incoming = self.incoming.lock().await;
+        incoming
+            .recv()
+            .await
+            .ok_or_else(|| NetworkError::Connection("RNS interface closed".to_string()))
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.outgoing.lock().unwrap().is_some()
+    }
+
+    fn wire_format(&self) -> WireFormat {
+        WireFormat::Rns
+    }
+
+    /// Dial the daemon again if it went away, announcing anew
+    async fn reopen(&self) -> Result<()> {
+        if self.is_ready().await {
+            return Ok(());
+        }
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        self.open(incoming_tx).await?;
+        *self.incoming.lock().await = incoming_rx;
+        info!("RNS interface reconnected to rnsd at {}", self.remote);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        info!("Closing RNS interface to {}", self.remote);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.outgoing.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketType;
+    use tokio::net::TcpListener;
+
+    /// Accept one connection, standing in for rnsd
+    async fn daemon() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    async fn read_frame(stream: &mut TcpStream, deframer: &mut hdlc::Deframer) -> Vec<u8> {
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if let Some(frame) = deframer.push(&buf[..n]).into_iter().next() {
+                return frame;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let (listener, addr) = daemon().await;
+        let interface = RnsdInterface::connect(addr).await.unwrap();
+        assert_eq!(interface.wire_format(), WireFormat::Rns);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let daemon = rns::Codec::new();
+        let mut deframer = hdlc::Deframer::new();
+
+        // The return identity is announced first
+        let announce = daemon
+            .decode(&read_frame(&mut stream, &mut deframer).await)
+            .unwrap();
+        assert_eq!(announce.packet_type, PacketType::Announce);
+        assert_eq!(
+            rns::parse_announce(&announce).unwrap().public_key,
+            interface.identity.public_key()
+        );
+
+        interface
+            .send(&Packet::data([1; 32], b"hello".to_vec()))
+            .await
+            .unwrap();
+        let raw = read_frame(&mut stream, &mut deframer).await;
+        let request = daemon.decode(&raw).unwrap();
+        assert_eq!(request.data.as_ref(), b"hello");
+        assert_eq!(request.destination, interface.identity.destination_hash());
+
+        // Replies come back from the daemon
+        let server = Identity::generate();
+        let announce = rns::Codec::new()
+            .encode(&rns::announce(&server, b"shell".to_vec()))
+            .unwrap();
+        stream.write_all(&hdlc::frame(&announce)).await.unwrap();
+        let received = interface.receive().await.unwrap();
+        assert_eq!(received.destination, server.destination_hash());
+
+        let reply =
+            rns::Codec::with_return_address(rns::destination_hash(&server.public_key()).unwrap())
+                .encode(&Packet::data(request.destination, b"world".to_vec()))
+                .unwrap();
+        stream.write_all(&hdlc::frame(&reply)).await.unwrap();
+        let reply = interface.receive().await.unwrap();
+        assert_eq!(reply.data.as_ref(), b"world");
+        assert_eq!(reply.destination, server.destination_hash());
+
+        interface.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let (listener, addr) = daemon().await;
+        let interface = RnsdInterface::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        drop(stream);
+        assert!(interface.receive().await.is_err());
+        assert!(!interface.is_ready().await);
+
+        interface.reopen().await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut deframer = hdlc::Deframer::new();
+        let announce = rns::Codec::new()
+            .decode(&read_frame(&mut stream, &mut deframer).await)
+            .unwrap();
+        assert_eq!(announce.packet_type, PacketType::Announce);
+        assert!(interface.is_ready().await);
+    }
+}
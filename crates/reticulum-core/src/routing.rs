@@ -0,0 +1,148 @@
+//! Routing table
+//!
+//! Remembers, for every destination heard announcing itself, the interface
+//! and the peer on it the announce came from: the next hop towards that
+//! destination, and the announce itself, to answer path requests with. Only
+//! announces newer than the one a route was learned from replace it, so an
+//! announce flooded around a loop is taken (and passed on) once.
+
+use crate::{Announce, DestinationHash};
+use std::collections::HashMap;
+use std::time::Duration;
+use web_time::Instant;
+
+/// Where to send packets for a destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Name of the interface leading to the destination
+    pub interface: String,
+
+    /// Peer on that interface the announce came from
+    pub next_hop: DestinationHash,
+
+    /// Announce the route was learned from
+    pub announce: Announce,
+
+    /// When the route was learned
+    learned: Instant,
+}
+
+/// Routes to the destinations heard announcing themselves
+#[derive(Debug)]
+pub struct RoutingTable {
+    routes: HashMap<DestinationHash, Route>,
+
+    /// How long a route is used without a fresh announce
+    lifetime: Duration,
+}
+
+impl RoutingTable {
+    /// Create an empty table whose routes last `lifetime`
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            routes: HashMap::new(),
+            lifetime,
+        }
+    }
+
+    /// Learn the route to the destination of a verified `announce`, heard on
+    /// `interface` from `next_hop`
+    ///
+    /// Returns whether the announce was new, and so worth passing on.
+    pub fn learn(
+        &mut self,
+        announce: &Announce,
+        interface: &str,
+        next_hop: DestinationHash,
+    ) -> bool {
+        let destination = announce.destination();
+        if let Some(route) = self.lookup(&destination) {
+            if route.announce.timestamp >= announce.timestamp {
+                return false;
+            }
+        }
+        self.routes.insert(
+            destination,
+            Route {
+                interface: interface.to_string(),
+                next_hop,
+                announce: announce.clone(),
+                learned: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Get the route to `destination`, unless it expired
+    pub fn lookup(&self, destination: &DestinationHash) -> Option<&Route> {
+        self.routes
+            .get(destination)
+            .filter(|route| route.learned.elapsed() < self.lifetime)
+    }
+
+    /// Drop expired routes
+    pub fn expire(&mut self) {
+        let lifetime = self.lifetime;
+        self.routes
+            .retain(|_, route| route.learned.elapsed() < lifetime);
+    }
+
+    /// Get the number of routes, expired ones included
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Check whether the table has no routes
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    #[test]
+    fn test_learn() {
+        let identity = Identity::generate();
+        let mut announce = Announce::new(&identity, vec![]);
+        let mut table = RoutingTable::new(Duration::from_secs(60));
+
+        assert!(table.learn(&announce, "tcp:a", [1; 32]));
+        let route = table.lookup(&identity.destination_hash()).unwrap();
+        assert_eq!(route.interface, "tcp:a");
+        assert_eq!(route.next_hop, [1; 32]);
+
+        // The same announce coming around again changes nothing
+        assert!(!table.learn(&announce, "tcp:b", [2; 32]));
+        assert_eq!(
+            table.lookup(&identity.destination_hash()).unwrap().next_hop,
+            [1; 32]
+        );
+
+        announce.timestamp += 1;
+        assert!(table.learn(&announce, "tcp:b", [2; 32]));
+        assert_eq!(
+            table.lookup(&identity.destination_hash()).unwrap().next_hop,
+            [2; 32]
+        );
+        assert!(table.lookup(&[9; 32]).is_none());
+    }
+
+    #[test]
+    fn test_expire() {
+        let identity = Identity::generate();
+        let announce = Announce::new(&identity, vec![]);
+        let mut table = RoutingTable::new(Duration::ZERO);
+
+        assert!(table.learn(&announce, "tcp:a", [1; 32]));
+        assert!(table.lookup(&identity.destination_hash()).is_none());
+        assert_eq!(table.len(), 1);
+
+        // An expired route is learned again from the same announce
+        assert!(table.learn(&announce, "tcp:a", [1; 32]));
+        table.expire();
+        assert!(table.is_empty());
+    }
+}
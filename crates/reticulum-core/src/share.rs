@@ -0,0 +1,245 @@
+//! Share strings
+//!
+//! A [`ShareString`] carries everything a client needs to reach a server and
+//! trust its key — the public key, and the I2P destination and TCP address
+//! it listens on — in one copyable `rsh:` string, or a QR code of it. The
+//! destination hash is derived from the public key, so a hash that does not
+//! match the key cannot be passed on.
+//!
+//! Format (base64url without padding after `rsh:`):
+//! ```text
+//! [ 1 byte: version (1) ]
+//! [ 32 bytes: public key ]
+//! [ fields: 1 byte tag, 2 bytes length (big-endian), value ]
+//! [ 4 bytes: first bytes of the SHA-256 of everything above ]
+//! ```
+//!
+//! Fields are the I2P destination (tag 1), the TCP address (2) and a name
+//! (3), each UTF-8 and optional. Readers skip tags they do not know.
+
+use crate::{DestinationHash, Identity, NetworkError, Result, Signer};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Prefix of share strings
+pub const PREFIX: &str = "rsh:";
+
+/// Format version written
+const VERSION: u8 = 1;
+
+/// Length of the public key
+const KEY_LEN: usize = 32;
+
+/// Length of the checksum
+const CHECKSUM_LEN: usize = 4;
+
+const TAG_I2P_DESTINATION: u8 = 1;
+const TAG_TCP_ADDRESS: u8 = 2;
+const TAG_NAME: u8 = 3;
+
+/// How to reach a server, to hand to its users
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareString {
+    /// Public key of the server identity
+    pub public_key: Vec<u8>,
+
+    /// I2P destination the server listens on (base64)
+    pub i2p_destination: Option<String>,
+
+    /// TCP address the server listens on
+    pub tcp_address: Option<SocketAddr>,
+
+    /// Name the server goes by
+    pub name: Option<String>,
+}
+
+impl ShareString {
+    /// A share string for the server with `identity`, reachable by no
+    /// transport yet
+    pub fn new(identity: &dyn Signer) -> Self {
+        Self {
+            public_key: identity.public_key(),
+            i2p_destination: None,
+            tcp_address: None,
+            name: None,
+        }
+    }
+
+    /// Get the server's destination hash
+    pub fn destination(&self) -> DestinationHash {
+        Identity::hash_from_public_key(&self.public_key)
+    }
+
+    /// Encode as an `rsh:` string
+    pub fn encode(&self) -> String {
+        let mut buf = BytesMut::with_capacity(64);
+        buf.put_u8(VERSION);
+        buf.put_slice(&self.public_key);
+
+        let tcp_address = self.tcp_address.map(|address| address.to_string());
+        let fields = [
+            (TAG_I2P_DESTINATION, self.i2p_destination.as_deref()),
+            (TAG_TCP_ADDRESS, tcp_address.as_deref()),
+            (TAG_NAME, self.name.as_deref()),
+        ];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                buf.put_u8(tag);
+                buf.put_u16(value.len() as u16);
+                buf.put_slice(value.as_bytes());
+            }
+        }
+
+        let checksum = checksum(&buf);
+        buf.put_slice(&checksum);
+        format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode(&buf))
+    }
+
+    /// Decode an `rsh:` string, checking it was copied whole
+    pub fn decode(text: &str) -> Result<Self> {
+        let encoded = text
+            .trim()
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| invalid(format!("does not start with {}", PREFIX)))?;
+        let data = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|e| invalid(e.to_string()))?;
+        if data.len() < 1 + KEY_LEN + CHECKSUM_LEN {
+            return Err(invalid("too short".to_string()));
+        }
+
+        let (body, sum) = data.split_at(data.len() - CHECKSUM_LEN);
+        if checksum(body) != sum {
+            return Err(invalid(
+                "checksum mismatch (incomplete or mistyped)".to_string(),
+            ));
+        }
+
+        let mut buf = body;
+        let version = buf.get_u8();
+        if version != VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let mut share = Self {
+            public_key: buf[..KEY_LEN].to_vec(),
+            i2p_destination: None,
+            tcp_address: None,
+            name: None,
+        };
+        buf.advance(KEY_LEN);
+
+        while buf.has_remaining() {
+            if buf.len() < 3 {
+                return Err(invalid("truncated field".to_string()));
+            }
+            let tag = buf.get_u8();
+            let len = buf.get_u16() as usize;
+            if buf.len() < len {
+                return Err(invalid("truncated field".to_string()));
+            }
+            let value = std::str::from_utf8(&buf[..len])
+                .map_err(|_| invalid(format!("field {} is not UTF-8", tag)))?
+                .to_string();
+            buf.advance(len);
+
+            match tag {
+                TAG_I2P_DESTINATION => share.i2p_destination = Some(value),
+                TAG_TCP_ADDRESS => {
+                    let address = value
+                        .parse()
+                        .map_err(|_| invalid(format!("bad TCP address {:?}", value)))?;
+                    share.tcp_address = Some(address);
+                }
+                TAG_NAME => share.name = Some(value),
+                // Added by a later version
+                _ => {}
+            }
+        }
+        Ok(share)
+    }
+}
+
+impl fmt::Display for ShareString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+impl FromStr for ShareString {
+    type Err = NetworkError;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Self::decode(text)
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    let mut sum = [0u8; CHECKSUM_LEN];
+    sum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    sum
+}
+
+fn invalid(reason: String) -> NetworkError {
+    NetworkError::InvalidDestination(format!("Invalid share string: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_string_round_trip() {
+        let identity = Identity::generate();
+        let mut share = ShareString::new(&identity);
+        share.i2p_destination = Some("abc~-".repeat(100));
+        share.tcp_address = Some("192.0.2.7:4242".parse().unwrap());
+        share.name = Some("lab".to_string());
+
+        let text = share.encode();
+        assert!(text.starts_with(PREFIX));
+        let decoded: ShareString = text.parse().unwrap();
+        assert_eq!(decoded, share);
+        assert_eq!(decoded.destination(), identity.destination_hash());
+
+        let bare = ShareString::new(&identity);
+        assert_eq!(ShareString::decode(&bare.encode()).unwrap(), bare);
+    }
+
+    #[test]
+    fn test_share_string_damaged() {
+        let mut share = ShareString::new(&Identity::generate());
+        share.name = Some("lab".to_string());
+        let text = share.encode();
+
+        assert!(ShareString::decode(&text[..text.len() - 3]).is_err());
+        assert!(ShareString::decode(&text[PREFIX.len()..]).is_err());
+
+        // One character changed
+        let mut chars: Vec<char> = text.chars().collect();
+        let i = PREFIX.len() + 10;
+        chars[i] = if chars[i] == 'A' { 'B' } else { 'A' };
+        let mistyped: String = chars.into_iter().collect();
+        let error = ShareString::decode(&mistyped).unwrap_err().to_string();
+        assert!(error.contains("checksum"), "{}", error);
+    }
+
+    #[test]
+    fn test_share_string_unknown_field() {
+        let share = ShareString::new(&Identity::generate());
+        let mut data = URL_SAFE_NO_PAD
+            .decode(&share.encode()[PREFIX.len()..])
+            .unwrap();
+        data.truncate(data.len() - CHECKSUM_LEN);
+        data.extend_from_slice(&[9, 0, 2, b'h', b'i']);
+        let sum = checksum(&data);
+        data.extend_from_slice(&sum);
+
+        let text = format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode(&data));
+        assert_eq!(ShareString::decode(&text).unwrap(), share);
+    }
+}
@@ -0,0 +1,76 @@
+//! Private key operations
+//!
+//! A [`Signer`] does what needs an identity's private key — signing, and
+//! X25519 key agreement for session encryption — without necessarily
+//! holding it: an [`Identity`] does it in memory, a key agent in another
+//! process.
+
+use crate::{DestinationHash, Identity, Result};
+use std::fmt;
+
+/// Operations with the private key of an identity
+pub trait Signer: fmt::Debug + Send + Sync {
+    /// The Ed25519 public key
+    fn public_key(&self) -> Vec<u8>;
+
+    /// Sign `data` with the Ed25519 key
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// X25519 agreement of the identity's key (see
+    /// [`Identity::x25519_private_key`]) with the X25519 `public_key` of
+    /// another party; returns the shared secret
+    fn agree(&self, public_key: &[u8; 32]) -> Result<[u8; 32]>;
+
+    /// Get the destination hash (SHA-256 of public key)
+    fn destination_hash(&self) -> DestinationHash {
+        Identity::hash_from_public_key(&self.public_key())
+    }
+
+    /// Get destination hash as hex string
+    fn destination_hex(&self) -> String {
+        hex::encode(self.destination_hash())
+    }
+}
+
+impl Signer for Identity {
+    fn public_key(&self) -> Vec<u8> {
+        Identity::public_key(self)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Identity::sign(self, data))
+    }
+
+    fn agree(&self, public_key: &[u8; 32]) -> Result<[u8; 32]> {
+        let secret = x25519_dalek::StaticSecret::from(self.x25519_private_key());
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*public_key));
+        Ok(shared.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_signer() {
+        let identity = Identity::generate();
+        let signer: &dyn Signer = &identity;
+        assert_eq!(signer.destination_hex(), identity.destination_hex());
+
+        let signature = signer.sign(b"data").unwrap();
+        assert!(identity.verify(b"data", &signature).is_ok());
+
+        // Both ends of an agreement get the same secret
+        let other = Identity::generate();
+        let ours = signer
+            .agree(&Identity::x25519_public_key(&other.public_key()).unwrap())
+            .unwrap();
+        let theirs = Signer::agree(
+            &other,
+            &Identity::x25519_public_key(&identity.public_key()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ours, theirs);
+    }
+}
@@ -0,0 +1,409 @@
+//! TCP transport
+//!
+//! Packets are framed with a 4-byte big-endian length prefix. A listening
+//! interface accepts any number of peers; a connecting interface has exactly
+//! one peer and sends every packet to it.
+//!
+//! Peers are addressed by the SHA-256 hash of their socket address (like I2P
+//! sources are by the hash of their destination). Received packets carry that
+//! hash as their destination, so replies sent to `packet.destination` reach
+//! the peer they came from; the destination the peer addressed is kept as
+//! their final destination.
+//!
+//! The same framing can run over any byte stream ([`TcpInterface::over`]),
+//! e.g. a connection relayed by another host.
+
+use crate::{DestinationHash, NetworkError, NetworkInterface, Packet, Result};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Largest frame accepted (a maximal packet plus headroom)
+const MAX_FRAME: usize = 128 * 1024;
+
+type Peers = Arc<StdMutex<HashMap<DestinationHash, mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// Packet transport over TCP
+pub struct TcpInterface {
+    name: String,
+    local_addr: StdMutex<SocketAddr>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Packet>>,
+    peers: Peers,
+
+    /// The only peer of a connecting interface
+    upstream: Option<DestinationHash>,
+
+    /// Address a connecting interface dials, again when reopened
+    remote: Option<SocketAddr>,
+
+    /// Accept loop and per-peer tasks, aborted on close
+    tasks: Arc<StdMutex<Vec<JoinHandle<()>>>>,
+}
+
+impl TcpInterface {
+    /// Listen for peers on `addr`
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("TCP interface listening on {}", local_addr);
+
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let peers: Peers = Arc::new(StdMutex::new(HashMap::new()));
+        let tasks = Arc::new(StdMutex::new(Vec::new()));
+
+        let accept = {
+            let peers = Arc::clone(&peers);
+            let tasks = Arc::clone(&tasks);
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            debug!("TCP peer connected: {}", peer);
+                            let (reader, writer) = stream.into_split();
+                            let handles = spawn_peer(
+                                reader,
+                                writer,
+                                peer.to_string(),
+                                &peers,
+                                incoming_tx.clone(),
+                            );
+                            let mut tasks = tasks.lock().unwrap();
+                            tasks.retain(|task| !task.is_finished());
+                            tasks.extend(handles);
+                        }
+                        Err(e) => warn!("TCP accept failed: {}", e),
+                    }
+                }
+            })
+        };
+        tasks.lock().unwrap().push(accept);
+
+        Ok(Self {
+            name: format!("tcp:{}", local_addr),
+            local_addr: StdMutex::new(local_addr),
+            incoming: Mutex::new(incoming_rx),
+            peers,
+            upstream: None,
+            remote: None,
+            tasks,
+        })
+    }
+
+    /// Connect to a listening interface at `addr`
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        let local_addr = stream.local_addr()?;
+        info!("TCP interface connected to {}", addr);
+
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            remote: Some(addr),
+            ..Self::with_upstream(reader, writer, addr.to_string(), local_addr)
+        })
+    }
+
+    /// Talk to a single peer over an established byte stream, such as a
+    /// connection relayed by another host; `name` identifies the peer
+    ///
+    /// The interface has no socket of its own, so its local address is
+    /// unspecified.
+    pub fn over<S>(stream: S, name: &str) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        Self::with_upstream(
+            reader,
+            writer,
+            name.to_string(),
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+        )
+    }
+
+    fn with_upstream<R, W>(reader: R, writer: W, peer: String, local_addr: SocketAddr) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let peers: Peers = Arc::new(StdMutex::new(HashMap::new()));
+        let upstream = peer_id(&peer);
+        let name = format!("tcp:{}", peer);
+        let handles = spawn_peer(reader, writer, peer, &peers, incoming_tx);
+
+        Self {
+            name,
+            local_addr: StdMutex::new(local_addr),
+            incoming: Mutex::new(incoming_rx),
+            peers,
+            upstream: Some(upstream),
+            remote: None,
+            tasks: Arc::new(StdMutex::new(Vec::from(handles))),
+        }
+    }
+
+    /// Get the local socket address
+    pub fn local_addr(&self) -> SocketAddr {
+        *self.local_addr.lock().unwrap()
+    }
+}
+
+/// Hash identifying a peer
+pub fn peer_hash(addr: &SocketAddr) -> DestinationHash {
+    peer_id(&addr.to_string())
+}
+
+fn peer_id(peer: &str) -> DestinationHash {
+    Sha256::digest(peer.as_bytes()).into()
+}
+
+/// Start the reader and writer tasks of a connected peer
+fn spawn_peer<R, W>(
+    reader: R,
+    writer: W,
+    peer: String,
+    peers: &Peers,
+    incoming: mpsc::UnboundedSender<Packet>,
+) -> [JoinHandle<()>; 2]
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let hash = peer_id(&peer);
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    peers.lock().unwrap().insert(hash, outgoing_tx);
+
+    let writer = tokio::spawn(write_frames(writer, outgoing_rx));
+
+    let peers = Arc::clone(peers);
+    let reader = tokio::spawn(async move {
+        if let Err(e) = read_frames(reader, hash, &incoming).await {
+            debug!("TCP peer {} disconnected: {}", peer, e);
+        }
+        peers.lock().unwrap().remove(&hash);
+    });
+
+    [reader, writer]
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    peer: DestinationHash,
+    incoming: &mpsc::UnboundedSender<Packet>,
+) -> Result<()> {
+    loop {
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_FRAME {
+            return Err(NetworkError::Packet(format!(
+                "Frame too large: {} bytes",
+                len
+            )));
+        }
+
+        let mut frame = vec![0u8; len];
+        reader.read_exact(&mut frame).await?;
+
+        match Packet::decode(&frame) {
+            Ok(mut packet) => {
+                // Return address for replies
+                packet.final_destination = Some(packet.destination);
+                packet.destination = peer;
+                if incoming.send(packet).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => warn!("Dropping undecodable TCP frame: {}", e),
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    while let Some(frame) = outgoing.recv().await {
+        let result = async {
+            writer.write_u32(frame.len() as u32).await?;
+            writer.write_all(&frame).await
+        }
+        .await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+#[async_trait]
+impl NetworkInterface for TcpInterface {
+    async fn send(&self, packet: &Packet) -> Result<()> {
+        let peer = self.upstream.unwrap_or(packet.destination);
+        let peers = self.peers.lock().unwrap();
+        let sender = peers
+            .get(&peer)
+            .ok_or_else(|| NetworkError::Connection("TCP peer not connected".to_string()))?;
+        sender
+            .send(packet.encode())
+            .map_err(|_| NetworkError::Connection("TCP peer disconnected".to_string()))
+    }
+
+    async fn broadcast(&self, packet: &Packet) -> Result<()> {
+        let frame = packet.encode();
+        for sender in self.peers.lock().unwrap().values() {
+            // Peers that just went away are dropped by their reader task
+            let _ = sender.send(frame.clone());
+        }
+        Ok(())
+    }
+
+    async fn receive(&self) -> Result<Packet> {
+        let mut incoming = self.incoming.lock().await;
+        incoming
+            .recv()
+            .await
+            .ok_or_else(|| NetworkError::Connection("TCP interface closed".to_string()))
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.upstream.is_none() || !self.peers.lock().unwrap().is_empty()
+    }
+
+    /// Dial the peer of a connecting interface again if it went away
+    ///
+    /// Listening interfaces wait for their peers to come back instead, and
+    /// streams handed to [`TcpInterface::over`] cannot be reopened.
+    async fn reopen(&self) -> Result<()> {
+        let Some(upstream) = self.upstream else {
+            return Ok(());
+        };
+        if self.peers.lock().unwrap().contains_key(&upstream) {
+            return Ok(());
+        }
+        let remote = self
+            .remote
+            .ok_or_else(|| NetworkError::Connection(format!("{} cannot be reopened", self.name)))?;
+
+        let stream = TcpStream::connect(remote).await?;
+        *self.local_addr.lock().unwrap() = stream.local_addr()?;
+        let (reader, writer) = stream.into_split();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let handles = spawn_peer(reader, writer, remote.to_string(), &self.peers, incoming_tx);
+        *self.incoming.lock().await = incoming_rx;
+
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.extend(handles);
+        info!("TCP interface reconnected to {}", remote);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        info!("Closing TCP interface {}", self.local_addr());
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.peers.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketType;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let server = TcpInterface::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = TcpInterface::connect(server.local_addr()).await.unwrap();
+        assert!(client.is_ready().await);
+
+        client
+            .send(&Packet::data([1; 32], b"hello".to_vec()))
+            .await
+            .unwrap();
+        let request = server.receive().await.unwrap();
+        assert_eq!(request.data.as_ref(), b"hello");
+        assert_eq!(request.destination, peer_hash(&client.local_addr()));
+        assert_eq!(request.final_destination, Some([1; 32]));
+
+        // Replies go back to the sender
+        server
+            .send(&Packet::data(request.destination, b"world".to_vec()))
+            .await
+            .unwrap();
+        let reply = client.receive().await.unwrap();
+        assert_eq!(reply.data.as_ref(), b"world");
+
+        assert!(server.send(&Packet::data([9; 32], vec![])).await.is_err());
+
+        server
+            .broadcast(&Packet::announce([2; 32], b"here".to_vec()))
+            .await
+            .unwrap();
+        let announce = client.receive().await.unwrap();
+        assert_eq!(announce.packet_type, PacketType::Announce);
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_over_stream() {
+        let (a, b) = tokio::io::duplex(1024);
+        let a = TcpInterface::over(a, "hop:a");
+        let b = TcpInterface::over(b, "hop:b");
+        assert_eq!(a.name(), "tcp:hop:a");
+
+        a.send(&Packet::data([1; 32], b"ping".to_vec()))
+            .await
+            .unwrap();
+        let packet = b.receive().await.unwrap();
+        assert_eq!(packet.data.as_ref(), b"ping");
+        assert_eq!(packet.destination, peer_id("hop:b"));
+
+        b.send(&Packet::data(packet.destination, b"pong".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(a.receive().await.unwrap().data.as_ref(), b"pong");
+        assert!(a.reopen().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let server = TcpInterface::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let client = TcpInterface::connect(addr).await.unwrap();
+
+        // The server goes away, and with it the connection
+        server.close().await.unwrap();
+        drop(server);
+        assert!(client.receive().await.is_err());
+        assert!(client.send(&Packet::data([1; 32], vec![])).await.is_err());
+
+        let server = TcpInterface::bind(addr).await.unwrap();
+        client.reopen().await.unwrap();
+        client
+            .send(&Packet::data([1; 32], b"again".to_vec()))
+            .await
+            .unwrap();
+        let packet = server.receive().await.unwrap();
+        assert_eq!(packet.data.as_ref(), b"again");
+        assert_eq!(packet.destination, peer_hash(&client.local_addr()));
+    }
+}
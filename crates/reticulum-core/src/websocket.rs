@@ -0,0 +1,213 @@
+//! WebSocket transport, for clients running in a browser
+//!
+//! A browser can open neither TCP connections nor an I2P session, only
+//! WebSockets, so a client running in one reaches the network through a
+//! gateway that accepts WebSocket connections and passes packets on. Each
+//! binary message carries one packet; message boundaries do what the TCP
+//! framing's length prefix does.
+//!
+//! As with a connecting TCP interface, the gateway is the only peer: every
+//! packet is sent to it, and received packets carry the hash of its URL as
+//! their destination, keeping the destination the gateway addressed as
+//! their final destination.
+//!
+//! The browser runs everything on one thread, so the socket and its
+//! callbacks, which cannot be sent between threads, are kept in a
+//! [`SendWrapper`] to satisfy [`NetworkInterface`].
+
+use crate::{DestinationHash, NetworkError, NetworkInterface, Packet, Result};
+use async_trait::async_trait;
+use send_wrapper::SendWrapper;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Mutex as StdMutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{info, warn};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+/// Packet transport over a WebSocket to a gateway
+pub struct WebSocketInterface {
+    url: String,
+    connection: StdMutex<SendWrapper<Connection>>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Packet>>,
+}
+
+/// An open socket and the callbacks it calls
+struct Connection {
+    socket: WebSocket,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl WebSocketInterface {
+    /// Connect to the gateway at `url` (`ws://` or `wss://`)
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (connection, incoming) = open(url).await?;
+        info!("WebSocket interface connected to {}", url);
+
+        Ok(Self {
+            url: url.to_string(),
+            connection: StdMutex::new(SendWrapper::new(connection)),
+            incoming: Mutex::new(incoming),
+        })
+    }
+
+    /// Get the gateway's URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn is_open(&self) -> bool {
+        self.connection.lock().unwrap().socket.ready_state() == WebSocket::OPEN
+    }
+}
+
+/// Hash identifying the gateway
+pub fn gateway_hash(url: &str) -> DestinationHash {
+    Sha256::digest(url.as_bytes()).into()
+}
+
+/// Open a socket to `url`, waiting until it is connected; returns it with
+/// the packets it will receive
+async fn open(url: &str) -> Result<(Connection, mpsc::UnboundedReceiver<Packet>)> {
+    let socket = WebSocket::new(url).map_err(|e| js_error("connection", e))?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    // Either event settles the connection attempt
+    let (opened_tx, opened_rx) = oneshot::channel();
+    let opened_tx = Rc::new(RefCell::new(Some(opened_tx)));
+    let settle = |opened: bool| {
+        let opened_tx = Rc::clone(&opened_tx);
+        Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+            if let Some(tx) = opened_tx.borrow_mut().take() {
+                let _ = tx.send(opened);
+            }
+        })
+    };
+    let (on_open, on_error) = (settle(true), settle(false));
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    let opened = opened_rx.await.unwrap_or(false);
+    socket.set_onopen(None);
+    socket.set_onerror(None);
+    if !opened {
+        return Err(NetworkError::Connection(format!(
+            "Could not connect to the WebSocket gateway {}",
+            url
+        )));
+    }
+
+    // The sender goes away with the socket, ending `receive`
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let incoming_tx = Rc::new(RefCell::new(Some(incoming_tx)));
+    let gateway = gateway_hash(url);
+
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new({
+        let incoming_tx = Rc::clone(&incoming_tx);
+        move |event: MessageEvent| {
+            let Ok(buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() else {
+                warn!("Dropping a text WebSocket message");
+                return;
+            };
+            let data = js_sys::Uint8Array::new(&buffer).to_vec();
+            match Packet::decode(&data) {
+                Ok(mut packet) => {
+                    // Return address for replies
+                    packet.final_destination = Some(packet.destination);
+                    packet.destination = gateway;
+                    if let Some(tx) = incoming_tx.borrow().as_ref() {
+                        let _ = tx.send(packet);
+                    }
+                }
+                Err(e) => warn!("Dropping undecodable WebSocket message: {}", e),
+            }
+        }
+    });
+    let on_close = Closure::<dyn FnMut(CloseEvent)>::new({
+        let url = url.to_string();
+        move |event: CloseEvent| {
+            info!(
+                "WebSocket gateway {} closed the connection ({})",
+                url,
+                event.code()
+            );
+            incoming_tx.borrow_mut().take();
+        }
+    });
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+    let connection = Connection {
+        socket,
+        _on_message: on_message,
+        _on_close: on_close,
+    };
+    Ok((connection, incoming_rx))
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The callbacks are dropped with us; the browser must not call them
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+fn js_error(what: &str, error: JsValue) -> NetworkError {
+    NetworkError::Connection(format!("WebSocket {} failed: {:?}", what, error))
+}
+
+#[async_trait]
+impl NetworkInterface for WebSocketInterface {
+    async fn send(&self, packet: &Packet) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        if connection.socket.ready_state() != WebSocket::OPEN {
+            return Err(NetworkError::Connection(
+                "WebSocket gateway disconnected".to_string(),
+            ));
+        }
+        connection
+            .socket
+            .send_with_u8_array(&packet.encode())
+            .map_err(|e| js_error("send", e))
+    }
+
+    async fn receive(&self) -> Result<Packet> {
+        let mut incoming = self.incoming.lock().await;
+        incoming
+            .recv()
+            .await
+            .ok_or_else(|| NetworkError::Connection("WebSocket interface closed".to_string()))
+    }
+
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.is_open()
+    }
+
+    /// Connect to the gateway again if the connection went away
+    async fn reopen(&self) -> Result<()> {
+        if self.is_open() {
+            return Ok(());
+        }
+        // Only ever polled on the browser's one thread
+        let (connection, incoming) = SendWrapper::new(open(&self.url)).await?;
+        *self.connection.lock().unwrap() = SendWrapper::new(connection);
+        *self.incoming.lock().await = incoming;
+        info!("WebSocket interface reconnected to {}", self.url);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        info!("Closing WebSocket interface {}", self.url);
+        let connection = self.connection.lock().unwrap();
+        connection.socket.close().map_err(|e| js_error("close", e))
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * Smoke test of the C interface
+ *
+ * Creates an identity, signs with it and saves and loads it; given the
+ * address of a SAM bridge, also opens an I2P interface and sends a packet
+ * to itself. From crates/reticulum-ffi:
+ *
+ *     cargo build -p reticulum-ffi --release
+ *     cc -Iinclude examples/smoke.c -L../../target/release -lreticulum -o smoke
+ *     LD_LIBRARY_PATH=../../target/release ./smoke [127.0.0.1:7656]
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "reticulum.h"
+
+#define CHECK(call)                                                           \
+    do {                                                                      \
+        int status = (call);                                                  \
+        if (status != RNS_OK) {                                               \
+            fprintf(stderr, "%s: %d (%s)\n", #call, status, rns_last_error()); \
+            return 1;                                                         \
+        }                                                                     \
+    } while (0)
+
+static void print_hex(const char *label, const uint8_t *data, size_t length)
+{
+    printf("%s: ", label);
+    for (size_t i = 0; i < length; i++)
+        printf("%02x", data[i]);
+    printf("\n");
+}
+
+static int identities(void)
+{
+    static const uint8_t message[] = "smoke";
+    uint8_t hash[RNS_HASH_LENGTH], loaded_hash[RNS_HASH_LENGTH];
+    uint8_t signature[RNS_SIGNATURE_LENGTH];
+    char path[] = "/tmp/rns-smoke-identity";
+
+    RnsIdentity *identity = rns_identity_generate();
+    CHECK(rns_identity_destination_hash(identity, hash));
+    print_hex("destination", hash, sizeof hash);
+
+    CHECK(rns_identity_sign(identity, message, sizeof message, signature));
+    CHECK(rns_identity_verify(identity, message, sizeof message, signature));
+    signature[0] ^= 1;
+    if (rns_identity_verify(identity, message, sizeof message, signature) != RNS_ERR_IDENTITY) {
+        fprintf(stderr, "a bad signature verified\n");
+        return 1;
+    }
+
+    CHECK(rns_identity_save(identity, path, "passphrase"));
+    RnsIdentity *loaded = rns_identity_load(path, "passphrase");
+    remove(path);
+    if (loaded == NULL) {
+        fprintf(stderr, "rns_identity_load: %s\n", rns_last_error());
+        return 1;
+    }
+    CHECK(rns_identity_destination_hash(loaded, loaded_hash));
+    if (memcmp(hash, loaded_hash, sizeof hash) != 0) {
+        fprintf(stderr, "the loaded identity differs\n");
+        return 1;
+    }
+
+    rns_identity_free(loaded);
+    rns_identity_free(identity);
+    printf("identities: ok\n");
+    return 0;
+}
+
+static int loopback(const char *sam_address)
+{
+    static const uint8_t payload[] = "hello over i2p";
+    uint8_t self[RNS_HASH_LENGTH];
+    RnsPacket *packet = NULL;
+
+    RnsInterface *interface = rns_interface_open_i2p(sam_address);
+    if (interface == NULL) {
+        fprintf(stderr, "rns_interface_open_i2p: %s\n", rns_last_error());
+        return 1;
+    }
+    CHECK(rns_interface_register_destination(
+        interface, rns_interface_local_destination(interface), self));
+    print_hex("i2p destination", self, sizeof self);
+
+    // Tunnels take a while to build
+    CHECK(rns_interface_send(interface, self, NULL, RNS_PACKET_DATA, payload, sizeof payload));
+    CHECK(rns_interface_receive(interface, 120000, &packet));
+    if (packet->data_length != sizeof payload || memcmp(packet->data, payload, sizeof payload) != 0) {
+        fprintf(stderr, "received another payload\n");
+        return 1;
+    }
+
+    rns_packet_free(packet);
+    rns_interface_free(interface);
+    printf("loopback: ok\n");
+    return 0;
+}
+
+int main(int argc, char **argv)
+{
+    if (identities() != 0)
+        return 1;
+    if (argc > 1 && loopback(argv[1]) != 0)
+        return 1;
+    return 0;
+}
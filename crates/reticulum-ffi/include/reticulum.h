@@ -0,0 +1,236 @@
+/* Generated by cbindgen from crates/reticulum-ffi; do not edit. */
+
+#ifndef RETICULUM_H
+#define RETICULUM_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The call succeeded
+#define RNS_OK 0
+
+// An argument was NULL or not valid
+#define RNS_ERR_ARGUMENT -1
+
+// An identity could not be created, loaded or saved
+#define RNS_ERR_IDENTITY -2
+
+// A packet could not be encoded or decoded
+#define RNS_ERR_PACKET -3
+
+// The transport failed: the SAM bridge, the connection or the I2P network
+#define RNS_ERR_TRANSPORT -4
+
+// Nothing arrived in time
+#define RNS_ERR_TIMEOUT -5
+
+// Bytes of a destination hash
+#define RNS_HASH_LENGTH 32
+
+// Bytes of a public key
+#define RNS_PUBLIC_KEY_LENGTH 32
+
+// Bytes of a signature
+#define RNS_SIGNATURE_LENGTH 64
+
+// Data packet
+#define RNS_PACKET_DATA 0
+
+// Announce packet
+#define RNS_PACKET_ANNOUNCE 1
+
+// Link request
+#define RNS_PACKET_LINK_REQUEST 2
+
+// Link response
+#define RNS_PACKET_LINK_RESPONSE 3
+
+// Proof packet
+#define RNS_PACKET_PROOF 4
+
+// Path request
+#define RNS_PACKET_PATH_REQUEST 5
+
+// An identity (Ed25519 keypair)
+typedef struct RnsIdentity RnsIdentity;
+
+// An I2P interface, through a SAM bridge
+typedef struct RnsInterface RnsInterface;
+
+// A received packet
+typedef struct RnsPacket {
+  // Packet type (`RNS_PACKET_*`)
+  uint8_t packet_type;
+  // Destination hash of the I2P peer that sent it, to send replies to
+  uint8_t source[32];
+  // Destination the sender addressed
+  uint8_t destination[32];
+  // Payload, owned by the packet
+  uint8_t *data;
+  // Bytes of payload
+  size_t data_length;
+} RnsPacket;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Why the last failed call on this thread failed, or NULL
+//
+// The string stays valid until the next call on the same thread.
+const char *rns_last_error(void);
+
+// Generate a new random identity
+RnsIdentity *rns_identity_generate(void);
+
+// Load the identity file at `path`, decrypting it with `passphrase` if it
+// is encrypted (NULL if it is not); NULL on failure
+//
+// # Safety
+//
+// `path` must be a NUL-terminated string, `passphrase` one or NULL.
+RnsIdentity *rns_identity_load(const char *path, const char *passphrase);
+
+// Save `identity` to the file `path`, encrypted with `passphrase` unless
+// it is NULL
+//
+// # Safety
+//
+// `identity` must be a live identity, `path` a NUL-terminated string and
+// `passphrase` one or NULL.
+int rns_identity_save(const RnsIdentity *identity, const char *path, const char *passphrase);
+
+// Copy the public key of `identity` ([`RNS_PUBLIC_KEY_LENGTH`] bytes) to
+// `out`
+//
+// # Safety
+//
+// `identity` must be a live identity and `out` must have room for
+// [`RNS_PUBLIC_KEY_LENGTH`] bytes.
+int rns_identity_public_key(const RnsIdentity *identity, uint8_t *out);
+
+// Copy the destination hash of `identity` ([`RNS_HASH_LENGTH`] bytes) to
+// `out`
+//
+// # Safety
+//
+// `identity` must be a live identity and `out` must have room for
+// [`RNS_HASH_LENGTH`] bytes.
+int rns_identity_destination_hash(const RnsIdentity *identity, uint8_t *out);
+
+// Sign the `length` bytes at `data` with `identity`, writing the
+// signature ([`RNS_SIGNATURE_LENGTH`] bytes) to `out`
+//
+// # Safety
+//
+// `identity` must be a live identity, `data` must point to `length` bytes
+// (or be NULL if `length` is 0) and `out` must have room for
+// [`RNS_SIGNATURE_LENGTH`] bytes.
+int rns_identity_sign(const RnsIdentity *identity, const uint8_t *data, size_t length, uint8_t *out);
+
+// Check that `signature` ([`RNS_SIGNATURE_LENGTH`] bytes) is `identity`'s
+// over the `length` bytes at `data`; [`RNS_ERR_IDENTITY`] if it is not
+//
+// # Safety
+//
+// `identity` must be a live identity, `data` must point to `length` bytes
+// (or be NULL if `length` is 0) and `signature` to
+// [`RNS_SIGNATURE_LENGTH`] bytes.
+int rns_identity_verify(const RnsIdentity *identity,
+                        const uint8_t *data,
+                        size_t length,
+                        const uint8_t *signature);
+
+// Free `identity` (NULL is ignored)
+//
+// # Safety
+//
+// `identity` must be NULL or an identity not freed yet.
+void rns_identity_free(RnsIdentity *identity);
+
+// Open an I2P interface through the SAM bridge at `sam_address`
+// (`host:port`, usually `127.0.0.1:7656`); NULL on failure
+//
+// The interface gets a new I2P destination of its own.
+//
+// # Safety
+//
+// `sam_address` must be a NUL-terminated string.
+RnsInterface *rns_interface_open_i2p(const char *sam_address);
+
+// The I2P destination (base64) of `interface`, for peers to register;
+// valid until the interface is freed
+//
+// # Safety
+//
+// `interface` must be a live interface.
+const char *rns_interface_local_destination(const RnsInterface *interface);
+
+// Make the I2P destination `i2p_destination` (base64) reachable, writing
+// the hash packets for it are sent to (32 bytes) to `out`
+//
+// Peers that sent us a packet are reachable already.
+//
+// # Safety
+//
+// `interface` must be a live interface, `i2p_destination` a NUL-terminated
+// string and `out` must have room for 32 bytes.
+int rns_interface_register_destination(const RnsInterface *interface,
+                                       const char *i2p_destination,
+                                       uint8_t *out);
+
+// Send a packet of `packet_type` with the `length` bytes at `data` to the
+// peer of `destination`, a registered hash
+//
+// A `final_destination` (or NULL) is the destination the packet is for
+// when the peer is only the next hop.
+//
+// # Safety
+//
+// `interface` must be a live interface, `destination` must point to 32
+// bytes, `final_destination` to 32 bytes or be NULL, and `data` to
+// `length` bytes (or be NULL if `length` is 0).
+int rns_interface_send(const RnsInterface *interface,
+                       const uint8_t *destination,
+                       const uint8_t *final_destination,
+                       uint8_t packet_type,
+                       const uint8_t *data,
+                       size_t length);
+
+// Wait up to `timeout_ms` milliseconds (0: for as long as it takes) for a
+// packet, storing it in `out`; free it with [`rns_packet_free`]
+//
+// Returns [`RNS_ERR_TIMEOUT`] if none arrived in time. The SAM bridge
+// carries one thing at a time, so until the next packet arrives a send
+// waits for the receive, also for one that timed out.
+//
+// # Safety
+//
+// `interface` must be a live interface and `out` must point to a packet
+// pointer.
+int rns_interface_receive(const RnsInterface *interface, uint32_t timeout_ms, RnsPacket **out);
+
+// Close and free `interface` (NULL is ignored)
+//
+// # Safety
+//
+// `interface` must be NULL or an interface not freed yet, used by no other
+// thread.
+void rns_interface_free(RnsInterface *interface);
+
+// Free `packet` and its payload (NULL is ignored)
+//
+// # Safety
+//
+// `packet` must be NULL or a packet from [`rns_interface_receive`] not
+// freed yet.
+void rns_packet_free(RnsPacket *packet);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RETICULUM_H */
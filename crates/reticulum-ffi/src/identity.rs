@@ -0,0 +1,279 @@
+//! Identities
+
+use crate::{fail, string, RNS_ERR_ARGUMENT, RNS_ERR_IDENTITY, RNS_OK};
+use reticulum_core::{Identity, NetworkError};
+use std::ffi::{c_char, c_int};
+
+/// Bytes of a destination hash
+pub const RNS_HASH_LENGTH: usize = 32;
+/// Bytes of a public key
+pub const RNS_PUBLIC_KEY_LENGTH: usize = 32;
+/// Bytes of a signature
+pub const RNS_SIGNATURE_LENGTH: usize = 64;
+
+/// An identity (Ed25519 keypair)
+pub struct RnsIdentity(pub(crate) Identity);
+
+/// Generate a new random identity
+#[no_mangle]
+pub extern "C" fn rns_identity_generate() -> *mut RnsIdentity {
+    Box::into_raw(Box::new(RnsIdentity(Identity::generate())))
+}
+
+/// Load the identity file at `path`, decrypting it with `passphrase` if it
+/// is encrypted (NULL if it is not); NULL on failure
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string, `passphrase` one or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_load(
+    path: *const c_char,
+    passphrase: *const c_char,
+) -> *mut RnsIdentity {
+    let Ok(path) = string(path, "path") else {
+        return std::ptr::null_mut();
+    };
+    let loaded = if passphrase.is_null() {
+        Identity::load_from_file(path)
+    } else {
+        let Ok(passphrase) = string(passphrase, "passphrase") else {
+            return std::ptr::null_mut();
+        };
+        std::fs::read(path)
+            .map_err(NetworkError::from)
+            .and_then(|data| Identity::from_encrypted_bytes(&data, passphrase))
+    };
+    match loaded {
+        Ok(identity) => Box::into_raw(Box::new(RnsIdentity(identity))),
+        Err(e) => {
+            fail(RNS_ERR_IDENTITY, e.to_string());
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Save `identity` to the file `path`, encrypted with `passphrase` unless
+/// it is NULL
+///
+/// # Safety
+///
+/// `identity` must be a live identity, `path` a NUL-terminated string and
+/// `passphrase` one or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_save(
+    identity: *const RnsIdentity,
+    path: *const c_char,
+    passphrase: *const c_char,
+) -> c_int {
+    let Some(identity) = identity.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "identity is NULL");
+    };
+    let path = match string(path, "path") {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
+    let saved = if passphrase.is_null() {
+        identity.0.save_to_file(path)
+    } else {
+        match string(passphrase, "passphrase") {
+            Ok(passphrase) => identity.0.save_encrypted(path, passphrase),
+            Err(status) => return status,
+        }
+    };
+    match saved {
+        Ok(()) => RNS_OK,
+        Err(e) => fail(RNS_ERR_IDENTITY, e.to_string()),
+    }
+}
+
+/// Copy the public key of `identity` ([`RNS_PUBLIC_KEY_LENGTH`] bytes) to
+/// `out`
+///
+/// # Safety
+///
+/// `identity` must be a live identity and `out` must have room for
+/// [`RNS_PUBLIC_KEY_LENGTH`] bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_public_key(
+    identity: *const RnsIdentity,
+    out: *mut u8,
+) -> c_int {
+    match identity.as_ref() {
+        Some(identity) => copy_out(&identity.0.public_key(), out),
+        None => fail(RNS_ERR_ARGUMENT, "identity is NULL"),
+    }
+}
+
+/// Copy the destination hash of `identity` ([`RNS_HASH_LENGTH`] bytes) to
+/// `out`
+///
+/// # Safety
+///
+/// `identity` must be a live identity and `out` must have room for
+/// [`RNS_HASH_LENGTH`] bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_destination_hash(
+    identity: *const RnsIdentity,
+    out: *mut u8,
+) -> c_int {
+    match identity.as_ref() {
+        Some(identity) => copy_out(&identity.0.destination_hash(), out),
+        None => fail(RNS_ERR_ARGUMENT, "identity is NULL"),
+    }
+}
+
+/// Sign the `length` bytes at `data` with `identity`, writing the
+/// signature ([`RNS_SIGNATURE_LENGTH`] bytes) to `out`
+///
+/// # Safety
+///
+/// `identity` must be a live identity, `data` must point to `length` bytes
+/// (or be NULL if `length` is 0) and `out` must have room for
+/// [`RNS_SIGNATURE_LENGTH`] bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_sign(
+    identity: *const RnsIdentity,
+    data: *const u8,
+    length: usize,
+    out: *mut u8,
+) -> c_int {
+    let Some(identity) = identity.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "identity is NULL");
+    };
+    match bytes(data, length) {
+        Ok(data) => copy_out(&identity.0.sign(data), out),
+        Err(status) => status,
+    }
+}
+
+/// Check that `signature` ([`RNS_SIGNATURE_LENGTH`] bytes) is `identity`'s
+/// over the `length` bytes at `data`; [`RNS_ERR_IDENTITY`] if it is not
+///
+/// # Safety
+///
+/// `identity` must be a live identity, `data` must point to `length` bytes
+/// (or be NULL if `length` is 0) and `signature` to
+/// [`RNS_SIGNATURE_LENGTH`] bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_verify(
+    identity: *const RnsIdentity,
+    data: *const u8,
+    length: usize,
+    signature: *const u8,
+) -> c_int {
+    let Some(identity) = identity.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "identity is NULL");
+    };
+    let (data, signature) = match (bytes(data, length), bytes(signature, RNS_SIGNATURE_LENGTH)) {
+        (Ok(data), Ok(signature)) => (data, signature),
+        (Err(status), _) | (_, Err(status)) => return status,
+    };
+    match identity.0.verify(data, signature) {
+        Ok(()) => RNS_OK,
+        Err(e) => fail(RNS_ERR_IDENTITY, e.to_string()),
+    }
+}
+
+/// Free `identity` (NULL is ignored)
+///
+/// # Safety
+///
+/// `identity` must be NULL or an identity not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rns_identity_free(identity: *mut RnsIdentity) {
+    if !identity.is_null() {
+        drop(Box::from_raw(identity));
+    }
+}
+
+/// The `length` bytes at `data`
+///
+/// # Safety
+///
+/// `data` must point to `length` bytes, or be NULL if `length` is 0.
+pub(crate) unsafe fn bytes<'a>(data: *const u8, length: usize) -> Result<&'a [u8], c_int> {
+    match (data.is_null(), length) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(fail(RNS_ERR_ARGUMENT, "data is NULL")),
+        (false, _) => Ok(std::slice::from_raw_parts(data, length)),
+    }
+}
+
+/// Copy `data` to `out`
+///
+/// # Safety
+///
+/// `out` must be NULL or have room for `data.len()` bytes.
+pub(crate) unsafe fn copy_out(data: &[u8], out: *mut u8) -> c_int {
+    if out.is_null() {
+        return fail(RNS_ERR_ARGUMENT, "out is NULL");
+    }
+    std::ptr::copy_nonoverlapping(data.as_ptr(), out, data.len());
+    RNS_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let identity = rns_identity_generate();
+        let mut signature = [0u8; RNS_SIGNATURE_LENGTH];
+        let mut hash = [0u8; RNS_HASH_LENGTH];
+        unsafe {
+            assert_eq!(
+                rns_identity_sign(identity, b"data".as_ptr(), 4, signature.as_mut_ptr()),
+                RNS_OK
+            );
+            assert_eq!(
+                rns_identity_verify(identity, b"data".as_ptr(), 4, signature.as_ptr()),
+                RNS_OK
+            );
+            assert_eq!(
+                rns_identity_verify(identity, b"atad".as_ptr(), 4, signature.as_ptr()),
+                RNS_ERR_IDENTITY
+            );
+
+            assert_eq!(
+                rns_identity_destination_hash(identity, hash.as_mut_ptr()),
+                RNS_OK
+            );
+            assert_eq!(hash, (*identity).0.destination_hash());
+            assert_eq!(
+                rns_identity_sign(
+                    std::ptr::null(),
+                    std::ptr::null(),
+                    0,
+                    signature.as_mut_ptr()
+                ),
+                RNS_ERR_ARGUMENT
+            );
+            rns_identity_free(identity);
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("id").to_str().unwrap()).unwrap();
+        let passphrase = CString::new("secret").unwrap();
+        let identity = rns_identity_generate();
+        unsafe {
+            assert_eq!(
+                rns_identity_save(identity, path.as_ptr(), passphrase.as_ptr()),
+                RNS_OK
+            );
+            // Encrypted, so not without the passphrase
+            assert!(rns_identity_load(path.as_ptr(), std::ptr::null()).is_null());
+            let loaded = rns_identity_load(path.as_ptr(), passphrase.as_ptr());
+            assert!(!loaded.is_null());
+            assert_eq!((*loaded).0.public_key(), (*identity).0.public_key());
+
+            rns_identity_free(loaded);
+            rns_identity_free(identity);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! I2P interfaces and the packets they carry
+
+use crate::identity::{bytes, copy_out};
+use crate::{
+    fail, fail_with, runtime, string, RNS_ERR_ARGUMENT, RNS_ERR_TIMEOUT, RNS_ERR_TRANSPORT, RNS_OK,
+};
+use reticulum_core::{I2pInterface, NetworkInterface, Packet, PacketType, Result};
+use std::ffi::{c_char, c_int, CString};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Data packet
+pub const RNS_PACKET_DATA: u8 = 0x00;
+/// Announce packet
+pub const RNS_PACKET_ANNOUNCE: u8 = 0x01;
+/// Link request
+pub const RNS_PACKET_LINK_REQUEST: u8 = 0x02;
+/// Link response
+pub const RNS_PACKET_LINK_RESPONSE: u8 = 0x03;
+/// Proof packet
+pub const RNS_PACKET_PROOF: u8 = 0x04;
+/// Path request
+pub const RNS_PACKET_PATH_REQUEST: u8 = 0x05;
+
+/// An I2P interface, through a SAM bridge
+pub struct RnsInterface {
+    i2p: Arc<I2pInterface>,
+
+    /// Our I2P destination, as `rns_interface_local_destination` hands it out
+    local_destination: CString,
+
+    /// A receive that outlived the timeout of its call, taken up by the
+    /// next call: reading a datagram cannot be stopped halfway
+    pending: Mutex<Option<JoinHandle<Result<Packet>>>>,
+}
+
+/// A received packet
+#[repr(C)]
+pub struct RnsPacket {
+    /// Packet type (`RNS_PACKET_*`)
+    pub packet_type: u8,
+
+    /// Destination hash of the I2P peer that sent it, to send replies to
+    pub source: [u8; 32],
+
+    /// Destination the sender addressed
+    pub destination: [u8; 32],
+
+    /// Payload, owned by the packet
+    pub data: *mut u8,
+
+    /// Bytes of payload
+    pub data_length: usize,
+}
+
+/// Open an I2P interface through the SAM bridge at `sam_address`
+/// (`host:port`, usually `127.0.0.1:7656`); NULL on failure
+///
+/// The interface gets a new I2P destination of its own.
+///
+/// # Safety
+///
+/// `sam_address` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_open_i2p(sam_address: *const c_char) -> *mut RnsInterface {
+    let Ok(sam_address) = string(sam_address, "sam_address") else {
+        return std::ptr::null_mut();
+    };
+    let i2p = match runtime().block_on(I2pInterface::new(sam_address)) {
+        Ok(i2p) => i2p,
+        Err(e) => {
+            fail_with(e);
+            return std::ptr::null_mut();
+        }
+    };
+    let Ok(local_destination) = CString::new(i2p.local_destination()) else {
+        fail(
+            RNS_ERR_TRANSPORT,
+            "The SAM bridge returned a malformed destination",
+        );
+        return std::ptr::null_mut();
+    };
+    Box::into_raw(Box::new(RnsInterface {
+        i2p: Arc::new(i2p),
+        local_destination,
+        pending: Mutex::new(None),
+    }))
+}
+
+/// The I2P destination (base64) of `interface`, for peers to register;
+/// valid until the interface is freed
+///
+/// # Safety
+///
+/// `interface` must be a live interface.
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_local_destination(
+    interface: *const RnsInterface,
+) -> *const c_char {
+    match interface.as_ref() {
+        Some(interface) => interface.local_destination.as_ptr(),
+        None => {
+            fail(RNS_ERR_ARGUMENT, "interface is NULL");
+            std::ptr::null()
+        }
+    }
+}
+
+/// Make the I2P destination `i2p_destination` (base64) reachable, writing
+/// the hash packets for it are sent to (32 bytes) to `out`
+///
+/// Peers that sent us a packet are reachable already.
+///
+/// # Safety
+///
+/// `interface` must be a live interface, `i2p_destination` a NUL-terminated
+/// string and `out` must have room for 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_register_destination(
+    interface: *const RnsInterface,
+    i2p_destination: *const c_char,
+    out: *mut u8,
+) -> c_int {
+    let Some(interface) = interface.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "interface is NULL");
+    };
+    let i2p_destination = match string(i2p_destination, "i2p_destination") {
+        Ok(i2p_destination) => i2p_destination.to_string(),
+        Err(status) => return status,
+    };
+    let hash = runtime().block_on(interface.i2p.register_destination(i2p_destination));
+    copy_out(&hash, out)
+}
+
+/// Send a packet of `packet_type` with the `length` bytes at `data` to the
+/// peer of `destination`, a registered hash
+///
+/// A `final_destination` (or NULL) is the destination the packet is for
+/// when the peer is only the next hop.
+///
+/// # Safety
+///
+/// `interface` must be a live interface, `destination` must point to 32
+/// bytes, `final_destination` to 32 bytes or be NULL, and `data` to
+/// `length` bytes (or be NULL if `length` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_send(
+    interface: *const RnsInterface,
+    destination: *const u8,
+    final_destination: *const u8,
+    packet_type: u8,
+    data: *const u8,
+    length: usize,
+) -> c_int {
+    let Some(interface) = interface.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "interface is NULL");
+    };
+    let Some(destination) = hash(destination) else {
+        return fail(RNS_ERR_ARGUMENT, "destination is NULL");
+    };
+    let packet_type = match PacketType::from_u8(packet_type) {
+        Ok(packet_type) => packet_type,
+        Err(e) => return fail_with(e),
+    };
+    let data = match bytes(data, length) {
+        Ok(data) => data.to_vec(),
+        Err(status) => return status,
+    };
+
+    let mut packet = Packet::new(packet_type, destination, data);
+    packet.final_destination = hash(final_destination);
+    match runtime().block_on(interface.i2p.send(&packet)) {
+        Ok(()) => RNS_OK,
+        Err(e) => fail_with(e),
+    }
+}
+
+/// Wait up to `timeout_ms` milliseconds (0: for as long as it takes) for a
+/// packet, storing it in `out`; free it with [`rns_packet_free`]
+///
+/// Returns [`RNS_ERR_TIMEOUT`] if none arrived in time. The SAM bridge
+/// carries one thing at a time, so until the next packet arrives a send
+/// waits for the receive, also for one that timed out.
+///
+/// # Safety
+///
+/// `interface` must be a live interface and `out` must point to a packet
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_receive(
+    interface: *const RnsInterface,
+    timeout_ms: u32,
+    out: *mut *mut RnsPacket,
+) -> c_int {
+    let Some(interface) = interface.as_ref() else {
+        return fail(RNS_ERR_ARGUMENT, "interface is NULL");
+    };
+    if out.is_null() {
+        return fail(RNS_ERR_ARGUMENT, "out is NULL");
+    }
+
+    let mut pending = interface.pending.lock().unwrap();
+    let mut task = pending.take().unwrap_or_else(|| {
+        let i2p = Arc::clone(&interface.i2p);
+        runtime().spawn(async move { i2p.receive().await })
+    });
+    let received = runtime().block_on(async {
+        if timeout_ms == 0 {
+            return Some((&mut task).await);
+        }
+        let timeout = Duration::from_millis(timeout_ms.into());
+        tokio::time::timeout(timeout, &mut task).await.ok()
+    });
+
+    match received {
+        None => {
+            *pending = Some(task);
+            fail(RNS_ERR_TIMEOUT, "No packet arrived in time")
+        }
+        Some(Ok(Ok(packet))) => {
+            *out = Box::into_raw(Box::new(RnsPacket::from(packet)));
+            RNS_OK
+        }
+        Some(Ok(Err(e))) => fail_with(e),
+        Some(Err(e)) => fail(RNS_ERR_TRANSPORT, format!("Receive failed: {}", e)),
+    }
+}
+
+/// Close and free `interface` (NULL is ignored)
+///
+/// # Safety
+///
+/// `interface` must be NULL or an interface not freed yet, used by no other
+/// thread.
+#[no_mangle]
+pub unsafe extern "C" fn rns_interface_free(interface: *mut RnsInterface) {
+    if interface.is_null() {
+        return;
+    }
+    let interface = Box::from_raw(interface);
+    if let Some(task) = interface.pending.lock().unwrap().take() {
+        task.abort();
+    }
+    let _ = runtime().block_on(interface.i2p.close());
+}
+
+/// Free `packet` and its payload (NULL is ignored)
+///
+/// # Safety
+///
+/// `packet` must be NULL or a packet from [`rns_interface_receive`] not
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rns_packet_free(packet: *mut RnsPacket) {
+    if packet.is_null() {
+        return;
+    }
+    let packet = Box::from_raw(packet);
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        packet.data,
+        packet.data_length,
+    )));
+}
+
+impl From<Packet> for RnsPacket {
+    fn from(packet: Packet) -> Self {
+        let data = packet.data.to_vec().into_boxed_slice();
+        Self {
+            packet_type: packet.packet_type as u8,
+            source: packet.destination,
+            destination: packet.final_destination.unwrap_or(packet.destination),
+            data_length: data.len(),
+            data: Box::into_raw(data).cast(),
+        }
+    }
+}
+
+/// The 32-byte hash at `hash`, if not NULL
+///
+/// # Safety
+///
+/// `hash` must be NULL or point to 32 bytes.
+unsafe fn hash(hash: *const u8) -> Option<[u8; 32]> {
+    (!hash.is_null()).then(|| std::ptr::read_unaligned(hash.cast()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_types() {
+        for (constant, packet_type) in [
+            (RNS_PACKET_DATA, PacketType::Data),
+            (RNS_PACKET_ANNOUNCE, PacketType::Announce),
+            (RNS_PACKET_LINK_REQUEST, PacketType::LinkRequest),
+            (RNS_PACKET_LINK_RESPONSE, PacketType::LinkResponse),
+            (RNS_PACKET_PROOF, PacketType::Proof),
+            (RNS_PACKET_PATH_REQUEST, PacketType::PathRequest),
+        ] {
+            assert_eq!(PacketType::from_u8(constant).unwrap(), packet_type);
+        }
+    }
+
+    #[test]
+    fn test_packet() {
+        let mut packet = Packet::data([2; 32], b"payload".to_vec());
+        packet.final_destination = Some([1; 32]);
+        let packet = Box::into_raw(Box::new(RnsPacket::from(packet)));
+        unsafe {
+            assert_eq!((*packet).packet_type, RNS_PACKET_DATA);
+            assert_eq!((*packet).source, [2; 32]);
+            assert_eq!((*packet).destination, [1; 32]);
+            let data = std::slice::from_raw_parts((*packet).data, (*packet).data_length);
+            assert_eq!(data, b"payload");
+            rns_packet_free(packet);
+        }
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        let mut packet = std::ptr::null_mut();
+        unsafe {
+            assert!(rns_interface_open_i2p(std::ptr::null()).is_null());
+            assert_eq!(
+                rns_interface_receive(std::ptr::null(), 0, &mut packet),
+                RNS_ERR_ARGUMENT
+            );
+            assert_eq!(
+                rns_interface_send(
+                    std::ptr::null(),
+                    [0; 32].as_ptr(),
+                    std::ptr::null(),
+                    RNS_PACKET_DATA,
+                    std::ptr::null(),
+                    0
+                ),
+                RNS_ERR_ARGUMENT
+            );
+            rns_interface_free(std::ptr::null_mut());
+            rns_packet_free(packet);
+        }
+    }
+
+    #[test]
+    fn test_open_unreachable() {
+        let address = CString::new("127.0.0.1:1").unwrap();
+        unsafe {
+            assert!(rns_interface_open_i2p(address.as_ptr()).is_null());
+        }
+        assert!(!crate::rns_last_error().is_null());
+    }
+}
@@ -0,0 +1,128 @@
+//! reticulum-ffi - C ABI for reticulum-core
+//!
+//! A minimal C interface to the transport layer, so programs in other
+//! languages can use the same identities and I2P interfaces as the shell:
+//! create, load and save identities, open an I2P interface through a SAM
+//! bridge, and send and receive packets on it. `include/reticulum.h`
+//! declares it; after changing the functions here, generate it again with
+//!
+//! ```text
+//! cbindgen --config crates/reticulum-ffi/cbindgen.toml \
+//!     --crate reticulum-ffi --output crates/reticulum-ffi/include/reticulum.h
+//! ```
+//!
+//! Identities and interfaces are opaque handles, freed by their own
+//! `_free` function. Fallible functions return [`RNS_OK`] or a negative
+//! status, with [`rns_last_error`] saying more. Calls block; an interface
+//! may be used from several threads at once (one receiving while another
+//! sends, say).
+//!
+//! `examples/smoke.c` exercises all of it.
+
+use reticulum_core::NetworkError;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+
+pub mod identity;
+pub mod interface;
+
+pub use identity::*;
+pub use interface::*;
+
+/// The call succeeded
+pub const RNS_OK: c_int = 0;
+/// An argument was NULL or not valid
+pub const RNS_ERR_ARGUMENT: c_int = -1;
+/// An identity could not be created, loaded or saved
+pub const RNS_ERR_IDENTITY: c_int = -2;
+/// A packet could not be encoded or decoded
+pub const RNS_ERR_PACKET: c_int = -3;
+/// The transport failed: the SAM bridge, the connection or the I2P network
+pub const RNS_ERR_TRANSPORT: c_int = -4;
+/// Nothing arrived in time
+pub const RNS_ERR_TIMEOUT: c_int = -5;
+
+thread_local! {
+    /// Why the last call on this thread failed
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Why the last failed call on this thread failed, or NULL
+///
+/// The string stays valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn rns_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Record `message` for [`rns_last_error`] and return `status`
+fn fail(status: c_int, message: impl Into<String>) -> c_int {
+    let message = message.into().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+    status
+}
+
+/// Record `err` for [`rns_last_error`] and return its status
+fn fail_with(err: NetworkError) -> c_int {
+    let status = match err {
+        NetworkError::Identity(_) | NetworkError::Crypto(_) => RNS_ERR_IDENTITY,
+        NetworkError::Packet(_) | NetworkError::Decode(_) | NetworkError::Serialization(_) => {
+            RNS_ERR_PACKET
+        }
+        NetworkError::Timeout => RNS_ERR_TIMEOUT,
+        NetworkError::InvalidDestination(_) => RNS_ERR_ARGUMENT,
+        NetworkError::I2p(_) | NetworkError::Io(_) | NetworkError::Connection(_) => {
+            RNS_ERR_TRANSPORT
+        }
+    };
+    fail(status, err.to_string())
+}
+
+/// The runtime the blocking calls run on, shared by all interfaces
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("Failed to start the tokio runtime"))
+}
+
+/// Read the C string `s`
+///
+/// # Safety
+///
+/// `s` must be NULL or a NUL-terminated string.
+unsafe fn string<'a>(s: *const c_char, what: &str) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(fail(RNS_ERR_ARGUMENT, format!("{} is NULL", what)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| fail(RNS_ERR_ARGUMENT, format!("{} is not UTF-8", what)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_error() {
+        assert_eq!(fail(RNS_ERR_PACKET, "bad\0packet"), RNS_ERR_PACKET);
+        let message = unsafe { CStr::from_ptr(rns_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "bad packet");
+
+        assert_eq!(fail_with(NetworkError::Timeout), RNS_ERR_TIMEOUT);
+        assert_eq!(
+            fail_with(NetworkError::Connection("refused".to_string())),
+            RNS_ERR_TRANSPORT
+        );
+
+        // Each thread has its own
+        std::thread::spawn(|| assert!(rns_last_error().is_null()))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Relay configuration
+
+use crate::{RelayError, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Relay configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Interfaces to forward between (`[[interfaces]]`)
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceConfig>,
+
+    /// How long a route is used without a fresh announce (seconds)
+    #[serde(default = "default_route_lifetime")]
+    pub route_lifetime: u64,
+}
+
+/// An interface the relay is attached to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InterfaceConfig {
+    /// Accept TCP peers on an address
+    Tcp { bind: SocketAddr },
+
+    /// Connect to a TCP peer, such as another relay or a server
+    TcpPeer { address: SocketAddr },
+
+    /// External I2P router through its SAM bridge
+    I2p {
+        #[serde(default = "default_sam_address")]
+        sam_address: String,
+
+        /// I2P destinations of the peers to forward to before they are
+        /// heard from
+        #[serde(default)]
+        peers: Vec<String>,
+    },
+}
+
+fn default_route_lifetime() -> u64 {
+    30 * 60
+}
+
+fn default_sam_address() -> String {
+    "127.0.0.1:7656".to_string()
+}
+
+impl RelayConfig {
+    /// Load configuration from TOML file
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| RelayError::Config(format!("Failed to parse config: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: RelayConfig = toml::from_str(
+            r#"
+            [[interfaces]]
+            type = "tcp"
+            bind = "0.0.0.0:4242"
+
+            [[interfaces]]
+            type = "tcp_peer"
+            address = "10.0.0.2:4242"
+
+            [[interfaces]]
+            type = "i2p"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.route_lifetime, 30 * 60);
+        assert_eq!(
+            config.interfaces,
+            vec![
+                InterfaceConfig::Tcp {
+                    bind: "0.0.0.0:4242".parse().unwrap()
+                },
+                InterfaceConfig::TcpPeer {
+                    address: "10.0.0.2:4242".parse().unwrap()
+                },
+                InterfaceConfig::I2p {
+                    sam_address: "127.0.0.1:7656".to_string(),
+                    peers: vec![],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Relay error types
+
+use thiserror::Error;
+
+/// Relay-related errors
+#[derive(Error, Debug)]
+pub enum RelayError {
+    /// Configuration error
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    /// Network error
+    #[error("Network error: {0}")]
+    Network(#[from] reticulum_core::NetworkError),
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Result type for relay operations
+pub type Result<T> = std::result::Result<T, RelayError>;
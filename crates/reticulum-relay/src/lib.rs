@@ -0,0 +1,14 @@
+//! Reticulum Relay - Packet forwarding between interfaces
+//!
+//! A transport node: it forwards packets between the interfaces it is
+//! attached to (I2P, TCP) along the routes announces teach it, without
+//! running a shell server, so nodes that cannot reach each other directly
+//! can talk through one or more relays.
+
+pub mod config;
+pub mod error;
+pub mod relay;
+
+pub use config::{InterfaceConfig, RelayConfig};
+pub use error::{RelayError, Result};
+pub use relay::Relay;
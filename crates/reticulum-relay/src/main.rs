@@ -0,0 +1,133 @@
+//! Reticulum Relay - Transport node forwarding packets between interfaces
+//!
+//! Attaches to the interfaces in its configuration (and on the command line)
+//! and forwards packets between them until interrupted.
+
+use clap::Parser;
+use reticulum_core::{I2pInterface, InterfaceManager, NetworkInterface, TcpInterface};
+use reticulum_relay::{InterfaceConfig, Relay, RelayConfig, RelayError, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path to configuration file (optional)
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Verbose logging
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Accept TCP peers on this address (host:port); may be repeated
+    #[arg(long)]
+    tcp_bind: Vec<SocketAddr>,
+
+    /// Connect to a TCP peer at this address (host:port); may be repeated
+    #[arg(long)]
+    tcp_peer: Vec<SocketAddr>,
+
+    /// Attach to I2P through the SAM bridge at this address
+    #[arg(long)]
+    sam_address: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let log_level = if args.verbose {
+        tracing::Level::DEBUG
+    } else {
+        tracing::Level::INFO
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_target(false)
+        .init();
+
+    let mut config = match &args.config {
+        Some(path) => {
+            info!("Loading configuration from {:?}", path);
+            RelayConfig::load_from_file(path)?
+        }
+        None => RelayConfig::default(),
+    };
+    config.interfaces.extend(
+        args.tcp_bind
+            .into_iter()
+            .map(|bind| InterfaceConfig::Tcp { bind }),
+    );
+    config.interfaces.extend(
+        args.tcp_peer
+            .into_iter()
+            .map(|address| InterfaceConfig::TcpPeer { address }),
+    );
+    if let Some(sam_address) = args.sam_address {
+        config.interfaces.push(InterfaceConfig::I2p {
+            sam_address,
+            peers: vec![],
+        });
+    }
+    if config.interfaces.is_empty() {
+        return Err(RelayError::Config(
+            "No interface configured: use [[interfaces]], --tcp-bind, --tcp-peer or --sam-address"
+                .to_string(),
+        ));
+    }
+
+    let mut interfaces = InterfaceManager::new();
+    for interface in &config.interfaces {
+        interfaces.add(open(interface).await?);
+    }
+
+    let relay = Arc::new(Relay::new(
+        interfaces,
+        Duration::from_secs(config.route_lifetime),
+    ));
+    tokio::select! {
+        () = Arc::clone(&relay).run() => {}
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("Error waiting for Ctrl+C: {}", e);
+            }
+            info!("Relay shutting down...");
+        }
+    }
+    relay.interfaces().close_all().await;
+    Ok(())
+}
+
+/// Open the interface described by `config`
+async fn open(config: &InterfaceConfig) -> Result<Arc<dyn NetworkInterface>> {
+    let interface: Arc<dyn NetworkInterface> = match config {
+        InterfaceConfig::Tcp { bind } => {
+            Arc::new(TcpInterface::bind(*bind).await.map_err(|e| {
+                error!("Failed to listen on TCP {}: {}", bind, e);
+                e
+            })?)
+        }
+        InterfaceConfig::TcpPeer { address } => {
+            Arc::new(TcpInterface::connect(*address).await.map_err(|e| {
+                error!("Failed to connect to {}: {}", address, e);
+                e
+            })?)
+        }
+        InterfaceConfig::I2p { sam_address, peers } => {
+            let interface = I2pInterface::new(sam_address).await.map_err(|e| {
+                error!("Failed to attach to I2P through {}: {}", sam_address, e);
+                e
+            })?;
+            info!("I2P destination: {}", interface.local_destination());
+            for peer in peers {
+                interface.register_destination(peer.clone()).await;
+            }
+            Arc::new(interface)
+        }
+    };
+    Ok(interface)
+}
@@ -0,0 +1,300 @@
+//! Packet forwarding
+//!
+//! Announces are verified, learned into the routing table and, if new,
+//! flooded to every interface. Path requests for a destination with a route
+//! are answered with the announce it was learned from, others are flooded
+//! (at most once per [`PATH_REQUEST_HOLDOFF`]). Every other packet goes to
+//! the next hop towards its final destination, which stays on the packet for
+//! the relays further on.
+//!
+//! Replies are addressed to the relay (the sender's return address), not to
+//! a destination it has a route to. The relay sends whatever comes from a
+//! next hop without a route back to the peer that last sent through that
+//! hop, so peers reaching the same next hop at the same time have their
+//! replies mixed up; give each its own next hop (or relay) if they must.
+
+use reticulum_core::{
+    announce, Announce, DestinationHash, InterfaceManager, NetworkInterface, Packet, PacketType,
+    RoutingTable,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// How long a flooded path request is not flooded again
+pub const PATH_REQUEST_HOLDOFF: Duration = Duration::from_secs(10);
+
+/// Pause before receiving again after an interface failed
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often expired routes are dropped
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An interface (by name) and a peer on it
+type Hop = (String, DestinationHash);
+
+/// Forwards packets between interfaces
+pub struct Relay {
+    interfaces: InterfaceManager,
+    routes: Mutex<RoutingTable>,
+
+    /// Peer that last sent through a next hop, where its replies go
+    returns: Mutex<HashMap<Hop, Hop>>,
+
+    /// When path requests for a destination were last flooded
+    path_requests: Mutex<HashMap<DestinationHash, Instant>>,
+}
+
+impl Relay {
+    /// Create a relay between `interfaces` whose routes last `route_lifetime`
+    pub fn new(interfaces: InterfaceManager, route_lifetime: Duration) -> Self {
+        Self {
+            interfaces,
+            routes: Mutex::new(RoutingTable::new(route_lifetime)),
+            returns: Mutex::new(HashMap::new()),
+            path_requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the interfaces the relay forwards between
+    pub fn interfaces(&self) -> &InterfaceManager {
+        &self.interfaces
+    }
+
+    /// Forward packets until the task is dropped
+    pub async fn run(self: Arc<Self>) {
+        let mut loops = JoinSet::new();
+        for interface in self.interfaces.interfaces() {
+            info!("Relaying on interface: {}", interface.name());
+            let relay = Arc::clone(&self);
+            let interface = Arc::clone(interface);
+            loops.spawn(async move { relay.receive_loop(interface).await });
+        }
+
+        loop {
+            sleep(EXPIRE_INTERVAL).await;
+            self.routes.lock().unwrap().expire();
+            self.path_requests
+                .lock()
+                .unwrap()
+                .retain(|_, flooded| flooded.elapsed() < PATH_REQUEST_HOLDOFF);
+        }
+    }
+
+    async fn receive_loop(&self, interface: Arc<dyn NetworkInterface>) {
+        loop {
+            match interface.receive().await {
+                Ok(packet) => self.handle(&*interface, packet).await,
+                Err(e) => {
+                    warn!(interface = %interface.name(), "Error receiving packet: {}", e);
+                    sleep(RETRY_DELAY).await;
+                    if let Err(e) = interface.reopen().await {
+                        debug!(interface = %interface.name(), "Reopen failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forward a packet received on `interface`
+    pub async fn handle(&self, interface: &dyn NetworkInterface, mut packet: Packet) {
+        let from: Hop = (interface.name().to_string(), packet.destination);
+
+        match packet.packet_type {
+            PacketType::Announce => {
+                let announce = match Announce::from_packet(&packet) {
+                    Ok(announce) => announce,
+                    Err(e) => {
+                        debug!("Dropping announce: {}", e);
+                        return;
+                    }
+                };
+                if !self
+                    .routes
+                    .lock()
+                    .unwrap()
+                    .learn(&announce, &from.0, from.1)
+                {
+                    return;
+                }
+                debug!(
+                    destination = %hex::encode(announce.destination()),
+                    interface = %from.0,
+                    "Learned route"
+                );
+                self.flood(&announce.to_packet()).await;
+            }
+            PacketType::PathRequest => {
+                let Some(requested) = announce::requested_destination(&packet) else {
+                    return;
+                };
+                let route = self.routes.lock().unwrap().lookup(&requested).cloned();
+                if let Some(route) = route {
+                    let mut response = route.announce.to_packet();
+                    response.destination = from.1;
+                    self.send(&from.0, &response).await;
+                    return;
+                }
+
+                let flood = {
+                    let mut path_requests = self.path_requests.lock().unwrap();
+                    let recent = path_requests
+                        .get(&requested)
+                        .is_some_and(|flooded| flooded.elapsed() < PATH_REQUEST_HOLDOFF);
+                    if !recent {
+                        path_requests.insert(requested, Instant::now());
+                    }
+                    !recent
+                };
+                if flood {
+                    self.flood(&announce::path_request(requested)).await;
+                }
+            }
+            _ => {
+                let route = packet.final_destination.and_then(|destination| {
+                    self.routes.lock().unwrap().lookup(&destination).cloned()
+                });
+                let to = match route {
+                    Some(route) if (&route.interface, route.next_hop) != (&from.0, from.1) => {
+                        let to = (route.interface, route.next_hop);
+                        self.returns.lock().unwrap().insert(to.clone(), from);
+                        to
+                    }
+                    _ => {
+                        let back = self.returns.lock().unwrap().get(&from).cloned();
+                        let Some(back) = back else {
+                            debug!(interface = %from.0, "No route for packet, dropping it");
+                            return;
+                        };
+                        packet.final_destination = None;
+                        back
+                    }
+                };
+                packet.destination = to.1;
+                self.send(&to.0, &packet).await;
+            }
+        }
+    }
+
+    /// Send `packet` on every interface, back to where it came from
+    /// included, since other peers may share that interface
+    async fn flood(&self, packet: &Packet) {
+        for interface in self.interfaces.interfaces() {
+            if let Err(e) = interface.broadcast(packet).await {
+                debug!(interface = %interface.name(), "Broadcast failed: {}", e);
+            }
+        }
+    }
+
+    /// Send `packet` on the interface named `name`
+    async fn send(&self, name: &str, packet: &Packet) {
+        let Some(interface) = self.interfaces.get(name) else {
+            return;
+        };
+        if let Err(e) = interface.send(packet).await {
+            debug!(interface = %name, "Forwarding failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reticulum_core::{Identity, TcpInterface};
+
+    /// A relay between a client and a server side, and the interfaces of
+    /// the peers on either side
+    fn relay() -> (Arc<Relay>, TcpInterface, TcpInterface) {
+        let (client, client_side) = tokio::io::duplex(64 * 1024);
+        let (server, server_side) = tokio::io::duplex(64 * 1024);
+        let interfaces = InterfaceManager::new()
+            .with(Arc::new(TcpInterface::over(client_side, "client")))
+            .with(Arc::new(TcpInterface::over(server_side, "server")));
+        let relay = Arc::new(Relay::new(interfaces, Duration::from_secs(60)));
+        tokio::spawn(Arc::clone(&relay).run());
+        (
+            relay,
+            TcpInterface::over(client, "relay"),
+            TcpInterface::over(server, "relay"),
+        )
+    }
+
+    /// Receive the next packet of `packet_type`, skipping flooded ones
+    async fn next(interface: &TcpInterface, packet_type: PacketType) -> Packet {
+        loop {
+            let packet = interface.receive().await.unwrap();
+            if packet.packet_type == packet_type {
+                return packet;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let (_relay, client, server) = relay();
+        let identity = Identity::generate();
+        let destination = identity.destination_hash();
+
+        // The server's announce teaches the relay the way to it
+        server
+            .send(&Announce::new(&identity, b"shell".to_vec()).to_packet())
+            .await
+            .unwrap();
+        let announce = next(&client, PacketType::Announce).await;
+        assert_eq!(
+            Announce::from_packet(&announce).unwrap().destination(),
+            destination
+        );
+
+        client
+            .send(&Packet::data(destination, b"hello".to_vec()))
+            .await
+            .unwrap();
+        let request = next(&server, PacketType::Data).await;
+        assert_eq!(request.data.as_ref(), b"hello");
+        assert_eq!(request.final_destination, Some(destination));
+
+        // The reply goes back to the client
+        server
+            .send(&Packet::data(request.destination, b"world".to_vec()))
+            .await
+            .unwrap();
+        let reply = next(&client, PacketType::Data).await;
+        assert_eq!(reply.data.as_ref(), b"world");
+    }
+
+    #[tokio::test]
+    async fn test_path_request() {
+        let (_relay, client, server) = relay();
+        let identity = Identity::generate();
+        let destination = identity.destination_hash();
+
+        // Unknown destinations are asked for on every interface
+        client
+            .send(&announce::path_request(destination))
+            .await
+            .unwrap();
+        let request = next(&server, PacketType::PathRequest).await;
+        assert_eq!(announce::requested_destination(&request), Some(destination));
+
+        server
+            .send(&Announce::new(&identity, vec![]).to_packet())
+            .await
+            .unwrap();
+        next(&client, PacketType::Announce).await;
+
+        // Known ones are answered by the relay
+        client
+            .send(&announce::path_request(destination))
+            .await
+            .unwrap();
+        let announce = next(&client, PacketType::Announce).await;
+        assert_eq!(
+            Announce::from_packet(&announce).unwrap().destination(),
+            destination
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! The agent side of the socket
+
+use reticulum_core::{Identity, Result, Signer};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Identities held for other processes
+#[derive(Default)]
+pub struct Agent {
+    identities: Mutex<Vec<Identity>>,
+}
+
+impl Agent {
+    /// An agent holding no identity yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold `identity`, unless it is held already; returns its public key
+    pub fn add(&self, identity: Identity) -> Vec<u8> {
+        let public_key = identity.public_key();
+        let mut identities = self.identities.lock().unwrap();
+        if !identities
+            .iter()
+            .any(|held| held.public_key() == public_key)
+        {
+            identities.push(identity);
+        }
+        public_key
+    }
+
+    /// Public keys of the identities held, in the order they were added
+    pub fn public_keys(&self) -> Vec<Vec<u8>> {
+        let identities = self.identities.lock().unwrap();
+        identities.iter().map(Identity::public_key).collect()
+    }
+
+    /// Serve the socket at `path` until the returned task is aborted
+    #[cfg(unix)]
+    pub async fn serve(self: Arc<Self>, path: &Path) -> Result<JoinHandle<()>> {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::net::UnixListener;
+
+        // A socket left behind by an unclean exit
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!(path = %path.display(), "Serving key agent socket");
+
+        Ok(tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                // Whatever the socket's mode, only our own user is served
+                if !same_user(&stream) {
+                    warn!("Refusing agent connection from another user");
+                    continue;
+                }
+
+                let agent = Arc::clone(&self);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &agent).await {
+                        debug!(error = %e, "Agent connection failed");
+                    }
+                });
+            }
+        }))
+    }
+
+    /// The key agent is Unix-only
+    #[cfg(not(unix))]
+    pub async fn serve(self: Arc<Self>, _path: &Path) -> Result<JoinHandle<()>> {
+        Err(reticulum_core::NetworkError::Connection(
+            "The key agent is only supported on Unix".to_string(),
+        ))
+    }
+
+    /// Answer one command line
+    pub fn handle(&self, line: &str) -> String {
+        match self.answer(line) {
+            Ok(results) if results.is_empty() => "ok\n".to_string(),
+            Ok(results) => format!("ok {}\n", results.join(" ")),
+            Err(e) => format!("error: {}\n", e),
+        }
+    }
+
+    fn answer(&self, line: &str) -> std::result::Result<Vec<String>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["list"] => Ok(self.public_keys().iter().map(hex::encode).collect()),
+            ["sign", public_key, data] => {
+                let data = decode(data)?;
+                self.with_identity(public_key, |identity| Ok(identity.sign(&data)))
+            }
+            ["agree", public_key, other] => {
+                let other: [u8; 32] = decode(other)?
+                    .try_into()
+                    .map_err(|_| "X25519 public keys are 32 bytes".to_string())?;
+                self.with_identity(public_key, |identity| {
+                    Signer::agree(identity, &other).map(|secret| secret.to_vec())
+                })
+            }
+            ["add", private_key] => {
+                let identity =
+                    Identity::from_bytes(&decode(private_key)?).map_err(|e| e.to_string())?;
+                info!(destination = %identity.destination_hex(), "Identity added");
+                Ok(vec![hex::encode(self.add(identity))])
+            }
+            ["remove", public_key] => {
+                let public_key = decode(public_key)?;
+                let mut identities = self.identities.lock().unwrap();
+                let count = identities.len();
+                identities.retain(|identity| identity.public_key() != public_key);
+                if identities.len() == count {
+                    return Err("no such identity".to_string());
+                }
+                Ok(vec![])
+            }
+            ["remove-all"] => {
+                self.identities.lock().unwrap().clear();
+                Ok(vec![])
+            }
+            _ => Err(format!("unknown command {:?}", line.trim())),
+        }
+    }
+
+    /// Run `operation` with the identity of `public_key` (hex), answering
+    /// its result in hex
+    fn with_identity(
+        &self,
+        public_key: &str,
+        operation: impl FnOnce(&Identity) -> Result<Vec<u8>>,
+    ) -> std::result::Result<Vec<String>, String> {
+        let public_key = decode(public_key)?;
+        let identities = self.identities.lock().unwrap();
+        let identity = identities
+            .iter()
+            .find(|identity| identity.public_key() == public_key)
+            .ok_or_else(|| "no such identity".to_string())?;
+        let result = operation(identity).map_err(|e| e.to_string())?;
+        Ok(vec![hex::encode(result)])
+    }
+}
+
+fn decode(word: &str) -> std::result::Result<Vec<u8>, String> {
+    hex::decode(word).map_err(|_| format!("not hex: {:?}", word))
+}
+
+#[cfg(unix)]
+async fn handle_connection(stream: tokio::net::UnixStream, agent: &Agent) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = agent.handle(&line);
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Whether the process at the other end of `stream` runs as our user
+#[cfg(unix)]
+fn same_user(stream: &tokio::net::UnixStream) -> bool {
+    let uid = unsafe { libc::getuid() };
+    stream.peer_cred().is_ok_and(|cred| cred.uid() == uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let agent = Agent::new();
+        let identity = Identity::generate();
+        let public_key = hex::encode(identity.public_key());
+        assert_eq!(agent.handle("list"), "ok\n");
+
+        let added = agent.handle(&format!("add {}", hex::encode(identity.private_key())));
+        assert_eq!(added, format!("ok {}\n", public_key));
+        agent.add(identity.clone());
+        assert_eq!(agent.handle("list"), format!("ok {}\n", public_key));
+
+        let signed = agent.handle(&format!("sign {} {}", public_key, hex::encode(b"data")));
+        let signature = hex::decode(signed.trim().strip_prefix("ok ").unwrap()).unwrap();
+        assert!(identity.verify(b"data", &signature).is_ok());
+
+        let other = Identity::generate();
+        let agreed = agent.handle(&format!(
+            "agree {} {}",
+            public_key,
+            hex::encode(Identity::x25519_public_key(&other.public_key()).unwrap())
+        ));
+        let expected = Signer::agree(
+            &other,
+            &Identity::x25519_public_key(&identity.public_key()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(agreed, format!("ok {}\n", hex::encode(expected)));
+
+        assert_eq!(agent.handle(&format!("remove {}", public_key)), "ok\n");
+        for line in [
+            format!("sign {} 00", public_key),
+            format!("remove {}", public_key),
+            "sign zz 00".to_string(),
+            "unlock".to_string(),
+        ] {
+            assert!(agent.handle(&line).starts_with("error: "), "{}", line);
+        }
+    }
+}
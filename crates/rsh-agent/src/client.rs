@@ -0,0 +1,248 @@
+//! The client side of the socket
+//!
+//! Requests are blocking: the [`Signer`] they back is called from
+//! synchronous code, and the agent answers from memory at once.
+
+use crate::SOCKET_ENV;
+use reticulum_core::{Identity, NetworkError, Result, Signer};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long an answer from the agent may take
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to a key agent
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    path: PathBuf,
+}
+
+impl AgentClient {
+    /// The agent listening on `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The agent named by `RSH_AUTH_SOCK`
+    pub fn from_env() -> Result<Self> {
+        std::env::var_os(SOCKET_ENV)
+            .map(Self::new)
+            .ok_or_else(|| NetworkError::Connection(format!("{} is not set", SOCKET_ENV)))
+    }
+
+    /// Path of the agent's socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Public keys of the identities the agent holds
+    pub fn list(&self) -> Result<Vec<Vec<u8>>> {
+        self.request("list")?
+            .iter()
+            .map(|word| decode(word))
+            .collect()
+    }
+
+    /// Have the agent hold `identity`
+    pub fn add(&self, identity: &Identity) -> Result<()> {
+        self.request(&format!("add {}", hex::encode(identity.private_key())))?;
+        Ok(())
+    }
+
+    /// Have the agent forget the identity of `public_key`
+    pub fn remove(&self, public_key: &[u8]) -> Result<()> {
+        self.request(&format!("remove {}", hex::encode(public_key)))?;
+        Ok(())
+    }
+
+    /// Have the agent forget all identities
+    pub fn remove_all(&self) -> Result<()> {
+        self.request("remove-all")?;
+        Ok(())
+    }
+
+    /// Sign `data` with the identity of `public_key`
+    pub fn sign(&self, public_key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let line = format!("sign {} {}", hex::encode(public_key), hex::encode(data));
+        self.single(&line)
+    }
+
+    /// X25519 agreement of the identity of `public_key` with `other`
+    pub fn agree(&self, public_key: &[u8], other: &[u8; 32]) -> Result<[u8; 32]> {
+        let line = format!("agree {} {}", hex::encode(public_key), hex::encode(other));
+        self.single(&line)?
+            .try_into()
+            .map_err(|_| unexpected("a shared secret of another length"))
+    }
+
+    /// Send a command expecting one result
+    fn single(&self, line: &str) -> Result<Vec<u8>> {
+        match self.request(line)?.as_slice() {
+            [word] => decode(word),
+            _ => Err(unexpected("not one result")),
+        }
+    }
+
+    /// Send one command line; returns the words of an `ok` answer
+    #[cfg(unix)]
+    fn request(&self, line: &str) -> Result<Vec<String>> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = UnixStream::connect(&self.path).map_err(|e| {
+            NetworkError::Connection(format!("No key agent at {}: {}", self.path.display(), e))
+        })?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.write_all(format!("{}\n", line).as_bytes())?;
+
+        let mut answer = String::new();
+        BufReader::new(stream).read_line(&mut answer)?;
+        if let Some(reason) = answer.trim_end().strip_prefix("error: ") {
+            return Err(NetworkError::Identity(format!("Key agent: {}", reason)));
+        }
+        let mut words = answer.split_whitespace();
+        if words.next() != Some("ok") {
+            return Err(unexpected("no answer"));
+        }
+        Ok(words.map(str::to_string).collect())
+    }
+
+    /// The key agent is Unix-only
+    #[cfg(not(unix))]
+    fn request(&self, _line: &str) -> Result<Vec<String>> {
+        Err(NetworkError::Connection(
+            "The key agent is only supported on Unix".to_string(),
+        ))
+    }
+}
+
+/// An identity held by a key agent
+#[derive(Debug, Clone)]
+pub struct AgentSigner {
+    client: AgentClient,
+    public_key: Vec<u8>,
+}
+
+impl AgentSigner {
+    /// The identity of `public_key` held by `client`, or the only one it
+    /// holds if `public_key` is `None`
+    pub fn new(client: AgentClient, public_key: Option<&[u8]>) -> Result<Self> {
+        let held = client.list()?;
+        let public_key = match (public_key, held.as_slice()) {
+            (Some(public_key), _) if held.iter().any(|key| key == public_key) => {
+                public_key.to_vec()
+            }
+            (Some(public_key), _) => {
+                return Err(NetworkError::Identity(format!(
+                    "The key agent at {} does not hold the identity {}",
+                    client.path.display(),
+                    hex::encode(public_key)
+                )))
+            }
+            (None, [only]) => only.clone(),
+            (None, []) => {
+                return Err(NetworkError::Identity(format!(
+                    "The key agent at {} holds no identity (add one with rsh-agent add)",
+                    client.path.display()
+                )))
+            }
+            (None, _) => {
+                return Err(NetworkError::Identity(format!(
+                    "The key agent at {} holds {} identities; choose one with identity_agent_key",
+                    client.path.display(),
+                    held.len()
+                )))
+            }
+        };
+        Ok(Self { client, public_key })
+    }
+
+    /// The identity of `public_key` (hex) held by the agent at `socket`, as
+    /// in [`new`](Self::new)
+    pub fn connect(socket: &Path, public_key: Option<&str>) -> Result<Self> {
+        let public_key = public_key
+            .map(|key| {
+                hex::decode(key)
+                    .map_err(|_| NetworkError::Identity(format!("Not a public key: {}", key)))
+            })
+            .transpose()?;
+        Self::new(AgentClient::new(socket), public_key.as_deref())
+    }
+}
+
+impl Signer for AgentSigner {
+    fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.client.sign(&self.public_key, data)
+    }
+
+    fn agree(&self, public_key: &[u8; 32]) -> Result<[u8; 32]> {
+        self.client.agree(&self.public_key, public_key)
+    }
+}
+
+fn decode(word: &str) -> Result<Vec<u8>> {
+    hex::decode(word).map_err(|_| unexpected("not hex"))
+}
+
+fn unexpected(what: &str) -> NetworkError {
+    NetworkError::Connection(format!("Unexpected answer from the key agent: {}", what))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::Agent;
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_agent_signer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let agent = Arc::new(Agent::new());
+        let task = Arc::clone(&agent).serve(&path).await.unwrap();
+
+        let client = AgentClient::new(&path);
+        assert!(AgentSigner::new(client.clone(), None).is_err());
+
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        tokio::task::spawn_blocking({
+            let client = client.clone();
+            let (identity, other) = (identity.clone(), other.clone());
+            move || {
+                client.add(&identity).unwrap();
+                let signer = AgentSigner::new(client.clone(), None).unwrap();
+                assert_eq!(signer.destination_hex(), identity.destination_hex());
+
+                let signature = signer.sign(b"data").unwrap();
+                assert!(identity.verify(b"data", &signature).is_ok());
+                let theirs = Identity::x25519_public_key(&other.public_key()).unwrap();
+                assert_eq!(
+                    signer.agree(&theirs).unwrap(),
+                    Signer::agree(&identity, &theirs).unwrap()
+                );
+
+                // With two identities one must be named
+                client.add(&other).unwrap();
+                assert!(AgentSigner::new(client.clone(), None).is_err());
+                let chosen = AgentSigner::new(client.clone(), Some(&other.public_key())).unwrap();
+                assert_eq!(chosen.public_key(), other.public_key());
+
+                client.remove_all().unwrap();
+                assert!(signer.sign(b"data").is_err());
+            }
+        })
+        .await
+        .unwrap();
+
+        task.abort();
+        assert!(AgentClient::new(dir.path().join("none.sock"))
+            .list()
+            .is_err());
+    }
+}
@@ -0,0 +1,31 @@
+//! rsh-agent - Key agent
+//!
+//! Holds decrypted identities in memory and does their private key
+//! operations for other processes over a Unix socket (mode 0600), so the
+//! client and the server can use an identity whose file stays encrypted
+//! without asking for its passphrase each time, and never hold the key
+//! themselves. Connections from processes of other users are refused.
+//!
+//! The socket answers one command per line with one line, `ok` and the
+//! results, or `error: <reason>`; keys and data are in hex:
+//!
+//! - `list`: the public keys held (`ok <public-key>...`)
+//! - `sign <public-key> <data>`: an Ed25519 signature (`ok <signature>`)
+//! - `agree <public-key> <x25519-public-key>`: the X25519 shared secret of
+//!   the identity's key with another (`ok <secret>`), for session encryption
+//! - `add <private-key>`: hold another identity (`ok <public-key>`)
+//! - `remove <public-key>` / `remove-all`: forget identities (`ok`)
+//!
+//! [`passphrase`] gets the passphrases of encrypted identity files for the
+//! command-line programs, which load identities with
+//! [`passphrase::load_identity`].
+
+pub mod agent;
+pub mod client;
+pub mod passphrase;
+
+pub use agent::Agent;
+pub use client::{AgentClient, AgentSigner};
+
+/// Environment variable naming the agent's socket
+pub const SOCKET_ENV: &str = "RSH_AUTH_SOCK";
@@ -0,0 +1,195 @@
+//! rsh-agent - Key agent
+//!
+//! `rsh-agent start` loads identities, asking for their passphrases once,
+//! and serves them on a Unix socket until interrupted; `add`, `list` and
+//! `remove` manage a running agent.
+
+use clap::{Parser, Subcommand};
+use reticulum_core::{Identity, NetworkError, Result};
+use rsh_agent::{passphrase, Agent, AgentClient, SOCKET_ENV};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::{error, info};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Agent socket (default: $RSH_AUTH_SOCK)
+    #[arg(short, long, global = true)]
+    socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run an agent holding these identities
+    Start {
+        /// Identity files to load
+        identities: Vec<PathBuf>,
+
+        /// Verbose logging
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Have a running agent hold more identities
+    Add {
+        /// Identity files to load
+        #[arg(required = true)]
+        identities: Vec<PathBuf>,
+    },
+
+    /// List the identities a running agent holds
+    List,
+
+    /// Have a running agent forget an identity
+    Remove {
+        /// Public key (hex) of the identity, as `list` shows it
+        #[arg(required_unless_present = "all")]
+        public_key: Option<String>,
+
+        /// Forget all identities
+        #[arg(long, conflicts_with = "public_key")]
+        all: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match args.command {
+        Command::Start {
+            identities,
+            verbose,
+        } => start(args.socket, &identities, verbose),
+        command => manage(args.socket, command),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rsh-agent: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Load `identities` and serve them until interrupted
+fn start(socket: Option<PathBuf>, identities: &[PathBuf], verbose: bool) -> Result<()> {
+    let log_level = if verbose {
+        tracing::Level::DEBUG
+    } else {
+        tracing::Level::INFO
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let agent = Arc::new(Agent::new());
+    for path in identities {
+        let identity = passphrase::load_identity(path)?;
+        info!(destination = %identity.destination_hex(), "Loaded {}", path.display());
+        agent.add(identity);
+    }
+
+    // By default in a directory no other user can enter, so nobody else can
+    // reach the socket in the moment before its mode is set
+    let socket = socket.or_else(|| std::env::var_os(SOCKET_ENV).map(PathBuf::from));
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty());
+    let (socket, created) = match (socket, runtime_dir) {
+        (Some(socket), _) => (socket, None),
+        (None, Some(dir)) => {
+            let name = format!("rsh-agent-{}.sock", std::process::id());
+            (PathBuf::from(dir).join(name), None)
+        }
+        (None, None) => {
+            let dir = private_dir()?;
+            (dir.join("agent.sock"), Some(dir))
+        }
+    };
+    println!(
+        "{}={}; export {};",
+        SOCKET_ENV,
+        socket.display(),
+        SOCKET_ENV
+    );
+
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let task = Arc::clone(&agent).serve(&socket).await?;
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Error waiting for Ctrl+C: {}", e);
+        }
+        info!("Agent shutting down...");
+        task.abort();
+        std::fs::remove_file(&socket)?;
+        if let Some(dir) = created {
+            std::fs::remove_dir(dir)?;
+        }
+        Ok::<_, NetworkError>(())
+    })
+}
+
+/// A new directory under the temporary one that only we can enter (mode
+/// 0700)
+#[cfg(unix)]
+fn private_dir() -> Result<PathBuf> {
+    use std::ffi::{CString, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let template = std::env::temp_dir().join("rsh-agent-XXXXXX");
+    let mut template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|_| NetworkError::Connection("Invalid temporary directory".to_string()))?
+        .into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+/// The key agent is Unix-only; `serve` says so
+#[cfg(not(unix))]
+fn private_dir() -> Result<PathBuf> {
+    Ok(std::env::temp_dir())
+}
+
+/// Run `command` against a running agent
+fn manage(socket: Option<PathBuf>, command: Command) -> Result<()> {
+    let client = match socket {
+        Some(socket) => AgentClient::new(socket),
+        None => AgentClient::from_env()
+            .map_err(|e| NetworkError::Connection(format!("{} (or use --socket)", e)))?,
+    };
+    match command {
+        Command::Start { .. } => unreachable!("handled by start"),
+        Command::Add { identities } => {
+            for path in identities {
+                let identity = passphrase::load_identity(&path)?;
+                client.add(&identity)?;
+                eprintln!("Added {} ({})", path.display(), identity.destination_hex());
+            }
+        }
+        Command::List => {
+            for public_key in client.list()? {
+                println!(
+                    "{} {}",
+                    hex::encode(&public_key),
+                    hex::encode(Identity::hash_from_public_key(&public_key))
+                );
+            }
+        }
+        Command::Remove { public_key, all } => match public_key {
+            Some(public_key) if !all => {
+                let public_key = hex::decode(&public_key).map_err(|_| {
+                    NetworkError::Identity(format!("Not a public key: {}", public_key))
+                })?;
+                client.remove(&public_key)?;
+            }
+            _ => client.remove_all()?,
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Passphrases for encrypted identity files
+//!
+//! The passphrase of an encrypted identity is taken from the first of:
+//!
+//! 1. the `RSH_IDENTITY_PASSPHRASE` environment variable,
+//! 2. the program named by `RSH_ASKPASS`, which gets the prompt as its
+//!    argument and prints the passphrase (its first line counts),
+//! 3. the terminal, read with echo off.
+//!
+//! A process without a terminal, such as a daemon or a service, needs one of
+//! the first two.
+
+use reticulum_core::{identity, Identity, NetworkError, Result};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Environment variable holding the passphrase
+pub const PASSPHRASE_ENV: &str = "RSH_IDENTITY_PASSPHRASE";
+
+/// Environment variable naming a program that prints the passphrase
+pub const ASKPASS_ENV: &str = "RSH_ASKPASS";
+
+/// Passphrase prompts on a terminal before giving up
+const PROMPT_ATTEMPTS: u32 = 3;
+
+/// Load the identity file at `path`, getting the passphrase as described
+/// above if it is encrypted
+///
+/// A passphrase typed on the terminal may be tried again after a typo;
+/// one from the environment or an askpass program is not.
+pub fn load_identity(path: &Path) -> Result<Identity> {
+    let data = std::fs::read(path)?;
+    if !identity::is_encrypted(&data) {
+        return Identity::from_bytes(&data);
+    }
+
+    let prompt = format!("Passphrase for {}: ", path.display());
+    if let Some(passphrase) = given(&prompt)? {
+        return Identity::from_encrypted_bytes(&data, &passphrase);
+    }
+    let mut attempt = 1;
+    loop {
+        let passphrase = self::prompt(&prompt)?;
+        match Identity::from_encrypted_bytes(&data, &passphrase) {
+            Err(NetworkError::Crypto(e)) if attempt < PROMPT_ATTEMPTS => {
+                eprintln!("{}; try again.", e);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The passphrase from the environment or the askpass program, if either
+/// is set up (`prompt` is passed to the program)
+pub fn given(prompt: &str) -> Result<Option<String>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(Some(passphrase));
+    }
+    match std::env::var_os(ASKPASS_ENV) {
+        Some(program) if !program.is_empty() => askpass(&program, prompt).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Run an askpass `program` with `prompt`, returning the first line it
+/// prints
+pub fn askpass(program: &OsStr, prompt: &str) -> Result<String> {
+    let name = program.to_string_lossy();
+    let output = Command::new(program)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| NetworkError::Identity(format!("Failed to run {}: {}", name, e)))?;
+    if !output.status.success() {
+        return Err(NetworkError::Identity(format!(
+            "{} gave no passphrase ({})",
+            name, output.status
+        )));
+    }
+    let output = String::from_utf8(output.stdout).map_err(|_| {
+        NetworkError::Identity(format!("{} printed a passphrase that is not UTF-8", name))
+    })?;
+    Ok(output.lines().next().unwrap_or_default().to_string())
+}
+
+/// A passphrase for a new identity: from `RSH_IDENTITY_PASSPHRASE`, or
+/// typed twice on the terminal
+pub fn choose(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = self::prompt(prompt)?;
+    if passphrase.is_empty() {
+        return Err(NetworkError::Identity("Empty passphrase".to_string()));
+    }
+    if self::prompt("Same passphrase again: ")? != passphrase {
+        return Err(NetworkError::Identity("The passphrases differ".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// Ask for a passphrase on the terminal, without echoing it
+#[cfg(unix)]
+pub fn prompt(prompt: &str) -> Result<String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::io::AsRawFd;
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| no_terminal(&e.to_string()))?;
+    let fd = tty.as_raw_fd();
+
+    // SAFETY: tcgetattr fills the termios it is given
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut hidden = original;
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+
+    tty.write_all(prompt.as_bytes())?;
+    tty.flush()?;
+    // SAFETY: only changes the terminal's settings, restored below
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) };
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    // SAFETY: restores settings read by tcgetattr
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    read?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Reading from the terminal without echo is only supported on Unix
+#[cfg(not(unix))]
+pub fn prompt(_prompt: &str) -> Result<String> {
+    Err(no_terminal("not supported on this platform"))
+}
+
+fn no_terminal(reason: &str) -> NetworkError {
+    NetworkError::Identity(format!(
+        "Cannot ask for the identity passphrase ({}); set {} or {}",
+        reason, PASSPHRASE_ENV, ASKPASS_ENV
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_askpass() {
+        let passphrase = askpass(OsStr::new("echo"), "hunter2").unwrap();
+        assert_eq!(passphrase, "hunter2");
+
+        assert!(askpass(OsStr::new("false"), "Passphrase: ").is_err());
+        assert!(askpass(OsStr::new("/nonexistent/askpass"), "Passphrase: ").is_err());
+    }
+
+    #[test]
+    fn test_load_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+        let identity = Identity::generate();
+        identity.save_to_file(&path).unwrap();
+        let loaded = load_identity(&path).unwrap();
+        assert_eq!(loaded.public_key(), identity.public_key());
+
+        identity.save_encrypted(&path, "s3cret").unwrap();
+        std::env::set_var(PASSPHRASE_ENV, "s3cret");
+        let loaded = load_identity(&path);
+        std::env::remove_var(PASSPHRASE_ENV);
+        assert_eq!(loaded.unwrap().public_key(), identity.public_key());
+    }
+}
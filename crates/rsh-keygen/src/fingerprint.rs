@@ -0,0 +1,93 @@
+//! Fingerprints
+//!
+//! Short forms of a public key for people to compare. All are taken from the
+//! SHA-256 of the key: `hex` is all of it, as the client shows host keys;
+//! `emoji` spells its first 48 bits with [`EMOJI`] (6 bits each) and `words`
+//! its first 66 with the BIP-39 English word list (11 bits each), which are
+//! easier to read out over the phone.
+
+use bip39::Language;
+use sha2::{Digest, Sha256};
+
+/// Emoji of the `emoji` format, one per 6-bit value
+pub const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐎", "🦄", "🐷", "🐘", "🐰", "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌", "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🎩", "👓", "🔧", "🎅", "👍", "☂️", "⌛", "⏰", "🎁", "💡", "📕", "✏️", "📎", "✂️", "🔒", "🔑",
+    "🔨", "☎️", "🏁", "🚂", "🚲", "✈️", "🚀", "🏆", "⚽", "🎸", "🎺", "🔔", "⚓", "🎧", "📁", "📌",
+];
+
+/// Emoji in an `emoji` fingerprint
+const EMOJI_COUNT: usize = 8;
+
+/// Words in a `words` fingerprint
+const WORD_COUNT: usize = 6;
+
+/// How to show a fingerprint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FingerprintFormat {
+    /// `SHA256:` and the hash in hex
+    #[default]
+    Hex,
+
+    /// Eight emoji
+    Emoji,
+
+    /// Six words
+    Words,
+}
+
+/// Fingerprint of `public_key` in `format`
+pub fn fingerprint(public_key: &[u8], format: FingerprintFormat) -> String {
+    let digest = Sha256::digest(public_key);
+    match format {
+        FingerprintFormat::Hex => format!("SHA256:{}", hex::encode(digest)),
+        FingerprintFormat::Emoji => (0..EMOJI_COUNT)
+            .map(|i| EMOJI[bits(&digest, i * 6, 6)])
+            .collect::<Vec<_>>()
+            .join(" "),
+        FingerprintFormat::Words => {
+            let words = Language::English.word_list();
+            (0..WORD_COUNT)
+                .map(|i| words[bits(&digest, i * 11, 11)])
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+/// The `len` bits of `bytes` from bit `start` on, most significant first
+fn bits(bytes: &[u8], start: usize, len: usize) -> usize {
+    (start..start + len).fold(0, |value, bit| {
+        (value << 1) | usize::from((bytes[bit / 8] >> (7 - bit % 8)) & 1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let shown = fingerprint(b"key", FingerprintFormat::Hex);
+        assert_eq!(
+            shown,
+            format!("SHA256:{}", hex::encode(Sha256::digest(b"key")))
+        );
+
+        let emoji = fingerprint(b"key", FingerprintFormat::Emoji);
+        assert_eq!(emoji.split(' ').count(), EMOJI_COUNT);
+        assert!(emoji.split(' ').all(|e| EMOJI.contains(&e)));
+
+        let words = fingerprint(b"key", FingerprintFormat::Words);
+        assert_eq!(words.split(' ').count(), WORD_COUNT);
+        assert_ne!(words, fingerprint(b"other", FingerprintFormat::Words));
+    }
+
+    #[test]
+    fn test_bits() {
+        let bytes = [0b1010_0000, 0b1111_0000];
+        assert_eq!(bits(&bytes, 0, 3), 0b101);
+        assert_eq!(bits(&bytes, 6, 6), 0b00_1111);
+    }
+}
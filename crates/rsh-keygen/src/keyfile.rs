@@ -0,0 +1,190 @@
+//! Key file formats
+//!
+//! An identity can be kept as:
+//! - `plain`: the 32-byte private key, as `--generate-identity` writes it
+//! - `encrypted`: the private key protected with a passphrase (see
+//!   [`reticulum_core::identity`])
+//! - `hex`: the private key in hex, on one line
+//! - `mnemonic`: the 24 BIP-39 English words spelling the private key, for
+//!   backups on paper
+//!
+//! [`read`] recognizes all of them. Files are written readable by their
+//! owner only.
+
+use bip39::Mnemonic;
+use reticulum_core::{Identity, NetworkError, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Format of a key file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyFormat {
+    /// The raw private key
+    Plain,
+
+    /// The private key encrypted with a passphrase
+    Encrypted,
+
+    /// The private key in hex
+    Hex,
+
+    /// The private key as 24 words
+    Mnemonic,
+}
+
+/// The 24 words spelling the private key of `identity`
+pub fn to_mnemonic(identity: &Identity) -> String {
+    Mnemonic::from_entropy(&identity.private_key())
+        .expect("32 bytes of entropy")
+        .to_string()
+}
+
+/// Restore an identity from the words of [`to_mnemonic`]
+pub fn from_mnemonic(words: &str) -> Result<Identity> {
+    let words = words.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mnemonic = Mnemonic::parse_normalized(&words.to_lowercase())
+        .map_err(|e| NetworkError::Identity(format!("Invalid mnemonic: {}", e)))?;
+    let entropy = mnemonic.to_entropy();
+    if entropy.len() != 32 {
+        return Err(NetworkError::Identity(format!(
+            "A mnemonic of {} words is not an identity (24 needed)",
+            mnemonic.word_count()
+        )));
+    }
+    Identity::from_bytes(&entropy)
+}
+
+/// Read the identity in the file at `path`, whatever its format, asking for
+/// the passphrase if it is encrypted
+pub fn read(path: &Path) -> Result<Identity> {
+    if Identity::is_encrypted_file(path)? {
+        return rsh_agent::passphrase::load_identity(path);
+    }
+    let data = fs::read(path)?;
+    if data.len() == 32 {
+        return Identity::from_bytes(&data);
+    }
+
+    let text = std::str::from_utf8(&data)
+        .map(str::trim)
+        .map_err(|_| NetworkError::Identity(format!("{} is not an identity", path.display())))?;
+    match hex::decode(text) {
+        Ok(key) if key.len() == 32 => Identity::from_bytes(&key),
+        _ => from_mnemonic(text),
+    }
+}
+
+/// Contents of a key file of `identity` in `format`
+///
+/// `passphrase` is needed for [`KeyFormat::Encrypted`].
+pub fn encode(identity: &Identity, format: KeyFormat, passphrase: Option<&str>) -> Result<Vec<u8>> {
+    Ok(match format {
+        KeyFormat::Plain => identity.private_key(),
+        KeyFormat::Encrypted => {
+            let passphrase = passphrase
+                .ok_or_else(|| NetworkError::Identity("A passphrase is needed".to_string()))?;
+            identity.to_encrypted_bytes(passphrase, reticulum_core::identity::KDF_ROUNDS)
+        }
+        KeyFormat::Hex => format!("{}\n", hex::encode(identity.private_key())).into_bytes(),
+        KeyFormat::Mnemonic => format!("{}\n", to_mnemonic(identity)).into_bytes(),
+    })
+}
+
+/// Write `contents` to a new key file at `path`, or over an existing one if
+/// `overwrite` is set
+pub fn write(path: &Path, contents: &[u8], overwrite: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => NetworkError::Identity(format!(
+            "{} already exists (--force overwrites it)",
+            path.display()
+        )),
+        _ => e.into(),
+    })?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+/// Replace the key file at `path` with `contents`, leaving the old one in
+/// place if writing fails
+pub fn replace(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    let temporary = Path::new(&temporary);
+    write(temporary, contents, true)?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic() {
+        let identity = Identity::generate();
+        let words = to_mnemonic(&identity);
+        assert_eq!(words.split(' ').count(), 24);
+
+        let restored = from_mnemonic(&format!("  {}\n", words.to_uppercase())).unwrap();
+        assert_eq!(restored.public_key(), identity.public_key());
+
+        let short = words.split(' ').take(12).collect::<Vec<_>>().join(" ");
+        assert!(from_mnemonic(&short).is_err());
+    }
+
+    #[test]
+    fn test_read_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+
+        for (name, format) in [
+            ("plain", KeyFormat::Plain),
+            ("hex", KeyFormat::Hex),
+            ("mnemonic", KeyFormat::Mnemonic),
+        ] {
+            let path = dir.path().join(name);
+            let contents = encode(&identity, format, None).unwrap();
+            write(&path, &contents, false).unwrap();
+            assert_eq!(read(&path).unwrap().public_key(), identity.public_key());
+        }
+
+        assert!(encode(&identity, KeyFormat::Encrypted, None).is_err());
+        let path = dir.path().join("junk");
+        write(&path, b"not a key", false).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        write(&path, b"one", false).unwrap();
+
+        let e = write(&path, b"two", false).unwrap_err().to_string();
+        assert!(e.contains("--force"), "{}", e);
+        write(&path, b"two", true).unwrap();
+        replace(&path, b"three").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"three");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
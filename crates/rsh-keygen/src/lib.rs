@@ -0,0 +1,11 @@
+//! rsh-keygen - Identity key management
+//!
+//! Generates identities, backs them up as mnemonics, sets and changes their
+//! passphrases, converts between key file formats and shows what to hand
+//! out: fingerprints to compare and public keys for server allowlists.
+
+pub mod fingerprint;
+pub mod keyfile;
+
+pub use fingerprint::{fingerprint, FingerprintFormat};
+pub use keyfile::KeyFormat;
@@ -0,0 +1,192 @@
+//! rsh-keygen - Identity key management tool
+//!
+//! Generates and restores identities, manages their passphrases, converts
+//! key files between formats and prints fingerprints and the public keys
+//! servers list in `allowed_clients`.
+
+use clap::{Parser, Subcommand};
+use reticulum_core::{Identity, Result};
+use rsh_agent::passphrase;
+use rsh_keygen::{fingerprint, keyfile, FingerprintFormat, KeyFormat};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a new identity
+    Generate {
+        /// Identity file to create
+        path: PathBuf,
+
+        /// Protect it with a passphrase (typed twice, or taken from
+        /// RSH_IDENTITY_PASSPHRASE)
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Also print the 24 words to restore it from
+        #[arg(long)]
+        mnemonic: bool,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Restore an identity from its 24 words, read from standard input
+    Restore {
+        /// Identity file to create
+        path: PathBuf,
+
+        /// Protect it with a passphrase
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Set or change the passphrase of an identity file
+    Passphrase {
+        /// Identity file to update
+        path: PathBuf,
+
+        /// Remove the passphrase instead, leaving the key unencrypted
+        #[arg(long)]
+        remove: bool,
+    },
+
+    /// Print the fingerprint of an identity's public key
+    Fingerprint {
+        /// Identity file, in any format
+        path: PathBuf,
+
+        /// How to show it
+        #[arg(long, value_enum, default_value_t)]
+        format: FingerprintFormat,
+    },
+
+    /// Write an identity in another format
+    Convert {
+        /// Identity file, in any format
+        input: PathBuf,
+
+        /// File to write
+        output: PathBuf,
+
+        /// Format to write
+        #[arg(long, value_enum)]
+        to: KeyFormat,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Print the public key, the entry for a server's allowed_clients, and
+    /// the destination hash
+    Public {
+        /// Identity file, in any format
+        path: PathBuf,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse().command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rsh-keygen: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Generate {
+            path,
+            encrypt,
+            mnemonic,
+            force,
+        } => {
+            let identity = Identity::generate();
+            save(&identity, &path, encrypt, force)?;
+            eprintln!("Identity saved to {}", path.display());
+            println!("{}", identity.destination_hex());
+            if mnemonic {
+                eprintln!("Write down these words; they restore the identity:");
+                println!("{}", keyfile::to_mnemonic(&identity));
+            }
+        }
+        Command::Restore {
+            path,
+            encrypt,
+            force,
+        } => {
+            let mut words = String::new();
+            std::io::stdin().read_to_string(&mut words)?;
+            let identity = keyfile::from_mnemonic(&words)?;
+            save(&identity, &path, encrypt, force)?;
+            eprintln!("Identity restored to {}", path.display());
+            println!("{}", identity.destination_hex());
+        }
+        Command::Passphrase { path, remove } => {
+            let identity = keyfile::read(&path)?;
+            let contents = if remove {
+                keyfile::encode(&identity, KeyFormat::Plain, None)?
+            } else {
+                let passphrase = passphrase::choose("New passphrase: ")?;
+                keyfile::encode(&identity, KeyFormat::Encrypted, Some(&passphrase))?
+            };
+            keyfile::replace(&path, &contents)?;
+            eprintln!("Updated {}", path.display());
+        }
+        Command::Fingerprint { path, format } => {
+            let identity = keyfile::read(&path)?;
+            println!("{}", fingerprint(&identity.public_key(), format));
+        }
+        Command::Convert {
+            input,
+            output,
+            to,
+            force,
+        } => {
+            let identity = keyfile::read(&input)?;
+            let passphrase = match to {
+                KeyFormat::Encrypted => Some(passphrase::choose("New passphrase: ")?),
+                _ => None,
+            };
+            let contents = keyfile::encode(&identity, to, passphrase.as_deref())?;
+            keyfile::write(&output, &contents, force)?;
+            eprintln!("Written to {}", output.display());
+        }
+        Command::Public { path } => {
+            let identity = keyfile::read(&path)?;
+            println!("{}", hex::encode(identity.public_key()));
+            eprintln!("Destination: {}", identity.destination_hex());
+        }
+    }
+    Ok(())
+}
+
+/// Save `identity` to a new identity file at `path`
+fn save(identity: &Identity, path: &std::path::Path, encrypt: bool, force: bool) -> Result<()> {
+    let (format, passphrase) = if encrypt {
+        (
+            KeyFormat::Encrypted,
+            Some(passphrase::choose("New passphrase: ")?),
+        )
+    } else {
+        (KeyFormat::Plain, None)
+    };
+    let contents = keyfile::encode(identity, format, passphrase.as_deref())?;
+    keyfile::write(path, &contents, force)
+}
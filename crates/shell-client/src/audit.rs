@@ -0,0 +1,254 @@
+//! Local audit log
+//!
+//! With `audit_log_path` set, the client appends a JSON line for every
+//! command it runs and every terminal session it opens: when it started, on
+//! which server, the command line, how long it took and how it ended. This
+//! is the operator's own record, kept whatever the server logs.
+//!
+//! Matches of the `audit_redact` regular expressions in the command line
+//! and in error messages are replaced by [`REDACTED`] before anything is
+//! written, e.g. `--password[= ]\S+` or `(?i)token=\S+`. The file is created
+//! readable by its owner only. Failing to write a record is logged as a
+//! warning and does not fail the command.
+
+use crate::{config::ClientConfig, ClientError, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use regex::Regex;
+use serde::Serialize;
+use shell_proto::CommandStatus;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+/// What redacted text is replaced by
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A command to record
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// Server destination (hex)
+    pub server: &'a str,
+
+    /// Command (None = the login shell of a terminal session)
+    pub command: Option<&'a str>,
+
+    /// Arguments
+    pub args: &'a [String],
+
+    /// Working directory asked for
+    pub cwd: Option<&'a str>,
+
+    /// Run in a terminal session
+    pub pty: bool,
+}
+
+/// How a recorded command ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The server ran it (terminal sessions have no status)
+    Finished {
+        status: Option<CommandStatus>,
+        exit_code: Option<i32>,
+    },
+
+    /// It could not be run, or its result never arrived
+    Failed(String),
+}
+
+/// One line of the log
+#[derive(Serialize)]
+struct Record<'a> {
+    /// When the command was sent (RFC 3339)
+    timestamp: String,
+
+    server: &'a str,
+
+    /// Command line, redacted
+    command: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pty: bool,
+
+    /// `ok`, `failed`, `timeout`, `killed`, `oom` or `error` (not run)
+    status: &'static str,
+
+    exit_code: Option<i32>,
+
+    duration_ms: u64,
+
+    /// Why the command could not be run, redacted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Append-only log of the commands run
+pub struct AuditLog {
+    /// Patterns to redact
+    redact: Vec<Regex>,
+
+    /// Log file
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open (or create) the log at `path`, redacting matches of `redact`
+    pub fn open(path: &Path, redact: &[String]) -> Result<Self> {
+        let redact = redact
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    ClientError::Config(format!(
+                        "Invalid audit_redact pattern {:?}: {}",
+                        pattern, e
+                    ))
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(Self {
+            redact,
+            file: Mutex::new(options.open(path)?),
+        })
+    }
+
+    /// Open the log described by the configuration, if any
+    pub fn from_config(config: &ClientConfig) -> Result<Option<Self>> {
+        config
+            .audit_log_path
+            .as_deref()
+            .map(|path| Self::open(path, &config.audit_redact))
+            .transpose()
+    }
+
+    /// Append a record of `entry`, sent at `started`, which ended with
+    /// `outcome` after `elapsed`
+    pub fn record(
+        &self,
+        entry: &Entry,
+        started: DateTime<Utc>,
+        elapsed: Duration,
+        outcome: &Outcome,
+    ) {
+        let mut argv: Vec<&str> = entry.command.into_iter().collect();
+        argv.extend(entry.args.iter().map(String::as_str));
+        let (status, exit_code, error) = match outcome {
+            Outcome::Finished { status, exit_code } => {
+                let status = match (status, exit_code) {
+                    (Some(status), _) => crate::output::status_name(*status),
+                    // A terminal session tells only the exit code
+                    (None, Some(code)) if *code != 0 => "failed",
+                    (None, _) => "ok",
+                };
+                (status, *exit_code, None)
+            }
+            Outcome::Failed(error) => ("error", None, Some(self.redacted(error))),
+        };
+        let record = Record {
+            timestamp: started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            server: entry.server,
+            command: self.redacted(&shell_words::join(argv)),
+            cwd: entry.cwd,
+            pty: entry.pty,
+            status,
+            exit_code,
+            duration_ms: elapsed.as_millis() as u64,
+            error,
+        };
+
+        let mut line = serde_json::to_string(&record).expect("records serialize");
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!(error = %e, "Failed to write the audit log");
+        }
+    }
+
+    /// `text` with every match of a redaction pattern replaced
+    fn redacted(&self, text: &str) -> String {
+        self.redact.iter().fold(text.to_string(), |text, pattern| {
+            pattern.replace_all(&text, REDACTED).into_owned()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path, &["--password[= ]\\S+".to_string()]).unwrap();
+
+        let args = vec!["--password=hunter2".to_string(), "my file".to_string()];
+        let entry = Entry {
+            server: "ab12",
+            command: Some("login"),
+            args: &args,
+            cwd: Some("/srv"),
+            pty: false,
+        };
+        let started = Utc::now();
+        log.record(
+            &entry,
+            started,
+            Duration::from_millis(42),
+            &Outcome::Finished {
+                status: Some(CommandStatus::Error),
+                exit_code: Some(2),
+            },
+        );
+        log.record(
+            &Entry {
+                command: None,
+                args: &[],
+                cwd: None,
+                pty: true,
+                ..entry
+            },
+            started,
+            Duration::ZERO,
+            &Outcome::Failed("login --password hunter2 failed".to_string()),
+        );
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter2"));
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["server"], "ab12");
+        assert_eq!(lines[0]["command"], "login [REDACTED] 'my file'");
+        assert_eq!(lines[0]["cwd"], "/srv");
+        assert_eq!(lines[0]["status"], "failed");
+        assert_eq!(lines[0]["exit_code"], 2);
+        assert_eq!(lines[0]["duration_ms"], 42);
+        assert!(lines[0].get("pty").is_none());
+        assert_eq!(lines[1]["pty"], true);
+        assert_eq!(lines[1]["status"], "error");
+        assert_eq!(lines[1]["error"], "login [REDACTED] failed");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(AuditLog::open(&path, &["(".to_string()]).is_err());
+    }
+}
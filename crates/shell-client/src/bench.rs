@@ -0,0 +1,253 @@
+//! Link benchmark (`--ping`)
+//!
+//! `--ping` sends a series of pings, then of small and of large ECHO
+//! payloads, one at a time, and reports for each series the round-trip
+//! times (min/avg/max), the jitter (mean difference between consecutive
+//! round trips), the loss, and for the echoes the payload bytes moved per
+//! second in both directions. Comparing the series tells a link with high
+//! latency from one with little bandwidth, which on I2P depend on the
+//! tunnels in use.
+//!
+//! A probe not answered within `connection_timeout` counts as lost. Servers
+//! without the `echo` capability only get the pings.
+
+use crate::{client::Client, Result};
+use serde::Serialize;
+use shell_proto::MAX_CHUNK_SIZE;
+use std::time::Duration;
+
+/// Payload of the small echoes
+pub const SMALL_PAYLOAD: usize = 64;
+
+/// Payload of the large echoes, the most one message carries
+pub const LARGE_PAYLOAD: usize = MAX_CHUNK_SIZE;
+
+/// Pause between probes, so they don't queue up behind each other
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What one series of probes measured
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Series {
+    /// `ping`, `echo-small` or `echo-large`
+    pub kind: &'static str,
+
+    /// Payload bytes of each probe (each way)
+    pub payload: usize,
+
+    /// Probes sent
+    pub sent: u32,
+
+    /// Probes not answered
+    pub lost: u32,
+
+    /// Shortest round trip (ms)
+    pub min_ms: Option<f64>,
+
+    /// Mean round trip (ms)
+    pub avg_ms: Option<f64>,
+
+    /// Longest round trip (ms)
+    pub max_ms: Option<f64>,
+
+    /// Mean difference between consecutive round trips (ms)
+    pub jitter_ms: Option<f64>,
+
+    /// Payload bytes per second, both directions together
+    pub throughput: Option<f64>,
+}
+
+impl Series {
+    /// Summarize the round trips of `kind` probes carrying `payload` bytes
+    /// (None = lost)
+    pub fn new(kind: &'static str, payload: usize, rtts: &[Option<Duration>]) -> Self {
+        let answered: Vec<f64> = rtts
+            .iter()
+            .flatten()
+            .map(|rtt| rtt.as_micros() as f64 / 1000.0)
+            .collect();
+        let total_ms: f64 = answered.iter().sum();
+        let jitter_ms = (answered.len() > 1).then(|| {
+            let diffs: f64 = answered.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+            diffs / (answered.len() - 1) as f64
+        });
+        let throughput = (payload > 0 && total_ms > 0.0)
+            .then(|| (2 * payload * answered.len()) as f64 * 1000.0 / total_ms);
+
+        Self {
+            kind,
+            payload,
+            sent: rtts.len() as u32,
+            lost: (rtts.len() - answered.len()) as u32,
+            min_ms: answered.iter().copied().reduce(f64::min),
+            avg_ms: (!answered.is_empty()).then(|| total_ms / answered.len() as f64),
+            max_ms: answered.iter().copied().reduce(f64::max),
+            jitter_ms,
+            throughput,
+        }
+    }
+
+    /// Share of probes lost, in percent
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        f64::from(self.lost) * 100.0 / f64::from(self.sent)
+    }
+}
+
+/// Send `count` probes of each kind, printing each round trip to stderr if
+/// `verbose`
+pub async fn run(client: &Client, count: u32, verbose: bool) -> Result<Vec<Series>> {
+    let echo = client
+        .health()
+        .await
+        .capabilities
+        .iter()
+        .any(|capability| capability == "echo");
+
+    let mut results = vec![probe(client, "ping", 0, count, verbose).await?];
+    if echo {
+        results.push(probe(client, "echo-small", SMALL_PAYLOAD, count, verbose).await?);
+        results.push(probe(client, "echo-large", LARGE_PAYLOAD, count, verbose).await?);
+    } else if verbose {
+        eprintln!("The server does not answer echoes; only pinged");
+    }
+    Ok(results)
+}
+
+/// Send `count` probes with `payload` bytes (0 = pings)
+async fn probe(
+    client: &Client,
+    kind: &'static str,
+    payload: usize,
+    count: u32,
+    verbose: bool,
+) -> Result<Series> {
+    let mut rtts = Vec::with_capacity(count as usize);
+    for seq in 1..=count {
+        if seq > 1 {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+        let result = if payload == 0 {
+            client.ping().await
+        } else {
+            client.echo(random_payload(payload)).await
+        };
+        let rtt = match result {
+            Ok(rtt) => Some(rtt),
+            // Lost on the way; a refusal is final
+            Err(e) if e.is_transient() => None,
+            Err(e) => return Err(e),
+        };
+        if verbose {
+            match rtt {
+                Some(rtt) => eprintln!(
+                    "{} {} bytes: seq={} time={:.1} ms",
+                    kind,
+                    payload,
+                    seq,
+                    rtt.as_secs_f64() * 1000.0
+                ),
+                None => eprintln!("{} {} bytes: seq={} lost", kind, payload, seq),
+            }
+        }
+        rtts.push(rtt);
+    }
+    Ok(Series::new(kind, payload, &rtts))
+}
+
+/// Bytes that no link compresses away
+fn random_payload(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    rand::Rng::fill(&mut rand::thread_rng(), data.as_mut_slice());
+    data
+}
+
+/// The results as a table
+pub fn report(results: &[Series]) -> String {
+    let ms = |value: Option<f64>| value.map_or_else(|| "-".to_string(), |v| format!("{:.1}", v));
+    let mut table = format!(
+        "{:<10}  {:>6}  {:>10}  {:>8}  {:>8}  {:>8}  {:>8}  {:>10}\n",
+        "PROBE", "BYTES", "LOSS", "MIN ms", "AVG ms", "MAX ms", "JITTER", "THROUGHPUT"
+    );
+    for series in results {
+        let throughput = series
+            .throughput
+            .map_or_else(|| "-".to_string(), format_rate);
+        let loss = format!(
+            "{}/{} {:.0}%",
+            series.lost,
+            series.sent,
+            series.loss_percent()
+        );
+        table.push_str(&format!(
+            "{:<10}  {:>6}  {:>10}  {:>8}  {:>8}  {:>8}  {:>8}  {:>10}\n",
+            series.kind,
+            series.payload,
+            loss,
+            ms(series.min_ms),
+            ms(series.avg_ms),
+            ms(series.max_ms),
+            ms(series.jitter_ms),
+            throughput
+        ));
+    }
+    table
+}
+
+/// Bytes per second, scaled
+fn format_rate(rate: f64) -> String {
+    if rate >= 1024.0 * 1024.0 {
+        format!("{:.1} MiB/s", rate / (1024.0 * 1024.0))
+    } else if rate >= 1024.0 {
+        format!("{:.1} KiB/s", rate / 1024.0)
+    } else {
+        format!("{:.0} B/s", rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn test_series() {
+        let series = Series::new("echo-large", 1000, &[ms(100), None, ms(300), ms(200)]);
+        assert_eq!(series.sent, 4);
+        assert_eq!(series.lost, 1);
+        assert_eq!(series.loss_percent(), 25.0);
+        assert_eq!(series.min_ms, Some(100.0));
+        assert_eq!(series.avg_ms, Some(200.0));
+        assert_eq!(series.max_ms, Some(300.0));
+        // |300 - 100| and |200 - 300|
+        assert_eq!(series.jitter_ms, Some(150.0));
+        // 3 echoes of 1000 bytes each way in 0.6 s
+        assert_eq!(series.throughput, Some(10_000.0));
+
+        let ping = Series::new("ping", 0, &[ms(50)]);
+        assert_eq!(ping.jitter_ms, None);
+        assert_eq!(ping.throughput, None);
+
+        let lost = Series::new("ping", 0, &[None, None]);
+        assert_eq!(lost.loss_percent(), 100.0);
+        assert_eq!(lost.avg_ms, None);
+    }
+
+    #[test]
+    fn test_report() {
+        let report = report(&[Series::new("ping", 0, &[ms(50), ms(70)])]);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("ping"));
+        assert!(lines[1].contains("0/2 0%"));
+        assert!(lines[1].contains("60.0"));
+        assert!(lines[1].trim_end().ends_with('-'));
+
+        assert_eq!(format_rate(512.0), "512 B/s");
+        assert_eq!(format_rate(2048.0), "2.0 KiB/s");
+    }
+}
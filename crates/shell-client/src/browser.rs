@@ -0,0 +1,533 @@
+//! Interactive file browser
+//!
+//! `files [DIR]` in the REPL opens an ftp-like prompt with its own remote
+//! working directory, for looking around and copying files without running
+//! programs on the server: everything is done with file operations and
+//! transfers. Remote paths are taken relative to the browser's directory
+//! and sent as absolute paths, so they are unaffected by how the server
+//! resolves relative ones.
+//!
+//! Tab completes commands, remote paths (listing directories with LIST,
+//! cached like the REPL's completion) and the local paths of `put`, `get`
+//! and `lcd`.
+
+use crate::{
+    client::Client,
+    completion::CACHE_TTL,
+    transfer::{self, TransferArgs},
+    ClientError, Result,
+};
+use chrono::{Local, TimeZone};
+use colored::Colorize;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use shell_proto::{FileEntry, FileKind, FileOp};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tracing::debug;
+
+/// Commands of the browser
+const COMMANDS: &[&str] = &[
+    "bye", "cd", "chmod", "exit", "get", "help", "lcd", "lpwd", "ls", "mkdir", "mv", "put", "pwd",
+    "quit", "rm",
+];
+
+/// The file browser
+pub struct FileBrowser {
+    /// Client connection
+    client: Arc<Client>,
+
+    /// Readline editor, completing against the server
+    editor: Editor<PathCompleter, DefaultHistory>,
+
+    /// Remote working directory (absolute)
+    cwd: Arc<Mutex<String>>,
+
+    /// Directory `cd` alone goes back to
+    home: String,
+}
+
+impl FileBrowser {
+    /// Open the browser in `dir`, or else in the server's working directory
+    pub async fn open(client: Arc<Client>, dir: Option<&str>) -> Result<Self> {
+        // File operations resolve "." against the server's directory
+        let home = match client
+            .file_op(FileOp::Stat {
+                path: ".".to_string(),
+            })
+            .await
+        {
+            Ok(entries) => entries
+                .into_iter()
+                .next()
+                .map_or_else(|| "/".to_string(), |entry| entry.name),
+            Err(e) => {
+                debug!("Cannot find the server's directory: {}", e);
+                "/".to_string()
+            }
+        };
+        let cwd = Arc::new(Mutex::new(home.clone()));
+        let mut editor = Editor::new().expect("Failed to create readline editor");
+        editor.set_helper(Some(PathCompleter::new(client.clone(), cwd.clone())));
+
+        let browser = Self {
+            client,
+            editor,
+            cwd,
+            home,
+        };
+        if let Some(dir) = dir {
+            browser.cd(dir).await?;
+        }
+        Ok(browser)
+    }
+
+    /// Read and run commands until `exit` or Ctrl+D
+    pub async fn run(&mut self) -> Result<()> {
+        println!("File browser: type 'help' for commands, 'exit' to return");
+        loop {
+            let prompt = format!("files:{}> ", self.cwd()).cyan().to_string();
+            match self.editor.readline(&prompt) {
+                Ok(line) => {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let _ = self.editor.add_history_entry(line);
+
+                    let result = match shell_words::split(line) {
+                        Ok(words) => self.handle(&words).await,
+                        Err(e) => Err(ClientError::Repl(format!("Invalid command syntax: {}", e))),
+                    };
+                    // The command may have changed what completes
+                    if let Some(completer) = self.editor.helper() {
+                        completer.invalidate();
+                    }
+                    match result {
+                        Ok(true) => {}
+                        Ok(false) => return Ok(()),
+                        Err(e) => eprintln!("{} {}", "Error:".red().bold(), e),
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    println!("^C");
+                }
+                Err(ReadlineError::Eof) => {
+                    println!("^D");
+                    return Ok(());
+                }
+                Err(err) => return Err(ClientError::Repl(err.to_string())),
+            }
+        }
+    }
+
+    /// Run one command, returning false to leave the browser
+    async fn handle(&self, words: &[String]) -> Result<bool> {
+        let (command, args) = words.split_first().expect("line is not empty");
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match (command.as_str(), args.as_slice()) {
+            ("exit" | "quit" | "bye", []) => return Ok(false),
+            ("help", []) => print_help(),
+            ("pwd", []) => println!("{}", self.cwd()),
+            ("cd", []) => *self.cwd.lock().unwrap() = self.home.clone(),
+            ("cd", [dir]) => self.cd(dir).await?,
+            ("ls", args) => self.ls(args).await?,
+            ("mkdir", ["-p", path]) => {
+                self.op(FileOp::Mkdir {
+                    path: self.resolve(path),
+                    recursive: true,
+                })
+                .await?
+            }
+            ("mkdir", [path]) => {
+                self.op(FileOp::Mkdir {
+                    path: self.resolve(path),
+                    recursive: false,
+                })
+                .await?
+            }
+            ("rm", ["-r", path]) => {
+                self.op(FileOp::Remove {
+                    path: self.resolve(path),
+                    recursive: true,
+                })
+                .await?
+            }
+            ("rm", [path]) => {
+                self.op(FileOp::Remove {
+                    path: self.resolve(path),
+                    recursive: false,
+                })
+                .await?
+            }
+            ("mv", [from, to]) => {
+                self.op(FileOp::Rename {
+                    from: self.resolve(from),
+                    to: self.resolve(to),
+                })
+                .await?
+            }
+            ("chmod", [mode, path]) => {
+                let mode = u32::from_str_radix(mode, 8).map_err(|_| {
+                    ClientError::Repl(format!("chmod: {} is not an octal mode", mode))
+                })?;
+                self.op(FileOp::Chmod {
+                    path: self.resolve(path),
+                    mode,
+                })
+                .await?
+            }
+            ("get", _) => {
+                let mut args = TransferArgs::parse("get", &words[1..])?;
+                args.source = self.resolve(&args.source);
+                transfer::get(&self.client, &args).await?;
+            }
+            ("put", _) => {
+                let mut args = TransferArgs::parse("put", &words[1..])?;
+                // Into the browser's directory unless told otherwise
+                args.target = Some(match args.target.take() {
+                    Some(target) if target.ends_with('/') => {
+                        format!("{}/", self.resolve(&target).trim_end_matches('/'))
+                    }
+                    Some(target) => self.resolve(&target),
+                    None => format!("{}/", self.cwd().trim_end_matches('/')),
+                });
+                transfer::put(&self.client, &args).await?;
+            }
+            ("lcd", [dir]) => std::env::set_current_dir(dir)?,
+            ("lpwd", []) => println!("{}", std::env::current_dir()?.display()),
+            (
+                "exit" | "quit" | "bye" | "help" | "pwd" | "cd" | "mkdir" | "rm" | "mv" | "chmod"
+                | "lcd" | "lpwd",
+                _,
+            ) => {
+                return Err(ClientError::Repl(format!(
+                    "{}: wrong arguments (see help)",
+                    command
+                )))
+            }
+            _ => {
+                return Err(ClientError::Repl(format!(
+                    "Unknown command {} (see help)",
+                    command
+                )))
+            }
+        }
+        Ok(true)
+    }
+
+    /// The remote working directory
+    fn cwd(&self) -> String {
+        self.cwd.lock().unwrap().clone()
+    }
+
+    /// `path` as an absolute remote path
+    fn resolve(&self, path: &str) -> String {
+        resolve(&self.cwd(), path)
+    }
+
+    /// Change the remote working directory, if `dir` can be listed
+    async fn cd(&self, dir: &str) -> Result<()> {
+        let dir = self.resolve(dir);
+        self.client
+            .file_op(FileOp::List { path: dir.clone() })
+            .await?;
+        *self.cwd.lock().unwrap() = dir;
+        Ok(())
+    }
+
+    /// List a directory (`-l`: with mode, size and modification time)
+    async fn ls(&self, args: &[&str]) -> Result<()> {
+        let (long, path) = match args {
+            [] => (false, None),
+            ["-l"] => (true, None),
+            ["-l", path] => (true, Some(*path)),
+            [path] => (false, Some(*path)),
+            _ => return Err(ClientError::Repl("usage: ls [-l] [path]".to_string())),
+        };
+        let path = path.map_or_else(|| self.cwd(), |path| self.resolve(path));
+        let entries = self.client.file_op(FileOp::List { path }).await?;
+        for entry in &entries {
+            if long {
+                println!("{}", long_line(entry));
+            } else {
+                println!("{}", display_name(entry));
+            }
+        }
+        Ok(())
+    }
+
+    /// Perform a file operation that answers nothing
+    async fn op(&self, op: FileOp) -> Result<()> {
+        self.client.file_op(op).await.map(|_| ())
+    }
+}
+
+/// Print the browser's commands
+fn print_help() {
+    println!("{}", "File browser commands:".bold());
+    println!("  ls [-l] [DIR]       - List a directory (-l: with mode, size and time)");
+    println!("  cd [DIR], pwd       - Change or show the remote directory");
+    println!("  get [-r] R [L]      - Download a file (--resume: continue)");
+    println!("  put [-r] L [R]      - Upload a file into the remote directory");
+    println!("  mkdir [-p] DIR      - Create a directory");
+    println!("  rm [-r] PATH        - Remove a file or directory");
+    println!("  mv FROM TO          - Rename or move");
+    println!("  chmod MODE PATH     - Change permissions (octal)");
+    println!("  lcd DIR, lpwd       - Change or show the local directory");
+    println!("  exit, quit, bye     - Return to the shell");
+}
+
+/// `path` taken relative to the absolute directory `cwd`, with `.` and `..`
+/// resolved
+pub fn resolve(cwd: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in base.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// The name of `entry`, marked `/` for directories and `@` for links
+fn display_name(entry: &FileEntry) -> String {
+    match entry.kind {
+        FileKind::Directory => format!("{}/", entry.name).blue().bold().to_string(),
+        FileKind::Symlink => format!("{}@", entry.name).cyan().to_string(),
+        FileKind::File | FileKind::Other => entry.name.clone(),
+    }
+}
+
+/// `entry` as a line of `ls -l`
+fn long_line(entry: &FileEntry) -> String {
+    let modified = Local
+        .timestamp_opt(entry.modified as i64, 0)
+        .single()
+        .map_or_else(String::new, |time| {
+            time.format("%Y-%m-%d %H:%M").to_string()
+        });
+    format!(
+        "{} {:>10} {} {}",
+        mode_string(entry.kind, entry.mode),
+        entry.size,
+        modified,
+        display_name(entry)
+    )
+}
+
+/// Mode bits as `ls` shows them, e.g. `drwxr-xr-x`
+fn mode_string(kind: FileKind, mode: u32) -> String {
+    let mut text = String::with_capacity(10);
+    text.push(match kind {
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::File => '-',
+        FileKind::Other => '?',
+    });
+    for shift in [6, 3, 0] {
+        let bits = mode >> shift;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    text
+}
+
+/// A directory listing kept for later keystrokes
+struct Cached {
+    fetched_at: Instant,
+    entries: Vec<FileEntry>,
+}
+
+/// Readline helper completing browser commands and paths
+pub struct PathCompleter {
+    /// Client connection
+    client: Arc<Client>,
+
+    /// Runtime the client runs on
+    runtime: Handle,
+
+    /// The browser's remote directory
+    cwd: Arc<Mutex<String>>,
+
+    /// Listings by absolute directory
+    cache: Mutex<HashMap<String, Cached>>,
+
+    /// Completion of local paths
+    local: FilenameCompleter,
+}
+
+impl PathCompleter {
+    /// Create a completer; must be called within the client's runtime
+    fn new(client: Arc<Client>, cwd: Arc<Mutex<String>>) -> Self {
+        Self {
+            client,
+            runtime: Handle::current(),
+            cwd,
+            cache: Mutex::new(HashMap::new()),
+            local: FilenameCompleter::new(),
+        }
+    }
+
+    /// Forget every cached listing
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// The entries of the absolute directory `dir`, from the cache if fresh
+    fn list(&self, dir: &str) -> Vec<FileEntry> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, cached| cached.fetched_at.elapsed() < CACHE_TTL);
+            if let Some(cached) = cache.get(dir) {
+                return cached.entries.clone();
+            }
+        }
+
+        // The browser waits in readline, so nothing else uses the connection
+        let op = FileOp::List {
+            path: dir.to_string(),
+        };
+        let response =
+            tokio::task::block_in_place(|| self.runtime.block_on(self.client.file_op(op)));
+        match response {
+            Ok(entries) => {
+                self.cache.lock().unwrap().insert(
+                    dir.to_string(),
+                    Cached {
+                        fetched_at: Instant::now(),
+                        entries: entries.clone(),
+                    },
+                );
+                entries
+            }
+            Err(e) => {
+                debug!("Listing {} failed: {}", dir, e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Completer for PathCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let Some((command, args)) = before.split_first() else {
+            let pairs = COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| Pair {
+                    display: command.to_string(),
+                    replacement: format!("{} ", command),
+                })
+                .collect();
+            return Ok((start, pairs));
+        };
+        if word.starts_with('-') {
+            return Ok((start, Vec::new()));
+        }
+        if local_argument(command, args) {
+            return self.local.complete(line, pos, ctx);
+        }
+
+        // Show only what follows the directory part, like a local shell
+        let dir_len = word.rfind('/').map_or(0, |i| i + 1);
+        let (dir, partial) = word.split_at(dir_len);
+        let listed = resolve(&self.cwd.lock().unwrap(), dir);
+        let pairs = self
+            .list(&listed)
+            .into_iter()
+            .filter(|entry| entry.name.starts_with(partial))
+            .filter(|entry| !entry.name.starts_with('.') || partial.starts_with('.'))
+            .map(|entry| {
+                let suffix = if entry.kind == FileKind::Directory {
+                    "/"
+                } else {
+                    ""
+                };
+                let candidate = format!("{}{}{}", dir, entry.name, suffix);
+                Pair {
+                    display: format!("{}{}", entry.name, suffix),
+                    replacement: shell_words::quote(&candidate).into_owned(),
+                }
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for PathCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for PathCompleter {}
+
+impl Validator for PathCompleter {}
+
+impl Helper for PathCompleter {}
+
+/// Check whether the next argument of `command`, after `args`, is a local
+/// path
+fn local_argument(command: &str, args: &[&str]) -> bool {
+    let paths = args.iter().filter(|arg| !arg.starts_with('-')).count();
+    match command {
+        "lcd" => true,
+        "put" => paths == 0,
+        "get" => paths == 1,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("/srv/app", "logs"), "/srv/app/logs");
+        assert_eq!(resolve("/srv/app", "../data/./x"), "/srv/data/x");
+        assert_eq!(resolve("/srv/app", "/etc/"), "/etc");
+        assert_eq!(resolve("/srv", "../../.."), "/");
+        assert_eq!(resolve("/", "."), "/");
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(FileKind::Directory, 0o755), "drwxr-xr-x");
+        assert_eq!(mode_string(FileKind::File, 0o640), "-rw-r-----");
+        assert_eq!(mode_string(FileKind::Symlink, 0o777), "lrwxrwxrwx");
+    }
+
+    #[test]
+    fn test_local_argument() {
+        assert!(local_argument("put", &["-r"]));
+        assert!(!local_argument("put", &["-r", "src"]));
+        assert!(!local_argument("get", &[]));
+        assert!(local_argument("get", &["--resume", "/var/log/syslog"]));
+        assert!(local_argument("lcd", &[]));
+        assert!(!local_argument("rm", &[]));
+    }
+}
@@ -1,15 +1,43 @@
 //! Client connection management
 
-use crate::{config::ClientConfig, ClientError, Result};
-use reticulum_core::{NetworkInterface, Packet};
+use crate::{
+    audit::{AuditLog, Entry, Outcome},
+    config::ClientConfig,
+    delta::DeltaBases,
+    events::{Direction, DisconnectReason, EventHandler, Notification, TransferProgress},
+    health::{Health, Meter},
+    keystore::Keystore,
+    known_hosts::{self, HostKeyStatus, KnownHosts},
+    predict::Prediction,
+    retry::{self, RetryPolicy},
+    time::{self, Instant, SystemTime, UNIX_EPOCH},
+    transcript::Transcript,
+    ClientError, Result,
+};
+use chrono::{DateTime, Utc};
+use reticulum_core::{Identity, NetworkInterface, Packet, PacketType, Signer};
+use sha2::{Digest, Sha256};
+use shell_proto::noise::{Handshake, SessionCipher, StaticKey};
 use shell_proto::{
-    CommandRequest, CommandResponse, ConnectMessage, Message, ProtocolCodec, SessionId,
-    CURRENT_PROTOCOL_VERSION,
+    packet_signing_payload, CancelRequest, ChunkRequest, CommandInput, CommandRequest,
+    CommandResponse, CompleteRequest, CompleteResponse, CompletionKind, ConnectMessage,
+    DownloadRequest, EchoMessage, FetchOutputRequest, FileChunk, FileEntry, FileOp, FileOpRequest,
+    HandshakeMessage, Message, OutputChunk, PendingNotice, ProtocolCodec, ProtocolError,
+    ProtocolVersion, SessionId, SetEnvRequest, StatsRequest, StatsResponse, TransferReady,
+    UnsetEnvRequest, UploadRequest, CURRENT_PROTOCOL_VERSION, MAX_CHUNK_SIZE,
 };
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex, MutexGuard, RwLock};
+use tracing::{debug, info, warn};
+
+/// Longest wait between reconnect attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 /// Connection state
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -20,6 +48,45 @@ enum ConnectionState {
     Disconnecting,
 }
 
+/// What the server agreed to in ACCEPT
+#[derive(Debug, Clone)]
+struct Accepted {
+    /// When the session was established
+    at: Instant,
+
+    /// Protocol version the server uses
+    protocol_version: ProtocolVersion,
+
+    /// Server capabilities
+    capabilities: Vec<String>,
+
+    /// How often the server wants to be pinged (seconds)
+    keepalive_interval: Option<u64>,
+}
+
+/// Settings for a single command, overriding the configured ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOptions {
+    /// Timeout in seconds (default: `command_timeout`)
+    pub timeout: Option<u64>,
+
+    /// Working directory (relative ones are taken from the session's)
+    pub working_dir: Option<String>,
+
+    /// Environment variables, over the configured ones
+    pub env: BTreeMap<String, String>,
+
+    /// The command is safe to run twice, so it is sent again after a
+    /// transport failure (up to `retry_attempts` times) as long as nothing
+    /// of it arrived yet
+    pub idempotent: bool,
+
+    /// Have stdout sent as a delta against that of the last run of the same
+    /// command, if the server offers `output-delta`; output is then not
+    /// streamed but comes whole in the response
+    pub delta: bool,
+}
+
 /// Shell client
 pub struct Client {
     /// Client configuration
@@ -31,9 +98,54 @@ pub struct Client {
     /// Session ID (if connected)
     session_id: Arc<RwLock<Option<SessionId>>>,
 
+    /// Token for reattaching to the session after a server restart
+    resume_token: Arc<RwLock<Option<Vec<u8>>>>,
+
+    /// Banner the server sent in ACCEPT
+    banner: Arc<RwLock<Option<String>>>,
+
+    /// Server refuses requests until the banner is acknowledged
+    banner_pending: Arc<AtomicBool>,
+
+    /// Identity key the server presented in ACCEPT
+    server_key: Arc<RwLock<Option<Vec<u8>>>>,
+
+    /// The server's key is not in the known hosts yet
+    host_key_unknown: Arc<AtomicBool>,
+
+    /// Session details from ACCEPT (if connected)
+    accepted: Arc<RwLock<Option<Accepted>>>,
+
+    /// Session keys, once the Noise handshake is done
+    cipher: Arc<std::sync::RwLock<Option<Arc<SessionCipher>>>>,
+
+    /// Traffic and ping counters
+    meter: Arc<Meter>,
+
+    /// Held while waiting for a reply, so nothing else reads it
+    busy: Arc<Mutex<()>>,
+
+    /// Keepalive pings went unanswered; reconnect before the next request
+    lost: Arc<AtomicBool>,
+
     /// Request ID counter
     next_request_id: Arc<AtomicU64>,
 
+    /// Sequence number of the last signed packet
+    packet_sequence: Arc<Mutex<u64>>,
+
+    /// Last output of commands run for deltas
+    deltas: Arc<DeltaBases>,
+
+    /// Local record of the commands run (if `audit_log_path` is set)
+    audit: Option<Arc<AuditLog>>,
+
+    /// Transcript of the session (if `session_log_path` is set)
+    transcript: Option<Arc<Transcript>>,
+
+    /// Told of output, notices and disconnects (if set)
+    events: Option<Arc<dyn EventHandler>>,
+
     /// Network interface
     interface: Option<Arc<dyn NetworkInterface>>,
 
@@ -45,12 +157,30 @@ impl Client {
     /// Create a new client
     pub async fn new(config: ClientConfig) -> Result<Self> {
         let server_dest = config.parse_server_destination()?;
+        let audit = AuditLog::from_config(&config)?.map(Arc::new);
+        let transcript =
+            Transcript::from_config(&config, &hex::encode(server_dest))?.map(Arc::new);
 
         Ok(Self {
             config: Arc::new(config),
             state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
             session_id: Arc::new(RwLock::new(None)),
+            resume_token: Arc::new(RwLock::new(None)),
+            banner: Arc::new(RwLock::new(None)),
+            banner_pending: Arc::new(AtomicBool::new(false)),
+            server_key: Arc::new(RwLock::new(None)),
+            host_key_unknown: Arc::new(AtomicBool::new(false)),
+            accepted: Arc::new(RwLock::new(None)),
+            cipher: Arc::default(),
+            meter: Arc::default(),
+            busy: Arc::default(),
+            lost: Arc::new(AtomicBool::new(false)),
             next_request_id: Arc::new(AtomicU64::new(1)),
+            packet_sequence: Arc::new(Mutex::new(0)),
+            deltas: Arc::default(),
+            audit,
+            transcript,
+            events: None,
             interface: None,
             server_destination: server_dest,
         })
@@ -63,18 +193,51 @@ impl Client {
         interface: Arc<dyn NetworkInterface>,
         server_destination: [u8; 32],
     ) -> Result<Self> {
+        let audit = AuditLog::from_config(&config)?.map(Arc::new);
+        let transcript =
+            Transcript::from_config(&config, &hex::encode(server_destination))?.map(Arc::new);
         Ok(Self {
             config: Arc::new(config),
             state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
             session_id: Arc::new(RwLock::new(None)),
+            resume_token: Arc::new(RwLock::new(None)),
+            banner: Arc::new(RwLock::new(None)),
+            banner_pending: Arc::new(AtomicBool::new(false)),
+            server_key: Arc::new(RwLock::new(None)),
+            host_key_unknown: Arc::new(AtomicBool::new(false)),
+            accepted: Arc::new(RwLock::new(None)),
+            cipher: Arc::default(),
+            meter: Arc::default(),
+            busy: Arc::default(),
+            lost: Arc::new(AtomicBool::new(false)),
             next_request_id: Arc::new(AtomicU64::new(1)),
+            packet_sequence: Arc::new(Mutex::new(0)),
+            deltas: Arc::default(),
+            audit,
+            transcript,
+            events: None,
             interface: Some(interface),
             server_destination,
         })
     }
 
+    /// Tell `handler` of the output of commands, notices from the server,
+    /// disconnects and transfer progress from now on
+    pub fn set_event_handler(&mut self, handler: Arc<dyn EventHandler>) {
+        self.events = Some(handler);
+    }
+
     /// Connect to server
+    ///
+    /// Transport failures are retried as configured (`retry_attempts`);
+    /// a rejection or a changed host key is not.
     pub async fn connect(&self) -> Result<()> {
+        RetryPolicy::from_config(&self.config)
+            .run("connecting", || self.connect_once())
+            .await
+    }
+
+    async fn connect_once(&self) -> Result<()> {
         // Check current state
         {
             let state = self.state.read().await;
@@ -83,11 +246,15 @@ impl Client {
             }
         }
 
+        let _busy = self.busy.lock().await;
+
         // Update state to connecting
         {
             let mut state = self.state.write().await;
             *state = ConnectionState::Connecting;
         }
+        // Keys of an earlier connection are no use for this one
+        *self.cipher.write().unwrap() = None;
 
         // Check if we have an interface (I2P or test mode)
         let interface = self.interface.as_ref().ok_or_else(|| {
@@ -109,26 +276,61 @@ impl Client {
             info!("Connecting to server: {}", hex::encode(self.server_destination));
         }
 
-        // Send CONNECT message
-        let connect_msg = ConnectMessage {
+        // Send CONNECT message, signed to prove we hold the identity
+        let mut connect_msg = ConnectMessage {
             protocol_version: CURRENT_PROTOCOL_VERSION,
             client_identity: self.config.identity.public_key(),
-            capabilities: vec!["command-exec".to_string()],
-            auth_token: None,
+            capabilities: vec![
+                "command-exec".to_string(),
+                "output-stream".to_string(),
+                "frame-priority".to_string(),
+            ],
+            auth_token: self.config.auth_token.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            nonce: rand::random(),
+            signature: vec![],
+            resume_token: self.resume_token.read().await.clone(),
+            noise: None,
+            server_destination: self.server_destination,
         };
+        connect_msg.signature = self.config.identity.sign(&connect_msg.signing_payload())?;
 
-        debug!("Sending CONNECT message");
+        // Begin the Noise handshake, bound to this CONNECT
+        let (handshake, first) = Handshake::initiate_with(
+            Arc::new(IdentityKey::new(Arc::clone(&self.config.identity))?),
+            &connect_msg.signing_payload(),
+        )?;
+        connect_msg.noise = Some(first);
 
-        // Encode and send
-        let message = Message::Connect(connect_msg);
-        let encoded = ProtocolCodec::encode(&message)?;
-        let packet = Packet::data(self.server_destination, encoded);
-        interface.send(&packet).await?;
+        debug!("Sending CONNECT message");
 
-        // Receive response
-        let response_packet = interface.receive().await?;
-        let mut buf = bytes::BytesMut::from(response_packet.data.as_ref());
-        let response_msg = ProtocolCodec::decode(&mut buf)?
+        // Send, then wait for the answer
+        let limit = self.connection_timeout();
+        let response = match self.transmit(&Message::Connect(connect_msg)).await {
+            Ok(()) => {
+                receive_within(
+                    interface.as_ref(),
+                    &self.meter,
+                    limit,
+                    "ACCEPT",
+                    "check that it is running and reachable, or raise connection_timeout",
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        let response_packet = match response {
+            Ok(packet) => packet,
+            Err(e) => {
+                *self.state.write().await = ConnectionState::Disconnected;
+                return Err(e);
+            }
+        };
+        let response_msg = self
+            .decode(&response_packet)?
             .ok_or_else(|| ClientError::Connection("No response from server".to_string()))?;
 
         // Handle response
@@ -136,6 +338,43 @@ impl Client {
             Message::Accept(accept) => {
                 info!("Connection accepted by server");
 
+                let pinned = match self.check_host_key(&accept.server_identity).await {
+                    Ok(pinned) => pinned,
+                    Err(e) => {
+                        let mut state = self.state.write().await;
+                        *state = ConnectionState::Disconnected;
+                        return Err(e);
+                    }
+                };
+
+                let encrypted = match &accept.noise {
+                    Some(second) => {
+                        self.finish_handshake(interface, handshake, second, &accept.server_identity)
+                            .await
+                    }
+                    // Only the handshake proves the server holds the key it
+                    // names, which anyone could copy into ACCEPT
+                    None if pinned => Err(ClientError::Connection(
+                        "The server does not encrypt the session, so it can't prove it holds \
+                         the key recorded in the known hosts"
+                            .to_string(),
+                    )),
+                    None if self.config.require_noise => Err(ClientError::Connection(
+                        "The server does not encrypt the session; set require_noise = false \
+                         to connect anyway"
+                            .to_string(),
+                    )),
+                    None => {
+                        warn!("The server does not encrypt the session");
+                        Ok(())
+                    }
+                };
+                if let Err(e) = encrypted {
+                    let mut state = self.state.write().await;
+                    *state = ConnectionState::Disconnected;
+                    return Err(e);
+                }
+
                 // Update state
                 {
                     let mut state = self.state.write().await;
@@ -144,9 +383,40 @@ impl Client {
 
                 {
                     let mut session = self.session_id.write().await;
+                    let mut accepted = self.accepted.write().await;
+                    let resumed = session.is_some_and(|id| id == accept.session_id);
+                    if resumed {
+                        info!("Resumed previous session");
+                    }
+                    let at = accepted
+                        .as_ref()
+                        .filter(|_| resumed)
+                        .map_or_else(Instant::now, |previous| previous.at);
+                    *accepted = Some(Accepted {
+                        at,
+                        protocol_version: accept.protocol_version,
+                        capabilities: accept.capabilities,
+                        keepalive_interval: accept.keepalive_interval,
+                    });
                     *session = Some(accept.session_id);
                 }
 
+                {
+                    let mut token = self.resume_token.write().await;
+                    *token = accept.resume_token;
+                }
+
+                if let Some(banner) = &accept.banner {
+                    let notification = Notification::Banner(banner.clone());
+                    self.emit(|events| events.on_notification(&notification));
+                }
+                {
+                    let mut banner = self.banner.write().await;
+                    *banner = accept.banner;
+                }
+                self.banner_pending
+                    .store(accept.banner_ack_required, Ordering::SeqCst);
+
                 info!("Connected successfully");
                 Ok(())
             }
@@ -175,6 +445,97 @@ impl Client {
         command: String,
         args: Vec<String>,
     ) -> Result<CommandResponse> {
+        self.execute_command_with(command, args, &CommandOptions::default())
+            .await
+    }
+
+    /// Execute a command on the server with its own timeout or working
+    /// directory
+    pub async fn execute_command_with(
+        &self,
+        command: String,
+        args: Vec<String>,
+        options: &CommandOptions,
+    ) -> Result<CommandResponse> {
+        self.execute_command_piped(command, args, options, None)
+            .await
+    }
+
+    /// Execute a command on the server like
+    /// [`execute_command_with`](Self::execute_command_with), writing what
+    /// arrives on `input` to its stdin
+    ///
+    /// The command sees end of file once `input` is closed; without `input`
+    /// its stdin is closed from the start. Fails if the server does not
+    /// take stdin.
+    pub async fn execute_command_piped(
+        &self,
+        command: String,
+        args: Vec<String>,
+        options: &CommandOptions,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+    ) -> Result<CommandResponse> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut response = self
+            .execute(
+                command,
+                args,
+                options,
+                input,
+                &mut |is_stderr: bool, data: &[u8]| {
+                    if is_stderr {
+                        stderr.extend_from_slice(data);
+                    } else {
+                        stdout.extend_from_slice(data);
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        // Streamed output isn't repeated in the response
+        stdout.append(&mut response.stdout);
+        stderr.append(&mut response.stderr);
+        response.stdout = stdout;
+        response.stderr = stderr;
+        Ok(response)
+    }
+
+    /// Execute a command on the server, passing its output to `on_output`
+    /// (`true` for stderr) as it arrives
+    ///
+    /// The response holds only output the server didn't stream. Each
+    /// message on `interrupts` cancels the command: the first sends it
+    /// SIGINT, any later one kills it.
+    ///
+    /// If the connection breaks before the server answered anything, the
+    /// request is sent again after reconnecting; once the command has
+    /// produced output it is not, so it does not run twice.
+    pub async fn execute_command_streaming(
+        &self,
+        command: String,
+        args: Vec<String>,
+        options: &CommandOptions,
+        on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+        interrupts: Option<&mut mpsc::UnboundedReceiver<()>>,
+    ) -> Result<CommandResponse> {
+        self.execute(command, args, options, None, on_output, interrupts)
+            .await
+    }
+
+    /// Execute a command, with stdin from `input` if given
+    pub(crate) async fn execute(
+        &self,
+        command: String,
+        args: Vec<String>,
+        options: &CommandOptions,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+        on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+        interrupts: Option<&mut mpsc::UnboundedReceiver<()>>,
+    ) -> Result<CommandResponse> {
+        self.recover_if_lost().await?;
+
         // Check connection state
         {
             let state = self.state.read().await;
@@ -183,10 +544,11 @@ impl Client {
             }
         }
 
-        // Check if we have an interface
-        let interface = self.interface.as_ref().ok_or_else(|| {
-            ClientError::NotConnected
-        })?;
+        if input.is_some() && !self.has_capability("stdin").await {
+            return Err(ClientError::Request(
+                "The server does not take stdin".to_string(),
+            ));
+        }
 
         let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
 
@@ -197,28 +559,215 @@ impl Client {
             "Executing command"
         );
 
-        let request = CommandRequest {
+        let mut request = CommandRequest {
             id: request_id,
             command,
             args,
-            env: None,
-            timeout: Some(self.config.command_timeout),
-            working_dir: None,
+            env: self.command_env(options),
+            timeout: Some(options.timeout.unwrap_or(self.config.command_timeout)),
+            working_dir: options.working_dir.clone(),
+            secrets: self.config.secrets.clone(),
+            stdin: input.is_some(),
+            delta_base: None,
         };
+        if options.delta && self.has_capability("output-delta").await {
+            request.delta_base = Some(self.deltas.base(&request));
+        }
 
-        // Encode and send request
-        let message = Message::CommandRequest(request);
-        let encoded = ProtocolCodec::encode(&message)?;
-        let packet = Packet::data(self.server_destination, encoded);
-        interface.send(&packet).await?;
+        let started = Utc::now();
+        if let Some(transcript) = &self.transcript {
+            transcript.command(Some(&request.command), &request.args, false);
+        }
+        let mut on_output = |is_stderr: bool, data: &[u8]| {
+            self.observe_output(request_id, is_stderr, data);
+            on_output(is_stderr, data);
+        };
+        let mut result = self
+            .send_command(&request, options, input, &mut on_output, interrupts)
+            .await;
+        if request.delta_base.is_some() {
+            result = result.and_then(|mut response| {
+                self.deltas.resolve(&request, &mut response)?;
+                Ok(response)
+            });
+        }
+        if let Ok(response) = &result {
+            self.observe_output(request_id, false, &response.stdout);
+            self.observe_output(request_id, true, &response.stderr);
+        }
+        let outcome = match &result {
+            Ok(response) => Outcome::Finished {
+                status: Some(response.status),
+                exit_code: (response.exit_code >= 0).then_some(response.exit_code),
+            },
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        self.audit(
+            Some(&request.command),
+            &request.args,
+            request.working_dir.as_deref(),
+            false,
+            started,
+            &outcome,
+        );
+        if let Some(transcript) = &self.transcript {
+            transcript.finished(started, &outcome);
+        }
+        result
+    }
+
+    /// Send a command request and wait for its response: again once after
+    /// reconnecting, or up to `retry_attempts` times after any transport
+    /// failure if it is safe to repeat
+    async fn send_command(
+        &self,
+        request: &CommandRequest,
+        options: &CommandOptions,
+        mut input: Option<mpsc::Receiver<Vec<u8>>>,
+        on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+        mut interrupts: Option<&mut mpsc::UnboundedReceiver<()>>,
+    ) -> Result<CommandResponse> {
+        let retry = RetryPolicy::from_config(&self.config);
+        let resends = if options.idempotent {
+            retry.attempts.max(1)
+        } else {
+            1
+        };
+        let mut answered = false;
+        let mut resent = 0;
+        loop {
+            let error = match self
+                .run_command(
+                    request,
+                    input.as_mut(),
+                    on_output,
+                    interrupts.as_deref_mut(),
+                    &mut answered,
+                )
+                .await
+            {
+                Err(e) => e,
+                result => return result,
+            };
+            let reconnect = self.should_reconnect(&error);
+            if reconnect {
+                self.recover(&error).await?;
+                if answered {
+                    return Err(ClientError::Connection(
+                        "Connection lost while the command was running".to_string(),
+                    ));
+                }
+            }
+            let again = reconnect || (options.idempotent && error.is_transient());
+            if answered || !again || resent == resends {
+                return Err(error);
+            }
+            resent += 1;
+            if !reconnect {
+                warn!(retry = resent, error = %error, "Retrying the command");
+                retry.wait(resent).await;
+            }
+        }
+    }
+
+    /// Send a command request and wait for its response, setting `answered`
+    /// once anything about it arrives or input for it was sent (so sending
+    /// it again could not repeat it)
+    async fn run_command(
+        &self,
+        request: &CommandRequest,
+        mut input: Option<&mut mpsc::Receiver<Vec<u8>>>,
+        on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+        mut interrupts: Option<&mut mpsc::UnboundedReceiver<()>>,
+        answered: &mut bool,
+    ) -> Result<CommandResponse> {
+        let _busy = self.busy.lock().await;
+        let interface = self.interface.as_ref().ok_or(ClientError::NotConnected)?;
+        let request_id = request.id;
+
+        self.transmit(&Message::CommandRequest(request.clone()))
+            .await?;
 
         debug!("Command request sent, waiting for response");
 
-        // Receive response
-        let response_packet = interface.receive().await?;
-        let mut buf = bytes::BytesMut::from(response_packet.data.as_ref());
-        let response_msg = ProtocolCodec::decode(&mut buf)?
-            .ok_or_else(|| ClientError::Connection("No response from server".to_string()))?;
+        // The server answers once the command ran out its timeout at the
+        // latest; waiting for approval comes on top
+        let wait = Duration::from_secs(
+            request.timeout.unwrap_or(self.config.command_timeout) + self.config.connection_timeout,
+        );
+        let mut deadline = Instant::now() + wait;
+
+        // Receive response, which may be preceded by a PENDING notice and
+        // streamed output
+        let mut interrupted = false;
+        let response_msg = loop {
+            let receive = receive_within(
+                interface.as_ref(),
+                &self.meter,
+                deadline.saturating_duration_since(Instant::now()),
+                "response to the command",
+                "it may be unreachable (the wait is the command's timeout plus connection_timeout)",
+            );
+            let response_packet = tokio::select! {
+                packet = receive => packet?,
+                Some(()) = next_interrupt(interrupts.as_deref_mut()) => {
+                    self.cancel(request_id, interrupted).await?;
+                    interrupted = true;
+                    continue;
+                }
+                data = next_input(input.as_deref_mut()), if input.is_some() => {
+                    // A closed input ends the command's
+                    let eof = data.is_none();
+                    if eof {
+                        input = None;
+                    }
+                    self.send(Message::CommandInput(CommandInput {
+                        id: request_id,
+                        data: data.unwrap_or_default(),
+                        eof,
+                    }))
+                    .await?;
+                    *answered = true;
+                    continue;
+                }
+            };
+            let message = self
+                .decode(&response_packet)?
+                .ok_or_else(|| ClientError::Connection("No response from server".to_string()))?;
+            *answered |= match &message {
+                Message::Pending(notice) => notice.id == request_id,
+                Message::CommandOutput(output) => output.id == request_id,
+                _ => false,
+            };
+            match message {
+                Message::Pending(PendingNotice {
+                    id,
+                    approval_id,
+                    timeout,
+                }) if id == request_id => {
+                    info!(
+                        "Waiting for operator approval (approval {}, up to {}s)",
+                        approval_id, timeout
+                    );
+                    deadline = Instant::now() + Duration::from_secs(timeout) + wait;
+                    self.emit(|events| {
+                        events.on_notification(&Notification::Pending {
+                            request_id,
+                            approval_id,
+                            timeout,
+                        })
+                    });
+                }
+                Message::CommandOutput(output) if output.id == request_id => {
+                    on_output(output.stderr, &output.data);
+                }
+                Message::Pong => debug!("Ignoring a late pong"),
+                Message::Disconnect(disconnect) => {
+                    return Err(self.closed_by_server(disconnect.reason).await)
+                }
+                message => break message,
+            }
+        };
 
         // Handle response
         match response_msg {
@@ -230,12 +779,811 @@ impl Client {
                 );
                 Ok(response)
             }
+            Message::Error(error) => Err(ClientError::Request(error.message)),
+            _ => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Ask the server to stop a running command: by SIGINT, or by killing
+    /// it if `force` is set
+    ///
+    /// Not answered; the command's response tells how it ended.
+    pub async fn cancel(&self, id: u64, force: bool) -> Result<()> {
+        debug!(id, force, "Cancelling command");
+        self.send(Message::CancelRequest(CancelRequest { id, force }))
+            .await
+    }
+
+    /// Set environment variables for every later command of the session
+    pub async fn set_env(&self, vars: BTreeMap<String, String>) -> Result<()> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let response = self.request(Message::SetEnv(SetEnvRequest { id, vars })).await?;
+        expect_ack(response)
+    }
+
+    /// Remove session environment variables
+    pub async fn unset_env(&self, names: Vec<String>) -> Result<()> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let response = self
+            .request(Message::UnsetEnv(UnsetEnvRequest { id, names }))
+            .await?;
+        expect_ack(response)
+    }
+
+    /// Get this identity's usage counters and the session's load
+    pub async fn stats(&self) -> Result<StatsResponse> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        match self.request(Message::StatsRequest(StatsRequest { id })).await? {
+            Message::StatsResponse(stats) => Ok(stats),
+            Message::Error(error) => Err(ClientError::Request(error.message)),
+            _ => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Read a range of a truncated command's spooled output
+    pub async fn fetch_output(
+        &self,
+        spool_id: u64,
+        stderr: bool,
+        offset: u64,
+        length: u32,
+    ) -> Result<OutputChunk> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let request = FetchOutputRequest {
+            id,
+            spool_id,
+            stderr,
+            offset,
+            length,
+        };
+        match self.request(Message::FetchOutput(request)).await? {
+            Message::OutputChunk(chunk) => Ok(chunk),
+            Message::Error(error) => Err(ClientError::Request(error.message)),
+            _ => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Read everything spooled of one output stream (empty if the stream
+    /// fit into the response)
+    pub async fn fetch_spooled(&self, spool_id: u64, stderr: bool) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        loop {
+            let chunk = self
+                .fetch_output(spool_id, stderr, data.len() as u64, MAX_CHUNK_SIZE as u32)
+                .await?;
+            if chunk.data.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk.data);
+            if data.len() as u64 >= chunk.total {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Ask the server how a partial path or command name could continue
+    pub async fn complete(&self, kind: CompletionKind, prefix: &str) -> Result<CompleteResponse> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let request = CompleteRequest {
+            id,
+            kind,
+            prefix: prefix.to_string(),
+        };
+        match self.request(Message::CompleteRequest(request)).await? {
+            Message::CompleteResponse(response) => Ok(response),
+            Message::Error(error) => Err(ClientError::Request(error.message)),
             _ => Err(ClientError::Connection(
                 "Unexpected response type".to_string(),
             )),
         }
     }
 
+    /// Upload a local file to `remote`, returning the bytes stored
+    ///
+    /// With `resume`, an interrupted upload continues where the server's
+    /// partial file ends.
+    pub async fn upload(&self, local: &Path, remote: &str, resume: bool) -> Result<u64> {
+        self.upload_with_progress(local, remote, resume, &mut |_, _| {})
+            .await
+    }
+
+    /// Upload a local file to `remote` like [`upload`](Self::upload),
+    /// calling `progress` with the bytes stored so far and the file's size
+    /// after every chunk
+    pub async fn upload_with_progress(
+        &self,
+        local: &Path,
+        remote: &str,
+        resume: bool,
+        progress: &mut (dyn FnMut(u64, u64) + Send),
+    ) -> Result<u64> {
+        let mut file = File::open(local)?;
+        let metadata = file.metadata()?;
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let mut progress = self.observe_transfer(id, Direction::Upload, remote, progress);
+        let request = UploadRequest {
+            id,
+            path: remote.to_string(),
+            size: metadata.len(),
+            sha256: hash_file(local)?,
+            mode: file_mode(&metadata),
+            resume,
+        };
+        let ready = expect_ready(self.request(Message::UploadStart(request)).await?)?;
+
+        // The final chunk, empty for an empty file, is answered with
+        // TRANSFER_COMPLETE
+        let mut offset = ready.offset;
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        progress(offset, metadata.len());
+        loop {
+            file.seek(SeekFrom::Start(offset))?;
+            let len = read_full(&mut file, &mut buf)?;
+            let chunk = FileChunk {
+                transfer_id: ready.transfer_id,
+                offset,
+                data: buf[..len].to_vec(),
+            };
+            match self.request(Message::FileChunk(chunk)).await? {
+                Message::ChunkAck(ack) => {
+                    offset = ack.offset;
+                    progress(offset, metadata.len());
+                }
+                Message::TransferComplete(complete) => {
+                    progress(complete.size, complete.size);
+                    return Ok(complete.size);
+                }
+                Message::Error(error) => return Err(ClientError::Request(error.message)),
+                _ => {
+                    return Err(ClientError::Connection(
+                        "Unexpected response type".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Download `remote` to a local file, returning its size
+    ///
+    /// Data goes to a `.part` file next to `local` that is renamed into
+    /// place once its SHA-256 matches. With `resume`, an existing partial
+    /// file is continued instead of started over.
+    pub async fn download(&self, remote: &str, local: &Path, resume: bool) -> Result<u64> {
+        self.download_with_progress(remote, local, resume, &mut |_, _| {})
+            .await
+    }
+
+    /// Download `remote` to a local file like [`download`](Self::download),
+    /// calling `progress` with the bytes received so far and the file's
+    /// size after every chunk
+    pub async fn download_with_progress(
+        &self,
+        remote: &str,
+        local: &Path,
+        resume: bool,
+        progress: &mut (dyn FnMut(u64, u64) + Send),
+    ) -> Result<u64> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let mut progress = self.observe_transfer(id, Direction::Download, remote, progress);
+        let request = DownloadRequest {
+            id,
+            path: remote.to_string(),
+        };
+        let ready = expect_ready(self.request(Message::DownloadStart(request)).await?)?;
+
+        let part_path = part_path(local);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(!resume)
+            .open(&part_path)?;
+        let mut offset = file.metadata()?.len();
+        if offset > ready.size {
+            // Stale partial file of a different download
+            file.set_len(0)?;
+            offset = 0;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        // The server forgets the transfer once the end has been read, so
+        // always ask at least once
+        loop {
+            let request = ChunkRequest {
+                transfer_id: ready.transfer_id,
+                offset,
+                max_bytes: MAX_CHUNK_SIZE as u32,
+            };
+            let chunk = match self.request(Message::ChunkRequest(request)).await? {
+                Message::FileChunk(chunk) => chunk,
+                Message::Error(error) => return Err(ClientError::Request(error.message)),
+                _ => {
+                    return Err(ClientError::Connection(
+                        "Unexpected response type".to_string(),
+                    ))
+                }
+            };
+            file.write_all(&chunk.data)?;
+            offset += chunk.data.len() as u64;
+            progress(offset, ready.size);
+            if chunk.data.is_empty() || offset >= ready.size {
+                break;
+            }
+        }
+        file.flush()?;
+        drop(file);
+
+        if hash_file(&part_path)? != ready.sha256 {
+            let _ = fs::remove_file(&part_path);
+            return Err(ClientError::Request(
+                "Hash mismatch, download discarded".to_string(),
+            ));
+        }
+        fs::rename(&part_path, local)?;
+        Ok(offset)
+    }
+
+    /// Perform a filesystem operation on the server
+    pub async fn file_op(&self, op: FileOp) -> Result<Vec<FileEntry>> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        match self.request(Message::FileOp(FileOpRequest { id, op })).await? {
+            Message::FileOpResult(result) => Ok(result.entries),
+            Message::Error(error) => Err(ClientError::Request(error.message)),
+            _ => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+        }
+    }
+
+    /// Get the banner the server sent when connecting
+    pub async fn banner(&self) -> Option<String> {
+        self.banner.read().await.clone()
+    }
+
+    /// Check whether the banner must be acknowledged before running commands
+    pub fn banner_ack_required(&self) -> bool {
+        self.banner_pending.load(Ordering::SeqCst)
+    }
+
+    /// The identity presented to the server: its name in the keystore, or
+    /// else the path it was loaded from
+    pub fn identity_name(&self) -> String {
+        Keystore::from_config(&self.config)
+            .name_of(&self.config.identity_path)
+            .unwrap_or_else(|| self.config.identity_path.display().to_string())
+    }
+
+    /// Destination hash of the identity presented to the server (hex)
+    pub fn identity_hex(&self) -> String {
+        self.config.identity.destination_hex()
+    }
+
+    /// Check whether the server's key is not in the known hosts yet, so
+    /// the user should be asked whether to trust it
+    pub fn host_key_unknown(&self) -> bool {
+        self.host_key_unknown.load(Ordering::SeqCst)
+    }
+
+    /// Fingerprint of the key the server presented
+    pub async fn host_key_fingerprint(&self) -> Option<String> {
+        self.server_key
+            .read()
+            .await
+            .as_deref()
+            .map(known_hosts::fingerprint)
+    }
+
+    /// Record the server's key in the known hosts
+    pub async fn trust_host_key(&self) -> Result<()> {
+        let key = self
+            .server_key
+            .read()
+            .await
+            .clone()
+            .ok_or(ClientError::NotConnected)?;
+        let path = &self.config.known_hosts_path;
+        known_hosts::add(path, &self.server_destination, &key)?;
+        self.host_key_unknown.store(false, Ordering::SeqCst);
+        info!("Added the server key to {}", path.display());
+        Ok(())
+    }
+
+    /// Check the key a server presented against the known hosts and the
+    /// key it presented before, trusting it if new ones are accepted
+    ///
+    /// Returns whether the known hosts recorded the key already.
+    async fn check_host_key(&self, key: &[u8]) -> Result<bool> {
+        let known = KnownHosts::load(&self.config.known_hosts_path)?;
+        let status = known.check(&self.server_destination, key);
+        let mut server_key = self.server_key.write().await;
+
+        if let HostKeyStatus::Changed { line } = status {
+            return Err(ClientError::HostKeyChanged(format!(
+                "{} is not the key recorded for {} (line {} of {}); someone may be \
+                 impersonating the server. If its key was replaced, remove that line",
+                known_hosts::fingerprint(key),
+                hex::encode(self.server_destination),
+                line,
+                known.path().display()
+            )));
+        }
+        if server_key
+            .as_deref()
+            .is_some_and(|previous| previous != key)
+        {
+            return Err(ClientError::HostKeyChanged(format!(
+                "{} is not the key the server presented before",
+                known_hosts::fingerprint(key)
+            )));
+        }
+        *server_key = Some(key.to_vec());
+        drop(server_key);
+
+        let unknown = status == HostKeyStatus::Unknown;
+        self.host_key_unknown.store(unknown, Ordering::SeqCst);
+        if unknown && self.config.accept_new_host_keys {
+            warn!(
+                fingerprint = %known_hosts::fingerprint(key),
+                "Trusting the key of a server not connected to before"
+            );
+            self.trust_host_key().await?;
+        }
+        Ok(status == HostKeyStatus::Known)
+    }
+
+    /// Acknowledge the server banner
+    pub async fn acknowledge_banner(&self) -> Result<()> {
+        expect_ack(self.request(Message::BannerAck).await?)?;
+        self.banner_pending.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// A fresh request ID
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Send a message without waiting for a reply
+    pub(crate) async fn send(&self, message: Message) -> Result<()> {
+        self.transmit(&message).await
+    }
+
+    /// Send a message, giving up after the connection timeout
+    ///
+    /// Once the session is encrypted the message goes out sealed; on an
+    /// unencrypted session the packet is signed for the session instead.
+    /// Messages go out in the order they are sent, in normal frames; only
+    /// the server orders what it sends by priority.
+    async fn transmit(&self, message: &Message) -> Result<()> {
+        let interface = self.interface.as_ref().ok_or(ClientError::NotConnected)?;
+        let cipher = self.cipher.read().unwrap().clone();
+        // Held until the packet is sent, so signed packets leave in order
+        let mut numbered = None;
+        let packet = match cipher {
+            Some(cipher) => Packet::data(
+                self.server_destination,
+                ProtocolCodec::encode(&cipher.seal(message)?)?,
+            ),
+            None => {
+                let encoded = ProtocolCodec::encode(message)?;
+                let session_id = *self.session_id.read().await;
+                let signed = match session_id {
+                    Some(session_id) => {
+                        let sequence = numbered.insert(self.packet_sequence.lock().await);
+                        **sequence += 1;
+                        let payload = packet_signing_payload(&session_id, **sequence, &encoded);
+                        Some((self.config.identity.sign(&payload)?, **sequence))
+                    }
+                    None => None,
+                };
+                let packet = Packet::data(self.server_destination, encoded);
+                match signed {
+                    Some((signature, sequence)) => {
+                        packet.with_signature(signature).with_sequence(sequence)
+                    }
+                    None => packet,
+                }
+            }
+        };
+        let limit = self.connection_timeout();
+        time::timeout(limit, interface.send(&packet))
+            .await
+            .map_err(|_| {
+                ClientError::Timeout(format!(
+                    "could not send to the server within {}s; the link may be down or \
+                     congested (raise connection_timeout on slow links)",
+                    limit.as_secs()
+                ))
+            })??;
+        self.meter.sent(packet.data.len());
+        Ok(())
+    }
+
+    /// Have the server send `data` back, returning the round-trip time
+    ///
+    /// Like a ping, an echo not answered within the connection timeout
+    /// fails; one answered with other data is an error.
+    pub async fn echo(&self, data: Vec<u8>) -> Result<Duration> {
+        let _busy = self.busy.lock().await;
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let timeout = self.connection_timeout();
+        let start = Instant::now();
+        let echo = EchoMessage { id, data };
+        let request = Message::Echo(echo.clone());
+        match time::timeout(timeout, self.exchange(&request)).await {
+            Ok(Ok(Message::EchoReply(reply))) if reply == echo => Ok(start.elapsed()),
+            Ok(Ok(Message::EchoReply(_))) => Err(ClientError::Connection(
+                "The echo came back changed".to_string(),
+            )),
+            Ok(Ok(Message::Error(error))) => Err(ClientError::Request(error.message)),
+            Ok(Ok(_)) => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ClientError::Timeout(format!(
+                "no echo within {}s",
+                timeout.as_secs()
+            ))),
+        }
+    }
+
+    /// How long to wait for the server to answer
+    fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.connection_timeout)
+    }
+
+    /// Keep the connection to the caller, who reads every message with
+    /// [`receive`](Self::receive), until the guard is dropped
+    pub(crate) async fn exclusive(&self) -> MutexGuard<'_, ()> {
+        self.busy.lock().await
+    }
+
+    /// Wait for the next message from the server, however long it takes
+    pub(crate) async fn receive(&self) -> Result<Message> {
+        let interface = self.interface.as_ref().ok_or(ClientError::NotConnected)?;
+        let packet = receive_data(interface.as_ref(), &self.meter).await?;
+        self.decode(&packet)?
+            .ok_or_else(|| ClientError::Connection("No response from server".to_string()))
+    }
+
+    /// Send a request and wait for the reply
+    ///
+    /// If the connection breaks, the request is sent again after
+    /// reconnecting, as it was never answered. Requests that are safe to
+    /// repeat are also retried after other transport failures, such as a
+    /// reply that went missing.
+    async fn request(&self, message: Message) -> Result<Message> {
+        self.recover_if_lost().await?;
+        if !retry::idempotent(&message) {
+            return self.request_reconnecting(&message).await;
+        }
+        RetryPolicy::from_config(&self.config)
+            .run("the request", || self.request_reconnecting(&message))
+            .await
+    }
+
+    /// Send a request and wait for the reply, sending it again after
+    /// reconnecting if the connection breaks
+    async fn request_reconnecting(&self, message: &Message) -> Result<Message> {
+        match self.request_once(message).await {
+            Err(e) if self.should_reconnect(&e) => {
+                self.recover(&e).await?;
+                self.request_once(message).await
+            }
+            result => result,
+        }
+    }
+
+    async fn request_once(&self, message: &Message) -> Result<Message> {
+        let _busy = self.busy.lock().await;
+        self.exchange(message).await
+    }
+
+    /// Send a message and read the reply; the caller holds `busy`
+    async fn exchange(&self, message: &Message) -> Result<Message> {
+        {
+            let state = self.state.read().await;
+            if *state != ConnectionState::Connected {
+                return Err(ClientError::NotConnected);
+            }
+        }
+        let interface = self.interface.as_ref().ok_or(ClientError::NotConnected)?;
+        self.transmit(message).await?;
+
+        let deadline = Instant::now() + self.connection_timeout();
+        loop {
+            let response_packet = receive_within(
+                interface.as_ref(),
+                &self.meter,
+                deadline.saturating_duration_since(Instant::now()),
+                "reply",
+                "the connection may be down (raise connection_timeout on slow links)",
+            )
+            .await?;
+            match self.decode(&response_packet)? {
+                // Answers a ping given up on
+                Some(Message::Pong) if !matches!(message, Message::Ping) => {
+                    debug!("Ignoring a late pong");
+                }
+                Some(Message::Disconnect(disconnect)) => {
+                    return Err(self.closed_by_server(disconnect.reason).await)
+                }
+                response => {
+                    return response.ok_or_else(|| {
+                        ClientError::Connection("No response from server".to_string())
+                    })
+                }
+            }
+        }
+    }
+
+    /// Complete the Noise handshake with the server's `second` message and
+    /// wait for the server to confirm it
+    ///
+    /// The server must prove it holds the key of `server_identity`, the one
+    /// checked against the known hosts.
+    async fn finish_handshake(
+        &self,
+        interface: &Arc<dyn NetworkInterface>,
+        handshake: Handshake,
+        second: &[u8],
+        server_identity: &[u8],
+    ) -> Result<()> {
+        let (cipher, third) = handshake.complete(second)?;
+        if cipher.remote_static() != &Identity::x25519_public_key(server_identity)? {
+            return Err(ClientError::Connection(
+                "The server's handshake key does not match its identity".to_string(),
+            ));
+        }
+        self.transmit(&Message::Handshake(HandshakeMessage { noise: third })).await?;
+
+        let packet = receive_within(
+            interface.as_ref(),
+            &self.meter,
+            self.connection_timeout(),
+            "handshake confirmation",
+            "raise connection_timeout on slow links",
+        )
+        .await?;
+        let mut buf = bytes::BytesMut::from(packet.data.as_ref());
+        match ProtocolCodec::decode(&mut buf)? {
+            Some(Message::Sealed(sealed)) => match cipher.open(&sealed)? {
+                Message::Ack(_) => {}
+                _ => {
+                    return Err(ClientError::Connection(
+                        "Unexpected response to the handshake".to_string(),
+                    ))
+                }
+            },
+            Some(Message::Reject(reject)) => return Err(ClientError::Rejected(reject.reason)),
+            _ => {
+                return Err(ClientError::Connection(
+                    "Unexpected response to the handshake".to_string(),
+                ))
+            }
+        }
+
+        *self.cipher.write().unwrap() = Some(Arc::new(cipher));
+        info!("Session encrypted");
+        Ok(())
+    }
+
+    /// Whether the session is encrypted (Noise)
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.read().unwrap().is_some()
+    }
+
+    /// Decode the message in `packet`, opening it if the session is
+    /// encrypted
+    ///
+    /// Once it is, anything not sealed with its keys is refused.
+    fn decode(&self, packet: &Packet) -> Result<Option<Message>> {
+        let mut buf = bytes::BytesMut::from(packet.data.as_ref());
+        let message = ProtocolCodec::decode(&mut buf)?;
+        let cipher = self.cipher.read().unwrap().clone();
+        match (cipher, message) {
+            (Some(cipher), Some(Message::Sealed(sealed))) => Ok(Some(cipher.open(&sealed)?)),
+            (Some(_), Some(_)) => Err(ClientError::Connection(
+                "Unencrypted message on an encrypted session".to_string(),
+            )),
+            (None, Some(Message::Sealed(_))) => Err(ClientError::Connection(
+                "Encrypted message before the handshake".to_string(),
+            )),
+            (_, message) => Ok(message),
+        }
+    }
+
+    /// The server closed the session, giving `reason`: tell the event
+    /// handler, and the error to fail the request with
+    async fn closed_by_server(&self, reason: Option<String>) -> ClientError {
+        warn!(reason = ?reason, "The server closed the session");
+        *self.state.write().await = ConnectionState::Disconnected;
+        let error = ClientError::Connection(match &reason {
+            Some(reason) => format!("The server closed the session: {}", reason),
+            None => "The server closed the session".to_string(),
+        });
+        self.emit(|events| events.on_disconnect(&DisconnectReason::Closed(reason)));
+        error
+    }
+
+    /// Pass output of request `id` to the transcript and the event handler
+    fn observe_output(&self, id: u64, stderr: bool, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(transcript) = &self.transcript {
+            transcript.output(data);
+        }
+        self.emit(|events| events.on_output(id, stderr, data));
+    }
+
+    /// `progress`, also telling the event handler of the progress of
+    /// transfer `id` of `path`
+    fn observe_transfer<'a>(
+        &'a self,
+        id: u64,
+        direction: Direction,
+        path: &'a str,
+        progress: &'a mut (dyn FnMut(u64, u64) + Send),
+    ) -> impl FnMut(u64, u64) + 'a {
+        move |bytes, size| {
+            self.emit(|events| {
+                events.on_transfer_progress(&TransferProgress {
+                    request_id: id,
+                    direction,
+                    path: path.to_string(),
+                    bytes,
+                    size,
+                })
+            });
+            progress(bytes, size);
+        }
+    }
+
+    /// Call the event handler, if there is one
+    fn emit(&self, event: impl FnOnce(&dyn EventHandler)) {
+        if let Some(events) = &self.events {
+            event(events.as_ref());
+        }
+    }
+
+    /// Add a record of a command, sent at `started`, to the audit log (if
+    /// one is kept)
+    pub(crate) fn audit(
+        &self,
+        command: Option<&str>,
+        args: &[String],
+        cwd: Option<&str>,
+        pty: bool,
+        started: DateTime<Utc>,
+        outcome: &Outcome,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let server = hex::encode(self.server_destination);
+        let entry = Entry {
+            server: &server,
+            command,
+            args,
+            cwd,
+            pty,
+        };
+        let elapsed = (Utc::now() - started).to_std().unwrap_or_default();
+        audit.record(&entry, started, elapsed, outcome);
+    }
+
+    /// Environment for a command: local variables matching `send_env`, the
+    /// configured ones, then those of `options`
+    fn command_env(&self, options: &CommandOptions) -> Option<BTreeMap<String, String>> {
+        let mut env = crate::env::matching(&self.config.send_env, std::env::vars());
+        env.extend(self.config.env.clone());
+        env.extend(options.env.clone());
+        (!env.is_empty()).then_some(env)
+    }
+
+    /// Reconnect if keepalive pings found the connection lost
+    async fn recover_if_lost(&self) -> Result<()> {
+        if self.lost.swap(false, Ordering::SeqCst) && self.config.reconnect_attempts > 0 {
+            let cause = ClientError::Connection("The server stopped answering pings".to_string());
+            self.recover(&cause).await?;
+        }
+        Ok(())
+    }
+
+    /// Check whether `error` means the connection broke and should be
+    /// re-established
+    fn should_reconnect(&self, error: &ClientError) -> bool {
+        self.config.reconnect_attempts > 0
+            && matches!(error, ClientError::Network(_) | ClientError::Io(_))
+    }
+
+    /// Reconnect after the connection broke with `cause`, resuming the
+    /// session
+    ///
+    /// Waits between attempts start at `reconnect_delay` and double up to
+    /// [`MAX_RECONNECT_DELAY`].
+    async fn recover(&self, cause: &ClientError) -> Result<()> {
+        warn!(error = %cause, "Connection lost, reconnecting");
+        let interface = self.interface.as_ref().ok_or(ClientError::NotConnected)?;
+        let previous = self.session_id().await;
+        let acknowledged = !self.banner_ack_required();
+        let attempts = self.config.reconnect_attempts;
+        let timeout = self.connection_timeout();
+        let mut delay = Duration::from_secs(self.config.reconnect_delay);
+
+        for attempt in 1..=attempts {
+            let result = match interface.reopen().await {
+                Ok(()) => time::timeout(timeout, self.reconnect_once())
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(ClientError::Timeout(format!(
+                            "reconnecting took over {}s",
+                            timeout.as_secs()
+                        )))
+                    }),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => {
+                    info!(attempt, "Reconnected");
+                    let new_session = self.session_id().await != previous;
+                    if new_session {
+                        warn!("The server started a new session; session state was lost");
+                    }
+                    self.emit(|events| {
+                        events.on_notification(&Notification::Reconnected { new_session })
+                    });
+                    // The banner was accepted before; a new session asks again
+                    if acknowledged && self.banner_ack_required() {
+                        expect_ack(self.request_once(&Message::BannerAck).await?)?;
+                        self.banner_pending.store(false, Ordering::SeqCst);
+                    }
+                    return Ok(());
+                }
+                // Asking again won't change the server's mind
+                Err(e @ (ClientError::Rejected(_) | ClientError::HostKeyChanged(_))) => {
+                    return Err(e)
+                }
+                Err(e) => warn!(attempt, error = %e, "Reconnect failed"),
+            }
+            if attempt < attempts {
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+
+        *self.state.write().await = ConnectionState::Disconnected;
+        let error = format!(
+            "Connection lost ({}) and {} reconnect attempts failed",
+            cause, attempts
+        );
+        self.emit(|events| events.on_disconnect(&DisconnectReason::Lost(error.clone())));
+        Err(ClientError::Connection(error))
+    }
+
+    /// Connect again, e.g. after the server restarted
+    ///
+    /// Reattaches to the previous session if the server persisted it.
+    pub async fn reconnect(&self) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            *state = ConnectionState::Disconnected;
+        }
+        self.connect().await
+    }
+
+    /// Connect again, once; [`recover`](Self::recover) does the retrying
+    async fn reconnect_once(&self) -> Result<()> {
+        *self.state.write().await = ConnectionState::Disconnected;
+        self.connect_once().await
+    }
+
     /// Disconnect from server
     pub async fn disconnect(&self) -> Result<()> {
         {
@@ -264,16 +1612,287 @@ impl Client {
             *session = None;
         }
 
+        {
+            let mut token = self.resume_token.write().await;
+            *token = None;
+        }
+
+        {
+            let mut accepted = self.accepted.write().await;
+            *accepted = None;
+        }
+
         info!("Disconnected");
 
         Ok(())
     }
 
+    /// Get the current session ID
+    pub async fn session_id(&self) -> Option<SessionId> {
+        *self.session_id.read().await
+    }
+
     /// Check if connected
     pub async fn is_connected(&self) -> bool {
         let state = self.state.read().await;
         *state == ConnectionState::Connected
     }
+
+    /// Ping the server, returning the round-trip time
+    ///
+    /// A ping not answered within the connection timeout counts as lost.
+    pub async fn ping(&self) -> Result<Duration> {
+        let _busy = self.busy.lock().await;
+        self.timed_ping(self.connection_timeout()).await
+    }
+
+    /// Ping the server unless a request is under way (`None`)
+    pub(crate) async fn ping_if_idle(&self, timeout: Duration) -> Option<Result<Duration>> {
+        let _busy = self.busy.try_lock().ok()?;
+        Some(self.timed_ping(timeout).await)
+    }
+
+    /// Ping, counting it lost if not answered within `timeout`; the caller
+    /// holds `busy`
+    async fn timed_ping(&self, timeout: Duration) -> Result<Duration> {
+        let start = Instant::now();
+        let result = match time::timeout(timeout, self.exchange(&Message::Ping)).await {
+            Ok(Ok(Message::Pong)) => Ok(start.elapsed()),
+            Ok(Ok(_)) => Err(ClientError::Connection(
+                "Unexpected response type".to_string(),
+            )),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ClientError::Timeout(format!(
+                "no pong within {}s",
+                timeout.as_secs()
+            ))),
+        };
+        match &result {
+            Ok(rtt) => self.meter.ping(Some(*rtt)),
+            Err(ClientError::Timeout(_) | ClientError::Network(_) | ClientError::Io(_)) => {
+                self.meter.ping(None)
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// How long to wait without hearing from the server before pinging it:
+    /// the configured interval, or the server's if shorter (None = don't
+    /// ping)
+    pub(crate) async fn keepalive_interval(&self) -> Option<Duration> {
+        let configured = self.config.keepalive_interval;
+        let requested = self
+            .accepted
+            .read()
+            .await
+            .as_ref()
+            .and_then(|accepted| accepted.keepalive_interval)
+            .unwrap_or(configured);
+        (configured > 0).then(|| Duration::from_secs(configured.min(requested).max(1)))
+    }
+
+    /// Transcript of the session, if one is kept
+    pub(crate) fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_deref()
+    }
+
+    /// When interactive terminals echo keystrokes before the server does
+    pub(crate) fn prediction(&self) -> Prediction {
+        self.config.predict
+    }
+
+    /// Time since anything was received from the server
+    pub(crate) fn idle_time(&self) -> Duration {
+        self.meter.idle_time()
+    }
+
+    /// Take the connection as lost: requests fail, or reconnect first if
+    /// reconnecting is enabled
+    pub(crate) async fn connection_lost(&self) {
+        warn!("The server stopped answering pings");
+        *self.state.write().await = ConnectionState::Disconnected;
+        self.lost.store(true, Ordering::SeqCst);
+        self.emit(|events| {
+            events.on_disconnect(&DisconnectReason::Lost(
+                "The server stopped answering pings".to_string(),
+            ))
+        });
+    }
+
+    /// The server announced `capability` in ACCEPT
+    async fn has_capability(&self, capability: &str) -> bool {
+        self.accepted
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|accepted| accepted.capabilities.iter().any(|c| c == capability))
+    }
+
+    /// How the connection is doing; the round-trip time is that of the
+    /// pings sent so far
+    pub async fn health(&self) -> Health {
+        let mut health = Health {
+            connected: self.is_connected().await,
+            ..Health::default()
+        };
+        if let Some(accepted) = &*self.accepted.read().await {
+            health.session_age = Some(accepted.at.elapsed());
+            health.protocol_version = Some(accepted.protocol_version);
+            health.capabilities = accepted.capabilities.clone();
+        }
+        self.meter.fill(&mut health);
+        health
+    }
+}
+
+/// Map an ACK reply to success and an ERROR reply to its message
+fn expect_ack(response: Message) -> Result<()> {
+    match response {
+        Message::Ack(_) => Ok(()),
+        Message::Error(error) => Err(ClientError::Request(error.message)),
+        _ => Err(ClientError::Connection(
+            "Unexpected response type".to_string(),
+        )),
+    }
+}
+
+/// Check for a TRANSFER_READY reply
+fn expect_ready(response: Message) -> Result<TransferReady> {
+    match response {
+        Message::TransferReady(ready) => Ok(ready),
+        Message::Error(error) => Err(ClientError::Request(error.message)),
+        _ => Err(ClientError::Connection(
+            "Unexpected response type".to_string(),
+        )),
+    }
+}
+
+/// Path of the partial file for a download destination
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Compute the SHA-256 of a file
+fn hash_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Read until `buf` is full or the file ends
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Permission bits to give an uploaded copy
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+/// The server's default permissions off Unix
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Receive the next data packet, skipping announces and other control
+/// traffic sharing the interface
+/// The next interrupt, or never without a channel
+async fn next_interrupt(interrupts: Option<&mut mpsc::UnboundedReceiver<()>>) -> Option<()> {
+    match interrupts {
+        Some(interrupts) => interrupts.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next chunk of command input, `None` once it ended
+async fn next_input(input: Option<&mut mpsc::Receiver<Vec<u8>>>) -> Option<Vec<u8>> {
+    match input {
+        Some(input) => input.recv().await,
+        None => None,
+    }
+}
+
+/// Wait up to `limit` for the next data packet, timing out with what was
+/// waited for and a `hint` on what to check
+async fn receive_within(
+    interface: &dyn NetworkInterface,
+    meter: &Meter,
+    limit: Duration,
+    waiting_for: &str,
+    hint: &str,
+) -> Result<Packet> {
+    time::timeout(limit, receive_data(interface, meter))
+        .await
+        .map_err(|_| {
+            ClientError::Timeout(format!(
+                "no {} from the server within {}s; {}",
+                waiting_for,
+                limit.as_secs(),
+                hint
+            ))
+        })?
+}
+
+async fn receive_data(interface: &dyn NetworkInterface, meter: &Meter) -> Result<Packet> {
+    loop {
+        let packet = interface.receive().await?;
+        if packet.packet_type == PacketType::Data {
+            meter.received(packet.data.len());
+            return Ok(packet);
+        }
+        debug!(packet_type = ?packet.packet_type, "Skipping non-data packet");
+    }
+}
+
+/// The X25519 key of the client's identity, for the handshake
+struct IdentityKey {
+    identity: Arc<dyn Signer>,
+    public_key: [u8; 32],
+}
+
+impl IdentityKey {
+    fn new(identity: Arc<dyn Signer>) -> Result<Self> {
+        let public_key = Identity::x25519_public_key(&identity.public_key())?;
+        Ok(Self {
+            identity,
+            public_key,
+        })
+    }
+}
+
+impl StaticKey for IdentityKey {
+    fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    fn agree(&self, public_key: &[u8; 32]) -> shell_proto::Result<[u8; 32]> {
+        self.identity
+            .agree(public_key)
+            .map_err(|e| ProtocolError::Crypto(e.to_string()))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,185 @@
+//! Remote tab completion for the REPL
+//!
+//! The first word of a line completes to command names, later words to
+//! paths, both asked of the server with COMPLETE_REQUEST. Over I2P a round
+//! trip can take seconds, so answers are cached: typing further into a word
+//! filters a cached answer locally instead of asking again. The cache is
+//! dropped after every command, which may have changed the working directory
+//! or the files in it.
+
+use crate::client::Client;
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use shell_proto::CompletionKind;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tracing::debug;
+
+/// How long a cached answer is used
+pub const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// A server answer kept for later keystrokes
+#[derive(Debug, Clone)]
+struct Cached {
+    fetched_at: Instant,
+    candidates: Vec<String>,
+    truncated: bool,
+}
+
+/// Readline helper completing against the server
+pub struct RemoteCompleter {
+    /// Client connection
+    client: Arc<Client>,
+
+    /// Runtime the client runs on
+    runtime: Handle,
+
+    /// Answers by kind and the prefix they were asked for
+    cache: Mutex<HashMap<(CompletionKind, String), Cached>>,
+}
+
+impl RemoteCompleter {
+    /// Create a completer; must be called within the client's runtime
+    pub fn new(client: Arc<Client>) -> Self {
+        Self {
+            client,
+            runtime: Handle::current(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forget every cached answer
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Candidates for `prefix`, from the cache if a fresh answer covers it
+    fn candidates(&self, kind: CompletionKind, prefix: &str) -> Vec<String> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, cached| cached.fetched_at.elapsed() < CACHE_TTL);
+            let hit = cache.iter().find(|((cached_kind, cached_prefix), cached)| {
+                *cached_kind == kind && !cached.truncated && covers(cached_prefix, prefix)
+            });
+            if let Some((_, cached)) = hit {
+                return cached
+                    .candidates
+                    .iter()
+                    .filter(|candidate| candidate.starts_with(prefix))
+                    .cloned()
+                    .collect();
+            }
+        }
+
+        // The REPL waits in readline, so nothing else uses the connection
+        let response = tokio::task::block_in_place(|| {
+            self.runtime.block_on(self.client.complete(kind, prefix))
+        });
+        match response {
+            Ok(response) => {
+                let candidates = response.candidates.clone();
+                self.cache.lock().unwrap().insert(
+                    (kind, prefix.to_string()),
+                    Cached {
+                        fetched_at: Instant::now(),
+                        candidates: response.candidates,
+                        truncated: response.truncated,
+                    },
+                );
+                candidates
+            }
+            Err(e) => {
+                debug!("Completion failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Completer for RemoteCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, word) = current_word(line, pos);
+        let kind = if line[..start].trim().is_empty() {
+            CompletionKind::Command
+        } else {
+            CompletionKind::Path
+        };
+
+        // Show only what follows the directory part, like a local shell
+        let dir_len = word.rfind('/').map_or(0, |i| i + 1);
+        let pairs = self
+            .candidates(kind, word)
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate[dir_len..].to_string(),
+                replacement: shell_words::quote(&candidate).into_owned(),
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for RemoteCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for RemoteCompleter {}
+
+impl Validator for RemoteCompleter {}
+
+impl Helper for RemoteCompleter {}
+
+/// The whitespace-separated word ending at `pos`, and where it starts
+fn current_word(line: &str, pos: usize) -> (usize, &str) {
+    let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    (start, &line[start..pos])
+}
+
+/// Check whether the answer for `cached` holds every candidate for `prefix`
+///
+/// That is the case while `prefix` extends `cached` within the same
+/// directory, unless it starts asking for hidden entries the answer left out.
+fn covers(cached: &str, prefix: &str) -> bool {
+    let Some(rest) = prefix.strip_prefix(cached) else {
+        return false;
+    };
+    let cached_partial = &cached[cached.rfind('/').map_or(0, |i| i + 1)..];
+    let partial = &prefix[prefix.rfind('/').map_or(0, |i| i + 1)..];
+    !rest.contains('/') && !(cached_partial.is_empty() && partial.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_word() {
+        assert_eq!(current_word("ls", 2), (0, "ls"));
+        assert_eq!(current_word("cat /etc/ho", 11), (4, "/etc/ho"));
+        assert_eq!(current_word("cat ", 4), (4, ""));
+        assert_eq!(current_word("cat /etc/hosts", 5), (4, "/"));
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers("/etc/", "/etc/ho"));
+        assert!(covers("/etc/h", "/etc/hos"));
+        assert!(covers("", "ls"));
+        assert!(!covers("/etc/", "/etc/ssh/"));
+        assert!(!covers("/etc/ho", "/etc/h"));
+        assert!(!covers("/etc/", "/etc/.pw"));
+        assert!(covers("/etc/.p", "/etc/.pw"));
+    }
+}
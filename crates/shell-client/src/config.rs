@@ -1,31 +1,118 @@
 //! Client configuration
 
-use crate::{ClientError, Result};
-use reticulum_core::Identity;
+use crate::{keystore::Keystore, predict::Prediction, ClientError, Result};
+use reticulum_core::{Identity, ShareString, Signer};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     /// Client identity (loaded, not serialized as private key)
     #[serde(skip, default = "default_identity")]
-    pub identity: Identity,
+    pub identity: Arc<dyn Signer>,
 
     /// Path to identity file
     pub identity_path: PathBuf,
 
+    /// Use the identity through the key agent on this socket (see
+    /// rsh-agent) instead of loading `identity_path`; `-i` and the profiles'
+    /// `identity_path` still load files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_agent: Option<PathBuf>,
+
+    /// Public key (hex) of the agent's identity to use; needed when it holds
+    /// several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_agent_key: Option<String>,
+
+    /// Directory of named identities to pick from with `-i`
+    #[serde(default = "default_keystore_path")]
+    pub keystore_path: PathBuf,
+
     /// Server destination (hex string)
     pub server_destination: String,
 
-    /// Connection timeout (seconds)
+    /// File with the identity keys of servers connected to before
+    #[serde(default = "default_known_hosts_path")]
+    pub known_hosts_path: PathBuf,
+
+    /// Trust the key of a server not connected to before without asking
+    #[serde(default)]
+    pub accept_new_host_keys: bool,
+
+    /// Refuse servers that do not encrypt the session (Noise handshake);
+    /// turning it off lets an attacker on the path strip the encryption
+    #[serde(default = "default_require_noise")]
+    pub require_noise: bool,
+
+    /// How long to wait for the server to accept a connection or answer a
+    /// request, and for a message to go out (seconds)
     #[serde(default = "default_connection_timeout")]
     pub connection_timeout: u64,
 
-    /// Command timeout (seconds)
+    /// Command timeout (seconds); the client waits this long plus
+    /// `connection_timeout` for the response
     #[serde(default = "default_command_timeout")]
     pub command_timeout: u64,
 
+    /// Attempts to reconnect after the connection broke (0 = don't)
+    #[serde(default = "default_reconnect_attempts")]
+    pub reconnect_attempts: u32,
+
+    /// Wait after the first failed reconnect attempt (seconds), doubled
+    /// after every further one
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay: u64,
+
+    /// Ping the server after this long without hearing from it (seconds,
+    /// 0 = don't); a server asking for less gets that
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+
+    /// Further attempts at connecting, or at a request that is safe to
+    /// repeat, after a transport failure such as a lost datagram (0 = don't)
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+
+    /// Wait before the first retry (milliseconds), doubled before every
+    /// further one
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+
+    /// Most that is added to each wait before a retry at random
+    /// (milliseconds)
+    #[serde(default = "default_retry_jitter_ms")]
+    pub retry_jitter_ms: u64,
+
+    /// Unix socket a `--master` client shares its session on, and that
+    /// `-e` runs commands through while a master listens (`%d` stands for
+    /// the server destination)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_path: Option<PathBuf>,
+
+    /// Append a record of every command run to this file (JSON lines)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log_path: Option<PathBuf>,
+
+    /// Regular expressions whose matches are replaced in the audit log
+    /// (e.g. `--password[= ]\S+`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit_redact: Vec<String>,
+
+    /// Append a transcript of the commands run and their output to this
+    /// file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_log_path: Option<PathBuf>,
+
+    /// Append the timing of the transcript to this file, for `scriptreplay`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_timing_path: Option<PathBuf>,
+
     /// Enable I2P transport
     #[serde(default)]
     pub enable_i2p: bool,
@@ -46,14 +133,111 @@ pub struct ClientConfig {
     /// Server I2P destination (base64 string, if using I2P)
     #[serde(default)]
     pub server_i2p_destination: Option<String>,
+
+    /// Reach the server over TCP at this address instead of I2P
+    #[serde(default)]
+    pub server_tcp_address: Option<SocketAddr>,
+
+    /// Auth token presented when connecting (if the server requires one)
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// Server-side secrets passed to every command as environment
+    /// variables (names only; the server holds the values)
+    #[serde(default)]
+    pub secrets: Vec<String>,
+
+    /// Local environment variables passed to every command, by name
+    /// (`*` and `?` globs, e.g. `LC_*`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub send_env: Vec<String>,
+
+    /// Environment variables set for every command
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+
+    /// Echo keystrokes in interactive terminals before the server does
+    /// (never, adaptive or always)
+    #[serde(default)]
+    pub predict: Prediction,
+
+    /// Servers to connect to by name (`--profile`, `connect` in the REPL)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<ServerProfile>,
+}
+
+/// A named server
+///
+/// Selecting a profile replaces the top-level server settings: its
+/// destination, I2P destination and TCP address stand in for
+/// `server_destination`, `server_i2p_destination` and `server_tcp_address`
+/// (unset ones are cleared), and set identities, tokens and timeouts win over
+/// the top-level ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerProfile {
+    /// Name to select the profile by
+    pub name: String,
+
+    /// Server destination (hex string)
+    #[serde(default)]
+    pub destination: Option<String>,
+
+    /// Server I2P destination (base64 string); enables I2P
+    #[serde(default)]
+    pub i2p_destination: Option<String>,
+
+    /// Reach the server over TCP at this address
+    #[serde(default)]
+    pub tcp_address: Option<SocketAddr>,
+
+    /// Identity to present to this server
+    #[serde(default)]
+    pub identity_path: Option<PathBuf>,
+
+    /// Auth token for this server
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// Connection timeout (seconds)
+    #[serde(default)]
+    pub connection_timeout: Option<u64>,
+
+    /// Command timeout (seconds)
+    #[serde(default)]
+    pub command_timeout: Option<u64>,
+}
+
+impl ServerProfile {
+    /// A profile named `name` for the server of a share string
+    pub fn from_share(name: &str, share: &ShareString) -> Self {
+        Self {
+            name: name.to_string(),
+            destination: Some(hex::encode(share.destination())),
+            i2p_destination: share.i2p_destination.clone(),
+            tcp_address: share.tcp_address,
+            ..Self::default()
+        }
+    }
 }
 
 fn default_sam_address() -> String {
     "127.0.0.1:7656".to_string()
 }
 
-fn default_identity() -> Identity {
-    Identity::generate()
+fn default_identity() -> Arc<dyn Signer> {
+    Arc::new(Identity::generate())
+}
+
+fn default_keystore_path() -> PathBuf {
+    PathBuf::from("identities")
+}
+
+fn default_known_hosts_path() -> PathBuf {
+    PathBuf::from("known_hosts")
+}
+
+fn default_require_noise() -> bool {
+    true
 }
 
 fn default_connection_timeout() -> u64 {
@@ -64,33 +248,264 @@ fn default_command_timeout() -> u64 {
     300 // 5 minutes
 }
 
+fn default_reconnect_attempts() -> u32 {
+    5
+}
+
+fn default_reconnect_delay() -> u64 {
+    1
+}
+
+fn default_keepalive_interval() -> u64 {
+    60
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_retry_jitter_ms() -> u64 {
+    250
+}
+
 impl ClientConfig {
     /// Load configuration from TOML file
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_identity(path, None)
+    }
+
+    /// Load configuration from TOML file, presenting the identity
+    /// `name_or_path` (see [`use_identity`](Self::use_identity)) if given
+    pub fn load_with_identity<P: AsRef<Path>>(path: P, name_or_path: Option<&str>) -> Result<Self> {
         let contents = std::fs::read_to_string(path)?;
         let mut config: ClientConfig = toml::from_str(&contents)
             .map_err(|e| ClientError::Config(format!("Failed to parse config: {}", e)))?;
 
-        // Load identity
-        config.identity = Identity::load_from_file(&config.identity_path)?;
+        if let Some(name_or_path) = name_or_path {
+            config.use_identity(name_or_path)?;
+            return Ok(config);
+        }
+
+        config.identity = config.load_identity()?;
+
+        Ok(config)
+    }
+
+    /// Load the identity: through the key agent if one is configured,
+    /// otherwise from `identity_path`, asking for its passphrase if it is
+    /// encrypted
+    pub fn load_identity(&self) -> Result<Arc<dyn Signer>> {
+        Ok(match &self.identity_agent {
+            Some(socket) => agent_identity(socket, self.identity_agent_key.as_deref())?,
+            None => Arc::new(file_identity(&self.identity_path)?),
+        })
+    }
+
+    /// Present the identity `name_or_path`, a name in the keystore or the
+    /// path of an identity file, to every server: instead of
+    /// `identity_path` and those of the profiles
+    pub fn use_identity(&mut self, name_or_path: &str) -> Result<()> {
+        let path = Keystore::from_config(self).resolve(name_or_path)?;
+        self.identity = Arc::new(file_identity(&path)?);
+        self.identity_path = path;
+        for profile in &mut self.servers {
+            profile.identity_path = None;
+        }
+        Ok(())
+    }
+
+    /// Check the configuration file at `path` and the identity it refers to
+    /// without connecting anywhere, returning one `<setting>: <problem>`
+    /// line per problem
+    pub fn check_file<P: AsRef<Path>>(path: P) -> Vec<String> {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return vec![format!("config: {}: {}", path.display(), e)],
+        };
+        let config: ClientConfig = match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => return vec![format!("config: {}", e.to_string().trim_end())],
+        };
+
+        let mut problems = Vec::new();
+        let identity = &config.identity_path;
+        if let Some(socket) = &config.identity_agent {
+            if let Err(e) = agent_identity(socket, config.identity_agent_key.as_deref()) {
+                problems.push(format!("identity_agent: {}", e));
+            }
+        } else if !identity.exists() {
+            problems.push(format!(
+                "identity_path: {} does not exist (create it with --generate-identity)",
+                identity.display()
+            ));
+        } else {
+            if let Err(e) = Identity::check_file(identity) {
+                problems.push(format!("identity_path: {}: {}", identity.display(), e));
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(identity)
+                    .map(|metadata| metadata.permissions().mode() & 0o777)
+                    .unwrap_or(0);
+                if mode & 0o077 != 0 {
+                    problems.push(format!(
+                        "identity_path: {} is accessible by other users (mode {:o})",
+                        identity.display(),
+                        mode
+                    ));
+                }
+            }
+        }
+
+        if let Err(e) = config.parse_server_destination() {
+            problems.push(format!("server_destination: {}", e));
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for profile in &config.servers {
+            let setting = format!("servers.{}", profile.name);
+            if profile.name.is_empty() {
+                problems.push("servers: a profile has no name".to_string());
+            } else if !names.insert(&profile.name) {
+                problems.push(format!("{}: defined more than once", setting));
+            }
+            if profile.destination.is_none()
+                && profile.i2p_destination.is_none()
+                && profile.tcp_address.is_none()
+            {
+                problems.push(format!(
+                    "{}: needs a destination, i2p_destination or tcp_address",
+                    setting
+                ));
+            }
+            if profile.destination.is_some() {
+                if let Err(e) = config.with_server(profile).parse_server_destination() {
+                    problems.push(format!("{}.destination: {}", setting, e));
+                }
+            }
+            if let Some(identity) = &profile.identity_path {
+                if let Err(e) = Identity::check_file(identity) {
+                    problems.push(format!(
+                        "{}.identity_path: {}: {}",
+                        setting,
+                        identity.display(),
+                        e
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Names of the server profiles
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.servers
+            .iter()
+            .map(|profile| profile.name.as_str())
+            .collect()
+    }
 
+    /// This configuration with the server profile `name` selected
+    pub fn with_profile(&self, name: &str) -> Result<Self> {
+        let profile = self
+            .servers
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| ClientError::Config(format!("No server profile named {:?}", name)))?;
+        let mut config = self.with_server(profile);
+        if let Some(identity_path) = &profile.identity_path {
+            config.identity = Arc::new(file_identity(identity_path)?);
+        }
         Ok(config)
     }
 
+    /// Add `profile`, also as a `[[servers]]` entry at the end of the
+    /// configuration file at `path`; the rest of the file is left as it is
+    pub fn add_profile<P: AsRef<Path>>(&mut self, path: P, profile: ServerProfile) -> Result<()> {
+        if profile.name.is_empty() {
+            return Err(ClientError::Config("A profile needs a name".to_string()));
+        }
+        if self.servers.iter().any(|known| known.name == profile.name) {
+            return Err(ClientError::Config(format!(
+                "A server profile named {:?} already exists",
+                profile.name
+            )));
+        }
+
+        let entry = toml::to_string(&profile)
+            .map_err(|e| ClientError::Config(format!("Failed to serialize profile: {}", e)))?;
+        let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+        write!(file, "\n[[servers]]\n{}", entry)?;
+        self.servers.push(profile);
+        Ok(())
+    }
+
+    /// Apply a profile's settings, except for loading its identity
+    fn with_server(&self, profile: &ServerProfile) -> Self {
+        let mut config = self.clone();
+        config.server_destination = profile.destination.clone().unwrap_or_default();
+        config.server_i2p_destination = profile.i2p_destination.clone();
+        config.server_tcp_address = profile.tcp_address;
+        config.enable_i2p = profile.i2p_destination.is_some();
+        if let Some(identity_path) = &profile.identity_path {
+            config.identity_path = identity_path.clone();
+        }
+        if profile.auth_token.is_some() {
+            config.auth_token = profile.auth_token.clone();
+        }
+        if let Some(timeout) = profile.connection_timeout {
+            config.connection_timeout = timeout;
+        }
+        if let Some(timeout) = profile.command_timeout {
+            config.command_timeout = timeout;
+        }
+        config
+    }
+
     /// Create a default configuration
     pub fn default() -> Self {
         Self {
-            identity: Identity::generate(),
+            identity: default_identity(),
             identity_path: PathBuf::from("client.identity"),
+            identity_agent: None,
+            identity_agent_key: None,
+            keystore_path: default_keystore_path(),
             server_destination: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+            known_hosts_path: default_known_hosts_path(),
+            accept_new_host_keys: false,
+            require_noise: default_require_noise(),
             connection_timeout: default_connection_timeout(),
             command_timeout: default_command_timeout(),
+            reconnect_attempts: default_reconnect_attempts(),
+            reconnect_delay: default_reconnect_delay(),
+            keepalive_interval: default_keepalive_interval(),
+            retry_attempts: default_retry_attempts(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            retry_jitter_ms: default_retry_jitter_ms(),
+            control_path: None,
+            audit_log_path: None,
+            audit_redact: vec![],
+            session_log_path: None,
+            session_timing_path: None,
             enable_i2p: false,
             router_mode: reticulum_core::RouterMode::default(),
             sam_address: default_sam_address(),
             #[cfg(feature = "embedded-router")]
             embedded_router: reticulum_core::EmbeddedRouterConfig::default(),
             server_i2p_destination: None,
+            server_tcp_address: None,
+            auth_token: None,
+            secrets: vec![],
+            send_env: vec![],
+            env: BTreeMap::new(),
+            predict: Prediction::default(),
+            servers: vec![],
         }
     }
 
@@ -118,3 +533,228 @@ impl ClientConfig {
         Ok(dest)
     }
 }
+
+/// The identity in the file at `path`, asking for its passphrase if it is
+/// encrypted
+#[cfg(not(target_arch = "wasm32"))]
+fn file_identity(path: &Path) -> Result<Identity> {
+    Ok(rsh_agent::passphrase::load_identity(path)?)
+}
+
+/// A browser has nowhere to ask for a passphrase
+#[cfg(target_arch = "wasm32")]
+fn file_identity(path: &Path) -> Result<Identity> {
+    Ok(Identity::load_from_file(path)?)
+}
+
+/// The identity held by the key agent at `socket`
+#[cfg(not(target_arch = "wasm32"))]
+fn agent_identity(socket: &Path, public_key: Option<&str>) -> Result<Arc<dyn Signer>> {
+    let signer = rsh_agent::AgentSigner::connect(socket, public_key)?;
+    Ok(Arc::new(signer))
+}
+
+/// A browser has no key agent to reach
+#[cfg(target_arch = "wasm32")]
+fn agent_identity(_socket: &Path, _public_key: Option<&str>) -> Result<Arc<dyn Signer>> {
+    Err(ClientError::Config(
+        "identity_agent: there is no key agent in a browser".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = dir.path().join("client.identity");
+        Identity::generate().save_to_file(&identity).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&identity, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+
+        let path = dir.path().join("client.toml");
+        let mut config = ClientConfig::default();
+        config.identity_path = identity;
+        config.save_to_file(&path).unwrap();
+        assert!(ClientConfig::check_file(&path).is_empty());
+
+        config.server_destination = "abc".to_string();
+        config.save_to_file(&path).unwrap();
+        let problems = ClientConfig::check_file(&path);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("server_destination: "));
+
+        std::fs::write(&path, "connection_timeout = 5\n").unwrap();
+        assert!(ClientConfig::check_file(&path)[0].starts_with("config: "));
+    }
+
+    #[test]
+    fn test_encrypted_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity_path = dir.path().join("client.identity");
+        let identity = Identity::generate();
+        std::fs::write(&identity_path, identity.to_encrypted_bytes("s3cret", 1000)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&identity_path, std::fs::Permissions::from_mode(0o600))
+                .unwrap();
+        }
+
+        let path = dir.path().join("client.toml");
+        let mut config = ClientConfig::default();
+        config.identity_path = identity_path;
+        config.save_to_file(&path).unwrap();
+        assert!(ClientConfig::check_file(&path).is_empty());
+
+        std::env::set_var(rsh_agent::passphrase::PASSPHRASE_ENV, "s3cret");
+        let loaded = ClientConfig::load_from_file(&path);
+        std::env::remove_var(rsh_agent::passphrase::PASSPHRASE_ENV);
+        assert_eq!(
+            loaded.unwrap().identity.destination_hex(),
+            identity.destination_hex()
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = dir.path().join("prod.identity");
+        let prod_identity = Identity::generate();
+        prod_identity.save_to_file(&identity).unwrap();
+
+        let config: ClientConfig = toml::from_str(&format!(
+            r#"
+            identity_path = "client.identity"
+            server_destination = "{}"
+            server_tcp_address = "127.0.0.1:4242"
+            command_timeout = 60
+
+            [[servers]]
+            name = "prod"
+            i2p_destination = "AAAA"
+            identity_path = "{}"
+            command_timeout = 600
+
+            [[servers]]
+            name = "lab"
+            tcp_address = "10.0.0.5:4242"
+            destination = "{}"
+            "#,
+            "11".repeat(32),
+            identity.display(),
+            "22".repeat(32),
+        ))
+        .unwrap();
+        assert_eq!(config.profile_names(), vec!["prod", "lab"]);
+
+        let prod = config.with_profile("prod").unwrap();
+        assert!(prod.enable_i2p);
+        assert_eq!(prod.server_i2p_destination.as_deref(), Some("AAAA"));
+        assert_eq!(prod.server_tcp_address, None);
+        assert_eq!(prod.command_timeout, 600);
+        assert_eq!(prod.identity.destination_hex(), prod_identity.destination_hex());
+
+        let lab = config.with_profile("lab").unwrap();
+        assert!(!lab.enable_i2p);
+        assert_eq!(lab.server_tcp_address, Some("10.0.0.5:4242".parse().unwrap()));
+        assert_eq!(lab.parse_server_destination().unwrap(), [0x22; 32]);
+        assert_eq!(lab.command_timeout, 60);
+
+        assert!(config.with_profile("staging").is_err());
+    }
+
+    #[test]
+    fn test_use_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = dir.path().join("identities");
+        std::fs::create_dir(&keystore).unwrap();
+        let ops = Identity::generate();
+        ops.save_to_file(keystore.join("ops.identity")).unwrap();
+        let prod = dir.path().join("prod.identity");
+        Identity::generate().save_to_file(&prod).unwrap();
+
+        let path = dir.path().join("client.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+                identity_path = "{}"
+                keystore_path = "{}"
+                server_destination = "{}"
+
+                [[servers]]
+                name = "prod"
+                identity_path = "{}"
+                "#,
+                dir.path().join("missing.identity").display(),
+                keystore.display(),
+                "11".repeat(32),
+                prod.display(),
+            ),
+        )
+        .unwrap();
+
+        // The identity picked wins over those of the profiles
+        let config = ClientConfig::load_with_identity(&path, Some("ops")).unwrap();
+        assert_eq!(config.identity_path, keystore.join("ops.identity"));
+        let profile = config.with_profile("prod").unwrap();
+        assert_eq!(profile.identity.destination_hex(), ops.destination_hex());
+
+        let config =
+            ClientConfig::load_with_identity(&path, Some(prod.to_str().unwrap())).unwrap();
+        assert_eq!(config.identity_path, prod);
+
+        assert!(ClientConfig::load_with_identity(&path, Some("staging")).is_err());
+    }
+
+    #[test]
+    fn test_add_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = dir.path().join("client.identity");
+        Identity::generate().save_to_file(&identity).unwrap();
+        let path = dir.path().join("client.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"# Lab client
+identity_path = "{}"
+server_destination = ""
+
+[env]
+LANG = "C"
+"#,
+                identity.display()
+            ),
+        )
+        .unwrap();
+
+        let server = Identity::generate();
+        let mut share = ShareString::new(&server);
+        share.tcp_address = Some("192.0.2.7:4242".parse().unwrap());
+        share.i2p_destination = Some("AAAA".to_string());
+
+        let mut config = ClientConfig::load_from_file(&path).unwrap();
+        config
+            .add_profile(&path, ServerProfile::from_share("lab", &share))
+            .unwrap();
+        let error = config
+            .add_profile(&path, ServerProfile::from_share("lab", &share))
+            .unwrap_err();
+        assert!(error.to_string().contains("already exists"));
+
+        let source = std::fs::read_to_string(&path).unwrap();
+        assert!(source.starts_with("# Lab client\n"));
+        let reloaded = ClientConfig::load_from_file(&path).unwrap();
+        assert_eq!(reloaded.env.get("LANG").map(String::as_str), Some("C"));
+        let lab = reloaded.with_profile("lab").unwrap();
+        assert_eq!(lab.server_destination, server.destination_hex());
+        assert_eq!(lab.server_tcp_address, share.tcp_address);
+        assert_eq!(lab.server_i2p_destination.as_deref(), Some("AAAA"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Opening connections
+//!
+//! Builds the client for a configuration: over TCP if it names a server
+//! address, otherwise over I2P if enabled (through an external router's SAM
+//! bridge or an embedded router), otherwise in local mode. The same path
+//! serves the initial connection and switching servers in the REPL.
+//!
+//! With jump hosts (`--via`), the client connects to the first hop as usual
+//! and has it open a forwarding channel to the next one; the protocol to the
+//! next hop then runs inside that channel, framed like a TCP connection. Every
+//! hop after the first, and the target, must therefore have a TCP address the
+//! hop before it may forward to.
+
+use crate::{client::Client, config::ClientConfig, ClientError, Result};
+use reticulum_core::{I2pInterface, NetworkInterface, TcpInterface};
+use shell_proto::{ChannelClose, ChannelData, ChannelKind, ChannelOpenRequest, Message};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tracing::{debug, error, info};
+
+/// Buffer between a jump host's channel and the connection running in it
+const TUNNEL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Size of the buffer used when reading from the tunnel
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Create a client for `config`, not yet connected
+pub async fn open(config: ClientConfig) -> Result<Client> {
+    if let Some(address) = config.server_tcp_address {
+        info!("Connecting to server over TCP at {}", address);
+
+        let interface = TcpInterface::connect(address).await.map_err(|e| {
+            error!("Failed to connect to {}: {}", address, e);
+            e
+        })?;
+        let server_dest_hash = config.parse_server_destination()?;
+
+        let interface: Arc<dyn NetworkInterface> = Arc::new(interface);
+        return Client::with_interface(config, interface, server_dest_hash).await;
+    }
+
+    if !config.enable_i2p {
+        info!("Connecting to server: {}", config.server_destination);
+        return Client::new(config).await;
+    }
+
+    let i2p_interface = i2p_interface(&config).await?;
+
+    // Parse and register server I2P destination
+    let server_dest_hash = if let Some(ref i2p_dest) = config.server_i2p_destination {
+        info!(
+            "Registering server I2P destination: {}...",
+            &i2p_dest[..20.min(i2p_dest.len())]
+        );
+        i2p_interface.register_destination(i2p_dest.clone()).await
+    } else {
+        error!("I2P enabled but no server I2P destination provided");
+        error!("Use --i2p-destination flag or set server_i2p_destination in config");
+        return Err(ClientError::Config(
+            "Missing server I2P destination".to_string(),
+        ));
+    };
+
+    info!(
+        "Server I2P destination hash: {}",
+        hex::encode(server_dest_hash)
+    );
+
+    let interface: Arc<dyn NetworkInterface> = Arc::new(i2p_interface);
+    Client::with_interface(config, interface, server_dest_hash).await
+}
+
+/// Create the I2P interface (embedded or external router)
+pub(crate) async fn i2p_interface(config: &ClientConfig) -> Result<I2pInterface> {
+    #[cfg(feature = "embedded-router")]
+    if matches!(config.router_mode, reticulum_core::RouterMode::Embedded) {
+        info!("Starting embedded I2P router...");
+
+        let router = reticulum_core::EmbeddedRouter::new(config.embedded_router.clone())
+            .await
+            .map_err(|e| {
+                error!("Failed to start embedded router: {}", e);
+                e
+            })?;
+
+        info!("Embedded router started successfully");
+
+        // Wait for router to be ready
+        router.wait_ready().await?;
+
+        info!("Connecting to embedded router via SAM...");
+        return match I2pInterface::new_embedded(&router).await {
+            Ok(iface) => {
+                log_destination(&iface);
+                Ok(iface)
+            }
+            Err(e) => {
+                error!("Failed to create I2P interface: {}", e);
+                Err(e.into())
+            }
+        };
+    }
+
+    let sam_address = &config.sam_address;
+    info!(
+        "Connecting to external I2P router via SAM bridge at {}",
+        sam_address
+    );
+
+    match I2pInterface::new(sam_address).await {
+        Ok(iface) => {
+            log_destination(&iface);
+            Ok(iface)
+        }
+        Err(e) => {
+            error!("Failed to create I2P interface: {}", e);
+            error!(
+                "Make sure I2P router is running with SAM bridge enabled on {}",
+                sam_address
+            );
+            Err(e.into())
+        }
+    }
+}
+
+/// Create a client for `target`, reached through the jump hosts named by
+/// `hops` (profiles of `profiles`), not yet connected itself
+///
+/// The banners of jump hosts cannot be shown to anyone, so those that must
+/// be acknowledged are only passed with `accept_banners`.
+pub async fn open_via(
+    profiles: &ClientConfig,
+    hops: &[String],
+    target: ClientConfig,
+    accept_banners: bool,
+) -> Result<Client> {
+    let mut configs = hops
+        .iter()
+        .map(|name| profiles.with_profile(name))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let Some(first) = configs.next() else {
+        return open(target).await;
+    };
+
+    let mut hop = open(first).await?;
+    for next in configs.chain(std::iter::once(target)) {
+        hop.connect().await?;
+        if hop.host_key_unknown() {
+            hop.disconnect().await?;
+            return Err(ClientError::Request(
+                "The key of a jump host is not known yet (see --accept-new-host-key)".to_string(),
+            ));
+        }
+        if let Some(banner) = hop.banner().await {
+            info!("Jump host banner: {}", banner.trim_end());
+        }
+        if hop.banner_ack_required() {
+            if !accept_banners {
+                hop.disconnect().await?;
+                return Err(ClientError::Request(
+                    "The banner of a jump host was not accepted (see --accept-banner)".to_string(),
+                ));
+            }
+            hop.acknowledge_banner().await?;
+        }
+        hop = through(hop, next).await?;
+    }
+    Ok(hop)
+}
+
+/// Create a client for `next`, reached through the connected client `hop`
+///
+/// `next` must have a TCP address. `hop` serves only the tunnel from now on
+/// and is disconnected when the tunnel closes.
+pub async fn through(hop: Client, next: ClientConfig) -> Result<Client> {
+    let address = next.server_tcp_address.ok_or_else(|| {
+        ClientError::Config("A server reached through a jump host needs a TCP address".to_string())
+    })?;
+    info!("Connecting to {} through a jump host", address);
+
+    let stream = tunnel(hop, address.ip().to_string(), address.port()).await?;
+    let server_dest_hash = next.parse_server_destination()?;
+
+    let interface: Arc<dyn NetworkInterface> =
+        Arc::new(TcpInterface::over(stream, &address.to_string()));
+    Client::with_interface(next, interface, server_dest_hash).await
+}
+
+/// Have `hop` open a channel to `host:port`, returning our end of it
+async fn tunnel(hop: Client, host: String, port: u16) -> Result<DuplexStream> {
+    let channel_id = hop.next_id();
+    hop.send(Message::ChannelOpen(ChannelOpenRequest {
+        channel_id,
+        kind: ChannelKind::Direct {
+            host: host.clone(),
+            port,
+        },
+    }))
+    .await?;
+
+    // The target may speak first, before the channel is acknowledged
+    let mut early = Vec::new();
+    loop {
+        match hop.receive().await? {
+            Message::Ack(ack) if ack.message_id == channel_id => break,
+            Message::ChannelData(data) if data.channel_id == channel_id => early.extend(data.data),
+            Message::ChannelClose(close) if close.channel_id == channel_id => {
+                return Err(ClientError::Connection(format!(
+                    "Jump host could not reach {}:{}: {}",
+                    host,
+                    port,
+                    close.reason.as_deref().unwrap_or("channel closed")
+                )));
+            }
+            Message::Error(error) if error.request_id == channel_id => {
+                return Err(ClientError::Request(error.message));
+            }
+            other => debug!(message = ?other, "Ignoring message while opening a tunnel"),
+        }
+    }
+
+    let (local, remote) = tokio::io::duplex(TUNNEL_BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = relay(&hop, channel_id, remote, early).await {
+            debug!(channel_id = channel_id, error = %e, "Tunnel ended");
+        }
+        let _ = hop.disconnect().await;
+    });
+    Ok(local)
+}
+
+/// Pass bytes between the channel of `hop` and `stream` until either closes
+async fn relay(hop: &Client, channel_id: u64, stream: DuplexStream, early: Vec<u8>) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(&early).await?;
+
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            message = hop.receive() => match message? {
+                Message::ChannelData(data) if data.channel_id == channel_id => {
+                    writer.write_all(&data.data).await?;
+                }
+                Message::ChannelClose(close) if close.channel_id == channel_id => return Ok(()),
+                _ => {}
+            },
+            n = reader.read(&mut buf) => {
+                let message = match n? {
+                    0 => Message::ChannelClose(ChannelClose {
+                        channel_id,
+                        reason: None,
+                    }),
+                    n => Message::ChannelData(ChannelData {
+                        channel_id,
+                        data: buf[..n].to_vec(),
+                    }),
+                };
+                let closed = matches!(message, Message::ChannelClose(_));
+                hop.send(message).await?;
+                if closed {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn log_destination(iface: &I2pInterface) {
+    info!("I2P interface created successfully");
+    info!("Client I2P destination: {}", iface.local_destination());
+    info!(
+        "Client I2P destination hash: {}",
+        hex::encode(iface.local_destination_hash())
+    );
+}
@@ -0,0 +1,387 @@
+//! Sharing one connection between client processes
+//!
+//! Building an I2P tunnel to the server takes a while, so a client started
+//! with `--master` keeps its session open and shares it on a Unix socket
+//! (`control_path`, mode 0600, answering only processes of the same user).
+//! While it runs, `-e` sends its command through that socket instead of
+//! connecting on its own; without a master it connects as usual.
+//!
+//! The socket speaks the framing of the wire protocol, one command per
+//! connection: COMMAND_REQUEST (its `env` holds the variables the caller
+//! passes, its `id` is the caller's), then any COMMAND_INPUT and CANCEL one
+//! way and COMMAND_OUTPUT the other, until a COMMAND_RESPONSE or ERROR ends
+//! it. The master sends the command under its own request ID and settings
+//! (secrets, configured environment). Commands of several callers take
+//! turns on the connection. A caller that goes away kills its command.
+
+use crate::{
+    client::{Client, CommandOptions},
+    config::ClientConfig,
+    ClientError, Result,
+};
+use bytes::BytesMut;
+use shell_proto::{
+    CommandInput, CommandOutput, CommandRequest, CommandResponse, ErrorMessage, Message,
+    ProtocolCodec,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Chunks of a caller's stdin buffered ahead of the server
+const INPUT_QUEUE: usize = 16;
+
+/// The control socket for the server of `config`, if one is configured
+pub fn socket_path(config: &ClientConfig) -> Option<PathBuf> {
+    let path = config.control_path.as_ref()?.to_string_lossy().into_owned();
+    Some(PathBuf::from(
+        path.replace("%d", &config.server_destination),
+    ))
+}
+
+/// Share the session of `client` on the socket at `path` until dropped,
+/// which removes the socket
+#[cfg(unix)]
+pub async fn serve(client: Arc<Client>, path: &Path) -> Result<()> {
+    use tokio::net::{UnixListener, UnixStream};
+
+    // Another master answers there; a socket nobody answers on is stale
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(ClientError::Config(format!(
+            "A master already listens on {}",
+            path.display()
+        )));
+    }
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    // Created 0600 rather than changed to it, so nobody else can connect
+    // in between
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = bound?;
+    let _socket = SocketFile(path.to_path_buf());
+    info!(path = %path.display(), "Sharing the session");
+
+    let uid = unsafe { libc::getuid() };
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        if !stream.peer_cred().is_ok_and(|cred| cred.uid() == uid) {
+            warn!("Refusing control connection from another user");
+            continue;
+        }
+        let client = Arc::clone(&client);
+        tokio::spawn(async move {
+            if let Err(e) = handle(&client, stream).await {
+                debug!(error = %e, "Control connection failed");
+            }
+        });
+    }
+}
+
+/// Removes the socket when the master stops
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Connection sharing is Unix-only
+#[cfg(not(unix))]
+pub async fn serve(_client: Arc<Client>, _path: &Path) -> Result<()> {
+    Err(ClientError::Config(
+        "control_path is only supported on Unix".to_string(),
+    ))
+}
+
+/// Run a command for one caller
+#[cfg(unix)]
+async fn handle(client: &Client, stream: tokio::net::UnixStream) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = BytesMut::new();
+    let request = match read_message(&mut reader, &mut buf).await? {
+        Some(Message::CommandRequest(request)) => request,
+        Some(_) => {
+            return Err(ClientError::Connection(
+                "Expected a command request".to_string(),
+            ))
+        }
+        None => return Ok(()),
+    };
+    let caller_id = request.id;
+    debug!(command = %request.command, "Running a command for a control client");
+
+    // Input and cancels from the caller; a caller gone kills the command
+    let (input_tx, input) = if request.stdin {
+        let (tx, rx) = mpsc::channel(INPUT_QUEUE);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let (interrupt_tx, mut interrupts) = mpsc::unbounded_channel();
+    let forward_input = tokio::spawn(async move {
+        let mut input_tx = input_tx;
+        loop {
+            match read_message(&mut reader, &mut buf).await {
+                Ok(Some(Message::CommandInput(input))) => {
+                    if let Some(tx) = &input_tx {
+                        if !input.data.is_empty() && tx.send(input.data).await.is_err() {
+                            input_tx = None;
+                        }
+                    }
+                    if input.eof {
+                        input_tx = None;
+                    }
+                }
+                Ok(Some(Message::CancelRequest(_))) => {
+                    let _ = interrupt_tx.send(());
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => {
+                    // The first interrupts, the second kills
+                    let _ = interrupt_tx.send(());
+                    let _ = interrupt_tx.send(());
+                    return;
+                }
+            }
+        }
+    });
+
+    // Output goes out as it arrives
+    let (output_tx, mut output) = mpsc::unbounded_channel();
+    let forward_output = tokio::spawn(async move {
+        while let Some(message) = output.recv().await {
+            if write_message(&mut writer, &message).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let options = CommandOptions {
+        timeout: request.timeout,
+        working_dir: request.working_dir,
+        env: request.env.unwrap_or_default(),
+        ..CommandOptions::default()
+    };
+    let stream_tx = output_tx.clone();
+    let result = client
+        .execute(
+            request.command,
+            request.args,
+            &options,
+            input,
+            &mut |stderr: bool, data: &[u8]| {
+                let _ = stream_tx.send(Message::CommandOutput(CommandOutput {
+                    id: caller_id,
+                    stderr,
+                    data: data.to_vec(),
+                }));
+            },
+            Some(&mut interrupts),
+        )
+        .await;
+    forward_input.abort();
+
+    let reply = match result {
+        Ok(response) => Message::CommandResponse(CommandResponse {
+            id: caller_id,
+            ..response
+        }),
+        Err(e) => Message::Error(ErrorMessage::new(
+            caller_id,
+            ErrorMessage::INTERNAL,
+            e.to_string(),
+        )),
+    };
+    let _ = output_tx.send(reply);
+    drop(output_tx);
+    let _ = forward_output.await;
+    Ok(())
+}
+
+/// A connection to a master
+pub struct Master {
+    #[cfg(unix)]
+    stream: tokio::net::UnixStream,
+}
+
+impl Master {
+    /// Connect to the master on `path`, if one answers there
+    #[cfg(unix)]
+    pub async fn connect(path: &Path) -> Option<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await.ok()?;
+        debug!(path = %path.display(), "Connected to the master");
+        Some(Self { stream })
+    }
+
+    /// Connection sharing is Unix-only
+    #[cfg(not(unix))]
+    pub async fn connect(_path: &Path) -> Option<Self> {
+        None
+    }
+
+    /// Run a command through the master
+    ///
+    /// Works like [`Client::execute_command_piped`], also passing the output
+    /// to `on_output` as it arrives.
+    #[cfg(unix)]
+    pub async fn execute(
+        self,
+        request: CommandRequest,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+        on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+    ) -> Result<CommandResponse> {
+        run_through(self.stream, request, input, on_output).await
+    }
+
+    /// Connection sharing is Unix-only
+    #[cfg(not(unix))]
+    pub async fn execute(
+        self,
+        _request: CommandRequest,
+        _input: Option<mpsc::Receiver<Vec<u8>>>,
+        _on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+    ) -> Result<CommandResponse> {
+        Err(ClientError::Config(
+            "control_path is only supported on Unix".to_string(),
+        ))
+    }
+}
+
+#[cfg(unix)]
+async fn run_through(
+    stream: tokio::net::UnixStream,
+    request: CommandRequest,
+    mut input: Option<mpsc::Receiver<Vec<u8>>>,
+    on_output: &mut (dyn FnMut(bool, &[u8]) + Send),
+) -> Result<CommandResponse> {
+    let (mut reader, mut writer) = stream.into_split();
+    let id = request.id;
+    write_message(&mut writer, &Message::CommandRequest(request)).await?;
+
+    let mut buf = BytesMut::new();
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    loop {
+        let message = tokio::select! {
+            message = read_message(&mut reader, &mut buf) => message?,
+            data = next_input(input.as_mut()), if input.is_some() => {
+                let eof = data.is_none();
+                if eof {
+                    input = None;
+                }
+                let message = Message::CommandInput(CommandInput {
+                    id,
+                    data: data.unwrap_or_default(),
+                    eof,
+                });
+                write_message(&mut writer, &message).await?;
+                continue;
+            }
+        };
+        match message {
+            Some(Message::CommandOutput(output)) => {
+                on_output(output.stderr, &output.data);
+                if output.stderr {
+                    stderr.extend_from_slice(&output.data);
+                } else {
+                    stdout.extend_from_slice(&output.data);
+                }
+            }
+            Some(Message::CommandResponse(mut response)) => {
+                stdout.append(&mut response.stdout);
+                stderr.append(&mut response.stderr);
+                response.stdout = stdout;
+                response.stderr = stderr;
+                return Ok(response);
+            }
+            Some(Message::Error(error)) => return Err(ClientError::Request(error.message)),
+            Some(_) => {}
+            None => {
+                return Err(ClientError::Connection(
+                    "The master closed the connection".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// The next chunk of input, `None` once it ended
+async fn next_input(input: Option<&mut mpsc::Receiver<Vec<u8>>>) -> Option<Vec<u8>> {
+    match input {
+        Some(input) => input.recv().await,
+        None => None,
+    }
+}
+
+/// Read one framed message, `None` at end of stream
+async fn read_message(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+) -> Result<Option<Message>> {
+    loop {
+        if let Some(message) = ProtocolCodec::decode(buf)? {
+            return Ok(Some(message));
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Write one framed message
+async fn write_message(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> Result<()> {
+    writer.write_all(&ProtocolCodec::encode(message)?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_path() {
+        let mut config = ClientConfig::default();
+        assert_eq!(socket_path(&config), None);
+
+        config.server_destination = "ab12".to_string();
+        config.control_path = Some(PathBuf::from("/tmp/rsh-%d.sock"));
+        assert_eq!(
+            socket_path(&config),
+            Some(PathBuf::from("/tmp/rsh-ab12.sock"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let mut config = ClientConfig::default();
+        config.server_destination = "00".repeat(32);
+        let client = Arc::new(Client::new(config).await.unwrap());
+        let master = tokio::spawn({
+            let path = path.clone();
+            async move { serve(client, &path).await }
+        });
+        while !path.exists() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        master.abort();
+    }
+}
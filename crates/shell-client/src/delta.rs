@@ -0,0 +1,139 @@
+//! Output deltas for commands run again and again
+//!
+//! With [`CommandOptions::delta`](crate::client::CommandOptions::delta) the
+//! client keeps the last stdout of a command and sends its SHA-256 with the
+//! next run; a server that offers `output-delta` may answer with a delta
+//! against it (see [`shell_proto::delta`]), which is applied here before
+//! anyone sees the response.
+
+use crate::{ClientError, Result};
+use sha2::{Digest, Sha256};
+use shell_proto::{delta, CommandRequest, CommandResponse};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Commands whose output is kept
+const MAX_ENTRIES: usize = 16;
+
+/// What identifies a command: command, arguments and working directory
+type CommandKey = (String, Vec<String>, Option<String>);
+
+/// The last stdout of the commands run for deltas
+#[derive(Debug, Default)]
+pub struct DeltaBases {
+    /// Least recently run first
+    entries: Mutex<VecDeque<(CommandKey, Vec<u8>)>>,
+}
+
+impl DeltaBases {
+    /// SHA-256 of the last stdout of the command `request` runs (of no
+    /// output if there is none), for its `delta_base`
+    pub fn base(&self, request: &CommandRequest) -> [u8; 32] {
+        let key = command_key(request);
+        let entries = self.entries.lock().unwrap();
+        let stdout = entries
+            .iter()
+            .find(|(command, _)| *command == key)
+            .map_or(&[][..], |(_, stdout)| stdout.as_slice());
+        Sha256::digest(stdout).into()
+    }
+
+    /// Apply the delta `response` may hold to the output it is against,
+    /// and keep the output for the next run
+    pub fn resolve(&self, request: &CommandRequest, response: &mut CommandResponse) -> Result<()> {
+        let key = command_key(request);
+        let mut entries = self.entries.lock().unwrap();
+        let previous = entries
+            .iter()
+            .position(|(command, _)| *command == key)
+            .and_then(|i| entries.remove(i))
+            .map(|(_, stdout)| stdout)
+            .unwrap_or_default();
+
+        if let Some(base) = response.delta_base.take() {
+            if <[u8; 32]>::from(Sha256::digest(&previous)) != base {
+                return Err(ClientError::Connection(
+                    "The server sent a delta against output this client does not have".to_string(),
+                ));
+            }
+            response.stdout = delta::apply(&previous, &response.stdout)?;
+        }
+
+        if !response.truncated {
+            entries.push_back((key, response.stdout.clone()));
+            if entries.len() > MAX_ENTRIES {
+                entries.pop_front();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn command_key(request: &CommandRequest) -> CommandKey {
+    (
+        request.command.clone(),
+        request.args.clone(),
+        request.working_dir.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::CommandStatus;
+
+    fn request(command: &str) -> CommandRequest {
+        CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    fn response(stdout: &[u8], delta_base: Option<[u8; 32]>) -> CommandResponse {
+        CommandResponse {
+            id: 1,
+            status: CommandStatus::Success,
+            stdout: stdout.to_vec(),
+            stderr: vec![],
+            exit_code: 0,
+            execution_time_ms: 1,
+            truncated: false,
+            total_bytes: stdout.len() as u64,
+            spool_id: None,
+            delta_base,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let bases = DeltaBases::default();
+        let uptime = request("uptime");
+        assert_eq!(bases.base(&uptime), <[u8; 32]>::from(Sha256::digest(b"")));
+
+        let first = b"load average: 0.10, 0.12, 0.09\n".repeat(4);
+        let mut reply = response(&first, None);
+        bases.resolve(&uptime, &mut reply).unwrap();
+        assert_eq!(reply.stdout, first);
+        let base = bases.base(&uptime);
+        assert_eq!(base, <[u8; 32]>::from(Sha256::digest(&first)));
+
+        let second = b"load average: 0.25, 0.12, 0.09\n".repeat(4);
+        let mut reply = response(&delta::diff(&first, &second), Some(base));
+        bases.resolve(&uptime, &mut reply).unwrap();
+        assert_eq!(reply.stdout, second);
+        assert!(reply.delta_base.is_none());
+
+        // Against output not held
+        let mut reply = response(&delta::diff(&first, &second), Some(base));
+        assert!(bases.resolve(&uptime, &mut reply).is_err());
+        let mut reply = response(&delta::diff(&first, &second), Some(base));
+        assert!(bases.resolve(&request("w"), &mut reply).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Server discovery (`--discover`)
+//!
+//! Servers announce their destination when they start and every
+//! `announce_interval` after, signed by their identity and carrying their
+//! name, protocol version and capabilities. `--discover` opens the
+//! interfaces of the configuration — the server's TCP address and those of
+//! the profiles, and I2P with every I2P destination configured — asks the
+//! destinations it knows of to announce themselves, and lists every server
+//! heard from while it listens.
+//!
+//! An announce proves only that its sender holds the key of the destination
+//! it announces, not who runs it: the key of a discovered server is still
+//! confirmed on the first connection.
+
+use crate::{
+    config::{ClientConfig, ServerProfile},
+    connect, Result,
+};
+use reticulum_core::{announce, Announce, NetworkInterface, PacketType, TcpInterface};
+use serde::Serialize;
+use shell_proto::AnnounceInfo;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// A server heard from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discovered {
+    /// Destination hash (hex)
+    pub destination: String,
+
+    /// Name the server announces
+    pub name: Option<String>,
+
+    /// Protocol version it speaks
+    pub protocol: Option<u32>,
+
+    /// Capabilities it announces
+    pub capabilities: Vec<String>,
+
+    /// Interface the announce came in on
+    pub interface: String,
+
+    /// TCP address that interface is connected to, if any
+    #[serde(skip)]
+    pub tcp_address: Option<SocketAddr>,
+
+    /// I2P destination configured for the server, if any
+    #[serde(skip)]
+    pub i2p_destination: Option<String>,
+}
+
+impl Discovered {
+    /// A profile for the server, named `name`, reached the way it was heard
+    pub fn profile(&self, name: &str) -> ServerProfile {
+        ServerProfile {
+            name: name.to_string(),
+            destination: Some(self.destination.clone()),
+            i2p_destination: self.i2p_destination.clone(),
+            tcp_address: self.tcp_address,
+            ..ServerProfile::default()
+        }
+    }
+}
+
+/// An interface to listen on, and what it leads to
+struct Listener {
+    interface: Arc<dyn NetworkInterface>,
+    tcp_address: Option<SocketAddr>,
+    i2p_destination: Option<String>,
+}
+
+/// Listen on the interfaces of `config` for `duration`, returning the
+/// servers heard from, by destination
+pub async fn run(config: &ClientConfig, duration: Duration) -> Result<Vec<Discovered>> {
+    let listeners = open(config).await?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let mut tasks = Vec::new();
+    for (index, listener) in listeners.iter().enumerate() {
+        for destination in known_destinations(config) {
+            if let Err(e) = listener
+                .interface
+                .broadcast(&announce::path_request(destination))
+                .await
+            {
+                debug!(interface = %listener.interface.name(), "Path request failed: {}", e);
+            }
+        }
+
+        let interface = Arc::clone(&listener.interface);
+        let tx = tx.clone();
+        tasks.push(tokio::spawn(async move {
+            while let Ok(packet) = interface.receive().await {
+                if packet.packet_type == PacketType::Announce && tx.send((index, packet)).is_err() {
+                    return;
+                }
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut found = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + duration;
+    while let Ok(Some((index, packet))) = tokio::time::timeout_at(deadline, rx.recv()).await {
+        let announce = match Announce::from_packet(&packet) {
+            Ok(announce) => announce,
+            Err(e) => {
+                debug!("Ignoring announce: {}", e);
+                continue;
+            }
+        };
+        let info = AnnounceInfo::from_bytes(&announce.app_data).ok();
+        let listener = &listeners[index];
+        found
+            .entry(announce.destination())
+            .or_insert_with(|| Discovered {
+                destination: hex::encode(announce.destination()),
+                name: info.as_ref().and_then(|info| info.name.clone()),
+                protocol: info.as_ref().map(|info| info.protocol_version),
+                capabilities: info.map(|info| info.capabilities).unwrap_or_default(),
+                interface: listener.interface.name().to_string(),
+                tcp_address: listener.tcp_address,
+                i2p_destination: listener.i2p_destination.clone(),
+            });
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+    for listener in &listeners {
+        let _ = listener.interface.close().await;
+    }
+    Ok(found.into_values().collect())
+}
+
+/// Open the interfaces of `config` and its profiles; those that fail are
+/// skipped
+async fn open(config: &ClientConfig) -> Result<Vec<Listener>> {
+    let mut addresses: Vec<SocketAddr> = config.server_tcp_address.into_iter().collect();
+    addresses.extend(
+        config
+            .servers
+            .iter()
+            .filter_map(|profile| profile.tcp_address),
+    );
+    addresses.sort();
+    addresses.dedup();
+
+    let mut listeners = Vec::new();
+    for address in addresses {
+        match TcpInterface::connect(address).await {
+            Ok(interface) => listeners.push(Listener {
+                interface: Arc::new(interface),
+                tcp_address: Some(address),
+                i2p_destination: None,
+            }),
+            Err(e) => warn!("Cannot listen through {}: {}", address, e),
+        }
+    }
+
+    let mut i2p_destinations: Vec<&String> = config.server_i2p_destination.iter().collect();
+    i2p_destinations.extend(
+        config
+            .servers
+            .iter()
+            .filter_map(|profile| profile.i2p_destination.as_ref()),
+    );
+    if config.enable_i2p || !i2p_destinations.is_empty() {
+        match connect::i2p_interface(config).await {
+            Ok(interface) => {
+                for destination in &i2p_destinations {
+                    interface
+                        .register_destination(destination.to_string())
+                        .await;
+                }
+                // Servers heard over I2P are reached through the one
+                // destination configured, if there is just one
+                let i2p_destination = match i2p_destinations[..] {
+                    [destination] => Some(destination.clone()),
+                    _ => None,
+                };
+                listeners.push(Listener {
+                    interface: Arc::new(interface),
+                    tcp_address: None,
+                    i2p_destination,
+                });
+            }
+            Err(e) => warn!("Cannot listen on I2P: {}", e),
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(crate::ClientError::Config(
+            "No interface to listen on: configure a server TCP address or I2P".to_string(),
+        ));
+    }
+    Ok(listeners)
+}
+
+/// Destinations of the configuration and its profiles
+fn known_destinations(config: &ClientConfig) -> Vec<[u8; 32]> {
+    let mut destinations: Vec<[u8; 32]> = std::iter::once(config.server_destination.as_str())
+        .chain(
+            config
+                .servers
+                .iter()
+                .filter_map(|profile| profile.destination.as_deref()),
+        )
+        .filter_map(|hex| hex::decode(hex).ok()?.try_into().ok())
+        .filter(|destination| *destination != [0; 32])
+        .collect();
+    destinations.sort();
+    destinations.dedup();
+    destinations
+}
+
+/// Profile name for `server`: its announced name, or the start of its
+/// destination, made unique among `taken`
+pub fn profile_name(server: &Discovered, taken: &[&str]) -> String {
+    let base = server
+        .name
+        .clone()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| format!("server-{}", &server.destination[..8]));
+    let mut name = base.clone();
+    let mut n = 2;
+    while taken.contains(&name.as_str()) {
+        name = format!("{}-{}", base, n);
+        n += 1;
+    }
+    name
+}
+
+/// The servers as a table
+pub fn report(servers: &[Discovered]) -> String {
+    let mut table = format!(
+        "{:<16}  {:<20}  {:<8}  {:<20}  {}\n",
+        "DESTINATION", "NAME", "PROTOCOL", "VIA", "CAPABILITIES"
+    );
+    for server in servers {
+        table.push_str(&format!(
+            "{:<16}  {:<20}  {:<8}  {:<20}  {}\n",
+            &server.destination[..16],
+            server.name.as_deref().unwrap_or("-"),
+            server
+                .protocol
+                .map_or_else(|| "-".to_string(), |version| version.to_string()),
+            server.interface,
+            server.capabilities.join(",")
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: Option<&str>) -> Discovered {
+        Discovered {
+            destination: "ab".repeat(32),
+            name: name.map(String::from),
+            protocol: Some(1),
+            capabilities: vec!["pty".to_string(), "echo".to_string()],
+            interface: "10.0.0.5:4242".to_string(),
+            tcp_address: Some("10.0.0.5:4242".parse().unwrap()),
+            i2p_destination: None,
+        }
+    }
+
+    #[test]
+    fn test_profile_name() {
+        assert_eq!(profile_name(&server(Some("lab")), &[]), "lab");
+        assert_eq!(
+            profile_name(&server(Some("lab")), &["lab", "lab-2"]),
+            "lab-3"
+        );
+        assert_eq!(profile_name(&server(None), &[]), "server-abababab");
+
+        let profile = server(None).profile("lab");
+        assert_eq!(profile.destination, Some("ab".repeat(32)));
+        assert_eq!(profile.tcp_address, Some("10.0.0.5:4242".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_report() {
+        let report = report(&[server(Some("lab")), server(None)]);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(&"ab".repeat(8)));
+        assert!(lines[1].contains("lab"));
+        assert!(lines[1].ends_with("pty,echo"));
+        assert!(lines[2].contains(" - "));
+    }
+}
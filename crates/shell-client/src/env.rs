@@ -0,0 +1,103 @@
+//! Environment passed to commands
+//!
+//! Every command gets the local variables whose names match a `send_env`
+//! pattern (or `--send-env`), then those of `env` in the configuration (or
+//! `--env`), then those given with the `:env` prefix for that command alone;
+//! later ones win. Unlike `export` in the REPL, nothing is kept in the
+//! server's session.
+
+use crate::{ClientError, Result};
+use std::collections::BTreeMap;
+
+/// Split `NAME=VALUE`
+pub fn parse_var(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(ClientError::Config(format!(
+            "Expected NAME=VALUE, got {:?}",
+            spec
+        ))),
+    }
+}
+
+/// The variables of `vars` whose names match one of `patterns` (`*` for
+/// any number of characters, `?` for one)
+pub fn matching(
+    patterns: &[String],
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> BTreeMap<String, String> {
+    if patterns.is_empty() {
+        return BTreeMap::new();
+    }
+    vars.into_iter()
+        .filter(|(name, _)| patterns.iter().any(|pattern| glob_match(pattern, name)))
+        .collect()
+}
+
+/// Match `name` against a glob `pattern`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was and how much of the name it took
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_var() {
+        assert_eq!(
+            parse_var("RUST_LOG=debug").unwrap(),
+            ("RUST_LOG".to_string(), "debug".to_string())
+        );
+        assert_eq!(
+            parse_var("OPTS=a=b").unwrap(),
+            ("OPTS".to_string(), "a=b".to_string())
+        );
+        assert_eq!(parse_var("EMPTY=").unwrap().1, "");
+        assert!(parse_var("NAME").is_err());
+        assert!(parse_var("=value").is_err());
+    }
+
+    #[test]
+    fn test_matching() {
+        let vars = ["LANG", "LC_ALL", "LC_TIME", "HOME", "TERM"]
+            .map(|name| (name.to_string(), "x".to_string()));
+        let patterns = ["LC_*".to_string(), "LAN?".to_string()];
+
+        let mut names: Vec<_> = matching(&patterns, vars.clone()).into_keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["LANG", "LC_ALL", "LC_TIME"]);
+        assert!(matching(&[], vars).is_empty());
+
+        assert!(glob_match("*", "ANYTHING"));
+        assert!(glob_match("A*B*C", "AxxBxxBxC"));
+        assert!(!glob_match("A*B", "AxxBx"));
+        assert!(!glob_match("LC_?", "LC_ALL"));
+    }
+}
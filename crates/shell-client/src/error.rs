@@ -29,13 +29,22 @@ pub enum ClientError {
     #[error("Server rejected connection: {0}")]
     Rejected(String),
 
+    /// Server presented a key other than the one recorded for it
+    #[error("Server key changed: {0}")]
+    HostKeyChanged(String),
+
+    /// Server refused or failed a request
+    #[error("Request failed: {0}")]
+    Request(String),
+
     /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 
-    /// Timeout error
-    #[error("Operation timed out")]
-    Timeout,
+    /// The server did not answer in time (what was waited for, and what to
+    /// check)
+    #[error("Timed out: {0}")]
+    Timeout(String),
 
     /// REPL error
     #[error("REPL error: {0}")]
@@ -0,0 +1,180 @@
+//! Events for embedding the client
+//!
+//! A GUI or bot hands the [`Client`](crate::client::Client) an
+//! [`EventHandler`] with
+//! [`set_event_handler`](crate::client::Client::set_event_handler) and is
+//! told of output, notices from the server, the connection going away and
+//! transfer progress as they happen, whichever call brought them in. Every
+//! method has an empty default, so handlers implement only what they need.
+//!
+//! The client reads from the server while a request is under way and when
+//! keepalive pings go out (see [`keepalive`](crate::keepalive)): a server
+//! going away between requests is noticed by the next ping. An
+//! `mpsc::UnboundedSender<Event>` is a handler too, for callers that would
+//! rather receive events in a task of their own.
+
+use tokio::sync::mpsc;
+
+/// Something the server told the client, besides answers to requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// The server sent a banner when the session was established
+    Banner(String),
+
+    /// Request `request_id` waits for operator approval (approval
+    /// `approval_id`, up to `timeout` seconds)
+    Pending {
+        request_id: u64,
+        approval_id: u64,
+        timeout: u64,
+    },
+
+    /// The connection broke and was established again; `new_session` if
+    /// the server could not resume the old session
+    Reconnected { new_session: bool },
+}
+
+/// Why the connection went away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server closed the session (with its reason, if it gave one)
+    Closed(Option<String>),
+
+    /// The connection was lost: keepalive pings went unanswered (the next
+    /// request reconnects, if `reconnect_attempts` allows), or reconnecting
+    /// failed
+    Lost(String),
+}
+
+/// Direction of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// To the server
+    Upload,
+
+    /// From the server
+    Download,
+}
+
+/// How far a transfer got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Request that started the transfer
+    pub request_id: u64,
+
+    /// Upload or download
+    pub direction: Direction,
+
+    /// Path on the server
+    pub path: String,
+
+    /// Bytes transferred (stored, for uploads) so far
+    pub bytes: u64,
+
+    /// Size of the file
+    pub size: u64,
+}
+
+/// Receives the events of a client
+///
+/// Called on the task that runs the request, so implementations should
+/// return quickly.
+pub trait EventHandler: Send + Sync {
+    /// Request `request_id` wrote `data` to stdout, or stderr if `stderr`
+    fn on_output(&self, _request_id: u64, _stderr: bool, _data: &[u8]) {}
+
+    /// The server told the client something
+    fn on_notification(&self, _notification: &Notification) {}
+
+    /// The connection went away
+    fn on_disconnect(&self, _reason: &DisconnectReason) {}
+
+    /// A transfer progressed
+    fn on_transfer_progress(&self, _progress: &TransferProgress) {}
+}
+
+/// An event, for handlers that pass them on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// [`EventHandler::on_output`]
+    Output {
+        request_id: u64,
+        stderr: bool,
+        data: Vec<u8>,
+    },
+
+    /// [`EventHandler::on_notification`]
+    Notification(Notification),
+
+    /// [`EventHandler::on_disconnect`]
+    Disconnect(DisconnectReason),
+
+    /// [`EventHandler::on_transfer_progress`]
+    TransferProgress(TransferProgress),
+}
+
+/// Sends every event down the channel; events for a closed receiver are
+/// dropped
+impl EventHandler for mpsc::UnboundedSender<Event> {
+    fn on_output(&self, request_id: u64, stderr: bool, data: &[u8]) {
+        let _ = self.send(Event::Output {
+            request_id,
+            stderr,
+            data: data.to_vec(),
+        });
+    }
+
+    fn on_notification(&self, notification: &Notification) {
+        let _ = self.send(Event::Notification(notification.clone()));
+    }
+
+    fn on_disconnect(&self, reason: &DisconnectReason) {
+        let _ = self.send(Event::Disconnect(reason.clone()));
+    }
+
+    fn on_transfer_progress(&self, progress: &TransferProgress) {
+        let _ = self.send(Event::TransferProgress(progress.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Counts output only
+    #[derive(Default)]
+    struct Bytes(Mutex<usize>);
+
+    impl EventHandler for Bytes {
+        fn on_output(&self, _request_id: u64, _stderr: bool, data: &[u8]) {
+            *self.0.lock().unwrap() += data.len();
+        }
+    }
+
+    #[test]
+    fn test_handlers() {
+        let bytes = Bytes::default();
+        bytes.on_output(1, false, b"hello");
+        bytes.on_disconnect(&DisconnectReason::Closed(None));
+        assert_eq!(*bytes.0.lock().unwrap(), 5);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tx.on_output(1, true, b"oops");
+        tx.on_notification(&Notification::Reconnected { new_session: false });
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event::Output {
+                request_id: 1,
+                stderr: true,
+                data: b"oops".to_vec()
+            }
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event::Notification(Notification::Reconnected { new_session: false })
+        );
+        drop(rx);
+        tx.on_disconnect(&DisconnectReason::Lost("gone".to_string()));
+    }
+}
@@ -0,0 +1,292 @@
+//! Running one command on many servers (`--hosts`, `--all`)
+//!
+//! Every named profile gets its own connection and the command runs on all
+//! of them at once. Output is passed through line by line, each line
+//! prefixed with the profile name, and a table of exit codes follows. A
+//! server that can't be reached is reported as unreachable (exit code 255)
+//! instead of stopping the others.
+
+use crate::{
+    client::{Client, CommandOptions},
+    config::ClientConfig,
+    output::{CommandRecord, OutputFormat},
+    script::Outcome,
+    session, ClientError, Result,
+};
+use shell_proto::CommandResponse;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How the command went on one server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostResult {
+    /// Profile name
+    pub host: String,
+
+    /// How the command ended
+    pub outcome: Outcome,
+
+    /// Time from connecting to the response
+    pub elapsed: Duration,
+}
+
+/// Run `argv` on the servers of the profiles `hosts` concurrently
+///
+/// Results are in the order of `hosts`. Banners that need acknowledging are
+/// only passed with `accept_banner`.
+pub async fn run(
+    profiles: &ClientConfig,
+    hosts: &[String],
+    argv: Vec<String>,
+    options: CommandOptions,
+    accept_banner: bool,
+    format: OutputFormat,
+) -> Vec<HostResult> {
+    let width = hosts.iter().map(String::len).max().unwrap_or(0);
+    let tasks: Vec<_> = hosts
+        .iter()
+        .map(|host| {
+            let config = profiles.with_profile(host);
+            let host = host.clone();
+            let argv = argv.clone();
+            let options = options.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let prefix = format!("{:width$} | ", host, width = width);
+                // A server that can't be reached is not a failed command
+                let result = match config {
+                    Ok(config) => match connect(config, accept_banner).await {
+                        Ok(client) => {
+                            let result = run_on(&client, &argv, &options, format, &prefix)
+                                .await
+                                .map_err(|e| (Outcome::from_error(&e), e));
+                            let _ = client.disconnect().await;
+                            result
+                        }
+                        Err(e) => Err((Outcome::Unreachable(e.to_string()), e)),
+                    },
+                    Err(e) => Err((Outcome::Failed(e.to_string()), e)),
+                };
+                let elapsed = start.elapsed();
+
+                let outcome = match result {
+                    Ok(response) => {
+                        if format == OutputFormat::Json {
+                            CommandRecord::finished(argv, &response, elapsed)
+                                .with_host(&host)
+                                .print();
+                        }
+                        Outcome::Finished {
+                            status: response.status,
+                            exit_code: response.exit_code,
+                        }
+                    }
+                    Err((outcome, e)) => {
+                        if format == OutputFormat::Json {
+                            CommandRecord::failed(argv, e.to_string(), elapsed)
+                                .with_host(&host)
+                                .print();
+                        } else {
+                            eprintln!("{}{}", prefix, e);
+                        }
+                        outcome
+                    }
+                };
+                HostResult {
+                    host,
+                    outcome,
+                    elapsed,
+                }
+            })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for (task, host) in tasks.into_iter().zip(hosts) {
+        results.push(task.await.unwrap_or_else(|e| HostResult {
+            host: host.clone(),
+            outcome: Outcome::Failed(e.to_string()),
+            elapsed: Duration::ZERO,
+        }));
+    }
+    results
+}
+
+/// Connect to one server
+async fn connect(config: ClientConfig, accept_banner: bool) -> Result<Client> {
+    let timeout = Duration::from_secs(config.connection_timeout);
+    tokio::time::timeout(timeout, session::open(config, accept_banner))
+        .await
+        .map_err(|_| {
+            ClientError::Timeout(format!(
+                "connecting took over {}s (connection_timeout)",
+                timeout.as_secs()
+            ))
+        })?
+}
+
+/// Run the command on one server
+async fn run_on(
+    client: &Client,
+    argv: &[String],
+    options: &CommandOptions,
+    format: OutputFormat,
+    prefix: &str,
+) -> Result<CommandResponse> {
+    if format == OutputFormat::Json {
+        client
+            .execute_command_with(argv[0].clone(), argv[1..].to_vec(), options)
+            .await
+    } else {
+        let mut pending = [Vec::new(), Vec::new()];
+        let response = client
+            .execute_command_streaming(
+                argv[0].clone(),
+                argv[1..].to_vec(),
+                options,
+                &mut |stderr: bool, data: &[u8]| {
+                    let pending = &mut pending[usize::from(stderr)];
+                    pending.extend_from_slice(data);
+                    if let Some(lines) = complete_lines(pending) {
+                        print_prefixed(stderr, prefix, &lines);
+                    }
+                },
+                None,
+            )
+            .await;
+        if let Ok(response) = &response {
+            pending[0].extend_from_slice(&response.stdout);
+            pending[1].extend_from_slice(&response.stderr);
+        }
+        for (stderr, rest) in [false, true].into_iter().zip(&pending) {
+            print_prefixed(stderr, prefix, rest);
+        }
+        response
+    }
+}
+
+/// Take the complete lines from `pending`, leaving a partial last line
+fn complete_lines(pending: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let end = pending.iter().rposition(|&b| b == b'\n')? + 1;
+    Some(pending.drain(..end).collect())
+}
+
+/// Print `data` with `prefix` before each of its lines
+fn print_prefixed(stderr: bool, prefix: &str, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    let mut text = String::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        text.push_str(prefix);
+        text.push_str(line);
+        text.push('\n');
+    }
+    // One write per chunk, so lines of different servers don't interleave
+    let _ = if stderr {
+        std::io::stderr().write_all(text.as_bytes())
+    } else {
+        std::io::stdout().write_all(text.as_bytes())
+    };
+}
+
+/// Exit code of a whole run: that of the first server (in the order given)
+/// where the command failed, 0 if it succeeded everywhere
+pub fn exit_code(results: &[HostResult]) -> i32 {
+    results
+        .iter()
+        .find(|result| result.outcome.is_failure())
+        .map_or(0, |result| result.outcome.exit_code())
+}
+
+/// Format the results as a table, one row per server
+pub fn summary(results: &[HostResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.host.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let mut table = format!(
+        "{:<width$}  {:<11}  {:>4}  {:>8}\n",
+        "HOST",
+        "STATUS",
+        "EXIT",
+        "TIME",
+        width = width
+    );
+    for result in results {
+        let (status, exit) = result.outcome.columns();
+        table.push_str(&format!(
+            "{:<width$}  {:<11}  {:>4}  {:>7.1}s\n",
+            result.host,
+            status,
+            exit,
+            result.elapsed.as_secs_f64(),
+            width = width
+        ));
+    }
+
+    let failed = results.iter().filter(|r| r.outcome.is_failure()).count();
+    table.push_str(&format!("{} servers, {} failed\n", results.len(), failed));
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::CommandStatus;
+
+    #[test]
+    fn test_complete_lines() {
+        let mut pending = b"one\ntwo\nthr".to_vec();
+        assert_eq!(complete_lines(&mut pending).unwrap(), b"one\ntwo\n");
+        assert_eq!(pending, b"thr");
+        assert_eq!(complete_lines(&mut pending), None);
+
+        pending.extend_from_slice(b"ee\n");
+        assert_eq!(complete_lines(&mut pending).unwrap(), b"three\n");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let result = |host: &str, outcome| HostResult {
+            host: host.to_string(),
+            outcome,
+            elapsed: Duration::ZERO,
+        };
+        let ok = result(
+            "prod1",
+            Outcome::Finished {
+                status: CommandStatus::Success,
+                exit_code: 0,
+            },
+        );
+        assert_eq!(exit_code(&[ok.clone()]), 0);
+
+        let results = [
+            ok,
+            result("prod2", Outcome::Failed("unreachable".to_string())),
+            result(
+                "prod3",
+                Outcome::Finished {
+                    status: CommandStatus::Error,
+                    exit_code: 3,
+                },
+            ),
+        ];
+        assert_eq!(exit_code(&results), crate::script::EXIT_FAILED);
+
+        let table = summary(&results);
+        assert!(table.contains("prod2  error"));
+        assert!(table.ends_with("3 servers, 2 failed\n"));
+
+        let results = [result(
+            "prod4",
+            Outcome::Unreachable("connection refused".to_string()),
+        )];
+        assert_eq!(exit_code(&results), crate::script::EXIT_FAILED);
+        assert!(summary(&results).contains("prod4  unreachable  255"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Dynamic port forwarding (`-D`)
+//!
+//! The client listens for SOCKS5 connections locally and opens a `Dynamic`
+//! channel for each; the server speaks SOCKS5 over the channel and connects
+//! to whatever the application asks for, within its `socks_allow` policy.
+//! The client only passes bytes, so any SOCKS5 application works through it.
+//!
+//! While forwarding, the connection carries nothing else: one task reads
+//! every message from the server and hands channel messages to the local
+//! connection they belong to.
+
+use crate::{client::Client, ClientError, Result};
+use shell_proto::{ChannelClose, ChannelData, ChannelKind, ChannelOpenRequest, Message};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Size of the buffer used when reading from local connections
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// What the server said about a channel
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    /// The channel is open
+    Opened,
+
+    /// Payload for the local connection
+    Data(Vec<u8>),
+
+    /// The channel was closed or could not be opened
+    Closed(Option<String>),
+}
+
+/// Open channels by ID
+type Channels = Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Event>>>>;
+
+/// Parse a listen address given as `PORT` (on localhost) or `ADDR:PORT`
+pub fn parse_bind(spec: &str) -> Result<SocketAddr> {
+    if let Ok(port) = spec.parse::<u16>() {
+        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+    }
+    spec.parse().map_err(|_| {
+        ClientError::Config(format!(
+            "Invalid listen address {:?} (expected PORT or ADDR:PORT)",
+            spec
+        ))
+    })
+}
+
+/// Run a SOCKS5 proxy on `bind` through the connected `client` until the
+/// connection to the server ends
+pub async fn dynamic(client: Arc<Client>, bind: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
+    serve_dynamic(client, listener).await
+}
+
+/// Forward every connection accepted on `listener` through a dynamic
+/// channel of `client`
+pub async fn serve_dynamic(client: Arc<Client>, listener: TcpListener) -> Result<()> {
+    let _exclusive = client.exclusive().await;
+    let channels = Channels::default();
+    let mut dispatcher = tokio::spawn(dispatch(Arc::clone(&client), Arc::clone(&channels)));
+
+    loop {
+        tokio::select! {
+            result = &mut dispatcher => {
+                return result.map_err(|e| ClientError::Connection(e.to_string()))?;
+            }
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let client = Arc::clone(&client);
+                let channels = Arc::clone(&channels);
+                tokio::spawn(async move {
+                    if let Err(e) = forward(&client, &channels, stream).await {
+                        warn!(peer = %peer, error = %e, "SOCKS connection failed");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Pass channel messages from the server to their connections
+async fn dispatch(client: Arc<Client>, channels: Channels) -> Result<()> {
+    loop {
+        let (channel_id, event) = match client.receive().await? {
+            Message::Ack(ack) => (ack.message_id, Event::Opened),
+            Message::ChannelData(data) => (data.channel_id, Event::Data(data.data)),
+            Message::ChannelClose(close) => (close.channel_id, Event::Closed(close.reason)),
+            Message::Error(error) => (error.request_id, Event::Closed(Some(error.message))),
+            other => {
+                debug!(message = ?other, "Ignoring message while forwarding");
+                continue;
+            }
+        };
+
+        let mut channels = channels.lock().unwrap();
+        let closed = matches!(event, Event::Closed(_));
+        let delivered = channels
+            .get(&channel_id)
+            .is_some_and(|tx| tx.send(event).is_ok());
+        if closed || !delivered {
+            channels.remove(&channel_id);
+        }
+    }
+}
+
+/// Carry one local connection over a channel of its own
+async fn forward(client: &Client, channels: &Channels, stream: TcpStream) -> Result<()> {
+    let channel_id = client.next_id();
+    let (tx, events) = mpsc::unbounded_channel();
+    channels.lock().unwrap().insert(channel_id, tx);
+
+    let result = relay(client, channel_id, stream, events).await;
+    channels.lock().unwrap().remove(&channel_id);
+    result
+}
+
+/// Open the channel, then pass bytes both ways until either side closes
+async fn relay(
+    client: &Client,
+    channel_id: u64,
+    stream: TcpStream,
+    mut events: mpsc::UnboundedReceiver<Event>,
+) -> Result<()> {
+    client
+        .send(Message::ChannelOpen(ChannelOpenRequest {
+            channel_id,
+            kind: ChannelKind::Dynamic,
+        }))
+        .await?;
+
+    let (mut reader, mut writer) = stream.into_split();
+    loop {
+        match events.recv().await {
+            Some(Event::Opened) => break,
+            Some(Event::Data(data)) => writer.write_all(&data).await?,
+            Some(Event::Closed(reason)) => {
+                return Err(ClientError::Request(
+                    reason.unwrap_or_else(|| "The server refused the channel".to_string()),
+                ));
+            }
+            None => return Ok(()),
+        }
+    }
+    debug!(channel_id = channel_id, "Dynamic forward channel opened");
+
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Event::Data(data)) => writer.write_all(&data).await?,
+                Some(Event::Opened) => {}
+                Some(Event::Closed(_)) | None => return Ok(()),
+            },
+            n = reader.read(&mut buf) => {
+                let n = n.unwrap_or(0);
+                if n == 0 {
+                    return client
+                        .send(Message::ChannelClose(ChannelClose {
+                            channel_id,
+                            reason: None,
+                        }))
+                        .await;
+                }
+                client
+                    .send(Message::ChannelData(ChannelData {
+                        channel_id,
+                        data: buf[..n].to_vec(),
+                    }))
+                    .await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(
+            parse_bind("1080").unwrap(),
+            "127.0.0.1:1080".parse().unwrap()
+        );
+        assert_eq!(
+            parse_bind("0.0.0.0:1080").unwrap(),
+            "0.0.0.0:1080".parse().unwrap()
+        );
+        assert_eq!(
+            parse_bind("[::1]:9050").unwrap(),
+            "[::1]:9050".parse().unwrap()
+        );
+        assert!(parse_bind("localhost:1080").is_err());
+        assert!(parse_bind("70000").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Connection health (`status` in the REPL)
+//!
+//! The client counts the bytes of the protocol messages it sends and
+//! receives, and times pings: the round-trip time is smoothed as TCP does
+//! (each sample counts for an eighth), and pings that go unanswered give the
+//! packet loss estimate.
+
+use crate::time::Instant;
+use shell_proto::ProtocolVersion;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Weight of the smoothed round-trip time against a new sample
+const RTT_SMOOTHING: u32 = 8;
+
+/// How the connection is doing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    /// The client is connected
+    pub connected: bool,
+
+    /// Time since the session was established
+    pub session_age: Option<Duration>,
+
+    /// Protocol version the server uses
+    pub protocol_version: Option<ProtocolVersion>,
+
+    /// Capabilities the server announced
+    pub capabilities: Vec<String>,
+
+    /// Round-trip time of the last ping answered
+    pub last_rtt: Option<Duration>,
+
+    /// Smoothed round-trip time
+    pub rtt: Option<Duration>,
+
+    /// Pings sent
+    pub pings: u64,
+
+    /// Pings that went unanswered
+    pub pings_lost: u64,
+
+    /// Bytes sent to the server
+    pub bytes_sent: u64,
+
+    /// Bytes received from the server
+    pub bytes_received: u64,
+}
+
+impl Health {
+    /// Share of pings lost, if any were sent
+    pub fn loss(&self) -> Option<f64> {
+        (self.pings > 0).then(|| self.pings_lost as f64 / self.pings as f64)
+    }
+}
+
+/// Counters kept by the client
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    pings: AtomicU64,
+    pings_lost: AtomicU64,
+
+    /// Last and smoothed round-trip time
+    rtt: Mutex<Option<(Duration, Duration)>>,
+
+    /// When the last message arrived
+    last_received: Mutex<Option<Instant>>,
+}
+
+impl Meter {
+    /// Count a message of `len` bytes sent
+    pub(crate) fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count a message of `len` bytes received
+    pub(crate) fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        *self.last_received.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Time since the last message arrived
+    pub(crate) fn idle_time(&self) -> Duration {
+        self.last_received
+            .lock()
+            .unwrap()
+            .map_or(Duration::MAX, |at| at.elapsed())
+    }
+
+    /// Record a ping, answered after `rtt` or lost (`None`)
+    pub(crate) fn ping(&self, rtt: Option<Duration>) {
+        self.pings.fetch_add(1, Ordering::Relaxed);
+        let Some(sample) = rtt else {
+            self.pings_lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let mut rtt = self.rtt.lock().unwrap();
+        let smoothed = match *rtt {
+            Some((_, smoothed)) => (smoothed * (RTT_SMOOTHING - 1) + sample) / RTT_SMOOTHING,
+            None => sample,
+        };
+        *rtt = Some((sample, smoothed));
+    }
+
+    /// Fill in the counted parts of `health`
+    pub(crate) fn fill(&self, health: &mut Health) {
+        let rtt = *self.rtt.lock().unwrap();
+        health.last_rtt = rtt.map(|(last, _)| last);
+        health.rtt = rtt.map(|(_, smoothed)| smoothed);
+        health.pings = self.pings.load(Ordering::Relaxed);
+        health.pings_lost = self.pings_lost.load(Ordering::Relaxed);
+        health.bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        health.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+    }
+}
+
+/// Format a duration as `1h 02m 03s`, `2m 03s` or `3s`
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        let meter = Meter::default();
+        meter.sent(100);
+        meter.received(40);
+        meter.received(2);
+        meter.ping(Some(Duration::from_millis(80)));
+        meter.ping(None);
+        meter.ping(Some(Duration::from_millis(160)));
+
+        assert!(meter.idle_time() < Duration::from_secs(1));
+        assert_eq!(Meter::default().idle_time(), Duration::MAX);
+
+        let mut health = Health::default();
+        meter.fill(&mut health);
+        assert_eq!(health.bytes_sent, 100);
+        assert_eq!(health.bytes_received, 42);
+        assert_eq!(health.pings, 3);
+        assert_eq!(health.pings_lost, 1);
+        assert_eq!(health.last_rtt, Some(Duration::from_millis(160)));
+        assert_eq!(health.rtt, Some(Duration::from_millis(90)));
+        assert_eq!(health.loss(), Some(1.0 / 3.0));
+        assert_eq!(Health::default().loss(), None);
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(3)), "3s");
+        assert_eq!(format_age(Duration::from_secs(123)), "2m 03s");
+        assert_eq!(format_age(Duration::from_secs(3723)), "1h 02m 03s");
+    }
+}
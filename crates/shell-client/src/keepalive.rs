@@ -0,0 +1,83 @@
+//! Keeping idle connections alive
+//!
+//! After connecting, a task pings the server whenever nothing came from it
+//! for the keepalive interval: `keepalive_interval` from the configuration,
+//! or the server's from ACCEPT if that is shorter. Answered pings update the
+//! round-trip time `status` shows. Once [`MAX_MISSED`] pings in a row go
+//! unanswered for [`PONG_TIMEOUT`] each, the connection counts as lost: the
+//! next request reconnects first (given `reconnect_attempts`) or fails as
+//! not connected.
+//!
+//! Pings are only sent while the connection is idle. During a request, a
+//! terminal session or forwarding, the connection is in use and its own
+//! traffic tells whether it still works.
+
+use crate::{client::Client, ClientError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// How long to wait for a pong
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unanswered pings in a row after which the connection counts as lost
+pub const MAX_MISSED: u32 = 3;
+
+/// Keep the connection of `client` alive until the client is dropped (or
+/// at once if `keepalive_interval` is 0)
+pub fn spawn(client: &Arc<Client>) -> JoinHandle<()> {
+    let client = Arc::downgrade(client);
+    tokio::spawn(async move {
+        let mut missed = 0;
+        loop {
+            // Not holding on to the client while waiting, so it can go
+            let wait = {
+                let Some(client) = client.upgrade() else {
+                    return;
+                };
+                let Some(interval) = client.keepalive_interval().await else {
+                    return;
+                };
+                let idle = client.idle_time();
+                if !client.is_connected().await {
+                    missed = 0;
+                    interval
+                } else if idle < interval {
+                    interval - idle
+                } else {
+                    match client.ping_if_idle(PONG_TIMEOUT).await {
+                        // In use
+                        None | Some(Err(ClientError::NotConnected)) => interval,
+                        Some(Ok(rtt)) => {
+                            debug!(rtt_ms = rtt.as_millis() as u64, "Keepalive ping answered");
+                            missed = 0;
+                            interval
+                        }
+                        Some(Err(ClientError::Timeout(_))) => {
+                            missed += 1;
+                            debug!(missed, "Keepalive ping went unanswered");
+                            if missed >= MAX_MISSED {
+                                client.connection_lost().await;
+                                missed = 0;
+                            }
+                            // The next one goes out at once
+                            Duration::ZERO
+                        }
+                        Some(Err(e @ (ClientError::Network(_) | ClientError::Io(_)))) => {
+                            debug!(error = %e, "Keepalive ping failed");
+                            client.connection_lost().await;
+                            missed = 0;
+                            interval
+                        }
+                        Some(Err(e)) => {
+                            debug!(error = %e, "Keepalive ping failed");
+                            interval
+                        }
+                    }
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    })
+}
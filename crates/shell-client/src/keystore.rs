@@ -0,0 +1,128 @@
+//! Named identities
+//!
+//! The keystore is a directory (`keystore_path`, default `identities`) of
+//! identity files named `<name>.identity`, plain or encrypted with a
+//! passphrase. `-i <name>` presents one of them instead of `identity_path`,
+//! to every server including those of profiles, so one operator can keep
+//! apart the identities they use for different servers. `-i` also takes the
+//! path of an identity file elsewhere.
+
+use crate::{config::ClientConfig, ClientError, Result};
+use std::path::{Path, PathBuf};
+
+/// Extension of the identity files in the keystore
+pub const EXTENSION: &str = "identity";
+
+/// A directory of named identities
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keystore {
+    dir: PathBuf,
+}
+
+impl Keystore {
+    /// The keystore in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The keystore of the configuration
+    pub fn from_config(config: &ClientConfig) -> Self {
+        Self::new(&config.keystore_path)
+    }
+
+    /// The file of the identity `name`
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, EXTENSION))
+    }
+
+    /// Names of the identities in the keystore, sorted (none if the
+    /// directory does not exist)
+    pub fn names(&self) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(name) = self.name_of(&path) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The name of the identity file at `path`, if it is in the keystore
+    pub fn name_of(&self, path: &Path) -> Option<String> {
+        if path.parent()? != self.dir || path.extension()? != EXTENSION {
+            return None;
+        }
+        Some(path.file_stem()?.to_string_lossy().into_owned())
+    }
+
+    /// The identity file `name_or_path` stands for: the identity of that
+    /// name in the keystore, or else the file at that path
+    pub fn resolve(&self, name_or_path: &str) -> Result<PathBuf> {
+        if !name_or_path.is_empty() && !name_or_path.chars().any(std::path::is_separator) {
+            let path = self.path(name_or_path);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+        let path = PathBuf::from(name_or_path);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let names = self.names().unwrap_or_default();
+        let known = if names.is_empty() {
+            "it holds none".to_string()
+        } else {
+            format!("it holds {}", names.join(", "))
+        };
+        Err(ClientError::Config(format!(
+            "No identity {:?} in the keystore {} ({}), nor a file of that name",
+            name_or_path,
+            self.dir.display(),
+            known
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reticulum_core::Identity;
+
+    #[test]
+    fn test_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::new(dir.path().join("identities"));
+        assert_eq!(keystore.names().unwrap(), Vec::<String>::new());
+
+        std::fs::create_dir(dir.path().join("identities")).unwrap();
+        for name in ["prod", "lab"] {
+            Identity::generate()
+                .save_to_file(keystore.path(name))
+                .unwrap();
+        }
+        std::fs::write(dir.path().join("identities/notes.txt"), "").unwrap();
+        assert_eq!(keystore.names().unwrap(), vec!["lab", "prod"]);
+
+        let prod = keystore.resolve("prod").unwrap();
+        assert_eq!(prod, keystore.path("prod"));
+        assert_eq!(keystore.name_of(&prod).as_deref(), Some("prod"));
+
+        // Paths outside the keystore work too
+        let other = dir.path().join("other.identity");
+        Identity::generate().save_to_file(&other).unwrap();
+        let resolved = keystore.resolve(other.to_str().unwrap()).unwrap();
+        assert_eq!(resolved, other);
+        assert_eq!(keystore.name_of(&resolved), None);
+
+        let error = keystore.resolve("staging").unwrap_err().to_string();
+        assert!(error.contains("lab, prod"), "{}", error);
+    }
+}
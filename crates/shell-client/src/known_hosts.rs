@@ -0,0 +1,166 @@
+//! Known server keys
+//!
+//! The first connection to a server records the identity key it presents in
+//! ACCEPT, once the user agrees to trust it (trust on first use). Later
+//! connections to the same destination must present that key again; a
+//! different one fails the connection, as someone may be impersonating the
+//! server.
+//!
+//! The file holds one `<destination hex> <public key hex>` line per server.
+//! Blank lines and lines starting with `#` are ignored. To accept a server's
+//! new key, remove its line.
+
+use crate::{ClientError, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// What the known hosts say about a server's key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The key recorded for the destination
+    Known,
+
+    /// No key is recorded for the destination
+    Unknown,
+
+    /// A different key is recorded for the destination (on `line`)
+    Changed { line: usize },
+}
+
+/// The known hosts file, as read
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    /// Where the file is
+    path: PathBuf,
+
+    /// Key and line number by destination
+    entries: HashMap<[u8; 32], (Vec<u8>, usize)>,
+}
+
+impl KnownHosts {
+    /// Read the file at `path`; a missing file knows no hosts
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut known = Self::parse(&source).map_err(|e| match e {
+            ClientError::Config(message) => {
+                ClientError::Config(format!("{}: {}", path.display(), message))
+            }
+            e => e,
+        })?;
+        known.path = path.to_path_buf();
+        Ok(known)
+    }
+
+    /// Parse the contents of a known hosts file
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut entries = HashMap::new();
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+
+            let entry = match text.split_whitespace().collect::<Vec<_>>()[..] {
+                [destination, key] => hex::decode(destination)
+                    .ok()
+                    .and_then(|d| <[u8; 32]>::try_from(d).ok())
+                    .zip(hex::decode(key).ok().filter(|key| !key.is_empty())),
+                _ => None,
+            };
+            let (destination, key) = entry.ok_or_else(|| {
+                ClientError::Config(format!(
+                    "line {}: expected <destination hex> <public key hex>",
+                    line
+                ))
+            })?;
+            // The first entry for a destination counts, as in OpenSSH
+            entries.entry(destination).or_insert((key, line));
+        }
+
+        Ok(Self {
+            path: PathBuf::new(),
+            entries,
+        })
+    }
+
+    /// Check `key` against the one recorded for `destination`
+    pub fn check(&self, destination: &[u8; 32], key: &[u8]) -> HostKeyStatus {
+        match self.entries.get(destination) {
+            None => HostKeyStatus::Unknown,
+            Some((known, _)) if known == key => HostKeyStatus::Known,
+            Some((_, line)) => HostKeyStatus::Changed { line: *line },
+        }
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Record `key` for `destination` at the end of the file at `path`
+pub fn add(path: &Path, destination: &[u8; 32], key: &[u8]) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", hex::encode(destination), hex::encode(key))?;
+    Ok(())
+}
+
+/// Short form of a key to show to users
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", hex::encode(Sha256::digest(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("known_hosts");
+        let known = KnownHosts::load(&path).unwrap();
+        assert_eq!(known.check(&[1; 32], b"key"), HostKeyStatus::Unknown);
+
+        add(&path, &[1; 32], b"key").unwrap();
+        add(&path, &[2; 32], b"other").unwrap();
+        let known = KnownHosts::load(&path).unwrap();
+        assert_eq!(known.check(&[1; 32], b"key"), HostKeyStatus::Known);
+        assert_eq!(
+            known.check(&[2; 32], b"key"),
+            HostKeyStatus::Changed { line: 2 }
+        );
+        assert_eq!(known.check(&[3; 32], b"key"), HostKeyStatus::Unknown);
+        assert_eq!(known.path(), path);
+
+        let known = KnownHosts::parse(&format!("# servers\n\n{} 00ff\n", "01".repeat(32))).unwrap();
+        assert_eq!(known.check(&[1; 32], &[0, 0xff]), HostKeyStatus::Known);
+
+        let extra = format!("{} 00ff extra", "01".repeat(32));
+        for source in ["0102 00ff", "zz 00ff", extra.as_str()] {
+            let e = KnownHosts::parse(source).unwrap_err().to_string();
+            assert!(e.contains("line 1"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let shown = fingerprint(b"key");
+        assert!(shown.starts_with("SHA256:"));
+        assert_eq!(shown.len(), "SHA256:".len() + 64);
+        assert_ne!(shown, fingerprint(b"other"));
+    }
+}
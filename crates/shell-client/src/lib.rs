@@ -1,10 +1,65 @@
 //! Shell Client Library
 //!
 //! Core functionality for the remote shell client
+//!
+//! The library also builds for `wasm32-unknown-unknown` (`cargo build
+//! --target wasm32-unknown-unknown --lib`), for a shell client running in a
+//! browser: the [`Client`](client::Client) over a
+//! `reticulum_core::WebSocketInterface` to a gateway. Whatever needs a
+//! terminal, processes, sockets or background tasks (the REPL, PTYs,
+//! scripts, forwarding, the control socket, discovery, ...) is left out.
 
+pub mod audit;
 pub mod client;
 pub mod config;
+pub mod delta;
+pub mod env;
 pub mod error;
+pub mod events;
+pub mod health;
+pub mod keystore;
+pub mod known_hosts;
+pub mod output;
+pub mod predict;
+pub mod retry;
+pub mod time;
+pub mod transcript;
+pub mod transfer;
+pub mod vars;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bench;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod browser;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod completion;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod connect;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod control;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discover;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fanout;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod forward;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod keepalive;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pager;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pty;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod redirect;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod repl;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod script;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod session;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod watch;
 
 pub use error::{ClientError, Result};
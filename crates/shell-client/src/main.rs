@@ -3,11 +3,34 @@
 //! Connects to a shell server and provides an interactive REPL for executing commands.
 
 use clap::Parser;
-use reticulum_core::{I2pInterface, NetworkInterface};
-use shell_client::{client::Client, config::ClientConfig, repl::Repl, Result};
-use std::path::PathBuf;
+use reticulum_core::ShareString;
+use shell_client::{
+    bench,
+    client::CommandOptions,
+    config::{ClientConfig, ServerProfile},
+    connect,
+    control::{self, Master},
+    discover, env, fanout, forward, keepalive,
+    known_hosts::{self, HostKeyStatus, KnownHosts},
+    output::{CommandRecord, OutputFormat},
+    predict::Prediction,
+    pty,
+    repl::Repl,
+    script::{self, Script},
+    watch, ClientError, Result,
+};
+use shell_proto::{CommandRequest, CommandResponse, MAX_CHUNK_SIZE};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Chunks of stdin read ahead of what was sent
+const STDIN_QUEUE: usize = 16;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,6 +43,39 @@ struct Args {
     #[arg(short, long, default_value = "client.toml")]
     config: PathBuf,
 
+    /// Server profile to connect to (a [[servers]] entry of the config)
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    /// Reach the server through these jump hosts, in order (comma-separated
+    /// profile names)
+    #[arg(long, value_delimiter = ',', value_name = "HOPS")]
+    via: Vec<String>,
+
+    /// Run the command given with -e on the servers of these profiles at
+    /// once (comma-separated profile names)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "PROFILES",
+        requires = "execute",
+        conflicts_with_all = ["profile", "via", "pty", "server", "tcp"]
+    )]
+    hosts: Vec<String>,
+
+    /// Run the command given with -e on the servers of all profiles at once
+    #[arg(
+        long,
+        requires = "execute",
+        conflicts_with_all = ["hosts", "profile", "via", "pty", "server", "tcp"]
+    )]
+    all: bool,
+
+    /// Identity to present: a name in the keystore (keystore_path) or the
+    /// path of an identity file, for every server and profile
+    #[arg(short, long, value_name = "NAME_OR_PATH")]
+    identity: Option<String>,
+
     /// Verbose logging
     #[arg(short, long)]
     verbose: bool,
@@ -28,10 +84,136 @@ struct Args {
     #[arg(long)]
     generate_identity: Option<PathBuf>,
 
+    /// Protect the generated identity with a passphrase (typed twice, or
+    /// taken from RSH_IDENTITY_PASSPHRASE)
+    #[arg(long, requires = "generate_identity")]
+    encrypt: bool,
+
+    /// Validate the configuration and identity file, then exit (status 1 if
+    /// there are errors)
+    #[arg(long)]
+    check_config: bool,
+
+    /// Add the server of a share string (printed by the server's
+    /// --show-destination) as a [[servers]] profile, trusting its key, then
+    /// exit
+    #[arg(long, value_name = "SHARE_STRING")]
+    add_server: Option<ShareString>,
+
+    /// Profile name for --add-server (default: the name in the share string)
+    #[arg(long, requires = "add_server")]
+    name: Option<String>,
+
     /// Execute a single command and exit
     #[arg(short = 'e', long)]
     execute: Option<String>,
 
+    /// Run the commands of a script file in order, then exit with the code
+    /// of the first that failed
+    #[arg(short = 'f', long, value_name = "SCRIPT", conflicts_with_all = ["execute", "pty"])]
+    file: Option<PathBuf>,
+
+    /// Timeout for the command run with -e (seconds; default:
+    /// command_timeout)
+    #[arg(short = 't', long, value_name = "SECS", requires = "execute")]
+    timeout: Option<u64>,
+
+    /// Run the command given with -e every SECS seconds, redrawing its
+    /// output, until interrupted
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "execute",
+        value_parser = parse_interval,
+        conflicts_with_all = ["pty", "hosts", "all"]
+    )]
+    watch: Option<Duration>,
+
+    /// Do not pass stdin on to the command run with -e (it is passed on
+    /// when not a terminal)
+    #[arg(short = 'n', long, requires = "execute")]
+    no_stdin: bool,
+
+    /// The command run with -e is safe to run twice: send it again when a
+    /// transport failure lost it or its reply (up to `retry_attempts` times)
+    #[arg(long, requires = "execute")]
+    idempotent: bool,
+
+    /// Working directory for the command run with -e (relative ones are
+    /// taken from the session's)
+    #[arg(short = 'w', long, value_name = "DIR", requires = "execute")]
+    working_dir: Option<String>,
+
+    /// Open an interactive terminal on the server instead of the REPL (with
+    /// -e, run that command in it)
+    #[arg(long)]
+    pty: bool,
+
+    /// Echo keystrokes in interactive terminals before the server does
+    /// (overrides `predict` in the configuration)
+    #[arg(long, value_enum, value_name = "MODE")]
+    predict: Option<Prediction>,
+
+    /// Run a SOCKS5 proxy on this local port (or address:port) whose
+    /// connections the server makes, until interrupted
+    #[arg(
+        short = 'D',
+        long,
+        value_name = "[ADDR:]PORT",
+        conflicts_with_all = ["execute", "file", "pty", "hosts", "all"]
+    )]
+    dynamic: Option<String>,
+
+    /// Keep the session open and share it on `control_path`, where -e in
+    /// other processes runs its commands, until interrupted
+    #[arg(
+        long,
+        conflicts_with_all = ["execute", "file", "pty", "dynamic", "hosts", "all"]
+    )]
+    master: bool,
+
+    /// Measure the link to the server: send COUNT pings, small and large
+    /// echoes and report round-trip times, jitter, loss and throughput
+    #[arg(
+        long,
+        value_name = "COUNT",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["execute", "file", "pty", "dynamic", "master", "hosts", "all"]
+    )]
+    ping: Option<u32>,
+
+    /// Listen SECS seconds for servers announcing themselves on the
+    /// configured interfaces and list them, then exit
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["execute", "file", "pty", "dynamic", "master", "hosts", "all", "ping"]
+    )]
+    discover: Option<u64>,
+
+    /// Open a terminal UI with a tab for each of these profiles (or the
+    /// server selected), to work on several servers side by side
+    #[cfg(feature = "tui")]
+    #[arg(
+        long,
+        value_name = "PROFILES",
+        value_delimiter = ',',
+        num_args = 0..=1,
+        conflicts_with_all = [
+            "execute", "file", "pty", "dynamic", "master", "hosts", "all", "ping", "discover"
+        ]
+    )]
+    tui: Option<Vec<String>>,
+
+    /// Save the servers found by --discover as [[servers]] profiles
+    #[arg(long, requires = "discover")]
+    save: bool,
+
     /// Enable I2P transport
     #[arg(long)]
     enable_i2p: bool,
@@ -48,12 +230,76 @@ struct Args {
     /// Server I2P destination (base64 string)
     #[arg(long)]
     i2p_destination: Option<String>,
+
+    /// Connect to the server over TCP (host:port) instead of I2P
+    #[arg(long)]
+    tcp: Option<SocketAddr>,
+
+    /// Auth token to present when connecting
+    #[arg(long)]
+    auth_token: Option<String>,
+
+    /// Acknowledge the server banner without asking
+    #[arg(long)]
+    accept_banner: bool,
+
+    /// Trust the key of a server not connected to before without asking
+    /// (a changed key still fails)
+    #[arg(long)]
+    accept_new_host_key: bool,
+
+    /// Have the server set this secret as an environment variable for every
+    /// command (repeatable)
+    #[arg(long = "with-secret", value_name = "NAME")]
+    with_secret: Vec<String>,
+
+    /// Set an environment variable for every command (repeatable)
+    #[arg(long = "env", value_name = "NAME=VALUE")]
+    env: Vec<String>,
+
+    /// Pass on the local environment variables whose names match, e.g.
+    /// 'LC_*' (repeatable)
+    #[arg(long = "send-env", value_name = "PATTERN")]
+    send_env: Vec<String>,
+
+    /// How -e and -f print results: as the commands write them, or one
+    /// JSON object per command on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Print long output in the REPL at once instead of through $PAGER or
+    /// the built-in pager
+    #[arg(long)]
+    no_pager: bool,
+
+    /// Append a transcript of the commands run and their output to this
+    /// file, like script(1)
+    #[arg(long, value_name = "FILE")]
+    log_session: Option<PathBuf>,
+
+    /// Append the timing of the transcript to this file, to play it back
+    /// with scriptreplay(1)
+    #[arg(long, value_name = "FILE", requires = "log_session")]
+    log_timing: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    if args.check_config {
+        let problems = ClientConfig::check_file(&args.config);
+        for problem in &problems {
+            println!("error: {}", problem);
+        }
+        if !problems.is_empty() {
+            eprintln!("{}: {} error(s)", args.config.display(), problems.len());
+            std::process::exit(1);
+        }
+        eprintln!("{}: OK", args.config.display());
+        return Ok(());
+    }
+
     // Initialize logging
     let log_level = if args.verbose {
         tracing::Level::DEBUG
@@ -61,16 +307,36 @@ async fn main() -> Result<()> {
         tracing::Level::INFO
     };
 
-    tracing_subscriber::fmt()
+    let logger = tracing_subscriber::fmt()
         .with_max_level(log_level)
-        .with_target(false)
-        .init();
+        .with_target(false);
+    // Keep stdout to the JSON records, and the terminal to the TUI
+    #[cfg(feature = "tui")]
+    let tui = args.tui.is_some();
+    #[cfg(not(feature = "tui"))]
+    let tui = false;
+    if tui {
+        #[cfg(feature = "tui")]
+        logger
+            .with_ansi(false)
+            .with_writer(shell_client::tui::LogWriter::default)
+            .init();
+    } else if args.output == OutputFormat::Json {
+        logger.with_writer(std::io::stderr).init();
+    } else {
+        logger.init();
+    }
 
     // Handle identity generation
     if let Some(identity_path) = args.generate_identity {
         info!("Generating new identity at {:?}", identity_path);
         let identity = reticulum_core::Identity::generate();
-        identity.save_to_file(&identity_path)?;
+        if args.encrypt {
+            let passphrase = rsh_agent::passphrase::choose("New passphrase: ")?;
+            identity.save_encrypted(&identity_path, &passphrase)?;
+        } else {
+            identity.save_to_file(&identity_path)?;
+        }
         info!("Identity saved: {}", identity.destination_hex());
         return Ok(());
     }
@@ -78,7 +344,7 @@ async fn main() -> Result<()> {
     // Load or create configuration
     let mut config = if args.config.exists() {
         info!("Loading configuration from {:?}", args.config);
-        ClientConfig::load_from_file(&args.config)?
+        ClientConfig::load_with_identity(&args.config, args.identity.as_deref())?
     } else {
         info!("Configuration file not found, creating default configuration");
 
@@ -88,11 +354,13 @@ async fn main() -> Result<()> {
         // Generate identity if it doesn't exist
         if !config.identity_path.exists() {
             info!("Generating new client identity at {:?}", config.identity_path);
-            config.identity.save_to_file(&config.identity_path)?;
-            info!("Client identity saved: {}", config.identity.destination_hex());
+            let identity = reticulum_core::Identity::generate();
+            identity.save_to_file(&config.identity_path)?;
+            info!("Client identity saved: {}", identity.destination_hex());
+            config.identity = Arc::new(identity);
         } else {
             // Load existing identity
-            config.identity = reticulum_core::Identity::load_from_file(&config.identity_path)?;
+            config.identity = config.load_identity()?;
         }
 
         // Save config for future use
@@ -100,123 +368,319 @@ async fn main() -> Result<()> {
         info!("Default configuration saved to {:?}", args.config);
         info!("IMPORTANT: Edit {:?} and set the server_destination", args.config);
 
+        if let Some(identity) = &args.identity {
+            config.use_identity(identity)?;
+        }
         config
     };
 
+    if let Some(share) = &args.add_server {
+        return add_server(&mut config, &args.config, share, args.name.as_deref());
+    }
+
+    // Settings for every server
+    config.secrets.extend(args.with_secret);
+    config.send_env.extend(args.send_env);
+    for spec in &args.env {
+        let (name, value) = env::parse_var(spec)?;
+        config.env.insert(name, value);
+    }
+    if let Some(sam_address) = args.sam_address {
+        config.sam_address = sam_address;
+    }
+    #[cfg(feature = "embedded-router")]
+    if args.use_embedded_router {
+        config.router_mode = reticulum_core::RouterMode::Embedded;
+    }
+
+    config.accept_new_host_keys |= args.accept_new_host_key;
+    if let Some(predict) = args.predict {
+        config.predict = predict;
+    }
+    if let Some(path) = args.log_session {
+        config.session_log_path = Some(path);
+        config.session_timing_path = args.log_timing;
+    }
+
+    // Select a server profile; the REPL can switch to the others
+    let profiles = config.clone();
+    if let Some(name) = &args.profile {
+        config = config.with_profile(name)?;
+    }
+
+    // Run on several servers at once, each with its own profile
+    if args.all || !args.hosts.is_empty() {
+        let hosts: Vec<String> = if args.all {
+            profiles
+                .profile_names()
+                .into_iter()
+                .map(String::from)
+                .collect()
+        } else {
+            args.hosts
+        };
+        if hosts.is_empty() {
+            return Err(shell_client::ClientError::Config(
+                "No server profiles are configured".to_string(),
+            ));
+        }
+
+        let command = args.execute.unwrap_or_default();
+        let argv = shell_words::split(&command)
+            .map_err(|e| shell_client::ClientError::Config(format!("Invalid command: {}", e)))?;
+        if argv.is_empty() {
+            error!("Empty command");
+            return Ok(());
+        }
+        let options = CommandOptions {
+            timeout: args.timeout,
+            working_dir: args.working_dir,
+            idempotent: args.idempotent,
+            ..CommandOptions::default()
+        };
+
+        let results = fanout::run(
+            &profiles,
+            &hosts,
+            argv,
+            options,
+            args.accept_banner,
+            args.output,
+        )
+        .await;
+        if args.output == OutputFormat::Text {
+            eprint!("{}", fanout::summary(&results));
+        }
+        std::process::exit(fanout::exit_code(&results));
+    }
+
     // Override server if provided via CLI
     if let Some(server) = args.server {
         config.server_destination = server;
     }
+    if args.auth_token.is_some() {
+        config.auth_token = args.auth_token;
+    }
 
     // Override I2P settings with CLI args if provided
-    let enable_i2p = args.enable_i2p || config.enable_i2p;
-    let sam_address = args.sam_address.unwrap_or(config.sam_address.clone());
-    let server_i2p_dest = args.i2p_destination.or(config.server_i2p_destination.clone());
-
-    #[cfg(feature = "embedded-router")]
-    let use_embedded = args.use_embedded_router
-        || matches!(config.router_mode, reticulum_core::RouterMode::Embedded);
+    config.enable_i2p |= args.enable_i2p;
+    if args.i2p_destination.is_some() {
+        config.server_i2p_destination = args.i2p_destination;
+    }
+    if args.tcp.is_some() {
+        config.server_tcp_address = args.tcp;
+    }
 
     info!("Client identity: {}", config.identity.destination_hex());
 
-    // Create client with optional I2P interface
-    let client = if enable_i2p {
-        // Create I2P interface (embedded or external)
-        let i2p_interface = {
-            #[cfg(feature = "embedded-router")]
-            if use_embedded {
-                info!("Starting embedded I2P router...");
+    #[cfg(feature = "tui")]
+    if let Some(names) = &args.tui {
+        let initial = if names.is_empty() {
+            let name = args.profile.clone().unwrap_or_else(|| "server".to_string());
+            vec![(name, config)]
+        } else {
+            names
+                .iter()
+                .map(|name| Ok((name.clone(), profiles.with_profile(name)?)))
+                .collect::<Result<_>>()?
+        };
+        return shell_client::tui::run(profiles, initial, args.accept_banner).await;
+    }
 
-                let router = reticulum_core::EmbeddedRouter::new(config.embedded_router.clone())
-                    .await
-                    .map_err(|e| {
-                        error!("Failed to start embedded router: {}", e);
-                        e
-                    })?;
-
-                info!("Embedded router started successfully");
-
-                // Wait for router to be ready
-                router.wait_ready().await?;
-
-                info!("Connecting to embedded router via SAM...");
-                match I2pInterface::new_embedded(&router).await {
-                    Ok(iface) => {
-                        info!("I2P interface created successfully");
-                        info!("Client I2P destination: {}", iface.local_destination());
-                        info!("Client I2P destination hash: {}", hex::encode(iface.local_destination_hash()));
-                        iface
-                    }
-                    Err(e) => {
-                        error!("Failed to create I2P interface: {}", e);
-                        return Err(e.into());
-                    }
-                }
-            } else {
-                info!("Connecting to external I2P router via SAM bridge at {}", sam_address);
-
-                match I2pInterface::new(&sam_address).await {
-                    Ok(iface) => {
-                        info!("I2P interface created successfully");
-                        info!("Client I2P destination: {}", iface.local_destination());
-                        info!("Client I2P destination hash: {}", hex::encode(iface.local_destination_hash()));
-                        iface
-                    }
-                    Err(e) => {
-                        error!("Failed to create I2P interface: {}", e);
-                        error!("Make sure I2P router is running with SAM bridge enabled on {}", sam_address);
-                        return Err(e.into());
-                    }
+    if let Some(secs) = args.discover {
+        let servers = discover::run(&config, Duration::from_secs(secs)).await?;
+        match args.output {
+            OutputFormat::Text if servers.is_empty() => eprintln!("No servers heard from"),
+            OutputFormat::Text => print!("{}", discover::report(&servers)),
+            OutputFormat::Json => {
+                for server in &servers {
+                    println!(
+                        "{}",
+                        serde_json::to_string(server).expect("servers serialize")
+                    );
                 }
             }
+        }
+        if args.save {
+            save_discovered(&mut config, &args.config, &servers)?;
+        }
+        return Ok(());
+    }
 
-            #[cfg(not(feature = "embedded-router"))]
-            {
-                info!("Connecting to external I2P router via SAM bridge at {}", sam_address);
-
-                match I2pInterface::new(&sam_address).await {
-                    Ok(iface) => {
-                        info!("I2P interface created successfully");
-                        info!("Client I2P destination: {}", iface.local_destination());
-                        info!("Client I2P destination hash: {}", hex::encode(iface.local_destination_hash()));
-                        iface
-                    }
-                    Err(e) => {
-                        error!("Failed to create I2P interface: {}", e);
-                        error!("Make sure I2P router is running with SAM bridge enabled on {}", sam_address);
-                        return Err(e.into());
-                    }
-                }
+    // Run -e through a master's connection if one shares it (--pty and
+    // --watch need a connection of their own)
+    let control_path = control::socket_path(&config);
+    let shared = !args.pty && args.watch.is_none();
+    if let (Some(command), Some(path), true) = (&args.execute, &control_path, shared) {
+        if let Some(master) = Master::connect(path).await {
+            let parts: Vec<String> = shell_words::split(command).map_err(|e| {
+                shell_client::ClientError::Config(format!("Invalid command: {}", e))
+            })?;
+            if parts.is_empty() {
+                error!("Empty command");
+                return Ok(());
             }
-        };
+            let mut env = env::matching(&config.send_env, std::env::vars());
+            env.extend(config.env.clone());
+            let input = (!args.no_stdin && !std::io::stdin().is_terminal()).then(read_stdin);
+            let request = CommandRequest {
+                id: 1,
+                command: parts[0].clone(),
+                args: parts[1..].to_vec(),
+                env: (!env.is_empty()).then_some(env),
+                timeout: args.timeout,
+                working_dir: args.working_dir.clone(),
+                secrets: vec![],
+                stdin: input.is_some(),
+                delta_base: None,
+            };
+            let start = std::time::Instant::now();
+            let result = master.execute(request, input, &mut |_, _| {}).await;
+            report(parts, result, args.output, start.elapsed());
+        }
+    }
 
-        // Parse and register server I2P destination
-        let server_dest_hash = if let Some(ref i2p_dest) = server_i2p_dest {
-            info!("Registering server I2P destination: {}...", &i2p_dest[..20.min(i2p_dest.len())]);
-            i2p_interface.register_destination(i2p_dest.clone()).await
+    // Check the script and the proxy address before connecting
+    let script = args.file.as_deref().map(Script::load).transpose()?;
+    let dynamic = args
+        .dynamic
+        .as_deref()
+        .map(forward::parse_bind)
+        .transpose()?;
+
+    // Create client with a TCP or I2P interface, through any jump hosts,
+    // and connect to the server
+    let connected = async {
+        let client = if args.via.is_empty() {
+            connect::open(config).await?
         } else {
-            error!("I2P enabled but no server I2P destination provided");
-            error!("Use --i2p-destination flag or set server_i2p_destination in config");
+            connect::open_via(&profiles, &args.via, config, args.accept_banner).await?
+        };
+        client.connect().await?;
+        Ok::<_, ClientError>(client)
+    };
+    let client = match connected.await {
+        Ok(client) => client,
+        // Scripts and -e tell a server out of reach from a failed command
+        Err(e) if (args.execute.is_some() || script.is_some()) && !args.pty => {
+            error!("Cannot connect to the server: {}", e);
+            std::process::exit(script::EXIT_FAILED);
+        }
+        Err(e) => return Err(e),
+    };
+    info!("Connected to server");
+
+    // Only ask when there is someone to answer
+    let interactive = (args.execute.is_none() && script.is_none()) || args.pty;
+    if client.host_key_unknown() {
+        eprintln!(
+            "The server's key is not known yet: {}",
+            client.host_key_fingerprint().await.unwrap_or_default()
+        );
+        if !(interactive && confirm("Trust it and continue? [y/N] ")) {
+            client.disconnect().await?;
+            return Err(shell_client::ClientError::Request(
+                "The server key was not trusted (see --accept-new-host-key)".to_string(),
+            ));
+        }
+        client.trust_host_key().await?;
+    }
+
+    if let Some(banner) = client.banner().await {
+        eprintln!("{}", banner.trim_end());
+    }
+    if client.banner_ack_required() {
+        let accepted = args.accept_banner || (interactive && confirm("Accept? [y/N] "));
+        if !accepted {
+            client.disconnect().await?;
+            return Err(shell_client::ClientError::Request(
+                "The server banner was not accepted (see --accept-banner)".to_string(),
+            ));
+        }
+        client.acknowledge_banner().await?;
+    }
+
+    if args.master {
+        let Some(path) = control_path else {
+            client.disconnect().await?;
             return Err(shell_client::ClientError::Config(
-                "Missing server I2P destination".to_string()
-            ).into());
+                "--master needs control_path in the configuration".to_string(),
+            ));
+        };
+        let client = Arc::new(client);
+        keepalive::spawn(&client);
+        let result = tokio::select! {
+            result = control::serve(Arc::clone(&client), &path) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
         };
+        let _ = client.disconnect().await;
+        return result;
+    }
 
-        info!("Server I2P destination hash: {}", hex::encode(server_dest_hash));
+    if let Some(count) = args.ping {
+        let result = bench::run(&client, count, args.output == OutputFormat::Text).await;
+        let _ = client.disconnect().await;
+        let results = result?;
+        match args.output {
+            OutputFormat::Text => print!("{}", bench::report(&results)),
+            OutputFormat::Json => {
+                for series in &results {
+                    println!(
+                        "{}",
+                        serde_json::to_string(series).expect("results serialize")
+                    );
+                }
+            }
+        }
+        // Nothing came back
+        if results.iter().any(|series| series.lost == series.sent) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-        let interface: Arc<dyn NetworkInterface> = Arc::new(i2p_interface);
-        Client::with_interface(config, interface, server_dest_hash).await?
-    } else {
-        info!("Connecting to server: {}", config.server_destination);
-        Client::new(config).await?
-    };
+    if let Some(bind) = dynamic {
+        let client = Arc::new(client);
+        let result = tokio::select! {
+            result = forward::dynamic(Arc::clone(&client), bind) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        };
+        let _ = client.disconnect().await;
+        return result;
+    }
 
-    // Connect to server
-    client.connect().await?;
-    info!("Connected to server");
+    // Open a terminal, run a script, execute a single command or start the
+    // REPL
+    if args.pty {
+        let (command, cmd_args) = match args.execute {
+            Some(command) => {
+                let mut parts = shell_words::split(&command)
+                    .map_err(|e| {
+                        shell_client::ClientError::Config(format!("Invalid command: {}", e))
+                    })?
+                    .into_iter();
+                (parts.next(), parts.collect())
+            }
+            None => (None, Vec::new()),
+        };
 
-    // Execute single command or start REPL
-    if let Some(command) = args.execute {
+        let result = pty::run(&client, command, cmd_args).await;
+        let _ = client.disconnect().await;
+        match result {
+            Ok(exit_code) => std::process::exit(exit_code.unwrap_or(1)),
+            Err(e) => {
+                error!("Terminal session failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(script) = script {
+        let results = script::run(&client, &script, args.output).await;
+        if args.output == OutputFormat::Text {
+            eprint!("{}", script::summary(&results));
+        }
+        let _ = client.disconnect().await;
+        std::process::exit(script::exit_code(&results));
+    } else if let Some(command) = args.execute {
         // Execute single command
         let parts: Vec<String> = shell_words::split(&command)
             .map_err(|e| shell_client::ClientError::Config(format!("Invalid command: {}", e)))?;
@@ -229,20 +693,50 @@ async fn main() -> Result<()> {
         let cmd = parts[0].clone();
         let cmd_args = parts[1..].to_vec();
 
-        match client.execute_command(cmd, cmd_args).await {
-            Ok(response) => {
-                print!("{}", String::from_utf8_lossy(&response.stdout));
-                eprint!("{}", String::from_utf8_lossy(&response.stderr));
-                std::process::exit(response.exit_code);
-            }
-            Err(e) => {
-                error!("Command execution failed: {}", e);
+        let options = CommandOptions {
+            timeout: args.timeout,
+            working_dir: args.working_dir,
+            idempotent: args.idempotent,
+            ..CommandOptions::default()
+        };
+        if let Some(interval) = args.watch {
+            let result = watch::run(&client, &parts, &options, interval).await;
+            let _ = client.disconnect().await;
+            if let Err(e) = result {
+                error!("Watch failed: {}", e);
                 std::process::exit(1);
             }
+            return Ok(());
+        }
+
+        // Piped or redirected stdin goes to the command, up to end of file
+        let input = (!args.no_stdin && !std::io::stdin().is_terminal()).then(read_stdin);
+
+        let start = std::time::Instant::now();
+        let mut result = client
+            .execute_command_piped(cmd, cmd_args, &options, input)
+            .await;
+        // Replace cut streams with their complete spooled output
+        if let Ok(response) = &mut result {
+            if let Some(spool_id) = response.spool_id {
+                for stderr in [false, true] {
+                    match client.fetch_spooled(spool_id, stderr).await {
+                        Ok(data) if data.is_empty() => {}
+                        Ok(data) if stderr => response.stderr = data,
+                        Ok(data) => response.stdout = data,
+                        Err(e) => warn!(error = %e, "Failed to fetch spooled output"),
+                    }
+                }
+                let kept = (response.stdout.len() + response.stderr.len()) as u64;
+                response.truncated = kept < response.total_bytes;
+            }
         }
+        report(parts, result, args.output, start.elapsed());
     } else {
         // Start interactive REPL
-        let mut repl = Repl::new(client);
+        let mut repl = Repl::new(client)
+            .with_profiles(profiles, args.profile)
+            .with_pager(!args.no_pager);
         if let Err(e) = repl.run().await {
             error!("REPL error: {}", e);
             return Err(e);
@@ -251,3 +745,145 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Parse the interval of `--watch`
+/// Add a profile for each of `servers` without one to the configuration at
+/// `config_path`
+fn save_discovered(
+    config: &mut ClientConfig,
+    config_path: &Path,
+    servers: &[discover::Discovered],
+) -> Result<()> {
+    for server in servers {
+        let known = config
+            .servers
+            .iter()
+            .any(|profile| profile.destination.as_deref() == Some(server.destination.as_str()));
+        if known {
+            continue;
+        }
+        let name = discover::profile_name(server, &config.profile_names());
+        config.add_profile(config_path, server.profile(&name))?;
+        eprintln!("Added server profile {:?}", name);
+    }
+    Ok(())
+}
+
+/// Add a profile for the server of `share` to the configuration at
+/// `config_path` and record its key in the known hosts
+fn add_server(
+    config: &mut ClientConfig,
+    config_path: &Path,
+    share: &ShareString,
+    name: Option<&str>,
+) -> Result<()> {
+    let name = name.or(share.name.as_deref()).ok_or_else(|| {
+        ClientError::Config("The share string names no server; give one with --name".to_string())
+    })?;
+
+    let destination = share.destination();
+    let known = KnownHosts::load(&config.known_hosts_path)?;
+    if let HostKeyStatus::Changed { line } = known.check(&destination, &share.public_key) {
+        return Err(ClientError::HostKeyChanged(format!(
+            "{} is not the key recorded for {} (line {} of {}); if its key was replaced, \
+             remove that line",
+            known_hosts::fingerprint(&share.public_key),
+            hex::encode(destination),
+            line,
+            known.path().display()
+        )));
+    }
+
+    config.add_profile(config_path, ServerProfile::from_share(name, share))?;
+    if known.check(&destination, &share.public_key) == HostKeyStatus::Unknown {
+        known_hosts::add(&config.known_hosts_path, &destination, &share.public_key)?;
+    }
+
+    println!("Added server profile {:?}", name);
+    println!("  Destination: {}", hex::encode(destination));
+    println!(
+        "  Key:         {}",
+        known_hosts::fingerprint(&share.public_key)
+    );
+    if let Some(address) = share.tcp_address {
+        println!("  TCP:         {}", address);
+    }
+    if share.i2p_destination.is_some() {
+        println!("  I2P:         yes");
+    }
+    println!("Connect with: --profile {}", name);
+    Ok(())
+}
+
+fn parse_interval(value: &str) -> std::result::Result<Duration, String> {
+    watch::parse_interval(value)
+        .ok_or_else(|| format!("expected seconds, at least {:?}", watch::MIN_INTERVAL))
+}
+
+/// Print the outcome of the command run with -e and exit with its code
+fn report(
+    parts: Vec<String>,
+    result: Result<CommandResponse>,
+    output: OutputFormat,
+    elapsed: Duration,
+) -> ! {
+    match result {
+        Ok(response) => {
+            if output == OutputFormat::Json {
+                CommandRecord::finished(parts, &response, elapsed).print();
+                std::process::exit(response.exit_code);
+            }
+            print!("{}", String::from_utf8_lossy(&response.stdout));
+            eprint!("{}", String::from_utf8_lossy(&response.stderr));
+            if response.truncated {
+                warn!(
+                    total_bytes = response.total_bytes,
+                    "Output was truncated by the server"
+                );
+            }
+            std::process::exit(response.exit_code);
+        }
+        Err(e) => {
+            if output == OutputFormat::Json {
+                CommandRecord::failed(parts, e.to_string(), elapsed).print();
+            }
+            error!("Command execution failed: {}", e);
+            std::process::exit(script::Outcome::from_error(&e).exit_code());
+        }
+    }
+}
+
+/// Read stdin in the background, closing the channel at end of file
+fn read_stdin() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(STDIN_QUEUE);
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        loop {
+            match stdin.read(&mut buf).await {
+                Ok(0) => return,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to read stdin");
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Ask a yes/no question on the terminal
+fn confirm(prompt: &str) -> bool {
+    use std::io::Write;
+
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,203 @@
+//! Machine-readable results (`--output json`)
+//!
+//! Each command yields one JSON object on a line of its own (JSON Lines),
+//! so tools can read results as they come. Output that is valid UTF-8 is
+//! included as text, anything else base64-encoded, tagged with the encoding
+//! used.
+
+use base64::Engine;
+use clap::ValueEnum;
+use serde::Serialize;
+use shell_proto::{CommandResponse, CommandStatus};
+use std::time::Duration;
+
+/// How results are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Output as the commands write it
+    #[default]
+    Text,
+
+    /// One JSON object per command
+    Json,
+}
+
+/// Output of one stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "encoding", content = "data", rename_all = "lowercase")]
+pub enum Stream {
+    /// Valid UTF-8, as is
+    Utf8(String),
+
+    /// Anything else, base64-encoded
+    Base64(String),
+}
+
+impl Stream {
+    /// Encode `data`, as text if it is valid UTF-8
+    pub fn encode(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(text) => Stream::Utf8(text.to_string()),
+            Err(_) => Stream::Base64(base64::engine::general_purpose::STANDARD.encode(data)),
+        }
+    }
+}
+
+/// Result of one command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandRecord {
+    /// Profile of the server the command ran on (fan-out only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    /// Line of the script the command is on (script mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+
+    /// Command and arguments
+    pub argv: Vec<String>,
+
+    /// `ok`, `failed`, `timeout`, `killed`, `oom`, `error` (not run by the
+    /// server) or `skipped` (not run after an earlier failure)
+    pub status: &'static str,
+
+    /// Exit code of the process, if it exited
+    pub exit_code: Option<i32>,
+
+    /// Time from request to response
+    pub duration_ms: u64,
+
+    /// Standard output
+    pub stdout: Stream,
+
+    /// Standard error
+    pub stderr: Stream,
+
+    /// Output was cut by the server's limit
+    pub truncated: bool,
+
+    /// Why the command could not be run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CommandRecord {
+    /// Record a command the server answered
+    pub fn finished(argv: Vec<String>, response: &CommandResponse, elapsed: Duration) -> Self {
+        Self {
+            host: None,
+            line: None,
+            argv,
+            status: status_name(response.status),
+            exit_code: (response.exit_code >= 0).then_some(response.exit_code),
+            duration_ms: elapsed.as_millis() as u64,
+            stdout: Stream::encode(&response.stdout),
+            stderr: Stream::encode(&response.stderr),
+            truncated: response.truncated,
+            error: None,
+        }
+    }
+
+    /// Record a command whose request failed
+    pub fn failed(argv: Vec<String>, error: String, elapsed: Duration) -> Self {
+        Self {
+            host: None,
+            line: None,
+            argv,
+            status: "error",
+            exit_code: None,
+            duration_ms: elapsed.as_millis() as u64,
+            stdout: Stream::Utf8(String::new()),
+            stderr: Stream::Utf8(String::new()),
+            truncated: false,
+            error: Some(error),
+        }
+    }
+
+    /// Record a command that was not run
+    pub fn skipped(argv: Vec<String>) -> Self {
+        Self {
+            status: "skipped",
+            error: None,
+            ..Self::failed(argv, String::new(), Duration::ZERO)
+        }
+    }
+
+    /// Set the script line
+    pub fn with_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Set the server profile
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Print the record as a line of JSON on stdout
+    pub fn print(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => println!("{}", json),
+            Err(e) => tracing::error!("Failed to encode result: {}", e),
+        }
+    }
+}
+
+/// Short name of a command status
+pub fn status_name(status: CommandStatus) -> &'static str {
+    match status {
+        CommandStatus::Success => "ok",
+        CommandStatus::Error => "failed",
+        CommandStatus::Timeout => "timeout",
+        CommandStatus::Killed => "killed",
+        CommandStatus::OomKilled => "oom",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let response = CommandResponse {
+            id: 1,
+            status: CommandStatus::Error,
+            stdout: b"hi\n".to_vec(),
+            stderr: vec![0xff, 0xfe],
+            exit_code: 2,
+            execution_time_ms: 5,
+            truncated: false,
+            total_bytes: 5,
+            spool_id: None,
+            delta_base: None,
+        };
+        let argv = vec!["sh".to_string(), "-c".to_string(), "exit 2".to_string()];
+        let record = CommandRecord::finished(argv, &response, Duration::from_millis(12));
+        let json: serde_json::Value = serde_json::to_value(record.with_line(4)).unwrap();
+
+        assert_eq!(json["line"], 4);
+        assert_eq!(json["argv"][2], "exit 2");
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["exit_code"], 2);
+        assert_eq!(json["duration_ms"], 12);
+        assert_eq!(json["stdout"]["encoding"], "utf8");
+        assert_eq!(json["stdout"]["data"], "hi\n");
+        assert_eq!(json["stderr"]["encoding"], "base64");
+        assert_eq!(json["stderr"]["data"], "//4=");
+        assert!(json.get("error").is_none());
+
+        let json = serde_json::to_value(CommandRecord::skipped(vec!["ls".to_string()])).unwrap();
+        assert_eq!(json["status"], "skipped");
+        assert!(json["exit_code"].is_null());
+        assert!(json.get("line").is_none() && json.get("error").is_none());
+        assert!(json.get("host").is_none());
+
+        let record =
+            CommandRecord::failed(vec!["ls".to_string()], "lost".to_string(), Duration::ZERO);
+        let json = serde_json::to_value(record.with_host("prod1")).unwrap();
+        assert_eq!(json["host"], "prod1");
+        assert_eq!(json["error"], "lost");
+    }
+}
@@ -0,0 +1,223 @@
+//! Paging long output in the REPL
+//!
+//! A command's output is held back until it fills the screen or the command
+//! ends. If the command ends first, the output is printed as usual; if it
+//! fills the screen, it goes to `$PAGER` (with `LESS=FRX` unless `LESS` is
+//! set, as git does) or, without one, to a simple built-in pager that stops
+//! after every screenful. Output that trickles in is not held for long: after
+//! [`HOLD_TIME`] it is printed as it arrives, unpaged, so following a slow
+//! command still works. Only stdout is paged, and only on a terminal.
+
+use crate::pty;
+use colored::Colorize;
+use std::io::{IsTerminal, Write};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long output that does not fill the screen is held back
+pub const HOLD_TIME: Duration = Duration::from_secs(1);
+
+/// Where output goes
+enum State {
+    /// Held back (since the first of it came)
+    Holding(String, Option<Instant>),
+
+    /// Printed as it comes
+    Direct,
+
+    /// Fed to an external pager
+    External(Child),
+
+    /// Shown by the built-in pager, with the lines shown on this page
+    Builtin(usize),
+
+    /// The pager was quit; the rest is dropped
+    Closed,
+}
+
+/// Stdout of one command, paged if long
+pub struct Pager {
+    /// Lines that fit on the screen
+    rows: usize,
+
+    /// Where output goes
+    state: State,
+}
+
+impl Pager {
+    /// Page stdout if `enabled` and it is a terminal of known size
+    pub fn new(enabled: bool) -> Self {
+        let rows = pty::window_size()
+            .filter(|_| enabled && std::io::stdout().is_terminal())
+            .map(|(_, rows)| usize::from(rows));
+        match rows {
+            Some(rows) if rows > 1 => Self {
+                rows,
+                state: State::Holding(String::new(), None),
+            },
+            _ => Self {
+                rows: 0,
+                state: State::Direct,
+            },
+        }
+    }
+
+    /// Add output
+    pub fn write(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.state = match std::mem::replace(&mut self.state, State::Closed) {
+            State::Holding(mut held, since) => {
+                held.push_str(text);
+                let since = since.unwrap_or_else(Instant::now);
+                if held.matches('\n').count() >= self.rows {
+                    self.start(held)
+                } else if since.elapsed() >= HOLD_TIME {
+                    print_direct(&held);
+                    State::Direct
+                } else {
+                    State::Holding(held, Some(since))
+                }
+            }
+            State::Direct => {
+                print_direct(text);
+                State::Direct
+            }
+            State::External(mut child) => {
+                let written = child
+                    .stdin
+                    .as_mut()
+                    .is_some_and(|stdin| stdin.write_all(text.as_bytes()).is_ok());
+                if written {
+                    State::External(child)
+                } else {
+                    // Quit by the user
+                    let _ = child.wait();
+                    State::Closed
+                }
+            }
+            State::Builtin(shown) => self.builtin(text, shown),
+            State::Closed => State::Closed,
+        };
+    }
+
+    /// Print what is still held back, and wait until the pager is quit
+    pub fn finish(self) {
+        match self.state {
+            State::Holding(held, _) => print_direct(&held),
+            State::External(mut child) => {
+                drop(child.stdin.take());
+                let _ = child.wait();
+            }
+            _ => {}
+        }
+    }
+
+    /// Hand `text` to `$PAGER`, or to the built-in pager without one
+    fn start(&self, text: String) -> State {
+        if let Some(command) = std::env::var("PAGER").ok().filter(|p| !p.trim().is_empty()) {
+            match spawn(&command) {
+                Ok(mut child) => {
+                    let written = child
+                        .stdin
+                        .as_mut()
+                        .is_some_and(|stdin| stdin.write_all(text.as_bytes()).is_ok());
+                    if written {
+                        return State::External(child);
+                    }
+                    let _ = child.wait();
+                    return State::Closed;
+                }
+                Err(e) => warn!(pager = %command, error = %e, "Failed to start the pager"),
+            }
+        }
+        self.builtin(&text, 0)
+    }
+
+    /// Show `text` a screenful at a time, `shown` lines of the current page
+    /// being on the screen already
+    fn builtin(&self, mut text: &str, mut shown: usize) -> State {
+        // One line is for the prompt
+        let page = self.rows - 1;
+        while !text.is_empty() {
+            if shown == page {
+                if !more() {
+                    return State::Closed;
+                }
+                shown = 0;
+            }
+            let (lines, rest) = take_lines(text, page - shown);
+            print_direct(lines);
+            shown += lines.matches('\n').count();
+            text = rest;
+        }
+        State::Builtin(shown)
+    }
+}
+
+/// Start `command` (program and arguments) with a pipe to its stdin
+fn spawn(command: &str) -> std::io::Result<Child> {
+    let words = shell_words::split(command)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let (program, args) = words.split_first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty pager command")
+    })?;
+
+    let mut command = Command::new(program);
+    command.args(args).stdin(Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    command.spawn()
+}
+
+/// Ask whether to show the next page; false to quit
+fn more() -> bool {
+    eprint!("{}", "--More-- (Enter: next page, q: quit)".reversed());
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok_and(|n| n > 0)
+        && !answer.trim().eq_ignore_ascii_case("q")
+}
+
+/// Split `text` after its first `count` lines
+fn take_lines(text: &str, count: usize) -> (&str, &str) {
+    if count == 0 {
+        return ("", text);
+    }
+    let end = text
+        .match_indices('\n')
+        .nth(count - 1)
+        .map_or(text.len(), |(i, _)| i + 1);
+    text.split_at(end)
+}
+
+/// Write to stdout at once
+fn print_direct(text: &str) {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_lines() {
+        let text = "one\ntwo\nthree\nfour";
+        assert_eq!(take_lines(text, 2), ("one\ntwo\n", "three\nfour"));
+        assert_eq!(take_lines(text, 3), ("one\ntwo\nthree\n", "four"));
+        assert_eq!(take_lines(text, 4), (text, ""));
+        assert_eq!(take_lines(text, 9), (text, ""));
+        assert_eq!(take_lines(text, 0), ("", text));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let pager = Pager::new(false);
+        assert!(matches!(pager.state, State::Direct));
+    }
+}
@@ -0,0 +1,470 @@
+//! Predictive local echo for interactive terminals (`predict`)
+//!
+//! Over I2P a keystroke can take seconds to come back from the remote
+//! shell. With prediction on, the client guesses what the server will echo
+//! and shows it right away, underlined until the server confirms it; when
+//! the real output arrives the guesses are taken off the screen, the output
+//! is written, and the guesses still unanswered are put back after it.
+//!
+//! Only the safe cases are guessed: printable characters and backspaces
+//! typed at the end of a line the client can follow the cursor on. Any
+//! other key (Enter, Tab, arrows, control keys), a full-screen program on
+//! the alternate screen or output the client cannot follow stops guessing
+//! until the server has caught up. After such a key nothing is shown until
+//! the server has echoed one guess, so passwords typed at a prompt that
+//! does not echo never appear.
+
+use crate::time::Instant;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Echo delay above which `adaptive` shows its guesses
+pub const SLOW_ECHO: Duration = Duration::from_millis(100);
+
+/// Weight of the smoothed echo delay against a new sample
+const DELAY_SMOOTHING: u32 = 8;
+
+/// Longest escape sequence parameter list kept
+const MAX_PARAMS: usize = 32;
+
+/// When to echo keystrokes before the server does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Prediction {
+    /// Only show what the server sends
+    #[default]
+    Never,
+
+    /// Guess when echoes take longer than [`SLOW_ECHO`]
+    Adaptive,
+
+    /// Always guess
+    Always,
+}
+
+/// Where the output parser is
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Parser {
+    /// Plain text
+    Ground,
+
+    /// After ESC
+    Escape,
+
+    /// In a control sequence (ESC [), with its parameters so far
+    Csi(Vec<u8>),
+
+    /// In a string (OSC, DCS, ...) ended by BEL or ESC \
+    Text,
+
+    /// After ESC in a string
+    TextEscape,
+
+    /// After ESC ( and the like, which take one more byte
+    Charset,
+}
+
+/// Guesses at the echo of keystrokes, and reconciles them with the output
+#[derive(Debug)]
+pub(crate) struct Predictor {
+    /// When guesses are shown
+    mode: Prediction,
+
+    /// Width of the terminal
+    cols: usize,
+
+    /// Column of the server's cursor, if the client could follow it
+    column: Option<usize>,
+
+    /// Column after the last character on the cursor's line, if known
+    end: Option<usize>,
+
+    /// Keystrokes guessed at and not echoed yet, with when they were typed
+    pending: Vec<(u8, Instant)>,
+
+    /// The guesses are on the screen
+    shown: bool,
+
+    /// The server echoed a guess since the last key not guessed at
+    confirmed: bool,
+
+    /// A key not guessed at is on its way; wait for the server
+    held: bool,
+
+    /// A full-screen program is on the alternate screen
+    alternate: bool,
+
+    /// Smoothed echo delay
+    delay: Option<Duration>,
+
+    parser: Parser,
+}
+
+impl Predictor {
+    /// A predictor for a terminal `cols` wide whose cursor is at the start
+    /// of an empty line, or None if `mode` is never; `rtt` is the link's
+    /// round-trip time, if measured, taken as the first echo delay
+    pub(crate) fn new(mode: Prediction, cols: u16, rtt: Option<Duration>) -> Option<Self> {
+        (mode != Prediction::Never).then(|| Self {
+            mode,
+            cols: cols as usize,
+            column: Some(0),
+            end: Some(0),
+            pending: Vec::new(),
+            shown: false,
+            confirmed: false,
+            held: false,
+            alternate: false,
+            delay: rtt,
+            parser: Parser::Ground,
+        })
+    }
+
+    /// The terminal is now `cols` wide
+    pub(crate) fn resize(&mut self, cols: u16) {
+        self.cols = cols as usize;
+        if self.column.is_some_and(|column| column >= self.cols) {
+            self.lose_cursor();
+        }
+    }
+
+    /// Keys typed: what to show of them now
+    pub(crate) fn typed(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut screen = Vec::new();
+        for &byte in input {
+            if self.held {
+                break;
+            }
+            match byte {
+                0x20..=0x7e if self.room(self.pending.len() + 1) => {
+                    if self.pending.is_empty() {
+                        self.shown = self.visible();
+                    }
+                    self.pending.push((byte, Instant::now()));
+                    if self.shown {
+                        draw(&mut screen, &[byte]);
+                    }
+                }
+                0x08 | 0x7f if !self.pending.is_empty() => {
+                    self.pending.pop();
+                    if self.shown {
+                        screen.extend_from_slice(b"\x08\x1b[K");
+                    }
+                }
+                _ => {
+                    self.held = true;
+                    self.confirmed = false;
+                }
+            }
+        }
+        screen
+    }
+
+    /// Output from the server: what to write to the terminal instead
+    pub(crate) fn output(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut screen = Vec::with_capacity(data.len() + 16);
+        if self.shown {
+            screen.resize(self.pending.len(), 0x08);
+            screen.extend_from_slice(b"\x1b[K");
+            self.shown = false;
+        }
+
+        let echoed = data
+            .iter()
+            .zip(&self.pending)
+            .take_while(|(byte, (guess, _))| *byte == guess)
+            .count();
+        if echoed > 0 {
+            for (_, typed) in self.pending.drain(..echoed).collect::<Vec<_>>() {
+                self.sample(typed.elapsed());
+            }
+            self.confirmed = true;
+        } else if !data.is_empty() && !self.pending.is_empty() {
+            // The keys did something else
+            self.pending.clear();
+            self.confirmed = false;
+        }
+
+        screen.extend_from_slice(data);
+        self.track(data);
+
+        if self.pending.is_empty() {
+            self.held = false;
+        } else if self.visible() && self.room(self.pending.len()) {
+            let guesses: Vec<u8> = self.pending.iter().map(|(byte, _)| *byte).collect();
+            draw(&mut screen, &guesses);
+            self.shown = true;
+        }
+        screen
+    }
+
+    /// Guesses may be shown
+    fn visible(&self) -> bool {
+        self.confirmed
+            && match self.mode {
+                Prediction::Never => false,
+                Prediction::Adaptive => self.delay.is_some_and(|delay| delay >= SLOW_ECHO),
+                Prediction::Always => true,
+            }
+    }
+
+    /// `len` guesses fit after the cursor, which is at the end of its line
+    fn room(&self, len: usize) -> bool {
+        match (self.column, self.end) {
+            (Some(column), Some(end)) => {
+                !self.alternate && column >= end && column + len < self.cols
+            }
+            _ => false,
+        }
+    }
+
+    /// Count an echo that took `delay`
+    fn sample(&mut self, delay: Duration) {
+        self.delay = Some(match self.delay {
+            Some(smoothed) => (smoothed * (DELAY_SMOOTHING - 1) + delay) / DELAY_SMOOTHING,
+            None => delay,
+        });
+    }
+
+    /// Follow the cursor through `data`
+    fn track(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.parser = match std::mem::replace(&mut self.parser, Parser::Ground) {
+                Parser::Ground if byte == 0x1b => Parser::Escape,
+                Parser::Ground => {
+                    self.print(byte);
+                    Parser::Ground
+                }
+                Parser::Escape => match byte {
+                    b'[' => Parser::Csi(Vec::new()),
+                    b']' | b'P' | b'X' | b'^' | b'_' => Parser::Text,
+                    b'(' | b')' | b'*' | b'+' => Parser::Charset,
+                    // Keypad modes
+                    b'=' | b'>' => Parser::Ground,
+                    _ => {
+                        self.lose_cursor();
+                        Parser::Ground
+                    }
+                },
+                Parser::Csi(mut params) => match byte {
+                    0x40..=0x7e => {
+                        self.control(&params, byte);
+                        Parser::Ground
+                    }
+                    _ => {
+                        if params.len() < MAX_PARAMS {
+                            params.push(byte);
+                        }
+                        Parser::Csi(params)
+                    }
+                },
+                Parser::Text | Parser::TextEscape if byte == 0x07 => Parser::Ground,
+                Parser::Text => match byte {
+                    0x1b => Parser::TextEscape,
+                    _ => Parser::Text,
+                },
+                Parser::TextEscape => match byte {
+                    b'\\' => Parser::Ground,
+                    _ => Parser::Text,
+                },
+                Parser::Charset => Parser::Ground,
+            };
+        }
+    }
+
+    /// Write `byte` outside escape sequences
+    fn print(&mut self, byte: u8) {
+        match byte {
+            b'\r' => self.column = Some(0),
+            b'\n' => self.end = self.column.map(|_| 0),
+            0x08 => self.column = self.column.map(|column| column.saturating_sub(1)),
+            b'\t' => {
+                if let Some(column) = self.column {
+                    self.advance(8 - column % 8);
+                }
+            }
+            // Control characters and UTF-8 continuation bytes
+            0x00..=0x1f | 0x7f..=0xbf => {}
+            _ => self.advance(1),
+        }
+    }
+
+    /// Move the cursor right over `width` new characters
+    fn advance(&mut self, width: usize) {
+        let Some(column) = self.column else {
+            return;
+        };
+        let column = column + width;
+        if column >= self.cols {
+            // The terminal wraps, in its own way
+            self.lose_cursor();
+            return;
+        }
+        self.column = Some(column);
+        self.end = self.end.map(|end| end.max(column));
+    }
+
+    /// Carry out control sequence `final_byte` with `params`
+    fn control(&mut self, params: &[u8], final_byte: u8) {
+        let private = params.first() == Some(&b'?');
+        let numbers: Vec<usize> = std::str::from_utf8(params)
+            .unwrap_or_default()
+            .trim_start_matches('?')
+            .split(';')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect();
+        let first = numbers.first().copied().unwrap_or(0);
+        let count = first.max(1);
+
+        match final_byte {
+            // Attributes, reports and the like leave the cursor be
+            b'm' | b'n' | b'c' | b't' | b'q' | b'X' => {}
+            b'h' | b'l' if private => {
+                if numbers.iter().any(|mode| matches!(mode, 47 | 1047 | 1049)) {
+                    self.alternate = final_byte == b'h';
+                }
+            }
+            b'h' | b'l' => {}
+            b'C' => {
+                if let Some(column) = self.column {
+                    self.column = Some((column + count).min(self.cols.saturating_sub(1)));
+                }
+            }
+            b'D' => self.column = self.column.map(|column| column.saturating_sub(count)),
+            b'G' => self.column = Some((count - 1).min(self.cols.saturating_sub(1))),
+            b'K' => match first {
+                0 => self.end = self.column,
+                2 => self.end = self.column.map(|_| 0),
+                _ => {}
+            },
+            b'J' if first == 0 => self.end = self.column,
+            b'P' => {
+                if let (Some(column), Some(end)) = (self.column, self.end) {
+                    self.end = Some(end.saturating_sub(count).max(column));
+                }
+            }
+            b'@' => self.end = self.end.map(|end| end + count),
+            _ => self.lose_cursor(),
+        }
+    }
+
+    /// Stop following the cursor until it is at the start of a new or
+    /// cleared line
+    fn lose_cursor(&mut self) {
+        self.column = None;
+        self.end = None;
+    }
+}
+
+/// Show `guesses` underlined at the cursor
+fn draw(screen: &mut Vec<u8>, guesses: &[u8]) {
+    screen.extend_from_slice(b"\x1b[4m");
+    screen.extend_from_slice(guesses);
+    screen.extend_from_slice(b"\x1b[24m");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A predictor whose last guess the server echoed, after a `$ ` prompt
+    fn confirmed(mode: Prediction, rtt: Option<Duration>) -> Predictor {
+        let mut predictor = Predictor::new(mode, 80, rtt).unwrap();
+        assert_eq!(predictor.output(b"$ "), b"$ ");
+        assert_eq!(predictor.typed(b"l"), b"");
+        assert_eq!(predictor.output(b"l"), b"l");
+        predictor
+    }
+
+    #[test]
+    fn test_predict_echo() {
+        assert!(Predictor::new(Prediction::Never, 80, None).is_none());
+
+        let mut predictor = confirmed(Prediction::Always, None);
+        assert_eq!(predictor.typed(b"s"), b"\x1b[4ms\x1b[24m");
+        assert_eq!(predictor.typed(b" -"), b"\x1b[4m \x1b[24m\x1b[4m-\x1b[24m");
+
+        // Echo of the first guess: the rest goes back up after it
+        assert_eq!(
+            predictor.output(b"s"),
+            b"\x08\x08\x08\x1b[Ks\x1b[4m -\x1b[24m"
+        );
+        assert_eq!(predictor.typed(b"\x7f"), b"\x08\x1b[K");
+        assert_eq!(predictor.output(b" "), b"\x08\x1b[K ");
+    }
+
+    #[test]
+    fn test_predict_miss() {
+        let mut predictor = confirmed(Prediction::Always, None);
+        assert_eq!(predictor.typed(b"x"), b"\x1b[4mx\x1b[24m");
+
+        // The server rang the bell instead: the guess goes, and the next
+        // ones wait for an echo
+        assert_eq!(predictor.output(b"\x07"), b"\x08\x1b[K\x07");
+        assert_eq!(predictor.typed(b"y"), b"");
+        assert_eq!(predictor.output(b"y"), b"y");
+        assert_eq!(predictor.typed(b"z"), b"\x1b[4mz\x1b[24m");
+    }
+
+    #[test]
+    fn test_predict_password() {
+        let mut predictor = confirmed(Prediction::Always, None);
+        assert_eq!(predictor.typed(b"\r"), b"");
+        assert_eq!(predictor.typed(b"a"), b"");
+        assert_eq!(predictor.output(b"\r\nPassword: "), b"\r\nPassword: ");
+        assert_eq!(predictor.typed(b"secret"), b"");
+        assert_eq!(predictor.output(b""), b"");
+    }
+
+    #[test]
+    fn test_predict_held() {
+        // Cursor moved back into the line
+        let mut predictor = confirmed(Prediction::Always, None);
+        predictor.output(b"s\x1b[D");
+        assert_eq!(predictor.typed(b"x"), b"");
+
+        // Full-screen program
+        let mut predictor = confirmed(Prediction::Always, None);
+        predictor.output(b"\x1b[?1049h");
+        assert_eq!(predictor.typed(b"j"), b"");
+
+        // Cursor position lost, and found again
+        let mut predictor = confirmed(Prediction::Always, None);
+        predictor.output(b"\x1b[5;1H");
+        assert_eq!(predictor.typed(b"x"), b"");
+        predictor.output(b"\r\x1b[K$ x");
+        assert_eq!(predictor.typed(b"y"), b"");
+        assert_eq!(predictor.output(b"y"), b"y");
+        assert_eq!(predictor.typed(b"z"), b"\x1b[4mz\x1b[24m");
+    }
+
+    #[test]
+    fn test_predict_edge() {
+        let mut predictor = confirmed(Prediction::Always, None);
+        predictor.resize(6);
+        assert_eq!(predictor.typed(b"a"), b"\x1b[4ma\x1b[24m");
+        assert_eq!(predictor.typed(b"b"), b"\x1b[4mb\x1b[24m");
+        assert_eq!(predictor.typed(b"c"), b"");
+    }
+
+    #[test]
+    fn test_predict_adaptive() {
+        let mut predictor = confirmed(Prediction::Adaptive, Some(Duration::from_millis(5)));
+        assert_eq!(predictor.typed(b"s"), b"");
+
+        let mut predictor = confirmed(Prediction::Adaptive, Some(Duration::from_secs(3)));
+        assert_eq!(predictor.typed(b"s"), b"\x1b[4ms\x1b[24m");
+    }
+
+    #[test]
+    fn test_track() {
+        let mut predictor = Predictor::new(Prediction::Always, 80, None).unwrap();
+        predictor.output(b"\x1b]0;title\x07\x1b[1;32muser\x1b(B\x1b[m:~$ ");
+        assert_eq!(predictor.column, Some(8));
+        predictor.output("\u{e9}\t".as_bytes());
+        assert_eq!(predictor.column, Some(16));
+        predictor.output(b"\r\n");
+        assert_eq!((predictor.column, predictor.end), (Some(0), Some(0)));
+    }
+}
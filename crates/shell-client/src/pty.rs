@@ -0,0 +1,340 @@
+//! Interactive remote terminal
+//!
+//! `shell` in the REPL (or `--pty`) opens a PTY on the server and hands it
+//! the local terminal: the terminal is put into raw mode, keystrokes are sent
+//! as PTY_DATA and output is written as it arrives, and window size changes
+//! are passed on with PTY_RESIZE. Ctrl+C and the like reach the remote
+//! program as bytes. The session ends when the remote program exits.
+//!
+//! With `predict` set, typed characters are echoed before the server's echo
+//! arrives (see [`crate::predict`]).
+
+use crate::{client::Client, ClientError, Result};
+use shell_proto::{Message, PtyClose};
+
+/// Terminal type sent when `TERM` is not set
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+/// What a message from the server means for the PTY
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    /// Output to show
+    Output(Vec<u8>),
+
+    /// The remote program exited (with its exit code, if it had one)
+    Exited(Option<i32>),
+
+    /// The PTY could not be opened or was closed by the server
+    Failed(String),
+
+    /// Nothing for the PTY
+    Ignored,
+}
+
+/// Interpret a message from the server for PTY `id`
+fn event(id: u64, message: Message) -> Event {
+    match message {
+        Message::PtyData(data) if data.id == id => Event::Output(data.data),
+        Message::PtyClose(PtyClose {
+            id: closed,
+            exit_code,
+            reason,
+        }) if closed == id => match (exit_code, reason) {
+            (None, Some(reason)) => Event::Failed(reason),
+            (exit_code, _) => Event::Exited(exit_code),
+        },
+        Message::Error(error) if error.request_id == id => Event::Failed(error.message),
+        _ => Event::Ignored,
+    }
+}
+
+/// Run `command` (None = the login shell) in a remote PTY attached to the
+/// local terminal, returning its exit code
+#[cfg(unix)]
+pub async fn run(
+    client: &Client,
+    command: Option<String>,
+    args: Vec<String>,
+) -> Result<Option<i32>> {
+    use crate::audit::Outcome;
+
+    let started = chrono::Utc::now();
+    if let Some(transcript) = client.transcript() {
+        transcript.command(command.as_deref(), &args, true);
+    }
+    let result = attach(client, command.clone(), args.clone()).await;
+    let outcome = match &result {
+        Ok(exit_code) => Outcome::Finished {
+            status: None,
+            exit_code: *exit_code,
+        },
+        Err(e) => Outcome::Failed(e.to_string()),
+    };
+    client.audit(command.as_deref(), &args, None, true, started, &outcome);
+    if let Some(transcript) = client.transcript() {
+        transcript.finished(started, &outcome);
+    }
+    result
+}
+
+#[cfg(unix)]
+async fn attach(
+    client: &Client,
+    command: Option<String>,
+    args: Vec<String>,
+) -> Result<Option<i32>> {
+    use crate::predict::Predictor;
+    use shell_proto::{PtyData, PtyOpenRequest, PtyResize};
+    use std::io::Write;
+    use tokio::signal::unix::{signal, SignalKind};
+    use tokio::sync::mpsc;
+    use tracing::debug;
+
+    let _exclusive = client.exclusive().await;
+    let id = client.next_id();
+    let (cols, rows) = terminal::window_size().unwrap_or((80, 24));
+    client
+        .send(Message::PtyOpen(PtyOpenRequest {
+            id,
+            command,
+            args,
+            term: std::env::var("TERM").unwrap_or_else(|_| DEFAULT_TERM.to_string()),
+            cols,
+            rows,
+            env: None,
+            working_dir: None,
+        }))
+        .await?;
+
+    let mut resizes = signal(SignalKind::window_change())?;
+    let _raw = terminal::RawMode::enable()?;
+    let (input_tx, mut input) = mpsc::unbounded_channel();
+    let _reader = terminal::InputReader::spawn(input_tx);
+    let mut stdout = std::io::stdout();
+    let mut predictor = Predictor::new(client.prediction(), cols, client.health().await.rtt);
+
+    loop {
+        tokio::select! {
+            message = client.receive() => match event(id, message?) {
+                Event::Output(data) => {
+                    if let Some(transcript) = client.transcript() {
+                        transcript.output(&data);
+                    }
+                    let data = match &mut predictor {
+                        Some(predictor) => predictor.output(&data),
+                        None => data,
+                    };
+                    stdout.write_all(&data)?;
+                    stdout.flush()?;
+                }
+                Event::Exited(exit_code) => return Ok(exit_code),
+                Event::Failed(reason) => return Err(ClientError::Request(reason)),
+                Event::Ignored => {}
+            },
+            Some(data) = input.recv() => {
+                if let Some(predictor) = &mut predictor {
+                    let echo = predictor.typed(&data);
+                    if !echo.is_empty() {
+                        stdout.write_all(&echo)?;
+                        stdout.flush()?;
+                    }
+                }
+                client.send(Message::PtyData(PtyData { id, data })).await?;
+            }
+            Some(()) = resizes.recv() => {
+                if let Some((cols, rows)) = terminal::window_size() {
+                    debug!(cols, rows, "Terminal resized");
+                    if let Some(predictor) = &mut predictor {
+                        predictor.resize(cols);
+                    }
+                    client
+                        .send(Message::PtyResize(PtyResize { id, cols, rows }))
+                        .await?;
+                }
+            }
+        }
+    }
+}
+
+/// Size of the local terminal as `(cols, rows)`, if known
+#[cfg(unix)]
+pub(crate) fn window_size() -> Option<(u16, u16)> {
+    terminal::window_size()
+}
+
+/// Size of the local terminal as `(cols, rows)`, if known
+#[cfg(not(unix))]
+pub(crate) fn window_size() -> Option<(u16, u16)> {
+    None
+}
+
+/// Raw terminals are a Unix concept
+#[cfg(not(unix))]
+pub async fn run(
+    _client: &Client,
+    _command: Option<String>,
+    _args: Vec<String>,
+) -> Result<Option<i32>> {
+    Err(ClientError::Repl(
+        "Interactive terminals need a Unix terminal".to_string(),
+    ))
+}
+
+#[cfg(unix)]
+mod terminal {
+    use crate::Result;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+    use tokio::sync::mpsc;
+
+    /// How long the input reader waits for a keystroke before checking
+    /// whether it should stop
+    const POLL_INTERVAL_MS: i32 = 100;
+
+    /// Size of the local terminal as `(cols, rows)`
+    pub fn window_size() -> Option<(u16, u16)> {
+        // SAFETY: TIOCGWINSZ only writes the winsize it is given
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        (ok && size.ws_col > 0 && size.ws_row > 0).then_some((size.ws_col, size.ws_row))
+    }
+
+    /// The terminal in raw mode; restored on drop
+    pub struct RawMode {
+        original: libc::termios,
+    }
+
+    impl RawMode {
+        /// Put the terminal on stdin into raw mode
+        pub fn enable() -> Result<Self> {
+            // SAFETY: tcgetattr fills the termios it is given
+            let mut original: libc::termios = unsafe { std::mem::zeroed() };
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let mut raw = original;
+            // SAFETY: cfmakeraw only changes the termios it is given
+            unsafe { libc::cfmakeraw(&mut raw) };
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(Self { original })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            // SAFETY: restores settings read by tcgetattr
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+        }
+    }
+
+    /// Reads stdin on a thread of its own until dropped
+    ///
+    /// A plain blocking read would outlive the PTY session and swallow the
+    /// next keystroke meant for the REPL, so the thread polls and checks
+    /// whether it should stop in between.
+    pub struct InputReader {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl InputReader {
+        /// Start reading, sending what is typed to `tx`
+        pub fn spawn(tx: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = Arc::clone(&stop);
+            let thread = std::thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while !stopped.load(Ordering::SeqCst) {
+                    let mut fds = libc::pollfd {
+                        fd: libc::STDIN_FILENO,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    // SAFETY: poll and read only touch the buffers given
+                    let ready = unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL_MS) };
+                    if ready == 0 || (ready < 0 && interrupted()) {
+                        continue;
+                    }
+                    if ready < 0 {
+                        return;
+                    }
+                    let n = unsafe {
+                        libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len())
+                    };
+                    if n < 0 && interrupted() {
+                        continue;
+                    }
+                    if n <= 0 || tx.send(buf[..n as usize].to_vec()).is_err() {
+                        return;
+                    }
+                }
+            });
+            Self {
+                stop,
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl Drop for InputReader {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Whether the last system call was interrupted by a signal
+    fn interrupted() -> bool {
+        io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::{ErrorMessage, PtyData};
+
+    #[test]
+    fn test_event() {
+        let data = |id| {
+            Message::PtyData(PtyData {
+                id,
+                data: b"$ ".to_vec(),
+            })
+        };
+        assert_eq!(event(1, data(1)), Event::Output(b"$ ".to_vec()));
+        assert_eq!(event(1, data(2)), Event::Ignored);
+
+        let close = |exit_code, reason: Option<&str>| {
+            Message::PtyClose(PtyClose {
+                id: 1,
+                exit_code,
+                reason: reason.map(str::to_string),
+            })
+        };
+        assert_eq!(event(1, close(Some(0), None)), Event::Exited(Some(0)));
+        assert_eq!(event(1, close(None, None)), Event::Exited(None));
+        assert_eq!(
+            event(1, close(None, Some("Command is denied by policy: sh"))),
+            Event::Failed("Command is denied by policy: sh".to_string())
+        );
+
+        let error = |request_id| {
+            Message::Error(ErrorMessage {
+                request_id,
+                code: ErrorMessage::DENIED,
+                message: "nope".to_string(),
+            })
+        };
+        assert_eq!(event(1, error(1)), Event::Failed("nope".to_string()));
+        assert_eq!(event(1, error(0)), Event::Ignored);
+        assert_eq!(event(1, Message::Pong), Event::Ignored);
+    }
+}
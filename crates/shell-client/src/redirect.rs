@@ -0,0 +1,273 @@
+//! Local redirection of remote output
+//!
+//! In the REPL, `command > file` writes the remote command's stdout to a
+//! local file instead of the screen (`>>` appends to it), byte for byte, and
+//! `command | program` feeds it to a local program, run by the local shell.
+//! Stderr still goes to the screen. Only an unquoted, unescaped `>` or `|`
+//! counts, so `'|'` passes one on to the server, e.g. for a server-side
+//! shell.
+
+use crate::{ClientError, Result};
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use tracing::warn;
+
+/// Where remote stdout goes instead of the screen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// A local file, truncated first unless appended to
+    File { path: PathBuf, append: bool },
+
+    /// The stdin of a local shell command
+    Pipe(String),
+}
+
+/// Split the redirection off `line`, returning the remote command line and
+/// where its output goes (None = the screen)
+pub fn parse(line: &str) -> Result<(&str, Option<Target>)> {
+    let Some(at) = operator(line) else {
+        return Ok((line, None));
+    };
+    let command = line[..at].trim();
+    let rest = &line[at..];
+    if command.is_empty() {
+        return Err(ClientError::Repl(format!(
+            "No command before {}",
+            &rest[..1]
+        )));
+    }
+
+    if let Some(program) = rest.strip_prefix('|') {
+        let program = program.trim();
+        if program.is_empty() {
+            return Err(ClientError::Repl("No local command after |".to_string()));
+        }
+        return Ok((command, Some(Target::Pipe(program.to_string()))));
+    }
+
+    let (append, file) = match rest.strip_prefix(">>") {
+        Some(file) => (true, file),
+        None => (false, &rest[1..]),
+    };
+    let words = shell_words::split(file)
+        .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+    match words.as_slice() {
+        [path] => Ok((
+            command,
+            Some(Target::File {
+                path: PathBuf::from(path),
+                append,
+            }),
+        )),
+        _ => Err(ClientError::Repl(format!(
+            "Expected one local file after {}",
+            if append { ">>" } else { ">" }
+        ))),
+    }
+}
+
+/// Where the first unquoted, unescaped `>` or `|` of `line` is
+fn operator(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote != Some('\'') => escaped = true,
+            '\'' | '"' if quote.is_none() => quote = Some(c),
+            c if quote == Some(c) => quote = None,
+            '>' | '|' if quote.is_none() => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Remote output on its way to a [`Target`]
+pub struct Sink {
+    /// Where the output is written (None once the reader went away)
+    writer: Option<Box<dyn Write + Send>>,
+
+    /// The local program being fed
+    child: Option<Child>,
+
+    /// Why writing failed, if it did
+    error: Option<io::Error>,
+}
+
+impl Sink {
+    /// Open the file or start the program of `target`
+    pub fn open(target: &Target) -> Result<Self> {
+        match target {
+            Target::File { path, append } => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(*append)
+                    .truncate(!*append)
+                    .open(path)
+                    .map_err(|e| {
+                        ClientError::Repl(format!("Cannot open {}: {}", path.display(), e))
+                    })?;
+                Ok(Self {
+                    writer: Some(Box::new(BufWriter::new(file))),
+                    child: None,
+                    error: None,
+                })
+            }
+            Target::Pipe(program) => {
+                let mut child = local_shell(program)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| ClientError::Repl(format!("Cannot run {}: {}", program, e)))?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                Ok(Self {
+                    writer: Some(Box::new(stdin)),
+                    child: Some(child),
+                    error: None,
+                })
+            }
+        }
+    }
+
+    /// Pass on `data`; after a failure the rest is dropped, and a program
+    /// that stopped reading (like `head`) is not an error
+    pub fn write(&mut self, data: &[u8]) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(e) = writer.write_all(data) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                self.error = Some(e);
+            }
+            self.writer = None;
+        }
+    }
+
+    /// Flush and close the output, waiting for the program to finish
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = writer.flush() {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    self.error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(mut child) = self.child.take() {
+            let status = child.wait()?;
+            if !status.success() {
+                warn!(%status, "Local command failed");
+            }
+        }
+        match self.error {
+            Some(e) => Err(ClientError::Repl(format!("Failed to write output: {}", e))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `program` run by the local shell
+fn local_shell(program: &str) -> Command {
+    #[cfg(unix)]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(program);
+        command
+    }
+    #[cfg(not(unix))]
+    {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(program);
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("ls -l").unwrap(), ("ls -l", None));
+        assert_eq!(
+            parse("cat /var/log/syslog > 'sys log.txt'").unwrap(),
+            (
+                "cat /var/log/syslog",
+                Some(Target::File {
+                    path: PathBuf::from("sys log.txt"),
+                    append: false
+                })
+            )
+        );
+        assert_eq!(
+            parse("date>>dates").unwrap(),
+            (
+                "date",
+                Some(Target::File {
+                    path: PathBuf::from("dates"),
+                    append: true
+                })
+            )
+        );
+        assert_eq!(
+            parse("ps aux | grep -c sshd | tee n").unwrap(),
+            (
+                "ps aux",
+                Some(Target::Pipe("grep -c sshd | tee n".to_string()))
+            )
+        );
+
+        // Quoted and escaped operators belong to the remote command
+        assert_eq!(parse("echo '>' \"a|b\" \\|").unwrap().1, None);
+
+        assert!(parse("> file").is_err());
+        assert!(parse("ls >").is_err());
+        assert!(parse("ls > a b").is_err());
+        assert!(parse("ls |").is_err());
+    }
+
+    #[test]
+    fn test_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin");
+        let target = |append| Target::File {
+            path: path.clone(),
+            append,
+        };
+
+        let mut sink = Sink::open(&target(false)).unwrap();
+        sink.write(&[0, 159, 146, 150]);
+        sink.finish().unwrap();
+        let mut sink = Sink::open(&target(true)).unwrap();
+        sink.write(b"\n");
+        sink.finish().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0, 159, 146, 150, b'\n']);
+
+        let sink = Sink::open(&target(false)).unwrap();
+        sink.finish().unwrap();
+        assert!(std::fs::read(&path).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("count");
+        let program = format!("wc -l > '{}'", path.display());
+
+        let mut sink = Sink::open(&Target::Pipe(program)).unwrap();
+        sink.write(b"one\ntwo\n");
+        sink.finish().unwrap();
+        let count = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(count.trim(), "2");
+
+        // A reader that stops early is fine
+        let mut sink = Sink::open(&Target::Pipe("head -c 1 >/dev/null".to_string())).unwrap();
+        for _ in 0..1000 {
+            sink.write(&[b'x'; 1024]);
+        }
+        sink.finish().unwrap();
+    }
+}
@@ -1,11 +1,27 @@
 //! Interactive REPL (Read-Eval-Print-Loop)
 
-use crate::{client::Client, ClientError, Result};
+use crate::{
+    browser::FileBrowser,
+    client::{Client, CommandOptions},
+    completion::RemoteCompleter,
+    config::ClientConfig,
+    connect, env, health, keepalive,
+    pager::Pager,
+    pty,
+    redirect::{self, Sink},
+    transfer::{self, TransferArgs},
+    vars::Variables,
+    watch, ClientError, Result,
+};
 use colored::Colorize;
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
 use shell_proto::CommandStatus;
+use std::collections::BTreeMap;
+use std::io::Write;
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tracing::{debug, error};
 
 /// Interactive REPL
@@ -13,28 +29,64 @@ pub struct Repl {
     /// Client connection
     client: Arc<Client>,
 
-    /// Readline editor
-    editor: DefaultEditor,
+    /// Readline editor, completing against the server
+    editor: Editor<RemoteCompleter, DefaultHistory>,
+
+    /// Configuration holding the server profiles to switch to
+    profiles: Option<ClientConfig>,
+
+    /// Name of the profile connected to
+    profile: Option<String>,
+
+    /// Whether long output goes through a pager
+    pager: bool,
+
+    /// Variables substituted in command lines
+    vars: Variables,
 }
 
 impl Repl {
     /// Create a new REPL
     pub fn new(client: Client) -> Self {
-        let editor = DefaultEditor::new().expect("Failed to create readline editor");
+        let client = Arc::new(client);
+        keepalive::spawn(&client);
+        let mut editor = Editor::new().expect("Failed to create readline editor");
+        editor.set_helper(Some(RemoteCompleter::new(client.clone())));
 
         Self {
-            client: Arc::new(client),
+            client,
             editor,
+            profiles: None,
+            profile: None,
+            pager: true,
+            vars: Variables::new(),
         }
     }
 
+    /// Allow switching to the server profiles of `config` with `connect`;
+    /// `current` names the profile connected to
+    pub fn with_profiles(mut self, config: ClientConfig, current: Option<String>) -> Self {
+        self.profiles = Some(config);
+        self.profile = current;
+        self
+    }
+
+    /// Page long command output (the default) or print it all at once
+    pub fn with_pager(mut self, enabled: bool) -> Self {
+        self.pager = enabled;
+        self
+    }
+
     /// Run the REPL
     pub async fn run(&mut self) -> Result<()> {
         println!("{}", "Reticulum Shell Client".bold().green());
         println!("Type 'help' for commands, 'exit' to quit\n");
 
         loop {
-            let prompt = "rsh> ".cyan().to_string();
+            let prompt = match &self.profile {
+                Some(name) => format!("rsh:{}> ", name).cyan().to_string(),
+                None => "rsh> ".cyan().to_string(),
+            };
 
             match self.editor.readline(&prompt) {
                 Ok(line) => {
@@ -48,6 +100,36 @@ impl Repl {
                     // Add to history
                     let _ = self.editor.add_history_entry(line);
 
+                    // The command may change what completes
+                    if let Some(completer) = self.editor.helper() {
+                        completer.invalidate();
+                    }
+
+                    // Substitute variables
+                    let line = match self.vars.expand(line) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            eprintln!("{} {}", "Error:".red().bold(), e);
+                            continue;
+                        }
+                    };
+                    let line = line.as_str();
+
+                    // Switching servers replaces the client
+                    let words: Vec<&str> = line.split_whitespace().collect();
+                    if words[0] == "set" {
+                        if let Err(e) = self.set_vars(line) {
+                            eprintln!("{} {}", "Error:".red().bold(), e);
+                        }
+                        continue;
+                    }
+                    if matches!(words[0], "connect" | "open") {
+                        if let Err(e) = self.switch(&words[1..]).await {
+                            eprintln!("{} {}", "Error:".red().bold(), e);
+                        }
+                        continue;
+                    }
+
                     // Handle special commands
                     if let Some(result) = self.handle_special_command(line).await? {
                         if !result {
@@ -106,21 +188,241 @@ impl Repl {
                 self.print_status().await;
                 return Ok(Some(true));
             }
+            "stats" => {
+                if let Err(e) = self.print_stats().await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
             "clear" => {
                 print!("\x1B[2J\x1B[1;1H"); // ANSI clear screen
                 return Ok(Some(true));
             }
+            "output" if parts.len() == 2 => {
+                if let Err(e) = self.print_spooled(parts[1]).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
+            "put" | "get" => {
+                if let Err(e) = self.transfer(line).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
+            "shell" => {
+                if let Err(e) = self.shell(line).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
+            "export" | "unset" => {
+                if let Err(e) = self.change_env(line).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
+            "files" => {
+                if let Err(e) = self.browse(&parts[1..]).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
+            "watch" => {
+                if let Err(e) = self.watch(line).await {
+                    eprintln!("{} {}", "Error:".red().bold(), e);
+                }
+                return Ok(Some(true));
+            }
             _ => {}
         }
 
         Ok(None)
     }
 
+    /// Connect to another server profile (`connect <name>`), or list them
+    ///
+    /// The current connection is kept if the new one can't be made.
+    async fn switch(&mut self, args: &[&str]) -> Result<()> {
+        let profiles = self
+            .profiles
+            .as_ref()
+            .ok_or_else(|| ClientError::Repl("No server profiles configured".to_string()))?;
+
+        let name = match args {
+            [] => {
+                for name in profiles.profile_names() {
+                    let current = Some(name) == self.profile.as_deref();
+                    println!("{} {}", if current { "*" } else { " " }, name);
+                }
+                return Ok(());
+            }
+            [name] => name.to_string(),
+            _ => return Err(ClientError::Repl("usage: connect <profile>".to_string())),
+        };
+
+        let client = connect::open(profiles.with_profile(&name)?).await?;
+        client.connect().await?;
+        if client.host_key_unknown() {
+            println!(
+                "{} {}",
+                "The server's key is not known yet:".yellow(),
+                client.host_key_fingerprint().await.unwrap_or_default()
+            );
+            let answer = self.editor.readline("Trust it? [y/N] ").unwrap_or_default();
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                let _ = client.disconnect().await;
+                return Err(ClientError::Request(
+                    "The server key was not trusted".to_string(),
+                ));
+            }
+            client.trust_host_key().await?;
+        }
+        if let Some(banner) = client.banner().await {
+            println!("{}", banner.trim_end());
+        }
+        if client.banner_ack_required() {
+            let answer = self.editor.readline("Accept? [y/N] ").unwrap_or_default();
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                let _ = client.disconnect().await;
+                return Err(ClientError::Request(
+                    "The server banner was not accepted".to_string(),
+                ));
+            }
+            client.acknowledge_banner().await?;
+        }
+
+        if let Err(e) = self.client.disconnect().await {
+            debug!("Failed to disconnect from the previous server: {}", e);
+        }
+        self.client = Arc::new(client);
+        keepalive::spawn(&self.client);
+        self.editor
+            .set_helper(Some(RemoteCompleter::new(Arc::clone(&self.client))));
+        println!("{} {}", "Connected to".green(), name);
+        self.profile = Some(name);
+        Ok(())
+    }
+
+    /// Set (`export NAME=VALUE ...`) or remove (`unset NAME ...`) session
+    /// environment variables
+    async fn change_env(&self, line: &str) -> Result<()> {
+        let parts = shell_words::split(line)
+            .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        let (command, args) = parts.split_first().expect("line is not empty");
+
+        if command == "unset" {
+            return self.client.unset_env(args.to_vec()).await;
+        }
+
+        let mut vars = BTreeMap::new();
+        for arg in args {
+            let (name, value) = arg.split_once('=').ok_or_else(|| {
+                ClientError::Repl(format!("export: expected NAME=VALUE, got {}", arg))
+            })?;
+            vars.insert(name.to_string(), value.to_string());
+        }
+        self.client.set_env(vars).await
+    }
+
+    /// Set variables (`set NAME=VALUE...`), remove them (`set -u NAME...`)
+    /// or list them (`set`)
+    fn set_vars(&mut self, line: &str) -> Result<()> {
+        let parts = shell_words::split(line)
+            .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        match &parts[1..] {
+            [] => {
+                for (name, value) in self.vars.iter() {
+                    println!("{}={}", name, shell_words::quote(value));
+                }
+            }
+            [flag, names @ ..] if flag == "-u" => {
+                for name in names {
+                    self.vars.remove(name);
+                }
+            }
+            specs => {
+                for spec in specs {
+                    let (name, value) = spec.split_once('=').ok_or_else(|| {
+                        ClientError::Repl(format!("set: expected NAME=VALUE, got {}", spec))
+                    })?;
+                    self.vars.set(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the file browser (`files [DIR]`)
+    async fn browse(&self, args: &[&str]) -> Result<()> {
+        let dir = match args {
+            [] => None,
+            [dir] => Some(*dir),
+            _ => return Err(ClientError::Repl("usage: files [DIR]".to_string())),
+        };
+        FileBrowser::open(self.client.clone(), dir)
+            .await?
+            .run()
+            .await
+    }
+
+    /// Copy files to (`put`) or from (`get`) the server
+    async fn transfer(&self, line: &str) -> Result<()> {
+        let parts = shell_words::split(line)
+            .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        let (command, words) = parts.split_first().expect("line is not empty");
+        let args = TransferArgs::parse(command, words)?;
+
+        let summary = if command == "put" {
+            transfer::put(&self.client, &args).await?
+        } else {
+            transfer::get(&self.client, &args).await?
+        };
+        if args.recursive {
+            println!("{} files, {} bytes", summary.files, summary.bytes);
+        }
+        Ok(())
+    }
+
+    /// Run a command repeatedly (`watch SECS command...`) until Ctrl+C
+    async fn watch(&self, line: &str) -> Result<()> {
+        let parts = shell_words::split(line)
+            .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        let usage = || ClientError::Repl("usage: watch SECS command...".to_string());
+        let interval = parts
+            .get(1)
+            .ok_or_else(usage)
+            .and_then(|value| watch::parse_interval(value).ok_or_else(usage))?;
+        let (options, words) = command_options(parts[2..].to_vec())?;
+        if words.is_empty() {
+            return Err(usage());
+        }
+
+        watch::run(&self.client, &words, &options, interval).await
+    }
+
+    /// Open an interactive terminal (`shell [program [args...]]`)
+    async fn shell(&self, line: &str) -> Result<()> {
+        let parts = shell_words::split(line)
+            .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        let mut words = parts.into_iter().skip(1);
+        let command = words.next();
+
+        match pty::run(&self.client, command, words.collect()).await? {
+            Some(0) => {}
+            Some(code) => eprintln!("{}", format!("Exit code: {}", code).yellow()),
+            None => eprintln!("{}", "Terminal closed".yellow()),
+        }
+        Ok(())
+    }
+
     /// Execute a command line
     async fn execute_line(&self, line: &str) -> Result<()> {
-        // Parse command line
+        // Parse command line, taking off a local redirection
+        let (line, target) = redirect::parse(line)?;
         let parts = shell_words::split(line)
             .map_err(|e| ClientError::Repl(format!("Invalid command syntax: {}", e)))?;
+        let (options, parts) = command_options(parts)?;
 
         if parts.is_empty() {
             return Ok(());
@@ -129,19 +431,74 @@ impl Repl {
         let command = parts[0].clone();
         let args = parts[1..].to_vec();
 
-        debug!(command = %command, args = ?args, "Executing command");
+        debug!(command = %command, args = ?args, options = ?options, "Executing command");
+
+        // While the command runs, Ctrl+C is for it rather than for us: the
+        // first press interrupts it, another kills it
+        let (interrupt_tx, mut interrupts) = mpsc::unbounded_channel();
+        let listener = tokio::spawn(async move {
+            let mut presses = 0;
+            while tokio::signal::ctrl_c().await.is_ok() {
+                presses += 1;
+                if presses == 1 {
+                    eprintln!("{}", "^C (press again to kill)".yellow());
+                } else {
+                    eprintln!("{}", "^C".yellow());
+                }
+                if interrupt_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
 
-        // Execute command
-        let response = self.client.execute_command(command, args).await?;
+        // Execute command, showing output as it arrives; a chunk may end
+        // within a UTF-8 sequence, which is held back for the next one.
+        // Stdout is paged if long, unless redirected
+        let mut sink = target.as_ref().map(Sink::open).transpose()?;
+        let mut pager = Pager::new(self.pager && sink.is_none());
+        let mut pending = [Vec::new(), Vec::new()];
+        let response = self
+            .client
+            .execute_command_streaming(
+                command,
+                args,
+                &options,
+                &mut |stderr: bool, data: &[u8]| {
+                    if let (false, Some(sink)) = (stderr, &mut sink) {
+                        sink.write(data);
+                        return;
+                    }
+                    let pending = &mut pending[usize::from(stderr)];
+                    pending.extend_from_slice(data);
+                    let text = take_text(pending);
+                    if stderr {
+                        print_output(true, &text);
+                    } else {
+                        pager.write(&text);
+                    }
+                },
+                Some(&mut interrupts),
+            )
+            .await;
+        listener.abort();
+        pager.write(&String::from_utf8_lossy(&pending[0]));
+        print_output(true, &String::from_utf8_lossy(&pending[1]));
+        if let Ok(response) = &response {
+            if response.status == CommandStatus::Success {
+                match &mut sink {
+                    Some(sink) => sink.write(&response.stdout),
+                    None => pager.write(&String::from_utf8_lossy(&response.stdout)),
+                }
+            }
+        }
+        pager.finish();
+        let finished = sink.map(Sink::finish).transpose();
+        let response = response?;
+        finished?;
 
         // Display output
         match response.status {
             CommandStatus::Success => {
-                // Print stdout
-                if !response.stdout.is_empty() {
-                    print!("{}", String::from_utf8_lossy(&response.stdout));
-                }
-
                 // Print stderr in red
                 if !response.stderr.is_empty() {
                     eprint!("{}", String::from_utf8_lossy(&response.stderr).red());
@@ -163,6 +520,20 @@ impl Repl {
             CommandStatus::Killed => {
                 eprintln!("{}", "Command was killed".red().bold());
             }
+            CommandStatus::OomKilled => {
+                eprintln!("{}", "Command was killed: out of memory".red().bold());
+            }
+        }
+
+        if response.truncated {
+            let message = match response.spool_id {
+                Some(id) => format!(
+                    "Output truncated ({} bytes total, `output {}` shows all)",
+                    response.total_bytes, id
+                ),
+                None => format!("Output truncated ({} bytes total)", response.total_bytes),
+            };
+            eprintln!("{}", message.yellow());
         }
 
         Ok(())
@@ -172,21 +543,227 @@ impl Repl {
     fn print_help(&self) {
         println!("{}", "Available commands:".bold());
         println!("  help          - Show this help message");
-        println!("  status        - Show connection status");
+        println!("  status        - Show connection health (round-trip time, traffic, ...)");
+        println!("  stats         - Show your usage on the server");
         println!("  clear         - Clear screen");
+        println!("  export K=V    - Set a variable for later commands");
+        println!("  unset K       - Remove a variable");
+        println!("  set K=V       - Set a local variable, used as $K (-u K: remove; none: list)");
+        println!("  output N      - Show the complete output of a truncated command");
+        println!("  put L [R]     - Upload a file (-r: a directory, --resume: continue)");
+        println!("  get R [L]     - Download a file (-r: a directory, --resume: continue)");
+        println!("  shell [CMD]   - Open an interactive terminal (default: login shell)");
+        println!("  watch N CMD   - Run a command every N seconds until Ctrl+C");
+        println!("  files [DIR]   - Browse the server's files (ls, cd, get, put, rm, ...)");
+        println!("  connect NAME  - Switch to a server profile (alias: open; none: list)");
         println!("  exit, quit    - Exit the shell");
         println!("\nAny other command will be executed on the remote server.");
+        println!("Prefix it with :timeout SECS, :cd DIR or :env K=V to change those for it alone.");
+        println!("End it with > FILE or >> FILE to save its output, | CMD to pipe it locally.");
+        println!("Ctrl+C interrupts it, pressed again kills it.");
+        println!("Tab completes remote command names and paths.");
     }
 
-    /// Print connection status
+    /// Print the spooled output of a truncated command
+    async fn print_spooled(&self, id: &str) -> Result<()> {
+        let spool_id = id
+            .parse()
+            .map_err(|_| ClientError::Repl(format!("output: invalid spool ID {}", id)))?;
+        let stdout = self.client.fetch_spooled(spool_id, false).await?;
+        let stderr = self.client.fetch_spooled(spool_id, true).await?;
+        print!("{}", String::from_utf8_lossy(&stdout));
+        eprint!("{}", String::from_utf8_lossy(&stderr));
+        Ok(())
+    }
+
+    /// Print the server's usage counters for this identity
+    async fn print_stats(&self) -> Result<()> {
+        let stats = self.client.stats().await?;
+
+        println!("{}", "Usage:".bold());
+        println!("  Sessions:   {}", stats.sessions);
+        println!("  Commands:   {}", stats.commands);
+        println!("  CPU time:   {:.1}s", stats.cpu_time_ms as f64 / 1000.0);
+        println!("  Uploaded:   {} bytes", stats.bytes_uploaded);
+        println!("  Downloaded: {} bytes", stats.bytes_downloaded);
+        println!("  Running:    {} ({} queued)", stats.running, stats.queued);
+        Ok(())
+    }
+
+    /// Print connection status, pinging the server for a fresh round-trip
+    /// time
     async fn print_status(&self) {
-        let connected = self.client.is_connected().await;
+        if self.client.is_connected().await {
+            if let Err(e) = self.client.ping().await {
+                debug!(error = %e, "Ping failed");
+            }
+        }
+        let health = self.client.health().await;
 
         println!("{}", "Connection Status:".bold());
-        if connected {
-            println!("  Status: {}", "Connected".green().bold());
+        if health.connected {
+            println!("  Status:       {}", "Connected".green().bold());
         } else {
-            println!("  Status: {}", "Disconnected".red().bold());
+            println!("  Status:       {}", "Disconnected".red().bold());
+        }
+        if let Some(profile) = &self.profile {
+            println!("  Profile:      {}", profile);
+        }
+        let identity = self.client.identity_hex();
+        println!(
+            "  Identity:     {} ({})",
+            self.client.identity_name(),
+            &identity[..identity.len().min(16)]
+        );
+        if let Some(age) = health.session_age {
+            println!("  Session age:  {}", health::format_age(age));
+        }
+        if let Some(version) = health.protocol_version {
+            println!("  Protocol:     v{}", version);
+        }
+        if !health.capabilities.is_empty() {
+            println!("  Capabilities: {}", health.capabilities.join(", "));
+        }
+        if let (Some(last), Some(rtt)) = (health.last_rtt, health.rtt) {
+            println!(
+                "  RTT:          {} ms (average {} ms)",
+                last.as_millis(),
+                rtt.as_millis()
+            );
+        }
+        if let Some(loss) = health.loss() {
+            println!(
+                "  Packet loss:  {:.0}% ({} of {} pings)",
+                loss * 100.0,
+                health.pings_lost,
+                health.pings
+            );
+        }
+        println!("  Sent:         {} bytes", health.bytes_sent);
+        println!("  Received:     {} bytes", health.bytes_received);
+    }
+}
+
+/// Split the leading `:timeout SECS`, `:cd DIR` and `:env NAME=VALUE`
+/// prefixes off a command
+pub(crate) fn command_options(words: Vec<String>) -> Result<(CommandOptions, Vec<String>)> {
+    let mut options = CommandOptions::default();
+    let mut words = words.into_iter().peekable();
+    let mut prefixed = false;
+
+    while let Some(prefix) = words.next_if(|word| word.starts_with(':') && word.len() > 1) {
+        let value = words
+            .next()
+            .ok_or_else(|| ClientError::Repl(format!("{}: missing value", prefix)))?;
+        match prefix.as_str() {
+            ":timeout" => {
+                let secs = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| {
+                        ClientError::Repl(format!(":timeout: invalid number of seconds {}", value))
+                    })?;
+                options.timeout = Some(secs);
+            }
+            ":cd" => options.working_dir = Some(value),
+            ":env" => {
+                let (name, value) = env::parse_var(&value).map_err(|_| {
+                    ClientError::Repl(format!(":env: expected NAME=VALUE, got {}", value))
+                })?;
+                options.env.insert(name, value);
+            }
+            _ => {
+                return Err(ClientError::Repl(format!(
+                    "Unknown prefix {} (expected :timeout, :cd or :env)",
+                    prefix
+                )))
+            }
+        }
+        prefixed = true;
+    }
+
+    let words: Vec<String> = words.collect();
+    if prefixed && words.is_empty() {
+        return Err(ClientError::Repl(
+            "No command after the prefixes".to_string(),
+        ));
+    }
+    Ok((options, words))
+}
+
+/// Print streamed command output, stderr in red
+fn print_output(stderr: bool, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if stderr {
+        eprint!("{}", text.red());
+    } else {
+        print!("{}", text);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Take the text from `pending`, leaving an incomplete UTF-8 sequence at
+/// its end in place
+fn take_text(pending: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(pending) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_text() {
+        let euro = "\u{20ac}".as_bytes();
+        let mut pending = b"price: ".to_vec();
+        pending.extend_from_slice(&euro[..1]);
+        assert_eq!(take_text(&mut pending), "price: ");
+        assert_eq!(pending, &euro[..1]);
+
+        pending.extend_from_slice(&euro[1..]);
+        assert_eq!(take_text(&mut pending), "\u{20ac}");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_command_options() {
+        let words = |line: &str| shell_words::split(line).unwrap();
+
+        let (options, rest) = command_options(words(":timeout 600 :cd /srv make all")).unwrap();
+        assert_eq!(options.timeout, Some(600));
+        assert_eq!(options.working_dir.as_deref(), Some("/srv"));
+        assert_eq!(rest, words("make all"));
+
+        // Only leading words are prefixes
+        let (options, rest) = command_options(words(":env A=1 :env 'B=x y' env")).unwrap();
+        assert_eq!(options.env.len(), 2);
+        assert_eq!(options.env["B"], "x y");
+        assert_eq!(rest, words("env"));
+
+        let (options, rest) = command_options(words("echo :cd x")).unwrap();
+        assert_eq!(options, CommandOptions::default());
+        assert_eq!(rest, words("echo :cd x"));
+        let (_, rest) = command_options(words(": noop")).unwrap();
+        assert_eq!(rest, words(": noop"));
+
+        for line in [
+            ":timeout",
+            ":timeout soon ls",
+            ":timeout 0 ls",
+            ":cd /srv",
+            ":env A ls",
+            ":nice 5 ls",
+        ] {
+            assert!(command_options(words(line)).is_err(), "{}", line);
         }
     }
 }
@@ -0,0 +1,187 @@
+//! Retrying after transient failures
+//!
+//! A datagram lost on the way shows as a send that fails or a reply that
+//! never comes. Such transport failures ([`ClientError::is_transient`]) are
+//! retried when connecting and for requests that are safe to repeat: up to
+//! `retry_attempts` more times, waiting `retry_backoff_ms` before the first
+//! retry and twice as long before each further one, plus up to
+//! `retry_jitter_ms` at random so clients that failed together don't retry
+//! together. What the server answered, such as a failed command or a
+//! rejected connection, is final.
+//!
+//! Commands only count as safe to repeat if the caller says so
+//! ([`CommandOptions::idempotent`](crate::client::CommandOptions)), and only
+//! until anything of them arrived.
+
+use crate::{config::ClientConfig, ClientError, Result};
+use rand::Rng;
+use shell_proto::{FileOp, Message};
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Longest wait between retries, before jitter
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often and how patiently to retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt (0 = don't retry)
+    pub attempts: u32,
+
+    /// Wait before the first retry, doubled for each further one
+    pub backoff: Duration,
+
+    /// Most that is added to each wait at random
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// The policy configured in `config`
+    pub fn from_config(config: &ClientConfig) -> Self {
+        Self {
+            attempts: config.retry_attempts,
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+            jitter: Duration::from_millis(config.retry_jitter_ms),
+        }
+    }
+
+    /// The wait before retry `retry` (1 for the first), without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(MAX_RETRY_DELAY)
+    }
+
+    /// Wait before retry `retry`
+    pub async fn wait(&self, retry: u32) {
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_millis() as u64);
+        crate::time::sleep(self.backoff(retry) + Duration::from_millis(jitter)).await;
+    }
+
+    /// Run `attempt` until it succeeds, fails for good, or the retries ran
+    /// out, returning its last result
+    pub async fn run<T, F, Fut>(&self, what: &str, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.is_transient() && retry < self.attempts => {
+                    retry += 1;
+                    warn!(retry, error = %e, "Retrying {}", what);
+                    self.wait(retry).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Check whether sending `message` twice has the same effect as sending it
+/// once, so it can be sent again when its reply went missing
+pub fn idempotent(message: &Message) -> bool {
+    match message {
+        Message::Ping
+        | Message::BannerAck
+        | Message::SetEnv(_)
+        | Message::UnsetEnv(_)
+        | Message::StatsRequest(_)
+        | Message::FetchOutput(_)
+        | Message::CompleteRequest(_)
+        // Chunks are asked for and written by offset
+        | Message::ChunkRequest(_)
+        | Message::FileChunk(_) => true,
+        Message::FileOp(request) => matches!(request.op, FileOp::Stat { .. } | FileOp::List { .. }),
+        _ => false,
+    }
+}
+
+impl ClientError {
+    /// Check whether the error is a transport failure, after which trying
+    /// again may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Network(_) | ClientError::Io(_) | ClientError::Timeout(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::{FileOpRequest, StatsRequest};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(1),
+            jitter: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(40), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        // Transport failures are retried until the retries run out
+        let tries = AtomicU32::new(0);
+        let result: Result<()> = policy(2)
+            .run("test", || async {
+                tries.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::Timeout("no reply".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+        assert_eq!(tries.load(Ordering::SeqCst), 3);
+
+        let tries = AtomicU32::new(0);
+        let result = policy(2)
+            .run("test", || async {
+                match tries.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(ClientError::Timeout("no reply".to_string())),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 1);
+
+        // Answers are final
+        let tries = AtomicU32::new(0);
+        let result: Result<()> = policy(2)
+            .run("test", || async {
+                tries.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::Rejected("unknown client".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(ClientError::Rejected(_))));
+        assert_eq!(tries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        assert!(idempotent(&Message::StatsRequest(StatsRequest { id: 1 })));
+        let op = |op| Message::FileOp(FileOpRequest { id: 1, op });
+        assert!(idempotent(&op(FileOp::List {
+            path: "/tmp".to_string()
+        })));
+        assert!(!idempotent(&op(FileOp::Remove {
+            path: "/tmp/x".to_string(),
+            recursive: false
+        })));
+    }
+}
@@ -0,0 +1,429 @@
+//! Script files (`-f`)
+//!
+//! A script holds one command per line, run in order over one session.
+//! Blank lines and `#` comments are skipped, and a command may carry the
+//! REPL's `:timeout SECS`, `:cd DIR` and `:env NAME=VALUE` prefixes.
+//! `:on-error continue` lets the lines after a failing command run anyway,
+//! `:on-error stop` (the default) ends the script at the first failure; each
+//! applies to the lines below it.
+
+use crate::{
+    client::{Client, CommandOptions},
+    output::{status_name, CommandRecord, OutputFormat},
+    repl::command_options,
+    ClientError, Result,
+};
+use shell_proto::CommandStatus;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Exit code for a command that timed out (as with `timeout(1)`)
+pub const EXIT_TIMEOUT: i32 = 124;
+
+/// Exit code for a command that was killed (as for SIGKILL in a shell)
+pub const EXIT_KILLED: i32 = 137;
+
+/// Exit code for a command the server refused, or that never ran or
+/// finished as the server could not be reached (as with `ssh`)
+pub const EXIT_FAILED: i32 = 255;
+
+/// What to do when a command fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Skip the rest of the script
+    Stop,
+
+    /// Run the next command anyway
+    Continue,
+}
+
+/// One command of a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Line number in the file (from 1)
+    pub line: usize,
+
+    /// Command and arguments, without prefixes
+    pub words: Vec<String>,
+
+    /// Timeout and working directory set by prefixes
+    pub options: CommandOptions,
+
+    /// What a failure of this command does to the rest
+    pub on_error: OnError,
+}
+
+/// A parsed script
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    /// Commands in order
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    /// Read and parse a script file
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path)?;
+        Self::parse(&source).map_err(|e| match e {
+            ClientError::Config(message) => {
+                ClientError::Config(format!("{}: {}", path.display(), message))
+            }
+            e => e,
+        })
+    }
+
+    /// Parse a script, failing with the first line in error
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut on_error = OnError::Stop;
+        let mut steps = Vec::new();
+
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let error =
+                |message: String| ClientError::Config(format!("line {}: {}", line, message));
+
+            let words =
+                shell_words::split(text).map_err(|e| error(format!("invalid syntax: {}", e)))?;
+            if words.is_empty() {
+                continue;
+            }
+
+            if words[0] == ":on-error" {
+                on_error = match words.get(1).map(String::as_str) {
+                    Some("stop") if words.len() == 2 => OnError::Stop,
+                    Some("continue") if words.len() == 2 => OnError::Continue,
+                    _ => return Err(error("usage: :on-error stop|continue".to_string())),
+                };
+                continue;
+            }
+
+            let (options, words) = command_options(words).map_err(|e| match e {
+                ClientError::Repl(message) => error(message),
+                e => e,
+            })?;
+            steps.push(Step {
+                line,
+                words,
+                options,
+                on_error,
+            });
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+/// How a step ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The command ran to its end (or its timeout)
+    Finished {
+        status: CommandStatus,
+        exit_code: i32,
+    },
+
+    /// The request failed, e.g. the command was denied
+    Failed(String),
+
+    /// The server did not answer in time
+    TimedOut(String),
+
+    /// The server could not be reached, or the connection was lost
+    Unreachable(String),
+
+    /// Not run, as an earlier command failed
+    Skipped,
+}
+
+impl Outcome {
+    /// The outcome of a request that failed with `error`
+    pub fn from_error(error: &ClientError) -> Self {
+        match error {
+            ClientError::Timeout(_) => Outcome::TimedOut(error.to_string()),
+            ClientError::Connection(_)
+            | ClientError::NotConnected
+            | ClientError::Network(_)
+            | ClientError::Io(_)
+            | ClientError::Rejected(_)
+            | ClientError::HostKeyChanged(_) => Outcome::Unreachable(error.to_string()),
+            _ => Outcome::Failed(error.to_string()),
+        }
+    }
+
+    /// Check whether the step counts as failed
+    pub fn is_failure(&self) -> bool {
+        match self {
+            Outcome::Finished { status, .. } => *status != CommandStatus::Success,
+            Outcome::Failed(_) | Outcome::TimedOut(_) | Outcome::Unreachable(_) => true,
+            Outcome::Skipped => false,
+        }
+    }
+
+    /// The exit code standing for this outcome
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Finished { status, exit_code } => match status {
+                CommandStatus::Success => 0,
+                CommandStatus::Error if *exit_code > 0 => *exit_code,
+                CommandStatus::Error => 1,
+                CommandStatus::Timeout => EXIT_TIMEOUT,
+                CommandStatus::Killed | CommandStatus::OomKilled => EXIT_KILLED,
+            },
+            Outcome::TimedOut(_) => EXIT_TIMEOUT,
+            Outcome::Failed(_) | Outcome::Unreachable(_) => EXIT_FAILED,
+            Outcome::Skipped => 0,
+        }
+    }
+
+    /// Status and exit code columns of the summary tables
+    pub(crate) fn columns(&self) -> (&'static str, String) {
+        match self {
+            Outcome::Finished { status, .. } => {
+                (status_name(*status), self.exit_code().to_string())
+            }
+            Outcome::Failed(_) => ("error", self.exit_code().to_string()),
+            Outcome::TimedOut(_) => ("timeout", self.exit_code().to_string()),
+            Outcome::Unreachable(_) => ("unreachable", self.exit_code().to_string()),
+            Outcome::Skipped => ("skipped", "-".to_string()),
+        }
+    }
+}
+
+/// A step and how it went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepResult {
+    /// Line number in the file
+    pub line: usize,
+
+    /// The command as run
+    pub command: String,
+
+    /// How it ended
+    pub outcome: Outcome,
+
+    /// Time from request to response
+    pub elapsed: Duration,
+}
+
+/// Run the steps of `script` in order
+///
+/// As text, their output is passed through as it arrives; as JSON, a record
+/// is printed for each step once it is done.
+pub async fn run(client: &Client, script: &Script, format: OutputFormat) -> Vec<StepResult> {
+    let json = format == OutputFormat::Json;
+    let mut results = Vec::with_capacity(script.steps.len());
+    let mut stopped = false;
+
+    for step in &script.steps {
+        let command = shell_words::join(&step.words);
+        if stopped {
+            if json {
+                CommandRecord::skipped(step.words.clone())
+                    .with_line(step.line)
+                    .print();
+            }
+            results.push(StepResult {
+                line: step.line,
+                command,
+                outcome: Outcome::Skipped,
+                elapsed: Duration::ZERO,
+            });
+            continue;
+        }
+
+        let start = Instant::now();
+        let response = if json {
+            client
+                .execute_command_with(
+                    step.words[0].clone(),
+                    step.words[1..].to_vec(),
+                    &step.options,
+                )
+                .await
+        } else {
+            eprintln!("+ {}", command);
+            client
+                .execute_command_streaming(
+                    step.words[0].clone(),
+                    step.words[1..].to_vec(),
+                    &step.options,
+                    &mut |stderr: bool, data: &[u8]| {
+                        let _ = if stderr {
+                            std::io::stderr().write_all(data)
+                        } else {
+                            std::io::stdout().write_all(data)
+                        };
+                    },
+                    None,
+                )
+                .await
+        };
+        let outcome = match response {
+            Ok(response) => {
+                if json {
+                    CommandRecord::finished(step.words.clone(), &response, start.elapsed())
+                        .with_line(step.line)
+                        .print();
+                } else {
+                    let _ = std::io::stdout().write_all(&response.stdout);
+                    let _ = std::io::stderr().write_all(&response.stderr);
+                    let _ = std::io::stdout().flush();
+                }
+                Outcome::Finished {
+                    status: response.status,
+                    exit_code: response.exit_code,
+                }
+            }
+            Err(e) => {
+                if json {
+                    CommandRecord::failed(step.words.clone(), e.to_string(), start.elapsed())
+                        .with_line(step.line)
+                        .print();
+                } else {
+                    eprintln!("line {}: {}", step.line, e);
+                }
+                Outcome::from_error(&e)
+            }
+        };
+
+        stopped = outcome.is_failure() && step.on_error == OnError::Stop;
+        results.push(StepResult {
+            line: step.line,
+            command,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+    }
+
+    results
+}
+
+/// Exit code of a whole run: that of the first failed command, 0 if none
+/// failed
+pub fn exit_code(results: &[StepResult]) -> i32 {
+    results
+        .iter()
+        .find(|result| result.outcome.is_failure())
+        .map_or(0, |result| result.outcome.exit_code())
+}
+
+/// Format the results as a table, one row per command
+pub fn summary(results: &[StepResult]) -> String {
+    let mut table = format!(
+        "{:>5}  {:<11}  {:>4}  {:>8}  {}\n",
+        "LINE", "STATUS", "EXIT", "TIME", "COMMAND"
+    );
+    for result in results {
+        let (status, exit) = result.outcome.columns();
+        table.push_str(&format!(
+            "{:>5}  {:<11}  {:>4}  {:>7.1}s  {}\n",
+            result.line,
+            status,
+            exit,
+            result.elapsed.as_secs_f64(),
+            result.command
+        ));
+    }
+
+    let failed = results.iter().filter(|r| r.outcome.is_failure()).count();
+    let skipped = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Skipped)
+        .count();
+    table.push_str(&format!(
+        "{} commands, {} failed, {} skipped\n",
+        results.len(),
+        failed,
+        skipped
+    ));
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = Script::parse(
+            "# deploy\n\
+             \n\
+             git pull  # update\n\
+             :on-error continue\n\
+             :timeout 600 :cd /srv/app make 'all tests'\n\
+             :on-error stop\n\
+             systemctl restart app\n",
+        )
+        .unwrap();
+
+        let lines: Vec<_> = script.steps.iter().map(|step| step.line).collect();
+        assert_eq!(lines, vec![3, 5, 7]);
+        assert_eq!(script.steps[0].words, vec!["git", "pull"]);
+        assert_eq!(script.steps[0].on_error, OnError::Stop);
+        assert_eq!(script.steps[1].words, vec!["make", "all tests"]);
+        assert_eq!(script.steps[1].options.timeout, Some(600));
+        assert_eq!(
+            script.steps[1].options.working_dir.as_deref(),
+            Some("/srv/app")
+        );
+        assert_eq!(script.steps[1].on_error, OnError::Continue);
+        assert_eq!(script.steps[2].on_error, OnError::Stop);
+
+        for source in ["ls\n:on-error maybe", "echo 'open", ":timeout x ls"] {
+            let e = Script::parse(source).unwrap_err().to_string();
+            assert!(e.contains("line "), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let result = |outcome| StepResult {
+            line: 1,
+            command: "true".to_string(),
+            outcome,
+            elapsed: Duration::ZERO,
+        };
+        let finished = |status, exit_code| Outcome::Finished { status, exit_code };
+
+        let ok = result(finished(CommandStatus::Success, 0));
+        assert_eq!(exit_code(&[ok.clone(), result(Outcome::Skipped)]), 0);
+        assert_eq!(exit_code(&[]), 0);
+
+        // The first failure decides
+        let results = [
+            ok.clone(),
+            result(finished(CommandStatus::Error, 2)),
+            result(finished(CommandStatus::Timeout, -1)),
+        ];
+        assert_eq!(exit_code(&results), 2);
+        assert_eq!(
+            exit_code(&[result(finished(CommandStatus::Timeout, -1))]),
+            EXIT_TIMEOUT
+        );
+        assert_eq!(
+            exit_code(&[result(finished(CommandStatus::Killed, -1))]),
+            EXIT_KILLED
+        );
+        assert_eq!(
+            exit_code(&[result(Outcome::Failed("denied".to_string()))]),
+            EXIT_FAILED
+        );
+
+        // Failures of the link, not of the command
+        let timeout = Outcome::from_error(&ClientError::Timeout("no response".to_string()));
+        assert_eq!(exit_code(&[ok.clone(), result(timeout)]), EXIT_TIMEOUT);
+        let lost = Outcome::from_error(&ClientError::NotConnected);
+        assert_eq!(
+            lost,
+            Outcome::Unreachable("Not connected to server".to_string())
+        );
+        assert_eq!(exit_code(&[result(lost.clone())]), EXIT_FAILED);
+        assert!(summary(&[result(lost)]).contains("unreachable  255"));
+
+        let table = summary(&results);
+        assert!(table.contains("failed"));
+        assert!(table.ends_with("3 commands, 2 failed, 0 skipped\n"));
+    }
+}
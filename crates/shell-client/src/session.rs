@@ -0,0 +1,325 @@
+//! Embedding remote execution in other programs
+//!
+//! [`ShellSession`] wraps a connected [`Client`] in the few calls a program
+//! needs: run a command and read its output as it arrives, copy files, and
+//! forward connections. Nothing here prints or prompts; a server whose key is
+//! not known yet, or whose banner needs acknowledging, is an error unless the
+//! caller allowed it up front.
+//!
+//! ```no_run
+//! use shell_client::{config::ClientConfig, session::{OutputEvent, ShellSession}};
+//!
+//! # async fn example() -> shell_client::Result<()> {
+//! let config = ClientConfig::load_from_file("client.toml")?;
+//! let session = ShellSession::connect(config, false).await?;
+//!
+//! let mut execution = session.exec(["uname", "-a"], Default::default());
+//! while let Some(event) = execution.next().await {
+//!     match event? {
+//!         OutputEvent::Stdout(data) => print!("{}", String::from_utf8_lossy(&data)),
+//!         OutputEvent::Stderr(data) => eprint!("{}", String::from_utf8_lossy(&data)),
+//!         OutputEvent::Exit(response) => println!("exit {}", response.exit_code),
+//!     }
+//! }
+//! session.disconnect().await
+//! # }
+//! ```
+//!
+//! A connection carries one request at a time, so the calls of a session
+//! wait for each other: a command runs only once the one before it ended,
+//! and nothing else runs while forwarding.
+
+use crate::{
+    client::{Client, CommandOptions},
+    config::ClientConfig,
+    connect,
+    events::EventHandler,
+    forward, keepalive, ClientError, Result,
+};
+use shell_proto::CommandResponse;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+
+/// Something a running command did
+#[derive(Debug, Clone)]
+pub enum OutputEvent {
+    /// Output on stdout
+    Stdout(Vec<u8>),
+
+    /// Output on stderr
+    Stderr(Vec<u8>),
+
+    /// The command ended; its output came before as `Stdout` and `Stderr`,
+    /// so the response's own is empty
+    Exit(CommandResponse),
+}
+
+/// A connection to a server for running commands from a program
+pub struct ShellSession {
+    /// Connected client
+    client: Arc<Client>,
+
+    /// Held while a request is in flight
+    busy: Arc<Mutex<()>>,
+}
+
+impl ShellSession {
+    /// Connect to the server of `config`
+    ///
+    /// Fails if the server's key is not in the known hosts (unless
+    /// `accept_new_host_keys` is set), or if it has a banner to acknowledge
+    /// and `accept_banner` is not set.
+    pub async fn connect(config: ClientConfig, accept_banner: bool) -> Result<Self> {
+        Ok(Self::from_client(open(config, accept_banner).await?))
+    }
+
+    /// Connect like [`connect`](Self::connect), telling `handler` of the
+    /// client's events from the start (the server's banner included)
+    pub async fn connect_with_events(
+        config: ClientConfig,
+        accept_banner: bool,
+        handler: Arc<dyn EventHandler>,
+    ) -> Result<Self> {
+        Ok(Self::from_client(
+            open_with(config, accept_banner, Some(handler)).await?,
+        ))
+    }
+
+    /// Use a client that is already connected, keeping its connection
+    /// alive
+    pub fn from_client(client: Client) -> Self {
+        let client = Arc::new(client);
+        keepalive::spawn(&client);
+        Self {
+            client,
+            busy: Arc::default(),
+        }
+    }
+
+    /// The underlying client, for what the session does not cover
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Start running `argv` (the command and its arguments)
+    ///
+    /// The command runs in the background; its output is read from the
+    /// returned [`Execution`].
+    pub fn exec<I, S>(&self, argv: I, options: CommandOptions) -> Execution
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut argv: Vec<String> = argv.into_iter().map(Into::into).collect();
+        let (event_tx, events) = mpsc::unbounded_channel();
+        let (interrupt_tx, mut interrupts) = mpsc::unbounded_channel();
+        let client = Arc::clone(&self.client);
+        let busy = Arc::clone(&self.busy);
+
+        tokio::spawn(async move {
+            if argv.is_empty() {
+                let _ = event_tx.send(Err(ClientError::Request("Empty command".to_string())));
+                return;
+            }
+            let _busy = busy.lock().await;
+            let args = argv.split_off(1);
+            let output_tx = event_tx.clone();
+            let result = client
+                .execute_command_streaming(
+                    argv.remove(0),
+                    args,
+                    &options,
+                    &mut |stderr: bool, data: &[u8]| {
+                        let data = data.to_vec();
+                        let event = if stderr {
+                            OutputEvent::Stderr(data)
+                        } else {
+                            OutputEvent::Stdout(data)
+                        };
+                        let _ = output_tx.send(Ok(event));
+                    },
+                    Some(&mut interrupts),
+                )
+                .await;
+
+            let _ = match result {
+                Ok(mut response) => {
+                    let stdout = std::mem::take(&mut response.stdout);
+                    let stderr = std::mem::take(&mut response.stderr);
+                    if !stdout.is_empty() {
+                        let _ = event_tx.send(Ok(OutputEvent::Stdout(stdout)));
+                    }
+                    if !stderr.is_empty() {
+                        let _ = event_tx.send(Ok(OutputEvent::Stderr(stderr)));
+                    }
+                    event_tx.send(Ok(OutputEvent::Exit(response)))
+                }
+                Err(e) => event_tx.send(Err(e)),
+            };
+        });
+
+        Execution {
+            events,
+            interrupts: interrupt_tx,
+        }
+    }
+
+    /// Upload the local file `local` to `remote`, continuing an earlier
+    /// upload if `resume` is set; returns the bytes sent
+    pub async fn upload(&self, local: &Path, remote: &str, resume: bool) -> Result<u64> {
+        let _busy = self.busy.lock().await;
+        self.client.upload(local, remote, resume).await
+    }
+
+    /// Download the remote file `remote` to `local`, continuing an earlier
+    /// download if `resume` is set; returns the bytes received
+    pub async fn download(&self, remote: &str, local: &Path, resume: bool) -> Result<u64> {
+        let _busy = self.busy.lock().await;
+        self.client.download(remote, local, resume).await
+    }
+
+    /// Run a SOCKS5 proxy on `bind` whose connections the server makes,
+    /// until the connection to the server ends
+    pub async fn forward(&self, bind: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(bind).await?;
+        self.forward_on(listener).await
+    }
+
+    /// Like [`forward`](Self::forward), on a listener bound already
+    pub async fn forward_on(&self, listener: TcpListener) -> Result<()> {
+        let _busy = self.busy.lock().await;
+        forward::serve_dynamic(Arc::clone(&self.client), listener).await
+    }
+
+    /// Close the connection
+    pub async fn disconnect(&self) -> Result<()> {
+        let _busy = self.busy.lock().await;
+        self.client.disconnect().await
+    }
+}
+
+/// A command started by [`ShellSession::exec`]
+pub struct Execution {
+    /// Output and the end of the command, or why it failed
+    events: mpsc::UnboundedReceiver<Result<OutputEvent>>,
+
+    /// Cancels the command
+    interrupts: mpsc::UnboundedSender<()>,
+}
+
+impl Execution {
+    /// The next event, `None` once the command ended
+    ///
+    /// An error (e.g. as the command was denied or the connection lost) is
+    /// the last item.
+    pub async fn next(&mut self) -> Option<Result<OutputEvent>> {
+        self.events.recv().await
+    }
+
+    /// Send the command SIGINT; a second call kills it
+    pub fn interrupt(&self) {
+        let _ = self.interrupts.send(());
+    }
+
+    /// A handle to interrupt the command with while another task waits in
+    /// [`next`](Self::next)
+    pub fn interrupter(&self) -> Interrupter {
+        Interrupter {
+            interrupts: self.interrupts.clone(),
+        }
+    }
+
+    /// Wait for the command to end, collecting its output into the response
+    pub async fn wait(mut self) -> Result<CommandResponse> {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        while let Some(event) = self.next().await {
+            match event? {
+                OutputEvent::Stdout(data) => stdout.extend_from_slice(&data),
+                OutputEvent::Stderr(data) => stderr.extend_from_slice(&data),
+                OutputEvent::Exit(mut response) => {
+                    response.stdout = stdout;
+                    response.stderr = stderr;
+                    return Ok(response);
+                }
+            }
+        }
+        Err(ClientError::Connection(
+            "The command ended without a response".to_string(),
+        ))
+    }
+}
+
+/// Interrupts a command started by [`ShellSession::exec`]
+#[derive(Debug, Clone)]
+pub struct Interrupter {
+    interrupts: mpsc::UnboundedSender<()>,
+}
+
+impl Interrupter {
+    /// Send the command SIGINT; a second call kills it
+    pub fn interrupt(&self) {
+        let _ = self.interrupts.send(());
+    }
+}
+
+/// Connect to the server of `config`, acknowledging the banner if allowed
+/// to; fails on a server key not known yet
+pub(crate) async fn open(config: ClientConfig, accept_banner: bool) -> Result<Client> {
+    open_with(config, accept_banner, None).await
+}
+
+/// [`open`], with `handler` told of the client's events
+async fn open_with(
+    config: ClientConfig,
+    accept_banner: bool,
+    handler: Option<Arc<dyn EventHandler>>,
+) -> Result<Client> {
+    let mut client = connect::open(config).await?;
+    if let Some(handler) = handler {
+        client.set_event_handler(handler);
+    }
+    client.connect().await?;
+    if client.host_key_unknown() {
+        let _ = client.disconnect().await;
+        return Err(ClientError::Request(
+            "The server key is not known yet (see --accept-new-host-key)".to_string(),
+        ));
+    }
+    if client.banner_ack_required() {
+        if !accept_banner {
+            let _ = client.disconnect().await;
+            return Err(ClientError::Request(
+                "The server banner was not accepted (see --accept-banner)".to_string(),
+            ));
+        }
+        client.acknowledge_banner().await?;
+    }
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_exec_errors() {
+        let config = ClientConfig::default();
+        let session = ShellSession::from_client(Client::new(config).await.unwrap());
+
+        let execution = session.exec(Vec::<String>::new(), CommandOptions::default());
+        assert!(matches!(
+            execution.wait().await,
+            Err(ClientError::Request(_))
+        ));
+
+        let mut execution = session.exec(["true"], CommandOptions::default());
+        assert!(matches!(
+            execution.next().await,
+            Some(Err(ClientError::NotConnected))
+        ));
+        assert!(execution.next().await.is_none());
+    }
+}
@@ -0,0 +1,34 @@
+//! Clocks and timers
+//!
+//! Natively these are `std`'s clocks and tokio's timers. A browser
+//! (`wasm32`) has neither a clock `std` can read nor a tokio runtime to
+//! drive timers, so there the clocks come from `web-time` and the timers
+//! from the browser's. The client core times everything through here.
+
+pub use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tokio::time::{error::Elapsed, sleep, timeout};
+
+/// Wait until `duration` has passed
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: std::time::Duration) {
+    gloo_timers::future::sleep(duration).await
+}
+
+/// Run `future`, giving up once `duration` has passed
+#[cfg(target_arch = "wasm32")]
+pub async fn timeout<F: std::future::Future>(
+    duration: std::time::Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    tokio::select! {
+        output = future => Ok(output),
+        () = sleep(duration) => Err(Elapsed),
+    }
+}
+
+/// A [`timeout`] ran out
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
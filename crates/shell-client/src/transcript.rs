@@ -0,0 +1,228 @@
+//! Session transcripts (`--log-session`)
+//!
+//! With `session_log_path` set (or `--log-session FILE`), the client writes
+//! down what a terminal would have shown of the session: every command line
+//! it sends, with when it was sent, everything the command writes to stdout
+//! and stderr, and how it ended. Terminal sessions are recorded byte for
+//! byte, as `script` does.
+//!
+//! With `session_timing_path` as well (`--log-timing FILE`), the time each
+//! piece of the transcript arrived is noted in the timing format of
+//! `script`, so the session can be played back at its own pace:
+//! `scriptreplay --timing=FILE TRANSCRIPT`.
+//!
+//! Both files are appended to and created readable by their owner only: a
+//! transcript holds whatever the commands print, secrets included. Failing
+//! to write is logged as a warning and does not fail the command.
+
+use crate::{audit::Outcome, config::ClientConfig, output::status_name, time::Instant, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Transcript of a session, and its timing
+pub struct Transcript {
+    files: Mutex<Files>,
+}
+
+struct Files {
+    /// What was shown
+    log: File,
+
+    /// When it was shown (`<seconds since the last write> <bytes>`)
+    timing: Option<File>,
+
+    /// When the last write was
+    last: Instant,
+
+    /// The last write ended a line
+    line_start: bool,
+}
+
+impl Transcript {
+    /// Open (or create) the transcript at `path`, and its timing at
+    /// `timing`, for a session with `server`
+    pub fn open(path: &Path, timing: Option<&Path>, server: &str) -> Result<Self> {
+        let mut log = append(path)?;
+        let header = format!("Script started on {} [server {}]\n", now(), server);
+        let fresh = log.metadata()?.len() == 0;
+        if fresh {
+            // `scriptreplay` skips the first line, which has no timing
+            log.write_all(header.as_bytes())?;
+        }
+
+        let transcript = Self {
+            files: Mutex::new(Files {
+                log,
+                timing: timing.map(append).transpose()?,
+                last: Instant::now(),
+                line_start: true,
+            }),
+        };
+        if !fresh {
+            transcript.note(&header);
+        }
+        Ok(transcript)
+    }
+
+    /// Open the transcript described by the configuration, if any
+    pub fn from_config(config: &ClientConfig, server: &str) -> Result<Option<Self>> {
+        config
+            .session_log_path
+            .as_deref()
+            .map(|path| Self::open(path, config.session_timing_path.as_deref(), server))
+            .transpose()
+    }
+
+    /// Note that `command` (None = the login shell of a terminal session)
+    /// was sent
+    pub fn command(&self, command: Option<&str>, args: &[String], pty: bool) {
+        let mut argv: Vec<&str> = pty.then_some("shell").into_iter().collect();
+        argv.extend(command);
+        argv.extend(args.iter().map(String::as_str));
+        self.note(&format!("[{}] $ {}\n", now(), shell_words::join(argv)));
+    }
+
+    /// Add output
+    pub fn output(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.write(data);
+        }
+    }
+
+    /// Note how the command sent at `started` ended
+    pub fn finished(&self, started: DateTime<Utc>, outcome: &Outcome) {
+        let elapsed = (Utc::now() - started).to_std().unwrap_or_default();
+        let result = match outcome {
+            Outcome::Finished {
+                exit_code: Some(code),
+                ..
+            } => format!("exit {}", code),
+            Outcome::Finished {
+                status: Some(status),
+                exit_code: None,
+            } => status_name(*status).to_string(),
+            Outcome::Finished { .. } => "closed".to_string(),
+            Outcome::Failed(error) => format!("error: {}", error),
+        };
+        self.note(&format!(
+            "[{}] {} after {:.1}s\n",
+            now(),
+            result,
+            elapsed.as_secs_f64()
+        ));
+    }
+
+    /// Append `line` of the client's own, after ending the output's last
+    /// line if need be
+    fn note(&self, line: &str) {
+        let start = !self.files.lock().unwrap().line_start;
+        let mut data = Vec::with_capacity(line.len() + 1);
+        if start {
+            data.push(b'\n');
+        }
+        data.extend_from_slice(line.as_bytes());
+        self.write(&data);
+    }
+
+    /// Append `data`, and its timing
+    fn write(&self, data: &[u8]) {
+        let mut files = self.files.lock().unwrap();
+        let mut write = || -> std::io::Result<()> {
+            files.log.write_all(data)?;
+            let delay = files.last.elapsed();
+            files.last = Instant::now();
+            if let Some(timing) = &mut files.timing {
+                writeln!(timing, "{:.6} {}", delay.as_secs_f64(), data.len())?;
+            }
+            Ok(())
+        };
+        if let Err(e) = write() {
+            warn!(error = %e, "Failed to write the session transcript");
+        }
+        files.line_start = data.ends_with(b"\n");
+    }
+}
+
+/// Open `path` for appending, readable by its owner only if created
+fn append(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
+
+/// The time now (RFC 3339)
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::CommandStatus;
+
+    #[test]
+    fn test_transcript() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.log");
+        let timing = dir.path().join("session.timing");
+
+        let transcript = Transcript::open(&path, Some(&timing), "ab12").unwrap();
+        let started = Utc::now();
+        let args = vec!["-l".to_string(), "my dir".to_string()];
+        transcript.command(Some("ls"), &args, false);
+        transcript.output(b"total 0\n");
+        transcript.output(b"");
+        transcript.finished(
+            started,
+            &Outcome::Finished {
+                status: Some(CommandStatus::Success),
+                exit_code: Some(0),
+            },
+        );
+        transcript.command(None, &[], true);
+        transcript.output(b"$ exit");
+        transcript.finished(started, &Outcome::Failed("lost".to_string()));
+        drop(transcript);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].starts_with("Script started on "));
+        assert!(lines[0].ends_with(" [server ab12]"));
+        assert!(lines[1].ends_with("] $ ls -l 'my dir'"));
+        assert_eq!(lines[2], "total 0");
+        assert!(lines[3].contains("] exit 0 after "));
+        assert!(lines[4].ends_with("] $ shell"));
+        assert_eq!(lines[5], "$ exit");
+        assert!(lines[6].contains("] error: lost after "));
+
+        // Timing covers everything after the first line
+        let timing = std::fs::read_to_string(&timing).unwrap();
+        let timed: usize = timing
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap().parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(timed, contents.len() - lines[0].len() - 1);
+        assert_eq!(timing.lines().count(), 6);
+
+        // Reopened: the new header is timed too
+        Transcript::open(&path, None, "ab12").unwrap();
+        let reopened = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(reopened.lines().count(), 8);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! `put` and `get` in the REPL
+//!
+//! A single file is one upload or download. With `-r`, directories are
+//! copied recursively: `put -r` creates remote directories with MKDIR file
+//! operations, and `get -r` lists them with LIST. File operations and
+//! transfers resolve relative paths differently (transfers place them in the
+//! server's transfer directory), so recursive transfers need an absolute
+//! remote path.
+//!
+//! While a file is copied, its progress is shown on stderr if that is a
+//! terminal.
+
+use crate::{client::Client, time::Instant, ClientError, Result};
+use shell_proto::{FileKind, FileOp};
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often the progress of a file is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Parsed `put`/`get` arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferArgs {
+    /// Local path for `put`, remote path for `get`
+    pub source: String,
+
+    /// Where to put the copy (defaults to the source's file name)
+    pub target: Option<String>,
+
+    /// Copy directories and their contents
+    pub recursive: bool,
+
+    /// Continue interrupted transfers
+    pub resume: bool,
+}
+
+impl TransferArgs {
+    /// Parse the words following `command`
+    pub fn parse(command: &str, words: &[String]) -> Result<Self> {
+        let usage = || {
+            let (source, target) = match command {
+                "put" => ("<local>", "[remote]"),
+                _ => ("<remote>", "[local]"),
+            };
+            ClientError::Repl(format!(
+                "usage: {} [-r] [--resume] {} {}",
+                command, source, target
+            ))
+        };
+
+        let mut recursive = false;
+        let mut resume = false;
+        let mut paths = Vec::new();
+        let mut flags = true;
+        for word in words {
+            match word.as_str() {
+                "-r" | "--recursive" if flags => recursive = true,
+                "--resume" if flags => resume = true,
+                "--" if flags => flags = false,
+                flag if flags && flag.starts_with('-') && flag.len() > 1 => return Err(usage()),
+                path => paths.push(path.to_string()),
+            }
+        }
+
+        let mut paths = paths.into_iter();
+        let source = paths.next().ok_or_else(usage)?;
+        let target = paths.next();
+        if paths.next().is_some() {
+            return Err(usage());
+        }
+
+        Ok(Self {
+            source,
+            target,
+            recursive,
+            resume,
+        })
+    }
+}
+
+/// What a transfer copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Files copied
+    pub files: u64,
+
+    /// Bytes in those files
+    pub bytes: u64,
+}
+
+/// Upload a local file or directory
+pub async fn put(client: &Client, args: &TransferArgs) -> Result<Summary> {
+    let local = PathBuf::from(&args.source);
+    let name = file_name(&args.source)?;
+    let remote = match &args.target {
+        None => name.to_string(),
+        Some(target) if target.ends_with('/') => format!("{}{}", target, name),
+        Some(target) => target.clone(),
+    };
+
+    let mut summary = Summary::default();
+    if !fs::metadata(&local)?.is_dir() {
+        summary.bytes = upload(client, &local, &remote, args.resume).await?;
+        summary.files = 1;
+        println!(
+            "{} -> {} ({} bytes)",
+            local.display(),
+            remote,
+            summary.bytes
+        );
+        return Ok(summary);
+    }
+    if !args.recursive {
+        return Err(ClientError::Repl(format!(
+            "put: {} is a directory (use -r)",
+            args.source
+        )));
+    }
+    if !remote.starts_with('/') {
+        return Err(ClientError::Repl(
+            "put -r: the remote path must be absolute".to_string(),
+        ));
+    }
+
+    let mut dirs = vec![(local, remote)];
+    while let Some((local_dir, remote_dir)) = dirs.pop() {
+        client
+            .file_op(FileOp::Mkdir {
+                path: remote_dir.clone(),
+                recursive: true,
+            })
+            .await?;
+
+        let mut entries = fs::read_dir(&local_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let local_path = entry.path();
+            let remote_path = join(&remote_dir, &entry.file_name().to_string_lossy());
+            // Symlinks and special files are left out, as they would be by
+            // a download
+            let file_type = fs::symlink_metadata(&local_path)?.file_type();
+            if file_type.is_dir() {
+                dirs.push((local_path, remote_path));
+            } else if file_type.is_file() {
+                let bytes = upload(client, &local_path, &remote_path, args.resume).await?;
+                println!(
+                    "{} -> {} ({} bytes)",
+                    local_path.display(),
+                    remote_path,
+                    bytes
+                );
+                summary.files += 1;
+                summary.bytes += bytes;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Download a remote file or directory
+pub async fn get(client: &Client, args: &TransferArgs) -> Result<Summary> {
+    let remote = args.source.clone();
+    let name = file_name(&remote)?;
+    let local = match &args.target {
+        Some(target) if Path::new(target).is_dir() => Path::new(target).join(name),
+        Some(target) => PathBuf::from(target),
+        None => PathBuf::from(name),
+    };
+
+    if args.recursive && !remote.starts_with('/') {
+        return Err(ClientError::Repl(
+            "get -r: the remote path must be absolute".to_string(),
+        ));
+    }
+
+    let mut summary = Summary::default();
+    let is_dir = args.recursive
+        && client
+            .file_op(FileOp::Stat {
+                path: remote.clone(),
+            })
+            .await?
+            .first()
+            .is_some_and(|entry| entry.kind == FileKind::Directory);
+    if !is_dir {
+        summary.bytes = download(client, &remote, &local, args.resume).await?;
+        summary.files = 1;
+        println!(
+            "{} -> {} ({} bytes)",
+            remote,
+            local.display(),
+            summary.bytes
+        );
+        return Ok(summary);
+    }
+
+    let mut dirs = vec![(remote, local)];
+    while let Some((remote_dir, local_dir)) = dirs.pop() {
+        fs::create_dir_all(&local_dir)?;
+
+        let entries = client
+            .file_op(FileOp::List {
+                path: remote_dir.clone(),
+            })
+            .await?;
+        for entry in entries {
+            let remote_path = join(&remote_dir, &entry.name);
+            let local_path = local_dir.join(&entry.name);
+            match entry.kind {
+                FileKind::Directory => dirs.push((remote_path, local_path)),
+                FileKind::File => {
+                    let bytes = download(client, &remote_path, &local_path, args.resume).await?;
+                    println!(
+                        "{} -> {} ({} bytes)",
+                        remote_path,
+                        local_path.display(),
+                        bytes
+                    );
+                    summary.files += 1;
+                    summary.bytes += bytes;
+                }
+                // Downloads only serve regular files
+                FileKind::Symlink | FileKind::Other => {}
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Upload one file, showing its progress
+pub async fn upload(client: &Client, local: &Path, remote: &str, resume: bool) -> Result<u64> {
+    let mut progress = Progress::new(local.display().to_string());
+    let result = client
+        .upload_with_progress(local, remote, resume, &mut |done, total| {
+            progress.update(done, total)
+        })
+        .await;
+    progress.finish();
+    result
+}
+
+/// Download one file, showing its progress
+pub async fn download(client: &Client, remote: &str, local: &Path, resume: bool) -> Result<u64> {
+    let mut progress = Progress::new(remote.to_string());
+    let result = client
+        .download_with_progress(remote, local, resume, &mut |done, total| {
+            progress.update(done, total)
+        })
+        .await;
+    progress.finish();
+    result
+}
+
+/// Progress of a file being copied, drawn over one line of the terminal
+struct Progress {
+    /// Path of the file
+    label: String,
+
+    /// When the line was last drawn (None = not yet)
+    drawn: Option<Instant>,
+
+    /// Whether there is a terminal to draw on
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(label: String) -> Self {
+        Self {
+            label,
+            drawn: None,
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Show that `done` of `total` bytes are copied
+    fn update(&mut self, done: u64, total: u64) {
+        if !self.enabled
+            || self
+                .drawn
+                .is_some_and(|at| at.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+        eprint!("\r\x1B[K{}", progress_line(&self.label, done, total));
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Clear the line again
+    fn finish(self) {
+        if self.drawn.is_some() {
+            eprint!("\r\x1B[K");
+        }
+    }
+}
+
+/// `label` with `done` of `total` bytes copied
+pub(crate) fn progress_line(label: &str, done: u64, total: u64) -> String {
+    let percent = if total == 0 {
+        100
+    } else {
+        done.min(total) * 100 / total
+    };
+    format!("{} {:>3}% ({}/{} bytes)", label, percent, done, total)
+}
+
+/// The last component of a local or remote path
+pub(crate) fn file_name(path: &str) -> Result<&str> {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+        .ok_or_else(|| ClientError::Repl(format!("{}: no file name to copy to", path)))
+}
+
+/// Append `name` to a remote directory path
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = TransferArgs::parse("put", &words("-r --resume src /srv/src")).unwrap();
+        assert_eq!(
+            args,
+            TransferArgs {
+                source: "src".to_string(),
+                target: Some("/srv/src".to_string()),
+                recursive: true,
+                resume: true,
+            }
+        );
+
+        let args = TransferArgs::parse("get", &words("-- -odd")).unwrap();
+        assert_eq!(args.source, "-odd");
+        assert_eq!(args.target, None);
+        assert!(!args.recursive && !args.resume);
+
+        for line in ["", "a b c", "-x a"] {
+            assert!(TransferArgs::parse("get", &words(line)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("/var/log/").unwrap(), "log");
+        assert_eq!(file_name("notes.txt").unwrap(), "notes.txt");
+        assert!(file_name("/").is_err());
+        assert!(file_name("..").is_err());
+        assert_eq!(join("/srv/", "a"), "/srv/a");
+    }
+
+    #[test]
+    fn test_progress_line() {
+        assert_eq!(
+            progress_line("a.bin", 512, 2048),
+            "a.bin  25% (512/2048 bytes)"
+        );
+        assert_eq!(progress_line("empty", 0, 0), "empty 100% (0/0 bytes)");
+    }
+}
@@ -0,0 +1,1080 @@
+//! Terminal UI for several sessions at once (`--tui`, feature `tui`)
+//!
+//! Each server gets a tab with a connection of its own, opened through the
+//! embedding API: a [`ShellSession`] whose client reports to an
+//! [`EventHandler`](crate::events::EventHandler). Commands typed on the input
+//! line run in the tab shown, and their output appears as it arrives; copies
+//! queued with `:put` and `:get` are listed with their progress; the log pane
+//! has the client's log and what the servers announced (banners, approvals,
+//! reconnects and disconnects).
+//!
+//! Commands of the input line:
+//!
+//! - `:open PROFILE` opens a tab on a server profile
+//! - `:close` closes the tab shown
+//! - `:put [--resume] LOCAL [REMOTE]` and `:get [--resume] REMOTE [LOCAL]`
+//!   queue a copy of a file to or from the tab's server
+//! - `:quit` (or Ctrl+Q) leaves
+//!
+//! Tab and Shift+Tab switch tabs, PgUp and PgDn scroll the output, Ctrl+C
+//! interrupts the command running in the tab shown.
+
+use crate::{
+    client::CommandOptions,
+    config::ClientConfig,
+    events::{Direction, DisconnectReason, Event, Notification},
+    session::{OutputEvent, ShellSession},
+    transfer::{self, TransferArgs},
+    Result,
+};
+use ratatui::{
+    crossterm::event::{self as term, Event as TermEvent, KeyCode, KeyEvent, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, Paragraph, Tabs},
+    Frame,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Lines of output kept per tab
+const MAX_LINES: usize = 10_000;
+
+/// Lines kept in the log pane
+const MAX_LOG: usize = 500;
+
+/// Height of the transfer and log panes
+const PANE_HEIGHT: u16 = 8;
+
+/// Lines PgUp and PgDn scroll by
+const PAGE: usize = 10;
+
+/// How often the screen is redrawn when nothing else happens (for the log)
+const TICK: Duration = Duration::from_millis(250);
+
+/// How long the key reader waits for a key before checking it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long leaving waits for each connection to close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lines the client logged, waiting to be moved to the log pane
+static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Writes the client's log to the log pane, for `tracing_subscriber`
+/// (`with_writer(LogWriter::default)`)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogWriter;
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        LOG.lock().unwrap().extend(
+            text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string),
+        );
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a tab's session is doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TabState {
+    Connecting,
+    Ready,
+    Running,
+    Closed(String),
+}
+
+impl TabState {
+    fn describe(&self) -> String {
+        match self {
+            Self::Connecting => "connecting".to_string(),
+            Self::Ready => "ready".to_string(),
+            Self::Running => "running".to_string(),
+            Self::Closed(reason) => format!("closed: {}", reason),
+        }
+    }
+}
+
+/// Where an escape sequence in the output got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+    Osc,
+    OscEsc,
+}
+
+/// A session with one server
+struct Tab {
+    /// Unique for the run, as tabs close
+    id: usize,
+
+    /// Profile name
+    name: String,
+
+    state: TabState,
+
+    session: Option<Arc<ShellSession>>,
+
+    /// Interrupts the command running
+    interrupt: Option<mpsc::UnboundedSender<()>>,
+
+    /// How the last command ended
+    last: Option<String>,
+
+    /// Output without escape sequences; the last line may go on
+    lines: Vec<String>,
+
+    /// Lines scrolled back from the end
+    scroll: usize,
+
+    escape: Escape,
+}
+
+impl Tab {
+    fn new(id: usize, name: String) -> Self {
+        Self {
+            id,
+            name,
+            state: TabState::Connecting,
+            session: None,
+            interrupt: None,
+            last: None,
+            lines: vec![String::new()],
+            scroll: 0,
+            escape: Escape::None,
+        }
+    }
+
+    /// Add output, leaving out escape sequences and control characters
+    fn append(&mut self, data: &[u8]) {
+        for c in String::from_utf8_lossy(data).chars() {
+            self.escape = match (self.escape, c) {
+                (Escape::None, '\x1b') => Escape::Esc,
+                (Escape::None, '\n') => {
+                    self.lines.push(String::new());
+                    Escape::None
+                }
+                (Escape::None, '\t') => {
+                    let line = self.lines.last_mut().expect("a line");
+                    let spaces = 8 - line.chars().count() % 8;
+                    line.push_str(&" ".repeat(spaces));
+                    Escape::None
+                }
+                (Escape::None, c) if c.is_control() => Escape::None,
+                (Escape::None, c) => {
+                    self.lines.last_mut().expect("a line").push(c);
+                    Escape::None
+                }
+                (Escape::Esc, '[') => Escape::Csi,
+                (Escape::Esc, ']') => Escape::Osc,
+                (Escape::Csi, '\x40'..='\x7e') => Escape::None,
+                (Escape::Csi, _) => Escape::Csi,
+                (Escape::Osc, '\x07') => Escape::None,
+                (Escape::Osc, '\x1b') => Escape::OscEsc,
+                (Escape::Osc, _) => Escape::Osc,
+                (Escape::OscEsc, _) | (Escape::Esc, _) => Escape::None,
+            };
+        }
+        if self.lines.len() > MAX_LINES {
+            self.lines.drain(..self.lines.len() - MAX_LINES);
+        }
+    }
+
+    /// Start a new line if the output left one unfinished
+    fn end_line(&mut self) {
+        if !self.lines.last().expect("a line").is_empty() {
+            self.lines.push(String::new());
+        }
+    }
+}
+
+/// How far a queued copy got
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TransferState {
+    Queued,
+    Active,
+    Done,
+    Failed(String),
+}
+
+/// A copy queued with `:put` or `:get`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Transfer {
+    tab: usize,
+    host: String,
+    direction: Direction,
+    local: PathBuf,
+    remote: String,
+    resume: bool,
+    bytes: u64,
+    size: u64,
+    state: TransferState,
+}
+
+impl Transfer {
+    fn describe(&self) -> String {
+        let label = match self.direction {
+            Direction::Upload => {
+                format!("{} -> {}:{}", self.local.display(), self.host, self.remote)
+            }
+            Direction::Download => {
+                format!("{}:{} -> {}", self.host, self.remote, self.local.display())
+            }
+        };
+        match &self.state {
+            TransferState::Queued => format!("{} queued", label),
+            TransferState::Active => transfer::progress_line(&label, self.bytes, self.size),
+            TransferState::Done => format!("{} done ({} bytes)", label, self.bytes),
+            TransferState::Failed(error) => format!("{} failed: {}", label, error),
+        }
+    }
+}
+
+/// What happened, for the UI to show
+enum Update {
+    /// Tab `id` connected, or failed to
+    Connected(usize, std::result::Result<Arc<ShellSession>, String>),
+
+    /// The client of tab `id` reported an event
+    Client(usize, Event),
+
+    /// The command running in tab `id` ended (with its exit code)
+    Finished(usize, std::result::Result<i32, String>),
+
+    /// Transfer `index` ended (with the bytes copied)
+    Transferred(usize, std::result::Result<u64, String>),
+}
+
+/// What a key asks for beyond changing the screen
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    /// Run the command in tab `id`
+    Run(usize, Vec<String>),
+
+    /// Open a tab on the profile
+    Open(String),
+
+    /// Close tab `id`
+    Close(usize),
+
+    /// Start transfer `index`
+    Transfer(usize),
+
+    /// Interrupt the command running in tab `id`
+    Interrupt(usize),
+
+    Quit,
+}
+
+/// State of the UI
+#[derive(Default)]
+struct App {
+    tabs: Vec<Tab>,
+
+    /// Index of the tab shown
+    current: usize,
+
+    next_id: usize,
+
+    transfers: Vec<Transfer>,
+
+    log: VecDeque<String>,
+
+    input: String,
+}
+
+impl App {
+    /// Add a tab connecting to `name`, and show it
+    fn add_tab(&mut self, name: String) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.log(format!("{}: connecting", name));
+        self.tabs.push(Tab::new(id, name));
+        self.current = self.tabs.len() - 1;
+        id
+    }
+
+    /// Remove tab `id`, returning its session to close
+    fn close(&mut self, id: usize) -> Option<Arc<ShellSession>> {
+        let index = self.tabs.iter().position(|tab| tab.id == id)?;
+        let tab = self.tabs.remove(index);
+        self.log(format!("{}: closed", tab.name));
+        self.current = self.current.min(self.tabs.len().saturating_sub(1));
+        tab.session
+    }
+
+    fn tab(&mut self, id: usize) -> Option<&mut Tab> {
+        self.tabs.iter_mut().find(|tab| tab.id == id)
+    }
+
+    fn log(&mut self, line: String) {
+        let time = chrono::Local::now().format("%H:%M:%S");
+        self.log.push_back(format!("{} {}", time, line));
+        while self.log.len() > MAX_LOG {
+            self.log.pop_front();
+        }
+    }
+
+    /// Move what the client logged to the log pane
+    fn drain_log(&mut self) {
+        let lines = std::mem::take(&mut *LOG.lock().unwrap());
+        for line in lines {
+            self.log(line);
+        }
+    }
+
+    /// Handle a key press
+    fn key(&mut self, key: KeyEvent) -> Option<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('q') if ctrl => return Some(Action::Quit),
+            KeyCode::Char('c') if ctrl => {
+                let tab = self.tabs.get(self.current)?;
+                return tab.interrupt.as_ref().map(|_| Action::Interrupt(tab.id));
+            }
+            KeyCode::Tab if !self.tabs.is_empty() => {
+                self.current = (self.current + 1) % self.tabs.len();
+            }
+            KeyCode::BackTab if !self.tabs.is_empty() => {
+                self.current = (self.current + self.tabs.len() - 1) % self.tabs.len();
+            }
+            KeyCode::PageUp => {
+                if let Some(tab) = self.tabs.get_mut(self.current) {
+                    tab.scroll = (tab.scroll + PAGE).min(tab.lines.len().saturating_sub(1));
+                }
+            }
+            KeyCode::PageDown => {
+                if let Some(tab) = self.tabs.get_mut(self.current) {
+                    tab.scroll = tab.scroll.saturating_sub(PAGE);
+                }
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                return self.submit(line.trim());
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            _ => {}
+        }
+        None
+    }
+
+    /// Handle a line of input
+    fn submit(&mut self, line: &str) -> Option<Action> {
+        if line.is_empty() {
+            return None;
+        }
+        let (command, words) = match line.strip_prefix(':') {
+            Some(command) => (true, command),
+            None => (false, line),
+        };
+        let words = match shell_words::split(words) {
+            Ok(words) => words,
+            Err(e) => {
+                self.log(format!("Invalid command: {}", e));
+                return None;
+            }
+        };
+
+        if !command {
+            let tab = self.tabs.get_mut(self.current)?;
+            if tab.state != TabState::Ready {
+                let reason = format!(
+                    "{}: cannot run commands while {}",
+                    tab.name,
+                    tab.state.describe()
+                );
+                self.log(reason);
+                return None;
+            }
+            tab.end_line();
+            tab.append(format!("$ {}\n", line).as_bytes());
+            tab.scroll = 0;
+            tab.state = TabState::Running;
+            return Some(Action::Run(tab.id, words));
+        }
+
+        match words.first().map(String::as_str) {
+            Some("open") if words.len() == 2 => Some(Action::Open(words[1].clone())),
+            Some("close") => self.tabs.get(self.current).map(|tab| Action::Close(tab.id)),
+            Some(name @ ("put" | "get")) => self.queue(name, &words[1..]),
+            Some("quit" | "q") => Some(Action::Quit),
+            _ => {
+                self.log(
+                    "Commands: :open PROFILE, :close, :put LOCAL [REMOTE], \
+                     :get REMOTE [LOCAL], :quit"
+                        .to_string(),
+                );
+                None
+            }
+        }
+    }
+
+    /// Queue a copy to or from the server of the tab shown
+    fn queue(&mut self, command: &str, words: &[String]) -> Option<Action> {
+        let args = match TransferArgs::parse(command, words) {
+            Ok(args) if args.recursive => {
+                self.log(format!("{} -r is not available here", command));
+                return None;
+            }
+            Ok(args) => args,
+            Err(e) => {
+                self.log(e.to_string());
+                return None;
+            }
+        };
+        let target = match args.target {
+            Some(target) => target,
+            None => match transfer::file_name(&args.source) {
+                Ok(name) => name.to_string(),
+                Err(e) => {
+                    self.log(e.to_string());
+                    return None;
+                }
+            },
+        };
+
+        let tab = self.tabs.get(self.current)?;
+        if matches!(tab.state, TabState::Connecting | TabState::Closed(_)) {
+            let reason = format!("{}: cannot copy while {}", tab.name, tab.state.describe());
+            self.log(reason);
+            return None;
+        }
+        let (direction, local, remote) = match command {
+            "put" => (Direction::Upload, args.source, target),
+            _ => (Direction::Download, target, args.source),
+        };
+        self.transfers.push(Transfer {
+            tab: tab.id,
+            host: tab.name.clone(),
+            direction,
+            local: PathBuf::from(local),
+            remote,
+            resume: args.resume,
+            bytes: 0,
+            size: 0,
+            state: TransferState::Queued,
+        });
+        Some(Action::Transfer(self.transfers.len() - 1))
+    }
+
+    /// Show what happened
+    fn update(&mut self, update: Update) {
+        match update {
+            Update::Connected(id, result) => {
+                let Some(tab) = self.tab(id) else {
+                    // Closed while connecting
+                    if let Ok(session) = result {
+                        tokio::spawn(async move { session.disconnect().await });
+                    }
+                    return;
+                };
+                let line = match result {
+                    Ok(session) => {
+                        tab.session = Some(session);
+                        tab.state = TabState::Ready;
+                        format!("{}: connected", tab.name)
+                    }
+                    Err(e) => {
+                        tab.state = TabState::Closed(e.clone());
+                        format!("{}: {}", tab.name, e)
+                    }
+                };
+                self.log(line);
+            }
+            Update::Client(id, event) => self.event(id, event),
+            Update::Finished(id, result) => {
+                let Some(tab) = self.tab(id) else { return };
+                tab.interrupt = None;
+                if tab.state == TabState::Running {
+                    tab.state = TabState::Ready;
+                }
+                let line = match result {
+                    Ok(code) => {
+                        tab.last = Some(format!("exit {}", code));
+                        None
+                    }
+                    Err(e) => {
+                        tab.last = Some("failed".to_string());
+                        Some(format!("{}: {}", tab.name, e))
+                    }
+                };
+                if let Some(line) = line {
+                    self.log(line);
+                }
+            }
+            Update::Transferred(index, result) => {
+                let Some(transfer) = self.transfers.get_mut(index) else {
+                    return;
+                };
+                match result {
+                    Ok(bytes) => {
+                        transfer.bytes = bytes;
+                        transfer.state = TransferState::Done;
+                    }
+                    Err(e) => transfer.state = TransferState::Failed(e),
+                }
+                let line = transfer.describe();
+                self.log(line);
+            }
+        }
+    }
+
+    /// Show an event of the client of tab `id`
+    fn event(&mut self, id: usize, event: Event) {
+        let Some(tab) = self.tab(id) else { return };
+        let name = tab.name.clone();
+        match event {
+            Event::Output { data, .. } => tab.append(&data),
+            Event::Notification(Notification::Banner(banner)) => {
+                for line in banner.lines() {
+                    self.log(format!("{}: {}", name, line));
+                }
+            }
+            Event::Notification(Notification::Pending {
+                request_id,
+                approval_id,
+                timeout,
+            }) => self.log(format!(
+                "{}: request {} waits for approval {} (up to {}s)",
+                name, request_id, approval_id, timeout
+            )),
+            Event::Notification(Notification::Reconnected { new_session }) => {
+                let session = if new_session { " (new session)" } else { "" };
+                self.log(format!("{}: reconnected{}", name, session));
+            }
+            Event::Disconnect(DisconnectReason::Closed(reason)) => {
+                let reason = reason.unwrap_or_else(|| "closed by the server".to_string());
+                tab.state = TabState::Closed(reason.clone());
+                self.log(format!("{}: {}", name, reason));
+            }
+            // The next request reconnects
+            Event::Disconnect(DisconnectReason::Lost(reason)) => {
+                self.log(format!("{}: connection lost: {}", name, reason));
+            }
+            Event::TransferProgress(progress) => {
+                let transfer = self.transfers.iter_mut().find(|transfer| {
+                    transfer.tab == id
+                        && transfer.direction == progress.direction
+                        && transfer.remote == progress.path
+                        && matches!(
+                            transfer.state,
+                            TransferState::Queued | TransferState::Active
+                        )
+                });
+                if let Some(transfer) = transfer {
+                    transfer.state = TransferState::Active;
+                    transfer.bytes = progress.bytes;
+                    transfer.size = progress.size;
+                }
+            }
+        }
+    }
+}
+
+/// Draw the UI
+fn draw(frame: &mut Frame, app: &App) {
+    let [tabs_area, output_area, panes_area, input_area, help_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(PANE_HEIGHT),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let titles: Vec<Line> = app
+        .tabs
+        .iter()
+        .map(|tab| {
+            let mark = match tab.state {
+                TabState::Ready => "",
+                TabState::Running => "*",
+                TabState::Connecting => "...",
+                TabState::Closed(_) => "!",
+            };
+            Line::from(format!("{}{}", tab.name, mark))
+        })
+        .collect();
+    frame.render_widget(
+        Tabs::new(titles)
+            .select(app.current)
+            .highlight_style(Style::new().reversed()),
+        tabs_area,
+    );
+
+    let output = match app.tabs.get(app.current) {
+        Some(tab) => {
+            let height = output_area.height.saturating_sub(2) as usize;
+            let end = tab.lines.len().saturating_sub(tab.scroll);
+            let lines: Vec<Line> = tab.lines[end.saturating_sub(height)..end]
+                .iter()
+                .map(|line| Line::raw(line.as_str()))
+                .collect();
+            let mut title = format!(" {} ({}", tab.name, tab.state.describe());
+            if let Some(last) = tab.last.as_ref().filter(|_| tab.state == TabState::Ready) {
+                title.push_str(&format!(", {}", last));
+            }
+            if tab.scroll > 0 {
+                title.push_str(&format!(", {} lines back", tab.scroll));
+            }
+            title.push_str(") ");
+            Paragraph::new(lines).block(Block::bordered().title(title))
+        }
+        None => Paragraph::new("No sessions: :open PROFILE").block(Block::bordered()),
+    };
+    frame.render_widget(output, output_area);
+
+    let [transfers_area, log_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(panes_area);
+    let shown = PANE_HEIGHT.saturating_sub(2) as usize;
+    let transfers: Vec<String> = app
+        .transfers
+        .iter()
+        .skip(app.transfers.len().saturating_sub(shown))
+        .map(Transfer::describe)
+        .collect();
+    frame.render_widget(
+        List::new(transfers).block(Block::bordered().title(" Transfers ")),
+        transfers_area,
+    );
+    let log: Vec<&str> = app
+        .log
+        .iter()
+        .skip(app.log.len().saturating_sub(shown))
+        .map(String::as_str)
+        .collect();
+    frame.render_widget(
+        List::new(log).block(Block::bordered().title(" Log ")),
+        log_area,
+    );
+
+    let prompt = match app.tabs.get(app.current) {
+        Some(tab) => format!("{}> ", tab.name),
+        None => "> ".to_string(),
+    };
+    let cursor = (prompt.chars().count() + app.input.chars().count()) as u16;
+    frame.render_widget(
+        Paragraph::new(format!("{}{}", prompt, app.input)),
+        input_area,
+    );
+    frame.set_cursor_position((input_area.x + cursor.min(input_area.width), input_area.y));
+    frame.render_widget(
+        Paragraph::new("Tab: next session  PgUp/PgDn: scroll  Ctrl+C: interrupt  Ctrl+Q: quit")
+            .dim(),
+        help_area,
+    );
+}
+
+/// Run the UI with a tab on each of `initial` (name and configuration);
+/// `:open` picks from the profiles of `profiles`
+pub async fn run(
+    profiles: ClientConfig,
+    initial: Vec<(String, ClientConfig)>,
+    accept_banner: bool,
+) -> Result<()> {
+    let (updates_tx, mut updates) = mpsc::unbounded_channel();
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    let mut app = App::default();
+    for (name, config) in initial {
+        open(&mut app, &updates_tx, name, config, accept_banner);
+    }
+
+    let mut terminal = ratatui::init();
+    let reader = KeyReader::spawn(keys_tx);
+    let mut tick = tokio::time::interval(TICK);
+    let result = loop {
+        app.drain_log();
+        if let Err(e) = terminal.draw(|frame| draw(frame, &app)) {
+            break Err(e.into());
+        }
+        let action = tokio::select! {
+            Some(event) = keys.recv() => match event {
+                TermEvent::Key(key) if key.kind == term::KeyEventKind::Press => app.key(key),
+                _ => None,
+            },
+            Some(update) = updates.recv() => {
+                app.update(update);
+                None
+            }
+            _ = tick.tick() => None,
+        };
+        match action {
+            Some(Action::Quit) => break Ok(()),
+            Some(action) => perform(&mut app, action, &profiles, &updates_tx, accept_banner),
+            None => {}
+        }
+    };
+    drop(reader);
+    ratatui::restore();
+
+    for tab in &app.tabs {
+        if let Some(session) = &tab.session {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, session.disconnect()).await;
+        }
+    }
+    result
+}
+
+/// Do what the user asked for
+fn perform(
+    app: &mut App,
+    action: Action,
+    profiles: &ClientConfig,
+    updates: &mpsc::UnboundedSender<Update>,
+    accept_banner: bool,
+) {
+    match action {
+        Action::Open(name) => match profiles.with_profile(&name) {
+            Ok(config) => open(app, updates, name, config, accept_banner),
+            Err(e) => app.log(e.to_string()),
+        },
+        Action::Close(id) => {
+            if let Some(session) = app.close(id) {
+                tokio::spawn(async move { session.disconnect().await });
+            }
+        }
+        Action::Run(id, argv) => {
+            let Some(tab) = app.tab(id) else { return };
+            let Some(session) = tab.session.clone() else {
+                return;
+            };
+            let (interrupt_tx, interrupts) = mpsc::unbounded_channel();
+            tab.interrupt = Some(interrupt_tx);
+            tokio::spawn(run_command(session, argv, interrupts, id, updates.clone()));
+        }
+        Action::Interrupt(id) => {
+            if let Some(interrupt) = app.tab(id).and_then(|tab| tab.interrupt.as_ref()) {
+                let _ = interrupt.send(());
+            }
+        }
+        Action::Transfer(index) => {
+            let transfer = app.transfers[index].clone();
+            let Some(session) = app.tab(transfer.tab).and_then(|tab| tab.session.clone()) else {
+                return;
+            };
+            let updates = updates.clone();
+            tokio::spawn(async move {
+                // The session runs one request at a time, so copies queue
+                // behind each other and the tab's commands
+                let result = match transfer.direction {
+                    Direction::Upload => {
+                        session
+                            .upload(&transfer.local, &transfer.remote, transfer.resume)
+                            .await
+                    }
+                    Direction::Download => {
+                        session
+                            .download(&transfer.remote, &transfer.local, transfer.resume)
+                            .await
+                    }
+                };
+                let _ = updates.send(Update::Transferred(
+                    index,
+                    result.map_err(|e| e.to_string()),
+                ));
+            });
+        }
+        Action::Quit => {}
+    }
+}
+
+/// Add a tab for `name` and connect it in the background
+fn open(
+    app: &mut App,
+    updates: &mpsc::UnboundedSender<Update>,
+    name: String,
+    config: ClientConfig,
+    accept_banner: bool,
+) {
+    let id = app.add_tab(name);
+    let updates = updates.clone();
+    tokio::spawn(async move {
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let forward = updates.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if forward.send(Update::Client(id, event)).is_err() {
+                    return;
+                }
+            }
+        });
+        let result = ShellSession::connect_with_events(config, accept_banner, Arc::new(events_tx))
+            .await
+            .map(Arc::new)
+            .map_err(|e| e.to_string());
+        let _ = updates.send(Update::Connected(id, result));
+    });
+}
+
+/// Run `argv` in tab `id`; its output is shown from the client's events
+async fn run_command(
+    session: Arc<ShellSession>,
+    argv: Vec<String>,
+    mut interrupts: mpsc::UnboundedReceiver<()>,
+    id: usize,
+    updates: mpsc::UnboundedSender<Update>,
+) {
+    let mut execution = session.exec(argv, CommandOptions::default());
+    let result = loop {
+        tokio::select! {
+            event = execution.next() => match event {
+                Some(Ok(OutputEvent::Exit(response))) => break Ok(response.exit_code),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e.to_string()),
+                None => break Err("The command ended without a response".to_string()),
+            },
+            Some(()) = interrupts.recv() => execution.interrupt(),
+        }
+    };
+    let _ = updates.send(Update::Finished(id, result));
+}
+
+/// Reads terminal events on a thread of its own, as crossterm blocks
+struct KeyReader {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeyReader {
+    fn spawn(keys: mpsc::UnboundedSender<TermEvent>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match term::poll(POLL_INTERVAL) {
+                    Ok(true) => match term::read() {
+                        Ok(event) if keys.send(event).is_ok() => {}
+                        _ => return,
+                    },
+                    Ok(false) => {}
+                    Err(_) => return,
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TransferProgress;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    fn press(app: &mut App, code: KeyCode) -> Option<Action> {
+        app.key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_line(app: &mut App, line: &str) -> Option<Action> {
+        for c in line.chars() {
+            press(app, KeyCode::Char(c));
+        }
+        press(app, KeyCode::Enter)
+    }
+
+    fn ready(app: &mut App, name: &str) -> usize {
+        let id = app.add_tab(name.to_string());
+        app.tab(id).unwrap().state = TabState::Ready;
+        id
+    }
+
+    #[test]
+    fn test_input() {
+        let mut app = App::default();
+        assert_eq!(
+            type_line(&mut app, ":open lab"),
+            Some(Action::Open("lab".to_string()))
+        );
+
+        let lab = ready(&mut app, "lab");
+        assert_eq!(
+            type_line(&mut app, "ls -l 'my dir'"),
+            Some(Action::Run(
+                lab,
+                vec!["ls".into(), "-l".into(), "my dir".into()]
+            ))
+        );
+        assert_eq!(app.tabs[0].state, TabState::Running);
+        assert_eq!(app.tabs[0].lines[0], "$ ls -l 'my dir'");
+        assert!(app.input.is_empty());
+
+        // One command at a time per tab
+        assert_eq!(type_line(&mut app, "uptime"), None);
+
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(app.key(ctrl_c), None);
+        app.tabs[0].interrupt = Some(mpsc::unbounded_channel().0);
+        assert_eq!(app.key(ctrl_c), Some(Action::Interrupt(lab)));
+
+        let web = ready(&mut app, "web");
+        assert_eq!(app.current, 1);
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.current, 0);
+        press(&mut app, KeyCode::BackTab);
+        assert_eq!(app.current, 1);
+
+        press(&mut app, KeyCode::Char('x'));
+        press(&mut app, KeyCode::Esc);
+        assert_eq!(type_line(&mut app, ":close"), Some(Action::Close(web)));
+        assert_eq!(type_line(&mut app, ":bogus"), None);
+        assert_eq!(type_line(&mut app, ":quit"), Some(Action::Quit));
+    }
+
+    #[test]
+    fn test_output() {
+        let mut tab = Tab::new(0, "lab".to_string());
+        tab.append(b"\x1b[1mbold\x1b[0m\r\nnext\x1b]0;ti");
+        tab.append(b"tle\x07\tline\n");
+        assert_eq!(tab.lines, vec!["bold", "next    line", ""]);
+
+        tab.append(b"partial");
+        tab.end_line();
+        assert_eq!(tab.lines.len(), 4);
+
+        tab.append("x\n".repeat(MAX_LINES).as_bytes());
+        assert_eq!(tab.lines.len(), MAX_LINES);
+    }
+
+    #[test]
+    fn test_transfers() {
+        let mut app = App::default();
+        assert_eq!(type_line(&mut app, ":put notes.txt"), None);
+
+        let lab = ready(&mut app, "lab");
+        assert_eq!(type_line(&mut app, ":put -r dir"), None);
+        assert_eq!(
+            type_line(&mut app, ":put /tmp/notes.txt"),
+            Some(Action::Transfer(0))
+        );
+        assert_eq!(
+            type_line(&mut app, ":get --resume logs/app.log"),
+            Some(Action::Transfer(1))
+        );
+        assert_eq!(app.transfers[0].remote, "notes.txt");
+        assert_eq!(app.transfers[1].local, PathBuf::from("app.log"));
+        assert!(app.transfers[1].resume);
+
+        app.update(Update::Client(
+            lab,
+            Event::TransferProgress(TransferProgress {
+                request_id: 3,
+                direction: Direction::Upload,
+                path: "notes.txt".to_string(),
+                bytes: 50,
+                size: 200,
+            }),
+        ));
+        assert_eq!(app.transfers[0].state, TransferState::Active);
+        assert!(app.transfers[0].describe().ends_with(" 25% (50/200 bytes)"));
+
+        app.update(Update::Transferred(0, Ok(200)));
+        app.update(Update::Transferred(1, Err("No such file".to_string())));
+        assert_eq!(app.transfers[0].state, TransferState::Done);
+        assert!(app.log.back().unwrap().ends_with("failed: No such file"));
+    }
+
+    #[test]
+    fn test_events() {
+        let mut app = App::default();
+        let lab = ready(&mut app, "lab");
+        app.update(Update::Client(
+            lab,
+            Event::Output {
+                request_id: 1,
+                stderr: false,
+                data: b"hello\n".to_vec(),
+            },
+        ));
+        app.update(Update::Client(
+            lab,
+            Event::Notification(Notification::Banner("Authorized use only".to_string())),
+        ));
+        app.update(Update::Client(
+            lab,
+            Event::Disconnect(DisconnectReason::Closed(None)),
+        ));
+        app.update(Update::Finished(lab, Ok(2)));
+        // Events of a closed tab
+        app.update(Update::Finished(lab + 1, Ok(0)));
+
+        assert_eq!(app.tabs[0].lines[0], "hello");
+        assert_eq!(app.tabs[0].last.as_deref(), Some("exit 2"));
+        let log: Vec<&String> = app.log.iter().collect();
+        assert!(log[1].ends_with("lab: Authorized use only"));
+        assert!(log[2].ends_with("lab: closed by the server"));
+
+        // The client's log
+        io::Write::write_all(&mut LogWriter, b"  INFO Connected\n").unwrap();
+        app.drain_log();
+        assert!(app.log.iter().any(|line| line.ends_with("INFO Connected")));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut app = App::default();
+        let lab = ready(&mut app, "lab");
+        ready(&mut app, "web");
+        app.current = 0;
+        app.event(
+            lab,
+            Event::Output {
+                request_id: 1,
+                stderr: false,
+                data: b"uptime: 3 days\n".to_vec(),
+            },
+        );
+        app.input = "who".to_string();
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| draw(frame, &app)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("lab (ready)"));
+        assert!(screen.contains("web"));
+        assert!(screen.contains("uptime: 3 days"));
+        assert!(screen.contains("Transfers"));
+        assert!(screen.contains("lab> who"));
+
+        // Without sessions
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| draw(frame, &App::default())).unwrap();
+    }
+}
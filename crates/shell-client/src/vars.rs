@@ -0,0 +1,201 @@
+//! REPL variables
+//!
+//! `set NAME=VALUE` keeps a value in the REPL, and `$NAME` or `${NAME}` in
+//! later command lines is replaced by it before the line is split into
+//! words, so long paths and host names are typed once. Nothing is sent to
+//! the server (`export` is for the environment of remote commands).
+//!
+//! Substitution follows the shell's quoting: not inside single quotes, nor
+//! after a backslash (`\$HOME` stays `$HOME`). A value is substituted as one
+//! word even if it holds spaces or quotes. `$NAME` of a variable that was
+//! never set is left alone, for the remote command to see.
+
+use crate::{ClientError, Result};
+use std::collections::BTreeMap;
+
+/// Variables set in the REPL
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Variables {
+    values: BTreeMap<String, String>,
+}
+
+impl Variables {
+    /// No variables
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `name` to `value`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        if !valid_name(name) {
+            return Err(ClientError::Repl(format!(
+                "Invalid variable name {:?} (letters, digits and _, not starting with a digit)",
+                name
+            )));
+        }
+        self.values.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Remove `name`, returning whether it was set
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.values.remove(name).is_some()
+    }
+
+    /// The value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// The variables, by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// `line` with the variables substituted
+    pub fn expand(&self, line: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(line.len());
+        // The quote we are inside of, if any
+        let mut quote = None;
+        let mut rest = line;
+
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '\\' if quote != Some('\'') => {
+                    expanded.push(c);
+                    if let Some(escaped) = rest.chars().next() {
+                        expanded.push(escaped);
+                        rest = &rest[escaped.len_utf8()..];
+                    }
+                }
+                '\'' | '"' if quote.is_none() => {
+                    quote = Some(c);
+                    expanded.push(c);
+                }
+                c if quote == Some(c) => {
+                    quote = None;
+                    expanded.push(c);
+                }
+                '$' if quote != Some('\'') => {
+                    let Some((name, len)) = reference(rest)? else {
+                        expanded.push(c);
+                        continue;
+                    };
+                    match self.get(name) {
+                        Some(value) if quote.is_some() => expanded.push_str(&escape_quoted(value)),
+                        Some(value) => expanded.push_str(&shell_words::quote(value)),
+                        None => {
+                            expanded.push(c);
+                            expanded.push_str(&rest[..len]);
+                        }
+                    }
+                    rest = &rest[len..];
+                }
+                _ => expanded.push(c),
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// The name referred to at the start of `text` (after a `$`), and how long
+/// the reference is
+fn reference(text: &str) -> Result<Option<(&str, usize)>> {
+    if let Some(braced) = text.strip_prefix('{') {
+        let end = braced
+            .find('}')
+            .ok_or_else(|| ClientError::Repl("Missing } after ${".to_string()))?;
+        let name = &braced[..end];
+        if !valid_name(name) {
+            return Err(ClientError::Repl(format!("Bad substitution ${{{}}}", name)));
+        }
+        return Ok(Some((name, end + 2)));
+    }
+
+    let len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let name = &text[..len];
+    Ok(valid_name(name).then_some((name, len)))
+}
+
+/// Check whether `name` can name a variable
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `value` escaped to stand inside double quotes
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Variables {
+        let mut vars = Variables::new();
+        vars.set("APP", "/srv/my app").unwrap();
+        vars.set("HOST", "db1").unwrap();
+        vars
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        shell_words::split(&vars().expand(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            words("ls -l $APP/logs"),
+            vec!["ls", "-l", "/srv/my app/logs"]
+        );
+        assert_eq!(words("ping ${HOST}.lan"), vec!["ping", "db1.lan"]);
+        assert_eq!(
+            words("echo \"at $HOST: $APP\""),
+            vec!["echo", "at db1: /srv/my app"]
+        );
+
+        // Quoting and escaping keep the text as it is
+        assert_eq!(
+            words("echo '$HOST' \\$HOST"),
+            vec!["echo", "$HOST", "$HOST"]
+        );
+        assert_eq!(words("echo $HOME $ $1"), vec!["echo", "$HOME", "$", "$1"]);
+
+        // Values are substituted as they are, not split or interpreted
+        let mut vars = vars();
+        vars.set("Q", "it's \"$x\"").unwrap();
+        let expanded = vars.expand("echo $Q \"$Q\"").unwrap();
+        assert_eq!(
+            shell_words::split(&expanded).unwrap(),
+            vec!["echo", "it's \"$x\"", "it's \"$x\""]
+        );
+
+        assert!(vars.expand("echo ${HOST").is_err());
+        assert!(vars.expand("echo ${1x}").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut vars = vars();
+        assert!(vars.set("2X", "y").is_err());
+        assert!(vars.set("A-B", "y").is_err());
+        assert!(vars.remove("HOST"));
+        assert!(!vars.remove("HOST"));
+        assert_eq!(
+            vars.iter().collect::<Vec<_>>(),
+            vec![("APP", "/srv/my app")]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Repeated commands (`watch` in the REPL, `--watch` with `-e`)
+//!
+//! As with watch(1), the command runs again and again with a pause in
+//! between. Each time the screen is cleared and the latest output drawn under
+//! a header with the interval, the command, when it ran and how it ended.
+//! Output is collected rather than streamed, so every redraw replaces the
+//! previous one at once; from a server that offers it, stdout comes as a
+//! delta against that of the run before (see [`delta`](crate::delta)).
+//! Ctrl+C interrupts the command if it is running and ends the watch.
+
+use crate::{
+    client::{Client, CommandOptions},
+    output::status_name,
+    Result,
+};
+use chrono::{DateTime, Local};
+use shell_proto::{CommandResponse, CommandStatus};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Shortest interval accepted
+pub const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ANSI: clear the screen and move the cursor to the top left
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[1;1H";
+
+/// Parse an interval in seconds (fractions allowed), at least
+/// [`MIN_INTERVAL`]
+pub fn parse_interval(value: &str) -> Option<Duration> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= MIN_INTERVAL.as_secs_f64())
+        .map(Duration::from_secs_f64)
+}
+
+/// Run `argv` every `interval` until Ctrl+C, redrawing its output each time
+///
+/// Fails if a run fails to get a response, e.g. as the command is denied.
+pub async fn run(
+    client: &Client,
+    argv: &[String],
+    options: &CommandOptions,
+    interval: Duration,
+) -> Result<()> {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (interrupt_tx, mut interrupts) = mpsc::unbounded_channel();
+    let listener = {
+        let stopped = Arc::clone(&stopped);
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                stopped.store(true, Ordering::SeqCst);
+                if interrupt_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+
+    let result = watch(client, argv, options, interval, &stopped, &mut interrupts).await;
+    listener.abort();
+    println!();
+    result
+}
+
+/// The watch loop; `stopped` is set and `interrupts` receives on Ctrl+C
+async fn watch(
+    client: &Client,
+    argv: &[String],
+    options: &CommandOptions,
+    interval: Duration,
+    stopped: &AtomicBool,
+    interrupts: &mut mpsc::UnboundedReceiver<()>,
+) -> Result<()> {
+    let command = shell_words::join(argv);
+    let options = CommandOptions {
+        delta: true,
+        ..options.clone()
+    };
+    loop {
+        let started = Local::now();
+        let mut output = Vec::new();
+        let response = client
+            .execute_command_streaming(
+                argv[0].clone(),
+                argv[1..].to_vec(),
+                &options,
+                &mut |_stderr: bool, data: &[u8]| output.extend_from_slice(data),
+                Some(&mut *interrupts),
+            )
+            .await?;
+        output.extend_from_slice(&response.stdout);
+        output.extend_from_slice(&response.stderr);
+
+        let mut stdout = std::io::stdout();
+        let _ = write!(
+            stdout,
+            "{}{}\n\n{}",
+            CLEAR_SCREEN,
+            header(interval, &command, started, &response),
+            String::from_utf8_lossy(&output)
+        );
+        let _ = stdout.flush();
+
+        if stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = interrupts.recv() => return Ok(()),
+        }
+    }
+}
+
+/// The line above the output of a run
+fn header(
+    interval: Duration,
+    command: &str,
+    started: DateTime<Local>,
+    response: &CommandResponse,
+) -> String {
+    let ended = match response.status {
+        CommandStatus::Success | CommandStatus::Error => format!("exit {}", response.exit_code),
+        status => status_name(status).to_string(),
+    };
+    format!(
+        "Every {:.1}s: {}    {}    [{}]",
+        interval.as_secs_f64(),
+        command,
+        started.format("%Y-%m-%d %H:%M:%S"),
+        ended
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_interval("0.5"), Some(Duration::from_millis(500)));
+        for value in ["0", "0.01", "-1", "soon", "inf", "NaN"] {
+            assert_eq!(parse_interval(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut response = CommandResponse {
+            id: 1,
+            status: CommandStatus::Error,
+            stdout: vec![],
+            stderr: vec![],
+            exit_code: 3,
+            execution_time_ms: 5,
+            truncated: false,
+            total_bytes: 0,
+            spool_id: None,
+            delta_base: None,
+        };
+        let started = Local.with_ymd_and_hms(2026, 10, 15, 9, 30, 5).unwrap();
+        assert_eq!(
+            header(Duration::from_secs(2), "df -h", started, &response),
+            "Every 2.0s: df -h    2026-10-15 09:30:05    [exit 3]"
+        );
+
+        response.status = CommandStatus::Timeout;
+        assert!(header(Duration::from_secs(2), "df -h", started, &response).ends_with("[timeout]"));
+    }
+}
@@ -0,0 +1,10 @@
+//! uniffi-bindgen - Generates the Kotlin and Swift bindings
+//!
+//! ```text
+//! cargo run -p shell-mobile --features bindgen --bin uniffi-bindgen -- \
+//!     generate --library target/release/libshell_mobile.so --language kotlin --out-dir out
+//! ```
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
@@ -0,0 +1,78 @@
+//! Errors as the app sees them
+
+use reticulum_core::NetworkError;
+use shell_client::ClientError;
+
+/// Why a call failed; the message says more
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum SessionError {
+    /// The configuration or the identity is unusable
+    #[error("{0}")]
+    Config(String),
+
+    /// The server could not be reached, or the connection was lost
+    #[error("{0}")]
+    Connection(String),
+
+    /// The server refused the client, or its key changed
+    #[error("{0}")]
+    Rejected(String),
+
+    /// The server refused a request
+    #[error("{0}")]
+    Request(String),
+
+    /// The server did not answer in time
+    #[error("{0}")]
+    Timeout(String),
+
+    /// A local file could not be read or written
+    #[error("{0}")]
+    Io(String),
+}
+
+impl From<ClientError> for SessionError {
+    fn from(err: ClientError) -> Self {
+        let message = err.to_string();
+        match err {
+            ClientError::Config(_) | ClientError::Repl(_) => Self::Config(message),
+            ClientError::Network(_)
+            | ClientError::Protocol(_)
+            | ClientError::Connection(_)
+            | ClientError::NotConnected => Self::Connection(message),
+            ClientError::Rejected(_) | ClientError::HostKeyChanged(_) => Self::Rejected(message),
+            ClientError::Request(_) => Self::Request(message),
+            ClientError::Timeout(_) => Self::Timeout(message),
+            ClientError::Io(_) => Self::Io(message),
+        }
+    }
+}
+
+/// Errors of the identity handed over
+impl From<NetworkError> for SessionError {
+    fn from(err: NetworkError) -> Self {
+        Self::Config(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_client_error() {
+        let err = SessionError::from(ClientError::Timeout("no reply".to_string()));
+        assert!(matches!(err, SessionError::Timeout(_)));
+        assert_eq!(err.to_string(), "Timed out: no reply");
+
+        assert!(matches!(
+            SessionError::from(ClientError::NotConnected),
+            SessionError::Connection(_)
+        ));
+        assert!(matches!(
+            SessionError::from(ClientError::HostKeyChanged("x".to_string())),
+            SessionError::Rejected(_)
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+//! Identities handed over by the app
+
+use crate::SessionError;
+use reticulum_core::Identity;
+
+/// A new identity, as the bytes [`Session::connect`](crate::Session::connect)
+/// takes; the app keeps them
+#[uniffi::export]
+pub fn generate_identity() -> Vec<u8> {
+    Identity::generate().private_key()
+}
+
+/// The public key (hex) of `identity`: the entry for a server's
+/// `allowed_clients`
+#[uniffi::export]
+pub fn identity_public_key(
+    identity: Vec<u8>,
+    passphrase: Option<String>,
+) -> Result<String, SessionError> {
+    let identity = load(&identity, passphrase.as_deref())?;
+    Ok(hex::encode(identity.public_key()))
+}
+
+/// `identity` as [`generate_identity`] returns it, or encrypted with
+/// `passphrase` like an encrypted identity file
+pub(crate) fn load(identity: &[u8], passphrase: Option<&str>) -> Result<Identity, SessionError> {
+    Ok(match passphrase {
+        Some(passphrase) => Identity::from_encrypted_bytes(identity, passphrase)?,
+        None => Identity::from_bytes(identity)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let identity = generate_identity();
+        let public_key = identity_public_key(identity.clone(), None).unwrap();
+        let loaded = Identity::from_bytes(&identity).unwrap();
+        assert_eq!(public_key, hex::encode(loaded.public_key()));
+
+        let encrypted = loaded.to_encrypted_bytes("secret", 1000);
+        assert_eq!(
+            identity_public_key(encrypted.clone(), Some("secret".to_string())).unwrap(),
+            public_key
+        );
+        assert!(matches!(
+            identity_public_key(encrypted, Some("wrong".to_string())),
+            Err(SessionError::Config(_))
+        ));
+        assert!(identity_public_key(vec![1, 2, 3], None).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! shell-mobile - Kotlin and Swift bindings
+//!
+//! Exposes the embeddable [`ShellSession`](shell_client::session::ShellSession)
+//! API to mobile apps through uniffi: connect, run commands and read their
+//! output as it arrives, and copy files. Built with the `embedded-router`
+//! feature (the default), a configuration with `enable_i2p = true` and
+//! `router_mode = "embedded"` runs the I2P router inside the app, so nothing
+//! else needs installing on the device.
+//!
+//! Nothing asks for anything: the app keeps its identity itself (in the
+//! Keychain or the Android Keystore, say) and hands it over as bytes, and
+//! passes the configuration as TOML text, its paths (known hosts, the
+//! router's data) inside the app's storage.
+//!
+//! The library builds as `libshell_mobile.so` (Android) and
+//! `libshell_mobile.a` (iOS); `uniffi-bindgen`, built with the `bindgen`
+//! feature, generates the Kotlin and Swift sources from it:
+//!
+//! ```text
+//! cargo build -p shell-mobile --release
+//! cargo run -p shell-mobile --features bindgen --bin uniffi-bindgen -- \
+//!     generate --library target/release/libshell_mobile.so --language kotlin --out-dir out
+//! ```
+//!
+//! ```kotlin
+//! val session = Session.connect(config, identity, null, false)
+//! val execution = session.exec(listOf("uname", "-a"), ExecOptions(null, null, mapOf(), false))
+//! while (true) {
+//!     when (val event = execution.next() ?: break) {
+//!         is OutputEvent.Stdout -> print(event.data.decodeToString())
+//!         is OutputEvent.Stderr -> System.err.print(event.data.decodeToString())
+//!         is OutputEvent.Exit -> println("exit ${event.result.exitCode}")
+//!     }
+//! }
+//! session.disconnect()
+//! ```
+
+pub mod error;
+pub mod identity;
+pub mod session;
+
+pub use error::SessionError;
+pub use identity::{generate_identity, identity_public_key};
+pub use session::{CommandResult, ExecOptions, Execution, ExitStatus, OutputEvent, Session};
+
+uniffi::setup_scaffolding!();
@@ -0,0 +1,298 @@
+//! Sessions and the commands they run
+
+use crate::{identity, SessionError};
+use shell_client::client::{Client, CommandOptions};
+use shell_client::config::ClientConfig;
+use shell_client::session::{self, Interrupter, ShellSession};
+use shell_proto::{CommandResponse, CommandStatus};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Settings for a single command, overriding the configured ones
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct ExecOptions {
+    /// Timeout in seconds (default: `command_timeout`)
+    pub timeout_secs: Option<u64>,
+
+    /// Working directory (relative ones are taken from the session's)
+    pub working_dir: Option<String>,
+
+    /// Environment variables, over the configured ones
+    pub env: HashMap<String, String>,
+
+    /// The command is safe to run twice, so it is sent again after a
+    /// transport failure as long as nothing of it arrived yet
+    pub idempotent: bool,
+}
+
+impl From<ExecOptions> for CommandOptions {
+    fn from(options: ExecOptions) -> Self {
+        Self {
+            timeout: options.timeout_secs,
+            working_dir: options.working_dir,
+            env: options.env.into_iter().collect(),
+            idempotent: options.idempotent,
+            delta: false,
+        }
+    }
+}
+
+/// How a command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ExitStatus {
+    /// It exited by itself (with any exit code)
+    Success,
+
+    /// It ran out its timeout
+    Timeout,
+
+    /// It could not be run
+    Error,
+
+    /// It was killed
+    Killed,
+
+    /// It was killed for exceeding its memory limit
+    OomKilled,
+}
+
+impl From<CommandStatus> for ExitStatus {
+    fn from(status: CommandStatus) -> Self {
+        match status {
+            CommandStatus::Success => Self::Success,
+            CommandStatus::Timeout => Self::Timeout,
+            CommandStatus::Error => Self::Error,
+            CommandStatus::Killed => Self::Killed,
+            CommandStatus::OomKilled => Self::OomKilled,
+        }
+    }
+}
+
+/// The end of a command
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct CommandResult {
+    /// How it ended
+    pub status: ExitStatus,
+
+    /// Process exit code
+    pub exit_code: i32,
+
+    /// Standard output not delivered as [`OutputEvent::Stdout`]
+    pub stdout: Vec<u8>,
+
+    /// Standard error not delivered as [`OutputEvent::Stderr`]
+    pub stderr: Vec<u8>,
+
+    /// Execution time in milliseconds
+    pub execution_time_ms: u64,
+
+    /// Output exceeded the server's cap and was cut
+    pub truncated: bool,
+}
+
+impl From<CommandResponse> for CommandResult {
+    fn from(response: CommandResponse) -> Self {
+        Self {
+            status: response.status.into(),
+            exit_code: response.exit_code,
+            stdout: response.stdout,
+            stderr: response.stderr,
+            execution_time_ms: response.execution_time_ms,
+            truncated: response.truncated,
+        }
+    }
+}
+
+/// Something a running command did
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum OutputEvent {
+    /// Output on stdout
+    Stdout { data: Vec<u8> },
+
+    /// Output on stderr
+    Stderr { data: Vec<u8> },
+
+    /// The command ended; its output came before as `Stdout` and `Stderr`
+    Exit { result: CommandResult },
+}
+
+impl From<session::OutputEvent> for OutputEvent {
+    fn from(event: session::OutputEvent) -> Self {
+        match event {
+            session::OutputEvent::Stdout(data) => Self::Stdout { data },
+            session::OutputEvent::Stderr(data) => Self::Stderr { data },
+            session::OutputEvent::Exit(response) => Self::Exit {
+                result: response.into(),
+            },
+        }
+    }
+}
+
+/// A connection to a server
+///
+/// A connection carries one request at a time, so the calls of a session
+/// wait for each other.
+#[derive(uniffi::Object)]
+pub struct Session {
+    session: ShellSession,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl Session {
+    /// Connect to the server of `config`, the TOML of a client
+    /// configuration, as `identity` (see
+    /// [`generate_identity`](crate::generate_identity)), decrypted with
+    /// `passphrase` if it is encrypted
+    ///
+    /// Fails if the server's key is not in the known hosts (unless
+    /// `accept_new_host_keys` is set), or if it has a banner to acknowledge
+    /// and `accept_banner` is not set.
+    #[uniffi::constructor]
+    pub async fn connect(
+        config: String,
+        identity: Vec<u8>,
+        passphrase: Option<String>,
+        accept_banner: bool,
+    ) -> Result<Arc<Self>, SessionError> {
+        let mut config: ClientConfig = toml::from_str(&config)
+            .map_err(|e| SessionError::Config(format!("Failed to parse config: {}", e)))?;
+        config.identity = Arc::new(identity::load(&identity, passphrase.as_deref())?);
+        let session = ShellSession::connect(config, accept_banner).await?;
+        Ok(Arc::new(Self { session }))
+    }
+
+    /// Start running `argv` (the command and its arguments); its output is
+    /// read from the returned [`Execution`]
+    pub async fn exec(&self, argv: Vec<String>, options: ExecOptions) -> Arc<Execution> {
+        let execution = self.session.exec(argv, options.into());
+        Arc::new(Execution {
+            interrupter: execution.interrupter(),
+            execution: Mutex::new(Some(execution)),
+        })
+    }
+
+    /// Upload the local file `local` to `remote`, continuing an earlier
+    /// upload if `resume` is set; returns the bytes sent
+    pub async fn upload(
+        &self,
+        local: String,
+        remote: String,
+        resume: bool,
+    ) -> Result<u64, SessionError> {
+        Ok(self
+            .session
+            .upload(Path::new(&local), &remote, resume)
+            .await?)
+    }
+
+    /// Download the remote file `remote` to `local`, continuing an earlier
+    /// download if `resume` is set; returns the bytes received
+    pub async fn download(
+        &self,
+        remote: String,
+        local: String,
+        resume: bool,
+    ) -> Result<u64, SessionError> {
+        Ok(self
+            .session
+            .download(&remote, Path::new(&local), resume)
+            .await?)
+    }
+
+    /// Close the connection
+    pub async fn disconnect(&self) -> Result<(), SessionError> {
+        Ok(self.session.disconnect().await?)
+    }
+}
+
+impl Session {
+    /// Use a client that is already connected
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            session: ShellSession::from_client(client),
+        }
+    }
+}
+
+/// A command started by [`Session::exec`]
+#[derive(uniffi::Object)]
+pub struct Execution {
+    /// Taken by `wait`
+    execution: Mutex<Option<session::Execution>>,
+
+    /// Usable while `next` waits
+    interrupter: Interrupter,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl Execution {
+    /// The next event, `None` once the command ended
+    ///
+    /// An error (e.g. as the command was denied or the connection lost) is
+    /// the last one.
+    pub async fn next(&self) -> Result<Option<OutputEvent>, SessionError> {
+        let mut execution = self.execution.lock().await;
+        let Some(execution) = execution.as_mut() else {
+            return Ok(None);
+        };
+        Ok(execution.next().await.transpose()?.map(OutputEvent::from))
+    }
+
+    /// Send the command SIGINT; a second call kills it
+    pub fn interrupt(&self) {
+        self.interrupter.interrupt();
+    }
+
+    /// Wait for the command to end, collecting the output not read with
+    /// [`next`](Self::next) into the result
+    pub async fn wait(&self) -> Result<CommandResult, SessionError> {
+        let execution = self.execution.lock().await.take().ok_or_else(|| {
+            SessionError::Request("The command was waited for already".to_string())
+        })?;
+        Ok(execution.wait().await?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_exec_not_connected() {
+        let client = Client::new(ClientConfig::default()).await.unwrap();
+        let session = Session::from_client(client);
+
+        let execution = session
+            .exec(vec!["true".to_string()], ExecOptions::default())
+            .await;
+        assert!(matches!(
+            execution.next().await,
+            Err(SessionError::Connection(_))
+        ));
+        assert!(execution.next().await.unwrap().is_none());
+
+        let execution = session.exec(vec![], ExecOptions::default()).await;
+        assert!(matches!(
+            execution.wait().await,
+            Err(SessionError::Request(_))
+        ));
+        assert!(execution.wait().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_bad_config() {
+        let identity = crate::generate_identity();
+        assert!(matches!(
+            Session::connect(
+                "connection_timeout = \"x\"".to_string(),
+                identity,
+                None,
+                false
+            )
+            .await,
+            Err(SessionError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//! Binary deltas between two outputs of the same command
+//!
+//! A command polled again and again (`watch`) mostly prints what it printed
+//! the time before. The server keeps the last stdout of such a command and
+//! sends what changed instead: a list of ranges to copy from the old output
+//! and bytes to insert between them. Output is matched a line at a time,
+//! each match extended as far as the two agree, so changed lines cost their
+//! length and unchanged ones almost nothing.
+//!
+//! A delta is the bincode encoding of a list of [`DeltaOp`]s.
+
+use crate::protocol::{deserialize, serialize, MAX_MESSAGE_SIZE};
+use crate::{ProtocolError, Result};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Shortest match worth a copy; an insert of fewer bytes is smaller
+const MIN_COPY: usize = 24;
+
+/// One step in building the new output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// Bytes of the old output
+    Copy { offset: u64, length: u64 },
+
+    /// New bytes
+    Insert(Vec<u8>),
+}
+
+/// The delta that turns `base` into `target`
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    // Where each line of the base first appears
+    let mut lines = BTreeMap::new();
+    for (start, line) in split_lines(base) {
+        lines.entry(line).or_insert(start);
+    }
+
+    let mut ops: Vec<DeltaOp> = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        let line = split_lines(&target[pos..])
+            .next()
+            .map_or(&[][..], |(_, line)| line);
+
+        // Go on where the last copy ended if the line is there, else take
+        // the first place it appears
+        let next = match ops.last() {
+            Some(DeltaOp::Copy { offset, length }) => Some((offset + length) as usize),
+            _ => None,
+        };
+        let source = next
+            .filter(|&next| base[next..].starts_with(line))
+            .or_else(|| lines.get(line).copied());
+        let length = source.map_or(0, |source| common_prefix(&base[source..], &target[pos..]));
+
+        match source {
+            Some(source) if length >= MIN_COPY || next == Some(source) => {
+                push_copy(&mut ops, source, length);
+                pos += length;
+            }
+            _ => {
+                match ops.last_mut() {
+                    Some(DeltaOp::Insert(bytes)) => bytes.extend_from_slice(line),
+                    _ => ops.push(DeltaOp::Insert(line.to_vec())),
+                }
+                pos += line.len();
+            }
+        }
+    }
+
+    serialize(&ops).expect("serializing plain data cannot fail")
+}
+
+/// Apply `delta` to `base`
+///
+/// Fails on a delta that is malformed, copies from outside `base` or builds
+/// an output larger than a message.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let ops: Vec<DeltaOp> = deserialize(delta)?;
+    let mut output = Vec::new();
+    for op in ops {
+        let bytes = match &op {
+            DeltaOp::Copy { offset, length } => usize::try_from(*offset)
+                .ok()
+                .zip(usize::try_from(*length).ok())
+                .and_then(|(offset, length)| base.get(offset..offset.checked_add(length)?))
+                .ok_or_else(|| {
+                    ProtocolError::InvalidFormat("Delta copies from outside its base".into())
+                })?,
+            DeltaOp::Insert(bytes) => bytes,
+        };
+        if output.len() + bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(ProtocolError::MessageTooLarge {
+                size: output.len() + bytes.len(),
+                max: MAX_MESSAGE_SIZE,
+            });
+        }
+        output.extend_from_slice(bytes);
+    }
+    Ok(output)
+}
+
+/// The lines of `bytes` with their offsets, each with its newline
+fn split_lines(bytes: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut start = 0;
+    bytes.split_inclusive(|&b| b == b'\n').map(move |line| {
+        let offset = start;
+        start += line.len();
+        (offset, line)
+    })
+}
+
+/// Number of bytes `a` and `b` start with in common
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Add a copy, extending the last one if it ends where this starts
+fn push_copy(ops: &mut Vec<DeltaOp>, offset: usize, length: usize) {
+    if let Some(DeltaOp::Copy {
+        offset: last,
+        length: last_length,
+    }) = ops.last_mut()
+    {
+        if *last + *last_length == offset as u64 {
+            *last_length += length as u64;
+            return;
+        }
+    }
+    ops.push(DeltaOp::Copy {
+        offset: offset as u64,
+        length: length as u64,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ls -l` of files modified at `minutes` past ten
+    fn listing(minutes: impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut output = Vec::new();
+        for (i, minute) in minutes.enumerate() {
+            let line = format!(
+                "-rw-r--r-- 1 user user 4096 Oct 15 10:{:02} file{}.log\n",
+                minute, i
+            );
+            output.extend_from_slice(line.as_bytes());
+        }
+        output
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // One line changed, one added
+        let base = listing(0..20);
+        let target = listing((0..21).map(|i| if i == 7 { 59 } else { i }));
+        let delta = diff(&base, &target);
+        assert_eq!(apply(&base, &delta).unwrap(), target);
+        assert!(delta.len() < target.len() / 4);
+
+        let cases: [(&[u8], &[u8]); 5] = [
+            (b"", b"new\n"),
+            (b"old\n", b""),
+            (b"no newline", b"no newline at all"),
+            (b"a\nb\nc\n", b"c\nb\na\n"),
+            (b"\xff\x00binary\x00", b"\x00binary\x00\xff"),
+        ];
+        for (base, target) in cases {
+            assert_eq!(apply(base, &diff(base, target)).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let output = listing(0..3);
+        let delta = diff(&output, &output);
+        let ops: Vec<DeltaOp> = deserialize(&delta).unwrap();
+        assert_eq!(
+            ops,
+            [DeltaOp::Copy {
+                offset: 0,
+                length: output.len() as u64
+            }]
+        );
+    }
+
+    #[test]
+    fn test_apply_malformed() {
+        let copy = |offset, length| serialize(&vec![DeltaOp::Copy { offset, length }]).unwrap();
+        assert!(apply(b"base", &copy(0, 4)).is_ok());
+        assert!(apply(b"base", &copy(1, 4)).is_err());
+        assert!(apply(b"base", &copy(u64::MAX, 2)).is_err());
+        assert!(apply(b"base", &[0xff; 3]).is_err());
+
+        // Copies that add up to more than a message
+        let base = vec![0; MAX_MESSAGE_SIZE / 16];
+        let ops = vec![
+            DeltaOp::Copy {
+                offset: 0,
+                length: base.len() as u64
+            };
+            17
+        ];
+        assert!(matches!(
+            apply(&base, &serialize(&ops).unwrap()),
+            Err(ProtocolError::MessageTooLarge { .. })
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 //! Protocol error types
 
+use alloc::string::{String, ToString};
 use thiserror::Error;
 
 /// Protocol-related errors
@@ -25,16 +26,39 @@ pub enum ProtocolError {
     #[error("Invalid message format: {0}")]
     InvalidFormat(String),
 
+    /// A frame too short to hold a message type
+    #[error("Empty frame")]
+    EmptyFrame,
+
+    /// A frame's message type is not that of the message it carries
+    #[error("Frame of message type {frame:#04x} carries a message of type {message:#04x}")]
+    MessageTypeMismatch { frame: u8, message: u8 },
+
+    /// Bytes follow the message in its frame
+    #[error("{0} bytes after the end of the message")]
+    TrailingBytes(usize),
+
+    /// Handshake or encryption failure
+    #[error("Encryption error: {0}")]
+    Crypto(String),
+
     /// I/O error
+    #[cfg(feature = "std")]
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
 
-impl From<bincode::Error> for ProtocolError {
-    fn from(err: bincode::Error) -> Self {
+impl From<bincode::error::EncodeError> for ProtocolError {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        ProtocolError::Serialization(err.to_string())
+    }
+}
+
+impl From<bincode::error::DecodeError> for ProtocolError {
+    fn from(err: bincode::error::DecodeError) -> Self {
         ProtocolError::Serialization(err.to_string())
     }
 }
 
 /// Result type for protocol operations
-pub type Result<T> = std::result::Result<T, ProtocolError>;
+pub type Result<T> = core::result::Result<T, ProtocolError>;
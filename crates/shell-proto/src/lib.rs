@@ -2,13 +2,35 @@
 //!
 //! This crate defines the wire protocol for reticulum-shell, including all message
 //! types, serialization, and protocol versioning.
+//!
+//! Without the default `std` feature the crate is `no_std` and needs only
+//! `alloc`, so devices with no operating system (an RTOS speaking over
+//! serial or LoRa) can implement the protocol with the same types. Session
+//! encryption (`noise`) and `ProtocolError::Io` need `std`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+pub mod delta;
 pub mod error;
 pub mod messages;
+#[cfg(feature = "std")]
+pub mod noise;
 pub mod protocol;
 
 pub use error::{ProtocolError, Result};
 pub use messages::{
-    CommandRequest, CommandResponse, CommandStatus, ConnectMessage, Message, SessionId,
+    AnnounceInfo, CancelRequest, ChannelClose, ChannelData, ChannelKind, ChannelOpenRequest,
+    ChunkAck, ChunkRequest, CommandInput, CommandOutput, CommandRequest, CommandResponse,
+    CommandStatus, CompleteRequest, CompleteResponse, CompletionKind, ConnectMessage,
+    DownloadRequest, EchoMessage, ErrorMessage, ExecUploadRequest, FetchOutputRequest, FileChunk,
+    FileEntry, FileKind, FileOp, FileOpRequest, FileOpResult, HandshakeMessage, HistoryEntry,
+    JobInfo, JobState, Message, OutputChunk, PendingNotice, PtyClose, PtyData, PtyOpenRequest,
+    PtyResize, RemoteForwardRequest, SealedMessage, SessionId, SetEnvRequest, StatsRequest,
+    StatsResponse, TransferComplete, TransferReady, UnsetEnvRequest, UploadRequest,
+};
+pub use protocol::{
+    packet_signing_payload, Priority, ProtocolCodec, ProtocolVersion, ReplayWindow,
+    CURRENT_PROTOCOL_VERSION, MAX_CHUNK_SIZE, REPLAY_WINDOW,
 };
-pub use protocol::{ProtocolCodec, ProtocolVersion, CURRENT_PROTOCOL_VERSION};
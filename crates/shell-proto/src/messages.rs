@@ -1,7 +1,11 @@
 //! Protocol message definitions
 
+use crate::protocol::Priority;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
 /// Unique session identifier
 pub type SessionId = [u8; 16];
@@ -33,11 +37,157 @@ pub enum Message {
     /// Acknowledgment message
     Ack(AckMessage),
 
+    /// Request-level failure
+    Error(ErrorMessage),
+
     /// Keep-alive ping
     Ping,
 
     /// Keep-alive pong
     Pong,
+
+    /// Client starts (or resumes) an upload
+    UploadStart(UploadRequest),
+
+    /// Client starts (or resumes) a download
+    DownloadStart(DownloadRequest),
+
+    /// Server is ready for a transfer
+    TransferReady(TransferReady),
+
+    /// File data (client → server for uploads, server → client for downloads)
+    FileChunk(FileChunk),
+
+    /// Client requests the next download chunk
+    ChunkRequest(ChunkRequest),
+
+    /// Server acknowledges an upload chunk
+    ChunkAck(ChunkAck),
+
+    /// Server confirms a verified, completed upload
+    TransferComplete(TransferComplete),
+
+    /// Client requests a filesystem operation
+    FileOp(FileOpRequest),
+
+    /// Server returns the result of a filesystem operation
+    FileOpResult(FileOpResult),
+
+    /// Client requests an interactive PTY
+    PtyOpen(PtyOpenRequest),
+
+    /// Terminal data (either direction)
+    PtyData(PtyData),
+
+    /// Client terminal was resized
+    PtyResize(PtyResize),
+
+    /// PTY closed (either direction)
+    PtyClose(PtyClose),
+
+    /// Open a forwarding channel (client → server for local forwards,
+    /// server → client for connections arriving on a remote forward)
+    ChannelOpen(ChannelOpenRequest),
+
+    /// Channel payload (either direction)
+    ChannelData(ChannelData),
+
+    /// Channel closed (either direction)
+    ChannelClose(ChannelClose),
+
+    /// Client asks the server to listen on a port and tunnel connections back
+    RemoteForward(RemoteForwardRequest),
+
+    /// Client stops a remote forward
+    CancelRemoteForward(RemoteForwardRequest),
+
+    /// Client starts a detached background job
+    JobStart(CommandRequest),
+
+    /// Client lists its background jobs
+    JobList(JobListRequest),
+
+    /// Client fetches buffered job output
+    JobOutputRequest(JobOutputRequest),
+
+    /// Client kills a background job
+    JobKill(JobKillRequest),
+
+    /// Server reports the state of a single job
+    JobStatus(JobStatusMessage),
+
+    /// Server lists background jobs
+    JobListResponse(JobListResponse),
+
+    /// Server returns buffered job output
+    JobOutput(JobOutput),
+
+    /// Client asks for the session's command history
+    HistoryRequest(HistoryRequest),
+
+    /// Server returns the session's command history
+    HistoryResponse(HistoryResponse),
+
+    /// Client sets session environment variables (acknowledged with ACK)
+    SetEnv(SetEnvRequest),
+
+    /// Client removes session environment variables (acknowledged with ACK)
+    UnsetEnv(UnsetEnvRequest),
+
+    /// Client acknowledges the banner sent in ACCEPT (acknowledged with ACK)
+    BannerAck,
+
+    /// Client asks for its usage counters
+    StatsRequest(StatsRequest),
+
+    /// Server returns the client's usage counters
+    StatsResponse(StatsResponse),
+
+    /// Client uploads a program to run once it is verified (answered with
+    /// TRANSFER_READY; the final chunk is answered with COMMAND_RESPONSE)
+    ExecUpload(ExecUploadRequest),
+
+    /// Client reads a range of spooled command output
+    FetchOutput(FetchOutputRequest),
+
+    /// Server returns a range of spooled command output
+    OutputChunk(OutputChunk),
+
+    /// Server reports that a request is waiting for operator approval (the
+    /// answer to the request follows once it is decided)
+    Pending(PendingNotice),
+
+    /// Client asks for completions of a partial path or command name
+    CompleteRequest(CompleteRequest),
+
+    /// Server returns completion candidates
+    CompleteResponse(CompleteResponse),
+
+    /// Server streams output of a running command (clients that announced
+    /// the `output-stream` capability)
+    CommandOutput(CommandOutput),
+
+    /// Client asks to stop a running command (not answered; the command's
+    /// response reports how it ended)
+    CancelRequest(CancelRequest),
+
+    /// Client sends stdin to a running command (not answered)
+    CommandInput(CommandInput),
+
+    /// Client sends a payload to be sent back, to measure the link (servers
+    /// with the `echo` capability)
+    Echo(EchoMessage),
+
+    /// Server sends an echo payload back
+    EchoReply(EchoMessage),
+
+    /// Client completes the Noise handshake begun in CONNECT and ACCEPT
+    /// (answered with a sealed ACK)
+    Handshake(HandshakeMessage),
+
+    /// Any other message, encrypted with the session keys once the
+    /// handshake completed
+    Sealed(SealedMessage),
 }
 
 /// Connection request from client
@@ -54,6 +204,53 @@ pub struct ConnectMessage {
 
     /// Optional authentication token
     pub auth_token: Option<String>,
+
+    /// Unix time (seconds) the message was created
+    pub timestamp: u64,
+
+    /// Random value; servers refuse a nonce they have already seen
+    pub nonce: [u8; 16],
+
+    /// Ed25519 signature over [`ConnectMessage::signing_payload`] by
+    /// `client_identity` (empty = unsigned)
+    pub signature: Vec<u8>,
+
+    /// Token from a previous ACCEPT, to reattach to that session after a
+    /// server restart
+    #[serde(default)]
+    pub resume_token: Option<Vec<u8>>,
+
+    /// First message of the Noise handshake (None = the client does not
+    /// encrypt the session); see [`noise`](crate::noise)
+    #[serde(default)]
+    pub noise: Option<Vec<u8>>,
+
+    /// Destination hash the client addressed, so the signature is no good
+    /// at any other server
+    #[serde(default)]
+    pub server_destination: [u8; 32],
+}
+
+impl ConnectMessage {
+    /// Domain separator for connect signatures
+    pub const SIGNATURE_CONTEXT: &'static str = "reticulum-shell-connect";
+
+    /// Bytes covered by `signature`: every other field but `noise`, which
+    /// the handshake binds to these bytes itself
+    pub fn signing_payload(&self) -> Vec<u8> {
+        crate::protocol::serialize(&(
+            Self::SIGNATURE_CONTEXT,
+            self.protocol_version,
+            &self.client_identity,
+            &self.capabilities,
+            &self.auth_token,
+            self.timestamp,
+            self.nonce,
+            &self.resume_token,
+            self.server_destination,
+        ))
+        .expect("serializing plain data cannot fail")
+    }
 }
 
 /// Server accepts connection
@@ -70,6 +267,31 @@ pub struct AcceptMessage {
 
     /// Server capabilities
     pub capabilities: Vec<String>,
+
+    /// Token for resuming this session after a server restart (None = the
+    /// server does not persist sessions)
+    #[serde(default)]
+    pub resume_token: Option<Vec<u8>>,
+
+    /// Notice to show the user before anything else (legal notice, usage
+    /// policy, ...)
+    #[serde(default)]
+    pub banner: Option<String>,
+
+    /// Requests other than PING and DISCONNECT are refused until the client
+    /// sends BANNER_ACK
+    #[serde(default)]
+    pub banner_ack_required: bool,
+
+    /// How often the server wants an idle client to ping it (seconds, None
+    /// = up to the client)
+    #[serde(default)]
+    pub keepalive_interval: Option<u64>,
+
+    /// Second message of the Noise handshake, answering the client's (None =
+    /// the session is not encrypted)
+    #[serde(default)]
+    pub noise: Option<Vec<u8>>,
 }
 
 /// Server rejects connection
@@ -95,13 +317,27 @@ pub struct CommandRequest {
     pub args: Vec<String>,
 
     /// Optional environment variables
-    pub env: Option<HashMap<String, String>>,
+    pub env: Option<BTreeMap<String, String>>,
 
     /// Optional execution timeout (seconds)
     pub timeout: Option<u64>,
 
     /// Optional working directory
     pub working_dir: Option<String>,
+
+    /// Names of server-side secrets to set as environment variables
+    pub secrets: Vec<String>,
+
+    /// The command's stdin comes in COMMAND_INPUT messages (otherwise it is
+    /// closed)
+    #[serde(default)]
+    pub stdin: bool,
+
+    /// SHA-256 of the stdout the client holds from the last run of the same
+    /// command (that of no output if none): stdout may come back as a delta
+    /// against it, and is not streamed (None = send it in full)
+    #[serde(default)]
+    pub delta_base: Option<[u8; 32]>,
 }
 
 /// Command execution response
@@ -124,6 +360,22 @@ pub struct CommandResponse {
 
     /// Execution time in milliseconds
     pub execution_time_ms: u64,
+
+    /// Output exceeded the server's cap and `stdout`/`stderr` were cut
+    pub truncated: bool,
+
+    /// Bytes the command wrote to stdout and stderr, including any that were
+    /// cut
+    pub total_bytes: u64,
+
+    /// Spool holding the complete output of a truncated response, read with
+    /// FETCH_OUTPUT (None = not spooled)
+    pub spool_id: Option<u64>,
+
+    /// `stdout` is a [delta](crate::delta) against the output with this
+    /// SHA-256, the request's `delta_base` (None = the output itself)
+    #[serde(default)]
+    pub delta_base: Option<[u8; 32]>,
 }
 
 /// Command execution status
@@ -140,6 +392,9 @@ pub enum CommandStatus {
 
     /// Command was killed
     Killed,
+
+    /// Command was killed for exceeding its memory limit
+    OomKilled,
 }
 
 /// Disconnect message
@@ -156,6 +411,779 @@ pub struct AckMessage {
     pub message_id: u64,
 }
 
+/// Request-level error
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    /// ID of the request that failed
+    pub request_id: u64,
+
+    /// Error code
+    pub code: u32,
+
+    /// Human-readable description
+    pub message: String,
+}
+
+impl ErrorMessage {
+    /// Request was malformed or referenced something that doesn't exist
+    pub const INVALID_REQUEST: u32 = 1;
+
+    /// Request was refused by server policy
+    pub const DENIED: u32 = 2;
+
+    /// Server-side failure while handling the request
+    pub const INTERNAL: u32 = 3;
+
+    /// Server is temporarily out of capacity
+    pub const OVERLOADED: u32 = 4;
+
+    /// Create a new error message
+    pub fn new(request_id: u64, code: u32, message: impl Into<String>) -> Self {
+        Self {
+            request_id,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Upload request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Destination path on the server
+    pub path: String,
+
+    /// Total file size in bytes
+    pub size: u64,
+
+    /// SHA-256 of the complete file
+    pub sha256: Vec<u8>,
+
+    /// Optional Unix permission bits for the created file
+    pub mode: Option<u32>,
+
+    /// Continue a previously interrupted upload
+    pub resume: bool,
+}
+
+/// Download request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Source path on the server
+    pub path: String,
+}
+
+/// Transfer accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferReady {
+    /// Request ID this message answers
+    pub request_id: u64,
+
+    /// Server-assigned transfer ID
+    pub transfer_id: u64,
+
+    /// Offset to continue from (non-zero when resuming an upload)
+    pub offset: u64,
+
+    /// Total file size in bytes
+    pub size: u64,
+
+    /// SHA-256 of the complete file (downloads only)
+    pub sha256: Vec<u8>,
+}
+
+/// A chunk of file data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChunk {
+    /// Transfer this chunk belongs to
+    pub transfer_id: u64,
+
+    /// Offset of the first byte
+    pub offset: u64,
+
+    /// File data
+    pub data: Vec<u8>,
+}
+
+/// Request for a download chunk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    /// Transfer to read from
+    pub transfer_id: u64,
+
+    /// Offset to read from
+    pub offset: u64,
+
+    /// Maximum bytes to return
+    pub max_bytes: u32,
+}
+
+/// Upload chunk acknowledgment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkAck {
+    /// Transfer being acknowledged
+    pub transfer_id: u64,
+
+    /// Next offset the server expects
+    pub offset: u64,
+}
+
+/// Upload completed and verified
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferComplete {
+    /// Completed transfer
+    pub transfer_id: u64,
+
+    /// Final file size
+    pub size: u64,
+
+    /// SHA-256 of the stored file
+    pub sha256: Vec<u8>,
+}
+
+/// Filesystem operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileOp {
+    /// Describe a single path
+    Stat { path: String },
+
+    /// List a directory
+    List { path: String },
+
+    /// Create a directory
+    Mkdir { path: String, recursive: bool },
+
+    /// Remove a file or directory
+    Remove { path: String, recursive: bool },
+
+    /// Rename or move a path
+    Rename { from: String, to: String },
+
+    /// Change permission bits
+    Chmod { path: String, mode: u32 },
+}
+
+/// Filesystem operation request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOpRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Operation to perform
+    pub op: FileOp,
+}
+
+/// Kind of filesystem entry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FileKind {
+    /// Regular file
+    File,
+
+    /// Directory
+    Directory,
+
+    /// Symbolic link
+    Symlink,
+
+    /// Anything else (device, socket, fifo, ...)
+    Other,
+}
+
+/// A filesystem entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Entry name (full path for Stat)
+    pub name: String,
+
+    /// Entry kind
+    pub kind: FileKind,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// Unix permission bits (0 on other platforms)
+    pub mode: u32,
+
+    /// Modification time (Unix seconds)
+    pub modified: u64,
+}
+
+/// Filesystem operation result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOpResult {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Entries (one for Stat, directory contents for List, empty otherwise)
+    pub entries: Vec<FileEntry>,
+}
+
+/// Interactive PTY request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyOpenRequest {
+    /// Unique PTY ID (chosen by the client)
+    pub id: u64,
+
+    /// Program to run (None = the server user's login shell)
+    pub command: Option<String>,
+
+    /// Program arguments
+    pub args: Vec<String>,
+
+    /// Terminal type (value for TERM)
+    pub term: String,
+
+    /// Initial terminal width
+    pub cols: u16,
+
+    /// Initial terminal height
+    pub rows: u16,
+
+    /// Optional environment variables
+    pub env: Option<BTreeMap<String, String>>,
+
+    /// Optional working directory
+    pub working_dir: Option<String>,
+}
+
+/// Raw terminal data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyData {
+    /// PTY this data belongs to
+    pub id: u64,
+
+    /// Raw bytes (keystrokes from the client, output from the server)
+    pub data: Vec<u8>,
+}
+
+/// Terminal resize notification
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyResize {
+    /// PTY to resize
+    pub id: u64,
+
+    /// New terminal width
+    pub cols: u16,
+
+    /// New terminal height
+    pub rows: u16,
+}
+
+/// PTY closed notification
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyClose {
+    /// PTY that was closed
+    pub id: u64,
+
+    /// Exit code of the PTY process (if it exited)
+    pub exit_code: Option<i32>,
+
+    /// Optional reason (e.g. spawn failure)
+    pub reason: Option<String>,
+}
+
+/// What a forwarding channel connects to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChannelKind {
+    /// Server connects to `host:port` (local forward)
+    Direct { host: String, port: u16 },
+
+    /// Connection accepted on a remote-forward listener
+    Forwarded { bind_port: u16, originator: String },
+
+    /// Channel payload is a SOCKS5 session relayed by the server (dynamic forward)
+    Dynamic,
+}
+
+/// Open a forwarding channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelOpenRequest {
+    /// Channel ID (chosen by the opening side)
+    pub channel_id: u64,
+
+    /// Channel target
+    pub kind: ChannelKind,
+}
+
+/// Channel payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelData {
+    /// Channel this data belongs to
+    pub channel_id: u64,
+
+    /// Raw bytes
+    pub data: Vec<u8>,
+}
+
+/// Channel closed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelClose {
+    /// Channel that was closed
+    pub channel_id: u64,
+
+    /// Optional reason (e.g. connection refused)
+    pub reason: Option<String>,
+}
+
+/// Remote forward request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteForwardRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Port to listen on
+    pub bind_port: u16,
+}
+
+/// Background job state
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JobState {
+    /// Job is still running
+    Running,
+
+    /// Job exited on its own
+    Exited,
+
+    /// Job was killed (by request or timeout)
+    Killed,
+
+    /// Job could not be waited on
+    Failed,
+}
+
+/// Background job summary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    /// Server-assigned job ID
+    pub job_id: u64,
+
+    /// Command being run
+    pub command: String,
+
+    /// Command arguments
+    pub args: Vec<String>,
+
+    /// Current state
+    pub state: JobState,
+
+    /// Exit code (once finished)
+    pub exit_code: Option<i32>,
+
+    /// Start time (Unix seconds)
+    pub started_at: u64,
+
+    /// Total stdout bytes produced so far
+    pub stdout_bytes: u64,
+
+    /// Total stderr bytes produced so far
+    pub stderr_bytes: u64,
+}
+
+/// List background jobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobListRequest {
+    /// Unique request ID
+    pub id: u64,
+}
+
+/// Fetch buffered output of a job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutputRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Job to read from
+    pub job_id: u64,
+
+    /// Stdout offset to read from (total bytes already seen)
+    pub stdout_offset: u64,
+
+    /// Stderr offset to read from (total bytes already seen)
+    pub stderr_offset: u64,
+
+    /// Maximum bytes to return per stream
+    pub max_bytes: u32,
+}
+
+/// Kill a background job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobKillRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Job to kill
+    pub job_id: u64,
+}
+
+/// State of a single job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatusMessage {
+    /// Request ID this message answers
+    pub request_id: u64,
+
+    /// Job summary
+    pub job: JobInfo,
+}
+
+/// List of background jobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobListResponse {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Jobs owned by the session
+    pub jobs: Vec<JobInfo>,
+}
+
+/// Buffered job output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutput {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Job the output belongs to
+    pub job_id: u64,
+
+    /// Offset of the first returned stdout byte
+    ///
+    /// May be greater than the requested offset if older output was discarded.
+    pub stdout_offset: u64,
+
+    /// Stdout bytes
+    pub stdout: Vec<u8>,
+
+    /// Offset of the first returned stderr byte
+    pub stderr_offset: u64,
+
+    /// Stderr bytes
+    pub stderr: Vec<u8>,
+
+    /// Current job state
+    pub state: JobState,
+
+    /// Exit code (once finished)
+    pub exit_code: Option<i32>,
+}
+
+/// A command executed in a session
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Command that was run
+    pub command: String,
+
+    /// Command arguments
+    pub args: Vec<String>,
+
+    /// Working directory requested
+    pub cwd: Option<String>,
+
+    /// Start time (Unix seconds)
+    pub started_at: u64,
+
+    /// Execution time (milliseconds)
+    pub duration_ms: u64,
+
+    /// Outcome (`None` if the command was refused)
+    pub status: Option<CommandStatus>,
+
+    /// Exit code (`None` if the command was refused)
+    pub exit_code: Option<i32>,
+}
+
+/// Fetch the session's command history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Return at most this many of the most recent entries
+    pub limit: Option<u32>,
+}
+
+/// Command history of a session, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryResponse {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Executed commands
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// Set environment variables for every later command of the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetEnvRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Variables to set (replacing earlier values)
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Remove session environment variables
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnsetEnvRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Variable names (unknown ones are ignored)
+    pub names: Vec<String>,
+}
+
+/// Fetch the client's usage counters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsRequest {
+    /// Unique request ID
+    pub id: u64,
+}
+
+/// Usage counters of a client identity, across all its sessions, plus the
+/// current session's load
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StatsResponse {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Sessions opened
+    pub sessions: u64,
+
+    /// Commands executed
+    pub commands: u64,
+
+    /// CPU time used by the commands (user + system, milliseconds)
+    pub cpu_time_ms: u64,
+
+    /// File bytes uploaded
+    pub bytes_uploaded: u64,
+
+    /// File bytes downloaded
+    pub bytes_downloaded: u64,
+
+    /// Commands of this session executing now
+    pub running: u32,
+
+    /// Commands of this session waiting for an execution slot
+    pub queued: u32,
+}
+
+/// Upload a program into the session's temporary directory and run it
+///
+/// The server answers with TRANSFER_READY and the client sends FILE_CHUNKs as
+/// for an upload. Once the last chunk arrives and the SHA-256 matches, the
+/// file is made executable and run with `args`; the final chunk is answered
+/// with the COMMAND_RESPONSE (its `id` is this request's) instead of
+/// TRANSFER_COMPLETE.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecUploadRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// File name of the program (no directories)
+    pub name: String,
+
+    /// Program size in bytes
+    pub size: u64,
+
+    /// SHA-256 of the program
+    pub sha256: Vec<u8>,
+
+    /// Arguments
+    pub args: Vec<String>,
+
+    /// Optional environment variables
+    pub env: Option<BTreeMap<String, String>>,
+
+    /// Optional timeout (seconds)
+    pub timeout: Option<u64>,
+
+    /// Delete the program once it has run
+    pub delete_after: bool,
+}
+
+/// Read part of a command's spooled output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchOutputRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Spool from the COMMAND_RESPONSE
+    pub spool_id: u64,
+
+    /// Read stderr instead of stdout
+    pub stderr: bool,
+
+    /// Offset of the first byte
+    pub offset: u64,
+
+    /// Maximum bytes to return (capped at the chunk size)
+    pub length: u32,
+}
+
+/// A range of spooled command output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputChunk {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Spool the data comes from
+    pub spool_id: u64,
+
+    /// Data is from stderr
+    pub stderr: bool,
+
+    /// Offset of the first byte
+    pub offset: u64,
+
+    /// Output bytes (empty at the end of the stream)
+    pub data: Vec<u8>,
+
+    /// Bytes of the stream in the spool
+    pub total: u64,
+}
+
+/// A request parked until an operator decides on it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingNotice {
+    /// ID of the waiting request
+    pub id: u64,
+
+    /// Approval ID the operator decides on
+    pub approval_id: u64,
+
+    /// Seconds until the request is refused if nobody decides
+    pub timeout: u64,
+}
+
+/// Output of a running command, sent as it is produced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandOutput {
+    /// Request ID of the command
+    pub id: u64,
+
+    /// Data is from stderr
+    pub stderr: bool,
+
+    /// Output bytes
+    pub data: Vec<u8>,
+}
+
+/// Request to stop a running command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelRequest {
+    /// Request ID of the command
+    pub id: u64,
+
+    /// Kill the command outright instead of sending it SIGINT
+    pub force: bool,
+}
+
+/// Input for a running command that takes stdin (not answered)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInput {
+    /// Request ID of the command
+    pub id: u64,
+
+    /// Input bytes
+    pub data: Vec<u8>,
+
+    /// The input ends here; the command's stdin is closed
+    pub eof: bool,
+}
+
+/// Payload of an ECHO and its reply
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EchoMessage {
+    /// Unique request ID
+    pub id: u64,
+
+    /// Bytes to send back (at most [`MAX_CHUNK_SIZE`](crate::MAX_CHUNK_SIZE))
+    pub data: Vec<u8>,
+}
+
+/// Last message of the Noise handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeMessage {
+    /// Noise handshake message
+    pub noise: Vec<u8>,
+}
+
+/// A message encrypted with the session keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessage {
+    /// Number of the message in its direction, used once
+    pub nonce: u64,
+
+    /// The framed message, encrypted and authenticated
+    pub ciphertext: Vec<u8>,
+}
+
+/// What a completion request completes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CompletionKind {
+    /// A path, relative to the session's working directory
+    Path,
+
+    /// A command name (executables on the server's `PATH` and builtins)
+    Command,
+}
+
+/// Ask for completions of a partial word
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompleteRequest {
+    /// Unique request ID
+    pub id: u64,
+
+    /// What the word is
+    pub kind: CompletionKind,
+
+    /// The partial word
+    pub prefix: String,
+}
+
+/// Completions of a partial word, sorted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompleteResponse {
+    /// Request ID this message answers
+    pub id: u64,
+
+    /// Full words starting with the prefix (directories end in `/`)
+    pub candidates: Vec<String>,
+
+    /// More candidates exist than were returned
+    pub truncated: bool,
+}
+
+/// Application data of a server's destination announce
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AnnounceInfo {
+    /// Human-readable server name
+    pub name: Option<String>,
+
+    /// Protocol version the server speaks
+    pub protocol_version: ProtocolVersion,
+
+    /// Server capabilities
+    pub capabilities: Vec<String>,
+}
+
+impl AnnounceInfo {
+    /// Encode as announce app data
+    pub fn to_bytes(&self) -> Vec<u8> {
+        crate::protocol::serialize(self).expect("serializing plain data cannot fail")
+    }
+
+    /// Decode from announce app data
+    pub fn from_bytes(data: &[u8]) -> crate::Result<Self> {
+        crate::protocol::deserialize(data)
+    }
+}
+
 impl Message {
     /// Get message type identifier
     pub fn message_type(&self) -> u8 {
@@ -163,12 +1191,78 @@ impl Message {
             Message::Connect(_) => 0x01,
             Message::Accept(_) => 0x02,
             Message::Reject(_) => 0x03,
+            Message::Handshake(_) => 0x04,
+            Message::Sealed(_) => 0x05,
             Message::CommandRequest(_) => 0x10,
             Message::CommandResponse(_) => 0x11,
             Message::Disconnect(_) => 0x20,
             Message::Ack(_) => 0x21,
+            Message::Error(_) => 0x22,
             Message::Ping => 0x30,
             Message::Pong => 0x31,
+            Message::Echo(_) => 0x32,
+            Message::EchoReply(_) => 0x33,
+            Message::UploadStart(_) => 0x40,
+            Message::DownloadStart(_) => 0x41,
+            Message::TransferReady(_) => 0x42,
+            Message::FileChunk(_) => 0x43,
+            Message::ChunkRequest(_) => 0x44,
+            Message::ChunkAck(_) => 0x45,
+            Message::TransferComplete(_) => 0x46,
+            Message::FileOp(_) => 0x48,
+            Message::FileOpResult(_) => 0x49,
+            Message::PtyOpen(_) => 0x50,
+            Message::PtyData(_) => 0x51,
+            Message::PtyResize(_) => 0x52,
+            Message::PtyClose(_) => 0x53,
+            Message::ChannelOpen(_) => 0x60,
+            Message::ChannelData(_) => 0x61,
+            Message::ChannelClose(_) => 0x62,
+            Message::RemoteForward(_) => 0x63,
+            Message::CancelRemoteForward(_) => 0x64,
+            Message::JobStart(_) => 0x70,
+            Message::JobList(_) => 0x71,
+            Message::JobOutputRequest(_) => 0x72,
+            Message::JobKill(_) => 0x73,
+            Message::JobStatus(_) => 0x74,
+            Message::JobListResponse(_) => 0x75,
+            Message::JobOutput(_) => 0x76,
+            Message::HistoryRequest(_) => 0x80,
+            Message::HistoryResponse(_) => 0x81,
+            Message::SetEnv(_) => 0x82,
+            Message::UnsetEnv(_) => 0x83,
+            Message::BannerAck => 0x84,
+            Message::StatsRequest(_) => 0x85,
+            Message::StatsResponse(_) => 0x86,
+            Message::ExecUpload(_) => 0x87,
+            Message::FetchOutput(_) => 0x88,
+            Message::OutputChunk(_) => 0x89,
+            Message::Pending(_) => 0x8A,
+            Message::CompleteRequest(_) => 0x8B,
+            Message::CompleteResponse(_) => 0x8C,
+            Message::CommandOutput(_) => 0x8D,
+            Message::CancelRequest(_) => 0x8E,
+            Message::CommandInput(_) => 0x8F,
+        }
+    }
+
+    /// How urgently the message should go out
+    ///
+    /// Keepalives, cancels and terminal traffic are interactive; file data
+    /// and fetched output are bulk. A command's input is normal, like the
+    /// request it follows, so it can't overtake it: input for a command
+    /// not started yet is dropped.
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::Ping
+            | Message::Pong
+            | Message::CancelRequest(_)
+            | Message::PtyData(_)
+            | Message::PtyResize(_) => Priority::Interactive,
+            Message::FileChunk(_) | Message::OutputChunk(_) | Message::JobOutput(_) => {
+                Priority::Bulk
+            }
+            _ => Priority::Normal,
         }
     }
 }
@@ -181,6 +1275,127 @@ mod tests {
     fn test_message_types() {
         assert_eq!(Message::Ping.message_type(), 0x30);
         assert_eq!(Message::Pong.message_type(), 0x31);
+        let echo = EchoMessage {
+            id: 1,
+            data: vec![0; 8],
+        };
+        assert_eq!(Message::Echo(echo.clone()).message_type(), 0x32);
+        assert_eq!(Message::EchoReply(echo).message_type(), 0x33);
+        assert_eq!(
+            Message::PtyData(PtyData {
+                id: 1,
+                data: vec![]
+            })
+            .message_type(),
+            0x51
+        );
+        assert_eq!(
+            Message::HistoryRequest(HistoryRequest { id: 1, limit: None }).message_type(),
+            0x80
+        );
+        assert_eq!(
+            Message::UnsetEnv(UnsetEnvRequest {
+                id: 1,
+                names: vec![]
+            })
+            .message_type(),
+            0x83
+        );
+        assert_eq!(Message::BannerAck.message_type(), 0x84);
+        assert_eq!(
+            Message::StatsResponse(StatsResponse::default()).message_type(),
+            0x86
+        );
+    }
+
+    #[test]
+    fn test_priorities() {
+        assert_eq!(Message::Ping.priority(), Priority::Interactive);
+        assert_eq!(
+            Message::CancelRequest(CancelRequest {
+                id: 1,
+                force: false
+            })
+            .priority(),
+            Priority::Interactive
+        );
+        assert_eq!(
+            Message::PtyData(PtyData {
+                id: 1,
+                data: vec![]
+            })
+            .priority(),
+            Priority::Interactive
+        );
+        assert_eq!(
+            Message::FileChunk(FileChunk {
+                transfer_id: 1,
+                offset: 0,
+                data: vec![]
+            })
+            .priority(),
+            Priority::Bulk
+        );
+        assert_eq!(Message::BannerAck.priority(), Priority::Normal);
+        assert_eq!(
+            Message::CommandInput(CommandInput {
+                id: 1,
+                data: vec![],
+                eof: false
+            })
+            .priority(),
+            Priority::Normal
+        );
+    }
+
+    #[test]
+    fn test_connect_signing_payload() {
+        let connect = ConnectMessage {
+            protocol_version: 1,
+            client_identity: vec![1; 32],
+            capabilities: vec!["command-exec".to_string()],
+            auth_token: None,
+            timestamp: 1_700_000_000,
+            nonce: [7; 16],
+            signature: vec![],
+            resume_token: None,
+            noise: None,
+            server_destination: [2; 32],
+        };
+        let payload = connect.signing_payload();
+
+        // The signature itself is not covered
+        let mut signed = connect.clone();
+        signed.signature = vec![9; 64];
+        assert_eq!(signed.signing_payload(), payload);
+
+        // Everything else is
+        let mut changed = connect.clone();
+        changed.auth_token = Some("token".to_string());
+        assert_ne!(changed.signing_payload(), payload);
+
+        let mut changed = connect.clone();
+        changed.nonce = [8; 16];
+        assert_ne!(changed.signing_payload(), payload);
+
+        let mut changed = connect.clone();
+        changed.resume_token = Some(vec![1; 32]);
+        assert_ne!(changed.signing_payload(), payload);
+
+        let mut changed = connect;
+        changed.server_destination = [3; 32];
+        assert_ne!(changed.signing_payload(), payload);
+    }
+
+    #[test]
+    fn test_announce_info() {
+        let info = AnnounceInfo {
+            name: Some("build-box".to_string()),
+            protocol_version: 1,
+            capabilities: vec!["command-exec".to_string()],
+        };
+        assert_eq!(AnnounceInfo::from_bytes(&info.to_bytes()).unwrap(), info);
+        assert!(AnnounceInfo::from_bytes(b"\xff").is_err());
     }
 
     #[test]
@@ -192,17 +1407,21 @@ mod tests {
             env: None,
             timeout: Some(30),
             working_dir: Some("/tmp".to_string()),
+            secrets: vec!["DB_PASS".to_string()],
+            stdin: false,
+            delta_base: None,
         };
 
         let msg = Message::CommandRequest(req.clone());
-        let serialized = bincode::serialize(&msg).unwrap();
-        let deserialized: Message = bincode::deserialize(&serialized).unwrap();
+        let serialized = crate::protocol::serialize(&msg).unwrap();
+        let deserialized: Message = crate::protocol::deserialize(&serialized).unwrap();
 
         match deserialized {
             Message::CommandRequest(decoded) => {
                 assert_eq!(decoded.id, req.id);
                 assert_eq!(decoded.command, req.command);
                 assert_eq!(decoded.args, req.args);
+                assert_eq!(decoded.secrets, req.secrets);
             }
             _ => panic!("Wrong message type"),
         }
@@ -0,0 +1,492 @@
+//! Session encryption (Noise)
+//!
+//! A client offering encryption runs a Noise XX handshake
+//! (`Noise_XX_25519_ChaChaPoly_SHA256`) alongside CONNECT and ACCEPT, with
+//! the X25519 forms of the client's and the server's Ed25519 identities as
+//! static keys:
+//!
+//! ```text
+//! CONNECT   { noise: -> e }
+//! ACCEPT    { noise: <- e, ee, s, es }
+//! HANDSHAKE { noise: -> s, se }          answered with a sealed ACK
+//! ```
+//!
+//! The prologue is the CONNECT's signing payload, tying the handshake to
+//! that request. Each side then checks that the static key the other proved
+//! it holds is that of the identity it claims: the client's, signed in
+//! CONNECT, and the server's, checked against the known hosts. From then on
+//! every message in either direction travels as SEALED, encrypted and
+//! authenticated with keys only the two ends know, whatever the transport.
+//!
+//! An end whose private key is kept elsewhere, by a key agent, performs its
+//! static key's Diffie-Hellman through a [`StaticKey`] instead.
+//!
+//! Datagrams may arrive out of order or twice, so each sealed message
+//! carries its nonce; a nonce is accepted once, and only within
+//! [`REPLAY_WINDOW`] of the highest seen.
+
+use crate::protocol::ReplayWindow;
+pub use crate::protocol::REPLAY_WINDOW;
+use crate::{Message, ProtocolCodec, ProtocolError, Result, SealedMessage};
+use bytes::BytesMut;
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Noise protocol name
+pub const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// Largest Noise message
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Authentication tag added to every encrypted message
+const TAG_LEN: usize = 16;
+
+/// Stand-in private key given to snow for a [`StaticKey`], never used
+const HELD_PRIVATE_KEY: [u8; 32] = [0; 32];
+
+/// A static key whose private half is held elsewhere, used through key
+/// agreement only
+pub trait StaticKey: Send + Sync {
+    /// The X25519 public key
+    fn public_key(&self) -> [u8; 32];
+
+    /// X25519 agreement with the `public_key` of the other end; returns the
+    /// shared secret
+    fn agree(&self, public_key: &[u8; 32]) -> Result<[u8; 32]>;
+}
+
+/// A handshake under way
+pub struct Handshake {
+    state: snow::HandshakeState,
+}
+
+impl Handshake {
+    /// Start a handshake as the client, whose static key is `private_key`;
+    /// returns the first message, for CONNECT
+    pub fn initiate(private_key: &[u8; 32], prologue: &[u8]) -> Result<(Self, Vec<u8>)> {
+        let mut state = snow::Builder::new(params())
+            .local_private_key(private_key)
+            .prologue(prologue)
+            .build_initiator()
+            .map_err(noise_error)?;
+        let first = write(&mut state)?;
+        Ok((Self { state }, first))
+    }
+
+    /// Start a handshake like [`initiate`](Self::initiate), with a static
+    /// key held elsewhere
+    pub fn initiate_with(key: Arc<dyn StaticKey>, prologue: &[u8]) -> Result<(Self, Vec<u8>)> {
+        let mut state = held(key)
+            .local_private_key(&HELD_PRIVATE_KEY)
+            .prologue(prologue)
+            .build_initiator()
+            .map_err(noise_error)?;
+        let first = write(&mut state)?;
+        Ok((Self { state }, first))
+    }
+
+    /// Answer the client's `first` message as the server, whose static key
+    /// is `private_key`; returns the second message, for ACCEPT
+    pub fn respond(
+        private_key: &[u8; 32],
+        prologue: &[u8],
+        first: &[u8],
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut state = snow::Builder::new(params())
+            .local_private_key(private_key)
+            .prologue(prologue)
+            .build_responder()
+            .map_err(noise_error)?;
+        read(&mut state, first)?;
+        let second = write(&mut state)?;
+        Ok((Self { state }, second))
+    }
+
+    /// Answer the client's `first` message like [`respond`](Self::respond),
+    /// with a static key held elsewhere
+    pub fn respond_with(
+        key: Arc<dyn StaticKey>,
+        prologue: &[u8],
+        first: &[u8],
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut state = held(key)
+            .local_private_key(&HELD_PRIVATE_KEY)
+            .prologue(prologue)
+            .build_responder()
+            .map_err(noise_error)?;
+        read(&mut state, first)?;
+        let second = write(&mut state)?;
+        Ok((Self { state }, second))
+    }
+
+    /// Read the server's `second` message as the client; returns the
+    /// session keys and the last message, for HANDSHAKE
+    ///
+    /// Check the server's key ([`SessionCipher::remote_static`]) before
+    /// sending the last message.
+    pub fn complete(mut self, second: &[u8]) -> Result<(SessionCipher, Vec<u8>)> {
+        read(&mut self.state, second)?;
+        let third = write(&mut self.state)?;
+        Ok((SessionCipher::new(self.state)?, third))
+    }
+
+    /// Read the client's `third` message as the server; returns the session
+    /// keys
+    pub fn finish(mut self, third: &[u8]) -> Result<SessionCipher> {
+        read(&mut self.state, third)?;
+        SessionCipher::new(self.state)
+    }
+}
+
+/// Keys of an established session
+pub struct SessionCipher {
+    transport: snow::StatelessTransportState,
+
+    /// Static key of the other end
+    remote_static: [u8; 32],
+
+    /// Nonce of the next message sent
+    next_nonce: AtomicU64,
+
+    /// Nonces received lately
+    received: Mutex<ReplayWindow>,
+}
+
+impl SessionCipher {
+    fn new(state: snow::HandshakeState) -> Result<Self> {
+        let remote_static = state
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| ProtocolError::Crypto("no static key from the peer".to_string()))?;
+        Ok(Self {
+            transport: state.into_stateless_transport_mode().map_err(noise_error)?,
+            remote_static,
+            next_nonce: AtomicU64::new(0),
+            received: Mutex::default(),
+        })
+    }
+
+    /// X25519 static key the other end proved it holds
+    pub fn remote_static(&self) -> &[u8; 32] {
+        &self.remote_static
+    }
+
+    /// Encrypt `message` into a SEALED message
+    pub fn seal(&self, message: &Message) -> Result<Message> {
+        let plaintext = ProtocolCodec::encode(message)?;
+        if plaintext.len() + TAG_LEN > MAX_NOISE_MESSAGE {
+            return Err(ProtocolError::MessageTooLarge {
+                size: plaintext.len(),
+                max: MAX_NOISE_MESSAGE - TAG_LEN,
+            });
+        }
+        let nonce = self.next_nonce.fetch_add(1, Ordering::SeqCst);
+        let mut ciphertext = vec![0u8; plaintext.len() + TAG_LEN];
+        let len = self
+            .transport
+            .write_message(nonce, &plaintext, &mut ciphertext)
+            .map_err(noise_error)?;
+        ciphertext.truncate(len);
+        Ok(Message::Sealed(SealedMessage { nonce, ciphertext }))
+    }
+
+    /// Decrypt a SEALED message; fails for one forged, damaged or seen
+    /// before
+    pub fn open(&self, sealed: &SealedMessage) -> Result<Message> {
+        let mut received = self.received.lock().unwrap();
+        if !received.fresh(sealed.nonce) {
+            return Err(ProtocolError::Crypto(format!(
+                "message {} replayed or too old",
+                sealed.nonce
+            )));
+        }
+        let mut plaintext = vec![0u8; sealed.ciphertext.len()];
+        let len = self
+            .transport
+            .read_message(sealed.nonce, &sealed.ciphertext, &mut plaintext)
+            .map_err(|_| ProtocolError::Crypto("message failed authentication".to_string()))?;
+        received.insert(sealed.nonce);
+        drop(received);
+
+        let mut buf = BytesMut::from(&plaintext[..len]);
+        match ProtocolCodec::decode(&mut buf)? {
+            Some(Message::Sealed(_) | Message::Handshake(_)) => Err(ProtocolError::InvalidFormat(
+                "handshake message inside a sealed one".to_string(),
+            )),
+            Some(message) => Ok(message),
+            None => Err(ProtocolError::InvalidFormat(
+                "incomplete sealed message".to_string(),
+            )),
+        }
+    }
+}
+
+fn params() -> snow::params::NoiseParams {
+    PATTERN.parse().expect("valid Noise protocol name")
+}
+
+/// A builder whose static key is `key`
+fn held(key: Arc<dyn StaticKey>) -> snow::Builder<'static> {
+    snow::Builder::with_resolver(params(), Box::new(HeldKeyResolver { key }))
+}
+
+/// Resolves Diffie-Hellman to [`HeldDh`], the rest to snow's defaults
+struct HeldKeyResolver {
+    key: Arc<dyn StaticKey>,
+}
+
+impl CryptoResolver for HeldKeyResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        Some(Box::new(HeldDh {
+            key: Arc::clone(&self.key),
+            public: self.key.public_key(),
+            ephemeral: DefaultResolver.resolve_dh(choice)?,
+            held: false,
+        }))
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+/// A Diffie-Hellman key of the handshake: the held static key once snow
+/// sets a private key, a generated ephemeral one otherwise
+struct HeldDh {
+    key: Arc<dyn StaticKey>,
+    public: [u8; 32],
+    ephemeral: Box<dyn Dh>,
+    held: bool,
+}
+
+impl Dh for HeldDh {
+    fn name(&self) -> &'static str {
+        self.ephemeral.name()
+    }
+
+    fn pub_len(&self) -> usize {
+        self.ephemeral.pub_len()
+    }
+
+    fn priv_len(&self) -> usize {
+        self.ephemeral.priv_len()
+    }
+
+    fn set(&mut self, _privkey: &[u8]) {
+        self.held = true;
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        self.held = false;
+        self.ephemeral.generate(rng);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        if self.held {
+            &self.public
+        } else {
+            self.ephemeral.pubkey()
+        }
+    }
+
+    fn privkey(&self) -> &[u8] {
+        if self.held {
+            &HELD_PRIVATE_KEY
+        } else {
+            self.ephemeral.privkey()
+        }
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> std::result::Result<(), snow::Error> {
+        if !self.held {
+            return self.ephemeral.dh(pubkey, out);
+        }
+        let public_key = pubkey.try_into().map_err(|_| snow::Error::Dh)?;
+        let shared = self.key.agree(public_key).map_err(|_| snow::Error::Dh)?;
+        out[..shared.len()].copy_from_slice(&shared);
+        Ok(())
+    }
+}
+
+fn write(state: &mut snow::HandshakeState) -> Result<Vec<u8>> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = state
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    message.truncate(len);
+    Ok(message)
+}
+
+fn read(state: &mut snow::HandshakeState, message: &[u8]) -> Result<()> {
+    let mut payload = vec![0u8; message.len()];
+    state
+        .read_message(message, &mut payload)
+        .map_err(noise_error)?;
+    Ok(())
+}
+
+fn noise_error(e: snow::Error) -> ProtocolError {
+    ProtocolError::Crypto(format!("Noise handshake failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EchoMessage;
+
+    fn keypair() -> snow::Keypair {
+        snow::Builder::new(params()).generate_keypair().unwrap()
+    }
+
+    fn key(bytes: &[u8]) -> [u8; 32] {
+        bytes.try_into().unwrap()
+    }
+
+    /// Client and server keys after a full handshake
+    fn handshake(client: &snow::Keypair, server: &snow::Keypair) -> (SessionCipher, SessionCipher) {
+        let (initiator, first) = Handshake::initiate(&key(&client.private), b"connect").unwrap();
+        let (responder, second) =
+            Handshake::respond(&key(&server.private), b"connect", &first).unwrap();
+        let (client_cipher, third) = initiator.complete(&second).unwrap();
+        let server_cipher = responder.finish(&third).unwrap();
+        (client_cipher, server_cipher)
+    }
+
+    fn echo(id: u64) -> Message {
+        Message::Echo(EchoMessage {
+            id,
+            data: b"secret".to_vec(),
+        })
+    }
+
+    fn sealed(message: Message) -> SealedMessage {
+        match message {
+            Message::Sealed(sealed) => sealed,
+            other => panic!("not sealed: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (client, server) = (keypair(), keypair());
+        let (client_cipher, server_cipher) = handshake(&client, &server);
+        assert_eq!(client_cipher.remote_static(), &key(&server.public));
+        assert_eq!(server_cipher.remote_static(), &key(&client.public));
+
+        let message = sealed(client_cipher.seal(&echo(1)).unwrap());
+        assert!(!message.ciphertext.windows(6).any(|w| w == b"secret"));
+        match server_cipher.open(&message).unwrap() {
+            Message::Echo(echo) => assert_eq!(echo.data, b"secret"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Keys differ per direction
+        let reply = sealed(server_cipher.seal(&echo(2)).unwrap());
+        assert!(server_cipher.open(&reply).is_err());
+        assert!(client_cipher.open(&reply).is_ok());
+    }
+
+    /// A static key behind [`StaticKey`], as a key agent would hold it
+    struct Held(Box<dyn Dh>);
+
+    impl Held {
+        fn new(keypair: &snow::Keypair) -> Arc<Self> {
+            let mut dh = DefaultResolver.resolve_dh(&DHChoice::Curve25519).unwrap();
+            dh.set(&keypair.private);
+            Arc::new(Self(dh))
+        }
+    }
+
+    impl StaticKey for Held {
+        fn public_key(&self) -> [u8; 32] {
+            key(self.0.pubkey())
+        }
+
+        fn agree(&self, public_key: &[u8; 32]) -> Result<[u8; 32]> {
+            let mut shared = [0u8; 32];
+            self.0
+                .dh(public_key, &mut shared)
+                .map_err(|e| ProtocolError::Crypto(e.to_string()))?;
+            Ok(shared)
+        }
+    }
+
+    /// Refuses every agreement, as an agent that was locked
+    struct Locked;
+
+    impl StaticKey for Locked {
+        fn public_key(&self) -> [u8; 32] {
+            [1; 32]
+        }
+
+        fn agree(&self, _public_key: &[u8; 32]) -> Result<[u8; 32]> {
+            Err(ProtocolError::Crypto("locked".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_held_keys() {
+        let (client, server) = (keypair(), keypair());
+        let (initiator, first) = Handshake::initiate_with(Held::new(&client), b"connect").unwrap();
+        let (responder, second) =
+            Handshake::respond_with(Held::new(&server), b"connect", &first).unwrap();
+        let (client_cipher, third) = initiator.complete(&second).unwrap();
+        let server_cipher = responder.finish(&third).unwrap();
+        assert_eq!(client_cipher.remote_static(), &key(&server.public));
+        assert_eq!(server_cipher.remote_static(), &key(&client.public));
+
+        let message = sealed(client_cipher.seal(&echo(1)).unwrap());
+        assert!(server_cipher.open(&message).is_ok());
+
+        // Without the agreement there is no handshake
+        let (_, first) = Handshake::initiate(&key(&client.private), b"connect").unwrap();
+        assert!(Handshake::respond_with(Arc::new(Locked), b"connect", &first).is_err());
+    }
+
+    #[test]
+    fn test_prologue_mismatch() {
+        let (client, server) = (keypair(), keypair());
+        let (initiator, first) = Handshake::initiate(&key(&client.private), b"connect").unwrap();
+        let (_, second) = Handshake::respond(&key(&server.private), b"other", &first).unwrap();
+        assert!(matches!(
+            initiator.complete(&second),
+            Err(ProtocolError::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay_and_tampering() {
+        let (client_cipher, server_cipher) = handshake(&keypair(), &keypair());
+        let first = sealed(client_cipher.seal(&echo(1)).unwrap());
+        let second = sealed(client_cipher.seal(&echo(2)).unwrap());
+
+        // Out of order is fine, twice is not
+        assert!(server_cipher.open(&second).is_ok());
+        assert!(server_cipher.open(&first).is_ok());
+        assert!(server_cipher.open(&first).is_err());
+
+        let mut tampered = sealed(client_cipher.seal(&echo(3)).unwrap());
+        tampered.ciphertext[0] ^= 1;
+        assert!(server_cipher.open(&tampered).is_err());
+
+        // Too far behind
+        let old = sealed(client_cipher.seal(&echo(4)).unwrap());
+        let mut latest = None;
+        for id in 0..REPLAY_WINDOW {
+            latest = Some(sealed(client_cipher.seal(&echo(id)).unwrap()));
+        }
+        server_cipher.open(&latest.unwrap()).unwrap();
+        assert!(server_cipher.open(&old).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 //! Protocol framing and serialization
 
-use crate::{Message, ProtocolError, Result};
+use crate::{Message, ProtocolError, Result, SessionId};
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 use bytes::{Buf, BufMut, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
 /// Current protocol version
 pub const CURRENT_PROTOCOL_VERSION: u32 = 1;
@@ -9,24 +13,180 @@ pub const CURRENT_PROTOCOL_VERSION: u32 = 1;
 /// Maximum message size (1 MB)
 pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
 
+/// Maximum file transfer chunk size (16 KB)
+///
+/// Kept well below the I2P datagram limit so a chunk fits in one packet.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
 /// Protocol version type
 pub type ProtocolVersion = u32;
 
+/// Bits of a frame's length word that hold the length; the top byte holds
+/// its [`Priority`]
+const LENGTH_MASK: u32 = 0x00ff_ffff;
+
+// The length (type byte included) of the largest message must fit in the
+// length bits, or raising the limit would corrupt frames
+const _: () = assert!(MAX_MESSAGE_SIZE < LENGTH_MASK as usize);
+
+/// How urgently a frame should go out
+///
+/// The server's send queue hands out the interactive messages it sends of
+/// its own accord (keepalives, terminal output) first and bulk ones (file
+/// data) last, so a transfer does not leave an interactive session waiting.
+/// Only server to client traffic is ordered so: a client sends its frames
+/// as they are made, in normal frames. Frames carry their class in the top
+/// byte of the length word, which is zero for [`Normal`](Self::Normal) and
+/// in frames of peers that know no classes; only peers that list the
+/// `frame-priority` capability are sent others. Receivers ignore the class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent when nothing else is waiting
+    Bulk,
+
+    /// Most messages
+    #[default]
+    Normal,
+
+    /// Sent ahead of everything else
+    Interactive,
+}
+
+impl Priority {
+    /// Value in the frame header
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Priority::Normal => 0,
+            Priority::Interactive => 1,
+            Priority::Bulk => 2,
+        }
+    }
+
+    /// Class of a value in the frame header; ones added later are normal
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Priority::Interactive,
+            2 => Priority::Bulk,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Domain separator for packet signatures
+pub const PACKET_SIGNATURE_CONTEXT: &str = "reticulum-shell-packet";
+
+/// Bytes a client signs for each packet it sends on an unencrypted session:
+/// the session, the packet's sequence number and the packet data
+///
+/// The signature and sequence number go in the packet's `signature` and
+/// `sequence`; the server checks the signature against the session's client
+/// identity, so a packet claiming another client's source is refused, and
+/// accepts each sequence number once, within [`REPLAY_WINDOW`] of the
+/// highest, so a captured packet can't be replayed.
+pub fn packet_signing_payload(session_id: &SessionId, sequence: u64, data: &[u8]) -> Vec<u8> {
+    serialize(&(PACKET_SIGNATURE_CONTEXT, session_id, sequence, data))
+        .expect("serializing plain data cannot fail")
+}
+
+/// How far behind the highest number received a nonce or sequence number
+/// may still arrive
+pub const REPLAY_WINDOW: u64 = 1024;
+
+/// Numbers received within [`REPLAY_WINDOW`] of the highest
+///
+/// Datagrams may arrive out of order or twice: each number is taken once,
+/// in any order, as long as it is recent enough to tell.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    /// `number` was not seen, and is recent enough to tell
+    pub fn fresh(&self, number: u64) -> bool {
+        let recent = match self.seen.last() {
+            Some(&highest) => number.saturating_add(REPLAY_WINDOW) > highest,
+            None => true,
+        };
+        recent && !self.seen.contains(&number)
+    }
+
+    /// Record `number` as seen
+    pub fn insert(&mut self, number: u64) {
+        self.seen.insert(number);
+        let highest = *self.seen.last().expect("just inserted");
+        while self
+            .seen
+            .first()
+            .is_some_and(|&oldest| oldest.saturating_add(REPLAY_WINDOW) <= highest)
+        {
+            self.seen.pop_first();
+        }
+    }
+
+    /// Record `number` if it is fresh; whether it was
+    pub fn accept(&mut self, number: u64) -> bool {
+        let fresh = self.fresh(number);
+        if fresh {
+            self.insert(number);
+        }
+        fresh
+    }
+}
+
+/// Encode `value` as bincode
+///
+/// With the settings of bincode 1's `serialize` (little-endian, fixed-size
+/// integers), which the wire format has always used.
+pub(crate) fn serialize<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let bytes = bincode::serde::encode_to_vec(value, bincode::config::legacy())?;
+    Ok(bytes)
+}
+
+/// Decode a value encoded by [`serialize`], ignoring any bytes after it
+pub(crate) fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let (value, _) = bincode::serde::decode_from_slice(bytes, decoding())?;
+    Ok(value)
+}
+
+/// Decode a value encoded by [`serialize`] that takes up all of `bytes`
+fn deserialize_exact<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let (value, read) = bincode::serde::decode_from_slice(bytes, decoding())?;
+    if read < bytes.len() {
+        return Err(ProtocolError::TrailingBytes(bytes.len() - read));
+    }
+    Ok(value)
+}
+
+/// The settings of [`serialize`], refusing lengths no message can have
+/// before allocating for them
+fn decoding() -> impl bincode::config::Config {
+    bincode::config::legacy().with_limit::<MAX_MESSAGE_SIZE>()
+}
+
 /// Protocol codec for encoding/decoding messages
 pub struct ProtocolCodec;
 
 impl ProtocolCodec {
-    /// Encode a message into bytes
+    /// Encode a message into bytes, as a frame of normal priority
     ///
     /// Frame format:
     /// ```text
-    /// [ 4 bytes: message length (u32, big-endian) ]
+    /// [ 1 byte: priority ]
+    /// [ 3 bytes: message length (u24, big-endian) ]
     /// [ 1 byte: message type ]
     /// [ N bytes: message payload (bincode-encoded) ]
     /// ```
     pub fn encode(message: &Message) -> Result<Vec<u8>> {
+        Self::encode_with_priority(message, Priority::Normal)
+    }
+
+    /// Encode a message into a frame of `priority`
+    ///
+    /// A sealed message should get the priority of the message inside.
+    pub fn encode_with_priority(message: &Message, priority: Priority) -> Result<Vec<u8>> {
         // Serialize the message
-        let payload = bincode::serialize(message)?;
+        let payload = serialize(message)?;
 
         // Check size limit
         if payload.len() > MAX_MESSAGE_SIZE {
@@ -39,8 +199,9 @@ impl ProtocolCodec {
         // Create frame
         let mut frame = BytesMut::with_capacity(5 + payload.len());
 
-        // Write length (4 bytes)
-        frame.put_u32((payload.len() + 1) as u32);
+        // Write priority and length (4 bytes)
+        frame.put_u8(priority.to_u8());
+        frame.put_uint((payload.len() + 1) as u64, 3);
 
         // Write message type (1 byte)
         frame.put_u8(message.message_type());
@@ -53,23 +214,26 @@ impl ProtocolCodec {
 
     /// Decode a message from bytes
     ///
-    /// Returns the decoded message and the number of bytes consumed
+    /// Returns `None` until `buf` holds a whole frame, which is then taken
+    /// off its front. No input makes it panic: a malformed frame is an
+    /// error, consumed unless its length is over the limit.
     pub fn decode(buf: &mut BytesMut) -> Result<Option<Message>> {
         // Need at least 4 bytes for length
-        if buf.len() < 4 {
+        let Some(&[a, b, c, d]) = buf.get(..4) else {
             return Ok(None);
-        }
+        };
+        let length = (u32::from_be_bytes([a, b, c, d]) & LENGTH_MASK) as usize;
 
-        // Read length without consuming
-        let length = {
-            let mut length_bytes = &buf[..4];
-            length_bytes.get_u32() as usize
+        // The length counts the message type byte
+        let Some(payload_len) = length.checked_sub(1) else {
+            buf.advance(4);
+            return Err(ProtocolError::EmptyFrame);
         };
 
         // Check size limit
-        if length > MAX_MESSAGE_SIZE {
+        if payload_len > MAX_MESSAGE_SIZE {
             return Err(ProtocolError::MessageTooLarge {
-                size: length,
+                size: payload_len,
                 max: MAX_MESSAGE_SIZE,
             });
         }
@@ -79,18 +243,19 @@ impl ProtocolCodec {
             return Ok(None);
         }
 
-        // Consume length bytes
-        buf.advance(4);
-
-        // Read message type
-        let _message_type = buf.get_u8();
-
-        // Read payload
-        let payload_len = length - 1; // Subtract message type byte
-        let payload = buf.split_to(payload_len);
+        // Consume the frame
+        let mut frame = buf.split_to(4 + length);
+        frame.advance(4);
+        let message_type = frame.get_u8();
 
         // Deserialize message
-        let message: Message = bincode::deserialize(&payload)?;
+        let message: Message = deserialize_exact(&frame)?;
+        if message.message_type() != message_type {
+            return Err(ProtocolError::MessageTypeMismatch {
+                frame: message_type,
+                message: message.message_type(),
+            });
+        }
 
         Ok(Some(message))
     }
@@ -124,6 +289,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
 
         let msg = Message::CommandRequest(req.clone());
@@ -185,6 +353,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
 
         let msg = Message::CommandRequest(large_cmd);
@@ -192,4 +363,126 @@ mod tests {
 
         assert!(matches!(result, Err(ProtocolError::MessageTooLarge { .. })));
     }
+
+    #[test]
+    fn test_decode_malformed() {
+        let encoded = ProtocolCodec::encode(&Message::Ping).unwrap();
+
+        // An empty frame is consumed, so the next one can be read
+        let mut buf = BytesMut::from(&[0, 0, 0, 0][..]);
+        buf.extend_from_slice(&encoded);
+        assert!(matches!(
+            ProtocolCodec::decode(&mut buf),
+            Err(ProtocolError::EmptyFrame)
+        ));
+        assert!(matches!(
+            ProtocolCodec::decode(&mut buf).unwrap(),
+            Some(Message::Ping)
+        ));
+
+        // Refused before waiting for the rest
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 0x30][..]);
+        assert!(matches!(
+            ProtocolCodec::decode(&mut buf),
+            Err(ProtocolError::MessageTooLarge { .. })
+        ));
+
+        let mut mismatch = encoded.clone();
+        mismatch[4] = 0x31;
+        assert!(matches!(
+            ProtocolCodec::decode(&mut BytesMut::from(&mismatch[..])),
+            Err(ProtocolError::MessageTypeMismatch {
+                frame: 0x31,
+                message: 0x30
+            })
+        ));
+
+        let mut trailing = encoded.clone();
+        trailing[3] += 1;
+        trailing.push(0);
+        assert!(matches!(
+            ProtocolCodec::decode(&mut BytesMut::from(&trailing[..])),
+            Err(ProtocolError::TrailingBytes(1))
+        ));
+
+        // A variant index no message has
+        let mut unknown = encoded.clone();
+        unknown[5] = 0xff;
+        assert!(matches!(
+            ProtocolCodec::decode(&mut BytesMut::from(&unknown[..])),
+            Err(ProtocolError::Serialization(_))
+        ));
+
+        // A CONNECT whose identity claims more bytes than a message can hold
+        let mut huge = vec![0, 0, 0, 17, 0x01, 0, 0, 0, 0, 1, 0, 0, 0];
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            ProtocolCodec::decode(&mut BytesMut::from(&huge[..])),
+            Err(ProtocolError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_priority() {
+        let encoded =
+            ProtocolCodec::encode_with_priority(&Message::Ping, Priority::Interactive).unwrap();
+        assert_eq!(encoded, [1, 0, 0, 5, 0x30, 8, 0, 0, 0]);
+        let mut buf = BytesMut::from(&encoded[..]);
+        assert!(matches!(
+            ProtocolCodec::decode(&mut buf).unwrap(),
+            Some(Message::Ping)
+        ));
+        assert!(buf.is_empty());
+
+        // Frames without a class are normal, and so are classes not known yet
+        let encoded = ProtocolCodec::encode(&Message::Ping).unwrap();
+        assert_eq!(Priority::from_u8(encoded[0]), Priority::Normal);
+        assert_eq!(Priority::from_u8(0x7f), Priority::Normal);
+        for priority in [Priority::Bulk, Priority::Normal, Priority::Interactive] {
+            assert_eq!(Priority::from_u8(priority.to_u8()), priority);
+        }
+        assert!(Priority::Interactive > Priority::Normal && Priority::Normal > Priority::Bulk);
+    }
+
+    #[test]
+    fn test_packet_signing_payload() {
+        let payload = packet_signing_payload(&[1; 16], 1, b"data");
+        assert_ne!(payload, packet_signing_payload(&[2; 16], 1, b"data"));
+        assert_ne!(payload, packet_signing_payload(&[1; 16], 2, b"data"));
+        assert_ne!(payload, packet_signing_payload(&[1; 16], 1, b"other"));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(5));
+        assert!(window.accept(3));
+        assert!(!window.accept(5));
+        assert!(!window.accept(3));
+
+        assert!(window.accept(REPLAY_WINDOW + 10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+    }
+
+    #[test]
+    fn test_wire_format() {
+        // Lengths are u64 little-endian, arrays have none
+        let mut expected = 22u64.to_le_bytes().to_vec();
+        expected.extend_from_slice(PACKET_SIGNATURE_CONTEXT.as_bytes());
+        expected.extend_from_slice(&[1; 16]);
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(&2u64.to_le_bytes());
+        expected.extend_from_slice(b"ab");
+        assert_eq!(packet_signing_payload(&[1; 16], 3, b"ab"), expected);
+
+        // Enum variants are u32 indices
+        let encoded = ProtocolCodec::encode(&Message::Ping).unwrap();
+        assert_eq!(encoded, [0, 0, 0, 5, 0x30, 8, 0, 0, 0]);
+        let mut buf = BytesMut::from(&encoded[..]);
+        assert!(matches!(
+            ProtocolCodec::decode(&mut buf).unwrap(),
+            Some(Message::Ping)
+        ));
+    }
 }
@@ -0,0 +1,91 @@
+//! Errors as Python sees them
+//!
+//! Every failure of the client is a [`ShellError`], one of its subclasses
+//! saying what went wrong; a local file that cannot be read or written is
+//! Python's own `OSError` (`FileNotFoundError` and so on) instead.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::PyErr;
+use shell_client::ClientError;
+
+create_exception!(
+    reticulum_shell,
+    ShellError,
+    PyException,
+    "A call to the shell failed"
+);
+create_exception!(
+    reticulum_shell,
+    ConfigError,
+    ShellError,
+    "The configuration or the identity is unusable"
+);
+create_exception!(
+    reticulum_shell,
+    ConnectionError,
+    ShellError,
+    "The server could not be reached, or the connection was lost"
+);
+create_exception!(
+    reticulum_shell,
+    RejectedError,
+    ShellError,
+    "The server refused the client, or its key changed"
+);
+create_exception!(
+    reticulum_shell,
+    RequestError,
+    ShellError,
+    "The server refused a request"
+);
+create_exception!(
+    reticulum_shell,
+    TimeoutError,
+    ShellError,
+    "The server did not answer in time"
+);
+
+/// The exception raised for `err`
+pub fn to_py(err: ClientError) -> PyErr {
+    let message = err.to_string();
+    match err {
+        ClientError::Config(_) | ClientError::Repl(_) => ConfigError::new_err(message),
+        ClientError::Network(_)
+        | ClientError::Protocol(_)
+        | ClientError::Connection(_)
+        | ClientError::NotConnected => ConnectionError::new_err(message),
+        ClientError::Rejected(_) | ClientError::HostKeyChanged(_) => {
+            RejectedError::new_err(message)
+        }
+        ClientError::Request(_) => RequestError::new_err(message),
+        ClientError::Timeout(_) => TimeoutError::new_err(message),
+        ClientError::Io(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::PyFileNotFoundError;
+    use pyo3::Python;
+
+    #[test]
+    fn test_to_py() {
+        Python::with_gil(|py| {
+            let err = to_py(ClientError::Timeout("no reply".to_string()));
+            assert!(err.is_instance_of::<TimeoutError>(py));
+            assert!(err.is_instance_of::<ShellError>(py));
+            assert_eq!(err.value_bound(py).to_string(), "Timed out: no reply");
+
+            assert!(to_py(ClientError::NotConnected).is_instance_of::<ConnectionError>(py));
+            assert!(to_py(ClientError::HostKeyChanged("x".to_string()))
+                .is_instance_of::<RejectedError>(py));
+
+            let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+            let err = to_py(ClientError::Io(missing));
+            assert!(err.is_instance_of::<PyFileNotFoundError>(py));
+            assert!(!err.is_instance_of::<ShellError>(py));
+        });
+    }
+}
@@ -0,0 +1,55 @@
+//! shell-python - Python bindings
+//!
+//! Exposes the embeddable [`ShellSession`](shell_client::session::ShellSession)
+//! API to Python as the `reticulum_shell` module, so scripts can run
+//! commands and copy files without shelling out to `rsh`. Every call
+//! returns an awaitable, run on a tokio runtime of the module's own and
+//! awaited from any asyncio event loop.
+//!
+//! The configuration is a client configuration file, as `rsh` reads it;
+//! errors are raised as `reticulum_shell.ShellError` and its subclasses.
+//! The module is built and installed with maturin:
+//!
+//! ```text
+//! cd crates/shell-python
+//! maturin develop --release
+//! ```
+//!
+//! ```python
+//! import asyncio
+//! import reticulum_shell
+//!
+//! async def main():
+//!     async with await reticulum_shell.connect("client.toml") as session:
+//!         result = await session.execute(["uname", "-a"], timeout=30)
+//!         print(result.exit_code, result.stdout.decode())
+//!         await session.download("/var/log/syslog", "syslog")
+//!
+//! asyncio.run(main())
+//! ```
+
+use pyo3::prelude::*;
+
+pub mod error;
+pub mod session;
+
+pub use session::{connect, CommandResult, Session};
+
+#[pymodule]
+fn reticulum_shell(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<Session>()?;
+    m.add_class::<CommandResult>()?;
+
+    m.add("ShellError", py.get_type_bound::<error::ShellError>())?;
+    m.add("ConfigError", py.get_type_bound::<error::ConfigError>())?;
+    m.add(
+        "ConnectionError",
+        py.get_type_bound::<error::ConnectionError>(),
+    )?;
+    m.add("RejectedError", py.get_type_bound::<error::RejectedError>())?;
+    m.add("RequestError", py.get_type_bound::<error::RequestError>())?;
+    m.add("TimeoutError", py.get_type_bound::<error::TimeoutError>())?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Sessions and the commands they run
+
+use crate::error::to_py;
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use shell_client::client::CommandOptions;
+use shell_client::config::ClientConfig;
+use shell_client::session::ShellSession;
+use shell_proto::{CommandResponse, CommandStatus};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Connect to the server of the client configuration at `config`
+///
+/// `identity` (a keystore name or the path of an identity file) replaces
+/// the configured one and `profile` selects a server profile. An encrypted
+/// identity's passphrase is taken from `RSH_IDENTITY_PASSPHRASE`, or asked
+/// for on the terminal.
+///
+/// Fails if the server's key is not in the known hosts (unless
+/// `accept_new_host_keys` is set), or if it has a banner to acknowledge
+/// and `accept_banner` is not set.
+#[pyfunction]
+#[pyo3(signature = (config, *, identity=None, profile=None, accept_banner=false))]
+pub fn connect(
+    py: Python<'_>,
+    config: PathBuf,
+    identity: Option<String>,
+    profile: Option<String>,
+    accept_banner: bool,
+) -> PyResult<Bound<'_, PyAny>> {
+    // Reading the identity may wait for the terminal
+    let config = py
+        .allow_threads(|| {
+            let config = ClientConfig::load_with_identity(&config, identity.as_deref())?;
+            match profile {
+                Some(profile) => config.with_profile(&profile),
+                None => Ok(config),
+            }
+        })
+        .map_err(to_py)?;
+
+    future_into_py(py, async move {
+        let session = ShellSession::connect(config, accept_banner)
+            .await
+            .map_err(to_py)?;
+        Ok(Session {
+            session: Arc::new(session),
+        })
+    })
+}
+
+/// A connection to a server, returned by `connect`
+///
+/// A connection carries one request at a time, so the calls of a session
+/// wait for each other. Used with `async with`, the session is
+/// disconnected at the end of the block.
+#[pyclass(frozen, module = "reticulum_shell")]
+pub struct Session {
+    session: Arc<ShellSession>,
+}
+
+#[pymethods]
+impl Session {
+    /// Run `argv` (the command and its arguments) and wait for it to end
+    ///
+    /// `timeout` (in seconds), `working_dir` and `env` override the
+    /// configured ones; an `idempotent` command is sent again after a
+    /// transport failure as long as nothing of it arrived yet.
+    #[pyo3(signature = (argv, *, timeout=None, working_dir=None, env=None, idempotent=false))]
+    fn execute<'py>(
+        &self,
+        py: Python<'py>,
+        argv: Vec<String>,
+        timeout: Option<u64>,
+        working_dir: Option<String>,
+        env: Option<BTreeMap<String, String>>,
+        idempotent: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let session = Arc::clone(&self.session);
+        let options = CommandOptions {
+            timeout,
+            working_dir,
+            env: env.unwrap_or_default(),
+            idempotent,
+            delta: false,
+        };
+        future_into_py(py, async move {
+            let response = session.exec(argv, options).wait().await.map_err(to_py)?;
+            Ok(CommandResult::from(response))
+        })
+    }
+
+    /// Upload the local file `local` to `remote`, continuing an earlier
+    /// upload if `resume` is set; returns the bytes sent
+    #[pyo3(signature = (local, remote, *, resume=false))]
+    fn upload<'py>(
+        &self,
+        py: Python<'py>,
+        local: PathBuf,
+        remote: String,
+        resume: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let session = Arc::clone(&self.session);
+        future_into_py(py, async move {
+            session.upload(&local, &remote, resume).await.map_err(to_py)
+        })
+    }
+
+    /// Download the remote file `remote` to `local`, continuing an earlier
+    /// download if `resume` is set; returns the bytes received
+    #[pyo3(signature = (remote, local, *, resume=false))]
+    fn download<'py>(
+        &self,
+        py: Python<'py>,
+        remote: String,
+        local: PathBuf,
+        resume: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let session = Arc::clone(&self.session);
+        future_into_py(py, async move {
+            session
+                .download(&remote, &local, resume)
+                .await
+                .map_err(to_py)
+        })
+    }
+
+    /// Close the connection
+    fn disconnect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let session = Arc::clone(&self.session);
+        future_into_py(py, async move { session.disconnect().await.map_err(to_py) })
+    }
+
+    fn __aenter__(slf: Py<Self>, py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+        future_into_py(py, async move { Ok(slf) })
+    }
+
+    fn __aexit__<'py>(
+        &self,
+        py: Python<'py>,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.disconnect(py)
+    }
+}
+
+/// The end of a command run by `Session.execute`
+#[pyclass(frozen, module = "reticulum_shell")]
+#[derive(Debug, Clone)]
+pub struct CommandResult {
+    status: CommandStatus,
+
+    /// Process exit code
+    #[pyo3(get)]
+    exit_code: i32,
+
+    stdout: Vec<u8>,
+
+    stderr: Vec<u8>,
+
+    /// Execution time in milliseconds
+    #[pyo3(get)]
+    execution_time_ms: u64,
+
+    /// Output exceeded the server's cap and was cut
+    #[pyo3(get)]
+    truncated: bool,
+}
+
+#[pymethods]
+impl CommandResult {
+    /// How the command ended: `"success"` (it exited by itself, with any
+    /// exit code), `"timeout"`, `"error"` (it could not be run), `"killed"`
+    /// or `"oom_killed"`
+    #[getter]
+    fn status(&self) -> &'static str {
+        match self.status {
+            CommandStatus::Success => "success",
+            CommandStatus::Timeout => "timeout",
+            CommandStatus::Error => "error",
+            CommandStatus::Killed => "killed",
+            CommandStatus::OomKilled => "oom_killed",
+        }
+    }
+
+    /// Standard output, as bytes
+    #[getter]
+    fn stdout(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    /// Standard error, as bytes
+    #[getter]
+    fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "CommandResult(status={:?}, exit_code={}, stdout=<{} bytes>, stderr=<{} bytes>)",
+            self.status(),
+            self.exit_code,
+            self.stdout.len(),
+            self.stderr.len()
+        )
+    }
+}
+
+impl From<CommandResponse> for CommandResult {
+    fn from(response: CommandResponse) -> Self {
+        Self {
+            status: response.status,
+            exit_code: response.exit_code,
+            stdout: response.stdout,
+            stderr: response.stderr,
+            execution_time_ms: response.execution_time_ms,
+            truncated: response.truncated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_result() {
+        let result = CommandResult::from(CommandResponse {
+            id: 1,
+            status: CommandStatus::OomKilled,
+            stdout: b"out".to_vec(),
+            stderr: Vec::new(),
+            exit_code: 137,
+            execution_time_ms: 20,
+            truncated: false,
+            total_bytes: 3,
+            spool_id: None,
+            delta_base: None,
+        });
+        assert_eq!(result.status(), "oom_killed");
+        assert_eq!(result.stdout(), b"out");
+        assert_eq!(
+            result.__repr__(),
+            "CommandResult(status=\"oom_killed\", exit_code=137, stdout=<3 bytes>, stderr=<0 bytes>)"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Per-client usage accounting
+//!
+//! Counts, per client identity, the sessions it opened, the commands it ran,
+//! the CPU time those commands used and the file bytes it moved. Counters
+//! live in memory and, with `accounting_file` set, are loaded at startup and
+//! written back (atomically, mode 0600) whenever a session starts or ends, so
+//! they survive restarts.
+//!
+//! CPU time comes from the command's cgroup when resource limits are
+//! configured and from `getrusage(RUSAGE_CHILDREN)` otherwise, which is
+//! approximate while several commands finish at the same time.
+
+use crate::auth::unix_time;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+/// Usage counters of one client identity
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientUsage {
+    /// Sessions opened
+    pub sessions: u64,
+
+    /// Commands executed
+    pub commands: u64,
+
+    /// CPU time used by commands (milliseconds)
+    pub cpu_time_ms: u64,
+
+    /// File bytes uploaded
+    pub bytes_uploaded: u64,
+
+    /// File bytes downloaded
+    pub bytes_downloaded: u64,
+
+    /// Unix time of the last activity
+    pub last_seen: u64,
+}
+
+/// Usage counters of all clients
+#[derive(Debug, Default)]
+pub struct Accounting {
+    /// Where counters are persisted (None = memory only)
+    path: Option<PathBuf>,
+
+    /// Counters by hex-encoded client identity
+    usage: Mutex<BTreeMap<String, ClientUsage>>,
+}
+
+impl Accounting {
+    /// Create in-memory accounting
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load (or start) the counters persisted at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        let usage = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(std::io::Error::from)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    /// Count a new session and persist the counters
+    pub fn session_started(&self, client_identity: &[u8]) {
+        self.update(client_identity, |usage| usage.sessions += 1);
+        self.save();
+    }
+
+    /// Count an executed command and its CPU time
+    pub fn command(&self, client_identity: &[u8], cpu_time: Duration) {
+        self.update(client_identity, |usage| {
+            usage.commands += 1;
+            usage.cpu_time_ms += cpu_time.as_millis() as u64;
+        });
+    }
+
+    /// Count file bytes moved in either direction
+    pub fn transferred(&self, client_identity: &[u8], uploaded: u64, downloaded: u64) {
+        self.update(client_identity, |usage| {
+            usage.bytes_uploaded += uploaded;
+            usage.bytes_downloaded += downloaded;
+        });
+    }
+
+    /// Get the counters of a client
+    pub fn get(&self, client_identity: &[u8]) -> ClientUsage {
+        self.usage
+            .lock()
+            .unwrap()
+            .get(&hex::encode(client_identity))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the counters of every client, by hex-encoded identity
+    pub fn all(&self) -> BTreeMap<String, ClientUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Write the counters to disk (if persisted), logging failures
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = self.write(path) {
+            warn!(path = %path.display(), error = %e, "Failed to save accounting");
+        }
+    }
+
+    fn update(&self, client_identity: &[u8], f: impl FnOnce(&mut ClientUsage)) {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(hex::encode(client_identity)).or_default();
+        f(entry);
+        entry.last_seen = unix_time();
+    }
+
+    /// Write through a temporary file so a crash never leaves half a file
+    fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&*self.usage.lock().unwrap())
+            .map_err(std::io::Error::from)?;
+        let tmp = path.with_extension("tmp");
+
+        let mut file = fs::File::create(&tmp)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// CPU time (user + system) used by reaped child processes so far
+#[cfg(unix)]
+pub fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: getrusage only writes into the struct we pass
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+
+    let time = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// CPU time (user + system) used by reaped child processes so far
+#[cfg(not(unix))]
+pub fn children_cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounting.json");
+
+        let accounting = Accounting::open(&path).unwrap();
+        accounting.session_started(&[0xaa]);
+        accounting.command(&[0xaa], Duration::from_millis(1500));
+        accounting.command(&[0xaa], Duration::from_millis(500));
+        accounting.transferred(&[0xaa], 100, 0);
+        accounting.transferred(&[0xbb], 0, 7);
+        accounting.save();
+
+        let reopened = Accounting::open(&path).unwrap();
+        let usage = reopened.get(&[0xaa]);
+        assert_eq!(usage.sessions, 1);
+        assert_eq!(usage.commands, 2);
+        assert_eq!(usage.cpu_time_ms, 2000);
+        assert_eq!(usage.bytes_uploaded, 100);
+        assert_eq!(reopened.get(&[0xbb]).bytes_downloaded, 7);
+        assert_eq!(reopened.get(&[0xcc]), ClientUsage::default());
+        assert_eq!(reopened.all().len(), 2);
+    }
+}
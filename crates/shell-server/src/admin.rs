@@ -0,0 +1,357 @@
+//! Admin socket for operators
+//!
+//! A Unix socket (`admin_socket`, mode 0600) answering one text command per
+//! line, e.g. with `socat - UNIX-CONNECT:shell-server.sock`:
+//!
+//! - `sessions`: one line per session
+//!   (`<session-id> <client> <commands> <running> <queued>`)
+//! - `history <session-id> [limit]`: the session's command history as JSON
+//!   lines, oldest first
+//! - `bans`: one line per banned identity or source (`<key> <seconds left>`)
+//! - `unban <key>`: lift a ban
+//! - `usage`: one line per client identity (`<client> <sessions> <commands>
+//!   <cpu-ms> <bytes-uploaded> <bytes-downloaded>`)
+//! - `approvals`: commands waiting for approval as JSON lines, oldest first
+//! - `approve <id>` / `deny <id>`: decide on a waiting command
+//! - `help`
+//!
+//! Errors are answered with a single `error: <reason>` line.
+
+use crate::accounting::Accounting;
+use crate::approval::ApprovalQueue;
+use crate::ban::BanList;
+use crate::session::Session;
+use crate::Result;
+use shell_proto::SessionId;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// Sessions shared with the server
+pub type Sessions = Arc<RwLock<HashMap<SessionId, Arc<Session>>>>;
+
+/// Server state the admin socket reports on
+#[derive(Clone)]
+pub struct AdminState {
+    /// Active sessions
+    pub sessions: Sessions,
+
+    /// Failed connect tracking and bans
+    pub bans: Arc<BanList>,
+
+    /// Per-client usage counters
+    pub accounting: Arc<Accounting>,
+
+    /// Commands waiting for approval
+    pub approvals: Arc<ApprovalQueue>,
+}
+
+const HELP: &str = "\
+sessions                      list active sessions
+history <session-id> [limit]  command history of a session (JSON lines)
+bans                          banned identities and sources
+unban <key>                   lift a ban (id:<hex> or dest:<hex>)
+usage                         usage counters per client
+approvals                     commands waiting for approval (JSON lines)
+approve <id>                  run a waiting command
+deny <id>                     refuse a waiting command
+help                          this text
+";
+
+/// Serve the admin socket at `path` until the returned task is aborted
+#[cfg(unix)]
+pub async fn serve(path: &Path, state: AdminState) -> Result<JoinHandle<()>> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+
+    // A socket left behind by an unclean exit
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "Serving admin socket");
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &state).await {
+                    debug!(error = %e, "Admin connection failed");
+                }
+            });
+        }
+    }))
+}
+
+/// The admin socket is Unix-only
+#[cfg(not(unix))]
+pub async fn serve(_path: &Path, _state: AdminState) -> Result<JoinHandle<()>> {
+    Err(crate::ServerError::Config(
+        "admin_socket is only supported on Unix".to_string(),
+    ))
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: tokio::net::UnixStream,
+    state: &AdminState,
+) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = handle_command(&line, state).await;
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Answer one command line
+async fn handle_command(line: &str, state: &AdminState) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => String::new(),
+        ["sessions"] => {
+            let sessions = state.sessions.read().await;
+            let mut lines: Vec<String> = sessions
+                .values()
+                .map(|session| {
+                    format!(
+                        "{} {} {} {} {}\n",
+                        session.id_string(),
+                        hex::encode(&session.client_identity),
+                        session.history(None).len(),
+                        session.running(),
+                        session.queued()
+                    )
+                })
+                .collect();
+            lines.sort();
+            lines.concat()
+        }
+        ["history", id, rest @ ..] if rest.len() <= 1 => {
+            let Ok(id) = Uuid::parse_str(id) else {
+                return "error: invalid session id\n".to_string();
+            };
+            let limit = match rest.first().map(|limit| limit.parse::<usize>()) {
+                Some(Ok(limit)) => Some(limit),
+                Some(Err(_)) => return "error: invalid limit\n".to_string(),
+                None => None,
+            };
+
+            let sessions = state.sessions.read().await;
+            let Some(session) = sessions.get(id.as_bytes()) else {
+                return "error: no such session\n".to_string();
+            };
+            session
+                .history(limit)
+                .iter()
+                .filter_map(|entry| serde_json::to_string(entry).ok())
+                .map(|line| line + "\n")
+                .collect()
+        }
+        ["bans"] => state
+            .bans
+            .list()
+            .into_iter()
+            .map(|(key, remaining)| format!("{} {}\n", key, remaining.as_secs()))
+            .collect(),
+        ["unban", key] => {
+            if state.bans.unban(key) {
+                info!(key = %key, "Ban lifted");
+                "ok\n".to_string()
+            } else {
+                "error: not banned\n".to_string()
+            }
+        }
+        ["usage"] => state
+            .accounting
+            .all()
+            .into_iter()
+            .map(|(client, usage)| {
+                format!(
+                    "{} {} {} {} {} {}\n",
+                    client,
+                    usage.sessions,
+                    usage.commands,
+                    usage.cpu_time_ms,
+                    usage.bytes_uploaded,
+                    usage.bytes_downloaded
+                )
+            })
+            .collect(),
+        ["approvals"] => state
+            .approvals
+            .pending()
+            .iter()
+            .filter_map(|pending| serde_json::to_string(pending).ok())
+            .map(|line| line + "\n")
+            .collect(),
+        [verb @ ("approve" | "deny"), id] => {
+            let Ok(id) = id.parse::<u64>() else {
+                return "error: invalid approval id\n".to_string();
+            };
+            if state.approvals.decide(id, *verb == "approve") {
+                "ok\n".to_string()
+            } else {
+                "error: no such approval\n".to_string()
+            }
+        }
+        ["help"] => HELP.to_string(),
+        _ => "error: unknown command (try help)\n".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::CommandExecutor;
+    use shell_proto::{CommandRequest, Message};
+    use std::time::Duration;
+
+    async fn state_with_history() -> (AdminState, String) {
+        let session = Session::new(vec![1, 2, 3], Arc::new(CommandExecutor::new(30)));
+        let request = CommandRequest {
+            id: 1,
+            command: "true".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        session
+            .handle_message(Message::CommandRequest(request))
+            .await
+            .unwrap();
+
+        let id = session.id_string();
+        let sessions = Arc::new(RwLock::new(HashMap::from([(
+            session.id,
+            Arc::new(session),
+        )])));
+        let state = AdminState {
+            sessions,
+            bans: Arc::new(BanList::new(1, Duration::from_secs(60), Duration::from_secs(600))),
+            accounting: Arc::new(Accounting::new()),
+            approvals: Arc::new(ApprovalQueue::new(Duration::from_secs(30))),
+        };
+        (state, id)
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (state, id) = state_with_history().await;
+
+        assert_eq!(
+            handle_command("sessions", &state).await,
+            format!("{} 010203 1 0 0\n", id)
+        );
+
+        let history = handle_command(&format!("history {}", id), &state).await;
+        let entry: serde_json::Value = serde_json::from_str(history.trim()).unwrap();
+        assert_eq!(entry["command"], "true");
+        assert_eq!(entry["exit_code"], 0);
+
+        assert!(handle_command(&format!("history {} 0", id), &state)
+            .await
+            .is_empty());
+        assert!(handle_command("history nope", &state)
+            .await
+            .starts_with("error:"));
+        assert!(
+            handle_command(&format!("history {}", Uuid::new_v4()), &state)
+                .await
+                .starts_with("error:")
+        );
+        assert!(handle_command("reboot", &state)
+            .await
+            .starts_with("error:"));
+
+        assert!(handle_command("bans", &state).await.is_empty());
+        state.bans.record_failure("dest:01");
+        assert!(handle_command("bans", &state)
+            .await
+            .starts_with("dest:01 "));
+        assert_eq!(handle_command("unban dest:01", &state).await, "ok\n");
+        assert!(handle_command("unban dest:01", &state)
+            .await
+            .starts_with("error:"));
+
+        assert!(handle_command("usage", &state).await.is_empty());
+        state.accounting.command(&[1, 2, 3], Duration::from_millis(5));
+        assert_eq!(handle_command("usage", &state).await, "010203 0 1 5 0 0\n");
+    }
+
+    #[tokio::test]
+    async fn test_approvals() {
+        let (state, _) = state_with_history().await;
+        assert!(handle_command("approvals", &state).await.is_empty());
+
+        let request = CommandRequest {
+            id: 2,
+            command: "reboot".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let approvals = Arc::clone(&state.approvals);
+        let waiter = tokio::spawn(async move {
+            approvals.wait([1; 16], &[1, 2, 3], &request, |_| {}).await
+        });
+        while state.approvals.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let listed = handle_command("approvals", &state).await;
+        let entry: serde_json::Value = serde_json::from_str(listed.trim()).unwrap();
+        assert_eq!(entry["command"], "reboot");
+        let id = entry["id"].as_u64().unwrap();
+
+        assert!(handle_command("approve x", &state)
+            .await
+            .starts_with("error:"));
+        assert_eq!(handle_command(&format!("deny {}", id), &state).await, "ok\n");
+        assert!(waiter.await.unwrap().is_err());
+        assert!(handle_command(&format!("approve {}", id), &state)
+            .await
+            .starts_with("error:"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("admin.sock");
+        let (state, id) = state_with_history().await;
+        let task = serve(&path, state).await.unwrap();
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"sessions\n").await.unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.starts_with(&id));
+
+        task.abort();
+    }
+}
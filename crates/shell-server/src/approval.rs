@@ -0,0 +1,225 @@
+//! Operator approval of commands
+//!
+//! Clients whose profile sets `require_approval` don't get their commands
+//! run right away: each one is parked here, the client is sent a PENDING
+//! notice, and an operator approves or denies it through the admin socket
+//! (`approvals`, `approve <id>`, `deny <id>`). Commands nobody decides on
+//! within `approval_timeout` seconds are refused.
+
+use crate::{auth::unix_time, config::ServerConfig, Result, ServerError};
+use serde::Serialize;
+use shell_proto::{CommandRequest, SessionId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::info;
+use uuid::Uuid;
+
+/// A parked command, as listed to operators
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PendingApproval {
+    /// Approval ID
+    pub id: u64,
+
+    /// Session the command came from
+    pub session_id: String,
+
+    /// Client identity (hex)
+    pub client: String,
+
+    /// Command as sent by the client
+    pub command: String,
+
+    /// Command arguments
+    pub args: Vec<String>,
+
+    /// Working directory
+    pub cwd: Option<String>,
+
+    /// When the command was parked (Unix seconds)
+    pub requested_at: u64,
+}
+
+struct Waiting {
+    info: PendingApproval,
+    decide: oneshot::Sender<bool>,
+}
+
+/// Commands waiting for an operator
+pub struct ApprovalQueue {
+    /// How long a command waits before it is refused
+    timeout: Duration,
+
+    /// Next approval ID
+    next_id: AtomicU64,
+
+    /// Parked commands by approval ID
+    waiting: Mutex<HashMap<u64, Waiting>>,
+}
+
+impl ApprovalQueue {
+    /// Create a queue refusing commands undecided after `timeout`
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: AtomicU64::new(1),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create the queue from server configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(Duration::from_secs(config.approval_timeout))
+    }
+
+    /// How long a command waits before it is refused
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Park `request` until an operator decides on it
+    ///
+    /// `notify` is called with the approval ID once the command is listed.
+    /// Dropping the returned future withdraws the command.
+    pub async fn wait(
+        &self,
+        session_id: SessionId,
+        client_identity: &[u8],
+        request: &CommandRequest,
+        notify: impl FnOnce(u64),
+    ) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (decide, decision) = oneshot::channel();
+        let info = PendingApproval {
+            id,
+            session_id: Uuid::from_bytes(session_id).to_string(),
+            client: hex::encode(client_identity),
+            command: request.command.clone(),
+            args: request.args.clone(),
+            cwd: request.working_dir.clone(),
+            requested_at: unix_time(),
+        };
+        info!(
+            approval_id = id,
+            session_id = %info.session_id,
+            command = %info.command,
+            "Command awaiting approval"
+        );
+        self.waiting.lock().unwrap().insert(id, Waiting { info, decide });
+        let _withdraw = Withdraw { queue: self, id };
+        notify(id);
+
+        match tokio::time::timeout(self.timeout, decision).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) | Ok(Err(_)) => Err(ServerError::Denied(
+                "Command was denied by the operator".to_string(),
+            )),
+            Err(_) => Err(ServerError::Denied(format!(
+                "Command was not approved within {}s",
+                self.timeout.as_secs()
+            ))),
+        }
+    }
+
+    /// Parked commands, oldest first
+    pub fn pending(&self) -> Vec<PendingApproval> {
+        let mut pending: Vec<PendingApproval> = self
+            .waiting
+            .lock()
+            .unwrap()
+            .values()
+            .map(|waiting| waiting.info.clone())
+            .collect();
+        pending.sort_by_key(|info| info.id);
+        pending
+    }
+
+    /// Approve or deny a parked command, returning false if there is none
+    /// with that ID
+    pub fn decide(&self, id: u64, approve: bool) -> bool {
+        let Some(waiting) = self.waiting.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        info!(approval_id = id, approved = approve, "Command approval decided");
+        // The session may have given up in the meantime
+        let _ = waiting.decide.send(approve);
+        true
+    }
+}
+
+/// Removes a command from the queue when its waiter goes away
+struct Withdraw<'a> {
+    queue: &'a ApprovalQueue,
+    id: u64,
+}
+
+impl Drop for Withdraw<'_> {
+    fn drop(&mut self) {
+        self.queue.waiting.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn request() -> CommandRequest {
+        CommandRequest {
+            id: 7,
+            command: "reboot".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_approve_and_deny() {
+        let queue = Arc::new(ApprovalQueue::new(Duration::from_secs(30)));
+
+        for approve in [true, false] {
+            let waiter = {
+                let queue = Arc::clone(&queue);
+                tokio::spawn(async move {
+                    queue.wait([1; 16], &[1, 2, 3], &request(), |_| {}).await
+                })
+            };
+            while queue.pending().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            let pending = queue.pending();
+            assert_eq!(pending[0].command, "reboot");
+            assert_eq!(pending[0].client, "010203");
+
+            assert!(queue.decide(pending[0].id, approve));
+            assert_eq!(waiter.await.unwrap().is_ok(), approve);
+            assert!(queue.pending().is_empty());
+            assert!(!queue.decide(pending[0].id, true));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_and_withdraw() {
+        let queue = ApprovalQueue::new(Duration::from_millis(50));
+        let mut notified = None;
+        let result = queue
+            .wait([1; 16], &[1, 2, 3], &request(), |id| notified = Some(id))
+            .await;
+        assert!(matches!(result, Err(ServerError::Denied(_))));
+        assert!(notified.is_some());
+        assert!(queue.pending().is_empty());
+
+        // A waiter that goes away takes its command with it
+        let queue = ApprovalQueue::new(Duration::from_secs(30));
+        let wait = queue.wait([1; 16], &[1, 2, 3], &request(), |_| {});
+        let _ = tokio::time::timeout(Duration::from_millis(10), wait).await;
+        assert!(queue.pending().is_empty());
+    }
+}
@@ -0,0 +1,842 @@
+//! Audit log
+//!
+//! Security-relevant events (connections, commands, file transfers) are
+//! appended to a file as JSON lines. The file is rotated once it exceeds a
+//! size limit, keeping a fixed number of old files (`audit.log.1`, ...).
+//!
+//! Records form a hash chain: each one carries a sequence number and the
+//! SHA-256 of the previous line, across rotations. Every
+//! `audit_checkpoint_interval` records the server signs the chain head with
+//! its identity, so edits, insertions and deletions are detected by
+//! [`verify`] up to the last checkpoint (and by the chain after it).
+//!
+//! The same lines can also be sent to syslog or journald (see
+//! [`crate::audit_sink`]).
+
+use crate::audit_sink::{AuditSink, AuditSinkKind};
+use crate::{config::ServerConfig, Result, ServerError};
+use reticulum_core::Identity;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use shell_proto::{CommandStatus, SessionId};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+use uuid::Uuid;
+
+/// `prev_hash` of the first record in a chain
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Domain separator for checkpoint signatures
+const CHECKPOINT_CONTEXT: &str = "reticulum-shell-audit-checkpoint";
+
+/// An audited event
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Connection accepted
+    Connect,
+
+    /// Connection accepted, reattaching to a persisted session
+    Resume,
+
+    /// Connection rejected
+    Reject { reason: String },
+
+    /// Identity or source destination banned after repeated failures
+    Ban { key: String, duration_secs: u64 },
+
+    /// Client acknowledged the connect banner
+    BannerAck,
+
+    /// Client disconnected
+    Disconnect { reason: Option<String> },
+
+    /// Command executed (or refused); secrets are logged by name only
+    Command {
+        command: String,
+        args: Vec<String>,
+        cwd: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        secrets: Vec<String>,
+        status: Option<CommandStatus>,
+        exit_code: Option<i32>,
+        duration_ms: u64,
+        stdout_bytes: u64,
+        stderr_bytes: u64,
+        error: Option<String>,
+    },
+
+    /// Builtin run in the server process (or refused)
+    Builtin {
+        command: String,
+        args: Vec<String>,
+        status: Option<CommandStatus>,
+        exit_code: Option<i32>,
+        duration_ms: u64,
+        error: Option<String>,
+    },
+
+    /// Background job started (or refused); secrets are logged by name only
+    JobStart {
+        job_id: Option<u64>,
+        command: String,
+        args: Vec<String>,
+        cwd: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        secrets: Vec<String>,
+        error: Option<String>,
+    },
+
+    /// Session environment changed (or refused); values are not logged
+    Env {
+        set: Vec<String>,
+        unset: Vec<String>,
+        error: Option<String>,
+    },
+
+    /// Interactive PTY opened (or refused)
+    PtyOpen {
+        command: Option<String>,
+        args: Vec<String>,
+        error: Option<String>,
+    },
+
+    /// Upload accepted
+    Upload {
+        transfer_id: u64,
+        path: String,
+        size: u64,
+    },
+
+    /// Upload verified and stored
+    UploadComplete {
+        transfer_id: u64,
+        size: u64,
+        sha256: String,
+    },
+
+    /// Download started
+    Download {
+        transfer_id: u64,
+        path: String,
+        size: u64,
+    },
+
+    /// Transfer refused
+    TransferDenied { path: String, error: String },
+
+    /// Program uploaded for execution (or refused)
+    ExecUpload {
+        transfer_id: Option<u64>,
+        name: String,
+        size: u64,
+        sha256: String,
+        error: Option<String>,
+    },
+
+    /// Signature over the chain head (this record's `seq` and `prev_hash`)
+    Checkpoint {
+        public_key: String,
+        signature: String,
+    },
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Position in the chain (starts at 1)
+    seq: u64,
+
+    /// SHA-256 of the previous line (hex)
+    prev_hash: String,
+
+    /// RFC 3339 timestamp
+    timestamp: String,
+
+    /// Client identity (hex); absent for server events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+
+    /// Session ID (if the event belongs to a session)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Open log file and its current size
+struct Writer {
+    file: File,
+    size: u64,
+}
+
+/// Writer plus the head of the hash chain
+struct State {
+    /// Log file (None = other sinks only)
+    writer: Option<Writer>,
+
+    /// Per sink: whether the last delivery failed (to warn once)
+    sink_failing: Vec<bool>,
+
+    /// Sequence number of the last record written
+    seq: u64,
+
+    /// Hash of the last record written
+    last_hash: String,
+
+    /// Records written since the last checkpoint
+    unsigned: u64,
+}
+
+/// Signs checkpoints
+struct Signer {
+    identity: Arc<dyn reticulum_core::Signer>,
+    interval: u64,
+}
+
+/// Append-only audit log with size-based rotation
+pub struct AuditLog {
+    /// Log file path
+    path: PathBuf,
+
+    /// Rotate once the file exceeds this many bytes
+    max_size: u64,
+
+    /// Number of rotated files to keep
+    max_files: usize,
+
+    /// Checkpoint signer (None = chain only)
+    signer: Option<Signer>,
+
+    /// Additional destinations for every record
+    sinks: Vec<Box<dyn AuditSink>>,
+
+    /// Open file and chain head (None = auditing disabled)
+    state: Mutex<Option<State>>,
+}
+
+impl AuditLog {
+    /// Open (or create) an audit log, continuing the chain of existing
+    /// records
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> Result<Self> {
+        let path = path.into();
+        let writer = Self::open_writer(&path)?;
+
+        let mut log = Self {
+            path,
+            max_size,
+            max_files,
+            signer: None,
+            sinks: Vec::new(),
+            state: Mutex::new(None),
+        };
+
+        let (seq, last_hash) = log.chain_head()?;
+        log.state = Mutex::new(Some(State {
+            writer: Some(writer),
+            sink_failing: Vec::new(),
+            seq,
+            last_hash,
+            unsigned: 0,
+        }));
+        Ok(log)
+    }
+
+    /// Sign a checkpoint every `interval` records (0 = never)
+    pub fn with_checkpoints(
+        mut self,
+        identity: Arc<dyn reticulum_core::Signer>,
+        interval: u64,
+    ) -> Self {
+        self.signer = (interval > 0).then_some(Signer { identity, interval });
+        self
+    }
+
+    /// Also send every record to `sink` (enables a disabled log)
+    pub fn with_sink(mut self, sink: Box<dyn AuditSink>) -> Self {
+        self.sinks.push(sink);
+
+        let state = self.state.get_mut().unwrap().get_or_insert_with(|| State {
+            writer: None,
+            sink_failing: Vec::new(),
+            seq: 0,
+            last_hash: GENESIS_HASH.to_string(),
+            unsigned: 0,
+        });
+        state.sink_failing.push(false);
+        self
+    }
+
+    /// Create an audit log that discards everything
+    pub fn disabled() -> Self {
+        Self {
+            path: PathBuf::new(),
+            max_size: 0,
+            max_files: 0,
+            signer: None,
+            sinks: Vec::new(),
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Open the log described by the server configuration
+    pub fn from_config(config: &ServerConfig) -> Result<Self> {
+        if !config.audit_logging {
+            return Ok(Self::disabled());
+        }
+
+        let mut log = if config.audit_sinks.contains(&AuditSinkKind::File) {
+            Self::open(
+                &config.audit_log_path,
+                config.audit_max_size,
+                config.audit_max_files,
+            )?
+        } else {
+            Self::disabled()
+        };
+
+        for kind in &config.audit_sinks {
+            log = match kind {
+                AuditSinkKind::File => log,
+                #[cfg(unix)]
+                AuditSinkKind::Syslog => log.with_sink(Box::new(
+                    crate::audit_sink::SyslogSink::new(
+                        &config.audit_syslog_socket,
+                        config.audit_syslog_facility,
+                    )?,
+                )),
+                #[cfg(unix)]
+                AuditSinkKind::Journald => log.with_sink(Box::new(
+                    crate::audit_sink::JournaldSink::new(
+                        Path::new(crate::audit_sink::JOURNALD_SOCKET),
+                        config.audit_syslog_facility,
+                    )?,
+                )),
+                #[cfg(not(unix))]
+                _ => {
+                    return Err(ServerError::Config(format!(
+                        "Audit sink {:?} is only supported on Unix",
+                        kind
+                    )))
+                }
+            };
+        }
+
+        Ok(log.with_checkpoints(config.identity.clone(), config.audit_checkpoint_interval))
+    }
+
+    /// Check whether events are being written
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().is_some()
+    }
+
+    /// Record an event
+    ///
+    /// Write failures are logged; they never fail the audited operation.
+    pub fn record(&self, client: &[u8], session_id: Option<&SessionId>, event: AuditEvent) {
+        let mut guard = self.state.lock().unwrap();
+        let Some(state) = guard.as_mut() else {
+            return;
+        };
+
+        let session_id = session_id.map(|id| Uuid::from_bytes(*id).to_string());
+        if !self.append(state, Some(hex::encode(client)), session_id, &event) {
+            return;
+        }
+
+        if let Some(signer) = &self.signer {
+            state.unsigned += 1;
+            if state.unsigned >= signer.interval {
+                self.checkpoint(state, signer);
+            }
+        }
+    }
+
+    /// Sign the current chain head now (e.g. on shutdown)
+    pub fn flush_checkpoint(&self) {
+        let mut guard = self.state.lock().unwrap();
+        if let (Some(state), Some(signer)) = (guard.as_mut(), &self.signer) {
+            if state.unsigned > 0 {
+                self.checkpoint(state, signer);
+            }
+        }
+    }
+
+    fn checkpoint(&self, state: &mut State, signer: &Signer) {
+        let signature = match signer
+            .identity
+            .sign(&checkpoint_payload(state.seq + 1, &state.last_hash))
+        {
+            Ok(signature) => signature,
+            Err(e) => {
+                // Left unsigned; the next checkpoint covers these records
+                warn!(error = %e, "Failed to sign audit checkpoint");
+                return;
+            }
+        };
+        let event = AuditEvent::Checkpoint {
+            public_key: hex::encode(signer.identity.public_key()),
+            signature: hex::encode(signature),
+        };
+
+        if self.append(state, None, None, &event) {
+            state.unsigned = 0;
+        }
+    }
+
+    /// Append one record, advancing the chain if it was written
+    fn append(
+        &self,
+        state: &mut State,
+        client: Option<String>,
+        session_id: Option<String>,
+        event: &AuditEvent,
+    ) -> bool {
+        let record = AuditRecord {
+            seq: state.seq + 1,
+            prev_hash: state.last_hash.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client,
+            session_id,
+            event,
+        };
+
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Failed to serialize audit record");
+                return false;
+            }
+        };
+        let hash = hex::encode(Sha256::digest(&line));
+
+        for (sink, failing) in self.sinks.iter().zip(state.sink_failing.iter_mut()) {
+            match sink.send(&line) {
+                Ok(()) => *failing = false,
+                Err(e) => {
+                    if !*failing {
+                        warn!(sink = sink.name(), error = %e, "Failed to send audit record");
+                    }
+                    *failing = true;
+                }
+            }
+        }
+
+        if let Some(writer) = state.writer.as_mut() {
+            line.push('\n');
+
+            if writer.size > 0 && writer.size + line.len() as u64 > self.max_size {
+                match self.rotate() {
+                    Ok(new_writer) => *writer = new_writer,
+                    Err(e) => warn!(error = %e, "Failed to rotate audit log"),
+                }
+            }
+
+            match writer.file.write_all(line.as_bytes()) {
+                Ok(()) => writer.size += line.len() as u64,
+                Err(e) => {
+                    warn!(error = %e, path = %self.path.display(), "Failed to write audit log");
+                    return false;
+                }
+            }
+        }
+
+        state.seq += 1;
+        state.last_hash = hash;
+        true
+    }
+
+    /// Find the last record on disk (the current file, or the newest rotated
+    /// one if the current file is empty)
+    fn chain_head(&self) -> Result<(u64, String)> {
+        for path in [self.path.clone(), self.rotated_path(1)] {
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let Some(line) = contents
+                .split(|&b| b == b'\n')
+                .rev()
+                .find(|line| !line.is_empty())
+            else {
+                continue;
+            };
+
+            // Records from before chaining start a fresh chain
+            let seq = serde_json::from_slice::<serde_json::Value>(line)
+                .ok()
+                .and_then(|record| record["seq"].as_u64());
+            return Ok(match seq {
+                Some(seq) => (seq, hex::encode(Sha256::digest(line))),
+                None => (0, GENESIS_HASH.to_string()),
+            });
+        }
+
+        Ok((0, GENESIS_HASH.to_string()))
+    }
+
+    /// Shift `log.N` to `log.N+1`, move the current file to `log.1` and
+    /// reopen
+    fn rotate(&self) -> Result<Writer> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        Self::open_writer(&self.path)
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        rotated_path(&self.path, n)
+    }
+
+    fn open_writer(path: &Path) -> Result<Writer> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Writer { file, size })
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        // Cover the tail of the log when the server exits
+        self.flush_checkpoint();
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn checkpoint_payload(seq: u64, prev_hash: &str) -> Vec<u8> {
+    format!("{}:{}:{}", CHECKPOINT_CONTEXT, seq, prev_hash).into_bytes()
+}
+
+/// Outcome of a successful [`verify`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Records checked
+    pub records: u64,
+
+    /// Valid checkpoints
+    pub checkpoints: u64,
+
+    /// Sequence number of the last valid checkpoint
+    pub last_checkpoint: Option<u64>,
+
+    /// Records after the last checkpoint (chained but not yet signed)
+    pub unsigned: u64,
+
+    /// Whether the oldest record starts the chain; false if older files have
+    /// been rotated away
+    pub complete: bool,
+
+    /// Lines written before chaining was enabled
+    pub unchained: u64,
+}
+
+/// List a log and its rotated files, oldest first
+pub fn log_files(path: &Path) -> Vec<PathBuf> {
+    let mut rotated: Vec<PathBuf> = (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|p| p.exists())
+        .collect();
+    rotated.reverse();
+    rotated.push(path.to_path_buf());
+    rotated
+}
+
+/// Verify the hash chain and checkpoint signatures of a log and its rotated
+/// files
+///
+/// Checkpoints must be signed by `public_key` if given; otherwise every
+/// checkpoint must carry the same key as the first one.
+pub fn verify(path: &Path, public_key: Option<&[u8]>) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut expected_key = public_key.map(hex::encode);
+    let mut prev: Option<(u64, String)> = None;
+
+    for file in log_files(path) {
+        let contents = match fs::read(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for (index, line) in contents.split(|&b| b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let fail = |reason: &str| {
+                ServerError::AuditTampered(format!(
+                    "{}:{}: {}",
+                    file.display(),
+                    index + 1,
+                    reason
+                ))
+            };
+
+            let record: serde_json::Value =
+                serde_json::from_slice(line).map_err(|_| fail("not a JSON record"))?;
+            let (Some(seq), Some(prev_hash)) =
+                (record["seq"].as_u64(), record["prev_hash"].as_str())
+            else {
+                if prev.is_some() {
+                    return Err(fail("record is not chained"));
+                }
+                report.unchained += 1;
+                continue;
+            };
+
+            match &prev {
+                Some((prev_seq, prev_line_hash)) => {
+                    if seq != prev_seq + 1 {
+                        return Err(fail(&format!("expected seq {}, found {}", prev_seq + 1, seq)));
+                    }
+                    if prev_hash != prev_line_hash {
+                        return Err(fail("hash chain broken"));
+                    }
+                }
+                None => report.complete = seq == 1 && prev_hash == GENESIS_HASH,
+            }
+
+            if record["event"] == "checkpoint" {
+                let key = record["public_key"].as_str().unwrap_or_default();
+                let expected = expected_key.get_or_insert_with(|| key.to_string());
+                if key != expected.as_str() {
+                    return Err(fail("checkpoint signed by an unexpected key"));
+                }
+
+                let key = hex::decode(key).map_err(|_| fail("invalid public key"))?;
+                let signature = record["signature"]
+                    .as_str()
+                    .and_then(|s| hex::decode(s).ok())
+                    .ok_or_else(|| fail("invalid signature"))?;
+                Identity::verify_external(&key, &checkpoint_payload(seq, prev_hash), &signature)
+                    .map_err(|_| fail("bad checkpoint signature"))?;
+
+                report.checkpoints += 1;
+                report.last_checkpoint = Some(seq);
+                report.unsigned = 0;
+            } else {
+                report.unsigned += 1;
+            }
+
+            report.records += 1;
+            prev = Some((seq, hex::encode(Sha256::digest(line))));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_event(command: &str) -> AuditEvent {
+        AuditEvent::Command {
+            command: command.to_string(),
+            args: vec!["-la".to_string()],
+            cwd: Some("/tmp".to_string()),
+            secrets: vec![],
+            status: Some(CommandStatus::Success),
+            exit_code: Some(0),
+            duration_ms: 12,
+            stdout_bytes: 100,
+            stderr_bytes: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path, 1024 * 1024, 3).unwrap();
+
+        log.record(&[0xab, 0xcd], None, AuditEvent::Connect);
+        log.record(&[0xab, 0xcd], Some(&[7u8; 16]), command_event("ls"));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "connect");
+        assert_eq!(lines[0]["client"], "abcd");
+        assert!(lines[0].get("session_id").is_none());
+        assert_eq!(lines[1]["event"], "command");
+        assert_eq!(lines[1]["command"], "ls");
+        assert_eq!(lines[1]["exit_code"], 0);
+        assert_eq!(lines[1]["stdout_bytes"], 100);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path, 1000, 2).unwrap();
+
+        for _ in 0..20 {
+            log.record(&[1], None, command_event("ls"));
+        }
+
+        assert!(path.exists());
+        assert!(dir.path().join("audit.log.1").exists());
+        assert!(dir.path().join("audit.log.2").exists());
+        assert!(!dir.path().join("audit.log.3").exists());
+        assert!(fs::metadata(&path).unwrap().len() <= 1000);
+    }
+
+    #[test]
+    fn test_chain_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let identity = Identity::generate();
+
+        let log = AuditLog::open(&path, 2000, 10)
+            .unwrap()
+            .with_checkpoints(Arc::new(identity.clone()), 5);
+        for _ in 0..12 {
+            log.record(&[1], None, command_event("ls"));
+        }
+
+        let report = verify(&path, Some(&identity.public_key())).unwrap();
+        assert_eq!(report.records, 14);
+        assert_eq!(report.checkpoints, 2);
+        assert_eq!(report.unsigned, 2);
+        assert!(report.complete);
+        assert!(dir.path().join("audit.log.1").exists());
+
+        // Dropping signs the tail
+        drop(log);
+        let report = verify(&path, Some(&identity.public_key())).unwrap();
+        assert_eq!(report.checkpoints, 3);
+        assert_eq!(report.unsigned, 0);
+
+        // Another key is rejected
+        let other = Identity::generate();
+        assert!(verify(&path, Some(&other.public_key())).is_err());
+    }
+
+    #[test]
+    fn test_chain_resumes_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        let log = AuditLog::open(&path, 1024 * 1024, 3).unwrap();
+        log.record(&[1], None, AuditEvent::Connect);
+        drop(log);
+
+        let log = AuditLog::open(&path, 1024 * 1024, 3).unwrap();
+        log.record(&[1], None, AuditEvent::Connect);
+
+        let report = verify(&path, None).unwrap();
+        assert_eq!(report.records, 2);
+        assert_eq!(report.checkpoints, 0);
+    }
+
+    #[test]
+    fn test_tampering_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let identity = Identity::generate();
+
+        let log = AuditLog::open(&path, 1024 * 1024, 3)
+            .unwrap()
+            .with_checkpoints(Arc::new(identity.clone()), 100);
+        log.record(&[1], None, command_event("ls"));
+        log.record(&[1], None, command_event("id"));
+        log.record(&[1], None, command_event("uptime"));
+        drop(log);
+
+        let original = fs::read_to_string(&path).unwrap();
+        assert!(verify(&path, None).is_ok());
+
+        // Edited record
+        fs::write(&path, original.replacen("\"id\"", "\"ps\"", 1)).unwrap();
+        assert!(matches!(verify(&path, None), Err(ServerError::AuditTampered(_))));
+
+        // Deleted record
+        let lines: Vec<&str> = original.lines().collect();
+        let without_second = [lines[0], lines[2], lines[3]].join("\n") + "\n";
+        fs::write(&path, without_second).unwrap();
+        assert!(verify(&path, None).is_err());
+
+        // Truncated tail is still a valid chain, but no longer ends signed
+        let truncated = lines[..2].join("\n") + "\n";
+        fs::write(&path, truncated).unwrap();
+        let report = verify(&path, None).unwrap();
+        assert_eq!(report.checkpoints, 0);
+        assert_eq!(report.unsigned, 2);
+    }
+
+    #[test]
+    fn test_extra_sinks() {
+        use std::sync::Arc;
+
+        struct Collect(Arc<Mutex<Vec<String>>>);
+
+        impl AuditSink for Collect {
+            fn send(&self, record: &str) -> std::io::Result<()> {
+                self.0.lock().unwrap().push(record.to_string());
+                Ok(())
+            }
+
+            fn name(&self) -> &'static str {
+                "collect"
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let records = Arc::new(Mutex::new(Vec::new()));
+
+        let log = AuditLog::open(&path, 1024 * 1024, 3)
+            .unwrap()
+            .with_sink(Box::new(Collect(Arc::clone(&records))));
+        log.record(&[1], None, AuditEvent::Connect);
+        log.record(&[1], None, command_event("ls"));
+
+        // Sinks get exactly the lines in the file
+        let file: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(*records.lock().unwrap(), file);
+
+        // Sink-only log
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::disabled().with_sink(Box::new(Collect(Arc::clone(&records))));
+        assert!(log.is_enabled());
+        log.record(&[1], None, AuditEvent::Connect);
+        assert_eq!(records.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let log = AuditLog::disabled();
+        assert!(!log.is_enabled());
+        log.record(&[1], None, AuditEvent::Connect);
+    }
+}
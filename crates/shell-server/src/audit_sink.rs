@@ -0,0 +1,265 @@
+//! Audit sinks besides the log file
+//!
+//! Records can additionally be sent to the local syslog daemon or to
+//! journald over their datagram sockets, so they reach centralized logging
+//! directly. Delivery is best effort: the sockets are non-blocking and a
+//! record that can't be sent right away is dropped with a warning.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Identifier attached to every record
+const IDENTIFIER: &str = "reticulum-shell";
+
+/// syslog/journald priority of audit records (LOG_NOTICE)
+const PRIORITY: u8 = 5;
+
+/// Where audit records go
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditSinkKind {
+    /// `audit_log_path`, hash-chained and rotated
+    File,
+
+    /// Local syslog daemon (`audit_syslog_socket`)
+    Syslog,
+
+    /// systemd-journald native protocol
+    Journald,
+}
+
+/// syslog facility for audit records
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Auth,
+    #[default]
+    Authpriv,
+    Daemon,
+    User,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    /// Numeric facility code
+    pub fn code(self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Auth => 4,
+            SyslogFacility::Authpriv => 10,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+/// Default path of the journald native socket
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Destination for serialized audit records
+pub trait AuditSink: Send + Sync {
+    /// Deliver one record (a single JSON line without the newline)
+    fn send(&self, record: &str) -> io::Result<()>;
+
+    /// Name used in warnings
+    fn name(&self) -> &'static str;
+}
+
+/// Datagram socket that is not bound to a peer, so the daemon can restart
+#[cfg(unix)]
+struct Datagram {
+    socket: std::os::unix::net::UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Datagram {
+    fn new(path: &Path) -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.socket.send_to(data, &self.path).map(|_| ())
+    }
+}
+
+/// RFC 3164 messages to a local syslog socket (usually `/dev/log`)
+#[cfg(unix)]
+pub struct SyslogSink {
+    socket: Datagram,
+    facility: SyslogFacility,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    /// Create a sink writing to `path`
+    pub fn new(path: &Path, facility: SyslogFacility) -> io::Result<Self> {
+        Ok(Self {
+            socket: Datagram::new(path)?,
+            facility,
+        })
+    }
+
+    fn format(&self, record: &str) -> String {
+        // The local daemon adds timestamp and hostname
+        format!(
+            "<{}>{}[{}]: {}",
+            self.facility.code() * 8 + PRIORITY,
+            IDENTIFIER,
+            std::process::id(),
+            record
+        )
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for SyslogSink {
+    fn send(&self, record: &str) -> io::Result<()> {
+        self.socket.send(self.format(record).as_bytes())
+    }
+
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+}
+
+/// journald native protocol (newline-separated `FIELD=value` datagrams)
+#[cfg(unix)]
+pub struct JournaldSink {
+    socket: Datagram,
+    facility: SyslogFacility,
+}
+
+#[cfg(unix)]
+impl JournaldSink {
+    /// Create a sink writing to the journald socket at `path`
+    pub fn new(path: &Path, facility: SyslogFacility) -> io::Result<Self> {
+        Ok(Self {
+            socket: Datagram::new(path)?,
+            facility,
+        })
+    }
+
+    fn format(&self, record: &str) -> String {
+        let mut message = format!(
+            "MESSAGE={}\nPRIORITY={}\nSYSLOG_FACILITY={}\nSYSLOG_IDENTIFIER={}\n",
+            record,
+            PRIORITY,
+            self.facility.code(),
+            IDENTIFIER
+        );
+
+        // Structured copies of the main fields, for journalctl filtering
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(record) {
+            for (key, field) in [
+                ("event", "RETICULUM_EVENT"),
+                ("client", "RETICULUM_CLIENT"),
+                ("session_id", "RETICULUM_SESSION"),
+                ("command", "RETICULUM_COMMAND"),
+            ] {
+                if let Some(value) = fields.get(key).and_then(|v| v.as_str()) {
+                    if !value.contains('\n') {
+                        message.push_str(&format!("{}={}\n", field, value));
+                    }
+                }
+            }
+        }
+
+        message
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for JournaldSink {
+    fn send(&self, record: &str) -> io::Result<()> {
+        self.socket.send(self.format(record).as_bytes())
+    }
+
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_sink_config() {
+        #[derive(Deserialize)]
+        struct Config {
+            sinks: Vec<AuditSinkKind>,
+            facility: SyslogFacility,
+        }
+
+        let config: Config =
+            toml::from_str("sinks = [\"file\", \"journald\"]\nfacility = \"local3\"").unwrap();
+        assert_eq!(config.sinks, vec![AuditSinkKind::File, AuditSinkKind::Journald]);
+        assert_eq!(config.facility.code(), 19);
+    }
+
+    #[test]
+    fn test_syslog_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let sink = SyslogSink::new(&path, SyslogFacility::Authpriv).unwrap();
+        sink.send(r#"{"event":"connect"}"#).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        assert!(message.starts_with("<85>reticulum-shell["));
+        assert!(message.ends_with(r#"]: {"event":"connect"}"#));
+    }
+
+    #[test]
+    fn test_journald_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let sink = JournaldSink::new(&path, SyslogFacility::Authpriv).unwrap();
+        sink.send(r#"{"event":"command","client":"abcd","command":"ls"}"#)
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        let fields: Vec<&str> = message.lines().collect();
+        assert!(fields.contains(&"SYSLOG_IDENTIFIER=reticulum-shell"));
+        assert!(fields.contains(&"PRIORITY=5"));
+        assert!(fields.contains(&"RETICULUM_EVENT=command"));
+        assert!(fields.contains(&"RETICULUM_CLIENT=abcd"));
+        assert!(fields.contains(&"RETICULUM_COMMAND=ls"));
+    }
+
+    #[test]
+    fn test_missing_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = SyslogSink::new(&dir.path().join("absent"), SyslogFacility::Daemon).unwrap();
+        assert!(sink.send("{}").is_err());
+    }
+}
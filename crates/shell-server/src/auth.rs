@@ -0,0 +1,295 @@
+//! Connect-time authentication
+//!
+//! CONNECT messages are signed by the identity they claim (see
+//! [`ConnectVerifier`]), so a peer can't pass the allowlist with someone
+//! else's public key. A timestamp window plus a nonce cache stop replays.
+//!
+//! Auth tokens are a second factor on top of the identity allowlist. A client
+//! either has a static token configured in `auth_tokens`, or, if
+//! `auth_hmac_secret` is set, must present
+//! `hex(HMAC-SHA256(secret, client public key))`, which lets an operator hand
+//! out tokens without listing every client.
+
+use crate::config::ServerConfig;
+use hmac::{Hmac, Mac};
+use reticulum_core::Identity;
+use sha2::{Digest, Sha256};
+use shell_proto::messages::ConnectMessage;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Checks the `auth_token` of CONNECT messages
+#[derive(Debug, Clone, Default)]
+pub struct TokenVerifier {
+    /// Static tokens by hex-encoded client public key
+    tokens: BTreeMap<String, String>,
+
+    /// Secret for derived tokens
+    hmac_secret: Option<Vec<u8>>,
+}
+
+impl TokenVerifier {
+    /// Build the verifier from the server configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            tokens: config
+                .auth_tokens
+                .iter()
+                .map(|(client, token)| (client.to_lowercase(), token.clone()))
+                .collect(),
+            hmac_secret: config
+                .auth_hmac_secret
+                .as_ref()
+                .map(|secret| secret.as_bytes().to_vec()),
+        }
+    }
+
+    /// Check whether `client` must present a token
+    pub fn is_required(&self, client: &[u8]) -> bool {
+        self.hmac_secret.is_some() || self.tokens.contains_key(&hex::encode(client))
+    }
+
+    /// Check the token presented by `client`
+    ///
+    /// A static token for the client takes precedence over the HMAC secret.
+    /// Clients without either always pass.
+    pub fn verify(&self, client: &[u8], token: Option<&str>) -> bool {
+        if let Some(expected) = self.tokens.get(&hex::encode(client)) {
+            return token.is_some_and(|token| constant_time_eq(token, expected));
+        }
+
+        match &self.hmac_secret {
+            Some(secret) => {
+                let Some(tag) = token.and_then(|token| hex::decode(token).ok()) else {
+                    return false;
+                };
+                let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes any key size");
+                mac.update(client);
+                mac.verify_slice(&tag).is_ok()
+            }
+            None => true,
+        }
+    }
+}
+
+/// Checks CONNECT signatures
+#[derive(Debug)]
+pub struct ConnectVerifier {
+    /// Refuse unsigned CONNECT messages
+    required: bool,
+
+    /// Maximum clock difference accepted (seconds)
+    max_skew: u64,
+
+    /// Destination hashes clients may address this server by
+    destinations: Vec<[u8; 32]>,
+
+    /// Nonces seen within the window, with their timestamps
+    seen: Mutex<HashMap<[u8; 16], u64>>,
+}
+
+impl ConnectVerifier {
+    /// Build the verifier from the server configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            required: config.require_signed_connect,
+            max_skew: config.connect_max_skew,
+            destinations: vec![config.identity.destination_hash()],
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also accept CONNECT messages addressed to `destination`
+    pub fn add_destination(&mut self, destination: [u8; 32]) {
+        self.destinations.push(destination);
+    }
+
+    /// Check that `connect` was signed by its `client_identity` recently, for
+    /// this server, and hasn't been seen before
+    ///
+    /// Unsigned messages pass only if signatures aren't required.
+    pub fn verify(&self, connect: &ConnectMessage) -> std::result::Result<(), String> {
+        if connect.signature.is_empty() {
+            return if self.required {
+                Err("CONNECT is not signed".to_string())
+            } else {
+                Ok(())
+            };
+        }
+
+        Identity::verify_external(
+            &connect.client_identity,
+            &connect.signing_payload(),
+            &connect.signature,
+        )
+        .map_err(|_| "Invalid CONNECT signature".to_string())?;
+
+        if !self.destinations.contains(&connect.server_destination) {
+            return Err("CONNECT is addressed to another server".to_string());
+        }
+
+        let now = unix_time();
+        if connect.timestamp.abs_diff(now) > self.max_skew {
+            return Err("CONNECT timestamp outside the allowed window".to_string());
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        // Nonces older than the window can't be replayed anyway
+        seen.retain(|_, timestamp| timestamp.abs_diff(now) <= self.max_skew);
+        if seen.insert(connect.nonce, connect.timestamp).is_some() {
+            return Err("Replayed CONNECT".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Current Unix time in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Derive the token for `client` from an HMAC secret
+pub fn hmac_token(secret: &str, client: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(client);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Compare digests so timing doesn't reveal how much of the token matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes()) == Sha256::digest(b.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_connect(
+        config: &ServerConfig,
+        identity: &Identity,
+        timestamp: u64,
+        nonce: u8,
+    ) -> ConnectMessage {
+        let mut connect = ConnectMessage {
+            protocol_version: shell_proto::CURRENT_PROTOCOL_VERSION,
+            client_identity: identity.public_key(),
+            capabilities: vec![],
+            auth_token: None,
+            timestamp,
+            nonce: [nonce; 16],
+            signature: vec![],
+            resume_token: None,
+            noise: None,
+            server_destination: config.identity.destination_hash(),
+        };
+        connect.signature = identity.sign(&connect.signing_payload());
+        connect
+    }
+
+    #[test]
+    fn test_connect_signature() {
+        let config = ServerConfig::default();
+        let verifier = ConnectVerifier::from_config(&config);
+        let identity = Identity::generate();
+        let now = unix_time();
+        let signed_connect =
+            |identity, timestamp, nonce| signed_connect(&config, identity, timestamp, nonce);
+
+        assert!(verifier.verify(&signed_connect(&identity, now, 1)).is_ok());
+
+        // Replay
+        assert!(verifier.verify(&signed_connect(&identity, now, 1)).is_err());
+
+        // Claiming another identity
+        let mut forged = signed_connect(&identity, now, 2);
+        forged.client_identity = Identity::generate().public_key();
+        assert!(verifier.verify(&forged).is_err());
+
+        // Tampered after signing
+        let mut tampered = signed_connect(&identity, now, 3);
+        tampered.auth_token = Some("token".to_string());
+        assert!(verifier.verify(&tampered).is_err());
+
+        // Stale
+        assert!(verifier.verify(&signed_connect(&identity, now - 3600, 4)).is_err());
+
+        // Unsigned
+        let mut unsigned = signed_connect(&identity, now, 5);
+        unsigned.signature.clear();
+        assert!(verifier.verify(&unsigned).is_err());
+
+        let mut config = ServerConfig::default();
+        config.require_signed_connect = false;
+        assert!(ConnectVerifier::from_config(&config).verify(&unsigned).is_ok());
+    }
+
+    #[test]
+    fn test_connect_destination() {
+        let (config, other) = (ServerConfig::default(), ServerConfig::default());
+        let mut verifier = ConnectVerifier::from_config(&config);
+        let identity = Identity::generate();
+        let now = unix_time();
+
+        // Signed for another server
+        assert!(verifier
+            .verify(&signed_connect(&other, &identity, now, 1))
+            .is_err());
+
+        // Addressed by a destination of the server's interfaces
+        let mut connect = signed_connect(&config, &identity, now, 2);
+        connect.server_destination = [5; 32];
+        connect.signature = identity.sign(&connect.signing_payload());
+        assert!(verifier.verify(&connect).is_err());
+        verifier.add_destination([5; 32]);
+        connect.nonce = [3; 16];
+        connect.signature = identity.sign(&connect.signing_payload());
+        assert!(verifier.verify(&connect).is_ok());
+    }
+
+    #[test]
+    fn test_static_tokens() {
+        let mut config = ServerConfig::default();
+        config
+            .auth_tokens
+            .insert("0102".to_string(), "s3cret".to_string());
+        let verifier = TokenVerifier::from_config(&config);
+
+        assert!(verifier.is_required(&[1, 2]));
+        assert!(verifier.verify(&[1, 2], Some("s3cret")));
+        assert!(!verifier.verify(&[1, 2], Some("wrong")));
+        assert!(!verifier.verify(&[1, 2], None));
+
+        // Clients without a token are unaffected
+        assert!(!verifier.is_required(&[3, 4]));
+        assert!(verifier.verify(&[3, 4], None));
+    }
+
+    #[test]
+    fn test_hmac_tokens() {
+        let mut config = ServerConfig::default();
+        config.auth_hmac_secret = Some("fleet-secret".to_string());
+        config
+            .auth_tokens
+            .insert("0102".to_string(), "static".to_string());
+        let verifier = TokenVerifier::from_config(&config);
+
+        let token = hmac_token("fleet-secret", &[3, 4]);
+        assert!(verifier.is_required(&[3, 4]));
+        assert!(verifier.verify(&[3, 4], Some(&token)));
+        assert!(!verifier.verify(&[5, 6], Some(&token)));
+        assert!(!verifier.verify(&[3, 4], Some("not hex")));
+        assert!(!verifier.verify(&[3, 4], None));
+
+        // Static token wins over the derived one
+        assert!(verifier.verify(&[1, 2], Some("static")));
+        assert!(!verifier.verify(&[1, 2], Some(&hmac_token("fleet-secret", &[1, 2]))));
+    }
+}
@@ -0,0 +1,195 @@
+//! Temporary bans after repeated connection failures
+//!
+//! Every failed CONNECT counts against the source destination it came from
+//! and, once the signature proved the client holds it, against the claimed
+//! identity. A key with `ban_threshold` failures within `ban_window` seconds
+//! is banned for `ban_duration` seconds; CONNECTs from banned keys are
+//! rejected before any other check. Unverified identities are never charged,
+//! so nobody can get someone else's identity banned by forging CONNECTs.
+//!
+//! Keys are `id:<hex public key>` and `dest:<hex destination hash>`.
+
+use crate::config::ServerConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Failure counts and active bans
+#[derive(Debug)]
+pub struct BanList {
+    /// Failures that trigger a ban (0 = never ban)
+    threshold: usize,
+
+    /// Window failures are counted in
+    window: Duration,
+
+    /// How long a ban lasts
+    duration: Duration,
+
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    /// Recent failures, oldest first
+    failures: VecDeque<Instant>,
+
+    /// End of the current ban
+    banned_until: Option<Instant>,
+}
+
+impl BanList {
+    /// Create a ban list
+    pub fn new(threshold: usize, window: Duration, duration: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            duration,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a ban list from the server configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(
+            config.ban_threshold,
+            Duration::from_secs(config.ban_window),
+            Duration::from_secs(config.ban_duration),
+        )
+    }
+
+    /// Key for a client identity
+    pub fn identity_key(identity: &[u8]) -> String {
+        format!("id:{}", hex::encode(identity))
+    }
+
+    /// Key for a source destination
+    pub fn source_key(destination: &[u8]) -> String {
+        format!("dest:{}", hex::encode(destination))
+    }
+
+    /// Get the remaining ban time of `key` (None = not banned)
+    pub fn banned(&self, key: &str) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        let until = entries.get(key)?.banned_until?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Count a failure against `key`, returning true if this bans it
+    pub fn record_failure(&self, key: &str) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.to_string()).or_default();
+        while entry
+            .failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) > self.window)
+        {
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+
+        if entry.failures.len() < self.threshold {
+            return false;
+        }
+        entry.failures.clear();
+        entry.banned_until = Some(now + self.duration);
+        warn!(
+            key = %key,
+            duration_secs = self.duration.as_secs(),
+            "Too many failed connection attempts, banning"
+        );
+        true
+    }
+
+    /// Forget the failures of `key`, e.g. after a successful connect
+    pub fn clear_failures(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(key) {
+            entry.failures.clear();
+        }
+    }
+
+    /// Lift the ban on `key`, returning whether it was banned
+    pub fn unban(&self, key: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(key)
+            .is_some_and(|entry| entry.banned_until.is_some_and(|until| until > Instant::now()))
+    }
+
+    /// Active bans and their remaining time, sorted by key
+    pub fn list(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| {
+            entry.banned_until.is_some_and(|until| until > now) || !entry.failures.is_empty()
+        });
+
+        let mut bans: Vec<(String, Duration)> = entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let remaining = entry.banned_until?.checked_duration_since(now)?;
+                Some((key.clone(), remaining))
+            })
+            .collect();
+        bans.sort();
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_after_threshold() {
+        let bans = BanList::new(3, Duration::from_secs(60), Duration::from_secs(600));
+        let key = BanList::identity_key(&[0xaa]);
+
+        assert!(!bans.record_failure(&key));
+        assert!(!bans.record_failure(&key));
+        assert!(bans.banned(&key).is_none());
+        assert!(bans.record_failure(&key));
+        assert!(bans.banned(&key).is_some());
+        assert_eq!(bans.list().len(), 1);
+        assert_eq!(bans.list()[0].0, "id:aa");
+
+        // Other keys are unaffected
+        assert!(bans.banned(&BanList::source_key(&[0xaa])).is_none());
+
+        assert!(bans.unban(&key));
+        assert!(bans.banned(&key).is_none());
+        assert!(!bans.unban(&key));
+    }
+
+    #[test]
+    fn test_clear_and_disabled() {
+        let bans = BanList::new(2, Duration::from_secs(60), Duration::from_secs(600));
+        bans.record_failure("dest:01");
+        bans.clear_failures("dest:01");
+        assert!(!bans.record_failure("dest:01"));
+
+        let disabled = BanList::new(0, Duration::from_secs(60), Duration::from_secs(600));
+        for _ in 0..10 {
+            assert!(!disabled.record_failure("dest:01"));
+        }
+        assert!(disabled.list().is_empty());
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let bans = BanList::new(1, Duration::from_secs(60), Duration::ZERO);
+        assert!(bans.record_failure("dest:01"));
+        assert!(bans.banned("dest:01").is_none());
+        assert!(bans.list().is_empty());
+    }
+}
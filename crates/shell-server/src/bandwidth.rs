@@ -0,0 +1,152 @@
+//! Bandwidth limits for bulk traffic
+//!
+//! File transfers and fetched output (spooled output, job output) can fill
+//! a narrow I2P tunnel and leave interactive commands waiting behind them.
+//! `bandwidth_limit` caps the bytes per second all sessions together move
+//! this way, `session_bandwidth_limit` the bytes of each session. Both are
+//! token buckets holding up to a second's worth of traffic.
+//!
+//! Bulk replies that exceed the budget are held back rather than refused:
+//! the session sends them once the buckets have refilled, while everything
+//! else (command responses, PTY data, forwarding) goes out unthrottled.
+//! Upload chunks count too; their acknowledgements are held back, which
+//! paces the client.
+
+use crate::config::ServerConfig;
+use shell_proto::MAX_CHUNK_SIZE;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at a fixed rate
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+
+    /// Most bytes the bucket holds
+    burst: u64,
+
+    /// Tokens (negative when in debt) and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket passing `rate` bytes per second
+    pub fn new(rate: u64) -> Self {
+        // A chunk must always fit, or it would never go out
+        let burst = rate.max(MAX_CHUNK_SIZE as u64);
+        Self {
+            rate,
+            burst,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// sending them
+    ///
+    /// The bucket may go into debt; later callers then wait behind this
+    /// one, so concurrent senders share the rate fairly.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, counted_at) = &mut *state;
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(*counted_at).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        *counted_at = now;
+
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate as f64)
+        }
+    }
+}
+
+/// The buckets one session's bulk traffic passes through
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimiter {
+    /// Shared by all sessions (None = unlimited)
+    global: Option<Arc<TokenBucket>>,
+
+    /// This session's own (None = unlimited)
+    session: Option<Arc<TokenBucket>>,
+}
+
+impl BandwidthLimiter {
+    /// Create the global bucket, if configured
+    pub fn global_from_config(config: &ServerConfig) -> Option<Arc<TokenBucket>> {
+        (config.bandwidth_limit > 0).then(|| Arc::new(TokenBucket::new(config.bandwidth_limit)))
+    }
+
+    /// Create a session's limiter, with its own bucket if configured
+    pub fn for_session(config: &ServerConfig) -> Self {
+        Self {
+            global: None,
+            session: (config.session_bandwidth_limit > 0)
+                .then(|| Arc::new(TokenBucket::new(config.session_bandwidth_limit))),
+        }
+    }
+
+    /// The bucket shared with other sessions
+    pub fn global(&self) -> Option<Arc<TokenBucket>> {
+        self.global.clone()
+    }
+
+    /// Draw on `global`, the bucket shared with other sessions, as well
+    pub fn with_global(mut self, global: Option<Arc<TokenBucket>>) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// Account for `bytes` of bulk traffic, returning how long to hold it
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        [&self.global, &self.session]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.reserve(bytes))
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let chunk = MAX_CHUNK_SIZE as u64;
+        let bucket = TokenBucket::new(chunk);
+
+        // The burst goes out at once, then senders wait their turn
+        assert_eq!(bucket.reserve(chunk), Duration::ZERO);
+        let first = bucket.reserve(chunk);
+        let second = bucket.reserve(chunk);
+        assert!(first > Duration::from_millis(900) && first <= Duration::from_secs(1));
+        assert!(second > Duration::from_millis(1900) && second <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut config = ServerConfig::default();
+        assert!(BandwidthLimiter::global_from_config(&config).is_none());
+        assert_eq!(
+            BandwidthLimiter::for_session(&config).reserve(u64::MAX),
+            Duration::ZERO
+        );
+
+        let chunk = MAX_CHUNK_SIZE as u64;
+        config.bandwidth_limit = chunk;
+        config.session_bandwidth_limit = 4 * chunk;
+        let global = BandwidthLimiter::global_from_config(&config);
+        let first = BandwidthLimiter::for_session(&config).with_global(global.clone());
+        let second = BandwidthLimiter::for_session(&config).with_global(global);
+
+        // Each session has room, but they share the global rate
+        assert_eq!(first.reserve(chunk), Duration::ZERO);
+        assert!(second.reserve(chunk) > Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,344 @@
+//! Built-in commands
+//!
+//! A few inspection commands implemented in the server itself, so they work
+//! on minimal systems without coreutils. They are addressed with an `@`
+//! prefix (`@sysinfo`, `@df /var`, ...) to keep them apart from binaries of
+//! the same name, pass through the command policy like any other command and
+//! are audited as `builtin` events.
+//!
+//! Builtins run inside the server process, so they are unavailable to
+//! sandboxed or seccomp-confined profiles.
+
+use shell_proto::{CommandRequest, CommandResponse, CommandStatus};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Instant;
+
+/// Prefix that marks a builtin
+pub const PREFIX: char = '@';
+
+/// Names of all builtins (without the prefix)
+pub const BUILTINS: &[&str] = &["sysinfo", "uptime", "df", "env", "pwd", "whoami"];
+
+/// Check whether `command` names a builtin
+pub fn is_builtin(command: &str) -> bool {
+    command
+        .strip_prefix(PREFIX)
+        .is_some_and(|name| BUILTINS.contains(&name))
+}
+
+/// Run a builtin
+///
+/// Failures are reported like a failed process: exit code 1 and a message on
+/// stderr.
+pub fn run(request: &CommandRequest) -> CommandResponse {
+    let start = Instant::now();
+    let name = request.command.trim_start_matches(PREFIX);
+
+    let result = match name {
+        "sysinfo" => sysinfo(),
+        "uptime" => uptime(),
+        "df" => df(&request.args),
+        "env" => Ok(env(request)),
+        "pwd" => pwd(request),
+        "whoami" => whoami(),
+        _ => Err(format!("{}: unknown builtin", request.command)),
+    };
+
+    let (status, exit_code, stdout, stderr) = match result {
+        Ok(output) => (CommandStatus::Success, 0, output, String::new()),
+        Err(e) => (CommandStatus::Error, 1, String::new(), format!("{}\n", e)),
+    };
+
+    CommandResponse {
+        id: request.id,
+        status,
+        total_bytes: (stdout.len() + stderr.len()) as u64,
+        spool_id: None,
+        stdout: stdout.into_bytes(),
+        stderr: stderr.into_bytes(),
+        exit_code,
+        execution_time_ms: start.elapsed().as_millis() as u64,
+        truncated: false,
+        delta_base: None,
+    }
+}
+
+fn sysinfo() -> Result<String, String> {
+    let mut out = String::new();
+    let _ = writeln!(out, "hostname: {}", hostname().unwrap_or_else(|| "unknown".to_string()));
+    let _ = writeln!(out, "os:       {} ({})", std::env::consts::OS, std::env::consts::ARCH);
+    if let Some(kernel) = kernel_release() {
+        let _ = writeln!(out, "kernel:   {}", kernel);
+    }
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let _ = writeln!(out, "cpus:     {}", cpus);
+    if let Some((total, available)) = memory() {
+        let _ = writeln!(
+            out,
+            "memory:   {} total, {} available",
+            human_size(total),
+            human_size(available)
+        );
+    }
+    if let Ok(uptime) = uptime() {
+        let _ = write!(out, "uptime:   {}", uptime);
+    }
+    Ok(out)
+}
+
+fn uptime() -> Result<String, String> {
+    let uptime = std::fs::read_to_string("/proc/uptime")
+        .map_err(|_| "uptime: not available on this system".to_string())?;
+    let seconds = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| "uptime: unreadable /proc/uptime".to_string())? as u64;
+
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    let mut out = format!("up {} days, {:02}:{:02}", days, hours, minutes);
+
+    if let Ok(loadavg) = std::fs::read_to_string("/proc/loadavg") {
+        let load: Vec<&str> = loadavg.split_whitespace().take(3).collect();
+        let _ = write!(out, ", load average: {}", load.join(" "));
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+fn df(args: &[String]) -> Result<String, String> {
+    let paths: Vec<&str> = if args.is_empty() {
+        vec!["/"]
+    } else {
+        args.iter().map(String::as_str).collect()
+    };
+
+    let mut out = format!("{:<24} {:>10} {:>10} {:>10} {:>5}\n", "Path", "Size", "Used", "Avail", "Use%");
+    for path in paths {
+        let (total, free, available) = disk_usage(Path::new(path))
+            .map_err(|e| format!("df: {}: {}", path, e))?;
+        let used = total.saturating_sub(free);
+        // Like df: percentage of the space available to unprivileged users
+        let usable = used + available;
+        let percent = if usable == 0 { 0 } else { (used * 100).div_ceil(usable) };
+        let _ = writeln!(
+            out,
+            "{:<24} {:>10} {:>10} {:>10} {:>4}%",
+            path,
+            human_size(total),
+            human_size(used),
+            human_size(available),
+            percent
+        );
+    }
+    Ok(out)
+}
+
+/// The environment commands are started with (the request's variables only)
+fn env(request: &CommandRequest) -> String {
+    let mut vars: Vec<(&String, &String)> = request.env.iter().flatten().collect();
+    vars.sort();
+    vars.iter().map(|(key, value)| format!("{}={}\n", key, value)).collect()
+}
+
+/// The directory commands are started in
+fn pwd(request: &CommandRequest) -> Result<String, String> {
+    let dir = match &request.working_dir {
+        Some(dir) => std::fs::canonicalize(dir).map_err(|e| format!("pwd: {}: {}", dir, e))?,
+        None => std::env::current_dir().map_err(|e| format!("pwd: {}", e))?,
+    };
+    Ok(format!("{}\n", dir.display()))
+}
+
+fn whoami() -> Result<String, String> {
+    user_name()
+        .map(|name| format!("{}\n", name))
+        .ok_or_else(|| "whoami: cannot determine user".to_string())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T", "P"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+/// Total and available memory in bytes
+fn memory() -> Option<(u64, u64)> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kb| kb.parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some((field("MemTotal:")?, field("MemAvailable:")?))
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    uname().map(|(nodename, _)| nodename)
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(unix)]
+fn kernel_release() -> Option<String> {
+    uname().map(|(_, release)| release)
+}
+
+#[cfg(not(unix))]
+fn kernel_release() -> Option<String> {
+    None
+}
+
+/// Node name and kernel release
+#[cfg(unix)]
+fn uname() -> Option<(String, String)> {
+    use std::ffi::CStr;
+
+    let mut info: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut info) } != 0 {
+        return None;
+    }
+    let field = |chars: &[libc::c_char]| unsafe {
+        CStr::from_ptr(chars.as_ptr()).to_string_lossy().into_owned()
+    };
+    Some((field(&info.nodename), field(&info.release)))
+}
+
+/// Total, free and available-to-unprivileged bytes of the filesystem at `path`
+#[cfg(unix)]
+fn disk_usage(path: &Path) -> std::io::Result<(u64, u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let block = stat.f_frsize as u64;
+    Ok((
+        stat.f_blocks as u64 * block,
+        stat.f_bfree as u64 * block,
+        stat.f_bavail as u64 * block,
+    ))
+}
+
+#[cfg(not(unix))]
+fn disk_usage(_path: &Path) -> std::io::Result<(u64, u64, u64)> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn user_name() -> Option<String> {
+    use std::ffi::CStr;
+
+    let uid = unsafe { libc::geteuid() };
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+
+    let rc = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc == 0 && !result.is_null() {
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        return Some(name.to_string_lossy().into_owned());
+    }
+
+    // No passwd entry (common in containers)
+    Some(uid.to_string())
+}
+
+#[cfg(not(unix))]
+fn user_name() -> Option<String> {
+    std::env::var("USERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn request(command: &str, args: &[&str]) -> CommandRequest {
+        CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    #[test]
+    fn test_is_builtin() {
+        assert!(is_builtin("@sysinfo"));
+        assert!(is_builtin("@df"));
+        assert!(!is_builtin("df"));
+        assert!(!is_builtin("@rm"));
+    }
+
+    #[test]
+    fn test_env_and_pwd() {
+        let mut req = request("@env", &[]);
+        req.env = Some(BTreeMap::from([
+            ("B".to_string(), "2".to_string()),
+            ("A".to_string(), "1".to_string()),
+        ]));
+        let response = run(&req);
+        assert_eq!(response.status, CommandStatus::Success);
+        assert_eq!(String::from_utf8_lossy(&response.stdout), "A=1\nB=2\n");
+
+        let mut req = request("@pwd", &[]);
+        req.working_dir = Some("/".to_string());
+        assert_eq!(String::from_utf8_lossy(&run(&req).stdout), "/\n");
+
+        req.working_dir = Some("/nonexistent".to_string());
+        let response = run(&req);
+        assert_eq!(response.exit_code, 1);
+        assert!(!response.stderr.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_system_builtins() {
+        let response = run(&request("@sysinfo", &[]));
+        assert_eq!(response.status, CommandStatus::Success);
+        assert!(String::from_utf8_lossy(&response.stdout).contains("cpus:"));
+
+        let response = run(&request("@df", &["/"]));
+        assert_eq!(response.status, CommandStatus::Success);
+        assert!(String::from_utf8_lossy(&response.stdout).lines().count() == 2);
+
+        let response = run(&request("@whoami", &[]));
+        assert_eq!(response.status, CommandStatus::Success);
+        assert!(!response.stdout.is_empty());
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(10 * 1024 * 1024 * 1024), "10.0G");
+    }
+}
@@ -0,0 +1,251 @@
+//! Transient cgroups for resource limits (cgroup v2, Linux only)
+//!
+//! Each spawned command gets its own cgroup under a parent directory the
+//! server can write to. The child joins it between fork and exec, so limits
+//! apply from the first instruction and are inherited by everything it starts.
+
+use crate::{Result, ServerError};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+use tracing::{debug, warn};
+
+/// Length of a cgroup v2 CPU period (microseconds)
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// How long removal waits for killed processes to exit
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resource limits for spawned commands
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CgroupConfig {
+    /// Parent cgroup (must be on a cgroup v2 hierarchy and writable)
+    #[serde(default = "default_parent")]
+    pub parent: PathBuf,
+
+    /// Memory limit (bytes); the command is OOM-killed above it
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+
+    /// CPU limit in percent of one CPU (e.g. 50 = half a core, 200 = two cores)
+    #[serde(default)]
+    pub cpu_percent: Option<u32>,
+
+    /// Maximum number of processes/threads
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        Self {
+            parent: default_parent(),
+            memory_max: None,
+            cpu_percent: None,
+            pids_max: None,
+        }
+    }
+}
+
+fn default_parent() -> PathBuf {
+    PathBuf::from("/sys/fs/cgroup/reticulum-shell")
+}
+
+/// A cgroup that lives as long as one command
+///
+/// The cgroup is removed on drop; any process still in it is killed first.
+#[derive(Debug)]
+pub struct TransientCgroup {
+    /// Cgroup directory
+    path: PathBuf,
+}
+
+impl TransientCgroup {
+    /// Create a cgroup under `config.parent` and apply the limits
+    pub fn create(config: &CgroupConfig, name: &str) -> Result<Self> {
+        if cfg!(not(target_os = "linux")) {
+            return Err(ServerError::Config(
+                "cgroup limits are only supported on Linux".to_string(),
+            ));
+        }
+
+        fs::create_dir_all(&config.parent).map_err(|e| {
+            ServerError::Execution(format!(
+                "Failed to create cgroup {}: {}",
+                config.parent.display(),
+                e
+            ))
+        })?;
+
+        // Delegate the controllers we need; fails harmlessly if already enabled
+        // or managed by someone else
+        let _ = fs::write(
+            config.parent.join("cgroup.subtree_control"),
+            "+memory +cpu +pids",
+        );
+
+        let path = config.parent.join(name);
+        fs::create_dir(&path).map_err(|e| {
+            ServerError::Execution(format!("Failed to create cgroup {}: {}", path.display(), e))
+        })?;
+        let cgroup = Self { path };
+
+        if let Some(bytes) = config.memory_max {
+            cgroup.write("memory.max", &bytes.to_string())?;
+            // Without swap the limit triggers the OOM killer instead of paging
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        if let Some(percent) = config.cpu_percent {
+            let quota = CPU_PERIOD_US * percent.max(1) as u64 / 100;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))?;
+        }
+        if let Some(pids) = config.pids_max {
+            cgroup.write("pids.max", &pids.to_string())?;
+        }
+
+        debug!(cgroup = %cgroup.path.display(), "Created transient cgroup");
+        Ok(cgroup)
+    }
+
+    /// Configure `cmd` to join this cgroup before exec
+    #[cfg(target_os = "linux")]
+    pub fn attach(&self, cmd: &mut TokioCommand) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let procs = CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())
+            .unwrap_or_default();
+
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing "0" moves the writing process
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                let err = std::io::Error::last_os_error();
+                libc::close(fd);
+                if written != 1 {
+                    return Err(err);
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// cgroups are Linux-only; [`TransientCgroup::create`] fails elsewhere
+    #[cfg(not(target_os = "linux"))]
+    pub fn attach(&self, _cmd: &mut TokioCommand) {}
+
+    /// Check whether the OOM killer fired inside this cgroup
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events"))
+            .map(|events| {
+                events.lines().any(|line| {
+                    let mut fields = line.split_whitespace();
+                    fields.next() == Some("oom_kill")
+                        && fields.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0) > 0
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Check whether any process is still in this cgroup
+    fn populated(&self) -> bool {
+        fs::read_to_string(self.path.join("cgroup.events"))
+            .map(|events| events.lines().any(|line| line == "populated 1"))
+            .unwrap_or(false)
+    }
+
+    /// Get the CPU time used inside this cgroup
+    pub fn cpu_time(&self) -> Option<Duration> {
+        let stat = fs::read_to_string(self.path.join("cpu.stat")).ok()?;
+        stat.lines().find_map(|line| {
+            let usec = line.strip_prefix("usage_usec ")?.trim().parse().ok()?;
+            Some(Duration::from_micros(usec))
+        })
+    }
+
+    /// Get the cgroup directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value).map_err(|e| {
+            ServerError::Execution(format!("Failed to set {} = {}: {}", file, value, e))
+        })
+    }
+}
+
+impl Drop for TransientCgroup {
+    fn drop(&mut self) {
+        // Kill leftovers (e.g. after a timeout) so the directory can go
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        // The kill is asynchronous and the directory can't go while populated
+        let start = std::time::Instant::now();
+        while self.populated() && start.elapsed() < KILL_TIMEOUT {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if let Err(e) = fs::remove_dir(&self.path) {
+            warn!(cgroup = %self.path.display(), error = %e, "Failed to remove cgroup");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup_config() {
+        let config: CgroupConfig = toml::from_str(
+            r#"
+            memory_max = 268435456
+            cpu_percent = 50
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.parent, default_parent());
+        assert_eq!(config.memory_max, Some(256 * 1024 * 1024));
+        assert_eq!(config.cpu_percent, Some(50));
+        assert_eq!(config.pids_max, None);
+    }
+
+    #[test]
+    fn test_oom_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroup = TransientCgroup {
+            path: dir.path().join("cg"),
+        };
+        fs::create_dir(cgroup.path()).unwrap();
+
+        fs::write(cgroup.path().join("memory.events"), "low 0\noom 1\noom_kill 0\n").unwrap();
+        assert!(!cgroup.oom_killed());
+
+        fs::write(cgroup.path().join("memory.events"), "low 0\noom 2\noom_kill 1\n").unwrap();
+        assert!(cgroup.oom_killed());
+    }
+
+    #[test]
+    fn test_populated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroup = TransientCgroup {
+            path: dir.path().join("cg"),
+        };
+        fs::create_dir(cgroup.path()).unwrap();
+        assert!(!cgroup.populated());
+
+        let events = cgroup.path().join("cgroup.events");
+        fs::write(&events, "populated 1\nfrozen 0\n").unwrap();
+        assert!(cgroup.populated());
+
+        fs::write(&events, "populated 0\nfrozen 0\n").unwrap();
+        assert!(!cgroup.populated());
+        fs::remove_file(&events).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+//! Configuration checks (`--check-config`)
+//!
+//! Loading a configuration stops at the first problem. This module goes
+//! through everything it can without binding sockets or starting sessions:
+//! the TOML itself (command patterns included, as they are compiled while it
+//! is read), the identity file (or key agent), client allowlists, forwarding
+//! patterns, and the files and directories the server will use.
+//! Every problem is reported with the setting it concerns, so one run shows
+//! all that needs fixing.
+
+use crate::{config::ServerConfig, secrets::Secrets};
+use reticulum_core::Identity;
+use rsh_agent::AgentSigner;
+use std::fmt;
+use std::path::Path;
+
+/// How bad a problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The server would refuse to start or misbehave
+    Error,
+
+    /// Probably not what was meant
+    Warning,
+}
+
+/// A problem found in a configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// How bad it is
+    pub severity: Severity,
+
+    /// Setting it concerns, e.g. `profiles[1].clients[0]`
+    pub setting: String,
+
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.setting, self.message)
+    }
+}
+
+/// Problems found so far
+#[derive(Default)]
+struct Report(Vec<Problem>);
+
+impl Report {
+    fn error(&mut self, setting: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, setting.into(), message.into());
+    }
+
+    fn warning(&mut self, setting: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, setting.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, setting: String, message: String) {
+        self.0.push(Problem {
+            severity,
+            setting,
+            message,
+        });
+    }
+}
+
+/// Check the configuration file at `path` and the files it refers to
+pub fn check_file(path: &Path) -> Vec<Problem> {
+    let mut report = Report::default();
+
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            report.error("config", format!("{}: {}", path.display(), e));
+            return report.0;
+        }
+    };
+    let config: ServerConfig = match toml::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            report.error("config", e.to_string().trim_end());
+            return report.0;
+        }
+    };
+
+    match &config.identity_agent {
+        Some(socket) => check_agent(&mut report, &config, socket),
+        None => check_identity(&mut report, &config.identity_path),
+    }
+    report.0.extend(check(&config));
+    report.0
+}
+
+/// Check a parsed configuration (everything but the identity file)
+pub fn check(config: &ServerConfig) -> Vec<Problem> {
+    let mut report = Report::default();
+
+    check_clients(&mut report, config);
+    check_patterns(&mut report, config);
+    check_paths(&mut report, config);
+
+    if let Err(e) = config.validate() {
+        report.error("config", e.to_string());
+    }
+    report.0
+}
+
+/// The identity must load and stay private to the server user
+fn check_identity(report: &mut Report, path: &Path) {
+    if !path.exists() {
+        report.error(
+            "identity_path",
+            format!("{} does not exist (create it with --generate-identity)", path.display()),
+        );
+        return;
+    }
+    if let Err(e) = Identity::check_file(path) {
+        report.error("identity_path", format!("{}: {}", path.display(), e));
+    }
+    if let Some(mode) = group_or_other_access(path) {
+        report.error(
+            "identity_path",
+            format!("{} is accessible by other users (mode {:o})", path.display(), mode),
+        );
+    }
+}
+
+/// The key agent must answer and hold the identity to use
+fn check_agent(report: &mut Report, config: &ServerConfig, socket: &Path) {
+    if let Err(e) = AgentSigner::connect(socket, config.identity_agent_key.as_deref()) {
+        report.error("identity_agent", e.to_string());
+    }
+}
+
+/// Client keys must be hex-encoded public keys, or nobody would match them
+fn check_clients(report: &mut Report, config: &ServerConfig) {
+    let keys = config
+        .allowed_clients
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (format!("allowed_clients[{}]", i), key))
+        .chain(
+            config
+                .auth_tokens
+                .keys()
+                .map(|key| (format!("auth_tokens.{}", key), key)),
+        )
+        .chain(config.profiles.iter().enumerate().flat_map(|(i, profile)| {
+            profile
+                .clients
+                .iter()
+                .enumerate()
+                .map(move |(j, key)| (format!("profiles[{}].clients[{}]", i, j), key))
+        }));
+
+    for (setting, key) in keys {
+        match hex::decode(key) {
+            Ok(bytes) if bytes.len() == 32 => {}
+            Ok(bytes) => report.error(
+                setting,
+                format!("expected a 32-byte public key, got {} bytes", bytes.len()),
+            ),
+            Err(e) => report.error(setting, format!("not a hex-encoded public key: {}", e)),
+        }
+    }
+
+    for (i, profile) in config.profiles.iter().enumerate() {
+        if profile.clients.is_empty() {
+            report.warning(
+                format!("profiles[{}].clients", i),
+                format!("profile {:?} matches no clients", profile.name),
+            );
+        }
+    }
+}
+
+/// Forwarding and environment patterns
+fn check_patterns(report: &mut Report, config: &ServerConfig) {
+    let mut host_ports: Vec<(String, &[String])> = vec![
+        ("forward_allow".to_string(), config.forward_allow.as_slice()),
+        ("socks_allow".to_string(), config.socks_allow.as_slice()),
+        ("socks_deny".to_string(), config.socks_deny.as_slice()),
+    ];
+    let mut names: Vec<(String, &[String])> = vec![
+        ("env_deny".to_string(), config.env_deny.as_slice()),
+        ("session_env_deny".to_string(), config.session_env_deny.as_slice()),
+    ];
+    for (i, profile) in config.profiles.iter().enumerate() {
+        if let Some(patterns) = &profile.forward_allow {
+            host_ports.push((format!("profiles[{}].forward_allow", i), patterns.as_slice()));
+        }
+        if let Some(patterns) = &profile.socks_allow {
+            host_ports.push((format!("profiles[{}].socks_allow", i), patterns.as_slice()));
+        }
+        names.push((format!("profiles[{}].env_allow", i), profile.env_allow.as_slice()));
+    }
+
+    for (setting, patterns) in host_ports {
+        for (i, pattern) in patterns.iter().enumerate() {
+            // Bare host patterns allow every port
+            let Some((_, port)) = pattern.rsplit_once(':') else {
+                continue;
+            };
+            let valid = !port.is_empty()
+                && port.chars().all(|c| c.is_ascii_digit() || c == '*' || c == '?');
+            if !valid {
+                report.error(
+                    format!("{}[{}]", setting, i),
+                    format!("{:?}: port must be a number or glob", pattern),
+                );
+            }
+        }
+    }
+
+    for (setting, patterns) in names {
+        for (i, pattern) in patterns.iter().enumerate() {
+            if pattern.is_empty() || pattern.contains('=') {
+                report.warning(
+                    format!("{}[{}]", setting, i),
+                    format!("{:?} never matches a variable name", pattern),
+                );
+            }
+        }
+    }
+}
+
+/// Files and directories the server reads or creates
+fn check_paths(report: &mut Report, config: &ServerConfig) {
+    // Created on demand, but not if something else is in the way
+    let mut dirs = vec![("transfer_dir", Some(&config.transfer_dir))];
+    if config.session_recording {
+        dirs.push(("recording_dir", Some(&config.recording_dir)));
+    }
+    dirs.extend([
+        ("session_state_dir", config.session_state_dir.as_ref()),
+        ("session_dir_root", config.session_dir_root.as_ref()),
+        ("output_spool_dir", config.output_spool_dir.as_ref()),
+        ("job_spool_dir", config.job_spool_dir.as_ref()),
+    ]);
+    for (setting, dir) in dirs {
+        if let Some(dir) = dir.filter(|dir| dir.exists() && !dir.is_dir()) {
+            report.error(setting, format!("{} is not a directory", dir.display()));
+        }
+    }
+
+    // Resume tokens are as good as the identity for taking over a session
+    if let Some(dir) = &config.session_state_dir {
+        if let Some(mode) = group_or_other_access(dir) {
+            report.warning(
+                "session_state_dir",
+                format!("{} is accessible by other users (mode {:o})", dir.display(), mode),
+            );
+        }
+    }
+
+    // Files created in an existing directory
+    let mut files = vec![
+        ("admin_socket", config.admin_socket.as_ref()),
+        ("accounting_file", config.accounting_file.as_ref()),
+    ];
+    if config.audit_logging {
+        files.push(("audit_log_path", Some(&config.audit_log_path)));
+    }
+    for (setting, file) in files {
+        let Some(parent) = file.and_then(|file| file.parent()) else {
+            continue;
+        };
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            report.error(setting, format!("{} does not exist", parent.display()));
+        }
+    }
+
+    // Programs the server runs
+    let programs = [
+        ("shell", config.shell.as_ref()),
+        ("pre_exec_hook", config.pre_exec_hook.as_ref()),
+        ("post_exec_hook", config.post_exec_hook.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(setting, program)| Some((setting.to_string(), program?)))
+    .chain(config.profiles.iter().enumerate().filter_map(|(i, profile)| {
+        Some((format!("profiles[{}].shell", i), profile.shell.as_ref()?))
+    }));
+    for (setting, program) in programs {
+        check_program(report, setting, program);
+    }
+
+    // Paths clients are confined to
+    let mut allowed: Vec<(String, &Path)> = config
+        .fs_allowed_paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (format!("fs_allowed_paths[{}]", i), path.as_path()))
+        .chain(
+            config
+                .transfer_allowed_paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| (format!("transfer_allowed_paths[{}]", i), path.as_path())),
+        )
+        .collect();
+    for (i, profile) in config.profiles.iter().enumerate() {
+        allowed.extend(
+            profile
+                .allowed_paths
+                .iter()
+                .enumerate()
+                .map(|(j, path)| (format!("profiles[{}].allowed_paths[{}]", i, j), path.as_path())),
+        );
+        if let Some(path) = &profile.secrets_file {
+            if let Err(e) = Secrets::new(path.clone()).load() {
+                report.error(format!("profiles[{}].secrets_file", i), e.to_string());
+            }
+        }
+        if let Some(root) = profile.sandbox.as_ref().and_then(|sandbox| sandbox.root.as_ref()) {
+            if !root.is_dir() {
+                report.error(
+                    format!("profiles[{}].sandbox.root", i),
+                    format!("{} is not a directory", root.display()),
+                );
+            }
+        }
+    }
+    for (setting, path) in allowed {
+        if !path.exists() {
+            report.warning(setting, format!("{} does not exist", path.display()));
+        }
+    }
+}
+
+/// A program must be an executable file only its owner can replace
+fn check_program(report: &mut Report, setting: String, program: &Path) {
+    let metadata = match std::fs::metadata(program) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => {
+            report.error(setting, format!("{} is not a file", program.display()));
+            return;
+        }
+        Err(e) => {
+            report.error(setting, format!("{}: {}", program.display(), e));
+            return;
+        }
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o111 == 0 {
+            report.error(setting, format!("{} is not executable", program.display()));
+        } else if mode & 0o022 != 0 {
+            report.error(
+                setting,
+                format!("{} is writable by other users (mode {:o})", program.display(), mode),
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+}
+
+/// Permission bits of `path` granting group or other access, if any
+fn group_or_other_access(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).ok()?.permissions().mode() & 0o777;
+        (mode & 0o077 != 0).then_some(mode)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientProfile;
+
+    fn settings(problems: &[Problem], severity: Severity) -> Vec<&str> {
+        problems
+            .iter()
+            .filter(|problem| problem.severity == severity)
+            .map(|problem| problem.setting.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_config() {
+        let problems = check(&ServerConfig::default());
+        assert!(settings(&problems, Severity::Error).is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.allowed_clients = vec![hex::encode([1u8; 32]), "xyz".to_string()];
+        config.forward_allow = vec!["localhost:80".to_string(), "db:http".to_string()];
+        config.transfer_dir = dir.path().join("file");
+        std::fs::write(&config.transfer_dir, b"").unwrap();
+        config.fs_allowed_paths = vec![dir.path().join("missing")];
+        config.pre_exec_hook = Some(dir.path().join("hook"));
+        config.profiles.push(ClientProfile {
+            name: "ops".to_string(),
+            clients: vec![hex::encode([2u8; 16])],
+            require_approval: true,
+            ..Default::default()
+        });
+
+        let problems = check(&config);
+        let errors = settings(&problems, Severity::Error);
+        assert!(errors.contains(&"allowed_clients[1]"));
+        assert!(!errors.contains(&"allowed_clients[0]"));
+        assert!(errors.contains(&"profiles[0].clients[0]"));
+        assert!(errors.contains(&"forward_allow[1]"));
+        assert!(errors.contains(&"transfer_dir"));
+        assert!(errors.contains(&"pre_exec_hook"));
+        // require_approval without admin_socket
+        assert!(errors.contains(&"config"));
+        assert_eq!(settings(&problems, Severity::Warning), vec!["fs_allowed_paths[0]"]);
+
+        let problem = problems
+            .iter()
+            .find(|problem| problem.setting == "allowed_clients[1]")
+            .unwrap();
+        assert!(problem.to_string().starts_with("error: allowed_clients[1]: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let identity = dir.path().join("server.identity");
+        Identity::generate().save_to_file(&identity).unwrap();
+        std::fs::set_permissions(&identity, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let path = dir.path().join("server.toml");
+        std::fs::write(&path, format!("identity_path = {:?}\n", identity)).unwrap();
+        let problems = check_file(&path);
+        assert_eq!(settings(&problems, Severity::Error), vec!["identity_path"]);
+
+        std::fs::set_permissions(&identity, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(settings(&check_file(&path), Severity::Error).is_empty());
+
+        std::fs::write(&path, "max_sessions = \"many\"\n").unwrap();
+        assert_eq!(settings(&check_file(&path), Severity::Error), vec!["config"]);
+        std::fs::write(&path, "allowed_commands = [\"re:[\"]\n").unwrap();
+        assert_eq!(settings(&check_file(&path), Severity::Error), vec!["config"]);
+
+        // No agent listening
+        let socket = dir.path().join("agent.sock");
+        std::fs::write(
+            &path,
+            format!("identity_path = \"x\"\nidentity_agent = {:?}\n", socket),
+        )
+        .unwrap();
+        assert_eq!(
+            settings(&check_file(&path), Severity::Error),
+            vec!["identity_agent"]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Tab completion
+//!
+//! Answers COMPLETE_REQUEST with the paths and command names a partial word
+//! could become. Paths go through the client's [`FileService`], so only
+//! entries under its allowed roots are offered; command names are the
+//! executables on the server's `PATH` plus builtins, filtered by the command
+//! policy.
+
+use crate::{builtins, files::FileService, policy::CommandPolicy, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Most candidates returned for one request
+pub const MAX_CANDIDATES: usize = 256;
+
+/// Complete a path relative to `cwd`
+///
+/// Candidates keep the directory part of `prefix` as typed, and directories
+/// end in `/`. Hidden entries are only offered once the prefix asks for them.
+pub fn complete_path(files: &FileService, cwd: &Path, prefix: &str) -> Result<(Vec<String>, bool)> {
+    let (dir_part, partial) = match prefix.rfind('/') {
+        Some(i) => prefix.split_at(i + 1),
+        None => ("", prefix),
+    };
+    let dir = files.resolve_dir(&cwd.join(dir_part))?;
+
+    let mut candidates = BTreeSet::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(partial) || (name.starts_with('.') && !partial.starts_with('.')) {
+            continue;
+        }
+        // Follow symlinks, so a link to a directory completes like one
+        let suffix = if entry.path().is_dir() { "/" } else { "" };
+        candidates.insert(format!("{}{}{}", dir_part, name, suffix));
+    }
+
+    Ok(limit(candidates))
+}
+
+/// Complete a command name from the server's `PATH` and the builtins
+///
+/// `builtins` says whether builtins are available to the client.
+pub fn complete_command(
+    policy: &CommandPolicy,
+    builtins: bool,
+    prefix: &str,
+) -> (Vec<String>, bool) {
+    let dirs = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect())
+        .unwrap_or_default();
+    commands_in(dirs, policy, builtins, prefix)
+}
+
+fn commands_in(
+    dirs: Vec<PathBuf>,
+    policy: &CommandPolicy,
+    builtins: bool,
+    prefix: &str,
+) -> (Vec<String>, bool) {
+    let mut candidates = BTreeSet::new();
+
+    if builtins {
+        candidates.extend(
+            builtins::BUILTINS
+                .iter()
+                .map(|name| format!("{}{}", builtins::PREFIX, name))
+                .filter(|name| name.starts_with(prefix)),
+        );
+    }
+
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && is_executable(&entry.path()) {
+                candidates.insert(name);
+            }
+        }
+    }
+
+    candidates.retain(|name| policy.permits_command(name));
+    limit(candidates)
+}
+
+/// Check whether `path` is a file someone may execute
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Any file counts off Unix
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Cap the candidates at [`MAX_CANDIDATES`], reporting whether any were cut
+fn limit(candidates: BTreeSet<String>) -> (Vec<String>, bool) {
+    let truncated = candidates.len() > MAX_CANDIDATES;
+    (candidates.into_iter().take(MAX_CANDIDATES).collect(), truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::CommandRule;
+
+    #[test]
+    fn test_complete_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("alps")).unwrap();
+        fs::write(root.join("alpha"), "").unwrap();
+        fs::write(root.join("beta"), "").unwrap();
+        fs::write(root.join(".alpine"), "").unwrap();
+
+        let files = FileService::new(vec![root.clone()]).unwrap();
+        let cwd = root.canonicalize().unwrap();
+
+        let (candidates, truncated) = complete_path(&files, &cwd, "al").unwrap();
+        assert_eq!(candidates, vec!["alpha", "alps/"]);
+        assert!(!truncated);
+
+        let (candidates, _) = complete_path(&files, &cwd, ".al").unwrap();
+        assert_eq!(candidates, vec![".alpine"]);
+
+        // The typed directory part is kept
+        fs::write(root.join("alps/peak"), "").unwrap();
+        let (candidates, _) = complete_path(&files, &cwd, "alps/").unwrap();
+        assert_eq!(candidates, vec!["alps/peak"]);
+
+        // Nothing outside the allowed roots
+        assert!(complete_path(&files, &cwd, "../").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_complete_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        for (name, mode) in [("sudo", 0o755), ("sum", 0o755), ("sunset", 0o644)] {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let dirs = vec![dir.path().to_path_buf()];
+
+        let policy = CommandPolicy::default();
+        let (candidates, _) = commands_in(dirs.clone(), &policy, true, "su");
+        assert_eq!(candidates, vec!["sudo", "sum"]);
+
+        let (candidates, _) = commands_in(dirs.clone(), &policy, true, "@u");
+        assert_eq!(candidates, vec!["@uptime"]);
+        let (candidates, _) = commands_in(dirs.clone(), &policy, false, "@u");
+        assert!(candidates.is_empty());
+
+        let policy =
+            CommandPolicy::new(vec![], vec![CommandRule::Command("sudo".parse().unwrap())]);
+        let (candidates, _) = commands_in(dirs, &policy, true, "su");
+        assert_eq!(candidates, vec!["sum"]);
+    }
+}
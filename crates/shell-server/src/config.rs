@@ -1,28 +1,106 @@
 //! Server configuration
 
-use crate::{Result, ServerError};
-use reticulum_core::Identity;
+use crate::{
+    audit_sink::{AuditSinkKind, SyslogFacility},
+    cgroup::CgroupConfig,
+    policy::{CommandRule, Pattern},
+    rlimit::RlimitConfig,
+    container::ContainerConfig,
+    sandbox::SandboxConfig,
+    seccomp::SeccompConfig,
+    Result, ServerError,
+};
+use reticulum_core::{Identity, Signer};
+use rsh_agent::AgentSigner;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     /// Server identity (loaded, not serialized as private key)
     #[serde(skip, default = "default_identity")]
-    pub identity: Identity,
+    pub identity: Arc<dyn Signer>,
 
     /// Path to identity file
     pub identity_path: PathBuf,
 
+    /// Use the identity through the key agent on this socket (see
+    /// rsh-agent) instead of loading `identity_path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_agent: Option<PathBuf>,
+
+    /// Public key (hex) of the agent's identity to use; needed when it holds
+    /// several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_agent_key: Option<String>,
+
     /// Maximum concurrent sessions
     #[serde(default = "default_max_sessions")]
     pub max_sessions: usize,
 
+    /// Failed connects from one identity or source destination within
+    /// `ban_window` that get it banned (0 = never ban)
+    #[serde(default = "default_ban_threshold")]
+    pub ban_threshold: usize,
+
+    /// Window failed connects are counted in (seconds)
+    #[serde(default = "default_ban_window")]
+    pub ban_window: u64,
+
+    /// How long a ban lasts (seconds)
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+
     /// Command execution timeout (seconds)
     #[serde(default = "default_command_timeout")]
     pub command_timeout: u64,
 
+    /// Format of the server's own log (not the audit log)
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// Serve Prometheus metrics on this address (None = disabled)
+    #[serde(default)]
+    pub metrics_bind: Option<SocketAddr>,
+
+    /// Unix socket for operator queries (None = disabled)
+    #[serde(default)]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Commands kept in each session's history (0 = disabled)
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+
+    /// Record every session's I/O as an asciicast file
+    #[serde(default)]
+    pub session_recording: bool,
+
+    /// Directory for session recordings
+    #[serde(default = "default_recording_dir")]
+    pub recording_dir: PathBuf,
+
+    /// Persist session state here so clients can resume after a restart
+    /// (None = sessions end with the server)
+    #[serde(default)]
+    pub session_state_dir: Option<PathBuf>,
+
+    /// Forget persisted sessions not seen for this long (seconds)
+    #[serde(default = "default_session_resume_ttl")]
+    pub session_resume_ttl: u64,
+
+    /// Persist per-client usage counters in this file (None = memory only)
+    #[serde(default)]
+    pub accounting_file: Option<PathBuf>,
+
+    /// On shutdown, how long to wait for running commands before killing
+    /// them (seconds)
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+
     /// Enable audit logging
     #[serde(default = "default_audit_logging")]
     pub audit_logging: bool,
@@ -31,10 +109,212 @@ pub struct ServerConfig {
     #[serde(default = "default_audit_log_path")]
     pub audit_log_path: PathBuf,
 
+    /// Rotate the audit log once it exceeds this size (bytes)
+    #[serde(default = "default_audit_max_size")]
+    pub audit_max_size: u64,
+
+    /// Number of rotated audit logs to keep
+    #[serde(default = "default_audit_max_files")]
+    pub audit_max_files: usize,
+
+    /// Sign the audit chain every this many records (0 = never)
+    #[serde(default = "default_audit_checkpoint_interval")]
+    pub audit_checkpoint_interval: u64,
+
+    /// Where audit records go
+    #[serde(default = "default_audit_sinks")]
+    pub audit_sinks: Vec<AuditSinkKind>,
+
+    /// Local syslog socket for the syslog sink
+    #[serde(default = "default_audit_syslog_socket")]
+    pub audit_syslog_socket: PathBuf,
+
+    /// syslog facility for the syslog and journald sinks
+    #[serde(default)]
+    pub audit_syslog_facility: SyslogFacility,
+
     /// Allowed client identities (empty = allow all)
     #[serde(default)]
     pub allowed_clients: Vec<String>,
 
+    /// Refuse CONNECT messages not signed by the claimed identity
+    #[serde(default = "default_require_signed_connect")]
+    pub require_signed_connect: bool,
+
+    /// Refuse clients that do not encrypt the session (Noise handshake)
+    #[serde(default)]
+    pub require_noise: bool,
+
+    /// Maximum clock difference for signed CONNECT messages (seconds)
+    #[serde(default = "default_connect_max_skew")]
+    pub connect_max_skew: u64,
+
+    /// Per-client auth tokens (hex-encoded public key -> token)
+    #[serde(default)]
+    pub auth_tokens: BTreeMap<String, String>,
+
+    /// Secret for derived auth tokens; when set, every client without a
+    /// static token must present `hex(HMAC-SHA256(secret, public key))`
+    #[serde(default)]
+    pub auth_hmac_secret: Option<String>,
+
+    /// Commands clients may run (empty = everything not denied)
+    #[serde(default)]
+    pub allowed_commands: Vec<CommandRule>,
+
+    /// Commands clients may never run (checked before `allowed_commands`)
+    #[serde(default)]
+    pub denied_commands: Vec<CommandRule>,
+
+    /// Observation only: permit just `read_only_commands` and builtins, and
+    /// refuse uploads, filesystem changes, PTYs and forwarding
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Commands permitted in read-only mode (on top of `allowed_commands`
+    /// and `denied_commands`)
+    #[serde(default = "default_read_only_commands")]
+    pub read_only_commands: Vec<CommandRule>,
+
+    /// Foreground commands a session runs at once; further requests wait
+    /// in a FIFO queue
+    #[serde(default = "default_max_concurrent_commands")]
+    pub max_concurrent_commands: usize,
+
+    /// Requests a session may have waiting before new ones are refused
+    #[serde(default = "default_command_queue_size")]
+    pub command_queue_size: usize,
+
+    /// Maximum running background jobs per session
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+
+    /// In-memory output buffer per background job stream (bytes)
+    #[serde(default = "default_job_buffer_size")]
+    pub job_buffer_size: usize,
+
+    /// Directory for spilling background job output to disk (None = memory only)
+    #[serde(default)]
+    pub job_spool_dir: Option<PathBuf>,
+
+    /// Staging directory for file transfers (relative upload paths land here)
+    #[serde(default = "default_transfer_dir")]
+    pub transfer_dir: PathBuf,
+
+    /// Additional directories clients may upload to or download from
+    #[serde(default)]
+    pub transfer_allowed_paths: Vec<PathBuf>,
+
+    /// Maximum size of a single uploaded file (bytes)
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+
+    /// Maximum total bytes a session may upload
+    #[serde(default = "default_session_upload_quota")]
+    pub session_upload_quota: u64,
+
+    /// Bytes per second of file transfers and fetched output, all sessions
+    /// together (0 = unlimited)
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+
+    /// Bytes per second of file transfers and fetched output per session
+    /// (0 = unlimited)
+    #[serde(default)]
+    pub session_bandwidth_limit: u64,
+
+    /// Give each session a private directory under this one, used as its
+    /// initial working directory and upload staging area (None = disabled)
+    #[serde(default)]
+    pub session_dir_root: Option<PathBuf>,
+
+    /// Bytes a session directory may hold (0 = unlimited)
+    #[serde(default = "default_session_dir_quota")]
+    pub session_dir_quota: u64,
+
+    /// Paths clients may access through filesystem operations (empty = unrestricted)
+    #[serde(default)]
+    pub fs_allowed_paths: Vec<PathBuf>,
+
+    /// `host:port` glob patterns clients may open local forwards to (empty = disabled)
+    #[serde(default)]
+    pub forward_allow: Vec<String>,
+
+    /// Ports clients may listen on for remote forwards
+    #[serde(default)]
+    pub remote_forward_ports: Vec<u16>,
+
+    /// Address remote-forward listeners bind to
+    #[serde(default = "default_remote_forward_bind")]
+    pub remote_forward_bind: String,
+
+    /// `host:port` glob patterns reachable through the SOCKS relay (empty = disabled)
+    #[serde(default)]
+    pub socks_allow: Vec<String>,
+
+    /// `host:port` glob patterns never reachable through the SOCKS relay
+    #[serde(default)]
+    pub socks_deny: Vec<String>,
+
+    /// cgroup resource limits for every spawned command (Linux only)
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+
+    /// rlimits for every spawned command (Unix)
+    #[serde(default)]
+    pub rlimits: RlimitConfig,
+
+    /// Serve `@`-prefixed builtins (`@sysinfo`, `@df`, ...) from the server
+    /// process
+    #[serde(default = "default_builtins")]
+    pub builtins: bool,
+
+    /// Bytes of stdout and of stderr returned per command; anything beyond
+    /// is discarded and the response marked as truncated
+    #[serde(default = "default_max_output_size")]
+    pub max_output_size: usize,
+
+    /// Directory the complete output of truncated commands is spooled to,
+    /// for clients to fetch (None = discard output beyond the cap)
+    #[serde(default)]
+    pub output_spool_dir: Option<PathBuf>,
+
+    /// Bytes of output each session may spool
+    #[serde(default = "default_output_spool_quota")]
+    pub output_spool_quota: u64,
+
+    /// Run commands through `<shell> -c` so pipes, globs and redirects work
+    /// (None = exec the command directly)
+    #[serde(default)]
+    pub shell: Option<PathBuf>,
+
+    /// Program run before each command with the request as JSON on stdin;
+    /// a non-zero exit vetoes the command
+    #[serde(default)]
+    pub pre_exec_hook: Option<PathBuf>,
+
+    /// Program run after each command with the request and result as JSON
+    #[serde(default)]
+    pub post_exec_hook: Option<PathBuf>,
+
+    /// How long hook programs may run (seconds)
+    #[serde(default = "default_hook_timeout")]
+    pub hook_timeout: u64,
+
+    /// How long commands wait for operator approval before they are
+    /// refused (seconds)
+    #[serde(default = "default_approval_timeout")]
+    pub approval_timeout: u64,
+
+    /// Glob patterns of variable names clients may not set with SETENV
+    #[serde(default = "default_session_env_deny")]
+    pub session_env_deny: Vec<String>,
+
+    /// Glob patterns of variable names clients may not pass to commands
+    /// at all, per request or as session variables
+    #[serde(default = "default_env_deny")]
+    pub env_deny: Vec<String>,
+
     /// Enable I2P transport
     #[serde(default)]
     pub enable_i2p: bool,
@@ -51,24 +331,231 @@ pub struct ServerConfig {
     #[cfg(feature = "embedded-router")]
     #[serde(default)]
     pub embedded_router: reticulum_core::EmbeddedRouterConfig,
+
+    /// Also listen on plain TCP at this address, next to I2P if enabled
+    #[serde(default)]
+    pub tcp_bind: Option<SocketAddr>,
+
+    /// Interfaces to listen on, all at once (`[[listeners]]`), in addition
+    /// to the I2P listener selected by `enable_i2p`/`router_mode`/
+    /// `sam_address` and the TCP one from `tcp_bind`
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Announce the server destination on every interface at startup and
+    /// then this often (seconds, 0 = only at startup)
+    #[serde(default = "default_announce_interval")]
+    pub announce_interval: u64,
+
+    /// Name advertised in announces (None = anonymous)
+    #[serde(default)]
+    pub announce_name: Option<String>,
+
+    /// Ask idle clients to ping this often (seconds, 0 = leave it to them)
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+
+    /// Notice sent to every client in ACCEPT (legal notice, usage policy)
+    #[serde(default)]
+    pub banner: Option<String>,
+
+    /// Refuse requests until the client acknowledges the banner
+    #[serde(default = "default_banner_ack_required")]
+    pub banner_ack_required: bool,
+
+    /// Per-client policy profiles
+    #[serde(default)]
+    pub profiles: Vec<ClientProfile>,
+}
+
+/// An interface the server listens on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListenerConfig {
+    /// External I2P router through its SAM bridge
+    I2p {
+        #[serde(default = "default_sam_address")]
+        sam_address: String,
+    },
+
+    /// Embedded I2P router (configured by `embedded_router`)
+    #[cfg(feature = "embedded-router")]
+    Embedded,
+
+    /// Plain TCP, for LANs and meshes carrying IP
+    Tcp { bind: SocketAddr },
+
+    /// A Python Reticulum daemon's TCPServerInterface, to be reached through
+    /// an existing Reticulum network
+    Rnsd { address: SocketAddr },
+}
+
+/// Format of the server log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+
+    /// One JSON object per line, event fields at the top level
+    Json,
+}
+
+/// Policy applied to a group of client identities
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientProfile {
+    /// Profile name (for logs)
+    pub name: String,
+
+    /// Client identities this profile applies to (hex-encoded public keys)
+    #[serde(default)]
+    pub clients: Vec<String>,
+
+    /// Paths the client may access through filesystem operations
+    /// (empty = use the server-wide `fs_allowed_paths`)
+    #[serde(default)]
+    pub allowed_paths: Vec<PathBuf>,
+
+    /// Local-forward targets (overrides the server-wide `forward_allow`)
+    #[serde(default)]
+    pub forward_allow: Option<Vec<String>>,
+
+    /// Remote-forward ports (overrides the server-wide `remote_forward_ports`)
+    #[serde(default)]
+    pub remote_forward_ports: Option<Vec<u16>>,
+
+    /// SOCKS relay targets (overrides the server-wide `socks_allow`)
+    #[serde(default)]
+    pub socks_allow: Option<Vec<String>>,
+
+    /// Run this profile's commands in namespaces/chroot (Linux only)
+    #[serde(default)]
+    pub sandbox: Option<SandboxConfig>,
+
+    /// Seccomp filter for this profile's commands (Linux only)
+    #[serde(default)]
+    pub seccomp: Option<SeccompConfig>,
+
+    /// Resource limits for this profile (overrides the server-wide `cgroup`)
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+
+    /// Run this profile's commands through `<shell> -c` (overrides the
+    /// server-wide `shell`)
+    #[serde(default)]
+    pub shell: Option<PathBuf>,
+
+    /// Variables this profile may set even though they match `env_deny`
+    #[serde(default)]
+    pub env_allow: Vec<String>,
+
+    /// Run this profile's commands in a Docker/Podman container
+    #[serde(default)]
+    pub container: Option<ContainerConfig>,
+
+    /// Allow EXEC_UPLOAD (push a program and run it)
+    #[serde(default)]
+    pub exec_upload: bool,
+
+    /// Read-only mode for this profile (overrides the server-wide `read_only`)
+    #[serde(default)]
+    pub read_only: Option<bool>,
+
+    /// Hold commands until an operator approves them on the admin socket
+    #[serde(default)]
+    pub require_approval: bool,
+
+    /// TOML file of named secrets (`NAME = "value"`) this profile's clients
+    /// may have set as environment variables
+    #[serde(default)]
+    pub secrets_file: Option<PathBuf>,
 }
 
 fn default_sam_address() -> String {
     "127.0.0.1:7656".to_string()
 }
 
-fn default_identity() -> Identity {
-    Identity::generate()
+fn default_announce_interval() -> u64 {
+    600
+}
+
+fn default_keepalive_interval() -> u64 {
+    60
+}
+
+fn default_banner_ack_required() -> bool {
+    true
+}
+
+fn default_identity() -> Arc<dyn Signer> {
+    Arc::new(Identity::generate())
 }
 
 fn default_max_sessions() -> usize {
     10
 }
 
+fn default_ban_threshold() -> usize {
+    5
+}
+
+fn default_ban_window() -> u64 {
+    600
+}
+
+fn default_ban_duration() -> u64 {
+    3600
+}
+
 fn default_command_timeout() -> u64 {
     300 // 5 minutes
 }
 
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+fn default_max_concurrent_commands() -> usize {
+    4
+}
+
+fn default_command_queue_size() -> usize {
+    16
+}
+
+fn default_max_jobs() -> usize {
+    8
+}
+
+fn default_job_buffer_size() -> usize {
+    64 * 1024
+}
+
+fn default_transfer_dir() -> PathBuf {
+    PathBuf::from("transfers")
+}
+
+fn default_max_upload_size() -> u64 {
+    100 * 1024 * 1024 // 100 MB
+}
+
+fn default_session_upload_quota() -> u64 {
+    1024 * 1024 * 1024 // 1 GB
+}
+
+fn default_session_dir_quota() -> u64 {
+    1024 * 1024 * 1024 // 1 GB
+}
+
+fn default_remote_forward_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
 fn default_audit_logging() -> bool {
     true
 }
@@ -77,34 +564,318 @@ fn default_audit_log_path() -> PathBuf {
     PathBuf::from("audit.log")
 }
 
+fn default_audit_max_size() -> u64 {
+    10 * 1024 * 1024 // 10 MB
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+fn default_audit_checkpoint_interval() -> u64 {
+    100
+}
+
+fn default_history_size() -> usize {
+    100
+}
+
+fn default_recording_dir() -> PathBuf {
+    PathBuf::from("recordings")
+}
+
+fn default_session_resume_ttl() -> u64 {
+    86400
+}
+
+/// Largest `max_output_size`: a response with both streams at the cap must
+/// still fit in one packet, whose length is a u16
+pub const MAX_OUTPUT_SIZE: usize = 30 * 1024;
+
+fn default_max_output_size() -> usize {
+    MAX_OUTPUT_SIZE
+}
+
+fn default_output_spool_quota() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_builtins() -> bool {
+    true
+}
+
+fn default_session_env_deny() -> Vec<String> {
+    vec!["LD_*".to_string(), "DYLD_*".to_string()]
+}
+
+fn default_approval_timeout() -> u64 {
+    300
+}
+
+fn default_read_only_commands() -> Vec<CommandRule> {
+    let commands = [
+        "cat", "df", "du", "free", "head", "id", "ls", "printenv", "ps", "pwd", "stat", "tail",
+        "uname", "uptime", "w", "wc", "who", "whoami",
+    ];
+    // Plain names and globs, which always compile
+    let pattern = |text: &str| text.parse::<Pattern>().unwrap();
+    commands
+        .into_iter()
+        .map(|command| CommandRule::Command(pattern(command)))
+        .chain(std::iter::once(CommandRule::WithArgs {
+            command: pattern("systemctl"),
+            args: vec![pattern("status"), pattern("**")],
+        }))
+        .collect()
+}
+
+fn default_env_deny() -> Vec<String> {
+    vec!["LD_*".to_string(), "DYLD_*".to_string(), "PATH".to_string()]
+}
+
+fn default_require_signed_connect() -> bool {
+    true
+}
+
+fn default_connect_max_skew() -> u64 {
+    300
+}
+
+fn default_audit_sinks() -> Vec<AuditSinkKind> {
+    vec![AuditSinkKind::File]
+}
+
+fn default_audit_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
 impl ServerConfig {
+    /// Read only `log_format` from a configuration file, so logging can be
+    /// set up before the rest is loaded. Unreadable files give the default.
+    pub fn log_format_from_file<P: AsRef<Path>>(path: P) -> LogFormat {
+        #[derive(Deserialize)]
+        struct Logging {
+            #[serde(default)]
+            log_format: LogFormat,
+        }
+
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str::<Logging>(&contents).ok())
+            .map(|logging| logging.log_format)
+            .unwrap_or_default()
+    }
+
     /// Load configuration from TOML file
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let contents = std::fs::read_to_string(path)?;
         let mut config: ServerConfig = toml::from_str(&contents)
             .map_err(|e| ServerError::Config(format!("Failed to parse config: {}", e)))?;
 
-        // Load identity
-        config.identity = Identity::load_from_file(&config.identity_path)?;
+        config.identity = config.load_identity()?;
 
+        config.validate()?;
         Ok(config)
     }
 
+    /// Load the identity: through the key agent if one is configured,
+    /// otherwise from `identity_path`, asking for its passphrase if it is
+    /// encrypted
+    pub fn load_identity(&self) -> Result<Arc<dyn Signer>> {
+        Ok(match &self.identity_agent {
+            Some(socket) => Arc::new(AgentSigner::connect(
+                socket,
+                self.identity_agent_key.as_deref(),
+            )?),
+            None => Arc::new(rsh_agent::passphrase::load_identity(&self.identity_path)?),
+        })
+    }
+
+    /// Check the rules that span several settings, stopping at the first
+    /// violation
+    pub fn validate(&self) -> Result<()> {
+        // A shell would run everything after the first word unchecked
+        let shell_read_only = std::iter::once((self.read_only, self.shell.is_some()))
+            .chain(self.profiles.iter().map(|profile| {
+                (
+                    profile.read_only.unwrap_or(self.read_only),
+                    profile.shell.is_some() || self.shell.is_some(),
+                )
+            }))
+            .any(|(read_only, shell)| read_only && shell);
+        if shell_read_only {
+            return Err(ServerError::Config(
+                "read_only can't be combined with shell mode".to_string(),
+            ));
+        }
+        // Nor could the command lists, which apply to every client
+        let shell = self.shell.is_some() || self.profiles.iter().any(|p| p.shell.is_some());
+        if shell && !(self.allowed_commands.is_empty() && self.denied_commands.is_empty()) {
+            return Err(ServerError::Config(
+                "allowed_commands and denied_commands can't be combined with shell mode"
+                    .to_string(),
+            ));
+        }
+
+        if self.max_output_size > MAX_OUTPUT_SIZE {
+            return Err(ServerError::Config(format!(
+                "max_output_size can be at most {} bytes, for responses to fit in a packet",
+                MAX_OUTPUT_SIZE
+            )));
+        }
+
+        if cfg!(not(target_os = "linux"))
+            && (self.cgroup.is_some() || self.profiles.iter().any(|p| p.cgroup.is_some()))
+        {
+            return Err(ServerError::Config(
+                "cgroup limits are only supported on Linux".to_string(),
+            ));
+        }
+
+        // A root that is missing would leave the client unconfined if it was
+        // the only one, so sessions refuse filesystem operations instead
+        for path in self
+            .fs_allowed_paths
+            .iter()
+            .chain(self.profiles.iter().flat_map(|p| &p.allowed_paths))
+        {
+            path.canonicalize().map_err(|e| {
+                ServerError::Config(format!("Allowed path {}: {}", path.display(), e))
+            })?;
+        }
+
+        for profile in &self.profiles {
+            if cfg!(not(target_os = "linux")) && profile.sandbox.is_some() {
+                return Err(ServerError::Config(format!(
+                    "Profile {:?} uses a sandbox, which is only supported on Linux",
+                    profile.name
+                )));
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(sandbox) = &profile.sandbox {
+                crate::sandbox::lookup_user(sandbox.user()).map_err(|e| {
+                    ServerError::Config(format!(
+                        "Profile {:?}: sandbox user {:?}: {}",
+                        profile.name,
+                        sandbox.user(),
+                        e
+                    ))
+                })?;
+            }
+            if let Some(container) = &profile.container {
+                container.validate().map_err(|e| {
+                    ServerError::Config(format!("Profile {:?}: {}", profile.name, e))
+                })?;
+                // Both would confine the runtime CLI rather than the command
+                if profile.sandbox.is_some() || profile.seccomp.is_some() {
+                    return Err(ServerError::Config(format!(
+                        "Profile {:?}: container can't be combined with sandbox or seccomp",
+                        profile.name
+                    )));
+                }
+            }
+            // Nobody could approve anything
+            if profile.require_approval && self.admin_socket.is_none() {
+                return Err(ServerError::Config(format!(
+                    "Profile {:?}: require_approval needs admin_socket",
+                    profile.name
+                )));
+            }
+            if let Some(seccomp) = &profile.seccomp {
+                seccomp.validate().map_err(|e| {
+                    ServerError::Config(format!("Profile {:?}: {}", profile.name, e))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Create a default configuration
     pub fn default() -> Self {
         Self {
-            identity: Identity::generate(),
+            identity: default_identity(),
             identity_path: PathBuf::from("server.identity"),
+            identity_agent: None,
+            identity_agent_key: None,
             max_sessions: default_max_sessions(),
+            ban_threshold: default_ban_threshold(),
+            ban_window: default_ban_window(),
+            ban_duration: default_ban_duration(),
             command_timeout: default_command_timeout(),
+            log_format: LogFormat::default(),
+            metrics_bind: None,
+            admin_socket: None,
+            history_size: default_history_size(),
+            session_recording: false,
+            recording_dir: default_recording_dir(),
+            session_state_dir: None,
+            session_resume_ttl: default_session_resume_ttl(),
+            accounting_file: None,
+            shutdown_grace_period: default_shutdown_grace_period(),
             audit_logging: default_audit_logging(),
             audit_log_path: default_audit_log_path(),
+            audit_max_size: default_audit_max_size(),
+            audit_max_files: default_audit_max_files(),
+            audit_checkpoint_interval: default_audit_checkpoint_interval(),
+            audit_sinks: default_audit_sinks(),
+            audit_syslog_socket: default_audit_syslog_socket(),
+            audit_syslog_facility: SyslogFacility::default(),
             allowed_clients: vec![],
+            require_signed_connect: default_require_signed_connect(),
+            require_noise: false,
+            connect_max_skew: default_connect_max_skew(),
+            auth_tokens: BTreeMap::new(),
+            auth_hmac_secret: None,
+            allowed_commands: vec![],
+            denied_commands: vec![],
+            read_only: false,
+            read_only_commands: default_read_only_commands(),
+            max_concurrent_commands: default_max_concurrent_commands(),
+            command_queue_size: default_command_queue_size(),
+            max_jobs: default_max_jobs(),
+            job_buffer_size: default_job_buffer_size(),
+            job_spool_dir: None,
+            transfer_dir: default_transfer_dir(),
+            transfer_allowed_paths: vec![],
+            max_upload_size: default_max_upload_size(),
+            session_upload_quota: default_session_upload_quota(),
+            bandwidth_limit: 0,
+            session_bandwidth_limit: 0,
+            session_dir_root: None,
+            session_dir_quota: default_session_dir_quota(),
+            fs_allowed_paths: vec![],
+            forward_allow: vec![],
+            remote_forward_ports: vec![],
+            remote_forward_bind: default_remote_forward_bind(),
+            socks_allow: vec![],
+            socks_deny: vec![],
+            cgroup: None,
+            rlimits: RlimitConfig::default(),
+            builtins: default_builtins(),
+            max_output_size: default_max_output_size(),
+            output_spool_dir: None,
+            output_spool_quota: default_output_spool_quota(),
+            shell: None,
+            pre_exec_hook: None,
+            post_exec_hook: None,
+            hook_timeout: default_hook_timeout(),
+            approval_timeout: default_approval_timeout(),
+            session_env_deny: default_session_env_deny(),
+            env_deny: default_env_deny(),
             enable_i2p: false,
             router_mode: reticulum_core::RouterMode::default(),
             sam_address: default_sam_address(),
             #[cfg(feature = "embedded-router")]
             embedded_router: reticulum_core::EmbeddedRouterConfig::default(),
+            tcp_bind: None,
+            listeners: vec![],
+            announce_interval: default_announce_interval(),
+            announce_name: None,
+            keepalive_interval: default_keepalive_interval(),
+            banner: None,
+            banner_ack_required: default_banner_ack_required(),
+            profiles: vec![],
         }
     }
 
@@ -125,4 +896,183 @@ impl ServerConfig {
         let client_hex = hex::encode(client_identity);
         self.allowed_clients.contains(&client_hex)
     }
+
+    /// Get the listeners to start: `listeners`, plus the I2P listener
+    /// selected by `enable_i2p` unless one is listed already, plus
+    /// `tcp_bind`
+    ///
+    /// All of them share the identity and the session table, so clients may
+    /// come in over whichever interface suits them.
+    pub fn effective_listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = self.listeners.clone();
+
+        let has_i2p = listeners.iter().any(|listener| match listener {
+            ListenerConfig::I2p { .. } => true,
+            #[cfg(feature = "embedded-router")]
+            ListenerConfig::Embedded => true,
+            ListenerConfig::Tcp { .. } | ListenerConfig::Rnsd { .. } => false,
+        });
+        if self.enable_i2p && !has_i2p {
+            listeners.push(match self.router_mode {
+                #[cfg(feature = "embedded-router")]
+                reticulum_core::RouterMode::Embedded => ListenerConfig::Embedded,
+                reticulum_core::RouterMode::External => ListenerConfig::I2p {
+                    sam_address: self.sam_address.clone(),
+                },
+            });
+        }
+
+        if let Some(bind) = self.tcp_bind {
+            let tcp = ListenerConfig::Tcp { bind };
+            if !listeners.contains(&tcp) {
+                listeners.push(tcp);
+            }
+        }
+        listeners
+    }
+
+    /// Find the profile that applies to a client identity
+    pub fn profile_for(&self, client_identity: &[u8]) -> Option<&ClientProfile> {
+        let client_hex = hex::encode(client_identity);
+        self.profiles
+            .iter()
+            .find(|profile| profile.clients.contains(&client_hex))
+    }
+
+    /// Whether a client is limited to read-only mode
+    pub fn read_only_for(&self, client_identity: &[u8]) -> bool {
+        self.profile_for(client_identity)
+            .and_then(|profile| profile.read_only)
+            .unwrap_or(self.read_only)
+    }
+
+    /// Paths a client may access through filesystem operations (empty = unrestricted)
+    pub fn fs_roots_for(&self, client_identity: &[u8]) -> Vec<PathBuf> {
+        match self.profile_for(client_identity) {
+            Some(profile) if !profile.allowed_paths.is_empty() => profile.allowed_paths.clone(),
+            _ => self.fs_allowed_paths.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_lookup() {
+        let mut config = ServerConfig::default();
+        config.fs_allowed_paths = vec![PathBuf::from("/srv")];
+        config.profiles.push(ClientProfile {
+            name: "ops".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            allowed_paths: vec![PathBuf::from("/var/log")],
+            ..Default::default()
+        });
+
+        assert_eq!(config.profile_for(&[1, 2, 3]).unwrap().name, "ops");
+        assert!(config.profile_for(&[4, 5, 6]).is_none());
+
+        assert_eq!(config.fs_roots_for(&[1, 2, 3]), vec![PathBuf::from("/var/log")]);
+        assert_eq!(config.fs_roots_for(&[4, 5, 6]), vec![PathBuf::from("/srv")]);
+    }
+
+    #[test]
+    fn test_max_output_size() {
+        let mut config = ServerConfig::default();
+        assert!(config.validate().is_ok());
+        config.max_output_size = MAX_OUTPUT_SIZE + 1;
+        assert!(matches!(config.validate(), Err(ServerError::Config(_))));
+    }
+
+    #[test]
+    fn test_shell_with_command_lists() {
+        let mut config = ServerConfig::default();
+        config.shell = Some(PathBuf::from("/bin/sh"));
+        assert!(config.validate().is_ok());
+        config.allowed_commands = vec![CommandRule::Command("ls".parse().unwrap())];
+        assert!(matches!(config.validate(), Err(ServerError::Config(_))));
+
+        // A profile's shell is checked by the same lists
+        config.shell = None;
+        config.profiles.push(ClientProfile {
+            name: "ops".to_string(),
+            shell: Some(PathBuf::from("/bin/sh")),
+            ..Default::default()
+        });
+        assert!(matches!(config.validate(), Err(ServerError::Config(_))));
+        config.allowed_commands.clear();
+        config.denied_commands = vec![CommandRule::Command("rm".parse().unwrap())];
+        assert!(matches!(config.validate(), Err(ServerError::Config(_))));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            identity_path = "server.identity"
+
+            [[listeners]]
+            type = "i2p"
+
+            [[listeners]]
+            type = "tcp"
+            bind = "0.0.0.0:4242"
+
+            [[listeners]]
+            type = "rnsd"
+            address = "127.0.0.1:4242"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.effective_listeners(),
+            vec![
+                ListenerConfig::I2p {
+                    sam_address: "127.0.0.1:7656".to_string()
+                },
+                ListenerConfig::Tcp {
+                    bind: "0.0.0.0:4242".parse().unwrap()
+                },
+                ListenerConfig::Rnsd {
+                    address: "127.0.0.1:4242".parse().unwrap()
+                },
+            ]
+        );
+
+        // Legacy single-interface settings
+        let mut config = ServerConfig::default();
+        assert!(config.effective_listeners().is_empty());
+        config.enable_i2p = true;
+        config.sam_address = "10.0.0.1:7656".to_string();
+        let i2p = ListenerConfig::I2p {
+            sam_address: "10.0.0.1:7656".to_string(),
+        };
+        assert_eq!(config.effective_listeners(), vec![i2p.clone()]);
+
+        // Dual-stack: I2P for remote clients, TCP for the LAN
+        let tcp = ListenerConfig::Tcp {
+            bind: "192.168.1.10:4242".parse().unwrap(),
+        };
+        config.tcp_bind = Some("192.168.1.10:4242".parse().unwrap());
+        assert_eq!(config.effective_listeners(), vec![i2p.clone(), tcp.clone()]);
+
+        // Neither is started twice
+        config.listeners = vec![tcp.clone(), i2p.clone()];
+        assert_eq!(config.effective_listeners(), vec![tcp, i2p]);
+    }
+
+    #[test]
+    fn test_log_format_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        assert_eq!(ServerConfig::log_format_from_file(&path), LogFormat::Text);
+
+        std::fs::write(&path, "identity_path = \"x\"\nlog_format = \"json\"\n").unwrap();
+        assert_eq!(ServerConfig::log_format_from_file(&path), LogFormat::Json);
+
+        // The rest of the file doesn't have to be valid yet
+        std::fs::write(&path, "log_format = \"json\"\nmax_sessions = \"many\"\n").unwrap();
+        assert_eq!(ServerConfig::log_format_from_file(&path), LogFormat::Json);
+    }
 }
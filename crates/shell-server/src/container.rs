@@ -0,0 +1,381 @@
+//! Container executor backend
+//!
+//! Profiles with a container table run every command inside a container
+//! instead of on the host, so their clients never see the host filesystem:
+//! either a fresh container per command from `image` (`<runtime> run --rm`)
+//! or an existing long-running `container` (`<runtime> exec`). Docker and
+//! Podman accept the same arguments for everything used here.
+//!
+//! The runtime CLI runs with the server's environment; the command's
+//! environment is passed with `--env`, secrets by name only with their values
+//! in the CLI's environment, so they don't show in the host's process list. Host-side confinement (sandbox, seccomp,
+//! rlimits, cgroups) would only apply to the CLI, so cgroup limits are handed
+//! to the runtime as `--memory`, `--cpus` and `--pids-limit` instead.
+
+use crate::cgroup::CgroupConfig;
+use serde::{Deserialize, Serialize};
+use shell_proto::CommandRequest;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::process::Command as TokioCommand;
+use tracing::debug;
+
+/// Container settings for a client profile
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ContainerConfig {
+    /// Container runtime CLI (`docker` or `podman`)
+    #[serde(default = "default_runtime")]
+    pub runtime: PathBuf,
+
+    /// Image to run each command in a fresh container of
+    #[serde(default)]
+    pub image: Option<String>,
+
+    /// Running container to execute commands in
+    #[serde(default)]
+    pub container: Option<String>,
+
+    /// Network of per-command containers (`none` = no network)
+    #[serde(default = "default_network")]
+    pub network: String,
+
+    /// Volumes mounted into per-command containers (`host:container[:ro]`)
+    #[serde(default)]
+    pub volumes: Vec<String>,
+
+    /// User to run commands as inside the container
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Additional arguments passed to `run`/`exec` before the image/container
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        Self {
+            runtime: default_runtime(),
+            image: None,
+            container: None,
+            network: default_network(),
+            volumes: Vec::new(),
+            user: None,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+fn default_runtime() -> PathBuf {
+    PathBuf::from("docker")
+}
+
+fn default_network() -> String {
+    "none".to_string()
+}
+
+impl ContainerConfig {
+    /// Check that exactly one of `image` and `container` is set
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match (&self.image, &self.container) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err("container needs exactly one of image and container".to_string()),
+        }
+    }
+
+    /// Build the runtime invocation running `argv` for `request`
+    ///
+    /// stdout and stderr are piped, stdin is closed. For per-command
+    /// containers the returned [`ContainerRun`] must be kept until the
+    /// command exits.
+    pub fn command(
+        &self,
+        argv: &[String],
+        request: &CommandRequest,
+        limits: Option<&CgroupConfig>,
+    ) -> (TokioCommand, Option<ContainerRun>) {
+        let mut cmd = TokioCommand::new(&self.runtime);
+        let mut run = None;
+
+        match (&self.image, &self.container) {
+            (_, Some(container)) => {
+                cmd.arg("exec");
+                self.common_args(&mut cmd, request);
+                cmd.args(&self.extra_args);
+                cmd.arg(container);
+            }
+            (Some(image), None) => {
+                let name = format!("reticulum-shell-{}", uuid::Uuid::new_v4());
+                cmd.args(["run", "--rm", "--init", "--name", &name]);
+                cmd.arg("--network").arg(&self.network);
+                for volume in &self.volumes {
+                    cmd.arg("-v").arg(volume);
+                }
+                if let Some(limits) = limits {
+                    limit_args(&mut cmd, limits);
+                }
+                self.common_args(&mut cmd, request);
+                cmd.args(&self.extra_args);
+                cmd.arg(image);
+                run = Some(ContainerRun {
+                    runtime: self.runtime.clone(),
+                    name,
+                    finished: false,
+                });
+            }
+            // Rejected when the configuration is loaded
+            (None, None) => {}
+        }
+        cmd.args(argv);
+
+        cmd.stdin(if request.stdin {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+
+        (cmd, run)
+    }
+
+    /// Arguments `run` and `exec` share
+    fn common_args(&self, cmd: &mut TokioCommand, request: &CommandRequest) {
+        if request.stdin {
+            cmd.arg("--interactive");
+        }
+        if let Some(user) = &self.user {
+            cmd.arg("--user").arg(user);
+        }
+        if let Some(work_dir) = &request.working_dir {
+            cmd.arg("--workdir").arg(work_dir);
+        }
+        if let Some(env) = &request.env {
+            for (key, value) in env {
+                if request.secrets.contains(key) {
+                    // Copied from the CLI's environment, off its command line
+                    cmd.arg("--env").arg(key);
+                    cmd.env(key, value);
+                } else {
+                    cmd.arg("--env").arg(format!("{}={}", key, value));
+                }
+            }
+        }
+    }
+}
+
+/// Pass cgroup limits on to the runtime
+fn limit_args(cmd: &mut TokioCommand, limits: &CgroupConfig) {
+    if let Some(memory) = limits.memory_max {
+        cmd.arg("--memory").arg(memory.to_string());
+    }
+    if let Some(percent) = limits.cpu_percent {
+        cmd.arg("--cpus").arg(format!("{:.2}", percent as f64 / 100.0));
+    }
+    if let Some(pids) = limits.pids_max {
+        cmd.arg("--pids-limit").arg(pids.to_string());
+    }
+}
+
+/// A per-command container
+///
+/// Killing the runtime CLI (e.g. on timeout) leaves the container running,
+/// so unless [`ContainerRun::finished`] was called it is force-removed on
+/// drop.
+#[derive(Debug)]
+pub struct ContainerRun {
+    runtime: PathBuf,
+    name: String,
+    finished: bool,
+}
+
+impl ContainerRun {
+    /// Mark the command as exited (`--rm` removes the container)
+    pub fn finished(&mut self) {
+        self.finished = true;
+    }
+
+    /// Container name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for ContainerRun {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        debug!(container = %self.name, "Removing abandoned container");
+        let child = std::process::Command::new(&self.runtime)
+            .args(["rm", "--force", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        // Reap it without blocking the caller
+        if let Ok(mut child) = child {
+            std::thread::spawn(move || child.wait());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn args(cmd: &TokioCommand) -> Vec<String> {
+        cmd.as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn request() -> CommandRequest {
+        let mut env = BTreeMap::new();
+        env.insert("LANG".to_string(), "C".to_string());
+        CommandRequest {
+            id: 1,
+            command: "ls".to_string(),
+            args: vec!["-l".to_string()],
+            env: Some(env),
+            timeout: None,
+            working_dir: Some("/work".to_string()),
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    #[test]
+    fn test_container_deserialize() {
+        let config: ContainerConfig = toml::from_str(
+            r#"
+            runtime = "podman"
+            image = "alpine:3.19"
+            volumes = ["/srv/data:/data:ro"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.runtime, PathBuf::from("podman"));
+        assert_eq!(config.network, "none");
+        assert!(config.validate().is_ok());
+
+        assert!(ContainerConfig::default().validate().is_err());
+        let both = ContainerConfig {
+            container: Some("tools".to_string()),
+            ..config
+        };
+        assert!(both.validate().is_err());
+    }
+
+    #[test]
+    fn test_run_args() {
+        let config = ContainerConfig {
+            image: Some("alpine".to_string()),
+            volumes: vec!["/srv/data:/data:ro".to_string()],
+            ..ContainerConfig::default()
+        };
+        let limits = CgroupConfig {
+            memory_max: Some(1 << 20),
+            cpu_percent: Some(50),
+            ..CgroupConfig::default()
+        };
+        let argv = vec!["ls".to_string(), "-l".to_string()];
+
+        let (cmd, run) = config.command(&argv, &request(), Some(&limits));
+        let mut run = run.unwrap();
+        let name = run.name().to_string();
+        run.finished();
+
+        assert_eq!(cmd.as_std().get_program(), "docker");
+        assert_eq!(
+            args(&cmd),
+            [
+                "run",
+                "--rm",
+                "--init",
+                "--name",
+                &name,
+                "--network",
+                "none",
+                "-v",
+                "/srv/data:/data:ro",
+                "--memory",
+                "1048576",
+                "--cpus",
+                "0.50",
+                "--workdir",
+                "/work",
+                "--env",
+                "LANG=C",
+                "alpine",
+                "ls",
+                "-l",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exec_args() {
+        let config = ContainerConfig {
+            container: Some("tools".to_string()),
+            user: Some("nobody".to_string()),
+            ..ContainerConfig::default()
+        };
+        let argv = vec!["ls".to_string()];
+
+        let (cmd, run) = config.command(&argv, &request(), None);
+        assert!(run.is_none());
+        assert_eq!(
+            args(&cmd),
+            [
+                "exec",
+                "--user",
+                "nobody",
+                "--workdir",
+                "/work",
+                "--env",
+                "LANG=C",
+                "tools",
+                "ls",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_secret_args() {
+        let config = ContainerConfig {
+            container: Some("tools".to_string()),
+            ..ContainerConfig::default()
+        };
+        let mut request = request();
+        request.env = Some(BTreeMap::from([(
+            "DB_PASS".to_string(),
+            "hunter2".to_string(),
+        )]));
+        request.secrets = vec!["DB_PASS".to_string()];
+
+        let (cmd, _) = config.command(&["env".to_string()], &request, None);
+        assert_eq!(
+            args(&cmd),
+            [
+                "exec",
+                "--workdir",
+                "/work",
+                "--env",
+                "DB_PASS",
+                "tools",
+                "env",
+            ]
+        );
+        assert!(cmd
+            .as_std()
+            .get_envs()
+            .any(|(key, value)| key == "DB_PASS" && value == Some("hunter2".as_ref())));
+    }
+}
@@ -0,0 +1,161 @@
+//! Running as a background daemon (for systems without systemd)
+//!
+//! [`daemonize`] must run before the async runtime and any other threads
+//! start: `fork` only carries the calling thread into the child.
+
+use crate::{Result, ServerError};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Detach from the terminal: double fork, new session, stdio to /dev/null
+///
+/// The working directory is kept so relative paths in the configuration
+/// still resolve.
+#[cfg(unix)]
+pub fn daemonize() -> Result<()> {
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(std::io::Error::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        // Second fork: the daemon is not a session leader and can never
+        // reacquire a controlling terminal
+        match libc::fork() {
+            -1 => return Err(std::io::Error::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        libc::umask(0o027);
+
+        let null = libc::open(b"/dev/null\0".as_ptr().cast(), libc::O_RDWR);
+        if null < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        for fd in 0..3 {
+            libc::dup2(null, fd);
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+
+    Ok(())
+}
+
+/// Daemon mode is Unix-only
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<()> {
+    Err(ServerError::Config(
+        "--daemon is only supported on Unix".to_string(),
+    ))
+}
+
+/// A pidfile, removed again on drop
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Fail if `path` names a running process
+    ///
+    /// Stale pidfiles (process gone, or unreadable contents) are ignored.
+    pub fn check(path: &Path) -> Result<()> {
+        let Ok(contents) = fs::read_to_string(path) else {
+            return Ok(());
+        };
+        let Ok(pid) = contents.trim().parse::<u32>() else {
+            return Ok(());
+        };
+
+        if pid != std::process::id() && process_alive(pid) {
+            return Err(ServerError::Config(format!(
+                "Already running with pid {} ({})",
+                pid,
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write the current pid to `path`
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        Self::check(&path)?;
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path })
+    }
+
+    /// Get the pidfile path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 checks for existence; EPERM means it exists but isn't ours
+    unsafe {
+        libc::kill(pid, 0) == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(pid_file.path()).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // Stale or garbage pidfiles are replaced
+        fs::write(&path, "not a pid").unwrap();
+        assert!(PidFile::create(&path).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_running_pid_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.pid");
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        fs::write(&path, child.id().to_string()).unwrap();
+        assert!(PidFile::check(&path).is_err());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(PidFile::check(&path).is_ok());
+    }
+}
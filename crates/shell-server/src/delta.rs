@@ -0,0 +1,175 @@
+//! Output deltas for commands run again and again
+//!
+//! A client polling a command (`watch`) can send the SHA-256 of the stdout
+//! it holds from the last run in `delta_base`. The session keeps the last
+//! stdout of each such command; if it is the one the client holds, the
+//! response carries a delta against it instead (see
+//! [`shell_proto::delta`]), unless that would not be smaller. Truncated
+//! output goes out whole and is not kept.
+//!
+//! Commands are told apart by command, arguments and working directory.
+//! The hash guards against anything else: a client that holds other
+//! output than the session kept gets the output in full.
+
+use sha2::{Digest, Sha256};
+use shell_proto::{delta, CommandRequest, CommandResponse};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Commands whose output a session keeps
+const MAX_ENTRIES: usize = 16;
+
+/// What identifies a command: command, arguments and working directory
+pub type CommandKey = (String, Vec<String>, Option<String>);
+
+/// The command `request` runs
+pub fn command_key(request: &CommandRequest) -> CommandKey {
+    (
+        request.command.clone(),
+        request.args.clone(),
+        request.working_dir.clone(),
+    )
+}
+
+/// The last stdout of the commands a session ran for deltas
+#[derive(Debug, Default)]
+pub struct DeltaCache {
+    /// Least recently run first
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    command: CommandKey,
+
+    /// SHA-256 of `stdout`
+    hash: [u8; 32],
+
+    stdout: Vec<u8>,
+}
+
+impl DeltaCache {
+    /// Turn the stdout of `response` into a delta if the client holds the
+    /// output with hash `base` from the last run of `command`, and keep it
+    /// for the next run
+    pub fn encode(&self, command: CommandKey, base: [u8; 32], response: &mut CommandResponse) {
+        let mut entries = self.entries.lock().unwrap();
+        let previous = entries
+            .iter()
+            .position(|entry| entry.command == command)
+            .and_then(|i| entries.remove(i));
+        if response.truncated {
+            return;
+        }
+
+        let stdout = response.stdout.clone();
+        if let Some(previous) = previous.filter(|previous| previous.hash == base) {
+            let delta = delta::diff(&previous.stdout, &stdout);
+            if delta.len() < stdout.len() {
+                response.stdout = delta;
+                response.delta_base = Some(base);
+            }
+        }
+
+        entries.push_back(Entry {
+            command,
+            hash: Sha256::digest(&stdout).into(),
+            stdout,
+        });
+        if entries.len() > MAX_ENTRIES {
+            entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::CommandStatus;
+
+    fn key(command: &str) -> CommandKey {
+        (command.to_string(), vec![], None)
+    }
+
+    fn response(stdout: &[u8]) -> CommandResponse {
+        CommandResponse {
+            id: 1,
+            status: CommandStatus::Success,
+            stdout: stdout.to_vec(),
+            stderr: vec![],
+            exit_code: 0,
+            execution_time_ms: 1,
+            truncated: false,
+            total_bytes: stdout.len() as u64,
+            spool_id: None,
+            delta_base: None,
+        }
+    }
+
+    fn hash(stdout: &[u8]) -> [u8; 32] {
+        Sha256::digest(stdout).into()
+    }
+
+    #[test]
+    fn test_delta_against_held_output() {
+        let cache = DeltaCache::default();
+        let first = "load average: 0.10\n".repeat(20);
+        let second = first.replacen("0.10", "0.25", 1);
+
+        // Nothing kept yet
+        let mut reply = response(first.as_bytes());
+        cache.encode(key("uptime"), hash(b""), &mut reply);
+        assert_eq!(reply.stdout, first.as_bytes());
+        assert!(reply.delta_base.is_none());
+
+        let mut reply = response(second.as_bytes());
+        cache.encode(key("uptime"), hash(first.as_bytes()), &mut reply);
+        assert_eq!(reply.delta_base, Some(hash(first.as_bytes())));
+        assert!(reply.stdout.len() < second.len());
+        assert_eq!(
+            delta::apply(first.as_bytes(), &reply.stdout).unwrap(),
+            second.as_bytes()
+        );
+
+        // The client holds other output, or it is of another command
+        let mut reply = response(second.as_bytes());
+        cache.encode(key("uptime"), hash(first.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_none());
+        let mut reply = response(second.as_bytes());
+        cache.encode(key("w"), hash(second.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_none());
+    }
+
+    #[test]
+    fn test_truncated_not_kept() {
+        let cache = DeltaCache::default();
+        let output = "x".repeat(100);
+        cache.encode(key("yes"), hash(b""), &mut response(output.as_bytes()));
+
+        let mut reply = response(output.as_bytes());
+        reply.truncated = true;
+        cache.encode(key("yes"), hash(output.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_none());
+
+        let mut reply = response(output.as_bytes());
+        cache.encode(key("yes"), hash(output.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_none());
+    }
+
+    #[test]
+    fn test_oldest_dropped() {
+        let cache = DeltaCache::default();
+        let output = "x".repeat(100);
+        for i in 0..=MAX_ENTRIES {
+            let mut reply = response(output.as_bytes());
+            cache.encode(key(&i.to_string()), hash(b""), &mut reply);
+        }
+
+        let mut reply = response(output.as_bytes());
+        cache.encode(key("1"), hash(output.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_some());
+        let mut reply = response(output.as_bytes());
+        cache.encode(key("0"), hash(output.as_bytes()), &mut reply);
+        assert!(reply.delta_base.is_none());
+    }
+}
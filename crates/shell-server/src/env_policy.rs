@@ -0,0 +1,114 @@
+//! Environment variable policy
+//!
+//! Commands start with an empty environment plus the variables the client
+//! sends, per request or as session variables. Some variables change what
+//! runs rather than how it behaves: `LD_PRELOAD` and `LD_LIBRARY_PATH` load
+//! arbitrary code into every dynamically linked program, and `PATH` decides
+//! which binary a bare command name resolves to, sidestepping
+//! `allowed_commands`. Variables matching `env_deny` are refused unless the
+//! client's profile lists them in `env_allow`.
+
+use crate::{config::ServerConfig, pattern::glob_match, Result, ServerError};
+use std::collections::BTreeMap;
+
+/// Variables a client may pass to its commands
+#[derive(Debug, Clone, Default)]
+pub struct EnvPolicy {
+    /// Glob patterns of refused variable names
+    deny: Vec<String>,
+
+    /// Glob patterns of names allowed despite matching `deny`
+    allow: Vec<String>,
+}
+
+impl EnvPolicy {
+    /// Create a policy from deny and allow patterns
+    pub fn new(deny: Vec<String>, allow: Vec<String>) -> Self {
+        Self { deny, allow }
+    }
+
+    /// Build the server-wide policy
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.env_deny.clone(), vec![])
+    }
+
+    /// Build the policy for a client, with its profile's exceptions
+    pub fn for_client(config: &ServerConfig, client_identity: &[u8]) -> Self {
+        let allow = config
+            .profile_for(client_identity)
+            .map(|profile| profile.env_allow.clone())
+            .unwrap_or_default();
+        Self::new(config.env_deny.clone(), allow)
+    }
+
+    /// Check whether a variable may be set
+    pub fn permits(&self, name: &str) -> bool {
+        !self.deny.iter().any(|pattern| glob_match(pattern, name))
+            || self.allow.iter().any(|pattern| glob_match(pattern, name))
+    }
+
+    /// Check a command's environment, refusing it if any variable is denied
+    pub fn check(&self, env: Option<&BTreeMap<String, String>>) -> Result<()> {
+        let mut denied: Vec<&str> = env
+            .into_iter()
+            .flat_map(|env| env.keys())
+            .map(String::as_str)
+            .filter(|name| !self.permits(name))
+            .collect();
+        if denied.is_empty() {
+            return Ok(());
+        }
+
+        denied.sort_unstable();
+        Err(ServerError::Denied(format!(
+            "Environment variable may not be set: {}",
+            denied.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientProfile;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = EnvPolicy::from_config(&ServerConfig::default());
+
+        assert!(policy.permits("LANG"));
+        assert!(!policy.permits("LD_PRELOAD"));
+        assert!(!policy.permits("LD_LIBRARY_PATH"));
+        assert!(!policy.permits("PATH"));
+        assert!(policy.permits("MANPATH"));
+
+        let mut env = BTreeMap::new();
+        env.insert("LANG".to_string(), "C".to_string());
+        assert!(policy.check(Some(&env)).is_ok());
+        assert!(policy.check(None).is_ok());
+
+        env.insert("PATH".to_string(), "/tmp".to_string());
+        env.insert("LD_PRELOAD".to_string(), "/tmp/evil.so".to_string());
+        let err = policy.check(Some(&env)).unwrap_err();
+        assert!(matches!(err, ServerError::Denied(_)));
+        assert!(err.to_string().contains("LD_PRELOAD, PATH"));
+    }
+
+    #[test]
+    fn test_profile_allow() {
+        let mut config = ServerConfig::default();
+        config.profiles.push(ClientProfile {
+            name: "builders".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            env_allow: vec!["PATH".to_string()],
+            ..Default::default()
+        });
+
+        let builders = EnvPolicy::for_client(&config, &[1, 2, 3]);
+        assert!(builders.permits("PATH"));
+        assert!(!builders.permits("LD_PRELOAD"));
+
+        let others = EnvPolicy::for_client(&config, &[4, 5, 6]);
+        assert!(!others.permits("PATH"));
+    }
+}
@@ -33,10 +33,36 @@ pub enum ServerError {
     #[error("Authentication failed: {0}")]
     Auth(String),
 
+    /// Request refused by server policy
+    #[error("Permission denied: {0}")]
+    Denied(String),
+
+    /// Too much work queued, retry later
+    #[error("Server busy: {0}")]
+    Overloaded(String),
+
+    /// Audit log failed verification
+    #[error("Audit log verification failed: {0}")]
+    AuditTampered(String),
+
     /// Timeout error
     #[error("Operation timed out")]
     Timeout,
 }
 
+impl ServerError {
+    /// Map to an [`shell_proto::ErrorMessage`] code
+    pub fn error_code(&self) -> u32 {
+        use shell_proto::ErrorMessage;
+
+        match self {
+            ServerError::Execution(_) | ServerError::Session(_) => ErrorMessage::INVALID_REQUEST,
+            ServerError::Auth(_) | ServerError::Denied(_) => ErrorMessage::DENIED,
+            ServerError::Overloaded(_) => ErrorMessage::OVERLOADED,
+            _ => ErrorMessage::INTERNAL,
+        }
+    }
+}
+
 /// Result type for server operations
 pub type Result<T> = std::result::Result<T, ServerError>;
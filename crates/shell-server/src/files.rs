@@ -0,0 +1,487 @@
+//! Remote filesystem operations
+//!
+//! Structured stat/list/mkdir/remove/rename/chmod so clients can manage files
+//! without spawning `ls`/`rm` and parsing their output. Relative paths are
+//! taken from the session's working directory.
+
+use crate::{Result, ServerError};
+use shell_proto::{FileEntry, FileKind, FileOp};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::debug;
+
+/// Filesystem operation handler for one client
+#[derive(Debug, Clone, Default)]
+pub struct FileService {
+    /// Canonicalized roots the client may touch (None = unrestricted)
+    roots: Option<Vec<PathBuf>>,
+}
+
+impl FileService {
+    /// Create a file service restricted to `roots` (empty = unrestricted)
+    ///
+    /// Fails if a root doesn't resolve: dropping it could leave no roots,
+    /// which would mean no restriction at all.
+    pub fn new(roots: Vec<PathBuf>) -> Result<Self> {
+        if roots.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let roots = roots
+            .into_iter()
+            .map(|root| {
+                root.canonicalize().map_err(|e| {
+                    ServerError::Config(format!("Allowed path {}: {}", root.display(), e))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { roots: Some(roots) })
+    }
+
+    /// A file service that permits no path at all
+    pub fn deny_all() -> Self {
+        Self {
+            roots: Some(vec![]),
+        }
+    }
+
+    /// Perform a filesystem operation, with relative paths taken from `cwd`
+    pub fn handle(&self, op: FileOp, cwd: &Path) -> Result<Vec<FileEntry>> {
+        debug!(op = ?op, "Handling file operation");
+
+        match op {
+            FileOp::Stat { path } => {
+                let resolved = self.resolve(&path, cwd)?;
+                let metadata = fs::symlink_metadata(&resolved)?;
+                Ok(vec![entry(resolved.display().to_string(), &metadata)])
+            }
+
+            FileOp::List { path } => {
+                let resolved = self.resolve(&path, cwd)?;
+                refuse_symlink(&resolved)?;
+                let mut entries = Vec::new();
+                for dir_entry in fs::read_dir(&resolved)? {
+                    let dir_entry = dir_entry?;
+                    let metadata = fs::symlink_metadata(dir_entry.path())?;
+                    entries.push(entry(
+                        dir_entry.file_name().to_string_lossy().into_owned(),
+                        &metadata,
+                    ));
+                }
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(entries)
+            }
+
+            FileOp::Mkdir { path, recursive } => {
+                if recursive {
+                    fs::create_dir_all(self.resolve_missing(&path, cwd)?)?;
+                } else {
+                    fs::create_dir(self.resolve(&path, cwd)?)?;
+                }
+                Ok(vec![])
+            }
+
+            FileOp::Remove { path, recursive } => {
+                let resolved = self.resolve(&path, cwd)?;
+                if self.roots.iter().flatten().any(|root| *root == resolved) {
+                    return Err(ServerError::Denied(
+                        "Cannot remove an allowed root".to_string(),
+                    ));
+                }
+
+                let metadata = fs::symlink_metadata(&resolved)?;
+                if metadata.is_dir() {
+                    if recursive {
+                        fs::remove_dir_all(&resolved)?;
+                    } else {
+                        fs::remove_dir(&resolved)?;
+                    }
+                } else {
+                    fs::remove_file(&resolved)?;
+                }
+                Ok(vec![])
+            }
+
+            FileOp::Rename { from, to } => {
+                let from = self.resolve(&from, cwd)?;
+                let to = self.resolve(&to, cwd)?;
+                fs::rename(from, to)?;
+                Ok(vec![])
+            }
+
+            FileOp::Chmod { path, mode } => {
+                let resolved = self.resolve(&path, cwd)?;
+                refuse_symlink(&resolved)?;
+                set_mode(&resolved, mode)?;
+                Ok(vec![])
+            }
+        }
+    }
+
+    /// Resolve a client path and check it against the allowed roots
+    ///
+    /// The parent directory is canonicalized (so symlinked directories can't
+    /// escape a root) but the final component is not, so operations on a
+    /// symlink act on the link itself; those that would follow it (list,
+    /// chmod) refuse symlinks. A relative path is taken from `cwd`.
+    pub fn resolve(&self, path: &str, cwd: &Path) -> Result<PathBuf> {
+        let path = join(cwd, path)?;
+        let resolved = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+            _ => path.canonicalize()?,
+        };
+
+        self.check_allowed(&resolved)?;
+        Ok(resolved)
+    }
+
+    /// Resolve a directory to create along with its missing parents, and
+    /// check it against the allowed roots
+    ///
+    /// The longest ancestor that exists is canonicalized and checked; the
+    /// rest can only name directories inside it, as a `..` in it fails to
+    /// resolve.
+    pub fn resolve_missing(&self, path: &str, cwd: &Path) -> Result<PathBuf> {
+        let path = join(cwd, path)?;
+        let mut existing = path.as_path();
+        let mut missing = Vec::new();
+        while fs::symlink_metadata(existing).is_err() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = parent;
+                }
+                _ => break,
+            }
+        }
+
+        let mut resolved = existing.canonicalize()?;
+        self.check_allowed(&resolved)?;
+        resolved.extend(missing.iter().rev());
+        Ok(resolved)
+    }
+
+    /// Resolve a directory to change into, following symlinks, and check it
+    /// against the allowed roots
+    pub fn resolve_dir(&self, path: &Path) -> Result<PathBuf> {
+        let resolved = path.canonicalize()?;
+        if !resolved.is_dir() {
+            return Err(ServerError::Execution(format!(
+                "Not a directory: {}",
+                resolved.display()
+            )));
+        }
+
+        self.check_allowed(&resolved)?;
+        Ok(resolved)
+    }
+
+    fn check_allowed(&self, resolved: &Path) -> Result<()> {
+        let Some(roots) = &self.roots else {
+            return Ok(());
+        };
+        if !roots.iter().any(|root| resolved.starts_with(root)) {
+            return Err(ServerError::Denied(format!(
+                "Path not permitted: {}",
+                resolved.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A client `path`, taken from `cwd` if relative
+fn join(cwd: &Path, path: &str) -> Result<PathBuf> {
+    if path.is_empty() {
+        return Err(ServerError::Execution("Path cannot be empty".to_string()));
+    }
+    Ok(cwd.join(path))
+}
+
+/// Refuse a final path component that is a symlink, which the operation
+/// would follow out of the allowed roots
+fn refuse_symlink(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Err(ServerError::Denied(format!(
+            "Path is a symlink: {}",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Build a protocol entry from metadata
+fn entry(name: String, metadata: &Metadata) -> FileEntry {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    FileEntry {
+        name,
+        kind,
+        size: metadata.len(),
+        mode: mode_of(metadata),
+        modified,
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &Metadata) -> u32 {
+    0
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Err(ServerError::Execution(
+        "chmod is not supported on this platform".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_str(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_file_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(vec![dir.path().to_path_buf()]).unwrap();
+        let sub = dir.path().join("sub");
+
+        service
+            .handle(
+                FileOp::Mkdir {
+                    path: path_str(&sub),
+                    recursive: false,
+                },
+                dir.path(),
+            )
+            .unwrap();
+        fs::write(sub.join("a.txt"), b"abc").unwrap();
+
+        let listing = service
+            .handle(
+                FileOp::List {
+                    path: path_str(&sub),
+                },
+                dir.path(),
+            )
+            .unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].name, "a.txt");
+        assert_eq!(listing[0].kind, FileKind::File);
+        assert_eq!(listing[0].size, 3);
+
+        service
+            .handle(
+                FileOp::Rename {
+                    from: path_str(&sub.join("a.txt")),
+                    to: path_str(&sub.join("b.txt")),
+                },
+                dir.path(),
+            )
+            .unwrap();
+        assert!(sub.join("b.txt").exists());
+
+        #[cfg(unix)]
+        {
+            service
+                .handle(
+                    FileOp::Chmod {
+                        path: path_str(&sub.join("b.txt")),
+                        mode: 0o600,
+                    },
+                    dir.path(),
+                )
+                .unwrap();
+            let stat = service
+                .handle(
+                    FileOp::Stat {
+                        path: path_str(&sub.join("b.txt")),
+                    },
+                    dir.path(),
+                )
+                .unwrap();
+            assert_eq!(stat[0].mode, 0o600);
+        }
+
+        service
+            .handle(
+                FileOp::Remove {
+                    path: path_str(&sub),
+                    recursive: true,
+                },
+                dir.path(),
+            )
+            .unwrap();
+        assert!(!sub.exists());
+    }
+
+    #[test]
+    fn test_path_restrictions() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(vec![dir.path().to_path_buf()]).unwrap();
+
+        assert!(service
+            .handle(
+                FileOp::List {
+                    path: "/etc".to_string()
+                },
+                dir.path()
+            )
+            .is_err());
+        assert!(service
+            .handle(
+                FileOp::Remove {
+                    path: path_str(dir.path()),
+                    recursive: true,
+                },
+                dir.path()
+            )
+            .is_err());
+        assert!(dir.path().exists());
+
+        // A root that doesn't resolve is an error, not dropped
+        assert!(matches!(
+            FileService::new(vec![dir.path().to_path_buf(), dir.path().join("missing")]),
+            Err(ServerError::Config(_))
+        ));
+        let closed = FileService::deny_all();
+        assert!(closed
+            .handle(
+                FileOp::Stat {
+                    path: path_str(dir.path())
+                },
+                dir.path()
+            )
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("secret");
+        fs::write(&target, b"").unwrap();
+        std::os::unix::fs::symlink(&target, dir.path().join("file")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("dir")).unwrap();
+        let service = FileService::new(vec![dir.path().to_path_buf()]).unwrap();
+
+        assert!(matches!(
+            service.handle(
+                FileOp::Chmod {
+                    path: path_str(&dir.path().join("file")),
+                    mode: 0o777,
+                },
+                dir.path()
+            ),
+            Err(ServerError::Denied(_))
+        ));
+        assert_ne!(mode_of(&fs::metadata(&target).unwrap()), 0o777);
+        assert!(matches!(
+            service.handle(
+                FileOp::List {
+                    path: path_str(&dir.path().join("dir"))
+                },
+                dir.path()
+            ),
+            Err(ServerError::Denied(_))
+        ));
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let service = FileService::new(vec![dir.path().to_path_buf()]).unwrap();
+
+        // mkdir -p a/b/c, from the session's directory
+        service
+            .handle(
+                FileOp::Mkdir {
+                    path: "a/b/c".to_string(),
+                    recursive: true,
+                },
+                dir.path(),
+            )
+            .unwrap();
+        assert!(dir.path().join("a/b/c").is_dir());
+        let listing = service
+            .handle(
+                FileOp::List {
+                    path: "b".to_string(),
+                },
+                &dir.path().join("a"),
+            )
+            .unwrap();
+        assert_eq!(listing[0].name, "c");
+
+        // Missing parents outside the roots, or reached through `..`
+        for path in [
+            path_str(&outside.path().join("x/y")),
+            "new/../../escape/z".to_string(),
+        ] {
+            assert!(service
+                .handle(
+                    FileOp::Mkdir {
+                        path,
+                        recursive: true,
+                    },
+                    dir.path(),
+                )
+                .is_err());
+        }
+        assert!(!outside.path().join("x").exists());
+        assert!(!dir.path().join("new").exists());
+    }
+
+    #[test]
+    fn test_resolve_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(vec![dir.path().to_path_buf()]).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("file"), b"").unwrap();
+
+        let sub = service.resolve_dir(&dir.path().join("sub/../sub")).unwrap();
+        assert_eq!(sub, dir.path().canonicalize().unwrap().join("sub"));
+
+        assert!(service.resolve_dir(&dir.path().join("file")).is_err());
+        assert!(service.resolve_dir(&dir.path().join("missing")).is_err());
+        assert!(matches!(
+            service.resolve_dir(&dir.path().join("..")),
+            Err(ServerError::Denied(_))
+        ));
+    }
+}
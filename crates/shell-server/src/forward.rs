@@ -0,0 +1,582 @@
+//! TCP port forwarding
+//!
+//! Local forwards: the client opens a `Direct` channel and the server connects
+//! to the target. Remote forwards: the server listens on a permitted port and
+//! opens a `Forwarded` channel towards the client for every inbound
+//! connection. Dynamic forwards: the channel carries a SOCKS5 session that the
+//! server relays (see [`crate::socks`]). Channel payload travels as
+//! `ChannelData` messages.
+
+use crate::{
+    config::ServerConfig, pattern::glob_match, session::Outbound, socks, Result, ServerError,
+};
+use shell_proto::{ChannelClose, ChannelData, ChannelKind, ChannelOpenRequest, Message};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Timeout for outbound TCP connects
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the buffer used when reading from sockets
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Buffer between a dynamic channel and its SOCKS relay
+const SOCKS_PIPE_SIZE: usize = 64 * 1024;
+
+/// Channel IDs allocated by the server start here so they never collide with
+/// client-chosen IDs
+const SERVER_CHANNEL_BASE: u64 = 1 << 63;
+
+/// Forwarding permissions for one client
+#[derive(Debug, Clone)]
+pub struct ForwardPolicy {
+    /// `host:port` glob patterns the client may connect to
+    pub allow: Vec<String>,
+
+    /// Ports the client may listen on for remote forwards
+    pub remote_ports: Vec<u16>,
+
+    /// Address remote-forward listeners bind to
+    pub bind_address: String,
+
+    /// `host:port` glob patterns reachable through dynamic (SOCKS) forwards
+    pub socks_allow: Vec<String>,
+
+    /// `host:port` glob patterns never reachable through dynamic forwards
+    pub socks_deny: Vec<String>,
+}
+
+impl ForwardPolicy {
+    /// Build the policy for a client, applying its profile overrides
+    pub fn for_client(config: &ServerConfig, client_identity: &[u8]) -> Self {
+        let profile = config.profile_for(client_identity);
+
+        Self {
+            allow: profile
+                .and_then(|p| p.forward_allow.clone())
+                .unwrap_or_else(|| config.forward_allow.clone()),
+            remote_ports: profile
+                .and_then(|p| p.remote_forward_ports.clone())
+                .unwrap_or_else(|| config.remote_forward_ports.clone()),
+            bind_address: config.remote_forward_bind.clone(),
+            socks_allow: profile
+                .and_then(|p| p.socks_allow.clone())
+                .unwrap_or_else(|| config.socks_allow.clone()),
+            socks_deny: config.socks_deny.clone(),
+        }
+    }
+
+    /// Resolve `host:port` for a local forward, returning the addresses it
+    /// may be reached at
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        resolve_vetted(&self.allow, &[], host, port).await
+    }
+
+    /// Resolve `host:port` for a SOCKS client, returning the addresses it
+    /// may be reached at
+    ///
+    /// Deny patterns win over allow patterns.
+    pub async fn resolve_socks(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        resolve_vetted(&self.socks_allow, &self.socks_deny, host, port).await
+    }
+}
+
+/// Resolve `host:port` and check the name and every address against the
+/// patterns
+///
+/// The target is allowed if its name matches `allow` or all its addresses
+/// do, and denied if its name or any of its addresses matches `deny`, so a
+/// name can't lead to a denied address. Connecting to the returned addresses
+/// rather than the name keeps a second lookup from answering differently.
+///
+/// The name is checked first: nothing is looked up when forwarding is off,
+/// when the name is denied, or when it is not allowed and no allow pattern
+/// is for addresses, so a client can't make the server resolve names it
+/// may not reach.
+async fn resolve_vetted(
+    allow: &[String],
+    deny: &[String],
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>> {
+    let refuse =
+        || ServerError::Denied(format!("Forwarding to {}:{} is not permitted", host, port));
+    let allowed_by_name = matches_any(allow, host, port);
+    if matches_any(deny, host, port)
+        || !(allowed_by_name || allow.iter().any(|pattern| is_address_pattern(pattern)))
+    {
+        return Err(refuse());
+    }
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    let ips: Vec<String> = addrs
+        .iter()
+        .map(|addr| canonical(addr.ip()).to_string())
+        .collect();
+
+    let denied = ips.iter().any(|ip| matches_any(deny, ip, port));
+    let allowed =
+        allowed_by_name || (!ips.is_empty() && ips.iter().all(|ip| matches_any(allow, ip, port)));
+    if denied || !allowed {
+        return Err(refuse());
+    }
+    if addrs.is_empty() {
+        return Err(ServerError::Execution(format!("{} has no address", host)));
+    }
+    Ok(addrs)
+}
+
+/// Whether `pattern` is for IP addresses rather than names: hex digits,
+/// dots, colons and wildcards, starting with a digit or holding `::`
+fn is_address_pattern(pattern: &str) -> bool {
+    pattern
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || ".:*?".contains(c))
+        && (pattern.starts_with(|c: char| c.is_ascii_digit()) || pattern.contains("::"))
+}
+
+/// An IPv4-mapped IPv6 address as the IPv4 address it stands for
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Check `host:port` against a list of patterns
+fn matches_any(patterns: &[String], host: &str, port: u16) -> bool {
+    let target = format!("{}:{}", host, port);
+    patterns.iter().any(|pattern| {
+        // Bare host patterns allow every port
+        if pattern.contains(':') {
+            glob_match(pattern, &target)
+        } else {
+            glob_match(pattern, host)
+        }
+    })
+}
+
+/// An open channel
+struct Channel {
+    /// Payload headed for the socket
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+
+    /// Task pumping the socket towards the client
+    reader: JoinHandle<()>,
+}
+
+type Channels = Arc<Mutex<HashMap<u64, Channel>>>;
+
+/// Per-session forwarding state
+pub struct ForwardService {
+    /// Forwarding permissions
+    policy: ForwardPolicy,
+
+    /// Open channels
+    channels: Channels,
+
+    /// Remote-forward listeners by port
+    listeners: Mutex<HashMap<u16, JoinHandle<()>>>,
+
+    /// Next server-allocated channel ID
+    next_channel: Arc<AtomicU64>,
+}
+
+impl ForwardService {
+    /// Create a new forwarding service
+    pub fn new(policy: ForwardPolicy) -> Self {
+        Self {
+            policy,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Mutex::new(HashMap::new()),
+            next_channel: Arc::new(AtomicU64::new(SERVER_CHANNEL_BASE)),
+        }
+    }
+
+    /// Get the forwarding policy
+    pub fn policy(&self) -> &ForwardPolicy {
+        &self.policy
+    }
+
+    /// Open a local-forward channel by connecting to `host:port`
+    pub async fn open_direct(
+        &self,
+        channel_id: u64,
+        host: &str,
+        port: u16,
+        outbound: Outbound,
+    ) -> Result<()> {
+        let addrs = match self.policy.resolve(host, port).await {
+            Err(e @ ServerError::Denied(_)) => {
+                warn!(host = %host, port = port, "Forward target not permitted");
+                return Err(e);
+            }
+            result => result?,
+        };
+
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addrs[..]))
+            .await
+            .map_err(|_| ServerError::Timeout)??;
+
+        self.attach(channel_id, stream, outbound)?;
+
+        info!(channel_id = channel_id, host = %host, port = port, "Forward channel opened");
+        Ok(())
+    }
+
+    /// Open a dynamic-forward channel served by the SOCKS relay
+    pub fn open_dynamic(&self, channel_id: u64, outbound: Outbound) -> Result<()> {
+        if self.policy.socks_allow.is_empty() {
+            return Err(ServerError::Denied(
+                "Dynamic forwarding is not permitted".to_string(),
+            ));
+        }
+
+        let (channel_end, relay_end) = tokio::io::duplex(SOCKS_PIPE_SIZE);
+        let policy = self.policy.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = socks::relay(relay_end, &policy).await {
+                debug!(channel_id = channel_id, error = %e, "SOCKS relay ended");
+            }
+        });
+
+        self.attach(channel_id, channel_end, outbound)?;
+
+        info!(channel_id = channel_id, "Dynamic forward channel opened");
+        Ok(())
+    }
+
+    /// Attach a connected stream to a channel
+    ///
+    /// Fails if `channel_id` is already open.
+    pub fn attach<S>(&self, channel_id: u64, stream: S, outbound: Outbound) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        attach(&self.channels, channel_id, stream, outbound)
+    }
+
+    /// Send client data into a channel
+    pub fn send(&self, channel_id: u64, data: Vec<u8>) -> Result<()> {
+        let channels = self.channels.lock().unwrap();
+        let channel = channels
+            .get(&channel_id)
+            .ok_or_else(|| ServerError::Execution(format!("Unknown channel: {}", channel_id)))?;
+
+        channel
+            .tx
+            .send(data)
+            .map_err(|_| ServerError::Execution(format!("Channel {} is closed", channel_id)))
+    }
+
+    /// Close a channel
+    pub fn close(&self, channel_id: u64) {
+        if let Some(channel) = self.channels.lock().unwrap().remove(&channel_id) {
+            channel.reader.abort();
+            debug!(channel_id = channel_id, "Forward channel closed");
+        }
+    }
+
+    /// Start listening for a remote forward
+    pub async fn start_remote(&self, bind_port: u16, outbound: Outbound) -> Result<()> {
+        if !self.policy.remote_ports.contains(&bind_port) {
+            return Err(ServerError::Denied(format!(
+                "Remote forwarding on port {} is not permitted",
+                bind_port
+            )));
+        }
+
+        if self.listeners.lock().unwrap().contains_key(&bind_port) {
+            return Err(ServerError::Execution(format!(
+                "Port {} is already forwarded",
+                bind_port
+            )));
+        }
+
+        let listener = TcpListener::bind((self.policy.bind_address.as_str(), bind_port)).await?;
+
+        info!(
+            bind = %self.policy.bind_address,
+            port = bind_port,
+            "Remote forward listening"
+        );
+
+        let channels = Arc::clone(&self.channels);
+        let next_channel = Arc::clone(&self.next_channel);
+
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!(port = bind_port, error = %e, "Remote forward accept failed");
+                        continue;
+                    }
+                };
+
+                let channel_id = next_channel.fetch_add(1, Ordering::SeqCst);
+                debug!(channel_id = channel_id, peer = %peer, "Remote forward connection");
+
+                let open = Message::ChannelOpen(ChannelOpenRequest {
+                    channel_id,
+                    kind: ChannelKind::Forwarded {
+                        bind_port,
+                        originator: peer.to_string(),
+                    },
+                });
+                if outbound.send(open).is_err() {
+                    break;
+                }
+
+                if let Err(e) = attach(&channels, channel_id, stream, outbound.clone()) {
+                    warn!(channel_id = channel_id, error = %e, "Remote forward not attached");
+                }
+            }
+        });
+
+        self.listeners.lock().unwrap().insert(bind_port, handle);
+        Ok(())
+    }
+
+    /// Stop a remote forward
+    pub fn stop_remote(&self, bind_port: u16) -> Result<()> {
+        let handle = self
+            .listeners
+            .lock()
+            .unwrap()
+            .remove(&bind_port)
+            .ok_or_else(|| ServerError::Execution(format!("Port {} is not forwarded", bind_port)))?;
+
+        handle.abort();
+        info!(port = bind_port, "Remote forward stopped");
+        Ok(())
+    }
+
+    /// Close all channels and listeners
+    pub fn shutdown(&self) {
+        for (_, handle) in self.listeners.lock().unwrap().drain() {
+            handle.abort();
+        }
+        for (_, channel) in self.channels.lock().unwrap().drain() {
+            channel.reader.abort();
+        }
+    }
+
+    /// Get number of open channels
+    pub fn channel_count(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+}
+
+/// Register the channel and spawn the pumps for a connected stream
+///
+/// The map stays locked until the channel is in it, so a stream that closes
+/// at once can't be removed before it was inserted.
+fn attach<S>(channels: &Channels, channel_id: u64, stream: S, outbound: Outbound) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut open = channels.lock().unwrap();
+    if open.contains_key(&channel_id) {
+        return Err(ServerError::Execution(format!(
+            "Channel {} is already open",
+            channel_id
+        )));
+    }
+
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    // Client → socket; ends when the channel is closed (sender dropped)
+    tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+            if write_half.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = write_half.shutdown().await;
+    });
+
+    // Socket → client
+    let reader_channels = Arc::clone(channels);
+    let reader = tokio::spawn(async move {
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        let reason = loop {
+            match read_half.read(&mut buf).await {
+                Ok(0) => break None,
+                Ok(n) => {
+                    let message = Message::ChannelData(ChannelData {
+                        channel_id,
+                        data: buf[..n].to_vec(),
+                    });
+                    if outbound.send(message).is_err() {
+                        break None;
+                    }
+                }
+                Err(e) => break Some(e.to_string()),
+            }
+        };
+
+        reader_channels.lock().unwrap().remove(&channel_id);
+        let _ = outbound.send(Message::ChannelClose(ChannelClose { channel_id, reason }));
+    });
+
+    open.insert(channel_id, Channel { tx, reader });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], remote_ports: &[u16]) -> ForwardPolicy {
+        ForwardPolicy {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            remote_ports: remote_ports.to_vec(),
+            bind_address: "127.0.0.1".to_string(),
+            socks_allow: vec![],
+            socks_deny: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let policy = policy(&["localhost:5432", "10.0.0.1:*", "127.0.0.2"], &[]);
+
+        assert!(policy.resolve("localhost", 5432).await.is_ok());
+        assert!(policy.resolve("localhost", 22).await.is_err());
+        assert!(policy.resolve("10.0.0.1", 80).await.is_ok());
+        assert!(policy.resolve("::ffff:127.0.0.2", 80).await.is_ok());
+        assert!(policy.resolve("127.0.0.1", 5432).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks_policy() {
+        let mut policy = policy(&[], &[]);
+        policy.socks_allow = vec!["*".to_string()];
+        policy.socks_deny = ["169.254.169.254", "127.*", "*:25"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        assert!(policy.resolve_socks("10.1.2.3", 443).await.is_ok());
+        assert!(policy.resolve_socks("169.254.169.254", 80).await.is_err());
+        assert!(policy.resolve_socks("10.1.2.3", 25).await.is_err());
+        // Names are checked by the addresses they resolve to
+        assert!(matches!(
+            policy.resolve_socks("localhost", 80).await,
+            Err(ServerError::Denied(_))
+        ));
+        assert!(policy.resolve_socks("::ffff:127.0.0.1", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_lookup_unless_needed() {
+        // `.invalid` never resolves: an I/O error would mean a lookup ran
+        let unresolvable = "probe.invalid";
+        let disabled = policy(&[], &[]);
+        assert!(matches!(
+            disabled.resolve(unresolvable, 80).await,
+            Err(ServerError::Denied(_))
+        ));
+        assert!(matches!(
+            disabled.resolve_socks(unresolvable, 80).await,
+            Err(ServerError::Denied(_))
+        ));
+
+        let mut names_only = policy(&["db.internal:5432"], &[]);
+        assert!(matches!(
+            names_only.resolve(unresolvable, 5432).await,
+            Err(ServerError::Denied(_))
+        ));
+        names_only.socks_allow = vec!["*".to_string()];
+        names_only.socks_deny = vec!["*.invalid".to_string()];
+        assert!(matches!(
+            names_only.resolve_socks(unresolvable, 80).await,
+            Err(ServerError::Denied(_))
+        ));
+
+        // Address patterns need the name resolved to be checked
+        let addresses = policy(&["10.0.0.*:*"], &[]);
+        assert!(matches!(
+            addresses.resolve(unresolvable, 80).await,
+            Err(ServerError::Io(_))
+        ));
+
+        assert!(is_address_pattern("10.0.0.*"));
+        assert!(is_address_pattern("fd00::*:22"));
+        assert!(!is_address_pattern("*"));
+        assert!(!is_address_pattern("db:5432"));
+        assert!(!is_address_pattern("*.internal:*"));
+    }
+
+    #[tokio::test]
+    async fn test_direct_forward() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(&buf[..n]).await.unwrap();
+        });
+
+        let service = ForwardService::new(policy(&["127.0.0.1:*"], &[]));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        service.open_direct(1, "127.0.0.1", port, tx).await.unwrap();
+        service.send(1, b"ping".to_vec()).unwrap();
+
+        match rx.recv().await.unwrap() {
+            Message::ChannelData(data) => {
+                assert_eq!(data.channel_id, 1);
+                assert_eq!(data.data, b"ping");
+            }
+            other => panic!("Expected ChannelData, got {:?}", other),
+        }
+
+        assert!(matches!(rx.recv().await.unwrap(), Message::ChannelClose(_)));
+    }
+
+    #[tokio::test]
+    async fn test_attach() {
+        let service = ForwardService::new(policy(&[], &[]));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let (live, _peer) = tokio::io::duplex(64);
+        service.attach(1, live, tx.clone()).unwrap();
+        let (other, _other_peer) = tokio::io::duplex(64);
+        assert!(service.attach(1, other, tx.clone()).is_err());
+        assert_eq!(service.channel_count(), 1);
+
+        // A stream closed from the start leaves nothing behind
+        let (closed, closed_peer) = tokio::io::duplex(64);
+        drop(closed_peer);
+        service.attach(2, closed, tx).unwrap();
+        match rx.recv().await.unwrap() {
+            Message::ChannelClose(close) => assert_eq!(close.channel_id, 2),
+            other => panic!("Expected ChannelClose, got {:?}", other),
+        }
+        assert_eq!(service.channel_count(), 1);
+        assert!(service.send(1, b"still open".to_vec()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_denied_targets() {
+        let service = ForwardService::new(policy(&[], &[]));
+        let (tx, _rx) = mpsc::unbounded_channel();
+
+        assert!(service.open_direct(1, "127.0.0.1", 22, tx.clone()).await.is_err());
+        assert!(service.open_dynamic(2, tx.clone()).is_err());
+        assert!(service.start_remote(8080, tx).await.is_err());
+    }
+}
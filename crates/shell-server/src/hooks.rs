@@ -0,0 +1,327 @@
+//! Pre/post execution hooks
+//!
+//! A [`Hook`] sees every command a client asks for before it runs and can
+//! veto it, and sees the result afterwards. This is the extension point for
+//! site policy that doesn't fit allow/deny lists: checking a ticket id in the
+//! environment, asking an external approval service, posting notifications.
+//!
+//! [`ScriptHook`] runs external programs configured with `pre_exec_hook` and
+//! `post_exec_hook`. Each gets the event as one JSON object on stdin:
+//!
+//! ```json
+//! {"hook": "pre", "client": "<hex>", "session_id": "<uuid>",
+//!  "command": "ls", "args": ["-l"], "working_dir": "/srv", "env": {...}}
+//! ```
+//!
+//! The post hook additionally gets `status`, `exit_code` and
+//! `execution_time_ms` (not the output). Programs started in a PTY go
+//! through the pre hook only. A pre hook vetoes the command by
+//! exiting non-zero; the first line it printed (stdout, else stderr) is
+//! returned to the client as the reason. Hooks that fail to run or exceed
+//! `hook_timeout` veto as well, so a broken policy script fails closed.
+
+use crate::config::ServerConfig;
+use serde_json::json;
+use shell_proto::{CommandRequest, CommandResponse, SessionId};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command as TokioCommand;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Future returned by hook methods
+pub type HookFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A command about to run or just finished
+#[derive(Debug, Clone, Copy)]
+pub struct HookEvent<'a> {
+    /// Client identity (public key)
+    pub client_identity: &'a [u8],
+
+    /// Session the command belongs to
+    pub session_id: SessionId,
+
+    /// The request, with session working directory and environment applied
+    pub request: &'a CommandRequest,
+}
+
+/// Called around every command a client runs
+pub trait Hook: Send + Sync {
+    /// Inspect a command before it runs; an error vetoes it with that reason
+    fn before<'a>(&'a self, event: HookEvent<'a>) -> HookFuture<'a, Result<(), String>>;
+
+    /// Inspect a command's result (not called for vetoed commands)
+    fn after<'a>(
+        &'a self,
+        _event: HookEvent<'a>,
+        _response: &'a CommandResponse,
+    ) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Run every hook's `before`, stopping at the first veto
+pub async fn run_before(hooks: &[Arc<dyn Hook>], event: HookEvent<'_>) -> Result<(), String> {
+    for hook in hooks {
+        hook.before(event).await?;
+    }
+    Ok(())
+}
+
+/// Run every hook's `after`
+pub async fn run_after(hooks: &[Arc<dyn Hook>], event: HookEvent<'_>, response: &CommandResponse) {
+    for hook in hooks {
+        hook.after(event, response).await;
+    }
+}
+
+/// Hook running external programs
+#[derive(Debug, Clone)]
+pub struct ScriptHook {
+    /// Program run before each command (exit status decides)
+    pre: Option<PathBuf>,
+
+    /// Program run after each command
+    post: Option<PathBuf>,
+
+    /// How long a hook program may run
+    timeout: Duration,
+}
+
+impl ScriptHook {
+    /// Create a hook running `pre` and/or `post`
+    pub fn new(pre: Option<PathBuf>, post: Option<PathBuf>, timeout: Duration) -> Self {
+        Self { pre, post, timeout }
+    }
+
+    /// Build the configured script hook, if any
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        if config.pre_exec_hook.is_none() && config.post_exec_hook.is_none() {
+            return None;
+        }
+        Some(Self::new(
+            config.pre_exec_hook.clone(),
+            config.post_exec_hook.clone(),
+            Duration::from_secs(config.hook_timeout),
+        ))
+    }
+
+    /// Run `program` with `input` on stdin, returning whether it succeeded
+    /// and the first line it printed
+    async fn run(&self, program: &Path, input: String) -> Result<(bool, String), String> {
+        let mut child = TokioCommand::new(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("hook {} failed to start: {}", program.display(), e))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let output = timeout(self.timeout, async move {
+            // A hook that ignores its input may exit before reading it
+            let _ = stdin.write_all(input.as_bytes()).await;
+            drop(stdin);
+            child.wait_with_output().await
+        })
+        .await
+        .map_err(|_| format!("hook {} timed out", program.display()))?
+        .map_err(|e| format!("hook {} failed: {}", program.display(), e))?;
+
+        let first_line = |bytes: &[u8]| {
+            String::from_utf8_lossy(bytes)
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .map(str::to_string)
+        };
+        let message = first_line(&output.stdout)
+            .or_else(|| first_line(&output.stderr))
+            .unwrap_or_default();
+        Ok((output.status.success(), message))
+    }
+}
+
+impl Hook for ScriptHook {
+    fn before<'a>(&'a self, event: HookEvent<'a>) -> HookFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let Some(program) = &self.pre else {
+                return Ok(());
+            };
+
+            let input = event_json("pre", event).to_string();
+            match self.run(program, input).await {
+                Ok((true, _)) => Ok(()),
+                Ok((false, message)) => {
+                    debug!(
+                        command = %event.request.command,
+                        reason = %message,
+                        "Hook vetoed command"
+                    );
+                    if message.is_empty() {
+                        Err("vetoed by pre-exec hook".to_string())
+                    } else {
+                        Err(message)
+                    }
+                }
+                Err(e) => {
+                    warn!(error = %e, "Pre-exec hook failed, refusing command");
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn after<'a>(
+        &'a self,
+        event: HookEvent<'a>,
+        response: &'a CommandResponse,
+    ) -> HookFuture<'a, ()> {
+        Box::pin(async move {
+            let Some(program) = &self.post else {
+                return;
+            };
+
+            let mut input = event_json("post", event);
+            input["status"] = json!(format!("{:?}", response.status));
+            input["exit_code"] = json!(response.exit_code);
+            input["execution_time_ms"] = json!(response.execution_time_ms);
+            match self.run(program, input.to_string()).await {
+                Ok((true, _)) => {}
+                Ok((false, message)) => {
+                    warn!(message = %message, "Post-exec hook exited unsuccessfully")
+                }
+                Err(e) => warn!(error = %e, "Post-exec hook failed"),
+            }
+        })
+    }
+}
+
+fn event_json(hook: &str, event: HookEvent<'_>) -> serde_json::Value {
+    json!({
+        "hook": hook,
+        "client": hex::encode(event.client_identity),
+        "session_id": Uuid::from_bytes(event.session_id).to_string(),
+        "command": event.request.command,
+        "args": event.request.args,
+        "working_dir": event.request.working_dir,
+        "env": event.request.env,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(dir: &Path, name: &str, body: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn request(command: &str) -> CommandRequest {
+        CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_script_veto() {
+        let dir = tempfile::tempdir().unwrap();
+        // Refuse anything but "ls", reading the command from the JSON event
+        let pre = script(
+            dir.path(),
+            "pre",
+            r#"grep -q '"command":"ls"' || { echo "only ls today"; exit 1; }"#,
+        );
+        let hook = ScriptHook::new(Some(pre), None, Duration::from_secs(5));
+
+        let allowed = request("ls");
+        let event = HookEvent {
+            client_identity: &[1, 2, 3],
+            session_id: [0; 16],
+            request: &allowed,
+        };
+        assert_eq!(hook.before(event).await, Ok(()));
+
+        let denied = request("rm");
+        let event = HookEvent {
+            request: &denied,
+            ..event
+        };
+        assert_eq!(hook.before(event).await, Err("only ls today".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_script_fails_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let slow = script(dir.path(), "slow", "sleep 5");
+        let request = request("ls");
+        let event = HookEvent {
+            client_identity: &[1, 2, 3],
+            session_id: [0; 16],
+            request: &request,
+        };
+
+        let hook = ScriptHook::new(Some(slow), None, Duration::from_millis(100));
+        assert!(hook.before(event).await.is_err());
+
+        let missing = ScriptHook::new(
+            Some(dir.path().join("missing")),
+            None,
+            Duration::from_secs(5),
+        );
+        assert!(missing.before(event).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let post = script(dir.path(), "post", &format!("cat > {}", log.display()));
+        let hook = ScriptHook::new(None, Some(post), Duration::from_secs(5));
+
+        let request = request("true");
+        let event = HookEvent {
+            client_identity: &[1, 2, 3],
+            session_id: [0; 16],
+            request: &request,
+        };
+        let response = CommandResponse {
+            id: 1,
+            status: shell_proto::CommandStatus::Success,
+            stdout: vec![],
+            stderr: vec![],
+            exit_code: 0,
+            execution_time_ms: 3,
+            truncated: false,
+            total_bytes: 0,
+            spool_id: None,
+            delta_base: None,
+        };
+        assert_eq!(hook.before(event).await, Ok(()));
+        hook.after(event, &response).await;
+
+        let logged: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(logged["hook"], "post");
+        assert_eq!(logged["client"], "010203");
+        assert_eq!(logged["exit_code"], 0);
+    }
+}
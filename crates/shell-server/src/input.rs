@@ -0,0 +1,139 @@
+//! Stdin of running commands
+//!
+//! A command request with `stdin` set gets a pipe as stdin, fed from the
+//! COMMAND_INPUT messages for its ID. Requests run aside from the message
+//! loop, so the session opens the command's feed as soon as the request
+//! arrives; input that comes before the command starts is held until then.
+
+use shell_proto::CommandInput;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Child;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Input for one command, ending when the sender is dropped
+pub type InputReceiver = UnboundedReceiver<Vec<u8>>;
+
+/// Input feeds of a session's commands, by request ID
+#[derive(Debug, Default)]
+pub struct Inputs {
+    feeds: Mutex<HashMap<u64, Feed>>,
+}
+
+#[derive(Debug)]
+struct Feed {
+    /// `None` once the input ended
+    tx: Option<UnboundedSender<Vec<u8>>>,
+
+    /// `None` once the command took it
+    rx: Option<InputReceiver>,
+}
+
+impl Inputs {
+    /// Accept input for the command of request `id` (again is a no-op)
+    pub fn open(&self, id: u64) {
+        self.feeds.lock().unwrap().entry(id).or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            Feed {
+                tx: Some(tx),
+                rx: Some(rx),
+            }
+        });
+    }
+
+    /// Pass on input; returns false if the command takes none (any more)
+    pub fn push(&self, input: CommandInput) -> bool {
+        let mut feeds = self.feeds.lock().unwrap();
+        let Some(feed) = feeds.get_mut(&input.id) else {
+            return false;
+        };
+        let Some(tx) = &feed.tx else {
+            return false;
+        };
+        if !input.data.is_empty() && tx.send(input.data).is_err() {
+            // The command no longer reads it
+            feed.tx = None;
+            return false;
+        }
+        if input.eof {
+            feed.tx = None;
+        }
+        true
+    }
+
+    /// The input of the command of request `id`, if it was opened
+    pub fn take(&self, id: u64) -> Option<InputReceiver> {
+        self.feeds
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .and_then(|feed| feed.rx.take())
+    }
+
+    /// Stop accepting input for the command of request `id`
+    pub fn close(&self, id: u64) {
+        self.feeds.lock().unwrap().remove(&id);
+    }
+
+    /// Stop accepting input for any command
+    pub fn clear(&self) {
+        self.feeds.lock().unwrap().clear();
+    }
+}
+
+/// Write `input` to the stdin of `child` as it arrives, closing the pipe
+/// when the input ends
+///
+/// Returns `None` if the child has no stdin pipe.
+pub fn feed(child: &mut Child, mut input: InputReceiver) -> Option<JoinHandle<()>> {
+    let mut stdin = child.stdin.take()?;
+    Some(tokio::spawn(async move {
+        while let Some(data) = input.recv().await {
+            if let Err(e) = stdin.write_all(&data).await {
+                // Typically the command exited or closed its stdin
+                debug!(error = %e, "Stopped writing command input");
+                return;
+            }
+        }
+        let _ = stdin.shutdown().await;
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(id: u64, data: &[u8], eof: bool) -> CommandInput {
+        CommandInput {
+            id,
+            data: data.to_vec(),
+            eof,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inputs() {
+        let inputs = Inputs::default();
+        assert!(!inputs.push(input(1, b"early", false)));
+
+        inputs.open(1);
+        assert!(inputs.push(input(1, b"held ", false)));
+        assert!(inputs.push(input(1, b"until taken", true)));
+        assert!(!inputs.push(input(1, b"after eof", false)));
+
+        let mut rx = inputs.take(1).unwrap();
+        assert!(inputs.take(1).is_none());
+        assert_eq!(rx.recv().await.unwrap(), b"held ");
+        assert_eq!(rx.recv().await.unwrap(), b"until taken");
+        assert!(rx.recv().await.is_none());
+
+        inputs.open(2);
+        let mut rx = inputs.take(2).unwrap();
+        inputs.close(2);
+        assert!(rx.recv().await.is_none());
+        assert!(!inputs.push(input(2, b"late", false)));
+    }
+}
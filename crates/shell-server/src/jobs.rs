@@ -0,0 +1,504 @@
+//! Background jobs
+//!
+//! Jobs are commands started detached from the request/response cycle. Their
+//! output is captured into per-stream ring buffers (optionally spilled to disk
+//! so older output stays retrievable) and fetched incrementally by offset.
+
+use crate::{process::ProcessGroup, shell::CommandExecutor, Result, ServerError};
+use shell_proto::{CommandRequest, JobInfo, JobState};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+/// Captured output of a single stream
+///
+/// The most recent `capacity` bytes are kept in memory. When a spill file is
+/// configured every byte is also appended to it, so reads behind the ring
+/// buffer are served from disk.
+pub struct OutputBuffer {
+    /// Most recent output
+    ring: VecDeque<u8>,
+
+    /// Maximum bytes kept in memory
+    capacity: usize,
+
+    /// Total bytes ever written
+    total: u64,
+
+    /// Spill file (path and append handle)
+    spill: Option<(PathBuf, File)>,
+}
+
+impl OutputBuffer {
+    /// Create a new buffer, optionally spilling to `spill_path`
+    pub fn new(capacity: usize, spill_path: Option<PathBuf>) -> Self {
+        let spill = spill_path.and_then(|path| match File::create(&path) {
+            Ok(file) => Some((path, file)),
+            Err(e) => {
+                warn!(path = ?path, error = %e, "Failed to create spill file, keeping output in memory only");
+                None
+            }
+        });
+
+        Self {
+            ring: VecDeque::with_capacity(capacity.min(64 * 1024)),
+            capacity,
+            total: 0,
+            spill,
+        }
+    }
+
+    /// Append output
+    pub fn push(&mut self, data: &[u8]) {
+        let spill_failed = match &mut self.spill {
+            Some((path, file)) => match file.write_all(data) {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!(path = ?path, error = %e, "Failed to write spill file, disabling spill");
+                    true
+                }
+            },
+            None => false,
+        };
+        if spill_failed {
+            self.spill = None;
+        }
+
+        self.ring.extend(data);
+        if self.ring.len() > self.capacity {
+            let excess = self.ring.len() - self.capacity;
+            self.ring.drain(..excess);
+        }
+
+        self.total += data.len() as u64;
+    }
+
+    /// Total bytes ever written
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Read up to `max` bytes starting at `offset`
+    ///
+    /// Returns the offset of the first returned byte, which is later than
+    /// `offset` if that output is no longer available.
+    pub fn read(&self, offset: u64, max: usize) -> (u64, Vec<u8>) {
+        let offset = offset.min(self.total);
+        let ring_start = self.total - self.ring.len() as u64;
+
+        if offset < ring_start {
+            if let Some((path, _)) = &self.spill {
+                match Self::read_spill(path, offset, max) {
+                    Ok(data) => return (offset, data),
+                    Err(e) => warn!(path = ?path, error = %e, "Failed to read spill file"),
+                }
+            }
+        }
+
+        let start = offset.max(ring_start);
+        let skip = (start - ring_start) as usize;
+        let data = self.ring.iter().skip(skip).take(max).copied().collect();
+
+        (start, data)
+    }
+
+    /// Read a range from the spill file
+    fn read_spill(path: &Path, offset: u64, max: usize) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::new();
+        file.take(max as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Delete the spill file, if any
+    pub fn remove_spill(&mut self) {
+        if let Some((path, _)) = self.spill.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Mutable state of a job
+struct JobRecord {
+    state: JobState,
+    exit_code: Option<i32>,
+}
+
+/// A background job
+struct Job {
+    id: u64,
+    command: String,
+    args: Vec<String>,
+    started_at: u64,
+    record: Mutex<JobRecord>,
+    stdout: Mutex<OutputBuffer>,
+    stderr: Mutex<OutputBuffer>,
+    kill: Notify,
+}
+
+impl Job {
+    fn info(&self) -> JobInfo {
+        let record = self.record.lock().unwrap();
+        JobInfo {
+            job_id: self.id,
+            command: self.command.clone(),
+            args: self.args.clone(),
+            state: record.state,
+            exit_code: record.exit_code,
+            started_at: self.started_at,
+            stdout_bytes: self.stdout.lock().unwrap().total(),
+            stderr_bytes: self.stderr.lock().unwrap().total(),
+        }
+    }
+}
+
+/// Output read from a job
+pub struct JobOutputChunk {
+    /// Offset of the first stdout byte
+    pub stdout_offset: u64,
+
+    /// Stdout bytes
+    pub stdout: Vec<u8>,
+
+    /// Offset of the first stderr byte
+    pub stderr_offset: u64,
+
+    /// Stderr bytes
+    pub stderr: Vec<u8>,
+
+    /// Job summary at the time of the read
+    pub info: JobInfo,
+}
+
+/// Per-session background job table
+pub struct JobManager {
+    /// Jobs by ID
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+
+    /// Next job ID
+    next_id: AtomicU64,
+
+    /// Maximum concurrently running jobs
+    max_jobs: usize,
+
+    /// In-memory buffer size per stream
+    buffer_size: usize,
+
+    /// Spill directory and file name prefix
+    spool: Option<(PathBuf, String)>,
+}
+
+impl JobManager {
+    /// Create a new job manager
+    ///
+    /// Spill files are named `<prefix>-job<id>.stdout|stderr` inside `spool_dir`.
+    pub fn new(
+        max_jobs: usize,
+        buffer_size: usize,
+        spool_dir: Option<PathBuf>,
+        prefix: &str,
+    ) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            max_jobs,
+            buffer_size,
+            spool: spool_dir.map(|dir| (dir, prefix.to_string())),
+        }
+    }
+
+    /// Start a job
+    pub fn start(&self, executor: &CommandExecutor, request: CommandRequest) -> Result<JobInfo> {
+        if self.running() >= self.max_jobs {
+            return Err(ServerError::Execution(format!(
+                "Maximum background jobs reached ({})",
+                self.max_jobs
+            )));
+        }
+
+        let (mut cmd, mut guard) = executor.build_command(&request)?;
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| ServerError::Execution(format!("Failed to start job: {}", e)))?;
+        guard.group = ProcessGroup::of(&child);
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let job = Arc::new(Job {
+            id,
+            command: request.command.clone(),
+            args: request.args.clone(),
+            started_at: chrono::Utc::now().timestamp() as u64,
+            record: Mutex::new(JobRecord {
+                state: JobState::Running,
+                exit_code: None,
+            }),
+            stdout: Mutex::new(OutputBuffer::new(self.buffer_size, self.spill_path(id, "stdout"))),
+            stderr: Mutex::new(OutputBuffer::new(self.buffer_size, self.spill_path(id, "stderr"))),
+            kill: Notify::new(),
+        });
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let timeout = request.timeout.map(Duration::from_secs);
+
+        let waiter = Arc::clone(&job);
+        tokio::spawn(async move {
+            let stdout_task = stdout.map(|s| tokio::spawn(pump(s, Arc::clone(&waiter), false)));
+            let stderr_task = stderr.map(|s| tokio::spawn(pump(s, Arc::clone(&waiter), true)));
+
+            let deadline = async {
+                match timeout {
+                    Some(t) => tokio::time::sleep(t).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let mut killed = false;
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = waiter.kill.notified() => {
+                    killed = true;
+                    guard.kill();
+                    let _ = child.start_kill();
+                    child.wait().await
+                }
+                _ = deadline => {
+                    killed = true;
+                    guard.kill();
+                    let _ = child.start_kill();
+                    child.wait().await
+                }
+            };
+
+            if let Some(task) = stdout_task {
+                let _ = task.await;
+            }
+            if let Some(task) = stderr_task {
+                let _ = task.await;
+            }
+
+            // An OOM kill shows up as a signal death; the job reports Killed
+            if guard.cgroup.as_ref().is_some_and(|cgroup| cgroup.oom_killed()) {
+                killed = true;
+            }
+            if !killed {
+                guard.finished();
+            }
+            drop(guard);
+
+            let mut record = waiter.record.lock().unwrap();
+            match status {
+                Ok(status) => {
+                    record.state = if killed { JobState::Killed } else { JobState::Exited };
+                    record.exit_code = status.code();
+                }
+                Err(e) => {
+                    warn!(job_id = waiter.id, error = %e, "Failed to wait for job");
+                    record.state = JobState::Failed;
+                }
+            }
+
+            debug!(job_id = waiter.id, state = ?record.state, exit_code = ?record.exit_code, "Job finished");
+        });
+
+        info!(job_id = id, command = %request.command, "Background job started");
+
+        let info = job.info();
+        self.jobs.lock().unwrap().insert(id, job);
+        Ok(info)
+    }
+
+    /// List all jobs
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<JobInfo> = self.jobs.lock().unwrap().values().map(|job| job.info()).collect();
+        jobs.sort_by_key(|job| job.job_id);
+        jobs
+    }
+
+    /// Read job output starting at the given offsets
+    pub fn output(
+        &self,
+        job_id: u64,
+        stdout_offset: u64,
+        stderr_offset: u64,
+        max_bytes: usize,
+    ) -> Result<JobOutputChunk> {
+        let job = self.get(job_id)?;
+
+        // Snapshot state first so a job reported as finished has all its output visible
+        let info = job.info();
+        let (stdout_offset, stdout) = job.stdout.lock().unwrap().read(stdout_offset, max_bytes);
+        let (stderr_offset, stderr) = job.stderr.lock().unwrap().read(stderr_offset, max_bytes);
+
+        Ok(JobOutputChunk {
+            stdout_offset,
+            stdout,
+            stderr_offset,
+            stderr,
+            info,
+        })
+    }
+
+    /// Request that a job be killed
+    pub fn kill(&self, job_id: u64) -> Result<JobInfo> {
+        let job = self.get(job_id)?;
+        job.kill.notify_one();
+        Ok(job.info())
+    }
+
+    /// Count jobs that are still running
+    pub fn running(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.record.lock().unwrap().state == JobState::Running)
+            .count()
+    }
+
+    /// Kill every running job and remove spill files
+    pub fn shutdown(&self) {
+        let jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().drain().map(|(_, job)| job).collect();
+        for job in jobs {
+            job.kill.notify_one();
+            job.stdout.lock().unwrap().remove_spill();
+            job.stderr.lock().unwrap().remove_spill();
+        }
+    }
+
+    fn get(&self, job_id: u64) -> Result<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| ServerError::Execution(format!("Unknown job: {}", job_id)))
+    }
+
+    fn spill_path(&self, id: u64, stream: &str) -> Option<PathBuf> {
+        self.spool
+            .as_ref()
+            .map(|(dir, prefix)| dir.join(format!("{}-job{}.{}", prefix, id, stream)))
+    }
+}
+
+/// Copy a child stream into a job's output buffer
+async fn pump<R: AsyncRead + Unpin>(mut stream: R, job: Arc<Job>, is_stderr: bool) {
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                let buffer = if is_stderr { &job.stderr } else { &job.stdout };
+                buffer.lock().unwrap().push(&buf[..n]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(command: &str, args: &[&str]) -> CommandRequest {
+        CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    async fn wait_finished(manager: &JobManager, job_id: u64) -> JobInfo {
+        for _ in 0..100 {
+            let info = manager.output(job_id, 0, 0, 0).unwrap().info;
+            if info.state != JobState::Running {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Job did not finish");
+    }
+
+    #[test]
+    fn test_output_buffer_ring() {
+        let mut buffer = OutputBuffer::new(4, None);
+        buffer.push(b"abcdef");
+
+        assert_eq!(buffer.total(), 6);
+        assert_eq!(buffer.read(0, 10), (2, b"cdef".to_vec()));
+        assert_eq!(buffer.read(4, 1), (4, b"e".to_vec()));
+        assert_eq!(buffer.read(10, 10), (6, vec![]));
+    }
+
+    #[test]
+    fn test_output_buffer_spill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        let mut buffer = OutputBuffer::new(4, Some(path.clone()));
+        buffer.push(b"abc");
+        buffer.push(b"def");
+
+        assert_eq!(buffer.read(0, 10), (0, b"abcdef".to_vec()));
+
+        buffer.remove_spill();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let executor = CommandExecutor::new(30);
+        let manager = JobManager::new(4, 1024, None, "test");
+
+        let info = manager.start(&executor, request("echo", &["hello"])).unwrap();
+        let finished = wait_finished(&manager, info.job_id).await;
+
+        assert_eq!(finished.state, JobState::Exited);
+        assert_eq!(finished.exit_code, Some(0));
+
+        let chunk = manager.output(info.job_id, 0, 0, 1024).unwrap();
+        assert_eq!(String::from_utf8_lossy(&chunk.stdout).trim(), "hello");
+        assert_eq!(manager.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_job_kill() {
+        let executor = CommandExecutor::new(30);
+        let manager = JobManager::new(4, 1024, None, "test");
+
+        let info = manager.start(&executor, request("sleep", &["30"])).unwrap();
+        manager.kill(info.job_id).unwrap();
+
+        let finished = wait_finished(&manager, info.job_id).await;
+        assert_eq!(finished.state, JobState::Killed);
+    }
+
+    #[tokio::test]
+    async fn test_job_limit() {
+        let executor = CommandExecutor::new(30);
+        let manager = JobManager::new(1, 1024, None, "test");
+
+        manager.start(&executor, request("sleep", &["30"])).unwrap();
+        assert!(manager.start(&executor, request("sleep", &["30"])).is_err());
+
+        manager.shutdown();
+        assert!(manager.list().is_empty());
+    }
+}
@@ -2,11 +2,49 @@
 //!
 //! Core functionality for the remote shell server
 
+pub mod accounting;
+pub mod admin;
+pub mod approval;
+pub mod audit;
+pub mod audit_sink;
+pub mod auth;
+pub mod ban;
+pub mod bandwidth;
+pub mod builtins;
+pub mod cgroup;
+pub mod check;
+pub mod completion;
 pub mod config;
+pub mod container;
+pub mod daemon;
+pub mod delta;
+pub mod env_policy;
 pub mod error;
+pub mod files;
+pub mod forward;
+pub mod hooks;
+pub mod input;
+pub mod jobs;
 pub mod listener;
+pub mod metrics;
+pub mod noise;
+pub mod pattern;
+pub mod policy;
+pub mod process;
+pub mod pty;
+pub mod recording;
+pub mod resume;
+pub mod rlimit;
+pub mod sandbox;
+pub mod seccomp;
+pub mod secrets;
+pub mod send_queue;
 pub mod server;
 pub mod session;
+pub mod session_dir;
 pub mod shell;
+pub mod socks;
+pub mod spool;
+pub mod transfer;
 
 pub use error::{Result, ServerError};
@@ -1,14 +1,44 @@
 //! Network listener for incoming connections
 
-use crate::{config::ServerConfig, session::Session, shell::CommandExecutor, Result};
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    auth::{ConnectVerifier, TokenVerifier},
+    ban::BanList,
+    config::ServerConfig,
+    env_policy::EnvPolicy,
+    metrics::Metrics,
+    policy::CommandPolicy,
+    resume::SessionStore,
+    session::Session,
+    shell::CommandExecutor,
+    Result,
+};
 use shell_proto::{
     messages::{AcceptMessage, ConnectMessage, RejectMessage},
     Message, CURRENT_PROTOCOL_VERSION,
 };
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
 
+/// Capabilities advertised in ACCEPT messages and announces
+pub const CAPABILITIES: &[&str] = &[
+    "command-exec",
+    "pty",
+    "jobs",
+    "file-transfer",
+    "file-ops",
+    "port-forward",
+    "socks",
+    "exec-upload",
+    "output-stream",
+    "stdin",
+    "echo",
+    "frame-priority",
+    "output-delta",
+];
+
 /// Connection listener
 pub struct Listener {
     /// Server configuration
@@ -17,27 +47,113 @@ pub struct Listener {
     /// Command executor
     executor: Arc<CommandExecutor>,
 
+    /// CONNECT signature checks
+    signatures: ConnectVerifier,
+
+    /// Auth token checks
+    tokens: TokenVerifier,
+
+    /// Failed connect tracking and bans
+    bans: Arc<BanList>,
+
     /// Active sessions
     sessions: Arc<RwLock<Vec<Arc<Session>>>>,
+
+    /// Audit log
+    audit: Arc<AuditLog>,
+
+    /// Metrics registry
+    metrics: Arc<Metrics>,
+
+    /// Persisted sessions (None = sessions are not resumable)
+    store: Option<Arc<SessionStore>>,
+
+    /// Set once the server starts shutting down
+    draining: AtomicBool,
 }
 
 impl Listener {
     /// Create a new listener
     pub fn new(config: ServerConfig) -> Self {
-        let executor = Arc::new(CommandExecutor::new(config.command_timeout));
+        let mut executor = CommandExecutor::new(config.command_timeout)
+            .with_policy(CommandPolicy::from_config(&config))
+            .with_rlimits(config.rlimits)
+            .with_builtins(config.builtins)
+            .with_max_output(config.max_output_size)
+            .with_env_policy(EnvPolicy::from_config(&config))
+            .with_read_only(config.read_only.then(|| config.read_only_commands.clone()));
+        if let Some(cgroup) = &config.cgroup {
+            executor = executor.with_cgroup(cgroup.clone());
+        }
+        if let Some(shell) = &config.shell {
+            executor = executor.with_shell(shell.clone());
+        }
+        let executor = Arc::new(executor);
 
         Self {
+            signatures: ConnectVerifier::from_config(&config),
+            tokens: TokenVerifier::from_config(&config),
+            bans: Arc::new(BanList::from_config(&config)),
             config: Arc::new(config),
             executor,
             sessions: Arc::new(RwLock::new(Vec::new())),
+            audit: Arc::new(AuditLog::disabled()),
+            metrics: Arc::new(Metrics::new()),
+            store: None,
+            draining: AtomicBool::new(false),
         }
     }
 
+    /// Record connection events to `audit`
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Count rejected connections in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Accept CONNECT messages addressed to `destination` too, that of an
+    /// interface with its own
+    pub fn with_destination(mut self, destination: [u8; 32]) -> Self {
+        self.signatures.add_destination(destination);
+        self
+    }
+
+    /// Hand out resume tokens and honour them through `store`
+    pub fn with_store(mut self, store: Arc<SessionStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Get the ban list (shared with the admin socket)
+    pub fn bans(&self) -> Arc<BanList> {
+        Arc::clone(&self.bans)
+    }
+
+    /// Reject all further connection attempts
+    pub fn stop_accepting(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
     /// Handle incoming connection
     pub async fn handle_connection(&self, message: Message) -> Result<Message> {
+        self.handle_connection_from(message, None).await
+    }
+
+    /// Handle incoming connection from the `source` destination (counted
+    /// towards its ban)
+    pub async fn handle_connection_from(
+        &self,
+        message: Message,
+        source: Option<&[u8]>,
+    ) -> Result<Message> {
         match message {
             Message::Connect(connect_msg) => {
-                self.handle_connect(connect_msg).await
+                self.handle_connect(connect_msg, source).await
             }
             _ => {
                 warn!("Unexpected message type during connection");
@@ -50,7 +166,11 @@ impl Listener {
     }
 
     /// Handle CONNECT message
-    async fn handle_connect(&self, connect: ConnectMessage) -> Result<Message> {
+    async fn handle_connect(
+        &self,
+        connect: ConnectMessage,
+        source: Option<&[u8]>,
+    ) -> Result<Message> {
         debug!(
             client = %hex::encode(&connect.client_identity),
             protocol_version = connect.protocol_version,
@@ -64,14 +184,52 @@ impl Listener {
                 actual = connect.protocol_version,
                 "Protocol version mismatch"
             );
-            return Ok(Message::Reject(RejectMessage {
-                reason: format!(
+            return Ok(self.reject(
+                &connect,
+                format!(
                     "Protocol version mismatch: expected {}, got {}",
                     CURRENT_PROTOCOL_VERSION, connect.protocol_version
                 ),
-                error_code: 2,
-            }));
+                2,
+            ));
+        }
+
+        if self.draining.load(Ordering::SeqCst) {
+            return Ok(self.reject(&connect, "Server shutting down".to_string(), 5));
+        }
+
+        // Refuse banned sources and identities before doing any work
+        let source_key = source.map(BanList::source_key);
+        let identity_key = BanList::identity_key(&connect.client_identity);
+        let banned = source_key
+            .iter()
+            .chain([&identity_key])
+            .find_map(|key| self.bans.banned(key));
+        if let Some(remaining) = banned {
+            warn!(
+                client = %hex::encode(&connect.client_identity),
+                remaining_secs = remaining.as_secs(),
+                "Banned client tried to connect"
+            );
+            return Ok(self.reject(
+                &connect,
+                format!("Banned for another {}s", remaining.as_secs()),
+                6,
+            ));
+        }
+
+        // Check that the client holds the identity it claims
+        if let Err(reason) = self.signatures.verify(&connect) {
+            warn!(
+                client = %hex::encode(&connect.client_identity),
+                reason = %reason,
+                "CONNECT signature rejected"
+            );
+            // The identity is unproven, only the source is charged
+            self.charge(&connect, source_key.as_slice());
+            return Ok(self.reject(&connect, reason, 3));
         }
+        let keys: Vec<String> = source_key.into_iter().chain([identity_key]).collect();
 
         // Check if client is allowed
         if !self.config.is_client_allowed(&connect.client_identity) {
@@ -79,10 +237,38 @@ impl Listener {
                 client = %hex::encode(&connect.client_identity),
                 "Client not in allowed list"
             );
-            return Ok(Message::Reject(RejectMessage {
-                reason: "Client not authorized".to_string(),
-                error_code: 3,
-            }));
+            self.charge(&connect, &keys);
+            return Ok(self.reject(&connect, "Client not authorized".to_string(), 3));
+        }
+
+        // Check auth token
+        if !self
+            .tokens
+            .verify(&connect.client_identity, connect.auth_token.as_deref())
+        {
+            warn!(
+                client = %hex::encode(&connect.client_identity),
+                token_present = connect.auth_token.is_some(),
+                "Invalid auth token"
+            );
+            self.charge(&connect, &keys);
+            return Ok(self.reject(&connect, "Invalid auth token".to_string(), 3));
+        }
+        for key in &keys {
+            self.bans.clear_failures(key);
+        }
+
+        // Check that the client encrypts the session, if required
+        if self.config.require_noise && connect.noise.is_none() {
+            warn!(
+                client = %hex::encode(&connect.client_identity),
+                "Client did not offer session encryption"
+            );
+            return Ok(self.reject(
+                &connect,
+                "Session encryption (Noise) is required".to_string(),
+                7,
+            ));
         }
 
         // Check session limit
@@ -90,18 +276,28 @@ impl Listener {
             let sessions = self.sessions.read().await;
             if sessions.len() >= self.config.max_sessions {
                 warn!("Maximum session limit reached");
-                return Ok(Message::Reject(RejectMessage {
-                    reason: "Maximum sessions reached".to_string(),
-                    error_code: 4,
-                }));
+                return Ok(self.reject(&connect, "Maximum sessions reached".to_string(), 4));
             }
         }
 
+        // Reattach to a persisted session if the client holds its token
+        let resumed = match (&self.store, &connect.resume_token) {
+            (Some(store), Some(token)) => store.resume(&connect.client_identity, token),
+            _ => None,
+        };
+        if connect.resume_token.is_some() && resumed.is_none() {
+            debug!(
+                client = %hex::encode(&connect.client_identity),
+                "Unknown or expired resume token, starting a new session"
+            );
+        }
+
         // Create new session
-        let session = Arc::new(Session::new(
-            connect.client_identity.clone(),
-            self.executor.clone(),
-        ));
+        let mut session = Session::new(connect.client_identity.clone(), self.executor.clone());
+        if let Some(record) = &resumed {
+            session = session.with_id(*record.session_id.as_bytes());
+        }
+        let session = Arc::new(session);
 
         // Add to active sessions
         {
@@ -112,18 +308,74 @@ impl Listener {
         info!(
             session_id = %session.id_string(),
             client = %hex::encode(&connect.client_identity),
+            resumed = resumed.is_some(),
             "Connection accepted"
         );
 
+        let event = match resumed {
+            Some(_) => AuditEvent::Resume,
+            None => AuditEvent::Connect,
+        };
+        self.audit
+            .record(&connect.client_identity, Some(&session.id), event);
+
+        // A fresh token each time, carrying over the resumed state
+        let resume_token = self.store.as_ref().and_then(|store| {
+            let (cwd, env) = resumed
+                .map(|record| (record.cwd, record.env))
+                .unwrap_or_default();
+            store
+                .issue(session.id, &connect.client_identity, cwd, env)
+                .map_err(|e| warn!(error = %e, "Failed to persist session"))
+                .ok()
+        });
+
         // Send ACCEPT message
         Ok(Message::Accept(AcceptMessage {
             protocol_version: CURRENT_PROTOCOL_VERSION,
             server_identity: self.config.identity.public_key(),
             session_id: session.id,
-            capabilities: vec!["command-exec".to_string()],
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            resume_token,
+            banner: self.config.banner.clone(),
+            banner_ack_required: self.config.banner.is_some() && self.config.banner_ack_required,
+            keepalive_interval: Some(self.config.keepalive_interval)
+                .filter(|&interval| interval > 0),
+            // Filled in by the server loop, which runs the handshake
+            noise: None,
         }))
     }
 
+    /// Count a failed connect against `keys`, auditing resulting bans
+    fn charge(&self, connect: &ConnectMessage, keys: &[String]) {
+        for key in keys {
+            if self.bans.record_failure(key) {
+                self.audit.record(
+                    &connect.client_identity,
+                    None,
+                    AuditEvent::Ban {
+                        key: key.clone(),
+                        duration_secs: self.config.ban_duration,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Build a REJECT reply and audit it
+    fn reject(&self, connect: &ConnectMessage, reason: String, error_code: u32) -> Message {
+        self.metrics.connect_rejected();
+        self.audit.record(
+            &connect.client_identity,
+            None,
+            AuditEvent::Reject {
+                reason: reason.clone(),
+            },
+        );
+
+        Message::Reject(RejectMessage { reason, error_code })
+    }
+
     /// Get number of active sessions
     pub async fn session_count(&self) -> usize {
         let sessions = self.sessions.read().await;
@@ -150,6 +402,29 @@ impl Listener {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::unix_time;
+    use reticulum_core::Identity;
+
+    fn signed_connect(
+        listener: &Listener,
+        identity: &Identity,
+        auth_token: Option<&str>,
+    ) -> Message {
+        let mut connect = ConnectMessage {
+            protocol_version: CURRENT_PROTOCOL_VERSION,
+            client_identity: identity.public_key(),
+            capabilities: vec![],
+            auth_token: auth_token.map(str::to_string),
+            timestamp: unix_time(),
+            nonce: *uuid::Uuid::new_v4().as_bytes(),
+            signature: vec![],
+            resume_token: None,
+            noise: None,
+            server_destination: listener.config.identity.destination_hash(),
+        };
+        connect.signature = identity.sign(&connect.signing_payload());
+        Message::Connect(connect)
+    }
 
     #[tokio::test]
     async fn test_listener_creation() {
@@ -164,17 +439,213 @@ mod tests {
         let config = ServerConfig::default();
         let listener = Listener::new(config);
 
-        let connect = ConnectMessage {
-            protocol_version: CURRENT_PROTOCOL_VERSION,
-            client_identity: vec![1, 2, 3, 4],
-            capabilities: vec![],
-            auth_token: None,
+        let response = listener
+            .handle_connection(signed_connect(&listener, &Identity::generate(), None))
+            .await
+            .unwrap();
+
+        assert!(matches!(response, Message::Accept(_)));
+        assert_eq!(listener.session_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handle_connect_while_draining() {
+        let listener = Listener::new(ServerConfig::default());
+        listener.stop_accepting();
+
+        match listener
+            .handle_connection(signed_connect(&listener, &Identity::generate(), None))
+            .await
+            .unwrap()
+        {
+            Message::Reject(reject) => assert_eq!(reject.error_code, 5),
+            other => panic!("Expected Reject message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_connect_without_noise() {
+        let mut config = ServerConfig::default();
+        config.require_noise = true;
+        let listener = Listener::new(config);
+
+        match listener
+            .handle_connection(signed_connect(&listener, &Identity::generate(), None))
+            .await
+            .unwrap()
+        {
+            Message::Reject(reject) => assert_eq!(reject.error_code, 7),
+            other => panic!("Expected Reject message, got {:?}", other),
+        }
+        assert_eq!(listener.session_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_handle_connect_forged_identity() {
+        let config = ServerConfig::default();
+        let listener = Listener::new(config);
+
+        // Signed by one identity, claiming another
+        let Message::Connect(mut connect) = signed_connect(&listener, &Identity::generate(), None)
+        else {
+            unreachable!()
         };
+        connect.client_identity = Identity::generate().public_key();
 
-        let response = listener.handle_connection(Message::Connect(connect)).await.unwrap();
+        match listener.handle_connection(Message::Connect(connect)).await.unwrap() {
+            Message::Reject(reject) => assert_eq!(reject.error_code, 3),
+            other => panic!("Expected Reject message, got {:?}", other),
+        }
+        assert_eq!(listener.session_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_ban_after_failed_connects() {
+        let identity = Identity::generate();
+        let mut config = ServerConfig::default();
+        config.ban_threshold = 2;
+        config
+            .auth_tokens
+            .insert(hex::encode(identity.public_key()), "s3cret".to_string());
+        let listener = Listener::new(config);
+        let source = [9u8; 32];
+
+        let code = |response: Message| match response {
+            Message::Reject(reject) => reject.error_code,
+            Message::Accept(_) => 0,
+            other => panic!("Unexpected response {:?}", other),
+        };
+
+        // Forged CONNECTs only count against the source
+        let Message::Connect(mut forged) = signed_connect(&listener, &Identity::generate(), None)
+        else {
+            unreachable!()
+        };
+        forged.client_identity = identity.public_key();
+        for _ in 0..2 {
+            let response = listener
+                .handle_connection_from(Message::Connect(forged.clone()), Some(&source))
+                .await
+                .unwrap();
+            assert_eq!(code(response), 3);
+        }
+        let response = listener
+            .handle_connection_from(
+                signed_connect(&listener, &identity, Some("s3cret")),
+                Some(&source),
+            )
+            .await
+            .unwrap();
+        assert_eq!(code(response), 6);
+        let response = listener
+            .handle_connection_from(
+                signed_connect(&listener, &identity, Some("s3cret")),
+                Some(&[8; 32]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(code(response), 0);
+
+        // Wrong tokens count against the identity wherever it comes from
+        for source in [[1u8; 32], [2; 32]] {
+            let response = listener
+                .handle_connection_from(
+                    signed_connect(&listener, &identity, Some("wrong")),
+                    Some(&source),
+                )
+                .await
+                .unwrap();
+            assert_eq!(code(response), 3);
+        }
+        let response = listener
+            .handle_connection_from(
+                signed_connect(&listener, &identity, Some("s3cret")),
+                Some(&[3; 32]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(code(response), 6);
+
+        let key = BanList::identity_key(&identity.public_key());
+        assert!(listener.bans().unban(&key));
+        let response = listener
+            .handle_connection(signed_connect(&listener, &identity, Some("s3cret")))
+            .await
+            .unwrap();
+        assert_eq!(code(response), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handle_connect_auth_token() {
+        let identity = Identity::generate();
+        let mut config = ServerConfig::default();
+        config
+            .auth_tokens
+            .insert(hex::encode(identity.public_key()), "s3cret".to_string());
+        let listener = Listener::new(config);
 
+        for token in [None, Some("wrong")] {
+            match listener
+                .handle_connection(signed_connect(&listener, &identity, token))
+                .await
+                .unwrap()
+            {
+                Message::Reject(reject) => assert_eq!(reject.error_code, 3),
+                other => panic!("Expected Reject message, got {:?}", other),
+            }
+        }
+
+        let response = listener
+            .handle_connection(signed_connect(&listener, &identity, Some("s3cret")))
+            .await
+            .unwrap();
         assert!(matches!(response, Message::Accept(_)));
-        assert_eq!(listener.session_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = || Arc::new(SessionStore::open(dir.path(), 3600).unwrap());
+        let identity = Identity::generate();
+
+        let config = ServerConfig::default();
+        let listener = Listener::new(config.clone()).with_store(store());
+        let Message::Accept(first) = listener
+            .handle_connection(signed_connect(&listener, &identity, None))
+            .await
+            .unwrap()
+        else {
+            panic!("Expected Accept message");
+        };
+        let token = first.resume_token.unwrap();
+
+        // A restarted server honours the token once and rotates it
+        let listener = Listener::new(config).with_store(store());
+        let resume = |token: Vec<u8>| {
+            let Message::Connect(mut connect) = signed_connect(&listener, &identity, None) else {
+                unreachable!()
+            };
+            connect.resume_token = Some(token);
+            connect.signature = identity.sign(&connect.signing_payload());
+            Message::Connect(connect)
+        };
+
+        let Message::Accept(resumed) = listener
+            .handle_connection(resume(token.clone()))
+            .await
+            .unwrap()
+        else {
+            panic!("Expected Accept message");
+        };
+        assert_eq!(resumed.session_id, first.session_id);
+        assert_ne!(resumed.resume_token.as_ref(), Some(&token));
+
+        // A stale token just gets a new session
+        let Message::Accept(fresh) = listener.handle_connection(resume(token)).await.unwrap()
+        else {
+            panic!("Expected Accept message");
+        };
+        assert_ne!(fresh.session_id, first.session_id);
     }
 
     #[tokio::test]
@@ -187,6 +658,12 @@ mod tests {
             client_identity: vec![1, 2, 3, 4],
             capabilities: vec![],
             auth_token: None,
+            timestamp: 0,
+            nonce: [0; 16],
+            signature: vec![],
+            resume_token: None,
+            noise: None,
+            server_destination: [0; 32],
         };
 
         let response = listener.handle_connection(Message::Connect(connect)).await.unwrap();
@@ -3,13 +3,22 @@
 //! This server listens for incoming connections over the Reticulum network
 //! and executes commands from authenticated clients.
 
-use clap::Parser;
-use reticulum_core::{I2pInterface, NetworkInterface};
-use shell_server::{config::ServerConfig, server::Server, Result};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use reticulum_core::{
+    I2pInterface, InterfaceManager, NetworkInterface, RnsdInterface, ShareString, TcpInterface,
+};
+use shell_server::{
+    audit,
+    check::{self, Severity},
+    config::{ListenerConfig, LogFormat, ServerConfig},
+    daemon,
+    server::Server,
+    Result, ServerError,
+};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::{error, info, warn};
-use tracing_subscriber;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -26,6 +35,16 @@ struct Args {
     #[arg(long)]
     generate_identity: Option<PathBuf>,
 
+    /// Protect the generated identity with a passphrase (typed twice, or
+    /// taken from RSH_IDENTITY_PASSPHRASE)
+    #[arg(long, requires = "generate_identity")]
+    encrypt: bool,
+
+    /// Validate the configuration and the files it refers to, then exit
+    /// (status 1 if there are errors)
+    #[arg(long)]
+    check_config: bool,
+
     /// Enable I2P transport
     #[arg(long)]
     enable_i2p: bool,
@@ -38,12 +57,84 @@ struct Args {
     /// SAM bridge address for external router (default: 127.0.0.1:7656)
     #[arg(long)]
     sam_address: Option<String>,
+
+    /// Also listen on plain TCP at this address (host:port), next to I2P
+    /// if enabled
+    #[arg(long)]
+    tcp_bind: Option<SocketAddr>,
+
+    /// Run in the background (Unix); stop with SIGTERM
+    #[arg(long)]
+    daemon: bool,
+
+    /// Pidfile written in daemon mode
+    #[arg(long, default_value = "shell-server.pid")]
+    pid_file: PathBuf,
+
+    /// Write logs to this file instead of stderr (default in daemon mode:
+    /// shell-server.log)
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Once listening, print a share string with the destination, public
+    /// key and addresses for clients to add with --add-server, as text or
+    /// also as a QR code
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "text"
+    )]
+    show_destination: Option<ShareFormat>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// How `--show-destination` prints the share string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ShareFormat {
+    /// The string alone
+    Text,
+
+    /// A QR code drawn in the terminal, then the string
+    Qr,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Verify the hash chain and checkpoint signatures of an audit log
+    VerifyAudit {
+        /// Audit log (rotated files next to it are checked too)
+        path: PathBuf,
+
+        /// Expected signing key (hex); defaults to the configured identity
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+
+    /// Print the auth token for a client derived from auth_hmac_secret
+    AuthToken {
+        /// Client public key (hex)
+        client: String,
+    },
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
+    // Needs neither logging nor a runtime, and must not start anything
+    if args.check_config {
+        check_config(&args.config);
+    }
+
+    // Forking has to happen before the runtime starts its threads
+    if args.daemon {
+        daemon::PidFile::check(&args.pid_file)?;
+        daemon::daemonize()?;
+    }
+
     // Initialize logging
     let log_level = if args.verbose {
         tracing::Level::DEBUG
@@ -51,22 +142,86 @@ async fn main() -> Result<()> {
         tracing::Level::INFO
     };
 
-    tracing_subscriber::fmt()
-        .with_max_level(log_level)
-        .with_target(false)
-        .init();
+    let log_file = args
+        .log_file
+        .clone()
+        .or_else(|| args.daemon.then(|| PathBuf::from("shell-server.log")));
+    let file = match log_file {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?,
+        ),
+        None => None,
+    };
+    init_logging(log_level, ServerConfig::log_format_from_file(&args.config), file);
 
+    // Removed again when the server exits
+    let _pid_file = if args.daemon {
+        let pid_file = daemon::PidFile::create(&args.pid_file)?;
+        info!("Running as daemon, pid {}", std::process::id());
+        Some(pid_file)
+    } else {
+        None
+    };
+
+    tokio::runtime::Runtime::new()?.block_on(run(args))
+}
+
+/// Install the global subscriber, writing to `file` or stderr
+fn init_logging(level: tracing::Level, format: LogFormat, file: Option<std::fs::File>) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false);
+
+    // Fields of every event (session_id, client, request_id, command, ...)
+    // become top-level keys next to timestamp, level and message
+    match (format, file) {
+        (LogFormat::Text, Some(file)) => builder
+            .with_ansi(false)
+            .with_writer(std::sync::Mutex::new(file))
+            .init(),
+        (LogFormat::Text, None) => builder.init(),
+        (LogFormat::Json, Some(file)) => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_writer(std::sync::Mutex::new(file))
+            .init(),
+        (LogFormat::Json, None) => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .init(),
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
     // Handle identity generation
     if let Some(identity_path) = args.generate_identity {
         info!("Generating new identity at {:?}", identity_path);
         let identity = reticulum_core::Identity::generate();
-        identity.save_to_file(&identity_path)?;
+        if args.encrypt {
+            let passphrase = rsh_agent::passphrase::choose("New passphrase: ")?;
+            identity.save_encrypted(&identity_path, &passphrase)?;
+        } else {
+            identity.save_to_file(&identity_path)?;
+        }
         info!("Identity saved: {}", identity.destination_hex());
         return Ok(());
     }
 
+    match args.command {
+        Some(Command::VerifyAudit { path, public_key }) => {
+            return verify_audit(&path, public_key, &args.config);
+        }
+        Some(Command::AuthToken { client }) => return print_auth_token(&client, &args.config),
+        None => {}
+    }
+
     // Load or create configuration
-    let config = if args.config.exists() {
+    let mut config = if args.config.exists() {
         info!("Loading configuration from {:?}", args.config);
         ServerConfig::load_from_file(&args.config)?
     } else {
@@ -78,11 +233,13 @@ async fn main() -> Result<()> {
         // Generate identity if it doesn't exist
         if !config.identity_path.exists() {
             info!("Generating new server identity at {:?}", config.identity_path);
-            config.identity.save_to_file(&config.identity_path)?;
-            info!("Server identity saved: {}", config.identity.destination_hex());
+            let identity = reticulum_core::Identity::generate();
+            identity.save_to_file(&config.identity_path)?;
+            info!("Server identity saved: {}", identity.destination_hex());
+            config.identity = Arc::new(identity);
         } else {
             // Load existing identity
-            config.identity = reticulum_core::Identity::load_from_file(&config.identity_path)?;
+            config.identity = config.load_identity()?;
         }
 
         // Save config for future use
@@ -95,93 +252,87 @@ async fn main() -> Result<()> {
     info!("Server destination: {}", config.identity.destination_hex());
 
     // Override config with CLI args if provided
-    let enable_i2p = args.enable_i2p || config.enable_i2p;
-    let sam_address = args.sam_address.unwrap_or(config.sam_address.clone());
+    if args.enable_i2p {
+        config.enable_i2p = true;
+    }
+    if let Some(sam_address) = args.sam_address {
+        config.sam_address = sam_address;
+    }
+    if args.tcp_bind.is_some() {
+        config.tcp_bind = args.tcp_bind;
+    }
+    #[cfg(feature = "embedded-router")]
+    if args.use_embedded_router {
+        config.router_mode = reticulum_core::RouterMode::Embedded;
+    }
 
+    let listeners = config.effective_listeners();
+    if listeners.is_empty() {
+        warn!("No listeners configured - server will run without network interface");
+        info!("To enable I2P: use --enable-i2p flag or set enable_i2p=true in config");
+        info!("To enable TCP: use --tcp-bind host:port or set tcp_bind in config");
+    }
+
+    // Open every interface; each gets its own message loop
+    let mut interfaces = InterfaceManager::new();
     #[cfg(feature = "embedded-router")]
-    let use_embedded = args.use_embedded_router
-        || matches!(config.router_mode, reticulum_core::RouterMode::Embedded);
-
-    // Create server with optional I2P interface
-    let server = if enable_i2p {
-        #[cfg(feature = "embedded-router")]
-        if use_embedded {
-            info!("Starting embedded I2P router...");
-
-            let router = reticulum_core::EmbeddedRouter::new(config.embedded_router.clone())
-                .await
-                .map_err(|e| {
-                    error!("Failed to start embedded router: {}", e);
-                    e
-                })?;
-
-            info!("Embedded router started successfully");
-
-            // Wait for router to be ready
-            router.wait_ready().await?;
-
-            info!("Connecting to embedded router via SAM...");
-            match I2pInterface::new_embedded(&router).await {
-                Ok(i2p_interface) => {
-                    info!("I2P interface created successfully");
-                    info!("I2P destination: {}", i2p_interface.local_destination());
-                    info!("I2P destination hash: {}", hex::encode(i2p_interface.local_destination_hash()));
-
-                    let interface: Arc<dyn NetworkInterface> = Arc::new(i2p_interface);
-                    Server::with_interface(config, interface).await?
-                }
-                Err(e) => {
-                    error!("Failed to create I2P interface: {}", e);
-                    return Err(e.into());
+    let mut embedded_router = None;
+    let mut share = ShareString::new(&config.identity);
+    share.name = config.announce_name.clone();
+
+    for listener in listeners {
+        let interface: Arc<dyn NetworkInterface> = match listener {
+            ListenerConfig::I2p { sam_address } => {
+                let interface = open_i2p(&sam_address).await?;
+                share.i2p_destination = Some(interface.local_destination().to_string());
+                Arc::new(interface)
+            }
+            #[cfg(feature = "embedded-router")]
+            ListenerConfig::Embedded => {
+                if embedded_router.is_some() {
+                    return Err(ServerError::Config(
+                        "Only one embedded router listener is supported".to_string(),
+                    ));
                 }
+                let (router, interface) = open_embedded_i2p(&config).await?;
+                share.i2p_destination = Some(interface.local_destination().to_string());
+                embedded_router = Some(router);
+                Arc::new(interface)
             }
-        } else {
-            info!("Connecting to external I2P router via SAM bridge at {}", sam_address);
-
-            match I2pInterface::new(&sam_address).await {
-                Ok(i2p_interface) => {
-                    info!("I2P interface created successfully");
-                    info!("I2P destination: {}", i2p_interface.local_destination());
-                    info!("I2P destination hash: {}", hex::encode(i2p_interface.local_destination_hash()));
-
-                    let interface: Arc<dyn NetworkInterface> = Arc::new(i2p_interface);
-                    Server::with_interface(config, interface).await?
+            ListenerConfig::Tcp { bind } => match TcpInterface::bind(bind).await {
+                Ok(interface) => {
+                    // A wildcard address says nothing about where to connect
+                    if !bind.ip().is_unspecified() {
+                        share.tcp_address = Some(bind);
+                    }
+                    Arc::new(interface)
                 }
                 Err(e) => {
-                    error!("Failed to create I2P interface: {}", e);
-                    error!("Make sure I2P router is running with SAM bridge enabled on {}", sam_address);
+                    error!("Failed to listen on TCP {}: {}", bind, e);
                     return Err(e.into());
                 }
-            }
-        }
-
-        #[cfg(not(feature = "embedded-router"))]
-        {
-            info!("Connecting to external I2P router via SAM bridge at {}", sam_address);
-
-            match I2pInterface::new(&sam_address).await {
-                Ok(i2p_interface) => {
-                    info!("I2P interface created successfully");
-                    info!("I2P destination: {}", i2p_interface.local_destination());
-                    info!("I2P destination hash: {}", hex::encode(i2p_interface.local_destination_hash()));
-
-                    let interface: Arc<dyn NetworkInterface> = Arc::new(i2p_interface);
-                    Server::with_interface(config, interface).await?
-                }
+            },
+            ListenerConfig::Rnsd { address } => match RnsdInterface::connect(address).await {
+                Ok(interface) => Arc::new(interface),
                 Err(e) => {
-                    error!("Failed to create I2P interface: {}", e);
-                    error!("Make sure I2P router is running with SAM bridge enabled on {}", sam_address);
+                    error!("Failed to connect to rnsd at {}: {}", address, e);
                     return Err(e.into());
                 }
-            }
-        }
-    } else {
-        warn!("I2P transport not enabled - server will run without network interface");
-        info!("To enable I2P: use --enable-i2p flag or set enable_i2p=true in config");
-        Server::new(config).await?
-    };
+            },
+        };
+        interfaces.add(interface);
+    }
+
+    let server = Server::with_interfaces(config, interfaces).await?;
+    #[cfg(feature = "embedded-router")]
+    if let Some(router) = embedded_router {
+        server.metrics().set_router(Arc::new(router));
+    }
 
     info!("Listening on Reticulum network...");
+    if let Some(format) = args.show_destination {
+        show_destination(&share, format)?;
+    }
 
     // Run server
     if let Err(e) = server.run().await {
@@ -191,3 +342,154 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Connect to an external I2P router
+async fn open_i2p(sam_address: &str) -> Result<I2pInterface> {
+    info!("Connecting to external I2P router via SAM bridge at {}", sam_address);
+
+    match I2pInterface::new(sam_address).await {
+        Ok(i2p_interface) => {
+            log_i2p_interface(&i2p_interface);
+            Ok(i2p_interface)
+        }
+        Err(e) => {
+            error!("Failed to create I2P interface: {}", e);
+            error!("Make sure I2P router is running with SAM bridge enabled on {}", sam_address);
+            Err(e.into())
+        }
+    }
+}
+
+/// Start the embedded I2P router and connect to it
+#[cfg(feature = "embedded-router")]
+async fn open_embedded_i2p(
+    config: &ServerConfig,
+) -> Result<(reticulum_core::EmbeddedRouter, I2pInterface)> {
+    info!("Starting embedded I2P router...");
+
+    let router = reticulum_core::EmbeddedRouter::new(config.embedded_router.clone())
+        .await
+        .map_err(|e| {
+            error!("Failed to start embedded router: {}", e);
+            e
+        })?;
+
+    info!("Embedded router started successfully");
+
+    // Wait for router to be ready
+    router.wait_ready().await?;
+
+    info!("Connecting to embedded router via SAM...");
+    match I2pInterface::new_embedded(&router).await {
+        Ok(i2p_interface) => {
+            log_i2p_interface(&i2p_interface);
+            Ok((router, i2p_interface))
+        }
+        Err(e) => {
+            error!("Failed to create I2P interface: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+fn log_i2p_interface(i2p_interface: &I2pInterface) {
+    info!("I2P interface created successfully");
+    info!("I2P destination: {}", i2p_interface.local_destination());
+    info!("I2P destination hash: {}", hex::encode(i2p_interface.local_destination_hash()));
+}
+
+/// Print the share string, under its QR code for `ShareFormat::Qr`
+fn show_destination(share: &ShareString, format: ShareFormat) -> Result<()> {
+    if share.i2p_destination.is_none() && share.tcp_address.is_none() {
+        warn!("No I2P destination or TCP address to share; clients will need one");
+    }
+    let text = share.encode();
+    if format == ShareFormat::Qr {
+        use qrcode::render::unicode::Dense1x2;
+
+        let code = qrcode::QrCode::new(text.as_bytes())
+            .map_err(|e| ServerError::Config(format!("Cannot draw a QR code: {}", e)))?;
+        // Dark modules as blank cells, which most terminals show dark
+        let image = code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        println!("{}", image);
+    }
+    println!("{}", text);
+    Ok(())
+}
+
+/// Print every problem in the configuration and exit
+fn check_config(path: &Path) -> ! {
+    let problems = check::check_file(path);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    let warnings = problems.len() - errors;
+    if errors > 0 {
+        eprintln!("{}: {} error(s), {} warning(s)", path.display(), errors, warnings);
+        std::process::exit(1);
+    }
+    eprintln!("{}: OK ({} warning(s))", path.display(), warnings);
+    std::process::exit(0);
+}
+
+fn print_auth_token(client: &str, config_path: &Path) -> Result<()> {
+    let config = ServerConfig::load_from_file(config_path)?;
+    let secret = config
+        .auth_hmac_secret
+        .ok_or_else(|| ServerError::Config("auth_hmac_secret is not set".to_string()))?;
+    let client = hex::decode(client)
+        .map_err(|e| ServerError::Config(format!("Invalid client key: {}", e)))?;
+
+    println!("{}", shell_server::auth::hmac_token(&secret, &client));
+    Ok(())
+}
+
+fn verify_audit(path: &Path, public_key: Option<String>, config_path: &Path) -> Result<()> {
+    let public_key = match public_key {
+        Some(key) => Some(
+            hex::decode(key).map_err(|e| ServerError::Config(format!("Invalid public key: {}", e)))?,
+        ),
+        None if config_path.exists() => {
+            Some(ServerConfig::load_from_file(config_path)?.identity.public_key())
+        }
+        None => {
+            warn!("No public key or configuration given; trusting the key in the first checkpoint");
+            None
+        }
+    };
+
+    match audit::verify(path, public_key.as_deref()) {
+        Ok(report) => {
+            info!(
+                "Audit log OK: {} records, {} checkpoints (last at seq {}), {} unsigned",
+                report.records,
+                report.checkpoints,
+                report
+                    .last_checkpoint
+                    .map(|seq| seq.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                report.unsigned
+            );
+            if !report.complete {
+                warn!("Log does not start at the beginning of the chain (older files rotated away)");
+            }
+            if report.unchained > 0 {
+                warn!("{} records predate chaining and were not verified", report.unchained);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Prometheus metrics
+//!
+//! Counters are plain atomics updated by the server, listener and sessions.
+//! When `metrics_bind` is set, [`serve`] exposes them in the Prometheus text
+//! format at `GET /metrics` on a minimal HTTP listener meant for localhost.
+
+use crate::Result;
+use shell_proto::CommandStatus;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+/// Upper bounds of the command latency buckets (seconds)
+const LATENCY_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// Largest request we read before answering
+const MAX_REQUEST: usize = 8192;
+
+/// Command outcomes, in label order
+const STATUSES: &[&str] = &["success", "error", "timeout", "killed", "oom_killed", "denied"];
+
+/// Server metrics
+pub struct Metrics {
+    sessions_active: AtomicI64,
+    connects_accepted: AtomicU64,
+    connects_rejected: AtomicU64,
+
+    /// Per entry of [`STATUSES`]
+    commands: [AtomicU64; 6],
+
+    /// Per entry of [`LATENCY_BUCKETS`], plus +Inf (not cumulative)
+    latency_buckets: [AtomicU64; 13],
+    latency_sum_us: AtomicU64,
+
+    packets_received: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+
+    /// Embedded I2P router, if running
+    #[cfg(feature = "embedded-router")]
+    router: std::sync::OnceLock<Arc<reticulum_core::EmbeddedRouter>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// Create a metrics registry with everything at zero
+    pub fn new() -> Self {
+        Self {
+            sessions_active: AtomicI64::new(0),
+            connects_accepted: AtomicU64::new(0),
+            connects_rejected: AtomicU64::new(0),
+            commands: Default::default(),
+            latency_buckets: Default::default(),
+            latency_sum_us: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            #[cfg(feature = "embedded-router")]
+            router: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// A session was opened
+    pub fn session_opened(&self) {
+        self.connects_accepted.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A session was closed
+    pub fn session_closed(&self) {
+        self.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A connection attempt was rejected
+    pub fn connect_rejected(&self) {
+        self.connects_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A command finished (None = refused or failed to start)
+    pub fn command_finished(&self, status: Option<CommandStatus>, duration: Duration) {
+        let index = match status {
+            Some(CommandStatus::Success) => 0,
+            Some(CommandStatus::Error) => 1,
+            Some(CommandStatus::Timeout) => 2,
+            Some(CommandStatus::Killed) => 3,
+            Some(CommandStatus::OomKilled) => 4,
+            None => 5,
+        };
+        self.commands[index].fetch_add(1, Ordering::Relaxed);
+
+        // Refused commands never ran; keep them out of the latency figures
+        if status.is_some() {
+            let seconds = duration.as_secs_f64();
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|&bound| seconds <= bound)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            self.latency_sum_us
+                .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// A packet arrived on the network interface
+    pub fn packet_received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A packet was sent on the network interface
+    pub fn packet_sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Report statistics of the embedded I2P router (keeps it alive)
+    #[cfg(feature = "embedded-router")]
+    pub fn set_router(&self, router: Arc<reticulum_core::EmbeddedRouter>) {
+        let _ = self.router.set(router);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        gauge(
+            &mut out,
+            "reticulum_shell_sessions_active",
+            "Open client sessions",
+            self.sessions_active.load(Ordering::Relaxed),
+        );
+
+        header(&mut out, "reticulum_shell_connects_total", "Connection attempts", "counter");
+        let _ = writeln!(
+            out,
+            "reticulum_shell_connects_total{{result=\"accepted\"}} {}",
+            load(&self.connects_accepted)
+        );
+        let _ = writeln!(
+            out,
+            "reticulum_shell_connects_total{{result=\"rejected\"}} {}",
+            load(&self.connects_rejected)
+        );
+
+        header(&mut out, "reticulum_shell_commands_total", "Commands by outcome", "counter");
+        for (status, count) in STATUSES.iter().zip(&self.commands) {
+            let _ = writeln!(
+                out,
+                "reticulum_shell_commands_total{{status=\"{}\"}} {}",
+                status,
+                load(count)
+            );
+        }
+
+        let failures: u64 = self.commands[1..].iter().map(load).sum();
+        header(
+            &mut out,
+            "reticulum_shell_command_failures_total",
+            "Commands that did not succeed",
+            "counter",
+        );
+        let _ = writeln!(out, "reticulum_shell_command_failures_total {}", failures);
+
+        header(
+            &mut out,
+            "reticulum_shell_command_duration_seconds",
+            "Command execution time",
+            "histogram",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += load(count);
+            let _ = writeln!(
+                out,
+                "reticulum_shell_command_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        cumulative += load(&self.latency_buckets[LATENCY_BUCKETS.len()]);
+        let _ = writeln!(
+            out,
+            "reticulum_shell_command_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            cumulative
+        );
+        let _ = writeln!(
+            out,
+            "reticulum_shell_command_duration_seconds_sum {}",
+            load(&self.latency_sum_us) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "reticulum_shell_command_duration_seconds_count {}",
+            cumulative
+        );
+
+        counter(
+            &mut out,
+            "reticulum_shell_packets_received_total",
+            "Packets received on the network interface",
+            load(&self.packets_received),
+        );
+        counter(
+            &mut out,
+            "reticulum_shell_packets_sent_total",
+            "Packets sent on the network interface",
+            load(&self.packets_sent),
+        );
+        counter(
+            &mut out,
+            "reticulum_shell_bytes_received_total",
+            "Payload bytes received on the network interface",
+            load(&self.bytes_received),
+        );
+        counter(
+            &mut out,
+            "reticulum_shell_bytes_sent_total",
+            "Payload bytes sent on the network interface",
+            load(&self.bytes_sent),
+        );
+
+        #[cfg(feature = "embedded-router")]
+        if let Some(router) = self.router.get() {
+            let stats = router.stats();
+            gauge(
+                &mut out,
+                "reticulum_shell_router_tunnels_active",
+                "Embedded router active tunnels",
+                stats.tunnels_active,
+            );
+            gauge(
+                &mut out,
+                "reticulum_shell_router_peers_known",
+                "Embedded router known peers",
+                stats.peers_known,
+            );
+            counter(
+                &mut out,
+                "reticulum_shell_router_bandwidth_in_bytes",
+                "Embedded router inbound bytes",
+                stats.bandwidth_in,
+            );
+            counter(
+                &mut out,
+                "reticulum_shell_router_bandwidth_out_bytes",
+                "Embedded router outbound bytes",
+                stats.bandwidth_out,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Bind `addr` and serve `GET /metrics` in the background
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(address = %listener.local_addr()?, "Serving metrics");
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let Ok((stream, peer)) = listener.accept().await else {
+                continue;
+            };
+
+            let metrics = Arc::clone(&metrics);
+            tokio::spawn(async move {
+                if let Err(e) = handle_request(stream, &metrics).await {
+                    debug!(peer = %peer, error = %e, "Metrics request failed");
+                }
+            });
+        }
+    }))
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, content_type, body) = if request_line.starts_with(b"GET /metrics ")
+        || request_line == b"GET /metrics"
+    {
+        ("200 OK", "text/plain; version=0.0.4", metrics.render())
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.session_opened();
+        metrics.connect_rejected();
+        metrics.command_finished(Some(CommandStatus::Success), Duration::from_millis(30));
+        metrics.command_finished(Some(CommandStatus::Timeout), Duration::from_secs(400));
+        metrics.command_finished(None, Duration::ZERO);
+        metrics.packet_received(100);
+
+        let text = metrics.render();
+        assert!(text.contains("reticulum_shell_sessions_active 1\n"));
+        assert!(text.contains("reticulum_shell_connects_total{result=\"rejected\"} 1\n"));
+        assert!(text.contains("reticulum_shell_commands_total{status=\"success\"} 1\n"));
+        assert!(text.contains("reticulum_shell_commands_total{status=\"denied\"} 1\n"));
+        assert!(text.contains("reticulum_shell_command_failures_total 2\n"));
+        assert!(text.contains("reticulum_shell_command_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("reticulum_shell_command_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("reticulum_shell_command_duration_seconds_bucket{le=\"300\"} 1\n"));
+        assert!(text.contains("reticulum_shell_command_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("reticulum_shell_command_duration_seconds_count 2\n"));
+        assert!(text.contains("reticulum_shell_bytes_received_total 100\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_endpoint() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.session_opened();
+
+        // Bind to an ephemeral port by hand to learn the address
+        let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = probe.local_addr().unwrap();
+        drop(probe);
+        let server = serve(Arc::clone(&metrics), addr).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("reticulum_shell_sessions_active 1"));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        server.abort();
+    }
+}
@@ -0,0 +1,312 @@
+//! Session encryption on the server side
+//!
+//! Wraps the handshake of [`shell_proto::noise`] for one session: answered
+//! in ACCEPT, completed by the client's HANDSHAKE, after which every message
+//! in either direction is sealed.
+
+use crate::{Result, ServerError};
+use reticulum_core::{Identity, Signer};
+use shell_proto::noise::{Handshake, SessionCipher, StaticKey};
+use shell_proto::{ConnectMessage, ErrorMessage, HandshakeMessage, Message, ProtocolError};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Encryption of one session
+pub struct Encryption {
+    /// X25519 key the client must prove it holds, that of its identity
+    client_key: [u8; 32],
+
+    /// Handshake waiting for the client's last message
+    handshake: Mutex<Option<Handshake>>,
+
+    /// Keys, once the handshake is done
+    cipher: OnceLock<SessionCipher>,
+}
+
+impl Encryption {
+    /// Answer the handshake the client began in `connect` with the server's
+    /// `identity`; returns the message for ACCEPT
+    pub fn respond(
+        identity: Arc<dyn Signer>,
+        connect: &ConnectMessage,
+        first: &[u8],
+    ) -> Result<(Self, Vec<u8>)> {
+        let client_key = Identity::x25519_public_key(&connect.client_identity)
+            .map_err(|e| ServerError::Auth(format!("Invalid client identity: {}", e)))?;
+        let (handshake, second) = Handshake::respond_with(
+            Arc::new(IdentityKey::new(identity)?),
+            &connect.signing_payload(),
+            first,
+        )?;
+        let encryption = Self {
+            client_key,
+            handshake: Mutex::new(Some(handshake)),
+            cipher: OnceLock::new(),
+        };
+        Ok((encryption, second))
+    }
+
+    /// Complete the handshake with the client's last message
+    ///
+    /// Fails if the client proved a key other than its identity's, or if the
+    /// handshake is already done.
+    pub fn finish(&self, message: &HandshakeMessage) -> Result<()> {
+        let handshake = self
+            .handshake
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| ServerError::Session("Handshake already completed".to_string()))?;
+        let cipher = handshake.finish(&message.noise)?;
+        if cipher.remote_static() != &self.client_key {
+            return Err(ServerError::Auth(
+                "Handshake key does not match the client identity".to_string(),
+            ));
+        }
+        let _ = self.cipher.set(cipher);
+        Ok(())
+    }
+
+    /// Whether the handshake is done
+    pub fn is_established(&self) -> bool {
+        self.cipher.get().is_some()
+    }
+
+    /// Seal `message` for the client; left as is until the handshake is done
+    ///
+    /// A reply too large for one Noise message isn't lost: a command response
+    /// goes out with its output cut, any other reply as an ERROR for its
+    /// request.
+    pub fn seal(&self, message: Message) -> Result<Message> {
+        let Some(cipher) = self.cipher.get() else {
+            return Ok(message);
+        };
+        match cipher.seal(&message) {
+            Err(ProtocolError::MessageTooLarge { size, max }) => match fit(message, size - max) {
+                Some(message) => Ok(cipher.seal(&message)?),
+                None => Err(ProtocolError::MessageTooLarge { size, max }.into()),
+            },
+            sealed => Ok(sealed?),
+        }
+    }
+
+    /// Open a sealed `message` from the client
+    ///
+    /// Anything else, or anything before the handshake is done, is refused.
+    pub fn open(&self, message: Message) -> Result<Message> {
+        let cipher = self
+            .cipher
+            .get()
+            .ok_or_else(|| ServerError::Session("Handshake not completed".to_string()))?;
+        match message {
+            Message::Sealed(sealed) => Ok(cipher.open(&sealed)?),
+            _ => Err(ServerError::Session(
+                "Unencrypted message on an encrypted session".to_string(),
+            )),
+        }
+    }
+}
+
+/// `message` shrunk by `excess` bytes or replaced with an ERROR, if it is a
+/// reply
+fn fit(message: Message, excess: usize) -> Option<Message> {
+    let id = match message {
+        // Cutting a delta would garble the output it stands for
+        Message::CommandResponse(mut response) if response.delta_base.is_none() => {
+            cut(&mut response.stdout, &mut response.stderr, excess);
+            response.truncated = true;
+            return Some(Message::CommandResponse(response));
+        }
+        Message::CommandResponse(response) => response.id,
+        Message::JobOutput(output) => output.id,
+        Message::JobStatus(status) => status.request_id,
+        Message::JobListResponse(response) => response.id,
+        Message::FileOpResult(result) => result.id,
+        Message::HistoryResponse(response) => response.id,
+        Message::StatsResponse(response) => response.id,
+        Message::CompleteResponse(response) => response.id,
+        Message::EchoReply(reply) => reply.id,
+        _ => return None,
+    };
+    Some(Message::Error(ErrorMessage::new(
+        id,
+        ErrorMessage::INTERNAL,
+        "Reply too large to send on an encrypted session",
+    )))
+}
+
+/// Drop `excess` bytes off the end of `stdout`, then of `stderr`
+fn cut(stdout: &mut Vec<u8>, stderr: &mut Vec<u8>, excess: usize) {
+    let from_stdout = excess.min(stdout.len());
+    stdout.truncate(stdout.len() - from_stdout);
+    let from_stderr = (excess - from_stdout).min(stderr.len());
+    stderr.truncate(stderr.len() - from_stderr);
+}
+
+/// The X25519 key of the server's identity, for the handshake
+struct IdentityKey {
+    identity: Arc<dyn Signer>,
+    public_key: [u8; 32],
+}
+
+impl IdentityKey {
+    fn new(identity: Arc<dyn Signer>) -> Result<Self> {
+        let public_key = Identity::x25519_public_key(&identity.public_key())?;
+        Ok(Self {
+            identity,
+            public_key,
+        })
+    }
+}
+
+impl StaticKey for IdentityKey {
+    fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    fn agree(&self, public_key: &[u8; 32]) -> shell_proto::Result<[u8; 32]> {
+        self.identity
+            .agree(public_key)
+            .map_err(|e| ProtocolError::Crypto(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::{CommandResponse, CommandStatus, EchoMessage};
+
+    fn connect(client: &Identity) -> ConnectMessage {
+        let mut connect = ConnectMessage {
+            protocol_version: shell_proto::CURRENT_PROTOCOL_VERSION,
+            client_identity: client.public_key(),
+            capabilities: vec![],
+            auth_token: None,
+            timestamp: 0,
+            nonce: [0; 16],
+            signature: vec![],
+            resume_token: None,
+            noise: None,
+            server_destination: [0; 32],
+        };
+        connect.signature = client.sign(&connect.signing_payload());
+        connect
+    }
+
+    fn echo() -> Message {
+        Message::Echo(EchoMessage {
+            id: 1,
+            data: b"hello".to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (client, server) = (Identity::generate(), Identity::generate());
+        let connect = connect(&client);
+        let (initiator, first) =
+            Handshake::initiate(&client.x25519_private_key(), &connect.signing_payload()).unwrap();
+        let (encryption, second) = Encryption::respond(Arc::new(server), &connect, &first).unwrap();
+        assert!(!encryption.is_established());
+        assert!(encryption.open(echo()).is_err());
+
+        let (cipher, third) = initiator.complete(&second).unwrap();
+        encryption
+            .finish(&HandshakeMessage {
+                noise: third.clone(),
+            })
+            .unwrap();
+        assert!(encryption.is_established());
+        assert!(encryption
+            .finish(&HandshakeMessage { noise: third })
+            .is_err());
+
+        // Only sealed messages from now on
+        assert!(encryption.open(echo()).is_err());
+        let opened = encryption.open(cipher.seal(&echo()).unwrap()).unwrap();
+        assert!(matches!(opened, Message::Echo(_)));
+        match encryption.seal(echo()).unwrap() {
+            Message::Sealed(sealed) => assert!(cipher.open(&sealed).is_ok()),
+            other => panic!("not sealed: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_oversized_reply() {
+        let (client, server) = (Identity::generate(), Identity::generate());
+        let connect = connect(&client);
+        let (initiator, first) =
+            Handshake::initiate(&client.x25519_private_key(), &connect.signing_payload()).unwrap();
+        let (encryption, second) = Encryption::respond(Arc::new(server), &connect, &first).unwrap();
+        let (cipher, third) = initiator.complete(&second).unwrap();
+        encryption
+            .finish(&HandshakeMessage { noise: third })
+            .unwrap();
+        let open = |message| match encryption.seal(message).unwrap() {
+            Message::Sealed(sealed) => cipher.open(&sealed).unwrap(),
+            other => panic!("not sealed: {:?}", other),
+        };
+
+        let response = CommandResponse {
+            id: 2,
+            status: CommandStatus::Success,
+            stdout: vec![b'a'; 70_000],
+            stderr: b"warning".to_vec(),
+            exit_code: 0,
+            execution_time_ms: 1,
+            truncated: false,
+            total_bytes: 70_007,
+            spool_id: None,
+            delta_base: None,
+        };
+        match open(Message::CommandResponse(response.clone())) {
+            Message::CommandResponse(cut) => {
+                assert!(cut.truncated);
+                assert!(cut.stdout.len() < 65_535);
+                assert_eq!(cut.stderr, b"warning");
+            }
+            other => panic!("not a response: {:?}", other),
+        }
+
+        // A delta can't be cut
+        let delta = CommandResponse {
+            delta_base: Some([0; 32]),
+            ..response
+        };
+        match open(Message::CommandResponse(delta)) {
+            Message::Error(error) => assert_eq!(error.request_id, 2),
+            other => panic!("not an error: {:?}", other),
+        }
+        let reply = Message::EchoReply(EchoMessage {
+            id: 3,
+            data: vec![0; 70_000],
+        });
+        match open(reply) {
+            Message::Error(error) => assert_eq!(error.request_id, 3),
+            other => panic!("not an error: {:?}", other),
+        }
+        assert!(encryption
+            .seal(Message::Echo(EchoMessage {
+                id: 4,
+                data: vec![0; 70_000],
+            }))
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_of_another_identity() {
+        let (client, server) = (Identity::generate(), Identity::generate());
+        let connect = connect(&client);
+        // Handshake run with a key other than the one CONNECT claims
+        let impostor = Identity::generate();
+        let (initiator, first) =
+            Handshake::initiate(&impostor.x25519_private_key(), &connect.signing_payload())
+                .unwrap();
+        let (encryption, second) = Encryption::respond(Arc::new(server), &connect, &first).unwrap();
+        let (_, third) = initiator.complete(&second).unwrap();
+        assert!(matches!(
+            encryption.finish(&HandshakeMessage { noise: third }),
+            Err(ServerError::Auth(_))
+        ));
+        assert!(!encryption.is_established());
+    }
+}
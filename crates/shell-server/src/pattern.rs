@@ -0,0 +1,52 @@
+//! Glob pattern matching for policy rules
+
+/// Match `text` against a glob `pattern`
+///
+/// `*` matches any run of characters (including none) and `?` matches exactly
+/// one character. Everything else matches literally.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("ls", "ls"));
+        assert!(!glob_match("ls", "lsof"));
+        assert!(glob_match("ls*", "lsof"));
+        assert!(glob_match("*.internal", "db.internal"));
+        assert!(!glob_match("*.internal", "db.internal.evil"));
+        assert!(glob_match("10.0.?.1", "10.0.5.1"));
+        assert!(!glob_match("10.0.?.1", "10.0.55.1"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+}
@@ -0,0 +1,308 @@
+//! Command allow/deny policy
+//!
+//! Patterns are globs (`*`, `?`) unless prefixed with `re:`, in which case the
+//! rest is a regular expression that must match the whole string. Patterns
+//! are compiled as the configuration is read, so a bad one fails the load.
+
+use crate::{config::ServerConfig, pattern::glob_match, Result, ServerError};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use shell_proto::CommandRequest;
+use std::str::FromStr;
+
+/// Argument pattern that matches any remaining arguments
+const REST_OF_ARGS: &str = "**";
+
+/// A glob or `re:` regex pattern
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern {
+    /// The pattern as written
+    source: String,
+
+    /// The compiled expression of a `re:` pattern
+    regex: Option<Regex>,
+}
+
+impl Pattern {
+    /// Compile a pattern
+    pub fn new(source: &str) -> Result<Self> {
+        let regex = match source.strip_prefix("re:") {
+            Some(expr) => Some(Regex::new(&format!("^(?:{})$", expr)).map_err(|e| {
+                ServerError::Config(format!("Invalid command pattern {:?}: {}", source, e))
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            source: source.to_string(),
+            regex,
+        })
+    }
+
+    /// The pattern as written
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Check whether `text` matches the pattern
+    pub fn matches(&self, text: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(text),
+            None => glob_match(&self.source, text),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = ServerError;
+
+    fn from_str(source: &str) -> Result<Self> {
+        Self::new(source)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = ServerError;
+
+    fn try_from(source: String) -> Result<Self> {
+        Self::new(&source)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.source
+    }
+}
+
+/// A command policy rule
+///
+/// In TOML either a bare pattern (`"ls"`), matched against the command, or a
+/// table with argument patterns (`{ command = "git", args = ["status", "**"] }`).
+/// The command is matched as sent by the client, so `"ls"` does not allow
+/// `"/bin/ls"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandRule {
+    /// Command pattern, any arguments
+    Command(Pattern),
+
+    /// Command pattern with positional argument patterns
+    WithArgs {
+        /// Command pattern
+        command: Pattern,
+
+        /// One pattern per argument; a final `"**"` matches any remaining
+        /// arguments (including none)
+        args: Vec<Pattern>,
+    },
+}
+
+impl CommandRule {
+    /// Check whether the rule matches a request
+    pub fn matches(&self, request: &CommandRequest) -> bool {
+        match self {
+            CommandRule::Command(command) => command.matches(&request.command),
+            CommandRule::WithArgs { command, args } => {
+                command.matches(&request.command) && args_match(args, &request.args)
+            }
+        }
+    }
+}
+
+/// Allow/deny lists applied to every command request
+#[derive(Debug, Clone, Default)]
+pub struct CommandPolicy {
+    /// Commands that may run (empty = everything not denied)
+    allowed: Vec<CommandRule>,
+
+    /// Commands that may never run (checked first)
+    denied: Vec<CommandRule>,
+}
+
+impl CommandPolicy {
+    /// Create a policy from allow and deny lists
+    pub fn new(allowed: Vec<CommandRule>, denied: Vec<CommandRule>) -> Self {
+        Self { allowed, denied }
+    }
+
+    /// Build the policy from server configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.allowed_commands.clone(), config.denied_commands.clone())
+    }
+
+    /// Check a request against the policy
+    pub fn check(&self, request: &CommandRequest) -> Result<()> {
+        for rule in &self.denied {
+            if rule.matches(request) {
+                return Err(ServerError::Denied(format!(
+                    "Command is denied by policy: {}",
+                    request.command
+                )));
+            }
+        }
+
+        if self.allowed.is_empty() {
+            return Ok(());
+        }
+
+        if self.allowed.iter().any(|rule| rule.matches(request)) {
+            return Ok(());
+        }
+
+        Err(ServerError::Denied(format!(
+            "Command is not allowed by policy: {}",
+            request.command
+        )))
+    }
+
+    /// Check whether `command` could run with some arguments
+    ///
+    /// Used to offer command names; rules restricting arguments neither
+    /// hide a command (when denying) nor rule it out (when allowing).
+    pub fn permits_command(&self, command: &str) -> bool {
+        let denied = self
+            .denied
+            .iter()
+            .any(|rule| matches!(rule, CommandRule::Command(pattern) if pattern.matches(command)));
+        if denied {
+            return false;
+        }
+
+        self.allowed.is_empty()
+            || self.allowed.iter().any(|rule| {
+                let pattern = match rule {
+                    CommandRule::Command(pattern) => pattern,
+                    CommandRule::WithArgs { command, .. } => command,
+                };
+                pattern.matches(command)
+            })
+    }
+}
+
+/// Match positional argument patterns
+fn args_match(patterns: &[Pattern], args: &[String]) -> bool {
+    let (patterns, rest) = match patterns.split_last() {
+        Some((last, init)) if last.as_str() == REST_OF_ARGS => (init, true),
+        _ => (patterns, false),
+    };
+
+    if args.len() < patterns.len() || (!rest && args.len() != patterns.len()) {
+        return false;
+    }
+
+    patterns
+        .iter()
+        .zip(args)
+        .all(|(pattern, arg)| pattern.matches(arg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(command: &str, args: &[&str]) -> CommandRequest {
+        CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        }
+    }
+
+    fn command(pattern: &str) -> CommandRule {
+        CommandRule::Command(pattern.parse().unwrap())
+    }
+
+    fn with_args(command: &str, args: &[&str]) -> CommandRule {
+        CommandRule::WithArgs {
+            command: command.parse().unwrap(),
+            args: args.iter().map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = CommandPolicy::new(
+            vec![
+                command("ls"),
+                with_args("git", &["re:status|log", "**"]),
+                with_args("systemctl", &["status", "*.service"]),
+            ],
+            vec![],
+        );
+
+        assert!(policy.check(&request("ls", &["-la"])).is_ok());
+        assert!(policy.check(&request("/bin/ls", &[])).is_err());
+        assert!(policy.check(&request("git", &["status"])).is_ok());
+        assert!(policy.check(&request("git", &["log", "--oneline"])).is_ok());
+        assert!(policy.check(&request("git", &["push"])).is_err());
+        assert!(policy.check(&request("systemctl", &["status", "sshd.service"])).is_ok());
+        assert!(policy
+            .check(&request("systemctl", &["status", "sshd.service", "-f"]))
+            .is_err());
+        assert!(policy.check(&request("rm", &["-rf", "/"])).is_err());
+    }
+
+    #[test]
+    fn test_denylist_wins() {
+        let policy = CommandPolicy::new(
+            vec![command("*")],
+            vec![
+                command("re:(/usr)?/s?bin/.*"),
+                with_args("rm", &["-rf", "**"]),
+            ],
+        );
+
+        assert!(policy.check(&request("echo", &["hi"])).is_ok());
+        assert!(policy.check(&request("/sbin/reboot", &[])).is_err());
+        assert!(policy.check(&request("rm", &["-rf", "/tmp/x"])).is_err());
+        assert!(policy.check(&request("rm", &["/tmp/x"])).is_ok());
+    }
+
+    #[test]
+    fn test_permits_command() {
+        let policy = CommandPolicy::new(
+            vec![command("ls"), with_args("git", &["status"]), command("rm")],
+            vec![with_args("rm", &["-rf", "**"])],
+        );
+        assert!(policy.permits_command("ls"));
+        assert!(policy.permits_command("git"));
+        assert!(policy.permits_command("rm"));
+        assert!(!policy.permits_command("cat"));
+
+        let policy = CommandPolicy::new(vec![], vec![command("re:sh.*")]);
+        assert!(policy.permits_command("ls"));
+        assert!(!policy.permits_command("shutdown"));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(matches!(Pattern::new("re:("), Err(ServerError::Config(_))));
+        assert!(Pattern::new("(").is_ok());
+    }
+
+    #[test]
+    fn test_rule_deserialize() {
+        #[derive(Deserialize)]
+        struct Rules {
+            rules: Vec<CommandRule>,
+        }
+
+        let rules: Rules = toml::from_str(
+            r#"rules = ["ls", { command = "git", args = ["status"] }]"#,
+        )
+        .unwrap();
+
+        assert!(matches!(&rules.rules[0], CommandRule::Command(c) if c.as_str() == "ls"));
+        assert!(
+            matches!(&rules.rules[1], CommandRule::WithArgs { command, .. } if command.as_str() == "git")
+        );
+        assert!(toml::from_str::<Rules>(r#"rules = ["re:["]"#).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Process groups of spawned commands
+//!
+//! Every command starts in a new session (`setsid`), so it leads its own
+//! process group. Killing only the direct child leaves whatever it forked
+//! running: the other stages of a shell pipeline, or a backgrounded
+//! grandchild that keeps the output pipes open. On timeout or cancellation
+//! the whole group is killed instead, and a reaper task keeps signalling it
+//! until no member is left. A group can also be interrupted, as Ctrl+C at a
+//! terminal would, leaving it to exit on its own.
+
+use std::time::Duration;
+use tokio::process::{Child, Command as TokioCommand};
+use tracing::{debug, warn};
+
+/// How often the reaper re-checks a killed group
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many times the reaper re-checks before giving up
+const REAP_ATTEMPTS: u32 = 50;
+
+/// Start `cmd` in a new session and process group
+#[cfg(unix)]
+pub fn new_session(cmd: &mut TokioCommand) {
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Process groups are a Unix concept
+#[cfg(not(unix))]
+pub fn new_session(_cmd: &mut TokioCommand) {}
+
+/// Process group led by a spawned command
+///
+/// Killed on drop unless [`ProcessGroup::finished`] was called, so a
+/// command abandoned by a dropped future takes its descendants with it.
+#[derive(Debug)]
+pub struct ProcessGroup {
+    pgid: u32,
+    armed: bool,
+}
+
+impl ProcessGroup {
+    /// Group of a child started with [`new_session`]
+    pub fn of(child: &Child) -> Option<Self> {
+        child.id().map(|pgid| Self { pgid, armed: true })
+    }
+
+    /// Process group ID
+    pub fn id(&self) -> u32 {
+        self.pgid
+    }
+
+    /// Note that the command exited on its own; its group is left alone
+    pub fn finished(&mut self) {
+        self.armed = false;
+    }
+
+    /// Kill every process in the group and reap stragglers in the background
+    pub fn kill(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.armed = false;
+
+        let pgid = self.pgid;
+        if !signal(pgid, KILL) {
+            return;
+        }
+        debug!(pgid, "Killed process group");
+
+        // A member may have been forking while the signal was delivered
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                for _ in 0..REAP_ATTEMPTS {
+                    tokio::time::sleep(REAP_INTERVAL).await;
+                    if !signal(pgid, KILL) {
+                        return;
+                    }
+                }
+                warn!(pgid, "Process group survived repeated kills");
+            });
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Send SIGINT to group `pgid`, returning whether it exists
+pub fn interrupt(pgid: u32) -> bool {
+    let sent = signal(pgid, INTERRUPT);
+    if sent {
+        debug!(pgid, "Interrupted process group");
+    }
+    sent
+}
+
+#[cfg(unix)]
+const KILL: i32 = libc::SIGKILL;
+#[cfg(unix)]
+const INTERRUPT: i32 = libc::SIGINT;
+#[cfg(not(unix))]
+const KILL: i32 = 9;
+#[cfg(not(unix))]
+const INTERRUPT: i32 = 2;
+
+/// Send `sig` to group `pgid`, returning whether it still exists
+#[cfg(unix)]
+fn signal(pgid: u32, sig: i32) -> bool {
+    // SAFETY: killpg only sends a signal; ESRCH means the group is gone
+    unsafe { libc::killpg(pgid as libc::pid_t, sig) == 0 }
+}
+
+#[cfg(not(unix))]
+fn signal(_pgid: u32, _sig: i32) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    /// Whether `pid` runs (zombies waiting for init to reap them don't)
+    fn alive(pid: i32) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or("").trim_start().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kill_group() {
+        // The shell forks a grandchild, prints its PID and waits
+        let mut cmd = TokioCommand::new("sh");
+        cmd.args(["-c", "sleep 30 & echo $!; wait"]);
+        cmd.stdout(Stdio::piped());
+        new_session(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+
+        let mut group = ProcessGroup::of(&child).unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while tokio::io::AsyncReadExt::read(&mut stdout, &mut byte).await.unwrap() == 1 {
+            if byte[0] == b'\n' {
+                break;
+            }
+            line.push(byte[0]);
+        }
+        let grandchild: i32 = String::from_utf8(line).unwrap().trim().parse().unwrap();
+        assert!(alive(grandchild));
+
+        group.kill();
+        child.wait().await.unwrap();
+        for _ in 0..REAP_ATTEMPTS {
+            if !alive(grandchild) {
+                break;
+            }
+            tokio::time::sleep(REAP_INTERVAL).await;
+        }
+        assert!(!alive(grandchild));
+    }
+
+    #[tokio::test]
+    async fn test_interrupt() {
+        let mut cmd = TokioCommand::new("sleep");
+        cmd.arg("30");
+        new_session(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        let mut group = ProcessGroup::of(&child).unwrap();
+
+        assert!(interrupt(group.id()));
+        let status = child.wait().await.unwrap();
+        group.finished();
+        assert_eq!(std::os::unix::process::ExitStatusExt::signal(&status), Some(libc::SIGINT));
+    }
+}
@@ -0,0 +1,323 @@
+//! Interactive PTY execution
+//!
+//! Allocates pseudo-terminals for clients that need full-screen programs or
+//! job control. Output is pushed to the client as `PtyData` messages through
+//! the session's outbound channel; input and resizes arrive as messages too.
+
+use crate::{session::Outbound, Result, ServerError};
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use shell_proto::{Message, PtyClose, PtyData, PtyOpenRequest};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Size of the buffer used when reading PTY output
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// A running PTY process
+struct PtyHandle {
+    /// Master side of the PTY (kept for resizing)
+    master: Box<dyn MasterPty + Send>,
+
+    /// Input for the thread writing to the PTY
+    input: mpsc::Sender<Vec<u8>>,
+
+    /// The spawned process
+    child: Box<dyn Child + Send + Sync>,
+}
+
+/// PTY executor
+///
+/// One executor is owned by each session so that all of a client's terminals
+/// can be torn down when it disconnects.
+pub struct PtyExecutor {
+    /// Open PTYs by client-chosen ID
+    handles: Arc<Mutex<HashMap<u64, PtyHandle>>>,
+}
+
+impl PtyExecutor {
+    /// Create a new PTY executor
+    pub fn new() -> Self {
+        Self {
+            handles: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Open a new PTY and start streaming its output to `outbound`
+    pub fn open(&self, request: PtyOpenRequest, outbound: Outbound) -> Result<()> {
+        let id = request.id;
+
+        if self.handles.lock().unwrap().contains_key(&id) {
+            return Err(ServerError::Execution(format!("PTY {} is already open", id)));
+        }
+
+        debug!(
+            pty_id = id,
+            command = ?request.command,
+            cols = request.cols,
+            rows = request.rows,
+            "Opening PTY"
+        );
+
+        let pair = native_pty_system()
+            .openpty(PtySize {
+                rows: request.rows,
+                cols: request.cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            })
+            .map_err(|e| ServerError::Execution(format!("Failed to allocate PTY: {}", e)))?;
+
+        let mut cmd = match &request.command {
+            Some(program) => {
+                let mut cmd = CommandBuilder::new(program);
+                cmd.args(&request.args);
+                cmd
+            }
+            None => CommandBuilder::new_default_prog(),
+        };
+
+        cmd.env("TERM", &request.term);
+        if let Some(env) = &request.env {
+            for (key, value) in env {
+                cmd.env(key, value);
+            }
+        }
+
+        if let Some(work_dir) = &request.working_dir {
+            cmd.cwd(work_dir);
+        }
+
+        let child = pair
+            .slave
+            .spawn_command(cmd)
+            .map_err(|e| ServerError::Execution(format!("Failed to spawn PTY process: {}", e)))?;
+
+        // Drop our copy of the slave so the reader sees EOF once the child exits
+        drop(pair.slave);
+
+        let mut reader = pair
+            .master
+            .try_clone_reader()
+            .map_err(|e| ServerError::Execution(format!("Failed to open PTY reader: {}", e)))?;
+        let mut writer = pair
+            .master
+            .take_writer()
+            .map_err(|e| ServerError::Execution(format!("Failed to open PTY writer: {}", e)))?;
+
+        // A child that stops reading its input blocks this thread only; it
+        // ends once the handle, and so the sender, is dropped
+        let (input, pending) = mpsc::channel::<Vec<u8>>();
+        std::thread::spawn(move || {
+            for data in pending {
+                let written = writer.write_all(&data).and_then(|_| writer.flush());
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.handles.lock().unwrap().insert(
+            id,
+            PtyHandle {
+                master: pair.master,
+                input,
+                child,
+            },
+        );
+
+        info!(pty_id = id, "PTY opened");
+
+        // PTY I/O is blocking, so pump output on a dedicated thread
+        let handles = Arc::clone(&self.handles);
+        std::thread::spawn(move || {
+            let mut buf = [0u8; READ_BUFFER_SIZE];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        let message = Message::PtyData(PtyData {
+                            id,
+                            data: buf[..n].to_vec(),
+                        });
+                        if outbound.send(message).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let handle = handles.lock().unwrap().remove(&id);
+            let exit_code = handle
+                .and_then(|mut h| h.child.wait().ok())
+                .map(|status| status.exit_code() as i32);
+
+            debug!(pty_id = id, exit_code = ?exit_code, "PTY process finished");
+
+            let _ = outbound.send(Message::PtyClose(PtyClose {
+                id,
+                exit_code,
+                reason: None,
+            }));
+        });
+
+        Ok(())
+    }
+
+    /// Write client input to a PTY
+    ///
+    /// The input is queued for the PTY's writer thread, so this never blocks.
+    pub fn write(&self, id: u64, data: &[u8]) -> Result<()> {
+        let handles = self.handles.lock().unwrap();
+        let handle = handles
+            .get(&id)
+            .ok_or_else(|| ServerError::Execution(format!("Unknown PTY: {}", id)))?;
+
+        handle
+            .input
+            .send(data.to_vec())
+            .map_err(|_| ServerError::Execution(format!("PTY {} no longer takes input", id)))
+    }
+
+    /// Resize a PTY
+    pub fn resize(&self, id: u64, cols: u16, rows: u16) -> Result<()> {
+        let handles = self.handles.lock().unwrap();
+        let handle = handles
+            .get(&id)
+            .ok_or_else(|| ServerError::Execution(format!("Unknown PTY: {}", id)))?;
+
+        handle
+            .master
+            .resize(PtySize {
+                rows,
+                cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            })
+            .map_err(|e| ServerError::Execution(format!("Failed to resize PTY: {}", e)))
+    }
+
+    /// Close a PTY, killing its process
+    pub fn close(&self, id: u64) {
+        let handle = self.handles.lock().unwrap().remove(&id);
+        if let Some(mut handle) = handle {
+            if let Err(e) = handle.child.kill() {
+                warn!(pty_id = id, error = %e, "Failed to kill PTY process");
+            }
+            info!(pty_id = id, "PTY closed");
+        }
+    }
+
+    /// Close every open PTY
+    pub fn close_all(&self) {
+        let ids: Vec<u64> = self.handles.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.close(id);
+        }
+    }
+
+    /// Get number of open PTYs
+    pub fn count(&self) -> usize {
+        self.handles.lock().unwrap().len()
+    }
+}
+
+impl Default for PtyExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn request(id: u64, command: &str, args: &[&str]) -> PtyOpenRequest {
+        PtyOpenRequest {
+            id,
+            command: Some(command.to_string()),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pty_output_and_close() {
+        let executor = PtyExecutor::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        executor.open(request(1, "echo", &["hello"]), tx).unwrap();
+
+        let mut output = Vec::new();
+        loop {
+            match rx.recv().await.unwrap() {
+                Message::PtyData(data) => output.extend_from_slice(&data.data),
+                Message::PtyClose(close) => {
+                    assert_eq!(close.id, 1);
+                    assert_eq!(close.exit_code, Some(0));
+                    break;
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+
+        assert!(String::from_utf8_lossy(&output).contains("hello"));
+        assert_eq!(executor.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pty_close_kills_process() {
+        let executor = PtyExecutor::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        executor.open(request(7, "sleep", &["30"]), tx).unwrap();
+        assert_eq!(executor.count(), 1);
+        executor.resize(7, 120, 40).unwrap();
+
+        executor.close_all();
+        assert_eq!(executor.count(), 0);
+
+        loop {
+            if let Message::PtyClose(close) = rx.recv().await.unwrap() {
+                assert_eq!(close.id, 7);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pty_input() {
+        let executor = PtyExecutor::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        executor.open(request(3, "head", &["-n", "1"]), tx).unwrap();
+        executor.write(3, b"typed\n").unwrap();
+
+        let mut output = Vec::new();
+        loop {
+            match rx.recv().await.unwrap() {
+                Message::PtyData(data) => output.extend_from_slice(&data.data),
+                Message::PtyClose(close) => {
+                    assert_eq!(close.exit_code, Some(0));
+                    break;
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+
+        assert!(String::from_utf8_lossy(&output).contains("typed"));
+    }
+
+    #[test]
+    fn test_unknown_pty() {
+        let executor = PtyExecutor::new();
+        assert!(executor.write(99, b"ls\n").is_err());
+        assert!(executor.resize(99, 80, 24).is_err());
+    }
+}
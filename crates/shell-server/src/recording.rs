@@ -0,0 +1,150 @@
+//! Session recording
+//!
+//! With `session_recording` enabled every session's I/O is written to an
+//! [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) file in
+//! `recording_dir`, so it can be replayed with `asciinema play`. Commands are
+//! recorded as an input line followed by their output; PTYs as the raw
+//! terminal streams including resizes.
+//!
+//! Events are written as they happen, so a recording survives a crash up to
+//! the last event.
+
+use crate::Result;
+use serde_json::json;
+use shell_proto::SessionId;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Terminal size announced in the header (PTYs report their own via resizes)
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// An asciicast recording of one session
+#[derive(Debug)]
+pub struct SessionRecorder {
+    path: PathBuf,
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl SessionRecorder {
+    /// Start a recording in `dir`, named after the start time and session
+    pub fn create(dir: &Path, session_id: SessionId, client: &[u8]) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let now = chrono::Utc::now();
+        let session = Uuid::from_bytes(session_id);
+        let path = dir.join(format!("{}-{}.cast", now.format("%Y%m%dT%H%M%SZ"), session));
+
+        let mut file = File::options().create_new(true).append(true).open(&path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+
+        let header = json!({
+            "version": 2,
+            "width": DEFAULT_SIZE.0,
+            "height": DEFAULT_SIZE.1,
+            "timestamp": now.timestamp(),
+            "title": format!("session {} from {}", session, hex::encode(client)),
+        });
+        writeln!(file, "{}", header)?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    /// Get the recording path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a command line as typed input
+    pub fn command(&self, command: &str, args: &[String]) {
+        let mut line = format!("$ {}", command);
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        line.push_str("\r\n");
+        self.event("i", &line);
+    }
+
+    /// Record output of a non-interactive command
+    ///
+    /// Bare newlines are translated so the replay doesn't staircase.
+    pub fn command_output(&self, data: &[u8]) {
+        if !data.is_empty() {
+            let text = String::from_utf8_lossy(data).replace("\r\n", "\n");
+            self.event("o", &text.replace('\n', "\r\n"));
+        }
+    }
+
+    /// Record raw terminal input
+    pub fn input(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.event("i", &String::from_utf8_lossy(data));
+        }
+    }
+
+    /// Record raw terminal output
+    pub fn output(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.event("o", &String::from_utf8_lossy(data));
+        }
+    }
+
+    /// Record a terminal resize
+    pub fn resize(&self, cols: u16, rows: u16) {
+        self.event("r", &format!("{}x{}", cols, rows));
+    }
+
+    fn event(&self, kind: &str, data: &str) {
+        let line = json!([self.start.elapsed().as_secs_f64(), kind, data]);
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!(path = %self.path.display(), error = %e, "Failed to write session recording");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = SessionRecorder::create(dir.path(), [7; 16], &[0xab]).unwrap();
+        recorder.command("echo", &["hi".to_string()]);
+        recorder.command_output(b"hi\n");
+        recorder.resize(120, 40);
+        recorder.output(b"");
+
+        let contents = fs::read_to_string(recorder.path()).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert!(lines[0]["title"].as_str().unwrap().ends_with("from ab"));
+        assert_eq!(lines[1][1], "i");
+        assert_eq!(lines[1][2], "$ echo hi\r\n");
+        assert_eq!(lines[2][1], "o");
+        assert_eq!(lines[2][2], "hi\r\n");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "120x40");
+        assert!(lines[3][0].as_f64().unwrap() >= lines[1][0].as_f64().unwrap());
+    }
+}
@@ -0,0 +1,272 @@
+//! Persistent session state
+//!
+//! With `session_state_dir` set, the server keeps a small record of every
+//! session on disk (one JSON file per session, mode 0600): its id, the
+//! client identity, working directory, environment and the hash of the
+//! resume token handed out in ACCEPT. After a restart a client presenting
+//! that token in CONNECT gets its old session id and state back instead of a
+//! fresh session.
+//!
+//! Records are removed when the client disconnects and expire after
+//! `session_resume_ttl` seconds. Only the token's SHA-256 is stored, so the
+//! files alone are not enough to take over a session.
+
+use crate::auth::unix_time;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shell_proto::SessionId;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// What survives a restart of one session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// Session ID
+    pub session_id: Uuid,
+
+    /// Client identity (hex-encoded public key)
+    pub client: String,
+
+    /// Working directory
+    #[serde(default)]
+    pub cwd: Option<String>,
+
+    /// Session environment variables
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// SHA-256 of the resume token (hex)
+    pub token_hash: String,
+
+    /// Unix time of the last update
+    pub updated_at: u64,
+}
+
+/// Session records kept in a directory
+#[derive(Debug)]
+pub struct SessionStore {
+    dir: PathBuf,
+    ttl: u64,
+}
+
+impl SessionStore {
+    /// Open (creating if needed) the store in `dir`; records older than
+    /// `ttl` seconds are ignored and pruned
+    pub fn open(dir: &Path, ttl: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            ttl,
+        })
+    }
+
+    /// Store a session's state under a new resume token and return the token
+    ///
+    /// Any previous token of the session stops working.
+    pub fn issue(
+        &self,
+        session_id: SessionId,
+        client_identity: &[u8],
+        cwd: Option<String>,
+        env: BTreeMap<String, String>,
+    ) -> Result<Vec<u8>> {
+        let mut token = Vec::with_capacity(32);
+        token.extend_from_slice(Uuid::new_v4().as_bytes());
+        token.extend_from_slice(Uuid::new_v4().as_bytes());
+
+        self.save(&SessionRecord {
+            session_id: Uuid::from_bytes(session_id),
+            client: hex::encode(client_identity),
+            cwd,
+            env,
+            token_hash: token_hash(&token),
+            updated_at: unix_time(),
+        })?;
+        Ok(token)
+    }
+
+    /// Find the live session `token` resumes for `client_identity`
+    pub fn resume(&self, client_identity: &[u8], token: &[u8]) -> Option<SessionRecord> {
+        let client = hex::encode(client_identity);
+        let hash = token_hash(token);
+        self.records()
+            .into_iter()
+            .find(|record| record.client == client && record.token_hash == hash)
+    }
+
+    /// Load the live record of a session
+    pub fn load(&self, session_id: SessionId) -> Option<SessionRecord> {
+        let record = read_record(&self.path(session_id))?;
+        (!self.expired(&record)).then_some(record)
+    }
+
+    /// Update a session's working directory and environment
+    pub fn update(
+        &self,
+        session_id: SessionId,
+        cwd: Option<String>,
+        env: BTreeMap<String, String>,
+    ) -> Result<()> {
+        let Some(mut record) = read_record(&self.path(session_id)) else {
+            return Ok(());
+        };
+        record.cwd = cwd;
+        record.env = env;
+        record.updated_at = unix_time();
+        self.save(&record)
+    }
+
+    /// Forget a session
+    pub fn remove(&self, session_id: SessionId) {
+        let path = self.path(session_id);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(path = %path.display(), error = %e, "Failed to remove session record");
+            }
+        }
+    }
+
+    /// Delete expired records, returning how many were removed
+    pub fn prune(&self) -> usize {
+        let mut removed = 0;
+        for (path, record) in self.entries() {
+            if self.expired(&record) {
+                debug!(session_id = %record.session_id, "Pruning expired session record");
+                let _ = fs::remove_file(path);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// All live records
+    fn records(&self) -> Vec<SessionRecord> {
+        self.entries()
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(|record| !self.expired(record))
+            .collect()
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, SessionRecord)> {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        dir.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| read_record(&path).map(|record| (path, record)))
+            .collect()
+    }
+
+    fn expired(&self, record: &SessionRecord) -> bool {
+        unix_time().saturating_sub(record.updated_at) > self.ttl
+    }
+
+    fn path(&self, session_id: SessionId) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", Uuid::from_bytes(session_id)))
+    }
+
+    /// Write through a temporary file so a crash never leaves half a record
+    fn save(&self, record: &SessionRecord) -> Result<()> {
+        let path = self.path(*record.session_id.as_bytes());
+        let tmp = path.with_extension("json.tmp");
+
+        let mut file = fs::File::create(&tmp)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(&serde_json::to_vec(record).map_err(std::io::Error::from)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn read_record(path: &Path) -> Option<SessionRecord> {
+    let data = fs::read(path).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "Ignoring unreadable session record");
+            None
+        }
+    }
+}
+
+fn token_hash(token: &[u8]) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::open(dir.path(), 3600).unwrap();
+        let env = BTreeMap::from([("LANG".to_string(), "C".to_string())]);
+
+        let token = store
+            .issue([1; 16], &[0xaa], Some("/tmp".to_string()), env.clone())
+            .unwrap();
+        assert_eq!(token.len(), 32);
+
+        let record = store.resume(&[0xaa], &token).unwrap();
+        assert_eq!(record.session_id, Uuid::from_bytes([1; 16]));
+        assert_eq!(record.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(record.env, env);
+
+        // Wrong client or token
+        assert!(store.resume(&[0xbb], &token).is_none());
+        assert!(store.resume(&[0xaa], b"guess").is_none());
+
+        // A new token replaces the old one
+        let renewed = store
+            .issue([1; 16], &[0xaa], None, BTreeMap::new())
+            .unwrap();
+        assert!(store.resume(&[0xaa], &token).is_none());
+        assert!(store.resume(&[0xaa], &renewed).is_some());
+
+        store
+            .update([1; 16], Some("/srv".to_string()), env)
+            .unwrap();
+        assert_eq!(store.load([1; 16]).unwrap().cwd.as_deref(), Some("/srv"));
+
+        store.remove([1; 16]);
+        assert!(store.resume(&[0xaa], &renewed).is_none());
+        assert!(store.load([1; 16]).is_none());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::open(dir.path(), 60).unwrap();
+        let token = store
+            .issue([2; 16], &[0xaa], None, BTreeMap::new())
+            .unwrap();
+
+        let mut record = store.load([2; 16]).unwrap();
+        record.updated_at -= 120;
+        store.save(&record).unwrap();
+
+        assert!(store.resume(&[0xaa], &token).is_none());
+        assert_eq!(store.prune(), 1);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
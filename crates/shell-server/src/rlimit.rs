@@ -0,0 +1,103 @@
+//! Resource limits (setrlimit) for child processes
+//!
+//! A portable, per-process complement to cgroup limits: applied between fork
+//! and exec on any Unix, no privileges or cgroup setup needed.
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command as TokioCommand;
+
+/// rlimits applied to every spawned command (soft and hard limit alike)
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RlimitConfig {
+    /// Maximum open file descriptors (RLIMIT_NOFILE)
+    #[serde(default)]
+    pub nofile: Option<u64>,
+
+    /// Maximum size of a file the process may write, in bytes (RLIMIT_FSIZE)
+    #[serde(default)]
+    pub fsize: Option<u64>,
+
+    /// CPU time in seconds (RLIMIT_CPU)
+    #[serde(default)]
+    pub cpu: Option<u64>,
+
+    /// Maximum processes for the user (RLIMIT_NPROC)
+    #[serde(default)]
+    pub nproc: Option<u64>,
+}
+
+impl RlimitConfig {
+    /// Check whether any limit is set
+    pub fn is_empty(&self) -> bool {
+        self.nofile.is_none() && self.fsize.is_none() && self.cpu.is_none() && self.nproc.is_none()
+    }
+}
+
+/// Configure `cmd` to apply the limits before exec
+#[cfg(unix)]
+pub fn apply(cmd: &mut TokioCommand, limits: &RlimitConfig) {
+    if limits.is_empty() {
+        return;
+    }
+
+    let limits = *limits;
+
+    unsafe {
+        cmd.pre_exec(move || {
+            // The resource constant's type differs between platforms
+            macro_rules! set {
+                ($resource:expr, $value:expr) => {
+                    if let Some(value) = $value {
+                        let limit = libc::rlimit {
+                            rlim_cur: value as libc::rlim_t,
+                            rlim_max: value as libc::rlim_t,
+                        };
+                        if libc::setrlimit($resource, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                };
+            }
+
+            set!(libc::RLIMIT_NOFILE, limits.nofile);
+            set!(libc::RLIMIT_FSIZE, limits.fsize);
+            set!(libc::RLIMIT_CPU, limits.cpu);
+            set!(libc::RLIMIT_NPROC, limits.nproc);
+            Ok(())
+        });
+    }
+}
+
+/// rlimits are Unix-only
+#[cfg(not(unix))]
+pub fn apply(_cmd: &mut TokioCommand, _limits: &RlimitConfig) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rlimit_config() {
+        let limits: RlimitConfig = toml::from_str("nofile = 64\ncpu = 10").unwrap();
+        assert_eq!(limits.nofile, Some(64));
+        assert_eq!(limits.cpu, Some(10));
+        assert!(!limits.is_empty());
+        assert!(RlimitConfig::default().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_limits_applied() {
+        let limits = RlimitConfig {
+            nofile: Some(32),
+            ..Default::default()
+        };
+
+        let mut cmd = TokioCommand::new("sh");
+        cmd.args(["-c", "ulimit -n"]);
+        apply(&mut cmd, &limits);
+
+        let output = cmd.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "32");
+    }
+}
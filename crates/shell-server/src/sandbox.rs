@@ -0,0 +1,301 @@
+//! Namespace/chroot sandboxing for executed commands
+//!
+//! On Linux a command can be started in fresh mount, PID and network
+//! namespaces and confined to a root directory. Setting up namespaces needs
+//! CAP_SYS_ADMIN, so the server must run as root for sandboxed profiles; the
+//! command itself then runs as an unprivileged user, since root could leave
+//! the chroot again.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::process::Command as TokioCommand;
+
+/// Sandbox settings for a client profile
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SandboxConfig {
+    /// Run in a new mount namespace (mounts made inside stay private)
+    #[serde(default)]
+    pub mount: bool,
+
+    /// Run in a new PID namespace (the command is PID 1 and can't see host processes)
+    #[serde(default)]
+    pub pid: bool,
+
+    /// Run in a new network namespace (no interfaces other than a down loopback)
+    #[serde(default)]
+    pub network: bool,
+
+    /// Directory to chroot into before executing
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+
+    /// User the command runs as when the server is root (None = `nobody`)
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl SandboxConfig {
+    /// Name of the user commands run as
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or("nobody")
+    }
+
+    /// Namespace flags to pass to `unshare(2)`
+    #[cfg(target_os = "linux")]
+    fn clone_flags(&self) -> libc::c_int {
+        let mut flags = 0;
+        if self.mount {
+            flags |= libc::CLONE_NEWNS;
+        }
+        if self.pid {
+            flags |= libc::CLONE_NEWPID;
+        }
+        if self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        flags
+    }
+}
+
+/// Configure `cmd` to enter the sandbox between fork and exec
+///
+/// `working_dir` is interpreted inside the sandbox root.
+#[cfg(target_os = "linux")]
+pub fn apply(cmd: &mut TokioCommand, sandbox: &SandboxConfig, working_dir: Option<&str>) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Everything the child needs is prepared here; only raw syscalls run
+    // after fork
+    let flags = sandbox.clone_flags();
+    let mount_ns = sandbox.mount;
+    let pid_ns = sandbox.pid;
+    let root = sandbox
+        .root
+        .as_ref()
+        .map(|root| CString::new(root.as_os_str().as_bytes()).unwrap_or_default());
+    let proc_dir = CString::new(
+        sandbox
+            .root
+            .as_ref()
+            .map(|root| root.join("proc"))
+            .unwrap_or_else(|| PathBuf::from("/proc"))
+            .as_os_str()
+            .as_bytes(),
+    )
+    .unwrap_or_default();
+    let workdir = CString::new(working_dir.unwrap_or("/")).unwrap_or_default();
+    // A user that doesn't resolve fails the command rather than keeping root
+    let ids = match unsafe { libc::geteuid() } {
+        0 => Some(lookup_user(sandbox.user()).map_err(|e| e.kind())),
+        _ => None,
+    };
+
+    unsafe {
+        cmd.pre_exec(move || {
+            let ids = ids
+                .map(|ids| ids.map_err(std::io::Error::from))
+                .transpose()?;
+            enter(
+                flags,
+                mount_ns,
+                pid_ns,
+                root.as_deref(),
+                &proc_dir,
+                &workdir,
+                ids,
+            )
+        });
+    }
+}
+
+/// Look up the uid and gid of `user`
+#[cfg(target_os = "linux")]
+pub fn lookup_user(user: &str) -> std::io::Result<(libc::uid_t, libc::gid_t)> {
+    use std::io::{Error, ErrorKind};
+
+    let name = std::ffi::CString::new(user)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(Error::from_raw_os_error(rc));
+    }
+    if result.is_null() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No user {:?}", user),
+        ));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Sandboxing is Linux-only; configuration loading rejects sandboxed profiles
+/// elsewhere
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_cmd: &mut TokioCommand, _sandbox: &SandboxConfig, _working_dir: Option<&str>) {}
+
+/// Enter the namespaces and root (runs in the forked child)
+#[cfg(target_os = "linux")]
+fn enter(
+    flags: libc::c_int,
+    mount_ns: bool,
+    pid_ns: bool,
+    root: Option<&std::ffi::CStr>,
+    proc_dir: &std::ffi::CStr,
+    workdir: &std::ffi::CStr,
+    ids: Option<(libc::uid_t, libc::gid_t)>,
+) -> std::io::Result<()> {
+    use std::io::Error;
+    use std::ptr;
+
+    unsafe {
+        if flags != 0 && libc::unshare(flags) != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        if mount_ns {
+            // Keep mounts made in the sandbox from propagating to the host
+            let rc = libc::mount(
+                ptr::null(),
+                b"/\0".as_ptr().cast(),
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            );
+            if rc != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        if pid_ns {
+            // A new PID namespace only applies to children, so fork once more:
+            // the grandchild becomes PID 1 and this process relays its exit
+            // status
+            match libc::fork() {
+                -1 => return Err(Error::last_os_error()),
+                0 => {
+                    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                }
+                child => {
+                    let mut status = 0;
+                    while libc::waitpid(child, &mut status, 0) == -1 {
+                        if Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                            libc::_exit(127);
+                        }
+                    }
+                    if libc::WIFEXITED(status) {
+                        libc::_exit(libc::WEXITSTATUS(status));
+                    }
+                    libc::_exit(128 + libc::WTERMSIG(status));
+                }
+            }
+
+            if mount_ns {
+                let rc = libc::mount(
+                    b"proc\0".as_ptr().cast(),
+                    proc_dir.as_ptr(),
+                    b"proc\0".as_ptr().cast(),
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                    ptr::null(),
+                );
+                if rc != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+        }
+
+        if let Some(root) = root {
+            if libc::chroot(root.as_ptr()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        if libc::chdir(workdir.as_ptr()) != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // Last, as everything above needs root
+        if let Some((uid, gid)) = ids {
+            if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_deserialize() {
+        let sandbox: SandboxConfig = toml::from_str(
+            r#"
+            mount = true
+            pid = true
+            root = "/srv/jail"
+            "#,
+        )
+        .unwrap();
+
+        assert!(sandbox.mount);
+        assert!(sandbox.pid);
+        assert!(!sandbox.network);
+        assert_eq!(sandbox.root, Some(PathBuf::from("/srv/jail")));
+        assert_eq!(sandbox.user(), "nobody");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lookup_user() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert!(lookup_user("no-such-user").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_network_namespace() {
+        // Creating namespaces needs CAP_SYS_ADMIN
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let sandbox = SandboxConfig {
+            mount: true,
+            pid: true,
+            network: true,
+            root: None,
+            user: None,
+        };
+
+        let mut cmd = TokioCommand::new("sh");
+        cmd.args([
+            "-c",
+            "echo $$; id -u; tail -n +3 /proc/net/dev | cut -d: -f1",
+        ]);
+        apply(&mut cmd, &sandbox, None);
+
+        let output = cmd.output().await.unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next(), Some("1"));
+        let nobody = lookup_user("nobody").unwrap().0.to_string();
+        assert_eq!(lines.next(), Some(nobody.as_str()));
+        assert!(lines.all(|iface| iface.trim() == "lo"));
+    }
+}
@@ -0,0 +1,130 @@
+//! Server-side secrets
+//!
+//! A profile's `secrets_file` is a TOML table of named values
+//! (`DB_PASS = "..."`). Clients ask for secrets by name in a command request
+//! and the server sets them as environment variables of that command, so
+//! the client never sends or receives the values. They are added only when
+//! the command is spawned: hooks, history and the audit log see the names, never the
+//! values.
+//!
+//! The file is read on every use, so rotating a secret needs no restart. It
+//! must not be accessible by other users.
+
+use crate::{config::ServerConfig, Result, ServerError};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A client's secrets file
+#[derive(Debug, Clone)]
+pub struct Secrets {
+    /// TOML file of named secrets
+    path: PathBuf,
+}
+
+impl Secrets {
+    /// Use the secrets in `path`
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// The secrets of a client's profile, if it has any
+    pub fn for_client(config: &ServerConfig, client_identity: &[u8]) -> Option<Self> {
+        let path = config.profile_for(client_identity)?.secrets_file.clone()?;
+        Some(Self::new(path))
+    }
+
+    /// Secrets file path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read every secret in the file
+    pub fn load(&self) -> Result<HashMap<String, String>> {
+        check_private(&self.path)?;
+        let contents = std::fs::read_to_string(&self.path)?;
+        toml::from_str(&contents).map_err(|e| {
+            ServerError::Config(format!(
+                "Failed to parse secrets file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Look up the values of `names`, refusing unknown ones
+    pub fn resolve(&self, names: &[String]) -> Result<HashMap<String, String>> {
+        let mut secrets = self.load()?;
+        let mut unknown: Vec<&str> = names
+            .iter()
+            .filter(|name| !secrets.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort_unstable();
+            return Err(ServerError::Denied(format!(
+                "Unknown secret: {}",
+                unknown.join(", ")
+            )));
+        }
+
+        Ok(names
+            .iter()
+            .filter_map(|name| secrets.remove_entry(name))
+            .collect())
+    }
+}
+
+/// Refuse a secrets file other users could read or replace
+#[cfg(unix)]
+pub fn check_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(ServerError::Config(format!(
+            "Secrets file {} is accessible by other users (mode {:o})",
+            path.display(),
+            mode
+        )));
+    }
+    Ok(())
+}
+
+/// Permissions aren't checked off Unix
+#[cfg(not(unix))]
+pub fn check_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.toml");
+        std::fs::write(&path, "DB_PASS = \"hunter2\"\nAPI_KEY = \"k\"\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let secrets = Secrets::new(path.clone());
+
+        let resolved = secrets.resolve(&["DB_PASS".to_string()]).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved["DB_PASS"], "hunter2");
+
+        let err = secrets
+            .resolve(&["DB_PASS".to_string(), "NOPE".to_string()])
+            .unwrap_err();
+        assert!(matches!(err, ServerError::Denied(_)));
+        assert!(err.to_string().contains("NOPE"));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            secrets.resolve(&["DB_PASS".to_string()]),
+            Err(ServerError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+//! Priority order for the messages a session sends of its own accord
+//!
+//! PTY output, streamed command output, forwarded data and throttled file
+//! chunks wait in the session's send queue until the interface takes them.
+//! The queue hands out the most urgent class first (see
+//! [`Priority`](shell_proto::Priority)), oldest first within a class, so a
+//! keystroke echo or a cancel does not wait behind a backlog of file
+//! chunks. Replies to requests are sent as they are made and never queue.
+//! Clients have no such queue: this orders server to client traffic only.
+
+use shell_proto::{Message, Priority};
+use std::collections::VecDeque;
+
+/// Messages waiting to be sent, by priority
+#[derive(Debug, Default)]
+pub struct SendQueue {
+    bulk: VecDeque<Message>,
+    normal: VecDeque<Message>,
+    interactive: VecDeque<Message>,
+}
+
+impl SendQueue {
+    /// Queue `message` behind the others of its priority
+    pub fn push(&mut self, message: Message) {
+        match message.priority() {
+            Priority::Bulk => self.bulk.push_back(message),
+            Priority::Normal => self.normal.push_back(message),
+            Priority::Interactive => self.interactive.push_back(message),
+        }
+    }
+
+    /// Take the message to send next
+    pub fn pop(&mut self) -> Option<Message> {
+        self.interactive
+            .pop_front()
+            .or_else(|| self.normal.pop_front())
+            .or_else(|| self.bulk.pop_front())
+    }
+
+    /// Number of messages waiting
+    pub fn len(&self) -> usize {
+        self.bulk.len() + self.normal.len() + self.interactive.len()
+    }
+
+    /// Nothing is waiting
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_proto::{CancelRequest, FileChunk, PtyData};
+
+    fn chunk(offset: u64) -> Message {
+        Message::FileChunk(FileChunk {
+            transfer_id: 1,
+            offset,
+            data: vec![0; 16],
+        })
+    }
+
+    #[test]
+    fn test_interactive_first() {
+        let mut queue = SendQueue::default();
+        queue.push(chunk(0));
+        queue.push(chunk(16));
+        queue.push(Message::BannerAck);
+        queue.push(Message::PtyData(PtyData {
+            id: 1,
+            data: b"x".to_vec(),
+        }));
+        queue.push(Message::CancelRequest(CancelRequest {
+            id: 2,
+            force: false,
+        }));
+        assert_eq!(queue.len(), 5);
+
+        assert!(matches!(queue.pop(), Some(Message::PtyData(_))));
+        assert!(matches!(queue.pop(), Some(Message::CancelRequest(_))));
+        assert!(matches!(queue.pop(), Some(Message::BannerAck)));
+        // Chunks keep their order
+        assert!(matches!(queue.pop(), Some(Message::FileChunk(c)) if c.offset == 0));
+        assert!(matches!(queue.pop(), Some(Message::FileChunk(c)) if c.offset == 16));
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+}
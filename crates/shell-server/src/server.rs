@@ -1,13 +1,39 @@
 //! Main server implementation
 
-use crate::{config::ServerConfig, listener::Listener, session::Session, Result, ServerError};
-use reticulum_core::{NetworkInterface, Packet};
-use shell_proto::{ProtocolCodec, SessionId};
+use crate::{
+    accounting::Accounting,
+    admin,
+    approval::ApprovalQueue,
+    audit::AuditLog,
+    bandwidth::{BandwidthLimiter, TokenBucket},
+    config::ServerConfig,
+    hooks::{Hook, ScriptHook},
+    listener::{Listener, CAPABILITIES},
+    metrics::{self, Metrics},
+    noise::Encryption,
+    resume::SessionStore,
+    send_queue::SendQueue,
+    session::{Outbound, Session},
+    Result, ServerError,
+};
+use reticulum_core::{
+    announce, rns, Announce, DestinationHash, Identity, InterfaceManager, NetworkInterface, Packet,
+    PacketType, WireFormat,
+};
+use shell_proto::messages::{AckMessage, RejectMessage};
+use shell_proto::{
+    packet_signing_payload, AnnounceInfo, Message, Priority, ProtocolCodec, SessionId,
+    CURRENT_PROTOCOL_VERSION,
+};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Instant};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 /// The main server
 pub struct Server {
@@ -17,24 +43,42 @@ pub struct Server {
     /// Connection listener
     listener: Arc<Listener>,
 
-    /// Network interface
-    interface: Option<Arc<dyn NetworkInterface>>,
+    /// Network interfaces, each served by its own message loop
+    interfaces: InterfaceManager,
 
     /// Active sessions
-    sessions: Arc<RwLock<HashMap<SessionId, Arc<Session>>>>,
+    sessions: admin::Sessions,
+
+    /// Session each client source is bound to, by interface name and
+    /// source hash
+    routes: RwLock<HashMap<(String, DestinationHash), SessionId>>,
+
+    /// Audit log
+    audit: Arc<AuditLog>,
+
+    /// Metrics registry
+    metrics: Arc<Metrics>,
+
+    /// Persisted sessions (None = not resumable)
+    store: Option<Arc<SessionStore>>,
+
+    /// Per-client usage counters
+    accounting: Arc<Accounting>,
+
+    /// Hooks called around every command
+    hooks: Vec<Arc<dyn Hook>>,
+
+    /// Commands waiting for operator approval
+    approvals: Arc<ApprovalQueue>,
+
+    /// Bandwidth shared by all sessions' bulk traffic (None = unlimited)
+    bandwidth: Option<Arc<TokenBucket>>,
 }
 
 impl Server {
     /// Create a new server
     pub async fn new(config: ServerConfig) -> Result<Self> {
-        let listener = Arc::new(Listener::new(config.clone()));
-
-        Ok(Self {
-            config: Arc::new(config),
-            listener,
-            interface: None,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
-        })
+        Self::with_interfaces(config, InterfaceManager::new()).await
     }
 
     /// Create a server with a specific network interface (for testing)
@@ -42,66 +86,206 @@ impl Server {
         config: ServerConfig,
         interface: Arc<dyn NetworkInterface>,
     ) -> Result<Self> {
-        let listener = Arc::new(Listener::new(config.clone()));
+        Self::with_interfaces(config, InterfaceManager::new().with(interface)).await
+    }
+
+    /// Create a server listening on several interfaces at once
+    pub async fn with_interfaces(
+        config: ServerConfig,
+        interfaces: InterfaceManager,
+    ) -> Result<Self> {
+        let audit = Arc::new(AuditLog::from_config(&config)?);
+        let metrics = Arc::new(Metrics::new());
+
+        let store = match &config.session_state_dir {
+            Some(dir) => {
+                let store = SessionStore::open(dir, config.session_resume_ttl)?;
+                let pruned = store.prune();
+                if pruned > 0 {
+                    info!(pruned, "Pruned expired session records");
+                }
+                Some(Arc::new(store))
+            }
+            None => None,
+        };
+
+        let accounting = Arc::new(match &config.accounting_file {
+            Some(path) => Accounting::open(path)?,
+            None => Accounting::new(),
+        });
+
+        let mut hooks: Vec<Arc<dyn Hook>> = Vec::new();
+        if let Some(hook) = ScriptHook::from_config(&config) {
+            hooks.push(Arc::new(hook));
+        }
+
+        let mut listener = Listener::new(config.clone())
+            .with_audit(Arc::clone(&audit))
+            .with_metrics(Arc::clone(&metrics));
+        if let Some(store) = &store {
+            listener = listener.with_store(Arc::clone(store));
+        }
+        for interface in interfaces.interfaces() {
+            if let Some(destination) = interface.destination_hash() {
+                listener = listener.with_destination(destination);
+            }
+        }
+        let listener = Arc::new(listener);
+        let approvals = Arc::new(ApprovalQueue::from_config(&config));
+        let bandwidth = BandwidthLimiter::global_from_config(&config);
 
         Ok(Self {
             config: Arc::new(config),
             listener,
-            interface: Some(interface),
+            interfaces,
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            routes: RwLock::new(HashMap::new()),
+            audit,
+            metrics,
+            store,
+            accounting,
+            hooks,
+            approvals,
+            bandwidth,
         })
     }
 
+    /// Call `hook` before and after every command (after configured hooks)
+    pub fn with_hook(mut self, hook: Arc<dyn Hook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Get the metrics registry
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
     /// Run the server
     pub async fn run(self) -> Result<()> {
         info!("Server starting...");
         info!("Destination: {}", self.config.identity.destination_hex());
 
-        // Check if we have a network interface
-        if let Some(ref interface) = self.interface {
+        let metrics_task = match self.config.metrics_bind {
+            Some(addr) => Some(metrics::serve(Arc::clone(&self.metrics), addr).await?),
+            None => None,
+        };
+
+        let admin_task = match &self.config.admin_socket {
+            Some(path) => {
+                let state = admin::AdminState {
+                    sessions: Arc::clone(&self.sessions),
+                    bans: self.listener.bans(),
+                    accounting: Arc::clone(&self.accounting),
+                    approvals: Arc::clone(&self.approvals),
+                };
+                Some(admin::serve(path, state).await?)
+            }
+            None => None,
+        };
+
+        // One message loop per interface
+        let server = Arc::new(self);
+        let announce_task = Arc::clone(&server).spawn_announcer();
+        let mut loops = JoinSet::new();
+        for interface in server.interfaces.interfaces() {
             info!("Running with network interface: {}", interface.name());
+            let server = Arc::clone(&server);
+            let interface = Arc::clone(interface);
+            loops.spawn(async move {
+                let name = interface.name().to_string();
+                (name, server.message_loop(interface).await)
+            });
+        }
 
-            // Clone the Arc for the message loop
-            let interface_clone = Arc::clone(interface);
+        if loops.is_empty() {
+            warn!("No network interface configured - server will wait for Ctrl+C");
+            info!("Server running. Press Ctrl+C to stop.");
+        }
 
-            // Run message loop and wait for shutdown signal concurrently
-            tokio::select! {
-                result = self.message_loop(interface_clone) => {
-                    if let Err(e) = result {
-                        error!("Message loop error: {}", e);
+        tokio::select! {
+            Some(joined) = loops.join_next() => {
+                loops.abort_all();
+                match joined {
+                    Ok((name, Err(e))) => {
+                        error!(interface = %name, "Message loop error: {}", e);
                         return Err(e);
                     }
+                    Ok((name, Ok(()))) => warn!(interface = %name, "Message loop ended"),
+                    Err(e) => error!("Message loop panicked: {}", e),
                 }
-                result = signal::ctrl_c() => {
-                    match result {
-                        Ok(()) => info!("Shutdown signal received"),
-                        Err(err) => {
-                            error!("Error waiting for shutdown signal: {}", err);
-                            return Err(ServerError::Io(err));
-                        }
+            }
+            result = shutdown_signal() => {
+                match result {
+                    Ok(()) => info!("Shutdown signal received"),
+                    Err(err) => {
+                        error!("Error waiting for shutdown signal: {}", err);
+                        return Err(ServerError::Io(err));
                     }
                 }
             }
-        } else {
-            warn!("No network interface configured - server will wait for Ctrl+C");
-            info!("Server running. Press Ctrl+C to stop.");
+        }
 
-            // Wait for shutdown signal
-            match signal::ctrl_c().await {
-                Ok(()) => {
-                    info!("Shutdown signal received");
-                }
-                Err(err) => {
-                    error!("Error waiting for shutdown signal: {}", err);
-                    return Err(ServerError::Io(err));
-                }
+        // The loops keep answering in-flight requests while draining;
+        // whatever is still running when shutdown() returns is dropped (and
+        // killed)
+        info!("Server shutting down...");
+        let result = server.shutdown().await;
+        loops.abort_all();
+
+        announce_task.abort();
+        if let Some(task) = metrics_task {
+            task.abort();
+        }
+        if let Some(task) = admin_task {
+            task.abort();
+            if let Some(path) = &server.config.admin_socket {
+                let _ = std::fs::remove_file(path);
             }
         }
 
-        info!("Server shutting down...");
-        self.shutdown().await?;
+        result
+    }
 
-        Ok(())
+    /// Build a signed announce of the server destination for an interface
+    /// in `format`
+    fn announce(&self, format: WireFormat) -> Result<Packet> {
+        let info = AnnounceInfo {
+            name: self.config.announce_name.clone(),
+            protocol_version: CURRENT_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        let identity = self.config.identity.as_ref();
+        Ok(match format {
+            WireFormat::Native => Announce::signed_by(identity, info.to_bytes())?.to_packet(),
+            WireFormat::Rns => rns::announce_signed_by(identity, info.to_bytes())?,
+        })
+    }
+
+    /// Announce on every interface now and then every `announce_interval`
+    fn spawn_announcer(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                for interface in self.interfaces.interfaces() {
+                    let packet = match self.announce(interface.wire_format()) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            warn!("Failed to sign announce: {}", e);
+                            continue;
+                        }
+                    };
+                    match interface.broadcast(&packet).await {
+                        Ok(()) => self.metrics.packet_sent(packet.data.len()),
+                        Err(e) => debug!(interface = %interface.name(), "Announce failed: {}", e),
+                    }
+                }
+
+                if self.config.announce_interval == 0 {
+                    return;
+                }
+                sleep(Duration::from_secs(self.config.announce_interval)).await;
+            }
+        })
     }
 
     /// Message processing loop
@@ -118,6 +302,8 @@ impl Server {
                 }
             };
 
+            self.metrics.packet_received(packet.data.len());
+
             debug!(
                 destination = %hex::encode(&packet.destination),
                 data_len = packet.data.len(),
@@ -125,6 +311,50 @@ impl Server {
                 "Received packet"
             );
 
+            match packet.packet_type {
+                PacketType::Announce => {
+                    debug!("Ignoring announce");
+                    continue;
+                }
+                PacketType::PathRequest => {
+                    // Answered by announcing; the requester may not have a
+                    // route we can address directly yet
+                    if announce::requested_destination(&packet)
+                        == Some(self.config.identity.destination_hash())
+                    {
+                        debug!("Answering path request");
+                        match self.announce(interface.wire_format()) {
+                            Ok(response) => match interface.broadcast(&response).await {
+                                Ok(()) => self.metrics.packet_sent(response.data.len()),
+                                Err(e) => warn!("Failed to answer path request: {}", e),
+                            },
+                            Err(e) => warn!("Failed to sign announce: {}", e),
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            // One signature and sequence number cover the whole packet,
+            // however many messages it carries: checked once, here
+            let authenticated = match self.route(&*interface, &packet.destination).await {
+                Some(session) if session.encryption().is_none() && packet.signature.is_some() => {
+                    match verify_packet(&packet, &session) {
+                        Ok(()) => Some(session.id),
+                        Err(reason) => {
+                            warn!(
+                                session_id = %Uuid::from_bytes(session.id),
+                                reason = %reason,
+                                "Dropping unauthenticated packet"
+                            );
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+
             // Try to decode as protocol message
             let mut buf = bytes::BytesMut::from(packet.data.as_ref());
             let messages = match ProtocolCodec::decode_multiple(&mut buf) {
@@ -137,29 +367,108 @@ impl Server {
 
             // Process each message
             for message in messages {
-                use shell_proto::Message;
-
                 let response = match message {
                     Message::Connect(ref connect) => {
                         debug!("Handling CONNECT message");
 
+                        // Answer the client's Noise handshake, if it began one
+                        let handshake = match &connect.noise {
+                            Some(first) => {
+                                match Encryption::respond(
+                                    Arc::clone(&self.config.identity),
+                                    connect,
+                                    first,
+                                ) {
+                                    Ok((encryption, second)) => {
+                                        Some((Arc::new(encryption), second))
+                                    }
+                                    Err(e) => {
+                                        warn!("Refusing Noise handshake: {}", e);
+                                        let reject = Message::Reject(RejectMessage {
+                                            reason: format!("Noise handshake failed: {}", e),
+                                            error_code: 1,
+                                        });
+                                        send_message(
+                                            &*interface,
+                                            packet.destination,
+                                            &self.metrics,
+                                            &reject,
+                                        )
+                                        .await?;
+                                        continue;
+                                    }
+                                }
+                            }
+                            None => None,
+                        };
+
                         // Handle connection and get response
-                        let response = self.listener.handle_connection(Message::Connect(connect.clone())).await?;
+                        let mut response = self
+                            .listener
+                            .handle_connection_from(
+                                Message::Connect(connect.clone()),
+                                Some(&packet.destination),
+                            )
+                            .await?;
 
                         // If connection accepted, create and store session
-                        if let Message::Accept(ref accept) = response {
+                        if let Message::Accept(ref mut accept) = response {
                             debug!("Connection accepted, creating session");
 
-                            let session = Arc::new(Session::new(
+                            let encryption = handshake.map(|(encryption, second)| {
+                                accept.noise = Some(second);
+                                encryption
+                            });
+
+                            let outbound = spawn_outbound_forwarder(
+                                Arc::clone(&interface),
+                                packet.destination,
+                                Arc::clone(&self.metrics),
+                                encryption.clone(),
+                                connect.capabilities.iter().any(|c| c == "frame-priority"),
+                            );
+
+                            let mut session = Session::new(
                                 connect.client_identity.clone(),
                                 self.listener.executor(),
-                            ));
+                            )
+                            .with_id(accept.session_id)
+                            .with_config(Arc::clone(&self.config))
+                            .with_outbound(outbound)
+                            .with_audit(Arc::clone(&self.audit))
+                            .with_metrics(Arc::clone(&self.metrics))
+                            .with_accounting(Arc::clone(&self.accounting))
+                            .with_hooks(self.hooks.clone())
+                            .with_approvals(Arc::clone(&self.approvals))
+                            .with_bandwidth(self.bandwidth.clone())
+                            .with_output_streaming(
+                                connect.capabilities.iter().any(|c| c == "output-stream"),
+                            );
+                            if let Some(store) = &self.store {
+                                session = session.with_store(Arc::clone(store));
+                            }
+                            if let Some(encryption) = encryption {
+                                session = session.with_encryption(encryption);
+                            }
+                            let session = Arc::new(session);
+
+                            // Bind the session to the source, and only that one
+                            {
+                                let mut routes = self.routes.write().await;
+                                routes.retain(|_, id| *id != accept.session_id);
+                                routes.insert(
+                                    (interface.name().to_string(), packet.destination),
+                                    accept.session_id,
+                                );
+                            }
 
                             let mut sessions = self.sessions.write().await;
                             sessions.insert(accept.session_id, session);
+                            self.metrics.session_opened();
+                            self.accounting.session_started(&connect.client_identity);
 
                             info!(
-                                session_id = %hex::encode(&accept.session_id),
+                                session_id = %Uuid::from_bytes(accept.session_id),
                                 client = %hex::encode(&connect.client_identity),
                                 "Client connected - new session created"
                             );
@@ -168,53 +477,332 @@ impl Server {
                         response
                     }
 
-                    Message::CommandRequest(_) | Message::Disconnect(_) | Message::Ping => {
+                    Message::Accept(_) | Message::Reject(_) => {
+                        warn!("Unexpected message type in server loop");
+                        continue;
+                    }
+
+                    _ => {
                         debug!("Handling session message");
 
-                        // For session messages, we need to find the session
-                        // For now, use the first session (simplification for MVP)
-                        let sessions = self.sessions.read().await;
-                        if let Some((session_id, session)) = sessions.iter().next() {
-                            debug!(session_id = %hex::encode(session_id), "Routing to session");
+                        // Messages go to the session of the source they came
+                        // from, and must prove they are from its client
+                        let Some(session) = self.route(&*interface, &packet.destination).await
+                        else {
+                            warn!(
+                                source = %hex::encode(packet.destination),
+                                "No session for source, dropping message"
+                            );
+                            continue;
+                        };
+                        let session_id = session.id;
+                        debug!(
+                            session_id = %Uuid::from_bytes(session_id),
+                            "Routing to session"
+                        );
+
+                        // Encrypted sessions are authenticated by their keys,
+                        // the others by a signature on every packet
+                        if session.encryption().is_none() && authenticated != Some(session_id) {
+                            warn!(
+                                session_id = %Uuid::from_bytes(session_id),
+                                "Dropping unsigned message"
+                            );
+                            continue;
+                        }
 
-                            match session.handle_message(message).await? {
-                                Some(msg) => msg,
-                                None => {
-                                    warn!("Session returned no response");
+                        // An encrypted session first takes the rest of the
+                        // handshake, then nothing but sealed messages
+                        let encryption = session.encryption();
+                        let message = match (encryption, message) {
+                            (Some(encryption), Message::Handshake(handshake)) => {
+                                let response = match encryption.finish(&handshake) {
+                                    Ok(()) => {
+                                        info!(
+                                            session_id = %Uuid::from_bytes(session_id),
+                                            "Session encrypted"
+                                        );
+                                        let ack = Message::Ack(AckMessage { message_id: 0 });
+                                        match encryption.seal(ack) {
+                                            Ok(sealed) => sealed,
+                                            Err(e) => {
+                                                warn!("Failed to seal ACK: {}", e);
+                                                continue;
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!("Noise handshake failed: {}", e);
+                                        session.close().await?;
+                                        Message::Reject(RejectMessage {
+                                            reason: format!("Noise handshake failed: {}", e),
+                                            error_code: 3,
+                                        })
+                                    }
+                                };
+                                send_message(
+                                    &*interface,
+                                    packet.destination,
+                                    &self.metrics,
+                                    &response,
+                                )
+                                .await?;
+                                continue;
+                            }
+                            (Some(encryption), message) => match encryption.open(message) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    warn!("Dropping message: {}", e);
                                     continue;
                                 }
+                            },
+                            (None, Message::Handshake(_) | Message::Sealed(_)) => {
+                                warn!("Encrypted message on an unencrypted session");
+                                continue;
                             }
-                        } else {
-                            warn!("No active sessions, dropping message");
+                            (None, message) => message,
+                        };
+
+                        // Commands may run for long; handled aside, they
+                        // leave the loop free to pass on a cancel or input
+                        if let Message::CommandRequest(req) = &message {
+                            session.open_input(req);
+                            let session = Arc::clone(&session);
+                            let interface = Arc::clone(&interface);
+                            let metrics = Arc::clone(&self.metrics);
+                            let destination = packet.destination;
+                            tokio::spawn(async move {
+                                let response = match session.handle_message(message).await {
+                                    Ok(Some(msg)) => msg,
+                                    Ok(None) => return,
+                                    Err(e) => {
+                                        warn!("Session failed to handle message: {}", e);
+                                        return;
+                                    }
+                                };
+                                let response = match session.encryption() {
+                                    Some(encryption) => match encryption.seal(response) {
+                                        Ok(sealed) => sealed,
+                                        Err(e) => {
+                                            warn!("Failed to seal command response: {}", e);
+                                            return;
+                                        }
+                                    },
+                                    None => response,
+                                };
+                                if let Err(e) =
+                                    send_message(&*interface, destination, &metrics, &response)
+                                        .await
+                                {
+                                    warn!("Failed to send command response: {}", e);
+                                }
+                            });
                             continue;
                         }
-                    }
 
-                    _ => {
-                        warn!("Unexpected message type in server loop");
-                        continue;
+                        let response = match session.handle_message(message).await {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => {
+                                debug!("Session returned no response");
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("Session failed to handle message: {}", e);
+                                continue;
+                            }
+                        };
+                        match session.encryption() {
+                            Some(encryption) => match encryption.seal(response) {
+                                Ok(sealed) => sealed,
+                                Err(e) => {
+                                    warn!("Failed to seal response: {}", e);
+                                    continue;
+                                }
+                            },
+                            None => response,
+                        }
                     }
                 };
 
                 debug!("Sending response");
+                send_message(&*interface, packet.destination, &self.metrics, &response).await?;
+                debug!("Response sent");
+            }
+        }
+    }
+
+    /// Session the client at `source` on `interface` is bound to
+    async fn route(
+        &self,
+        interface: &dyn NetworkInterface,
+        source: &DestinationHash,
+    ) -> Option<Arc<Session>> {
+        let key = (interface.name().to_string(), *source);
+        let session_id = *self.routes.read().await.get(&key)?;
+        self.sessions.read().await.get(&session_id).cloned()
+    }
 
-                // Encode response
-                let response_bytes = ProtocolCodec::encode(&response)?;
+    /// Shut the server down
+    ///
+    /// Stops accepting connections, tells every client, waits up to
+    /// `shutdown_grace_period` for running commands and jobs, then closes the
+    /// sessions (killing whatever is left), signs the audit log and closes the
+    /// interfaces. Requests keep being served while this runs if the message
+    /// loops are running alongside it, as in [`Server::run`].
+    pub async fn shutdown(&self) -> Result<()> {
+        self.listener.stop_accepting();
+
+        let sessions: Vec<Arc<Session>> = self.sessions.read().await.values().cloned().collect();
+        info!(sessions = sessions.len(), "Closing active sessions...");
+
+        for session in &sessions {
+            session.notify_disconnect("Server shutting down");
+        }
 
-                // Send response packet
-                let response_packet = Packet::data(packet.destination, response_bytes);
-                interface.send(&response_packet).await?;
+        let grace = Duration::from_secs(self.config.shutdown_grace_period);
+        let deadline = Instant::now() + grace;
+        while sessions.iter().any(|session| session.is_busy()) {
+            if Instant::now() >= deadline {
+                warn!(
+                    grace_period_secs = grace.as_secs(),
+                    "Commands still running after grace period, killing them"
+                );
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
 
-                debug!("Response sent");
+        for session in &sessions {
+            if let Err(e) = session.close().await {
+                warn!(session_id = %session.id_string(), error = %e, "Failed to close session");
             }
         }
-    }
+        self.sessions.write().await.clear();
+
+        self.audit.flush_checkpoint();
+
+        self.interfaces.close_all().await;
 
-    /// Shutdown the server
-    async fn shutdown(&self) -> Result<()> {
-        info!("Closing active sessions...");
-        // TODO: Close all active sessions
         info!("Server shutdown complete");
         Ok(())
     }
 }
+
+/// Wait for Ctrl+C or, on Unix, SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = term.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await
+    }
+}
+
+/// Check that `packet` is signed by the client of `session`, for it, and
+/// numbered differently from every packet accepted lately
+///
+/// See [`packet_signing_payload`].
+fn verify_packet(packet: &Packet, session: &Session) -> std::result::Result<(), String> {
+    let signature = packet
+        .signature
+        .as_ref()
+        .ok_or_else(|| "packet not signed".to_string())?;
+    let sequence = packet
+        .sequence
+        .ok_or_else(|| "packet not numbered".to_string())?;
+    Identity::verify_external(
+        &session.client_identity,
+        &packet_signing_payload(&session.id, sequence, &packet.data),
+        signature,
+    )
+    .map_err(|_| "signature does not match the session's client".to_string())?;
+    if !session.accept_sequence(sequence) {
+        return Err("packet replayed or out of order".to_string());
+    }
+    Ok(())
+}
+
+/// Encode `message` and send it to `destination`
+async fn send_message(
+    interface: &dyn NetworkInterface,
+    destination: DestinationHash,
+    metrics: &Metrics,
+    message: &shell_proto::Message,
+) -> Result<()> {
+    let bytes = ProtocolCodec::encode(message)?;
+    let len = bytes.len();
+    interface.send(&Packet::data(destination, bytes)).await?;
+    metrics.packet_sent(len);
+    Ok(())
+}
+
+/// Spawn a task that delivers a session's server-initiated messages
+///
+/// Messages that pile up go out most urgent first, sealed only when their
+/// turn comes so nonces follow the order they are sent in. Their frames
+/// carry that priority if the client takes `frame-priority`.
+fn spawn_outbound_forwarder(
+    interface: Arc<dyn NetworkInterface>,
+    destination: DestinationHash,
+    metrics: Arc<Metrics>,
+    encryption: Option<Arc<Encryption>>,
+    frame_priority: bool,
+) -> Outbound {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut queue = SendQueue::default();
+        loop {
+            if queue.is_empty() {
+                match rx.recv().await {
+                    Some(message) => queue.push(message),
+                    None => break,
+                }
+            }
+            while let Ok(message) = rx.try_recv() {
+                queue.push(message);
+            }
+            let Some(message) = queue.pop() else {
+                continue;
+            };
+
+            let priority = if frame_priority {
+                message.priority()
+            } else {
+                Priority::Normal
+            };
+            let message = match &encryption {
+                Some(encryption) => match encryption.seal(message) {
+                    Ok(sealed) => sealed,
+                    Err(e) => {
+                        warn!("Failed to seal outbound message: {}", e);
+                        continue;
+                    }
+                },
+                None => message,
+            };
+            let bytes = match ProtocolCodec::encode_with_priority(&message, priority) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Failed to encode outbound message: {}", e);
+                    continue;
+                }
+            };
+
+            let len = bytes.len();
+            match interface.send(&Packet::data(destination, bytes)).await {
+                Ok(()) => metrics.packet_sent(len),
+                Err(e) => warn!("Failed to send outbound message: {}", e),
+            }
+        }
+    });
+
+    tx
+}
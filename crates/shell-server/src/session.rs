@@ -1,12 +1,58 @@
 //! Client session management
 
-use crate::{shell::CommandExecutor, Result, ServerError};
-use shell_proto::{messages::AckMessage, Message, SessionId};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use crate::{
+    accounting::Accounting,
+    approval::ApprovalQueue,
+    audit::{AuditEvent, AuditLog},
+    auth::unix_time,
+    bandwidth::{BandwidthLimiter, TokenBucket},
+    builtins, completion,
+    config::ServerConfig,
+    delta::{self, DeltaCache},
+    env_policy::EnvPolicy,
+    files::FileService,
+    forward::{ForwardPolicy, ForwardService},
+    hooks::{self, Hook, HookEvent},
+    input::Inputs,
+    jobs::JobManager,
+    metrics::Metrics,
+    noise::Encryption,
+    pattern::glob_match,
+    pty::PtyExecutor,
+    recording::SessionRecorder,
+    resume::SessionStore,
+    secrets::Secrets,
+    session_dir::SessionDir,
+    shell::{CommandExecutor, Interrupter, OutputSink},
+    spool::OutputSpool,
+    transfer::TransferService,
+    Result, ServerError,
+};
+use shell_proto::{
+    messages::{
+        AckMessage, DisconnectMessage, HistoryResponse, JobListResponse, JobOutput,
+        JobStatusMessage,
+    },
+    CancelRequest, ChannelClose, ChannelKind, CommandInput, CommandOutput, CommandRequest,
+    CommandResponse, CommandStatus, CompleteRequest, CompleteResponse, CompletionKind,
+    ErrorMessage, ExecUploadRequest, FileEntry, FileOp, FileOpResult, HistoryEntry, Message,
+    OutputChunk, PendingNotice, PtyClose, ReplayWindow, SessionId, StatsResponse, TransferReady,
+    UploadRequest, MAX_CHUNK_SIZE,
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock, Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+/// Channel for messages the server pushes to a client outside of a
+/// request/response exchange (PTY output, notifications, ...)
+pub type Outbound = mpsc::UnboundedSender<Message>;
+
 /// A client session
 pub struct Session {
     /// Session ID
@@ -15,13 +61,126 @@ pub struct Session {
     /// Client identity (public key)
     pub client_identity: Vec<u8>,
 
+    /// Server configuration
+    config: Arc<ServerConfig>,
+
     /// Command executor
     executor: Arc<CommandExecutor>,
 
+    /// Background jobs owned by this session
+    jobs: JobManager,
+
+    /// File transfers in progress
+    transfers: Arc<TransferService>,
+
+    /// Filesystem operations
+    files: FileService,
+
+    /// Directories `cd` may change into: the client's allowed paths, even
+    /// where filesystem operations are refused
+    dirs: FileService,
+
+    /// TCP port forwards
+    forwards: ForwardService,
+
+    /// Interactive PTYs owned by this session
+    pty: PtyExecutor,
+
+    /// Channel for server-initiated messages
+    outbound: Option<Outbound>,
+
+    /// Noise encryption, if the client asked for it
+    encryption: Option<Arc<Encryption>>,
+
+    /// Audit log
+    audit: Arc<AuditLog>,
+
+    /// Metrics registry
+    metrics: Arc<Metrics>,
+
+    /// Per-client usage counters
+    accounting: Arc<Accounting>,
+
+    /// Foreground commands currently executing
+    in_flight: AtomicUsize,
+
+    /// Execution slots for foreground commands (fair, so waiting requests
+    /// run in arrival order)
+    slots: Semaphore,
+
+    /// Requests waiting for a slot
+    queued: AtomicUsize,
+
+    /// Recently executed commands, oldest first
+    history: Mutex<VecDeque<HistoryEntry>>,
+
+    /// asciicast recording of the session's I/O
+    recorder: Option<Arc<SessionRecorder>>,
+
+    /// Working directory
+    cwd: Mutex<Option<String>>,
+
+    /// Environment variables set for the session
+    env: Mutex<BTreeMap<String, String>>,
+
+    /// Where the session's state is persisted for resuming
+    store: Option<Arc<SessionStore>>,
+
+    /// Set once the client acknowledged the banner (or none is required)
+    banner_acked: AtomicBool,
+
+    /// Hooks called around every command
+    hooks: Vec<Arc<dyn Hook>>,
+
+    /// Uploaded programs to run once their transfer completes, by transfer ID
+    exec_uploads: Mutex<HashMap<u64, ExecUpload>>,
+
+    /// Complete output of truncated commands (None = not spooled)
+    spool: Option<OutputSpool>,
+
+    /// Private scratch directory (None = not configured)
+    session_dir: Option<Arc<SessionDir>>,
+
+    /// Commands waiting for an operator
+    approvals: Arc<ApprovalQueue>,
+
+    /// Commands must be approved by an operator before they run
+    require_approval: bool,
+
+    /// Secrets commands may ask for (None = no secrets file)
+    secrets: Option<Secrets>,
+
+    /// Paces file transfers and fetched output
+    bandwidth: BandwidthLimiter,
+
+    /// Stream command output as it is produced
+    stream_output: bool,
+
+    /// Last output of commands the client asked for deltas of
+    deltas: DeltaCache,
+
+    /// Commands that can be cancelled, by request ID
+    cancellable: Mutex<HashMap<u64, Arc<Interrupter>>>,
+
+    /// Stdin of commands that take it
+    inputs: Inputs,
+
+    /// Sequence numbers of signed packets accepted lately from the client
+    packet_sequences: Mutex<ReplayWindow>,
+
     /// Session state
     state: Arc<RwLock<SessionState>>,
 }
 
+/// A program being uploaded to run
+struct ExecUpload {
+    /// Request running it (command is the program's path)
+    request: CommandRequest,
+
+    /// Delete the program once it has run
+    delete_after: bool,
+}
+
 /// Session state
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum SessionState {
@@ -47,14 +206,263 @@ impl Session {
             "New session created"
         );
 
+        let config = Arc::new(ServerConfig::default());
+        let jobs = Self::job_manager(&config, session_id);
+        let transfers = Arc::new(TransferService::new(Arc::clone(&config)));
+        // The default configuration has no allowed paths
+        let files = FileService::default();
+        let dirs = FileService::default();
+        let forwards = ForwardService::new(ForwardPolicy::for_client(&config, &client_identity));
+
         Self {
             id: session_id,
             client_identity,
+            config,
             executor,
+            jobs,
+            transfers,
+            files,
+            dirs,
+            forwards,
+            pty: PtyExecutor::new(),
+            outbound: None,
+            encryption: None,
+            audit: Arc::new(AuditLog::disabled()),
+            metrics: Arc::new(Metrics::new()),
+            accounting: Arc::new(Accounting::new()),
+            in_flight: AtomicUsize::new(0),
+            slots: Semaphore::new(config.max_concurrent_commands),
+            queued: AtomicUsize::new(0),
+            history: Mutex::new(VecDeque::new()),
+            recorder: None,
+            cwd: Mutex::new(None),
+            env: Mutex::new(BTreeMap::new()),
+            store: None,
+            banner_acked: AtomicBool::new(true),
+            hooks: Vec::new(),
+            exec_uploads: Mutex::new(HashMap::new()),
+            spool: None,
+            session_dir: None,
+            approvals: Arc::new(ApprovalQueue::from_config(&config)),
+            require_approval: false,
+            secrets: None,
+            bandwidth: BandwidthLimiter::default(),
+            stream_output: false,
+            deltas: DeltaCache::default(),
+            cancellable: Mutex::new(HashMap::new()),
+            inputs: Inputs::default(),
+            packet_sequences: Mutex::default(),
             state: Arc::new(RwLock::new(SessionState::Active)),
         }
     }
 
+    /// Use a given session ID, e.g. of a resumed session
+    ///
+    /// Must come before [`Session::with_config`], which names jobs and
+    /// recordings after the ID.
+    pub fn with_id(mut self, id: SessionId) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Persist the session's state in `store`, restoring what it holds
+    /// for this session
+    pub fn with_store(mut self, store: Arc<SessionStore>) -> Self {
+        if let Some(record) = store.load(self.id) {
+            if record.cwd.is_some() {
+                *self.cwd.get_mut().unwrap() = record.cwd;
+            }
+            *self.env.get_mut().unwrap() = record.env;
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Apply the server configuration
+    pub fn with_config(mut self, config: Arc<ServerConfig>) -> Self {
+        if let Some(profile) = config.profile_for(&self.client_identity) {
+            if profile.sandbox.is_some()
+                || profile.seccomp.is_some()
+                || profile.cgroup.is_some()
+                || profile.shell.is_some()
+                || profile.container.is_some()
+                || !profile.env_allow.is_empty()
+                || profile.read_only.is_some()
+            {
+                info!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    profile = %profile.name,
+                    "Applying profile execution limits"
+                );
+
+                let mut executor = (*self.executor).clone();
+                if let Some(sandbox) = &profile.sandbox {
+                    executor = executor.with_sandbox(sandbox.clone());
+                }
+                if let Some(seccomp) = &profile.seccomp {
+                    executor = executor.with_seccomp(seccomp.clone());
+                }
+                if let Some(cgroup) = &profile.cgroup {
+                    executor = executor.with_cgroup(cgroup.clone());
+                }
+                if let Some(shell) = &profile.shell {
+                    executor = executor.with_shell(shell.clone());
+                }
+                if let Some(container) = &profile.container {
+                    executor = executor.with_container(container.clone());
+                }
+                if !profile.env_allow.is_empty() {
+                    executor = executor
+                        .with_env_policy(EnvPolicy::for_client(&config, &self.client_identity));
+                }
+                if let Some(read_only) = profile.read_only {
+                    executor = executor
+                        .with_read_only(read_only.then(|| config.read_only_commands.clone()));
+                }
+                self.executor = Arc::new(executor);
+            }
+        }
+
+        if config.session_recording {
+            match SessionRecorder::create(&config.recording_dir, self.id, &self.client_identity) {
+                Ok(recorder) => {
+                    info!(
+                        session_id = %Uuid::from_bytes(self.id),
+                        path = %recorder.path().display(),
+                        "Recording session"
+                    );
+                    self.recorder = Some(Arc::new(recorder));
+                }
+                Err(e) => warn!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    error = %e,
+                    "Failed to start session recording"
+                ),
+            }
+        }
+
+        self.session_dir = match SessionDir::from_config(&config, self.id) {
+            Some(Ok(dir)) => Some(Arc::new(dir)),
+            Some(Err(e)) => {
+                warn!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    error = %e,
+                    "Failed to create session directory"
+                );
+                None
+            }
+            None => None,
+        };
+        let mut fs_roots = config.fs_roots_for(&self.client_identity);
+        let mut transfers = TransferService::new(Arc::clone(&config));
+        if let Some(home) = self.host_session_dir() {
+            *self.cwd.get_mut().unwrap() = Some(home.display().to_string());
+        }
+        if let Some(dir) = &self.session_dir {
+            if !fs_roots.is_empty() {
+                fs_roots.push(dir.path().to_path_buf());
+            }
+            transfers = transfers.with_session_dir(Arc::clone(dir));
+        }
+
+        self.require_approval = config
+            .profile_for(&self.client_identity)
+            .is_some_and(|profile| profile.require_approval);
+        self.secrets = Secrets::for_client(&config, &self.client_identity);
+        self.banner_acked = AtomicBool::new(config.banner.is_none() || !config.banner_ack_required);
+        self.jobs = Self::job_manager(&config, self.id);
+        self.spool = OutputSpool::from_config(&config, self.id);
+        self.bandwidth =
+            BandwidthLimiter::for_session(&config).with_global(self.bandwidth.global());
+        self.slots = Semaphore::new(config.max_concurrent_commands);
+        self.transfers = Arc::new(transfers);
+        self.files = FileService::new(fs_roots).unwrap_or_else(|e| {
+            error!(
+                session_id = %Uuid::from_bytes(self.id),
+                error = %e,
+                "Denying filesystem operations"
+            );
+            FileService::deny_all()
+        });
+        // `cd` stays within the allowed paths, sandboxed or not
+        self.dirs = self.files.clone();
+        // Sandboxed commands see another filesystem than the host's
+        if self.executor.sandbox().is_some() {
+            self.files = FileService::deny_all();
+        }
+        self.forwards =
+            ForwardService::new(ForwardPolicy::for_client(&config, &self.client_identity));
+        self.config = config;
+        self
+    }
+
+    fn job_manager(config: &ServerConfig, session_id: SessionId) -> JobManager {
+        JobManager::new(
+            config.max_jobs,
+            config.job_buffer_size,
+            config.job_spool_dir.clone(),
+            &Uuid::from_bytes(session_id).to_string(),
+        )
+    }
+
+    /// Attach a channel for server-initiated messages
+    pub fn with_outbound(mut self, outbound: Outbound) -> Self {
+        self.outbound = Some(outbound);
+        self
+    }
+
+    /// Encrypt the session with `encryption`
+    pub fn with_encryption(mut self, encryption: Arc<Encryption>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Record audit events to `audit`
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Count commands and the session's lifetime in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Count the client's commands and transfers in `accounting`
+    pub fn with_accounting(mut self, accounting: Arc<Accounting>) -> Self {
+        self.accounting = accounting;
+        self
+    }
+
+    /// Share `bucket` with other sessions for bulk traffic
+    pub fn with_bandwidth(mut self, bucket: Option<Arc<TokenBucket>>) -> Self {
+        self.bandwidth = self.bandwidth.with_global(bucket);
+        self
+    }
+
+    /// Stream command output as COMMAND_OUTPUT messages while commands
+    /// run, for clients that announced the `output-stream` capability
+    ///
+    /// Needs an outbound channel; without one output arrives in the
+    /// response as usual.
+    pub fn with_output_streaming(mut self, enabled: bool) -> Self {
+        self.stream_output = enabled;
+        self
+    }
+
+    /// Park commands that need approval in `approvals`
+    pub fn with_approvals(mut self, approvals: Arc<ApprovalQueue>) -> Self {
+        self.approvals = approvals;
+        self
+    }
+
+    /// Call `hooks` before and after every command, in order
+    pub fn with_hooks(mut self, hooks: Vec<Arc<dyn Hook>>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
     /// Handle a message from the client
     pub async fn handle_message(&self, message: Message) -> Result<Option<Message>> {
         // Check session state
@@ -65,21 +473,43 @@ impl Session {
             }
         }
 
+        if !self.banner_acked.load(Ordering::SeqCst)
+            && !matches!(
+                message,
+                Message::BannerAck | Message::Ping | Message::Disconnect(_)
+            )
+        {
+            debug!(
+                session_id = %Uuid::from_bytes(self.id),
+                message_type = message.message_type(),
+                "Refusing request before banner acknowledgment"
+            );
+            return Ok(Some(Self::error_response(
+                0,
+                &ServerError::Denied("The server banner must be acknowledged first".to_string()),
+            )));
+        }
+
         match message {
-            Message::CommandRequest(req) => {
+            Message::CommandRequest(mut req) => {
                 debug!(
                     session_id = %Uuid::from_bytes(self.id),
-                    command_id = req.id,
+                    request_id = req.id,
+                    command = %req.command,
                     "Handling command request"
                 );
 
-                // Validate request
-                self.executor.validate_request(&req)?;
-
-                // Execute command
-                let response = self.executor.execute(req).await?;
+                self.open_input(&req);
+                req.working_dir = self.working_dir(req.working_dir.take());
+                req.env = self.with_env(req.env.take());
+                if req.command == "cd" {
+                    self.inputs.close(req.id);
+                    return Ok(Some(self.change_dir(&req).await));
+                }
 
-                Ok(Some(Message::CommandResponse(response)))
+                let validation = self.executor.validate_request(&req);
+                let reply = self.run_command(req, validation).await?;
+                Ok(self.after_output(reply))
             }
 
             Message::Disconnect(msg) => {
@@ -89,11 +519,45 @@ impl Session {
                     "Client disconnecting"
                 );
 
+                self.record(AuditEvent::Disconnect {
+                    reason: msg.reason.clone(),
+                });
+
+                // Ended on purpose, nothing to resume
+                if let Some(store) = &self.store {
+                    store.remove(self.id);
+                }
+
                 self.close().await?;
 
                 Ok(Some(Message::Ack(AckMessage { message_id: 0 })))
             }
 
+            Message::StatsRequest(req) => {
+                let usage = self.accounting.get(&self.client_identity);
+                Ok(Some(Message::StatsResponse(StatsResponse {
+                    id: req.id,
+                    sessions: usage.sessions,
+                    commands: usage.commands,
+                    cpu_time_ms: usage.cpu_time_ms,
+                    bytes_uploaded: usage.bytes_uploaded,
+                    bytes_downloaded: usage.bytes_downloaded,
+                    running: self.running() as u32,
+                    queued: self.queued() as u32,
+                })))
+            }
+
+            Message::BannerAck => {
+                info!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    "Banner acknowledged"
+                );
+                if !self.banner_acked.swap(true, Ordering::SeqCst) {
+                    self.record(AuditEvent::BannerAck);
+                }
+                Ok(Some(Message::Ack(AckMessage { message_id: 0 })))
+            }
+
             Message::Ping => {
                 debug!(
                     session_id = %Uuid::from_bytes(self.id),
@@ -102,6 +566,508 @@ impl Session {
                 Ok(Some(Message::Pong))
             }
 
+            Message::Echo(echo) => {
+                if echo.data.len() > MAX_CHUNK_SIZE {
+                    return Ok(Some(Self::error_response(
+                        echo.id,
+                        &ServerError::Execution(format!(
+                            "Echo payload larger than {} bytes",
+                            MAX_CHUNK_SIZE
+                        )),
+                    )));
+                }
+                Ok(Some(Message::EchoReply(echo)))
+            }
+
+            Message::JobStart(mut req) => {
+                req.working_dir = self.working_dir(req.working_dir.take());
+                req.env = self.with_env(req.env.take());
+                let id = req.id;
+                let command = req.command.clone();
+                let args = req.args.clone();
+                let cwd = req.working_dir.clone();
+
+                let mut result = self.executor.validate_request(&req);
+                if result.is_ok() {
+                    let event = HookEvent {
+                        client_identity: &self.client_identity,
+                        session_id: self.id,
+                        request: &req,
+                    };
+                    result = hooks::run_before(&self.hooks, event)
+                        .await
+                        .map_err(ServerError::Denied);
+                }
+                if result.is_ok() {
+                    result = self.await_approval(&req).await;
+                }
+                let secrets = req.secrets.clone();
+                let result = result
+                    .and_then(|()| self.inject_secrets(req))
+                    .and_then(|req| self.jobs.start(&self.executor, req));
+
+                self.record(AuditEvent::JobStart {
+                    job_id: result.as_ref().ok().map(|job| job.job_id),
+                    command,
+                    args,
+                    cwd,
+                    secrets,
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+
+                Ok(Some(match result {
+                    Ok(job) => Message::JobStatus(JobStatusMessage { request_id: id, job }),
+                    Err(e) => Self::error_response(id, &e),
+                }))
+            }
+
+            Message::JobList(req) => Ok(Some(Message::JobListResponse(JobListResponse {
+                id: req.id,
+                jobs: self.jobs.list(),
+            }))),
+
+            Message::HistoryRequest(req) => Ok(Some(Message::HistoryResponse(HistoryResponse {
+                id: req.id,
+                entries: self.history(req.limit.map(|limit| limit as usize)),
+            }))),
+
+            Message::SetEnv(req) => {
+                let mut names: Vec<String> = req.vars.keys().cloned().collect();
+                names.sort();
+
+                let result = self.set_env(req.vars);
+                self.record(AuditEvent::Env {
+                    set: names,
+                    unset: vec![],
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+
+                Ok(Some(match result {
+                    Ok(()) => Message::Ack(AckMessage { message_id: req.id }),
+                    Err(e) => Self::error_response(req.id, &e),
+                }))
+            }
+
+            Message::UnsetEnv(req) => {
+                {
+                    let mut env = self.env.lock().unwrap();
+                    for name in &req.names {
+                        env.remove(name);
+                    }
+                }
+                self.persist();
+
+                self.record(AuditEvent::Env {
+                    set: vec![],
+                    unset: req.names,
+                    error: None,
+                });
+                Ok(Some(Message::Ack(AckMessage { message_id: req.id })))
+            }
+
+            Message::JobOutputRequest(req) => {
+                let result = self.jobs.output(
+                    req.job_id,
+                    req.stdout_offset,
+                    req.stderr_offset,
+                    (req.max_bytes as usize).min(MAX_CHUNK_SIZE),
+                );
+
+                Ok(match result {
+                    Ok(chunk) => {
+                        let len = (chunk.stdout.len() + chunk.stderr.len()) as u64;
+                        let reply = Message::JobOutput(JobOutput {
+                            id: req.id,
+                            job_id: req.job_id,
+                            stdout_offset: chunk.stdout_offset,
+                            stdout: chunk.stdout,
+                            stderr_offset: chunk.stderr_offset,
+                            stderr: chunk.stderr,
+                            state: chunk.info.state,
+                            exit_code: chunk.info.exit_code,
+                        });
+                        self.throttle(reply, len).await
+                    }
+                    Err(e) => Some(Self::error_response(req.id, &e)),
+                })
+            }
+
+            Message::JobKill(req) => Ok(Some(match self.jobs.kill(req.job_id) {
+                Ok(job) => Message::JobStatus(JobStatusMessage {
+                    request_id: req.id,
+                    job,
+                }),
+                Err(e) => Self::error_response(req.id, &e),
+            })),
+
+            Message::UploadStart(req) => {
+                let id = req.id;
+                let path = req.path.clone();
+                let result = match self
+                    .check_writable("uploads")
+                    .and_then(|()| self.check_unsandboxed("Uploads"))
+                {
+                    Ok(()) => {
+                        self.transfer(move |transfers| transfers.start_upload(req))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok(Some(match result {
+                    Ok(ready) => {
+                        self.record(AuditEvent::Upload {
+                            transfer_id: ready.transfer_id,
+                            path,
+                            size: ready.size,
+                        });
+                        Message::TransferReady(ready)
+                    }
+                    Err(e) => {
+                        self.record(AuditEvent::TransferDenied {
+                            path,
+                            error: e.to_string(),
+                        });
+                        Self::error_response(id, &e)
+                    }
+                }))
+            }
+
+            Message::FileChunk(chunk) => {
+                let transfer_id = chunk.transfer_id;
+                let len = chunk.data.len() as u64;
+                let result = self
+                    .transfer(move |transfers| transfers.write_chunk(chunk))
+                    .await;
+                let reply = match result {
+                    Ok(reply) => {
+                        self.accounting.transferred(&self.client_identity, len, 0);
+                        if let Message::TransferComplete(complete) = &reply {
+                            self.record(AuditEvent::UploadComplete {
+                                transfer_id: complete.transfer_id,
+                                size: complete.size,
+                                sha256: hex::encode(&complete.sha256),
+                            });
+                            let upload = self.exec_uploads.lock().unwrap().remove(&transfer_id);
+                            if let Some(upload) = upload {
+                                let reply = self.run_exec_upload(upload).await;
+                                return Ok(self.after_output(reply));
+                            }
+                        }
+                        reply
+                    }
+                    Err(e) => return Ok(Some(Self::error_response(transfer_id, &e))),
+                };
+                // Holding back the acknowledgement paces the client
+                Ok(self.throttle(reply, len).await)
+            }
+
+            Message::FetchOutput(req) => {
+                let Some(spool) = &self.spool else {
+                    return Ok(Some(Self::error_response(
+                        req.id,
+                        &ServerError::Execution("Output spooling is disabled".to_string()),
+                    )));
+                };
+                let max = (req.length as usize).min(MAX_CHUNK_SIZE);
+                Ok(match spool.read(req.spool_id, req.stderr, req.offset, max) {
+                    Ok((data, total)) => {
+                        let len = data.len() as u64;
+                        let reply = Message::OutputChunk(OutputChunk {
+                            id: req.id,
+                            spool_id: req.spool_id,
+                            stderr: req.stderr,
+                            offset: req.offset,
+                            data,
+                            total,
+                        });
+                        self.throttle(reply, len).await
+                    }
+                    Err(e) => Some(Self::error_response(req.id, &e)),
+                })
+            }
+
+            Message::ExecUpload(req) => {
+                let id = req.id;
+                let name = req.name.clone();
+                let size = req.size;
+                let sha256 = hex::encode(&req.sha256);
+                let result = self.start_exec_upload(req).await;
+
+                self.record(AuditEvent::ExecUpload {
+                    transfer_id: result.as_ref().ok().map(|ready| ready.transfer_id),
+                    name,
+                    size,
+                    sha256,
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+
+                Ok(Some(match result {
+                    Ok(ready) => Message::TransferReady(ready),
+                    Err(e) => Self::error_response(id, &e),
+                }))
+            }
+
+            Message::DownloadStart(req) => {
+                let id = req.id;
+                let path = req.path.clone();
+                let result = match self.check_unsandboxed("Downloads") {
+                    Ok(()) => {
+                        self.transfer(move |transfers| transfers.start_download(req))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok(Some(match result {
+                    Ok(ready) => {
+                        self.record(AuditEvent::Download {
+                            transfer_id: ready.transfer_id,
+                            path,
+                            size: ready.size,
+                        });
+                        Message::TransferReady(ready)
+                    }
+                    Err(e) => {
+                        self.record(AuditEvent::TransferDenied {
+                            path,
+                            error: e.to_string(),
+                        });
+                        Self::error_response(id, &e)
+                    }
+                }))
+            }
+
+            Message::ChunkRequest(req) => {
+                let transfer_id = req.transfer_id;
+                let result = self
+                    .transfer(move |transfers| transfers.read_chunk(req))
+                    .await;
+                Ok(match result {
+                    Ok(chunk) => {
+                        let len = chunk.data.len() as u64;
+                        self.accounting.transferred(&self.client_identity, 0, len);
+                        self.throttle(Message::FileChunk(chunk), len).await
+                    }
+                    Err(e) => Some(Self::error_response(transfer_id, &e)),
+                })
+            }
+
+            Message::FileOp(req) => Ok(Some(match self.handle_file_op(req.op) {
+                Ok(entries) => Message::FileOpResult(FileOpResult {
+                    id: req.id,
+                    entries,
+                }),
+                Err(e) => Self::error_response(req.id, &e),
+            })),
+
+            Message::CompleteRequest(req) => Ok(Some(match self.complete(&req) {
+                Ok((candidates, truncated)) => Message::CompleteResponse(CompleteResponse {
+                    id: req.id,
+                    candidates,
+                    truncated,
+                }),
+                Err(e) => Self::error_response(req.id, &e),
+            })),
+
+            Message::PtyOpen(mut req) => {
+                req.working_dir = self.working_dir(req.working_dir.take());
+                req.env = self.with_env(req.env.take());
+                let id = req.id;
+                let outbound = self.outbound.clone().ok_or_else(|| {
+                    ServerError::Session("Session has no outbound channel".to_string())
+                })?;
+
+                // Interactive programs are subject to the same command policy;
+                // the default shell is checked as $SHELL
+                let probe = CommandRequest {
+                    id,
+                    command: req.command.clone().unwrap_or_else(|| {
+                        std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())
+                    }),
+                    args: req.args.clone(),
+                    env: req.env.clone(),
+                    timeout: None,
+                    working_dir: req.working_dir.clone(),
+                    secrets: vec![],
+                    stdin: false,
+                    delta_base: None,
+                };
+
+                let command = req.command.clone();
+                let args = req.args.clone();
+                let (cols, rows) = (req.cols, req.rows);
+
+                let result = if self.executor.is_confined() {
+                    Err(ServerError::Denied(
+                        "Interactive PTYs are not available to sandboxed clients".to_string(),
+                    ))
+                } else if self.executor.is_limited() {
+                    // The PTY's process would escape the limits
+                    Err(ServerError::Denied(
+                        "Interactive PTYs are not available to clients with resource limits"
+                            .to_string(),
+                    ))
+                } else if let Err(e) = self.check_writable("interactive PTYs") {
+                    Err(e)
+                } else if self.require_approval {
+                    Err(ServerError::Denied(
+                        "Interactive PTYs are not available to clients needing approval"
+                            .to_string(),
+                    ))
+                } else {
+                    self.executor
+                        .policy()
+                        .check(&probe)
+                        .and_then(|()| self.executor.env_policy().check(req.env.as_ref()))
+                };
+                let result = match result {
+                    Ok(()) => {
+                        let event = HookEvent {
+                            client_identity: &self.client_identity,
+                            session_id: self.id,
+                            request: &probe,
+                        };
+                        hooks::run_before(&self.hooks, event)
+                            .await
+                            .map_err(ServerError::Denied)
+                    }
+                    Err(e) => Err(e),
+                };
+                let result = result.and_then(|()| self.pty.open(req, self.recorded(id, outbound)));
+
+                if let (Ok(()), Some(recorder)) = (&result, &self.recorder) {
+                    recorder.resize(cols, rows);
+                    recorder.command(command.as_deref().unwrap_or(&probe.command), &args);
+                }
+
+                self.record(AuditEvent::PtyOpen {
+                    command,
+                    args,
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+
+                match result {
+                    Ok(()) => Ok(Some(Message::Ack(AckMessage { message_id: id }))),
+                    Err(e) => {
+                        warn!(
+                            session_id = %Uuid::from_bytes(self.id),
+                            pty_id = id,
+                            error = %e,
+                            "Failed to open PTY"
+                        );
+                        Ok(Some(Message::PtyClose(PtyClose {
+                            id,
+                            exit_code: None,
+                            reason: Some(e.to_string()),
+                        })))
+                    }
+                }
+            }
+
+            Message::PtyData(data) => {
+                self.pty.write(data.id, &data.data)?;
+                if let Some(recorder) = &self.recorder {
+                    recorder.input(&data.data);
+                }
+                Ok(None)
+            }
+
+            Message::PtyResize(resize) => {
+                self.pty.resize(resize.id, resize.cols, resize.rows)?;
+                if let Some(recorder) = &self.recorder {
+                    recorder.resize(resize.cols, resize.rows);
+                }
+                Ok(None)
+            }
+
+            Message::PtyClose(close) => {
+                self.pty.close(close.id);
+                Ok(None)
+            }
+
+            Message::ChannelOpen(req) => {
+                let channel_id = req.channel_id;
+                let outbound = self.outbound.clone().ok_or_else(|| {
+                    ServerError::Session("Session has no outbound channel".to_string())
+                })?;
+
+                let result = match req.kind {
+                    _ if self.executor.is_read_only() => self.check_writable("forwarding"),
+                    _ if self.executor.sandbox().is_some() => self.check_unsandboxed("Forwards"),
+                    ChannelKind::Direct { host, port } => {
+                        self.forwards
+                            .open_direct(channel_id, &host, port, outbound)
+                            .await
+                    }
+                    ChannelKind::Dynamic => self.forwards.open_dynamic(channel_id, outbound),
+                    ChannelKind::Forwarded { .. } => Err(ServerError::Execution(
+                        "Forwarded channels are opened by the server".to_string(),
+                    )),
+                };
+
+                Ok(Some(match result {
+                    Ok(()) => Message::Ack(AckMessage {
+                        message_id: channel_id,
+                    }),
+                    Err(e) => {
+                        warn!(
+                            session_id = %Uuid::from_bytes(self.id),
+                            channel_id = channel_id,
+                            error = %e,
+                            "Failed to open channel"
+                        );
+                        Message::ChannelClose(ChannelClose {
+                            channel_id,
+                            reason: Some(e.to_string()),
+                        })
+                    }
+                }))
+            }
+
+            Message::ChannelData(data) => {
+                self.forwards.send(data.channel_id, data.data)?;
+                Ok(None)
+            }
+
+            Message::ChannelClose(close) => {
+                self.forwards.close(close.channel_id);
+                Ok(None)
+            }
+
+            Message::RemoteForward(req) => {
+                let outbound = self.outbound.clone().ok_or_else(|| {
+                    ServerError::Session("Session has no outbound channel".to_string())
+                })?;
+
+                let result = match self
+                    .check_writable("forwarding")
+                    .and_then(|()| self.check_unsandboxed("Forwards"))
+                {
+                    Ok(()) => self.forwards.start_remote(req.bind_port, outbound).await,
+                    Err(e) => Err(e),
+                };
+                Ok(Some(match result {
+                    Ok(()) => Message::Ack(AckMessage { message_id: req.id }),
+                    Err(e) => Self::error_response(req.id, &e),
+                }))
+            }
+
+            Message::CancelRemoteForward(req) => {
+                Ok(Some(match self.forwards.stop_remote(req.bind_port) {
+                    Ok(()) => Message::Ack(AckMessage { message_id: req.id }),
+                    Err(e) => Self::error_response(req.id, &e),
+                }))
+            }
+
+            Message::CancelRequest(req) => {
+                self.cancel(&req);
+                Ok(None)
+            }
+
+            Message::CommandInput(input) => {
+                self.take_input(input);
+                Ok(None)
+            }
+
             _ => {
                 debug!(
                     session_id = %Uuid::from_bytes(self.id),
@@ -115,8 +1081,36 @@ impl Session {
     /// Close the session
     pub async fn close(&self) -> Result<()> {
         let mut state = self.state.write().await;
+        if *state != SessionState::Closed {
+            self.metrics.session_closed();
+        }
         *state = SessionState::Closed;
 
+        self.pty.close_all();
+        self.inputs.clear();
+        self.jobs.shutdown();
+        // Requests still waiting for a slot are refused
+        self.slots.close();
+        self.transfers.cancel_all();
+        self.exec_uploads.lock().unwrap().clear();
+        if let Some(spool) = &self.spool {
+            spool.clear();
+        }
+        if let Some(dir) = &self.session_dir {
+            dir.remove();
+        }
+        if let Err(e) = fs::remove_dir_all(self.exec_dir()) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    error = %e,
+                    "Failed to remove uploaded programs"
+                );
+            }
+        }
+        self.forwards.shutdown();
+        self.accounting.save();
+
         info!(
             session_id = %Uuid::from_bytes(self.id),
             "Session closed"
@@ -125,52 +1119,2240 @@ impl Session {
         Ok(())
     }
 
-    /// Check if session is active
-    pub async fn is_active(&self) -> bool {
-        let state = self.state.read().await;
-        *state == SessionState::Active
+    /// Run a command request whose working directory and environment are
+    /// resolved, recording it in history, audit log and metrics
+    async fn run_command(&self, req: CommandRequest, validation: Result<()>) -> Result<Message> {
+        let command = req.command.clone();
+        let args = req.args.clone();
+        let cwd = req.working_dir.clone();
+        let secrets = req.secrets.clone();
+        let started_at = unix_time();
+
+        // Output to diff is collected, not streamed
+        let delta = req.delta_base.map(|base| (delta::command_key(&req), base));
+        let stream = self.output_stream().filter(|_| delta.is_none());
+
+        if let Some(recorder) = &self.recorder {
+            recorder.command(&command, &args);
+        }
+
+        // Cancellable from here on; one cancelled before it starts never does
+        let id = req.id;
+        let interrupter = Arc::new(Interrupter::new());
+        self.cancellable.lock().unwrap().insert(id, Arc::clone(&interrupter));
+
+        // Ask the hooks, wait for a slot and execute
+        let hook_request = req.clone();
+        let event = HookEvent {
+            client_identity: &self.client_identity,
+            session_id: self.id,
+            request: &hook_request,
+        };
+        let mut vetoed = false;
+        let result = match validation {
+            Ok(()) => hooks::run_before(&self.hooks, event).await.map_err(|reason| {
+                vetoed = true;
+                ServerError::Denied(reason)
+            }),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(()) => self.await_approval(&hook_request).await.map_err(|e| {
+                vetoed = true;
+                e
+            }),
+            Err(e) => Err(e),
+        };
+        let result = match result.and_then(|()| self.inject_secrets(req)) {
+            Ok(req) => {
+                let _slot = match self.acquire_slot(id).await {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        self.cancellable.lock().unwrap().remove(&id);
+                        self.inputs.close(id);
+                        return Ok(Self::error_response(id, &e));
+                    }
+                };
+                self.in_flight.fetch_add(1, Ordering::SeqCst);
+                // Streamed output has reached the client in full, so there
+                // is nothing to spool
+                let sink = stream.map(|outbound| {
+                    move |stderr: bool, data: &[u8]| {
+                        if !data.is_empty() {
+                            let _ = outbound.send(Message::CommandOutput(CommandOutput {
+                                id,
+                                stderr,
+                                data: data.to_vec(),
+                            }));
+                        }
+                    }
+                });
+                let spool = if sink.is_some() { None } else { self.spool.as_ref() };
+                let result = self
+                    .executor
+                    .execute_streamed(
+                        req,
+                        spool,
+                        sink.as_ref().map(|sink| sink as &OutputSink),
+                        Some(&interrupter),
+                        self.inputs.take(id),
+                    )
+                    .await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                result.map(|(response, cpu_time)| {
+                    self.accounting.command(&self.client_identity, cpu_time);
+                    response
+                })
+            }
+            Err(e) => Err(e),
+        };
+        self.cancellable.lock().unwrap().remove(&id);
+        self.inputs.close(id);
+
+        if let Ok(response) = &result {
+            hooks::run_after(&self.hooks, event, response).await;
+        }
+
+        if let Some(dir) = &self.session_dir {
+            if let Err(e) = dir.check_quota(0) {
+                warn!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    error = %e,
+                    "Session directory is over its quota"
+                );
+            }
+        }
+
+        match &result {
+            Ok(response) => self.metrics.command_finished(
+                Some(response.status),
+                Duration::from_millis(response.execution_time_ms),
+            ),
+            Err(_) => self.metrics.command_finished(None, Duration::ZERO),
+        }
+
+        if let Some(recorder) = &self.recorder {
+            match &result {
+                Ok(response) => {
+                    recorder.command_output(&response.stdout);
+                    recorder.command_output(&response.stderr);
+                }
+                Err(e) => recorder.command_output(format!("{}\n", e).as_bytes()),
+            }
+        }
+
+        self.remember(HistoryEntry {
+            command: command.clone(),
+            args: args.clone(),
+            cwd: cwd.clone(),
+            started_at,
+            duration_ms: result.as_ref().map_or(0, |response| response.execution_time_ms),
+            status: result.as_ref().ok().map(|response| response.status),
+            exit_code: result.as_ref().ok().map(|response| response.exit_code),
+        });
+
+        self.record(match &result {
+            _ if builtins::is_builtin(&command) => AuditEvent::Builtin {
+                command,
+                args,
+                status: result.as_ref().ok().map(|response| response.status),
+                exit_code: result.as_ref().ok().map(|response| response.exit_code),
+                duration_ms: result
+                    .as_ref()
+                    .map_or(0, |response| response.execution_time_ms),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            },
+            Ok(response) => AuditEvent::Command {
+                command,
+                args,
+                cwd,
+                secrets,
+                status: Some(response.status),
+                exit_code: Some(response.exit_code),
+                duration_ms: response.execution_time_ms,
+                stdout_bytes: response.stdout.len() as u64,
+                stderr_bytes: response.stderr.len() as u64,
+                error: None,
+            },
+            Err(e) => AuditEvent::Command {
+                command,
+                args,
+                cwd,
+                secrets,
+                status: None,
+                exit_code: None,
+                duration_ms: 0,
+                stdout_bytes: 0,
+                stderr_bytes: 0,
+                error: Some(e.to_string()),
+            },
+        });
+
+        match result {
+            // Tell the client why, like other refusals of a request
+            Err(e) if vetoed => Ok(Self::error_response(id, &e)),
+            // The client has the output already
+            Ok(mut response) if stream.is_some() => {
+                response.stdout.clear();
+                response.stderr.clear();
+                response.truncated = false;
+                Ok(Message::CommandResponse(response))
+            }
+            Ok(mut response) => {
+                if let Some((key, base)) = delta {
+                    self.deltas.encode(key, base, &mut response);
+                }
+                Ok(Message::CommandResponse(response))
+            }
+            Err(e) => Err(e),
+        }
     }
 
-    /// Get session ID as UUID string
-    pub fn id_string(&self) -> String {
-        Uuid::from_bytes(self.id).to_string()
+    /// Accept stdin for the command `req` runs, if it takes any
+    ///
+    /// The server calls this before handing the request off, so input sent
+    /// right behind it is not lost; handling the request calls it too.
+    pub fn open_input(&self, req: &CommandRequest) {
+        // A request refused for want of the banner acknowledgment never runs
+        if req.stdin && self.banner_acked.load(Ordering::SeqCst) {
+            self.inputs.open(req.id);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use shell_proto::CommandRequest;
+    /// Pass input on to the running command it is for
+    ///
+    /// Input for commands that ended or take none is dropped.
+    fn take_input(&self, input: CommandInput) {
+        let id = input.id;
+        if !self.inputs.push(input) {
+            debug!(
+                session_id = %Uuid::from_bytes(self.id),
+                request_id = id,
+                "No command reading input"
+            );
+        }
+    }
 
-    #[tokio::test]
-    async fn test_session_creation() {
-        let client_identity = vec![1, 2, 3, 4];
-        let executor = Arc::new(CommandExecutor::new(30));
-        let session = Session::new(client_identity.clone(), executor);
+    /// Stop the running command a cancel request names
+    ///
+    /// Unknown IDs are ignored: the command may have finished while the
+    /// request was on its way.
+    fn cancel(&self, req: &CancelRequest) {
+        let Some(interrupter) = self.cancellable.lock().unwrap().get(&req.id).cloned() else {
+            debug!(
+                session_id = %Uuid::from_bytes(self.id),
+                request_id = req.id,
+                "No running command to cancel"
+            );
+            return;
+        };
 
-        assert_eq!(session.client_identity, client_identity);
-        assert!(session.is_active().await);
+        info!(
+            session_id = %Uuid::from_bytes(self.id),
+            request_id = req.id,
+            force = req.force,
+            "Cancelling command"
+        );
+        if req.force {
+            interrupter.kill();
+        } else {
+            interrupter.interrupt();
+        }
     }
 
-    #[tokio::test]
-    async fn test_session_close() {
-        let executor = Arc::new(CommandExecutor::new(30));
-        let session = Session::new(vec![1, 2, 3], executor);
+    /// The channel command output is streamed through, if streaming
+    fn output_stream(&self) -> Option<&Outbound> {
+        self.outbound.as_ref().filter(|_| self.stream_output)
+    }
 
-        assert!(session.is_active().await);
+    /// Send the reply to a command behind its streamed output
+    ///
+    /// Output travels through the outbound channel; a reply returned to the
+    /// message loop could overtake it.
+    fn after_output(&self, reply: Message) -> Option<Message> {
+        match self.output_stream() {
+            Some(outbound) => {
+                let _ = outbound.send(reply);
+                None
+            }
+            None => Some(reply),
+        }
+    }
 
-        session.close().await.unwrap();
+    /// Private directory uploaded programs of this session are run from
+    fn exec_dir(&self) -> PathBuf {
+        std::env::temp_dir().join(format!("reticulum-shell-exec-{}", Uuid::from_bytes(self.id)))
+    }
 
-        assert!(!session.is_active().await);
+    /// Wait for an operator to approve `request`, if this client needs that
+    ///
+    /// The client is told with a PENDING notice while the command waits.
+    async fn await_approval(&self, request: &CommandRequest) -> Result<()> {
+        if !self.require_approval {
+            return Ok(());
+        }
+
+        let timeout = self.approvals.timeout().as_secs();
+        self.approvals
+            .wait(self.id, &self.client_identity, request, |approval_id| {
+                if let Some(outbound) = &self.outbound {
+                    let _ = outbound.send(Message::Pending(PendingNotice {
+                        id: request.id,
+                        approval_id,
+                        timeout,
+                    }));
+                }
+            })
+            .await
     }
 
-    #[tokio::test]
-    async fn test_handle_ping() {
-        let executor = Arc::new(CommandExecutor::new(30));
-        let session = Session::new(vec![1, 2, 3], executor);
+    /// Send bulk traffic once the bandwidth limits allow
+    ///
+    /// With an outbound channel the reply is sent from a task of its own,
+    /// so the message loop isn't held up meanwhile; otherwise this waits.
+    async fn throttle(&self, reply: Message, bytes: u64) -> Option<Message> {
+        let delay = self.bandwidth.reserve(bytes);
+        if delay.is_zero() {
+            return Some(reply);
+        }
 
-        let response = session.handle_message(Message::Ping).await.unwrap();
+        match &self.outbound {
+            Some(outbound) => {
+                let outbound = outbound.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = outbound.send(reply);
+                });
+                None
+            }
+            None => {
+                tokio::time::sleep(delay).await;
+                Some(reply)
+            }
+        }
+    }
+
+    /// Run a transfer step off the async runtime, as it hashes and reads
+    /// or writes files
+    async fn transfer<T: Send + 'static>(
+        &self,
+        step: impl FnOnce(&TransferService) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let transfers = Arc::clone(&self.transfers);
+        tokio::task::spawn_blocking(move || step(&transfers))
+            .await
+            .map_err(|e| ServerError::Execution(format!("Transfer failed: {}", e)))?
+    }
+
+    /// Add the secrets `req` asks for to its environment
+    ///
+    /// Called last before execution, so nothing that records the request
+    /// sees the values.
+    fn inject_secrets(&self, mut req: CommandRequest) -> Result<CommandRequest> {
+        if req.secrets.is_empty() {
+            return Ok(req);
+        }
+
+        let secrets = self.secrets.as_ref().ok_or_else(|| {
+            ServerError::Denied("No secrets are available to this client".to_string())
+        })?;
+        let values = secrets.resolve(&req.secrets)?;
+        req.env.get_or_insert_with(BTreeMap::new).extend(values);
+        Ok(req)
+    }
+
+    /// Refuse `what` in read-only mode
+    fn check_writable(&self, what: &str) -> Result<()> {
+        if self.executor.is_read_only() {
+            return Err(ServerError::Denied(format!(
+                "Read-only mode: {} are not permitted",
+                what
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse `what` to sandboxed clients, as it would reach past the sandbox
+    fn check_unsandboxed(&self, what: &str) -> Result<()> {
+        if self.executor.sandbox().is_some() {
+            return Err(ServerError::Denied(format!(
+                "{} are not available to sandboxed clients",
+                what
+            )));
+        }
+        Ok(())
+    }
+
+    /// Perform a filesystem operation; only inspecting ones in read-only mode
+    fn handle_file_op(&self, op: FileOp) -> Result<Vec<FileEntry>> {
+        self.check_unsandboxed("Filesystem operations")?;
+        if !matches!(op, FileOp::Stat { .. } | FileOp::List { .. }) {
+            self.check_writable("filesystem changes")?;
+        }
+        let cwd = PathBuf::from(self.cwd().unwrap_or_else(|| ".".to_string()));
+        self.files.handle(op, &cwd)
+    }
+
+    /// Complete a partial path or command name
+    fn complete(&self, req: &CompleteRequest) -> Result<(Vec<String>, bool)> {
+        // Names with a slash are paths to programs
+        if req.kind == CompletionKind::Command && !req.prefix.contains('/') {
+            return Ok(completion::complete_command(
+                self.executor.policy(),
+                self.executor.builtins_available(),
+                &req.prefix,
+            ));
+        }
+
+        let cwd = PathBuf::from(self.cwd().unwrap_or_else(|| ".".to_string()));
+        completion::complete_path(&self.files, &cwd, &req.prefix)
+    }
+
+    /// Check an EXEC_UPLOAD and start receiving the program
+    async fn start_exec_upload(&self, req: ExecUploadRequest) -> Result<TransferReady> {
+        self.check_writable("uploads")?;
+        let permitted = self
+            .config
+            .profile_for(&self.client_identity)
+            .is_some_and(|profile| profile.exec_upload);
+        if !permitted {
+            return Err(ServerError::Denied("Uploading programs is not permitted".to_string()));
+        }
+        // The program would land outside the sandbox root or container
+        if self.executor.is_confined() {
+            return Err(ServerError::Denied(
+                "Uploading programs is not available to confined clients".to_string(),
+            ));
+        }
+
+        let mut components = Path::new(&req.name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(ServerError::Execution(format!(
+                "Invalid program name: {}",
+                req.name
+            )));
+        }
+
+        let dir = self.exec_dir();
+        match fs::create_dir(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+        if !fs::symlink_metadata(&dir)?.is_dir() {
+            return Err(ServerError::Execution(format!(
+                "Not a directory: {}",
+                dir.display()
+            )));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        let path = dir.join(&req.name);
+        let upload = UploadRequest {
+            id: req.id,
+            path: String::new(),
+            size: req.size,
+            sha256: req.sha256,
+            mode: Some(0o700),
+            resume: false,
+        };
+        let target = path.clone();
+        let ready = self
+            .transfer(move |transfers| transfers.start_upload_at(target, upload))
+            .await?;
+
+        let request = CommandRequest {
+            id: req.id,
+            command: path.display().to_string(),
+            args: req.args,
+            env: self.with_env(req.env),
+            timeout: req.timeout,
+            working_dir: self.working_dir(None),
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        self.exec_uploads.lock().unwrap().insert(
+            ready.transfer_id,
+            ExecUpload {
+                request,
+                delete_after: req.delete_after,
+            },
+        );
+        Ok(ready)
+    }
+
+    /// Run a completely uploaded program
+    async fn run_exec_upload(&self, upload: ExecUpload) -> Message {
+        let id = upload.request.id;
+        let path = PathBuf::from(&upload.request.command);
+
+        // The profile permission replaces the command allow/deny lists
+        let result = self.run_command(upload.request, Ok(())).await;
+
+        if upload.delete_after {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!(path = %path.display(), error = %e, "Failed to delete uploaded program");
+            }
+        }
+
+        result.unwrap_or_else(|e| Self::error_response(id, &e))
+    }
+
+    /// Tell the client the server is going away
+    ///
+    /// The session keeps serving requests already in progress until
+    /// [`Session::close`].
+    pub fn notify_disconnect(&self, reason: &str) {
+        self.record(AuditEvent::Disconnect {
+            reason: Some(reason.to_string()),
+        });
+
+        if let Some(outbound) = &self.outbound {
+            let _ = outbound.send(Message::Disconnect(DisconnectMessage {
+                reason: Some(reason.to_string()),
+            }));
+        }
+    }
+
+    /// Get the working directory
+    pub fn cwd(&self) -> Option<String> {
+        self.cwd.lock().unwrap().clone()
+    }
+
+    /// Set session variables, all or none
+    fn set_env(&self, vars: BTreeMap<String, String>) -> Result<()> {
+        for name in vars.keys() {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(ServerError::Execution(format!(
+                    "Invalid variable name: {:?}",
+                    name
+                )));
+            }
+            if self
+                .config
+                .session_env_deny
+                .iter()
+                .any(|pattern| glob_match(pattern, name))
+                || !self.executor.env_policy().permits(name)
+            {
+                return Err(ServerError::Denied(format!(
+                    "Variable may not be set: {}",
+                    name
+                )));
+            }
+        }
+        if vars.values().any(|value| value.contains('\0')) {
+            return Err(ServerError::Execution(
+                "Variable values may not contain NUL".to_string(),
+            ));
+        }
+
+        self.env.lock().unwrap().extend(vars);
+        self.persist();
+        Ok(())
+    }
+
+    /// Environment for a request: the session's, overridden by the
+    /// request's own variables
+    fn with_env(
+        &self,
+        requested: Option<BTreeMap<String, String>>,
+    ) -> Option<BTreeMap<String, String>> {
+        let mut env = self.env();
+        if env.is_empty() {
+            return requested;
+        }
+
+        env.extend(requested.unwrap_or_default());
+        Some(env)
+    }
+
+    /// Working directory for a request: the session's, unless the request
+    /// names one (relative ones are taken from the session's)
+    fn working_dir(&self, requested: Option<String>) -> Option<String> {
+        match (requested, self.cwd()) {
+            (Some(dir), Some(cwd)) if Path::new(&dir).is_relative() => {
+                Some(Path::new(&cwd).join(dir).display().to_string())
+            }
+            (Some(dir), _) => Some(dir),
+            (None, cwd) => cwd,
+        }
+    }
+
+    /// Handle `cd [dir]`
+    ///
+    /// The target must be a directory within the client's allowed paths.
+    /// Without an argument (or with `~`) it goes to the session directory,
+    /// else the first allowed path, or the server's home directory if the
+    /// client is unrestricted. Like any command it is put to the hooks and
+    /// recorded in the audit log.
+    async fn change_dir(&self, request: &CommandRequest) -> Message {
+        let start = Instant::now();
+        let previous = self.cwd();
+        let event = HookEvent {
+            client_identity: &self.client_identity,
+            session_id: self.id,
+            request,
+        };
+        if let Err(reason) = hooks::run_before(&self.hooks, event).await {
+            let error = ServerError::Denied(reason);
+            self.record(AuditEvent::Command {
+                command: request.command.clone(),
+                args: request.args.clone(),
+                cwd: previous,
+                secrets: vec![],
+                status: None,
+                exit_code: None,
+                duration_ms: 0,
+                stdout_bytes: 0,
+                stderr_bytes: 0,
+                error: Some(error.to_string()),
+            });
+            return Self::error_response(request.id, &error);
+        }
+
+        let target = match request.args.as_slice() {
+            [] => Ok(self.home_dir()),
+            [dir] if dir == "~" => Ok(self.home_dir()),
+            [dir] => Ok(match &previous {
+                Some(cwd) => Path::new(cwd).join(dir),
+                None => PathBuf::from(dir),
+            }),
+            _ => Err("cd: too many arguments".to_string()),
+        };
+        let result = target.and_then(|target| {
+            self.dirs
+                .resolve_dir(&target)
+                .map_err(|e| format!("cd: {}: {}", target.display(), e))
+        });
+
+        let (status, exit_code, stderr) = match result {
+            Ok(dir) => {
+                debug!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    cwd = %dir.display(),
+                    "Changed working directory"
+                );
+                *self.cwd.lock().unwrap() = Some(dir.display().to_string());
+                self.persist();
+                (CommandStatus::Success, 0, String::new())
+            }
+            Err(e) => (CommandStatus::Error, 1, format!("{}\n", e)),
+        };
+
+        let execution_time_ms = start.elapsed().as_millis() as u64;
+        self.remember(HistoryEntry {
+            command: request.command.clone(),
+            args: request.args.clone(),
+            cwd: previous.clone(),
+            started_at: unix_time(),
+            duration_ms: execution_time_ms,
+            status: Some(status),
+            exit_code: Some(exit_code),
+        });
+
+        let response = CommandResponse {
+            id: request.id,
+            status,
+            stdout: vec![],
+            total_bytes: stderr.len() as u64,
+            spool_id: None,
+            stderr: stderr.into_bytes(),
+            exit_code,
+            execution_time_ms,
+            truncated: false,
+            delta_base: None,
+        };
+        hooks::run_after(&self.hooks, event, &response).await;
+        self.record(AuditEvent::Command {
+            command: request.command.clone(),
+            args: request.args.clone(),
+            cwd: previous,
+            secrets: vec![],
+            status: Some(status),
+            exit_code: Some(exit_code),
+            duration_ms: execution_time_ms,
+            stdout_bytes: 0,
+            stderr_bytes: response.stderr.len() as u64,
+            error: None,
+        });
+        Message::CommandResponse(response)
+    }
+
+    fn home_dir(&self) -> PathBuf {
+        if let Some(dir) = self.host_session_dir() {
+            return dir;
+        }
+        self.config
+            .fs_roots_for(&self.client_identity)
+            .into_iter()
+            .next()
+            .or_else(|| std::env::var_os("HOME").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("/"))
+    }
+
+    /// The session directory, if commands run where they can use it
+    fn host_session_dir(&self) -> Option<PathBuf> {
+        // Sandboxed and containerized commands can't see the host's filesystem
+        if self.executor.sandbox().is_some() || self.executor.container().is_some() {
+            return None;
+        }
+        self.session_dir.as_ref().map(|dir| dir.path().to_path_buf())
+    }
+
+    /// Save the working directory and environment for resuming
+    fn persist(&self) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.update(self.id, self.cwd(), self.env()) {
+                warn!(
+                    session_id = %Uuid::from_bytes(self.id),
+                    error = %e,
+                    "Failed to persist session state"
+                );
+            }
+        }
+    }
+
+    /// Get the session environment
+    pub fn env(&self) -> BTreeMap<String, String> {
+        self.env.lock().unwrap().clone()
+    }
+
+    /// Wait for a free execution slot, refusing the request when the queue
+    /// is full
+    async fn acquire_slot(&self, request_id: u64) -> Result<SemaphorePermit<'_>> {
+        if let Ok(slot) = self.slots.try_acquire() {
+            return Ok(slot);
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.config.command_queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                session_id = %Uuid::from_bytes(self.id),
+                request_id,
+                "Command queue full, refusing request"
+            );
+            return Err(ServerError::Overloaded(format!(
+                "{} commands already queued",
+                self.config.command_queue_size
+            )));
+        }
+
+        debug!(
+            session_id = %Uuid::from_bytes(self.id),
+            request_id,
+            "Queueing command"
+        );
+        let slot = self.slots.acquire().await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        slot.map_err(|_| ServerError::Session("Session is closing".to_string()))
+    }
+
+    /// Get the number of foreground commands executing
+    pub fn running(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of commands waiting for an execution slot
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Noise encryption of the session, if any
+    pub fn encryption(&self) -> Option<&Arc<Encryption>> {
+        self.encryption.as_ref()
+    }
+
+    /// Record `sequence` of a signed packet from the client; false if it
+    /// was accepted before or is too far behind the highest to tell, i.e.
+    /// a possible replay
+    pub fn accept_sequence(&self, sequence: u64) -> bool {
+        self.packet_sequences.lock().unwrap().accept(sequence)
+    }
+
+    /// Check whether commands or background jobs are still running
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) > 0 || self.jobs.running() > 0
+    }
+
+    /// Check if session is active
+    pub async fn is_active(&self) -> bool {
+        let state = self.state.read().await;
+        *state == SessionState::Active
+    }
+
+    /// Route PTY output through the recorder (if recording) on its way to
+    /// the client
+    fn recorded(&self, pty_id: u64, outbound: Outbound) -> Outbound {
+        let Some(recorder) = self.recorder.clone() else {
+            return outbound;
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Message::PtyData(data) = &message {
+                    if data.id == pty_id {
+                        recorder.output(&data.data);
+                    }
+                }
+                if outbound.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        tx
+    }
+
+    /// Get the most recent `limit` (default: all) history entries, oldest
+    /// first
+    pub fn history(&self, limit: Option<usize>) -> Vec<HistoryEntry> {
+        let history = self.history.lock().unwrap();
+        let skip = limit.map_or(0, |limit| history.len().saturating_sub(limit));
+        history.iter().skip(skip).cloned().collect()
+    }
+
+    /// Append to the command history, dropping the oldest entries
+    fn remember(&self, entry: HistoryEntry) {
+        let capacity = self.config.history_size;
+        if capacity == 0 {
+            return;
+        }
+
+        let mut history = self.history.lock().unwrap();
+        while history.len() >= capacity {
+            history.pop_front();
+        }
+        history.push_back(entry);
+    }
+
+    /// Record an audit event for this session
+    fn record(&self, event: AuditEvent) {
+        self.audit.record(&self.client_identity, Some(&self.id), event);
+    }
+
+    /// Build an error reply for a failed request
+    fn error_response(request_id: u64, err: &ServerError) -> Message {
+        Message::Error(ErrorMessage::new(request_id, err.error_code(), err.to_string()))
+    }
+
+    /// Get session ID as UUID string
+    pub fn id_string(&self) -> String {
+        Uuid::from_bytes(self.id).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_session_creation() {
+        let client_identity = vec![1, 2, 3, 4];
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(client_identity.clone(), executor);
+
+        assert_eq!(session.client_identity, client_identity);
+        assert!(session.is_active().await);
+    }
+
+    #[tokio::test]
+    async fn test_session_close() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        assert!(session.is_active().await);
+
+        session.close().await.unwrap();
+
+        assert!(!session.is_active().await);
+    }
+
+    #[tokio::test]
+    async fn test_handle_ping() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let response = session.handle_message(Message::Ping).await.unwrap();
 
         assert!(matches!(response, Some(Message::Pong)));
     }
+
+    #[tokio::test]
+    async fn test_handle_echo() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let echo = shell_proto::EchoMessage {
+            id: 7,
+            data: vec![0xAB; 1000],
+        };
+        let response = session
+            .handle_message(Message::Echo(echo.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::EchoReply(reply)) if reply == echo));
+
+        let oversized = shell_proto::EchoMessage {
+            id: 8,
+            data: vec![0; MAX_CHUNK_SIZE + 1],
+        };
+        let response = session
+            .handle_message(Message::Echo(oversized))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::Error(error)) if error.request_id == 8));
+    }
+
+    #[tokio::test]
+    async fn test_pty_open_requires_outbound() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let request = shell_proto::PtyOpenRequest {
+            id: 1,
+            command: Some("true".to_string()),
+            args: vec![],
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        };
+
+        assert!(session.handle_message(Message::PtyOpen(request)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sandboxed_profile_refuses_pty() {
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "jailed".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            sandbox: Some(crate::sandbox::SandboxConfig {
+                pid: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_config(Arc::new(config))
+            .with_outbound(tx);
+
+        let request = shell_proto::PtyOpenRequest {
+            id: 1,
+            command: Some("true".to_string()),
+            args: vec![],
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        };
+
+        let response = session.handle_message(Message::PtyOpen(request)).await.unwrap();
+        assert!(matches!(response, Some(Message::PtyClose(close)) if close.reason.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_sandboxed_profile_refuses_host_access() {
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "jailed".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            sandbox: Some(crate::sandbox::SandboxConfig::default()),
+            ..Default::default()
+        });
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_config(Arc::new(config))
+            .with_outbound(tx);
+
+        let stat = Message::FileOp(shell_proto::FileOpRequest {
+            id: 1,
+            op: FileOp::Stat {
+                path: "/etc/hostname".to_string(),
+            },
+        });
+        let download = Message::DownloadStart(shell_proto::DownloadRequest {
+            id: 2,
+            path: "/etc/hostname".to_string(),
+        });
+        for request in [stat, download] {
+            match session.handle_message(request).await {
+                Ok(Some(Message::Error(error))) => {
+                    assert_eq!(error.code, ErrorMessage::DENIED);
+                }
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+
+        let open = Message::ChannelOpen(shell_proto::ChannelOpenRequest {
+            channel_id: 3,
+            kind: ChannelKind::Dynamic,
+        });
+        assert!(matches!(
+            session.handle_message(open).await,
+            Ok(Some(Message::ChannelClose(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_limited_profile_refuses_pty() {
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "limited".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            cgroup: Some(crate::cgroup::CgroupConfig::default()),
+            ..Default::default()
+        });
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_config(Arc::new(config))
+            .with_outbound(tx);
+
+        let request = shell_proto::PtyOpenRequest {
+            id: 1,
+            command: Some("true".to_string()),
+            args: vec![],
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        };
+
+        let response = session
+            .handle_message(Message::PtyOpen(request))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::PtyClose(close)) if close.reason.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_rlimits_refuse_pty() {
+        let rlimits = crate::rlimit::RlimitConfig {
+            nofile: Some(64),
+            ..Default::default()
+        };
+        let executor = Arc::new(CommandExecutor::new(30).with_rlimits(rlimits));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = Session::new(vec![1, 2, 3], executor).with_outbound(tx);
+
+        let request = shell_proto::PtyOpenRequest {
+            id: 1,
+            command: Some("true".to_string()),
+            args: vec![],
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        };
+
+        let response = session
+            .handle_message(Message::PtyOpen(request))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::PtyClose(close)) if close.reason.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_job_start_and_list() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let request = CommandRequest {
+            id: 5,
+            command: "sleep".to_string(),
+            args: vec!["30".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let response = session.handle_message(Message::JobStart(request)).await.unwrap();
+        let job_id = match response {
+            Some(Message::JobStatus(status)) => {
+                assert_eq!(status.request_id, 5);
+                status.job.job_id
+            }
+            other => panic!("Expected JobStatus, got {:?}", other),
+        };
+
+        let list = shell_proto::messages::JobListRequest { id: 6 };
+        match session.handle_message(Message::JobList(list)).await.unwrap() {
+            Some(Message::JobListResponse(list)) => assert_eq!(list.jobs[0].job_id, job_id),
+            other => panic!("Expected JobListResponse, got {:?}", other),
+        }
+
+        let kill = shell_proto::messages::JobKillRequest { id: 7, job_id: 99 };
+        assert!(matches!(
+            session.handle_message(Message::JobKill(kill)).await.unwrap(),
+            Some(Message::Error(_))
+        ));
+
+        session.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_job_output_clamped() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let request = CommandRequest {
+            id: 1,
+            command: "head".to_string(),
+            args: vec![
+                "-c".to_string(),
+                (2 * MAX_CHUNK_SIZE).to_string(),
+                "/dev/zero".to_string(),
+            ],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let job_id = match session
+            .handle_message(Message::JobStart(request))
+            .await
+            .unwrap()
+        {
+            Some(Message::JobStatus(status)) => status.job.job_id,
+            other => panic!("Expected JobStatus, got {:?}", other),
+        };
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let read = shell_proto::messages::JobOutputRequest {
+            id: 2,
+            job_id,
+            stdout_offset: 0,
+            stderr_offset: 0,
+            max_bytes: u32::MAX,
+        };
+        match session
+            .handle_message(Message::JobOutputRequest(read))
+            .await
+            .unwrap()
+        {
+            Some(Message::JobOutput(output)) => assert_eq!(output.stdout.len(), MAX_CHUNK_SIZE),
+            other => panic!("Expected JobOutput, got {:?}", other),
+        }
+
+        session.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notice() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_outbound(tx);
+
+        let request = CommandRequest {
+            id: 1,
+            command: "sleep".to_string(),
+            args: vec!["30".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        session.handle_message(Message::JobStart(request)).await.unwrap();
+        assert!(session.is_busy());
+
+        session.notify_disconnect("Server shutting down");
+        match rx.recv().await {
+            Some(Message::Disconnect(msg)) => {
+                assert_eq!(msg.reason.as_deref(), Some("Server shutting down"))
+            }
+            other => panic!("Expected Disconnect, got {:?}", other),
+        }
+
+        // Closing kills what is left
+        session.close().await.unwrap();
+        assert!(!session.is_busy());
+    }
+
+    #[tokio::test]
+    async fn test_command_history() {
+        let mut config = ServerConfig::default();
+        config.history_size = 2;
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config));
+
+        for (id, command) in [(1, "true"), (2, "false"), (3, "")].into_iter() {
+            let request = CommandRequest {
+                id,
+                command: command.to_string(),
+                args: vec![],
+                env: None,
+                timeout: None,
+                working_dir: None,
+                secrets: vec![],
+                stdin: false,
+                delta_base: None,
+            };
+            let _ = session.handle_message(Message::CommandRequest(request)).await;
+        }
+
+        let request = shell_proto::messages::HistoryRequest { id: 4, limit: None };
+        let entries = match session.handle_message(Message::HistoryRequest(request)).await {
+            Ok(Some(Message::HistoryResponse(response))) => response.entries,
+            other => panic!("Expected HistoryResponse, got {:?}", other),
+        };
+
+        // Bounded, oldest first; refused commands are kept without an outcome
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].command, "false");
+        assert_ne!(entries[0].exit_code, Some(0));
+        assert_eq!(entries[1].command, "");
+        assert_eq!(entries[1].status, None);
+
+        assert_eq!(session.history(Some(1)), entries[1..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_session_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.session_recording = true;
+        config.recording_dir = dir.path().to_path_buf();
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config));
+
+        let request = CommandRequest {
+            id: 1,
+            command: "echo".to_string(),
+            args: vec!["recorded".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        session
+            .handle_message(Message::CommandRequest(request))
+            .await
+            .unwrap();
+
+        let path = std::fs::read_dir(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(path.to_string_lossy().ends_with(&format!("{}.cast", session.id_string())));
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains(r#""i","$ echo recorded\r\n""#));
+        assert!(lines[2].contains(r#""o","recorded\r\n""#));
+    }
+
+    #[tokio::test]
+    async fn test_resumed_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SessionStore::open(dir.path(), 3600).unwrap());
+        let env = BTreeMap::from([("LANG".to_string(), "C".to_string())]);
+        store
+            .issue([4; 16], &[1, 2, 3], Some("/tmp".to_string()), env.clone())
+            .unwrap();
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_id([4; 16])
+            .with_store(Arc::clone(&store));
+        assert_eq!(session.cwd().as_deref(), Some("/tmp"));
+        assert_eq!(session.env(), env);
+
+        // A deliberate disconnect ends the session for good
+        let disconnect = DisconnectMessage { reason: None };
+        session
+            .handle_message(Message::Disconnect(disconnect))
+            .await
+            .unwrap();
+        assert!(store.load([4; 16]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_change_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        let store = Arc::new(SessionStore::open(&root.join("state"), 3600).unwrap());
+        store.issue([5; 16], &[1, 2, 3], None, BTreeMap::new()).unwrap();
+
+        let mut config = ServerConfig::default();
+        config.fs_allowed_paths = vec![root.clone()];
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_id([5; 16])
+            .with_config(Arc::new(config))
+            .with_store(Arc::clone(&store));
+
+        let run = |command: &str, args: &[&str]| {
+            let request = CommandRequest {
+                id: 1,
+                command: command.to_string(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                env: None,
+                timeout: None,
+                working_dir: None,
+                secrets: vec![],
+                stdin: false,
+                delta_base: None,
+            };
+            let session = &session;
+            async move {
+                match session.handle_message(Message::CommandRequest(request)).await {
+                    Ok(Some(Message::CommandResponse(response))) => response,
+                    other => panic!("Expected CommandResponse, got {:?}", other),
+                }
+            }
+        };
+
+        assert_eq!(run("cd", &[root.to_str().unwrap()]).await.exit_code, 0);
+        assert_eq!(run("cd", &["sub"]).await.exit_code, 0);
+        let sub = root.join("sub").display().to_string();
+        assert_eq!(session.cwd(), Some(sub.clone()));
+        assert_eq!(store.load([5; 16]).unwrap().cwd, Some(sub.clone()));
+
+        // Later commands start there
+        let pwd = run("pwd", &[]).await;
+        assert_eq!(String::from_utf8_lossy(&pwd.stdout).trim(), sub);
+
+        // Outside the allowed paths, missing or ambiguous: cwd unchanged
+        for args in [&["/etc"][..], &["missing"], &["a", "b"]] {
+            let response = run("cd", args).await;
+            assert_eq!(response.exit_code, 1);
+            assert!(String::from_utf8_lossy(&response.stderr).starts_with("cd: "));
+        }
+        assert_eq!(session.cwd(), Some(sub));
+
+        // Home is the first allowed path
+        assert_eq!(run("cd", &[]).await.exit_code, 0);
+        assert_eq!(session.cwd(), Some(root.display().to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_change_dir_sandboxed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "jailed".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            allowed_paths: vec![root.clone()],
+            sandbox: Some(crate::sandbox::SandboxConfig::default()),
+            ..Default::default()
+        });
+        let audit_path = root.join("audit.log");
+        let audit = Arc::new(AuditLog::open(&audit_path, 1 << 20, 1).unwrap());
+        let hook = Arc::new(NoRm(AtomicUsize::new(0)));
+        let hooks: Vec<Arc<dyn Hook>> = vec![hook.clone()];
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_config(Arc::new(config))
+            .with_hooks(hooks)
+            .with_audit(audit);
+
+        let sub = root.join("sub").display().to_string();
+        let request = CommandRequest {
+            id: 1,
+            command: "cd".to_string(),
+            args: vec![sub.clone()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        match session
+            .handle_message(Message::CommandRequest(request))
+            .await
+        {
+            Ok(Some(Message::CommandResponse(response))) => assert_eq!(response.exit_code, 0),
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+        assert_eq!(session.cwd(), Some(sub));
+
+        // Seen by the hooks and the audit log like any command
+        assert_eq!(hook.0.load(Ordering::SeqCst), 1);
+        let log = std::fs::read_to_string(&audit_path).unwrap();
+        assert!(log.contains(r#""command":"cd""#));
+    }
+
+    #[tokio::test]
+    async fn test_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("logs")).unwrap();
+        std::fs::write(root.join("notes.txt"), "").unwrap();
+
+        let mut config = ServerConfig::default();
+        config.fs_allowed_paths = vec![root.clone()];
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config));
+        *session.cwd.lock().unwrap() = Some(root.display().to_string());
+
+        let complete = |kind: CompletionKind, prefix: &str| {
+            let request = CompleteRequest {
+                id: 7,
+                kind,
+                prefix: prefix.to_string(),
+            };
+            let session = &session;
+            async move { session.handle_message(Message::CompleteRequest(request)).await.unwrap() }
+        };
+
+        match complete(CompletionKind::Path, "").await {
+            Some(Message::CompleteResponse(response)) => {
+                assert_eq!(response.id, 7);
+                assert_eq!(response.candidates, vec!["logs/", "notes.txt"]);
+            }
+            other => panic!("Expected CompleteResponse, got {:?}", other),
+        }
+        match complete(CompletionKind::Command, "@sys").await {
+            Some(Message::CompleteResponse(response)) => {
+                assert_eq!(response.candidates, vec!["@sysinfo"]);
+            }
+            other => panic!("Expected CompleteResponse, got {:?}", other),
+        }
+
+        // Paths outside the allowed roots aren't listed
+        assert!(matches!(
+            complete(CompletionKind::Path, "/etc/").await,
+            Some(Message::Error(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_env() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor);
+
+        let set = |id, vars: &[(&str, &str)]| {
+            Message::SetEnv(shell_proto::SetEnvRequest {
+                id,
+                vars: vars
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            })
+        };
+
+        let response = session
+            .handle_message(set(1, &[("GREETING", "hello"), ("NAME", "world")]))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::Ack(ack)) if ack.message_id == 1));
+
+        // Denied names reject the whole request
+        let response = session
+            .handle_message(set(2, &[("LD_PRELOAD", "/tmp/evil.so"), ("OTHER", "x")]))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Message::Error(_))));
+        assert!(!session.env().contains_key("OTHER"));
+
+        let unset = shell_proto::UnsetEnvRequest {
+            id: 3,
+            names: vec!["NAME".to_string()],
+        };
+        session
+            .handle_message(Message::UnsetEnv(unset))
+            .await
+            .unwrap();
+
+        // Applied to commands, with request variables taking precedence
+        let request = CommandRequest {
+            id: 4,
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo $GREETING $NAME $EXTRA".to_string()],
+            env: Some(BTreeMap::from([("EXTRA".to_string(), "!".to_string())])),
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        match session.handle_message(Message::CommandRequest(request)).await {
+            Ok(Some(Message::CommandResponse(response))) => {
+                assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "hello !")
+            }
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_queue() {
+        let mut config = ServerConfig::default();
+        config.max_concurrent_commands = 1;
+        config.command_queue_size = 1;
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Arc::new(
+            Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config)),
+        );
+
+        let request = |id: u64| CommandRequest {
+            id,
+            command: "sleep".to_string(),
+            args: vec!["0.3".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let spawn = |id: u64| {
+            let session = Arc::clone(&session);
+            tokio::spawn(async move {
+                session
+                    .handle_message(Message::CommandRequest(request(id)))
+                    .await
+            })
+        };
+
+        let first = spawn(1);
+        while session.running() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let second = spawn(2);
+        while session.queued() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(session.running(), 1);
+
+        // The queue is full
+        match session.handle_message(Message::CommandRequest(request(3))).await {
+            Ok(Some(Message::Error(error))) => {
+                assert_eq!(error.code, ErrorMessage::OVERLOADED);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        for (id, task) in [(1, first), (2, second)] {
+            match task.await.unwrap() {
+                Ok(Some(Message::CommandResponse(response))) => {
+                    assert_eq!(response.id, id);
+                    assert_eq!(response.status, CommandStatus::Success);
+                }
+                other => panic!("Expected CommandResponse, got {:?}", other),
+            }
+        }
+        assert_eq!(session.queued(), 0);
+        assert_eq!(session.running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_banner_ack() {
+        let mut config = ServerConfig::default();
+        config.banner = Some("Authorized use only".to_string());
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config));
+
+        let request = CommandRequest {
+            id: 1,
+            command: "true".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        match session
+            .handle_message(Message::CommandRequest(request.clone()))
+            .await
+        {
+            Ok(Some(Message::Error(error))) => assert_eq!(error.code, ErrorMessage::DENIED),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert!(matches!(
+            session.handle_message(Message::Ping).await,
+            Ok(Some(Message::Pong))
+        ));
+
+        assert!(matches!(
+            session.handle_message(Message::BannerAck).await,
+            Ok(Some(Message::Ack(_)))
+        ));
+        assert!(matches!(
+            session.handle_message(Message::CommandRequest(request)).await,
+            Ok(Some(Message::CommandResponse(_)))
+        ));
+    }
+
+    /// Vetoes "rm" and counts the commands it saw finish
+    struct NoRm(AtomicUsize);
+
+    impl Hook for NoRm {
+        fn before<'a>(
+            &'a self,
+            event: HookEvent<'a>,
+        ) -> hooks::HookFuture<'a, std::result::Result<(), String>> {
+            Box::pin(async move {
+                if event.request.command == "rm" {
+                    Err("rm is not allowed here".to_string())
+                } else {
+                    Ok(())
+                }
+            })
+        }
+
+        fn after<'a>(
+            &'a self,
+            _event: HookEvent<'a>,
+            _response: &'a CommandResponse,
+        ) -> hooks::HookFuture<'a, ()> {
+            Box::pin(async move {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let hook = Arc::new(NoRm(AtomicUsize::new(0)));
+        let executor = Arc::new(CommandExecutor::new(30));
+        let hooks: Vec<Arc<dyn Hook>> = vec![hook.clone()];
+        let session = Session::new(vec![1, 2, 3], executor).with_hooks(hooks);
+
+        let request = CommandRequest {
+            id: 1,
+            command: "rm".to_string(),
+            args: vec!["-rf".to_string(), "/nonexistent".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        match session
+            .handle_message(Message::CommandRequest(request.clone()))
+            .await
+        {
+            Ok(Some(Message::Error(error))) => {
+                assert_eq!(error.request_id, 1);
+                assert_eq!(error.code, ErrorMessage::DENIED);
+                assert!(error.message.contains("rm is not allowed here"));
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(hook.0.load(Ordering::SeqCst), 0);
+
+        let request = CommandRequest {
+            id: 2,
+            command: "true".to_string(),
+            args: vec![],
+            ..request
+        };
+        assert!(matches!(
+            session.handle_message(Message::CommandRequest(request)).await,
+            Ok(Some(Message::CommandResponse(_)))
+        ));
+        assert_eq!(hook.0.load(Ordering::SeqCst), 1);
+        assert_eq!(session.history(None).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hooks_veto_pty() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let hooks: Vec<Arc<dyn Hook>> = vec![Arc::new(NoRm(AtomicUsize::new(0)))];
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_hooks(hooks)
+            .with_outbound(tx);
+
+        let request = shell_proto::PtyOpenRequest {
+            id: 1,
+            command: Some("rm".to_string()),
+            args: vec!["-i".to_string()],
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            env: None,
+            working_dir: None,
+        };
+        match session.handle_message(Message::PtyOpen(request)).await {
+            Ok(Some(Message::PtyClose(close))) => {
+                assert!(close.reason.unwrap().contains("rm is not allowed here"));
+            }
+            other => panic!("Expected PtyClose, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.transfer_dir = dir.path().to_path_buf();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "monitoring".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            read_only: Some(true),
+            ..Default::default()
+        });
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(Arc::new(config));
+
+        let command = |command: &str| {
+            Message::CommandRequest(CommandRequest {
+                id: 1,
+                command: command.to_string(),
+                args: vec![],
+                env: None,
+                timeout: None,
+                working_dir: Some(dir.path().display().to_string()),
+                secrets: vec![],
+                stdin: false,
+                delta_base: None,
+            })
+        };
+        let file_op = |op: FileOp| Message::FileOp(shell_proto::FileOpRequest { id: 2, op });
+        let path = dir.path().join("new").display().to_string();
+
+        let permitted = [
+            command("ls"),
+            command("@uptime"),
+            file_op(FileOp::List {
+                path: dir.path().display().to_string(),
+            }),
+        ];
+        for message in permitted {
+            match session.handle_message(message).await.unwrap() {
+                Some(Message::CommandResponse(_)) | Some(Message::FileOpResult(_)) => {}
+                other => panic!("Expected a result, got {:?}", other),
+            }
+        }
+
+        assert!(matches!(
+            session.handle_message(command("touch")).await,
+            Err(ServerError::Denied(_))
+        ));
+        let refused = [
+            file_op(FileOp::Mkdir {
+                path: path.clone(),
+                recursive: false,
+            }),
+            Message::UploadStart(UploadRequest {
+                id: 3,
+                path: "upload.txt".to_string(),
+                size: 1,
+                sha256: vec![0; 32],
+                mode: None,
+                resume: false,
+            }),
+        ];
+        for message in refused {
+            match session.handle_message(message).await.unwrap() {
+                Some(Message::Error(error)) => assert!(error.message.contains("Read-only mode")),
+                other => panic!("Expected an error, got {:?}", other),
+            }
+        }
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_output_streaming() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_outbound(tx)
+            .with_output_streaming(true);
+
+        let request = CommandRequest {
+            id: 4,
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo out; echo err >&2; exit 3".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let reply = session.handle_message(Message::CommandRequest(request)).await.unwrap();
+        assert!(reply.is_none());
+
+        // Output first, then the response without it
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let response = loop {
+            match rx.recv().await {
+                Some(Message::CommandOutput(output)) => {
+                    assert_eq!(output.id, 4);
+                    if output.stderr {
+                        stderr.extend(output.data);
+                    } else {
+                        stdout.extend(output.data);
+                    }
+                }
+                Some(Message::CommandResponse(response)) => break response,
+                other => panic!("Expected output or a response, got {:?}", other),
+            }
+        };
+        assert_eq!(stdout, b"out\n");
+        assert_eq!(stderr, b"err\n");
+        assert_eq!(response.exit_code, 3);
+        assert!(response.stdout.is_empty() && response.stderr.is_empty());
+        assert_eq!(response.total_bytes, 8);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancel() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Arc::new(Session::new(vec![1, 2, 3], executor));
+
+        let request = |id: u64, script: &str| CommandRequest {
+            id,
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let spawn = |req: CommandRequest| {
+            let session = Arc::clone(&session);
+            tokio::spawn(async move { session.handle_message(Message::CommandRequest(req)).await })
+        };
+        let cancel = |id: u64, force: bool| {
+            session.handle_message(Message::CancelRequest(CancelRequest { id, force }))
+        };
+
+        // SIGINT reaches the command, which decides how to end
+        let task = spawn(request(1, "trap 'echo bye; exit 130' INT; sleep 30"));
+        while session.running() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cancel(1, false).await.unwrap().is_none());
+        match task.await.unwrap() {
+            Ok(Some(Message::CommandResponse(response))) => {
+                assert_eq!(response.exit_code, 130);
+                assert_eq!(response.stdout, b"bye\n");
+            }
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+
+        // A forced cancel kills it
+        let task = spawn(request(2, "trap '' INT; sleep 30"));
+        while session.running() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cancel(2, true).await.unwrap().is_none());
+        match task.await.unwrap() {
+            Ok(Some(Message::CommandResponse(response))) => {
+                assert_eq!(response.status, CommandStatus::Killed);
+            }
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+
+        // Nothing left to cancel
+        assert!(session.cancellable.lock().unwrap().is_empty());
+        assert!(cancel(2, true).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_command_input() {
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Arc::new(Session::new(vec![1, 2, 3], executor));
+        let input = |data: &[u8], eof: bool| {
+            session.handle_message(Message::CommandInput(CommandInput {
+                id: 1,
+                data: data.to_vec(),
+                eof,
+            }))
+        };
+        let request = CommandRequest {
+            id: 1,
+            command: "cat".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: true,
+            delta_base: None,
+        };
+
+        // Input sent right behind the request is held until the command runs
+        session.open_input(&request);
+        assert!(input(b"piped ", false).await.unwrap().is_none());
+        let task = {
+            let session = Arc::clone(&session);
+            tokio::spawn(async move {
+                session
+                    .handle_message(Message::CommandRequest(request))
+                    .await
+            })
+        };
+        assert!(input(b"in\n", true).await.unwrap().is_none());
+        match task.await.unwrap() {
+            Ok(Some(Message::CommandResponse(response))) => {
+                assert_eq!(response.status, CommandStatus::Success);
+                assert_eq!(response.stdout, b"piped in\n");
+            }
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+
+        // Late input is dropped
+        assert!(session.inputs.take(1).is_none());
+        assert!(input(b"late", true).await.unwrap().is_none());
+        assert!(session.inputs.take(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_require_approval() {
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "contractors".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            require_approval: true,
+            ..Default::default()
+        });
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let approvals = Arc::new(ApprovalQueue::new(Duration::from_secs(30)));
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Arc::new(
+            Session::new(vec![1, 2, 3], executor)
+                .with_config(Arc::new(config))
+                .with_outbound(tx)
+                .with_approvals(Arc::clone(&approvals)),
+        );
+
+        for approve in [true, false] {
+            let request = CommandRequest {
+                id: 1,
+                command: "true".to_string(),
+                args: vec![],
+                env: None,
+                timeout: None,
+                working_dir: None,
+                secrets: vec![],
+                stdin: false,
+                delta_base: None,
+            };
+            let waiter = {
+                let session = Arc::clone(&session);
+                tokio::spawn(async move {
+                    session
+                        .handle_message(Message::CommandRequest(request))
+                        .await
+                })
+            };
+
+            let approval_id = match rx.recv().await {
+                Some(Message::Pending(notice)) => {
+                    assert_eq!(notice.id, 1);
+                    assert_eq!(notice.timeout, 30);
+                    notice.approval_id
+                }
+                other => panic!("Expected Pending, got {:?}", other),
+            };
+            assert!(approvals.decide(approval_id, approve));
+
+            match waiter.await.unwrap().unwrap() {
+                Some(Message::CommandResponse(response)) if approve => {
+                    assert_eq!(response.exit_code, 0)
+                }
+                Some(Message::Error(error)) if !approve => {
+                    assert!(error.message.contains("denied"))
+                }
+                other => panic!("Unexpected response {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("big"), vec![7u8; 2 * MAX_CHUNK_SIZE]).unwrap();
+        let mut config = ServerConfig::default();
+        config.transfer_dir = dir.path().to_path_buf();
+        config.session_bandwidth_limit = MAX_CHUNK_SIZE as u64;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_config(Arc::new(config))
+            .with_outbound(tx);
+
+        let download = shell_proto::DownloadRequest {
+            id: 1,
+            path: "big".to_string(),
+        };
+        let transfer_id = match session.handle_message(Message::DownloadStart(download)).await {
+            Ok(Some(Message::TransferReady(ready))) => ready.transfer_id,
+            other => panic!("Expected TransferReady, got {:?}", other),
+        };
+        let chunk = |offset: usize| {
+            Message::ChunkRequest(shell_proto::ChunkRequest {
+                transfer_id,
+                offset: offset as u64,
+                max_bytes: MAX_CHUNK_SIZE as u32,
+            })
+        };
+
+        // The first chunk fits the burst, the second is sent once the
+        // bucket has refilled
+        assert!(matches!(
+            session.handle_message(chunk(0)).await,
+            Ok(Some(Message::FileChunk(_)))
+        ));
+        let started = Instant::now();
+        assert!(session.handle_message(chunk(MAX_CHUNK_SIZE)).await.unwrap().is_none());
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Some(Message::FileChunk(chunk))) => assert_eq!(chunk.offset, MAX_CHUNK_SIZE as u64),
+            other => panic!("Expected FileChunk, got {:?}", other),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_secrets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let secrets_file = dir.path().join("secrets.toml");
+        fs::write(&secrets_file, "DB_PASS = \"hunter2\"\n").unwrap();
+        fs::set_permissions(&secrets_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut config = ServerConfig::default();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "deploy".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            secrets_file: Some(secrets_file),
+            ..Default::default()
+        });
+        let config = Arc::new(config);
+
+        let request = |secret: &str| {
+            Message::CommandRequest(CommandRequest {
+                id: 1,
+                command: "printenv".to_string(),
+                args: vec!["DB_PASS".to_string()],
+                env: None,
+                timeout: None,
+                working_dir: None,
+                secrets: vec![secret.to_string()],
+                stdin: false,
+                delta_base: None,
+            })
+        };
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], Arc::clone(&executor))
+            .with_config(Arc::clone(&config));
+        match session.handle_message(request("DB_PASS")).await.unwrap() {
+            Some(Message::CommandResponse(response)) => assert_eq!(response.stdout, b"hunter2\n"),
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+
+        assert!(matches!(
+            session.handle_message(request("API_KEY")).await,
+            Err(ServerError::Denied(_))
+        ));
+
+        // Clients without a secrets file get none
+        let other = Session::new(vec![4, 5, 6], executor).with_config(config);
+        assert!(matches!(
+            other.handle_message(request("DB_PASS")).await,
+            Err(ServerError::Denied(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_session_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.session_dir_root = Some(dir.path().to_path_buf());
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor)
+            .with_id([6; 16])
+            .with_config(Arc::new(config));
+
+        let home = dir
+            .path()
+            .canonicalize()
+            .unwrap()
+            .join(Uuid::from_bytes([6; 16]).to_string());
+        assert_eq!(session.cwd(), Some(home.display().to_string()));
+
+        let run = |command: &str, args: &[&str]| {
+            let request = CommandRequest {
+                id: 1,
+                command: command.to_string(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                env: None,
+                timeout: None,
+                working_dir: None,
+                secrets: vec![],
+                stdin: false,
+                delta_base: None,
+            };
+            let session = &session;
+            async move {
+                match session.handle_message(Message::CommandRequest(request)).await {
+                    Ok(Some(Message::CommandResponse(response))) => response,
+                    other => panic!("Expected CommandResponse, got {:?}", other),
+                }
+            }
+        };
+
+        // Commands start in it and `cd` returns to it
+        assert_eq!(run("touch", &["scratch"]).await.exit_code, 0);
+        assert!(home.join("scratch").exists());
+        assert_eq!(run("cd", &["/"]).await.exit_code, 0);
+        assert_eq!(run("cd", &[]).await.exit_code, 0);
+        assert_eq!(session.cwd(), Some(home.display().to_string()));
+
+        session.close().await.unwrap();
+        assert!(!home.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exec_upload() {
+        use sha2::{Digest, Sha256};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.transfer_dir = dir.path().to_path_buf();
+        config.profiles.push(crate::config::ClientProfile {
+            name: "deployers".to_string(),
+            clients: vec![hex::encode([1u8, 2, 3])],
+            exec_upload: true,
+            ..Default::default()
+        });
+        let config = Arc::new(config);
+
+        let program = b"#!/bin/sh\necho hello $1\n".to_vec();
+        let request = ExecUploadRequest {
+            id: 9,
+            name: "greet".to_string(),
+            size: program.len() as u64,
+            sha256: Sha256::digest(&program).to_vec(),
+            args: vec!["world".to_string()],
+            env: None,
+            timeout: None,
+            delete_after: true,
+        };
+
+        // Only permitted profiles may upload programs
+        let executor = Arc::new(CommandExecutor::new(30));
+        let other = Session::new(vec![4, 5, 6], executor).with_config(Arc::clone(&config));
+        match other.handle_message(Message::ExecUpload(request.clone())).await {
+            Ok(Some(Message::Error(error))) => assert_eq!(error.code, ErrorMessage::DENIED),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session = Session::new(vec![1, 2, 3], executor).with_config(config);
+        let bad_name = ExecUploadRequest {
+            name: "../greet".to_string(),
+            ..request.clone()
+        };
+        assert!(matches!(
+            session.handle_message(Message::ExecUpload(bad_name)).await,
+            Ok(Some(Message::Error(_)))
+        ));
+
+        let ready = match session.handle_message(Message::ExecUpload(request)).await {
+            Ok(Some(Message::TransferReady(ready))) => ready,
+            other => panic!("Expected TransferReady, got {:?}", other),
+        };
+        assert_eq!(ready.request_id, 9);
+
+        let chunk = shell_proto::FileChunk {
+            transfer_id: ready.transfer_id,
+            offset: 0,
+            data: program,
+        };
+        match session.handle_message(Message::FileChunk(chunk)).await {
+            Ok(Some(Message::CommandResponse(response))) => {
+                assert_eq!(response.id, 9);
+                assert_eq!(response.status, CommandStatus::Success);
+                assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "hello world");
+            }
+            other => panic!("Expected CommandResponse, got {:?}", other),
+        }
+        assert!(!session.exec_dir().join("greet").exists());
+
+        session.close().await.unwrap();
+        assert!(!session.exec_dir().exists());
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let accounting = Arc::new(Accounting::new());
+        let executor = Arc::new(CommandExecutor::new(30));
+        let session =
+            Session::new(vec![1, 2, 3], executor).with_accounting(Arc::clone(&accounting));
+        accounting.session_started(&[1, 2, 3]);
+
+        let request = CommandRequest {
+            id: 1,
+            command: "true".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        session
+            .handle_message(Message::CommandRequest(request))
+            .await
+            .unwrap();
+
+        let request = shell_proto::StatsRequest { id: 2 };
+        match session.handle_message(Message::StatsRequest(request)).await {
+            Ok(Some(Message::StatsResponse(stats))) => {
+                assert_eq!(stats.id, 2);
+                assert_eq!(stats.sessions, 1);
+                assert_eq!(stats.commands, 1);
+                assert_eq!(stats.running, 0);
+            }
+            other => panic!("Expected StatsResponse, got {:?}", other),
+        }
+    }
 }
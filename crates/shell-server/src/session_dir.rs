@@ -0,0 +1,162 @@
+//! Per-session temporary directories
+//!
+//! With `session_dir_root` set, every session gets a fresh directory under
+//! it, readable only by the server user (mode 0700). It is the session's
+//! initial working directory and where relative upload paths land, so
+//! clients get scratch space that no other session can see. The directory
+//! and everything in it are deleted when the session closes.
+//!
+//! `session_dir_quota` bounds the bytes stored in it. Uploads that would
+//! exceed it are refused; commands can't be stopped mid-write, so they are
+//! only checked afterwards, and while the directory is over its quota no
+//! further uploads are accepted.
+
+use crate::{config::ServerConfig, Result, ServerError};
+use shell_proto::SessionId;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+/// A session's private directory
+#[derive(Debug)]
+pub struct SessionDir {
+    /// Directory path
+    path: PathBuf,
+
+    /// Bytes the directory may hold (0 = unlimited)
+    quota: u64,
+}
+
+impl SessionDir {
+    /// Create the directory at `path` (its parent must exist)
+    ///
+    /// An existing directory is kept, so a session resumed after a server
+    /// crash finds its files again.
+    pub fn create(path: PathBuf, quota: u64) -> Result<Self> {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        match builder.create(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+
+        // Never follow a symlink planted in its place
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Err(ServerError::Execution(format!(
+                "Not a directory: {}",
+                path.display()
+            )));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+        }
+
+        // Resolved upload paths are canonical, so compare against that
+        let path = path.canonicalize()?;
+        Ok(Self { path, quota })
+    }
+
+    /// Create the directory of a session, if configured
+    pub fn from_config(config: &ServerConfig, session_id: SessionId) -> Option<Result<Self>> {
+        let root = config.session_dir_root.as_ref()?;
+        Some(fs::create_dir_all(root).map_err(Into::into).and_then(|()| {
+            Self::create(
+                root.join(Uuid::from_bytes(session_id).to_string()),
+                config.session_dir_quota,
+            )
+        }))
+    }
+
+    /// Directory path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes currently stored in the directory
+    pub fn usage(&self) -> u64 {
+        dir_size(&self.path)
+    }
+
+    /// Check that `additional` more bytes fit within the quota
+    pub fn check_quota(&self, additional: u64) -> Result<()> {
+        if self.quota == 0 {
+            return Ok(());
+        }
+
+        let used = self.usage();
+        if used.saturating_add(additional) > self.quota {
+            return Err(ServerError::Denied(format!(
+                "Session directory quota exceeded: {} of {} bytes used",
+                used, self.quota
+            )));
+        }
+        Ok(())
+    }
+
+    /// Delete the directory and its contents
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(dir = %self.path.display(), error = %e, "Failed to remove session directory");
+            }
+        }
+    }
+}
+
+/// Total size of the files under `path`, not following symlinks
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+        .map(|(path, metadata)| {
+            if metadata.is_dir() {
+                dir_size(&path)
+            } else {
+                metadata.len()
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = SessionDir::create(root.path().join("session"), 10).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a"), b"12345").unwrap();
+        assert_eq!(dir.usage(), 5);
+        assert!(dir.check_quota(5).is_ok());
+        assert!(matches!(dir.check_quota(6), Err(ServerError::Denied(_))));
+
+        // Reopening it keeps the files
+        let dir = SessionDir::create(dir.path().to_path_buf(), 0).unwrap();
+        assert_eq!(dir.usage(), 5);
+        assert!(dir.check_quota(u64::MAX).is_ok());
+
+        dir.remove();
+        assert!(!dir.path().exists());
+    }
+}
@@ -1,31 +1,339 @@
 //! Command execution functionality
 
-use crate::{Result, ServerError};
+use crate::{
+    accounting, builtins,
+    cgroup::{CgroupConfig, TransientCgroup},
+    container::{ContainerConfig, ContainerRun},
+    env_policy::EnvPolicy,
+    input::{self, InputReceiver},
+    policy::{CommandPolicy, CommandRule},
+    process::{self, ProcessGroup},
+    spool::{OutputSpool, SpoolWriter},
+    rlimit::{self, RlimitConfig},
+    sandbox::{self, SandboxConfig},
+    seccomp::{self, SeccompConfig},
+    Result, ServerError,
+};
 use shell_proto::{CommandRequest, CommandResponse, CommandStatus};
-use std::process::Stdio;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
-use tokio::process::Command as TokioCommand;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command as TokioCommand};
+use tokio::sync::Notify;
 use tokio::time::timeout;
 use tracing::{debug, warn};
 
+/// Receives a command's output as it is produced, as `(stderr, data)`
+pub type OutputSink = dyn Fn(bool, &[u8]) + Send + Sync;
+
 /// Command executor
+#[derive(Clone)]
 pub struct CommandExecutor {
     /// Default timeout (seconds)
     default_timeout: u64,
+
+    /// Command allow/deny policy
+    policy: CommandPolicy,
+
+    /// Namespace/chroot sandbox for spawned commands
+    sandbox: Option<SandboxConfig>,
+
+    /// Seccomp filter for spawned commands
+    seccomp: Option<SeccompConfig>,
+
+    /// Resource limits applied through a per-command cgroup
+    cgroup: Option<CgroupConfig>,
+
+    /// rlimits for spawned commands
+    rlimits: RlimitConfig,
+
+    /// Serve builtins instead of spawning them
+    builtins: bool,
+
+    /// Shell commands are passed to with `-c` (None = exec directly)
+    shell: Option<PathBuf>,
+
+    /// Bytes of stdout and of stderr kept per command
+    max_output: usize,
+
+    /// Container commands run in instead of on the host
+    container: Option<ContainerConfig>,
+
+    /// Variables commands may be given
+    env_policy: EnvPolicy,
+
+    /// Commands permitted in read-only mode (None = not read-only)
+    read_only: Option<Vec<CommandRule>>,
+}
+
+/// Stops a running command from outside the executor
+///
+/// [`interrupt`](Self::interrupt) sends SIGINT to the command's process
+/// group and leaves it to exit as it sees fit; [`kill`](Self::kill) kills
+/// the group and reports the command as killed. A command stopped before it
+/// is spawned is never started.
+#[derive(Debug, Default)]
+pub struct Interrupter {
+    /// Process group of the command, once spawned
+    pgid: Mutex<Option<u32>>,
+
+    /// Set by [`kill`](Self::kill)
+    killed: AtomicBool,
+
+    /// Wakes the executor on [`kill`](Self::kill)
+    notify: Notify,
+}
+
+impl Interrupter {
+    /// Create an interrupter for a command not yet spawned
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send SIGINT to the command, or make sure it never starts
+    pub fn interrupt(&self) {
+        match *self.pgid.lock().unwrap() {
+            Some(pgid) => {
+                process::interrupt(pgid);
+            }
+            None => self.kill(),
+        }
+    }
+
+    /// Kill the command and everything it started
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        // Stores a permit if the executor is not waiting yet
+        self.notify.notify_one();
+    }
+
+    /// Whether [`kill`](Self::kill) was called
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// Note the process group of the spawned command
+    fn spawned(&self, pgid: Option<u32>) {
+        *self.pgid.lock().unwrap() = pgid;
+    }
+}
+
+/// Resources a spawned command holds until it exits
+#[derive(Debug, Default)]
+pub(crate) struct CommandGuard {
+    /// Process group the command leads (set once it is spawned)
+    pub(crate) group: Option<ProcessGroup>,
+
+    /// Cgroup the command runs in
+    pub(crate) cgroup: Option<TransientCgroup>,
+
+    /// Per-command container
+    pub(crate) container: Option<ContainerRun>,
+}
+
+impl CommandGuard {
+    /// Note that the command exited on its own
+    pub(crate) fn finished(&mut self) {
+        if let Some(group) = &mut self.group {
+            group.finished();
+        }
+        if let Some(container) = &mut self.container {
+            container.finished();
+        }
+    }
+
+    /// Kill the command and everything it started
+    pub(crate) fn kill(&mut self) {
+        if let Some(group) = &mut self.group {
+            group.kill();
+        }
+    }
 }
 
 impl CommandExecutor {
     /// Create a new command executor
     pub fn new(default_timeout: u64) -> Self {
-        Self { default_timeout }
+        Self {
+            default_timeout,
+            policy: CommandPolicy::default(),
+            sandbox: None,
+            seccomp: None,
+            cgroup: None,
+            rlimits: RlimitConfig::default(),
+            builtins: true,
+            shell: None,
+            max_output: usize::MAX,
+            container: None,
+            env_policy: EnvPolicy::default(),
+            read_only: None,
+        }
+    }
+
+    /// Apply a command allow/deny policy
+    pub fn with_policy(mut self, policy: CommandPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Run commands inside a sandbox
+    pub fn with_sandbox(mut self, sandbox: SandboxConfig) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Get the sandbox commands run in (if any)
+    pub fn sandbox(&self) -> Option<&SandboxConfig> {
+        self.sandbox.as_ref()
+    }
+
+    /// Install a seccomp filter in spawned commands
+    pub fn with_seccomp(mut self, seccomp: SeccompConfig) -> Self {
+        self.seccomp = Some(seccomp);
+        self
+    }
+
+    /// Run each command in a transient cgroup with resource limits
+    pub fn with_cgroup(mut self, cgroup: CgroupConfig) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Apply rlimits to spawned commands
+    pub fn with_rlimits(mut self, rlimits: RlimitConfig) -> Self {
+        self.rlimits = rlimits;
+        self
+    }
+
+    /// Enable or disable the `@`-prefixed builtins
+    pub fn with_builtins(mut self, enabled: bool) -> Self {
+        self.builtins = enabled;
+        self
+    }
+
+    /// Run commands through `<shell> -c` instead of executing them directly
+    ///
+    /// The command and its arguments are joined with spaces and interpreted
+    /// by the shell, so pipes, globs and redirects work. Policies would only
+    /// see the first word, so this is meant for clients with none (see
+    /// [`ServerConfig::validate`](crate::config::ServerConfig::validate)).
+    pub fn with_shell(mut self, shell: PathBuf) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    /// Keep at most `max_output` bytes of each of stdout and stderr
+    ///
+    /// The rest is read and discarded, so the command still runs to
+    /// completion; the response is flagged as truncated.
+    pub fn with_max_output(mut self, max_output: usize) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Run commands in a container instead of on the host
+    ///
+    /// Sandbox, seccomp and rlimit settings are not applied to containerized
+    /// commands; cgroup limits are passed to the container runtime.
+    pub fn with_container(mut self, container: ContainerConfig) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    /// Get the container commands run in (if any)
+    pub fn container(&self) -> Option<&ContainerConfig> {
+        self.container.as_ref()
+    }
+
+    /// Refuse commands whose environment sets variables `env_policy` denies
+    pub fn with_env_policy(mut self, env_policy: EnvPolicy) -> Self {
+        self.env_policy = env_policy;
+        self
+    }
+
+    /// Get the environment variable policy
+    pub fn env_policy(&self) -> &EnvPolicy {
+        &self.env_policy
+    }
+
+    /// Permit only commands matching `commands` (and builtins), or lift
+    /// read-only mode with `None`
+    pub fn with_read_only(mut self, commands: Option<Vec<CommandRule>>) -> Self {
+        self.read_only = commands;
+        self
+    }
+
+    /// Check whether the executor is in read-only mode
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.is_some()
+    }
+
+    /// Check whether spawned commands are sandboxed, filtered or containerized
+    pub fn is_confined(&self) -> bool {
+        self.sandbox.is_some() || self.seccomp.is_some() || self.container.is_some()
+    }
+
+    /// Check whether spawned commands run under resource limits
+    pub fn is_limited(&self) -> bool {
+        self.cgroup.is_some() || !self.rlimits.is_empty()
+    }
+
+    /// Get the command allow/deny policy
+    pub fn policy(&self) -> &CommandPolicy {
+        &self.policy
+    }
+
+    /// Check whether builtins can run for this executor's clients
+    pub fn builtins_available(&self) -> bool {
+        self.builtins && !self.is_confined()
     }
 
     /// Execute a command
     pub async fn execute(&self, request: CommandRequest) -> Result<CommandResponse> {
+        self.execute_measured(request).await.map(|(response, _)| response)
+    }
+
+    /// Execute a command, also returning the CPU time it used
+    pub async fn execute_measured(
+        &self,
+        request: CommandRequest,
+    ) -> Result<(CommandResponse, Duration)> {
+        self.execute_spooled(request, None).await
+    }
+
+    /// Execute a command, spooling the complete output to `spool` if it
+    /// exceeds the cap, and return the CPU time it used
+    pub async fn execute_spooled(
+        &self,
+        request: CommandRequest,
+        spool: Option<&OutputSpool>,
+    ) -> Result<(CommandResponse, Duration)> {
+        self.execute_streamed(request, spool, None, None, None)
+            .await
+    }
+
+    /// Execute a command like [`execute_spooled`](Self::execute_spooled),
+    /// also passing all output to `sink` as it is produced
+    ///
+    /// The response still holds the (capped) output; error messages that
+    /// stand in for output, such as a timeout notice, go to the sink too.
+    /// `interrupter`, if given, can stop the command while it runs. A
+    /// request with `stdin` set reads `input`, or sees end of file at once
+    /// without it.
+    pub async fn execute_streamed(
+        &self,
+        request: CommandRequest,
+        spool: Option<&OutputSpool>,
+        sink: Option<&OutputSink>,
+        interrupter: Option<&Interrupter>,
+        input: Option<InputReceiver>,
+    ) -> Result<(CommandResponse, Duration)> {
         let start_time = Instant::now();
 
         debug!(
-            id = request.id,
+            request_id = request.id,
             command = %request.command,
             args = ?request.args,
             "Executing command"
@@ -36,34 +344,63 @@ impl CommandExecutor {
             request.timeout.unwrap_or(self.default_timeout)
         );
 
-        // Build command
-        let mut cmd = TokioCommand::new(&request.command);
-        cmd.args(&request.args);
-        cmd.stdin(Stdio::null());
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
-
-        // Set environment variables
-        cmd.env_clear(); // Start with clean environment for security
-        if let Some(env) = &request.env {
-            for (key, value) in env {
-                cmd.env(key, value);
+        if builtins::is_builtin(&request.command) {
+            let response = self.run_builtin(&request)?;
+            if let Some(sink) = sink {
+                sink(false, &response.stdout);
+                sink(true, &response.stderr);
             }
+            return Ok((response, Duration::ZERO));
         }
 
-        // Set working directory
-        if let Some(work_dir) = &request.working_dir {
-            cmd.current_dir(work_dir);
-        }
+        let (mut cmd, mut guard) = self.build_command(&request)?;
+        let cpu_before = accounting::children_cpu_time();
 
-        // Execute with timeout
-        let result = timeout(cmd_timeout, cmd.output()).await;
+        // Execute with timeout; on timeout or kill the whole process group is
+        // killed, as grandchildren may still hold the output pipes open.
+        // `None` means the command was killed.
+        let result = if interrupter.is_some_and(Interrupter::is_killed) {
+            None
+        } else {
+            match cmd.spawn() {
+                Ok(mut child) => {
+                    guard.group = ProcessGroup::of(&child);
+                    // Without input the pipe is dropped here, closing stdin
+                    let feeder = input.and_then(|input| input::feed(&mut child, input));
+                    child.stdin.take();
+                    let run = timeout(cmd_timeout, capture(child, self.max_output, spool, sink));
+                    let result = match interrupter {
+                        Some(interrupter) => {
+                            interrupter.spawned(guard.group.as_ref().map(ProcessGroup::id));
+                            tokio::select! {
+                                result = run => Some(result),
+                                () = interrupter.notify.notified() => None,
+                            }
+                        }
+                        None => Some(run.await),
+                    };
+                    if let Some(feeder) = feeder {
+                        feeder.abort();
+                    }
+                    result
+                }
+                Err(e) => Some(Ok(Err(e))),
+            }
+        };
+        if !matches!(result, Some(Ok(_))) {
+            guard.kill();
+        }
 
         let execution_time_ms = start_time.elapsed().as_millis() as u64;
 
-        match result {
-            Ok(Ok(output)) => {
-                let status = if output.status.success() {
+        let response = match result {
+            Some(Ok(Ok(output))) => {
+                guard.finished();
+                let oom_killed = guard.cgroup.as_ref().is_some_and(|cgroup| cgroup.oom_killed());
+                let status = if oom_killed {
+                    warn!(request_id = request.id, "Command exceeded its memory limit");
+                    CommandStatus::OomKilled
+                } else if output.status.success() {
                     CommandStatus::Success
                 } else {
                     CommandStatus::Error
@@ -72,13 +409,21 @@ impl CommandExecutor {
                 let exit_code = output.status.code().unwrap_or(-1);
 
                 debug!(
-                    id = request.id,
+                    request_id = request.id,
                     exit_code = exit_code,
                     stdout_len = output.stdout.len(),
                     stderr_len = output.stderr.len(),
+                    total_bytes = output.total_bytes,
                     duration_ms = execution_time_ms,
                     "Command completed"
                 );
+                if output.truncated {
+                    warn!(
+                        request_id = request.id,
+                        total_bytes = output.total_bytes,
+                        "Command output truncated"
+                    );
+                }
 
                 Ok(CommandResponse {
                     id: request.id,
@@ -87,31 +432,204 @@ impl CommandExecutor {
                     stderr: output.stderr,
                     exit_code,
                     execution_time_ms,
+                    truncated: output.truncated,
+                    total_bytes: output.total_bytes,
+                    spool_id: output.spool_id,
+                    delta_base: None,
                 })
             }
-            Ok(Err(e)) => {
-                warn!(id = request.id, error = %e, "Command execution failed");
+            Some(Ok(Err(e))) => {
+                warn!(request_id = request.id, error = %e, "Command execution failed");
+                let stderr = format!("Execution error: {}", e).into_bytes();
+                if let Some(sink) = sink {
+                    sink(true, &stderr);
+                }
                 Ok(CommandResponse {
                     id: request.id,
                     status: CommandStatus::Error,
                     stdout: vec![],
-                    stderr: format!("Execution error: {}", e).into_bytes(),
+                    stderr,
                     exit_code: -1,
                     execution_time_ms,
+                    truncated: false,
+                    total_bytes: 0,
+                    spool_id: None,
+                    delta_base: None,
                 })
             }
-            Err(_) => {
-                warn!(id = request.id, "Command timed out");
+            Some(Err(_)) => {
+                warn!(request_id = request.id, "Command timed out");
+                let stderr = b"Command execution timed out".to_vec();
+                if let Some(sink) = sink {
+                    sink(true, &stderr);
+                }
                 Ok(CommandResponse {
                     id: request.id,
                     status: CommandStatus::Timeout,
                     stdout: vec![],
-                    stderr: b"Command execution timed out".to_vec(),
+                    stderr,
+                    exit_code: -1,
+                    execution_time_ms,
+                    truncated: false,
+                    total_bytes: 0,
+                    spool_id: None,
+                    delta_base: None,
+                })
+            }
+            None => {
+                debug!(request_id = request.id, "Command killed on request");
+                let stderr = b"Command was killed".to_vec();
+                if let Some(sink) = sink {
+                    sink(true, &stderr);
+                }
+                Ok(CommandResponse {
+                    id: request.id,
+                    status: CommandStatus::Killed,
+                    stdout: vec![],
+                    stderr,
                     exit_code: -1,
                     execution_time_ms,
+                    truncated: false,
+                    total_bytes: 0,
+                    spool_id: None,
+                    delta_base: None,
                 })
             }
+        };
+
+        // The cgroup knows exactly; the rusage delta may include other
+        // commands that finished meanwhile
+        let cpu_time = guard
+            .cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.cpu_time())
+            .unwrap_or_else(|| accounting::children_cpu_time().saturating_sub(cpu_before));
+        response.map(|response| (response, cpu_time))
+    }
+
+    /// Run a builtin in the server process
+    fn run_builtin(&self, request: &CommandRequest) -> Result<CommandResponse> {
+        if !self.builtins {
+            return Err(ServerError::Denied("Builtins are disabled".to_string()));
+        }
+        // The sandbox and seccomp filter only apply to spawned processes
+        if self.is_confined() {
+            return Err(ServerError::Denied(
+                "Builtins are not available to confined clients".to_string(),
+            ));
+        }
+
+        let response = builtins::run(request);
+        debug!(
+            request_id = request.id,
+            exit_code = response.exit_code,
+            duration_ms = response.execution_time_ms,
+            "Builtin completed"
+        );
+        Ok(response)
+    }
+
+    /// Build the process for a command request
+    ///
+    /// stdout and stderr are piped, stdin is closed. The returned guard must
+    /// be kept alive until the process exits.
+    pub(crate) fn build_command(
+        &self,
+        request: &CommandRequest,
+    ) -> Result<(TokioCommand, CommandGuard)> {
+        if builtins::is_builtin(&request.command) {
+            return Err(ServerError::Execution(format!(
+                "{} is a builtin and can't be run as a process",
+                request.command
+            )));
+        }
+
+        // Every spawned command passes through here, including ones that
+        // were never validated as a request (uploaded programs)
+        self.env_policy.check(request.env.as_ref())?;
+
+        if let Some(container) = &self.container {
+            let argv: Vec<String> = match &self.shell {
+                Some(shell) => {
+                    vec![shell.display().to_string(), "-c".to_string(), shell_script(request)]
+                }
+                None => std::iter::once(&request.command).chain(&request.args).cloned().collect(),
+            };
+            let (mut cmd, run) = container.command(&argv, request, self.cgroup.as_ref());
+            process::new_session(&mut cmd);
+            let guard = CommandGuard {
+                group: None,
+                cgroup: None,
+                container: run,
+            };
+            return Ok((cmd, guard));
+        }
+
+        let mut cmd = match &self.shell {
+            Some(shell) => {
+                let mut cmd = TokioCommand::new(shell);
+                cmd.arg("-c").arg(shell_script(request));
+                cmd
+            }
+            None => {
+                let mut cmd = TokioCommand::new(&request.command);
+                cmd.args(&request.args);
+                cmd
+            }
+        };
+        cmd.stdin(if request.stdin {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        // Timed-out or abandoned commands must not outlive their request
+        cmd.kill_on_drop(true);
+        process::new_session(&mut cmd);
+
+        // Set environment variables
+        cmd.env_clear(); // Start with clean environment for security
+        if let Some(env) = &request.env {
+            for (key, value) in env {
+                cmd.env(key, value);
+            }
+        }
+
+        // Join the cgroup first so namespaces and the command inherit it
+        let cgroup = match &self.cgroup {
+            Some(config) => {
+                let name = format!("cmd-{}", uuid::Uuid::new_v4());
+                let cgroup = TransientCgroup::create(config, &name)?;
+                cgroup.attach(&mut cmd);
+                Some(cgroup)
+            }
+            None => None,
+        };
+
+        // Set working directory (inside the sandbox root when sandboxed)
+        match &self.sandbox {
+            Some(sandbox) => sandbox::apply(&mut cmd, sandbox, request.working_dir.as_deref()),
+            None => {
+                if let Some(work_dir) = &request.working_dir {
+                    cmd.current_dir(work_dir);
+                }
+            }
         }
+
+        rlimit::apply(&mut cmd, &self.rlimits);
+
+        // The filter goes last: namespace setup needs syscalls it may deny
+        if let Some(filter) = &self.seccomp {
+            seccomp::apply(&mut cmd, filter);
+        }
+
+        let guard = CommandGuard {
+            group: None,
+            cgroup,
+            container: None,
+        };
+        Ok((cmd, guard))
     }
 
     /// Validate a command request (security checks)
@@ -130,20 +648,130 @@ impl CommandExecutor {
             }
         }
 
-        // Additional security checks could be added here:
-        // - Blacklist certain commands
-        // - Validate arguments
-        // - Check resource limits
-        // etc.
+        // Enforce command allow/deny lists
+        self.policy.check(request)?;
+
+        // Builtins only report on the system
+        if let Some(commands) = &self.read_only {
+            if !builtins::is_builtin(&request.command)
+                && !commands.iter().any(|rule| rule.matches(request))
+            {
+                return Err(ServerError::Denied(format!(
+                    "Read-only mode: {} is not permitted",
+                    request.command
+                )));
+            }
+        }
 
         Ok(())
     }
 }
 
+/// Output of a finished process
+struct CapturedOutput {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    total_bytes: u64,
+    truncated: bool,
+    spool_id: Option<u64>,
+}
+
+/// Wait for a process, keeping up to `limit` bytes of each output stream
+///
+/// With a spool, streams exceeding the limit are written to it in full.
+async fn capture(
+    mut child: Child,
+    limit: usize,
+    spool: Option<&OutputSpool>,
+    sink: Option<&OutputSink>,
+) -> std::io::Result<CapturedOutput> {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let (mut stdout_spill, mut stderr_spill) = match spool {
+        Some(spool) => {
+            let (stdout, stderr) = spool.start();
+            (Some(stdout), Some(stderr))
+        }
+        None => (None, None),
+    };
+    let ((stdout, stdout_total), (stderr, stderr_total), status) = tokio::try_join!(
+        read_capped(stdout, limit, stdout_spill.as_mut(), sink.map(|sink| (sink, false))),
+        read_capped(stderr, limit, stderr_spill.as_mut(), sink.map(|sink| (sink, true))),
+        child.wait()
+    )?;
+
+    let spool_id = match (spool, stdout_spill, stderr_spill) {
+        (Some(spool), Some(stdout), Some(stderr)) => spool.finish(stdout, stderr),
+        _ => None,
+    };
+    let total_bytes = stdout_total + stderr_total;
+    Ok(CapturedOutput {
+        status,
+        truncated: total_bytes > (stdout.len() + stderr.len()) as u64,
+        stdout,
+        stderr,
+        total_bytes,
+        spool_id,
+    })
+}
+
+/// Read a stream to the end, returning its first `limit` bytes and its length
+///
+/// Once the stream exceeds `limit`, all of it (from the start) goes to
+/// `spill`. Every piece read is passed to `sink` along with its stream flag.
+async fn read_capped<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    limit: usize,
+    mut spill: Option<&mut SpoolWriter<'_>>,
+    sink: Option<(&OutputSink, bool)>,
+) -> std::io::Result<(Vec<u8>, u64)> {
+    let mut kept = Vec::new();
+    let mut total = 0u64;
+    let Some(mut reader) = reader else {
+        return Ok((kept, total));
+    };
+
+    let mut chunk = [0u8; 8192];
+    let mut overflowed = false;
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        if let Some((sink, stderr)) = sink {
+            sink(stderr, &chunk[..n]);
+        }
+        let room = limit.saturating_sub(kept.len());
+        if let Some(spill) = spill.as_deref_mut() {
+            if overflowed {
+                spill.write(&chunk[..n]);
+            } else if n > room {
+                overflowed = true;
+                spill.write(&kept);
+                spill.write(&chunk[..n]);
+            }
+        }
+        kept.extend_from_slice(&chunk[..n.min(room)]);
+    }
+    Ok((kept, total))
+}
+
+/// Script a request runs as in shell mode
+fn shell_script(request: &CommandRequest) -> String {
+    let mut script = request.command.clone();
+    for arg in &request.args {
+        script.push(' ');
+        script.push_str(arg);
+    }
+    script
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
 
     #[tokio::test]
     async fn test_simple_command() {
@@ -155,6 +783,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
 
         let response = executor.execute(request).await.unwrap();
@@ -173,6 +804,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
 
         let response = executor.execute(request).await.unwrap();
@@ -192,6 +826,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: Some("/tmp".to_string()),
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
         assert!(executor.validate_request(&valid).is_ok());
 
@@ -203,6 +840,9 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
         assert!(executor.validate_request(&invalid_empty).is_err());
 
@@ -214,7 +854,450 @@ mod tests {
             env: None,
             timeout: None,
             working_dir: Some("../../etc".to_string()),
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
         };
         assert!(executor.validate_request(&invalid_traversal).is_err());
     }
+
+    #[test]
+    fn test_validate_request_policy() {
+        use crate::policy::CommandRule;
+
+        let executor = CommandExecutor::new(30).with_policy(CommandPolicy::new(
+            vec![CommandRule::Command("echo".parse().unwrap())],
+            vec![],
+        ));
+
+        let allowed = CommandRequest {
+            id: 1,
+            command: "echo".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        assert!(executor.validate_request(&allowed).is_ok());
+
+        let denied = CommandRequest {
+            command: "rm".to_string(),
+            ..allowed
+        };
+        assert!(matches!(
+            executor.validate_request(&denied),
+            Err(ServerError::Denied(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let executor = CommandExecutor::new(30).with_read_only(Some(vec![
+            CommandRule::Command("ls".parse().unwrap()),
+            CommandRule::WithArgs {
+                command: "systemctl".parse().unwrap(),
+                args: vec!["status".parse().unwrap(), "**".parse().unwrap()],
+            },
+        ]));
+        assert!(executor.is_read_only());
+
+        let request = |command: &str, args: &[&str]| CommandRequest {
+            id: 1,
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        assert!(executor.validate_request(&request("ls", &["-l"])).is_ok());
+        assert!(executor.validate_request(&request("@uptime", &[])).is_ok());
+        assert!(executor
+            .validate_request(&request("systemctl", &["status", "sshd"]))
+            .is_ok());
+
+        for (command, args) in [("rm", &["-rf", "/"][..]), ("systemctl", &["restart", "sshd"])] {
+            let err = executor.validate_request(&request(command, args)).unwrap_err();
+            assert!(matches!(err, ServerError::Denied(_)));
+            assert!(err.to_string().contains("Read-only mode"));
+        }
+
+        // An empty list permits no commands at all
+        let nothing = CommandExecutor::new(30).with_read_only(Some(vec![]));
+        assert!(nothing.validate_request(&request("ls", &[])).is_err());
+        assert!(!nothing.with_read_only(None).is_read_only());
+    }
+
+    #[tokio::test]
+    async fn test_builtin() {
+        let request = CommandRequest {
+            id: 1,
+            command: "@pwd".to_string(),
+            args: vec![],
+            env: None,
+            timeout: None,
+            working_dir: Some("/".to_string()),
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let executor = CommandExecutor::new(30);
+        let response = executor.execute(request.clone()).await.unwrap();
+        assert_eq!(response.status, CommandStatus::Success);
+        assert_eq!(String::from_utf8_lossy(&response.stdout), "/\n");
+
+        // Never spawned, so not usable as a background job
+        assert!(executor.build_command(&request).is_err());
+
+        let disabled = CommandExecutor::new(30).with_builtins(false);
+        assert!(matches!(
+            disabled.execute(request.clone()).await,
+            Err(ServerError::Denied(_))
+        ));
+
+        let confined = CommandExecutor::new(30).with_seccomp(SeccompConfig::default());
+        assert!(matches!(
+            confined.execute(request).await,
+            Err(ServerError::Denied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_env_policy() {
+        let executor = CommandExecutor::new(30)
+            .with_env_policy(EnvPolicy::new(vec!["LD_*".to_string(), "PATH".to_string()], vec![]));
+        let mut env = BTreeMap::new();
+        env.insert("GREETING".to_string(), "hi".to_string());
+        let mut request = CommandRequest {
+            id: 1,
+            command: "printenv".to_string(),
+            args: vec!["GREETING".to_string()],
+            env: Some(env),
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let response = executor.execute(request.clone()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response.stdout), "hi\n");
+
+        request
+            .env
+            .as_mut()
+            .unwrap()
+            .insert("LD_PRELOAD".to_string(), "/tmp/evil.so".to_string());
+        assert!(matches!(
+            executor.execute(request.clone()).await,
+            Err(ServerError::Denied(_))
+        ));
+        assert!(executor.build_command(&request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shell_mode() {
+        let request = CommandRequest {
+            id: 1,
+            command: "echo".to_string(),
+            args: ["a", "|", "tr", "a", "b"].map(String::from).to_vec(),
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        // Exec mode passes the pipe through as an argument
+        let executor = CommandExecutor::new(30);
+        let response = executor.execute(request.clone()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "a | tr a b");
+
+        let executor = CommandExecutor::new(30).with_shell(PathBuf::from("/bin/sh"));
+        let response = executor.execute(request).await.unwrap();
+        assert_eq!(response.status, CommandStatus::Success);
+        assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "b");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_timeout_kills_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+
+        // The shell exits at once, but its background child keeps stdout open
+        let request = CommandRequest {
+            id: 1,
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("sleep 30 & echo $! > {}", pid_file.display()),
+            ],
+            env: None,
+            timeout: Some(1),
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let executor = CommandExecutor::new(30);
+        let response = executor.execute(request).await.unwrap();
+        assert_eq!(response.status, CommandStatus::Timeout);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let mut running = true;
+        for _ in 0..50 {
+            // Gone, or a zombie waiting to be reaped by init
+            running = std::fs::read_to_string(&stat)
+                .is_ok_and(|stat| !stat.rsplit(')').next().unwrap_or("").trim().starts_with('Z'));
+            if !running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!running);
+    }
+
+    #[tokio::test]
+    async fn test_output_cap() {
+        let request = CommandRequest {
+            id: 1,
+            command: "head".to_string(),
+            args: vec!["-c".to_string(), "100000".to_string(), "/dev/zero".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let executor = CommandExecutor::new(30).with_max_output(1000);
+        let response = executor.execute(request.clone()).await.unwrap();
+        assert_eq!(response.status, CommandStatus::Success);
+        assert_eq!(response.stdout.len(), 1000);
+        assert!(response.truncated);
+        assert_eq!(response.total_bytes, 100000);
+
+        let response = CommandExecutor::new(30).execute(request).await.unwrap();
+        assert_eq!(response.stdout.len(), 100000);
+        assert!(!response.truncated);
+        assert_eq!(response.total_bytes, 100000);
+    }
+
+    #[tokio::test]
+    async fn test_output_stream() {
+        let request = CommandRequest {
+            id: 1,
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "head -c 100000 /dev/zero; echo oops >&2".to_string(),
+            ],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+
+        let received = std::sync::Mutex::new((0usize, Vec::new()));
+        let sink = |stderr: bool, data: &[u8]| {
+            let mut received = received.lock().unwrap();
+            if stderr {
+                received.1.extend_from_slice(data);
+            } else {
+                received.0 += data.len();
+            }
+        };
+        let executor = CommandExecutor::new(30).with_max_output(1000);
+        let (response, _) = executor
+            .execute_streamed(request, None, Some(&sink), None, None)
+            .await
+            .unwrap();
+
+        // The sink gets everything, the response only what fits the cap
+        assert_eq!(*received.lock().unwrap(), (100000, b"oops\n".to_vec()));
+        assert_eq!(response.stdout.len(), 1000);
+        assert!(response.truncated);
+
+        // Messages standing in for output are streamed too
+        let request = CommandRequest {
+            id: 2,
+            command: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            env: None,
+            timeout: Some(1),
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        received.lock().unwrap().1.clear();
+        let (response, _) = executor
+            .execute_streamed(request, None, Some(&sink), None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.status, CommandStatus::Timeout);
+        assert_eq!(received.lock().unwrap().1, response.stderr);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_interrupt() {
+        let request = |script: &str| CommandRequest {
+            id: 1,
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: None,
+            timeout: Some(30),
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let executor = CommandExecutor::new(30);
+
+        // SIGINT lets the command clean up and exit on its own
+        let interrupter = Interrupter::new();
+        let (result, ()) = tokio::join!(
+            executor.execute_streamed(
+                request("trap 'echo caught; exit 3' INT; sleep 30"),
+                None,
+                None,
+                Some(&interrupter),
+                None,
+            ),
+            async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                interrupter.interrupt();
+            }
+        );
+        let (response, _) = result.unwrap();
+        assert_eq!(response.status, CommandStatus::Error);
+        assert_eq!(response.exit_code, 3);
+        assert_eq!(response.stdout, b"caught\n");
+
+        // A command ignoring SIGINT can still be killed
+        let interrupter = Interrupter::new();
+        let start = Instant::now();
+        let (result, ()) = tokio::join!(
+            executor.execute_streamed(
+                request("trap '' INT; sleep 30"),
+                None,
+                None,
+                Some(&interrupter),
+                None,
+            ),
+            async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                interrupter.interrupt();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                interrupter.kill();
+            }
+        );
+        let (response, _) = result.unwrap();
+        assert_eq!(response.status, CommandStatus::Killed);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Killed before it starts, it never does
+        let interrupter = Interrupter::new();
+        interrupter.kill();
+        let (response, _) = executor
+            .execute_streamed(request("echo ran"), None, None, Some(&interrupter), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status, CommandStatus::Killed);
+        assert!(response.stdout.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stdin() {
+        let request = |stdin: bool| CommandRequest {
+            id: 1,
+            command: "cat".to_string(),
+            args: vec![],
+            env: None,
+            timeout: Some(30),
+            working_dir: None,
+            secrets: vec![],
+            stdin,
+            delta_base: None,
+        };
+        let executor = CommandExecutor::new(30);
+
+        // Input arriving while the command runs, up to end of file
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(b"hello ".to_vec()).unwrap();
+        let (result, ()) = tokio::join!(
+            executor.execute_streamed(request(true), None, None, None, Some(rx)),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                tx.send(b"world\n".to_vec()).unwrap();
+                drop(tx);
+            }
+        );
+        let (response, _) = result.unwrap();
+        assert_eq!(response.status, CommandStatus::Success);
+        assert_eq!(response.stdout, b"hello world\n");
+
+        // Without input stdin is at end of file at once
+        for stdin in [true, false] {
+            let (response, _) = executor
+                .execute_streamed(request(stdin), None, None, None, None)
+                .await
+                .unwrap();
+            assert_eq!(response.status, CommandStatus::Success);
+            assert!(response.stdout.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_output_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = OutputSpool::new(dir.path().to_path_buf(), 1 << 20);
+        let executor = CommandExecutor::new(30).with_max_output(1000);
+
+        let request = CommandRequest {
+            id: 1,
+            command: "seq".to_string(),
+            args: vec!["10000".to_string()],
+            env: None,
+            timeout: None,
+            working_dir: None,
+            secrets: vec![],
+            stdin: false,
+            delta_base: None,
+        };
+        let (response, _) = executor
+            .execute_spooled(request.clone(), Some(&spool))
+            .await
+            .unwrap();
+        assert!(response.truncated);
+        assert_eq!(response.stdout.len(), 1000);
+
+        let spool_id = response.spool_id.unwrap();
+        let (data, total) = spool.read(spool_id, false, 0, usize::MAX).unwrap();
+        assert_eq!(total, response.total_bytes);
+        assert!(data.starts_with(&response.stdout));
+        assert!(String::from_utf8(data).unwrap().ends_with("9999\n10000\n"));
+
+        // Output within the cap isn't spooled
+        let small = CommandRequest {
+            args: vec!["3".to_string()],
+            ..request
+        };
+        let (response, _) = executor.execute_spooled(small, Some(&spool)).await.unwrap();
+        assert_eq!(response.spool_id, None);
+    }
 }
@@ -0,0 +1,243 @@
+//! SOCKS5 relay for dynamic-forward channels
+//!
+//! The client points a local SOCKS application at its end of a `Dynamic`
+//! channel; the server speaks the SOCKS5 protocol (RFC 1928, CONNECT with no
+//! authentication) over the channel and connects to the requested target from
+//! its own network.
+
+use crate::{forward::ForwardPolicy, Result, ServerError};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+/// Timeout for connecting to the requested target
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Run a SOCKS5 session on `stream`, relaying to the requested target
+///
+/// Returns once either side closes. Targets are resolved and checked with
+/// [`ForwardPolicy::resolve_socks`], and only the vetted addresses are
+/// connected to.
+pub async fn relay<S>(mut stream: S, policy: &ForwardPolicy) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    negotiate_method(&mut stream).await?;
+    let (host, port) = read_request(&mut stream).await?;
+
+    let addrs = match policy.resolve_socks(&host, port).await {
+        Ok(addrs) => addrs,
+        Err(e @ ServerError::Denied(_)) => {
+            warn!(host = %host, port = port, "SOCKS target not permitted");
+            send_reply(&mut stream, REPLY_NOT_ALLOWED).await?;
+            return Err(e);
+        }
+        Err(e) => {
+            send_reply(&mut stream, REPLY_HOST_UNREACHABLE).await?;
+            return Err(e);
+        }
+    };
+
+    let connect = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addrs[..]));
+    let mut target = match connect.await {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            let code = match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                _ => REPLY_HOST_UNREACHABLE,
+            };
+            send_reply(&mut stream, code).await?;
+            return Err(e.into());
+        }
+        Err(_) => {
+            send_reply(&mut stream, REPLY_HOST_UNREACHABLE).await?;
+            return Err(ServerError::Timeout);
+        }
+    };
+
+    send_reply(&mut stream, REPLY_SUCCEEDED).await?;
+    debug!(host = %host, port = port, "SOCKS connection established");
+
+    tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+    Ok(())
+}
+
+/// Read the greeting and select "no authentication"
+async fn negotiate_method<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(ServerError::Execution(format!(
+            "Unsupported SOCKS version: {}",
+            header[0]
+        )));
+    }
+
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        return Err(ServerError::Execution(
+            "Client offered no supported SOCKS auth method".to_string(),
+        ));
+    }
+
+    stream.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+    Ok(())
+}
+
+/// Read a CONNECT request and return the target
+async fn read_request<S>(stream: &mut S) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _reserved, address_type] = header;
+
+    if version != SOCKS_VERSION {
+        return Err(ServerError::Execution(format!(
+            "Unsupported SOCKS version: {}",
+            version
+        )));
+    }
+
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .map_err(|_| ServerError::Execution("Invalid SOCKS domain name".to_string()))?
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        other => {
+            send_reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(ServerError::Execution(format!(
+                "Unsupported SOCKS address type: {}",
+                other
+            )));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        send_reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(ServerError::Execution(format!(
+            "Unsupported SOCKS command: {}",
+            command
+        )));
+    }
+
+    Ok((host, port))
+}
+
+/// Send a reply with an unspecified bound address
+async fn send_reply<S>(stream: &mut S, code: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[SOCKS_VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> ForwardPolicy {
+        ForwardPolicy {
+            allow: vec![],
+            remote_ports: vec![],
+            bind_address: "127.0.0.1".to_string(),
+            socks_allow: allow.iter().map(|s| s.to_string()).collect(),
+            socks_deny: deny.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    async fn connect_request(client: &mut tokio::io::DuplexStream, port: u16) -> [u8; 10] {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        let mut request = vec![5, 1, 0, ATYP_IPV4, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(&buf[..n]).await.unwrap();
+        });
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let policy = policy(&["127.0.0.1"], &[]);
+        tokio::spawn(async move { relay(server, &policy).await });
+
+        let reply = connect_request(&mut client, port).await;
+        assert_eq!(reply[1], REPLY_SUCCEEDED);
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_socks_denied() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let policy = policy(&["*"], &["127.0.0.1:22"]);
+        let relay = tokio::spawn(async move { relay(server, &policy).await });
+
+        let reply = connect_request(&mut client, 22).await;
+        assert_eq!(reply[1], REPLY_NOT_ALLOWED);
+        assert!(relay.await.unwrap().is_err());
+    }
+}
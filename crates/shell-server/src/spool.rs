@@ -0,0 +1,235 @@
+//! Spooling of oversized command output
+//!
+//! Command output beyond `max_output_size` is cut from the response. With
+//! `output_spool_dir` set, the complete output of such a command is written
+//! to files in a directory of its session instead of being discarded, and
+//! the client reads it back in ranges with FETCH_OUTPUT. Each session may
+//! spool up to `output_spool_quota` bytes; output beyond that is discarded
+//! as before. The directory is removed when the session closes.
+
+use crate::{config::ServerConfig, Result, ServerError};
+use shell_proto::SessionId;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Spooled output of one session
+#[derive(Debug)]
+pub struct OutputSpool {
+    /// Directory of this session's spool files
+    dir: PathBuf,
+
+    /// Bytes the session may spool
+    quota: u64,
+
+    /// Bytes spooled so far
+    used: AtomicU64,
+
+    /// Next spool ID
+    next_id: AtomicU64,
+
+    /// Spooled stream lengths (stdout, stderr) by spool ID
+    outputs: Mutex<HashMap<u64, (u64, u64)>>,
+}
+
+impl OutputSpool {
+    /// Create a spool writing to `dir` (created on first use)
+    pub fn new(dir: PathBuf, quota: u64) -> Self {
+        Self {
+            dir,
+            quota,
+            used: AtomicU64::new(0),
+            next_id: AtomicU64::new(1),
+            outputs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create the spool of a session, if spooling is configured
+    pub fn from_config(config: &ServerConfig, session_id: SessionId) -> Option<Self> {
+        let dir = config.output_spool_dir.as_ref()?;
+        Some(Self::new(
+            dir.join(Uuid::from_bytes(session_id).to_string()),
+            config.output_spool_quota,
+        ))
+    }
+
+    /// Start spooling one command's output
+    pub fn start(&self) -> (SpoolWriter<'_>, SpoolWriter<'_>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        (
+            SpoolWriter::new(self, id, false),
+            SpoolWriter::new(self, id, true),
+        )
+    }
+
+    /// Record what a command spooled, returning its spool ID if anything was
+    pub fn finish(&self, stdout: SpoolWriter<'_>, stderr: SpoolWriter<'_>) -> Option<u64> {
+        if stdout.file.is_none() && stderr.file.is_none() {
+            return None;
+        }
+        let id = stdout.id;
+        self.outputs
+            .lock()
+            .unwrap()
+            .insert(id, (stdout.written, stderr.written));
+        Some(id)
+    }
+
+    /// Read up to `max` bytes of a spooled stream from `offset`, returning
+    /// them and the stream's spooled length
+    pub fn read(&self, id: u64, stderr: bool, offset: u64, max: usize) -> Result<(Vec<u8>, u64)> {
+        let (stdout_len, stderr_len) = *self
+            .outputs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .ok_or_else(|| ServerError::Execution(format!("Unknown output spool: {}", id)))?;
+        let total = if stderr { stderr_len } else { stdout_len };
+        if total == 0 || offset >= total {
+            return Ok((vec![], total));
+        }
+
+        let mut file = File::open(self.path(id, stderr))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take((max as u64).min(total - offset))
+            .read_to_end(&mut data)?;
+        Ok((data, total))
+    }
+
+    /// Delete all spooled output
+    pub fn clear(&self) {
+        self.outputs.lock().unwrap().clear();
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(dir = %self.dir.display(), error = %e, "Failed to remove output spool");
+            }
+        }
+    }
+
+    /// Directory of the spool files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, id: u64, stderr: bool) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", id, if stderr { "stderr" } else { "stdout" }))
+    }
+
+    /// Reserve `len` bytes of the quota
+    fn reserve(&self, len: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + len <= self.quota).then_some(used + len)
+            })
+            .is_ok()
+    }
+}
+
+/// Writes one output stream to the spool once it overflows
+#[derive(Debug)]
+pub struct SpoolWriter<'a> {
+    spool: &'a OutputSpool,
+    id: u64,
+    stderr: bool,
+
+    /// Spool file, opened on the first write
+    file: Option<File>,
+
+    /// Bytes written
+    written: u64,
+
+    /// Writing stopped (quota reached or I/O error)
+    stopped: bool,
+}
+
+impl<'a> SpoolWriter<'a> {
+    fn new(spool: &'a OutputSpool, id: u64, stderr: bool) -> Self {
+        Self {
+            spool,
+            id,
+            stderr,
+            file: None,
+            written: 0,
+            stopped: false,
+        }
+    }
+
+    /// Append output; once the quota is used up the rest is discarded
+    pub fn write(&mut self, data: &[u8]) {
+        if self.stopped || data.is_empty() {
+            return;
+        }
+        if !self.spool.reserve(data.len() as u64) {
+            warn!(spool_id = self.id, "Output spool quota exceeded, discarding output");
+            self.stopped = true;
+            return;
+        }
+
+        if let Err(e) = self.append(data) {
+            warn!(spool_id = self.id, error = %e, "Failed to spool output");
+            self.stopped = true;
+            return;
+        }
+        self.written += data.len() as u64;
+    }
+
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.file.is_none() {
+            fs::create_dir_all(&self.spool.dir)?;
+            self.file = Some(File::create(self.spool.path(self.id, self.stderr))?);
+        }
+        self.file.as_mut().expect("file was just opened").write_all(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spool_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = OutputSpool::new(dir.path().join("session"), 1024);
+
+        // Nothing written, nothing spooled
+        let (stdout, stderr) = spool.start();
+        assert_eq!(spool.finish(stdout, stderr), None);
+
+        let (mut stdout, stderr) = spool.start();
+        stdout.write(b"hello ");
+        stdout.write(b"world");
+        let id = spool.finish(stdout, stderr).unwrap();
+
+        assert_eq!(spool.read(id, false, 0, 5).unwrap(), (b"hello".to_vec(), 11));
+        assert_eq!(spool.read(id, false, 6, 100).unwrap(), (b"world".to_vec(), 11));
+        assert_eq!(spool.read(id, false, 11, 100).unwrap(), (vec![], 11));
+        assert_eq!(spool.read(id, true, 0, 100).unwrap(), (vec![], 0));
+        assert!(spool.read(id + 1, false, 0, 100).is_err());
+
+        spool.clear();
+        assert!(!spool.dir().exists());
+        assert!(spool.read(id, false, 0, 5).is_err());
+    }
+
+    #[test]
+    fn test_spool_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = OutputSpool::new(dir.path().to_path_buf(), 8);
+
+        let (mut stdout, stderr) = spool.start();
+        stdout.write(b"12345");
+        stdout.write(b"67890");
+        stdout.write(b"1");
+        let id = spool.finish(stdout, stderr).unwrap();
+
+        // The write crossing the quota and everything after it are dropped
+        assert_eq!(spool.read(id, false, 0, 100).unwrap(), (b"12345".to_vec(), 5));
+    }
+}
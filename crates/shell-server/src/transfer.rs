@@ -0,0 +1,672 @@
+//! File transfer service
+//!
+//! Uploads are written chunk by chunk to a `.part` file next to their
+//! destination and renamed into place once the SHA-256 matches, so an
+//! interrupted upload can be resumed from the size of the partial file.
+//! Downloads are pulled by the client one chunk at a time.
+
+use crate::{config::ServerConfig, session_dir::SessionDir, Result, ServerError};
+use sha2::{Digest, Sha256};
+use shell_proto::{
+    ChunkAck, ChunkRequest, DownloadRequest, FileChunk, Message, TransferComplete, TransferReady,
+    UploadRequest, MAX_CHUNK_SIZE,
+};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// An upload in progress
+struct Upload {
+    /// Final destination
+    path: PathBuf,
+
+    /// Partial file being written
+    part_path: PathBuf,
+
+    /// Open handle to the partial file
+    file: File,
+
+    /// Expected total size
+    size: u64,
+
+    /// Expected SHA-256
+    sha256: Vec<u8>,
+
+    /// Requested permission bits
+    mode: Option<u32>,
+
+    /// Bytes written so far
+    received: u64,
+
+    /// Bytes of it written in this session (counted against the quota)
+    charged: u64,
+}
+
+/// A download in progress
+struct Download {
+    /// Source file
+    file: File,
+
+    /// File size when the download started
+    size: u64,
+}
+
+/// Per-session file transfer state
+pub struct TransferService {
+    /// Server configuration
+    config: Arc<ServerConfig>,
+
+    /// Directory relative upload paths land in
+    staging: PathBuf,
+
+    /// Session directory, whose quota uploads into it count against
+    session_dir: Option<Arc<SessionDir>>,
+
+    /// Active uploads
+    uploads: Mutex<HashMap<u64, Upload>>,
+
+    /// Active downloads
+    downloads: Mutex<HashMap<u64, Download>>,
+
+    /// Next transfer ID
+    next_id: AtomicU64,
+
+    /// Bytes written by uploads in this session (counts against the quota)
+    uploaded: AtomicU64,
+}
+
+impl TransferService {
+    /// Create a new transfer service
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Self {
+            staging: config.transfer_dir.clone(),
+            session_dir: None,
+            config,
+            uploads: Mutex::new(HashMap::new()),
+            downloads: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            uploaded: AtomicU64::new(0),
+        }
+    }
+
+    /// Stage relative uploads in the session's directory instead of
+    /// `transfer_dir`, within its quota
+    pub fn with_session_dir(mut self, session_dir: Arc<SessionDir>) -> Self {
+        self.staging = session_dir.path().to_path_buf();
+        self.session_dir = Some(session_dir);
+        self
+    }
+
+    /// Start or resume an upload
+    pub fn start_upload(&self, request: UploadRequest) -> Result<TransferReady> {
+        let path = self.resolve_path(&request.path)?;
+        self.start_upload_at(path, request)
+    }
+
+    /// Start or resume an upload to `path`, which the caller has vetted
+    /// (`request.path` is ignored)
+    pub fn start_upload_at(&self, path: PathBuf, request: UploadRequest) -> Result<TransferReady> {
+        if request.size > self.config.max_upload_size {
+            return Err(ServerError::Execution(format!(
+                "File too large: {} bytes (max: {})",
+                request.size, self.config.max_upload_size
+            )));
+        }
+
+        if request.sha256.len() != 32 {
+            return Err(ServerError::Execution("SHA-256 must be 32 bytes".to_string()));
+        }
+
+        let part_path = part_path(&path);
+
+        // A partial file larger than the upload is stale, from another one
+        let mut offset = match fs::symlink_metadata(&part_path) {
+            Ok(metadata) if request.resume && metadata.len() <= request.size => metadata.len(),
+            _ => 0,
+        };
+
+        // Only what is still to come counts; it is charged as it is written
+        self.check_upload_quota(request.size - offset)?;
+        if let Some(session_dir) = &self.session_dir {
+            if path.starts_with(session_dir.path()) {
+                session_dir.check_quota(request.size - offset)?;
+            }
+        }
+
+        let mut file = no_follow()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(&part_path)?;
+        if file.metadata()?.len() != offset {
+            // Changed since it was looked at
+            file.set_len(0)?;
+            offset = 0;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        let transfer_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        info!(
+            transfer_id = transfer_id,
+            path = ?path,
+            size = request.size,
+            offset = offset,
+            "Upload started"
+        );
+
+        self.uploads.lock().unwrap().insert(
+            transfer_id,
+            Upload {
+                path,
+                part_path,
+                file,
+                size: request.size,
+                sha256: request.sha256,
+                mode: request.mode,
+                received: offset,
+                charged: 0,
+            },
+        );
+
+        Ok(TransferReady {
+            request_id: request.id,
+            transfer_id,
+            offset,
+            size: request.size,
+            sha256: vec![],
+        })
+    }
+
+    /// Write an upload chunk
+    ///
+    /// Returns a `ChunkAck`, or a `TransferComplete` once the final chunk has
+    /// been written and the file verified.
+    pub fn write_chunk(&self, chunk: FileChunk) -> Result<Message> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads.get_mut(&chunk.transfer_id).ok_or_else(|| {
+            ServerError::Execution(format!("Unknown transfer: {}", chunk.transfer_id))
+        })?;
+
+        if chunk.offset != upload.received {
+            // Out of order; tell the client where to continue
+            return Ok(Message::ChunkAck(ChunkAck {
+                transfer_id: chunk.transfer_id,
+                offset: upload.received,
+            }));
+        }
+
+        if upload.received + chunk.data.len() as u64 > upload.size {
+            return Err(ServerError::Execution(
+                "Chunk exceeds declared file size".to_string(),
+            ));
+        }
+
+        let len = chunk.data.len() as u64;
+        self.check_upload_quota(len)?;
+        upload.file.write_all(&chunk.data)?;
+        upload.received += len;
+        upload.charged += len;
+        self.uploaded.fetch_add(len, Ordering::SeqCst);
+
+        if upload.received < upload.size {
+            return Ok(Message::ChunkAck(ChunkAck {
+                transfer_id: chunk.transfer_id,
+                offset: upload.received,
+            }));
+        }
+
+        let upload = uploads.remove(&chunk.transfer_id).unwrap();
+        drop(uploads);
+
+        self.finish_upload(chunk.transfer_id, upload)
+    }
+
+    /// Verify and move a completed upload into place
+    fn finish_upload(&self, transfer_id: u64, upload: Upload) -> Result<Message> {
+        let Upload {
+            path,
+            part_path,
+            mut file,
+            size,
+            sha256,
+            mode,
+            charged,
+            ..
+        } = upload;
+
+        // Through the open file, not the path, which may have been replaced
+        file.flush()?;
+        file.seek(SeekFrom::Start(0))?;
+        let actual = hash_reader(&mut file)?;
+        if actual != sha256 {
+            warn!(transfer_id = transfer_id, path = ?path, "Upload hash mismatch");
+            let _ = fs::remove_file(&part_path);
+            self.uploaded.fetch_sub(charged, Ordering::SeqCst);
+            return Err(ServerError::Execution(
+                "Hash mismatch, upload discarded".to_string(),
+            ));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = mode {
+                file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        drop(file);
+
+        fs::rename(&part_path, &path)?;
+
+        info!(transfer_id = transfer_id, path = ?path, size = size, "Upload complete");
+
+        Ok(Message::TransferComplete(TransferComplete {
+            transfer_id,
+            size,
+            sha256: actual,
+        }))
+    }
+
+    /// Start a download
+    pub fn start_download(&self, request: DownloadRequest) -> Result<TransferReady> {
+        let path = self.resolve_path(&request.path)?;
+
+        // Only the parent is canonicalized, so a symlink as the file itself
+        // could point anywhere: refuse it, also if one is swapped in
+        if !fs::symlink_metadata(&path)?.is_file() {
+            return Err(ServerError::Execution(format!(
+                "Not a regular file: {}",
+                request.path
+            )));
+        }
+        let mut file = no_follow().read(true).open(&path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(ServerError::Execution(format!(
+                "Not a regular file: {}",
+                request.path
+            )));
+        }
+
+        let sha256 = hash_reader(&mut file)?;
+        let transfer_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        info!(
+            transfer_id = transfer_id,
+            path = ?path,
+            size = metadata.len(),
+            "Download started"
+        );
+
+        self.downloads.lock().unwrap().insert(
+            transfer_id,
+            Download {
+                file,
+                size: metadata.len(),
+            },
+        );
+
+        Ok(TransferReady {
+            request_id: request.id,
+            transfer_id,
+            offset: 0,
+            size: metadata.len(),
+            sha256,
+        })
+    }
+
+    /// Read a download chunk
+    ///
+    /// The transfer is forgotten once a chunk reaching the end of the file has
+    /// been read; an empty chunk signals end of file.
+    pub fn read_chunk(&self, request: ChunkRequest) -> Result<FileChunk> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads.get_mut(&request.transfer_id).ok_or_else(|| {
+            ServerError::Execution(format!("Unknown transfer: {}", request.transfer_id))
+        })?;
+
+        let max = (request.max_bytes as usize).min(MAX_CHUNK_SIZE);
+        let remaining = download.size.saturating_sub(request.offset);
+        let len = (remaining as usize).min(max);
+
+        let mut data = vec![0u8; len];
+        download.file.seek(SeekFrom::Start(request.offset))?;
+        download.file.read_exact(&mut data)?;
+
+        if request.offset + len as u64 >= download.size {
+            debug!(transfer_id = request.transfer_id, "Download complete");
+            downloads.remove(&request.transfer_id);
+        }
+
+        Ok(FileChunk {
+            transfer_id: request.transfer_id,
+            offset: request.offset,
+            data,
+        })
+    }
+
+    /// Drop all in-progress transfers (partial uploads stay on disk for resume)
+    pub fn cancel_all(&self) {
+        self.uploads.lock().unwrap().clear();
+        self.downloads.lock().unwrap().clear();
+    }
+
+    /// Check that `additional` more uploaded bytes fit within the session's
+    /// upload quota
+    fn check_upload_quota(&self, additional: u64) -> Result<()> {
+        let uploaded = self.uploaded.load(Ordering::SeqCst);
+        if uploaded + additional > self.config.session_upload_quota {
+            return Err(ServerError::Execution(format!(
+                "Upload quota exceeded: {} of {} bytes used",
+                uploaded, self.config.session_upload_quota
+            )));
+        }
+        Ok(())
+    }
+
+    /// Resolve a client path against the staging directory and allowlist
+    ///
+    /// Relative paths are placed in the staging directory (the transfer
+    /// directory or the session directory). Absolute paths must fall under
+    /// it or one of `transfer_allowed_paths`.
+    pub fn resolve_path(&self, requested: &str) -> Result<PathBuf> {
+        let requested = Path::new(requested);
+
+        if requested
+            .components()
+            .any(|c| matches!(c, Component::ParentDir))
+        {
+            return Err(ServerError::Execution(
+                "Path traversal not allowed".to_string(),
+            ));
+        }
+
+        let staging = &self.staging;
+        fs::create_dir_all(staging)?;
+
+        let path = if requested.is_absolute() {
+            requested.to_path_buf()
+        } else {
+            staging.join(requested)
+        };
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| ServerError::Execution("Path has no file name".to_string()))?;
+        let parent = path
+            .parent()
+            .ok_or_else(|| ServerError::Execution("Path has no parent directory".to_string()))?;
+
+        // Canonicalize the parent so symlinks can't escape the allowed roots
+        let resolved = parent.canonicalize()?.join(file_name);
+
+        let allowed = std::iter::once(staging)
+            .chain(self.config.transfer_allowed_paths.iter())
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| resolved.starts_with(root));
+
+        if !allowed {
+            return Err(ServerError::Denied(format!(
+                "Path not permitted: {}",
+                resolved.display()
+            )));
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// Path of the partial file for an upload destination
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Options for opening a file that fail if it is a symlink
+fn no_follow() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options
+}
+
+/// Compute the SHA-256 of everything `reader` yields
+fn hash_reader(mut reader: impl Read) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(dir: &Path) -> TransferService {
+        let mut config = ServerConfig::default();
+        config.transfer_dir = dir.to_path_buf();
+        TransferService::new(Arc::new(config))
+    }
+
+    fn upload_request(path: &str, data: &[u8], resume: bool) -> UploadRequest {
+        UploadRequest {
+            id: 1,
+            path: path.to_string(),
+            size: data.len() as u64,
+            sha256: Sha256::digest(data).to_vec(),
+            mode: None,
+            resume,
+        }
+    }
+
+    #[test]
+    fn test_upload_and_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+        let data = b"hello transfer".to_vec();
+
+        let ready = service.start_upload(upload_request("file.txt", &data, false)).unwrap();
+        assert_eq!(ready.offset, 0);
+
+        let ack = service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 0,
+                data: data[..5].to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(ack, Message::ChunkAck(ChunkAck { offset: 5, .. })));
+
+        let done = service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 5,
+                data: data[5..].to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(done, Message::TransferComplete(_)));
+        assert_eq!(fs::read(dir.path().join("file.txt")).unwrap(), data);
+
+        let ready = service
+            .start_download(DownloadRequest {
+                id: 2,
+                path: "file.txt".to_string(),
+            })
+            .unwrap();
+        assert_eq!(ready.size, data.len() as u64);
+        assert_eq!(ready.sha256, Sha256::digest(&data).to_vec());
+
+        let chunk = service
+            .read_chunk(ChunkRequest {
+                transfer_id: ready.transfer_id,
+                offset: 0,
+                max_bytes: 1024,
+            })
+            .unwrap();
+        assert_eq!(chunk.data, data);
+    }
+
+    #[test]
+    fn test_session_dir_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = Arc::new(SessionDir::create(dir.path().join("session"), 8).unwrap());
+        let service = service(&dir.path().join("transfers")).with_session_dir(session_dir);
+
+        let ready = service.start_upload(upload_request("a.txt", b"12345", false)).unwrap();
+        let done = service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 0,
+                data: b"12345".to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(done, Message::TransferComplete(_)));
+        assert!(dir.path().join("session/a.txt").exists());
+
+        // 5 of 8 bytes are used
+        assert!(matches!(
+            service.start_upload(upload_request("b.txt", b"1234", false)),
+            Err(ServerError::Denied(_))
+        ));
+        assert!(service.start_upload(upload_request("b.txt", b"123", false)).is_ok());
+    }
+
+    #[test]
+    fn test_resume_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+        let data = b"resumable".to_vec();
+
+        fs::write(dir.path().join("r.bin.part"), &data[..4]).unwrap();
+
+        let ready = service.start_upload(upload_request("r.bin", &data, true)).unwrap();
+        assert_eq!(ready.offset, 4);
+
+        let done = service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 4,
+                data: data[4..].to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(done, Message::TransferComplete(_)));
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+
+        let mut request = upload_request("bad.bin", b"abc", false);
+        request.sha256 = vec![0u8; 32];
+        let ready = service.start_upload(request).unwrap();
+
+        let result = service.write_chunk(FileChunk {
+            transfer_id: ready.transfer_id,
+            offset: 0,
+            data: b"abc".to_vec(),
+        });
+        assert!(result.is_err());
+        assert!(!dir.path().join("bad.bin").exists());
+    }
+
+    #[test]
+    fn test_upload_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default();
+        config.transfer_dir = dir.path().to_path_buf();
+        config.session_upload_quota = 10;
+        let service = TransferService::new(Arc::new(config));
+        let data = b"12345678".to_vec();
+
+        // Starting again and resuming charge only what is written
+        service.start_upload(upload_request("q.bin", &data, false)).unwrap();
+        let ready = service.start_upload(upload_request("q.bin", &data, true)).unwrap();
+        service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 0,
+                data: data[..4].to_vec(),
+            })
+            .unwrap();
+        service.cancel_all();
+        let ready = service.start_upload(upload_request("q.bin", &data, true)).unwrap();
+        assert_eq!(ready.offset, 4);
+        let done = service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 4,
+                data: data[4..].to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(done, Message::TransferComplete(_)));
+
+        // 8 of 10 bytes are used; a discarded upload is refunded
+        assert!(service.start_upload(upload_request("r.bin", b"123", false)).is_err());
+        let mut request = upload_request("r.bin", b"12", false);
+        request.sha256 = vec![0u8; 32];
+        let ready = service.start_upload(request).unwrap();
+        assert!(service
+            .write_chunk(FileChunk {
+                transfer_id: ready.transfer_id,
+                offset: 0,
+                data: b"12".to_vec(),
+            })
+            .is_err());
+        assert!(service.start_upload(upload_request("r.bin", b"12", false)).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("written"),
+            dir.path().join("up.bin.part"),
+        )
+        .unwrap();
+        let service = service(dir.path());
+
+        assert!(service
+            .start_download(DownloadRequest {
+                id: 1,
+                path: "link".to_string(),
+            })
+            .is_err());
+        assert!(service.start_upload(upload_request("up.bin", b"data", false)).is_err());
+        assert!(!outside.path().join("written").exists());
+    }
+
+    #[test]
+    fn test_path_restrictions() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+
+        assert!(service.resolve_path("../escape").is_err());
+        assert!(service.resolve_path("/etc/passwd").is_err());
+        assert!(service.resolve_path("inside.txt").is_ok());
+    }
+}
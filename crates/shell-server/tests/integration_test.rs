@@ -1,7 +1,7 @@
 //! Integration test for full client-server command execution
 
-use reticulum_core::MockInterface;
-use shell_client::{client::Client, config::ClientConfig};
+use reticulum_core::{MockInterface, TcpInterface};
+use shell_client::{client::Client, config::ClientConfig, connect};
 use shell_server::{config::ServerConfig, server::Server};
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
@@ -12,7 +12,8 @@ async fn test_full_command_execution_flow() {
     let (client_interface, server_interface) = MockInterface::create_pair();
 
     // Create server config
-    let server_config = ServerConfig::default();
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
     let server_dest_hex = server_config.identity.destination_hex();
 
     // Create server with interface
@@ -65,7 +66,8 @@ async fn test_ps_command() {
     let (client_interface, server_interface) = MockInterface::create_pair();
 
     // Create server config
-    let server_config = ServerConfig::default();
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
     let server_dest_hex = server_config.identity.destination_hex();
 
     // Create server with interface
@@ -124,7 +126,8 @@ async fn test_ss_command() {
     let (client_interface, server_interface) = MockInterface::create_pair();
 
     // Create server config
-    let server_config = ServerConfig::default();
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
     let server_dest_hex = server_config.identity.destination_hex();
 
     // Create server with interface
@@ -176,3 +179,1174 @@ async fn test_ss_command() {
     // Output should contain typical ss headers or socket states
     assert!(output.contains("State") || output.contains("LISTEN") || output.contains("ESTAB"));
 }
+
+#[tokio::test]
+async fn test_multiple_listeners() {
+    use reticulum_core::InterfaceManager;
+
+    // Server on a mock interface and TCP at once
+    let (_client_interface, server_interface) = MockInterface::create_pair();
+    let tcp = TcpInterface::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let tcp_address = tcp.local_addr();
+    let interfaces = InterfaceManager::new()
+        .with(Arc::new(server_interface))
+        .with(Arc::new(tcp));
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest_hex = server_config.identity.destination_hex();
+
+    let server = Server::with_interfaces(server_config, interfaces).await.unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // Client over TCP
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = server_dest_hex;
+    let server_dest = client_config.parse_server_destination().unwrap();
+    let interface = TcpInterface::connect(tcp_address).await.unwrap();
+    let client = Client::with_interface(client_config, Arc::new(interface), server_dest)
+        .await
+        .unwrap();
+
+    client.connect().await.unwrap();
+    let response = client
+        .execute_command("echo".to_string(), vec!["over tcp".to_string()])
+        .await
+        .unwrap();
+
+    assert_eq!(response.exit_code, 0);
+    assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "over tcp");
+}
+
+#[tokio::test]
+async fn test_announce_and_path_request() {
+    use reticulum_core::{announce, Announce, NetworkInterface, PacketType};
+    use shell_proto::AnnounceInfo;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.announce_name = Some("test-box".to_string());
+    let server_dest = server_config.identity.destination_hash();
+
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            eprintln!("Server error: {}", e);
+        }
+    });
+
+    // Announced at startup
+    let packet = client_interface.receive().await.unwrap();
+    assert_eq!(packet.packet_type, PacketType::Announce);
+    let announce = Announce::from_packet(&packet).unwrap();
+    assert_eq!(announce.destination(), server_dest);
+    let info = AnnounceInfo::from_bytes(&announce.app_data).unwrap();
+    assert_eq!(info.name.as_deref(), Some("test-box"));
+    assert!(info.capabilities.contains(&"command-exec".to_string()));
+
+    // Path requests for other destinations are ignored
+    client_interface
+        .send(&announce::path_request([0; 32]))
+        .await
+        .unwrap();
+    client_interface
+        .send(&announce::path_request(server_dest))
+        .await
+        .unwrap();
+    let packet = client_interface.receive().await.unwrap();
+    assert_eq!(Announce::from_packet(&packet).unwrap().destination(), server_dest);
+}
+
+#[tokio::test]
+async fn test_discover() {
+    use reticulum_core::InterfaceManager;
+
+    let tcp = TcpInterface::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let tcp_address = tcp.local_addr();
+    let interfaces = InterfaceManager::new().with(Arc::new(tcp));
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.announce_name = Some("build-box".to_string());
+    let server_dest_hex = server_config.identity.destination_hex();
+    let server = Server::with_interfaces(server_config, interfaces)
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    // Connected after the startup announce, so heard from by asking
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = server_dest_hex.clone();
+    client_config.server_tcp_address = Some(tcp_address);
+    let servers = shell_client::discover::run(&client_config, Duration::from_millis(500))
+        .await
+        .unwrap();
+
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].destination, server_dest_hex);
+    assert_eq!(servers[0].name.as_deref(), Some("build-box"));
+    assert!(servers[0].capabilities.contains(&"echo".to_string()));
+    let profile = servers[0].profile("build-box");
+    assert_eq!(profile.tcp_address, Some(tcp_address));
+}
+
+#[tokio::test]
+async fn test_resume_after_server_restart() {
+    let state_dir = tempfile::tempdir().unwrap();
+    let (client_interface, server_interface) = MockInterface::create_pair();
+    let server_interface = Arc::new(server_interface);
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.session_state_dir = Some(state_dir.path().to_path_buf());
+    let server_dest = server_config.identity.destination_hash();
+
+    let start = |config: ServerConfig| {
+        let interface = Arc::clone(&server_interface);
+        async move {
+            let server = Server::with_interface(config, interface).await.unwrap();
+            tokio::spawn(server.run())
+        }
+    };
+
+    let first = start(server_config.clone()).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+    let session_id = client.session_id().await.unwrap();
+
+    // Restart the server on the same interface and state directory
+    first.abort();
+    let _ = first.await;
+    let _second = start(server_config).await;
+    sleep(Duration::from_millis(100)).await;
+
+    client.reconnect().await.unwrap();
+    assert_eq!(client.session_id().await, Some(session_id));
+
+    let response = client
+        .execute_command("echo".to_string(), vec!["resumed".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "resumed");
+}
+
+#[tokio::test]
+async fn test_jump_host() {
+    // The target is only reachable over TCP from the jump host
+    let mut target_config = ServerConfig::default();
+    target_config.audit_logging = false;
+    let target_dest = target_config.identity.destination_hash();
+    let target_interface = TcpInterface::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let target_address = target_interface.local_addr();
+    let target = Server::with_interface(target_config, Arc::new(target_interface))
+        .await
+        .unwrap();
+    tokio::spawn(target.run());
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+    let mut hop_config = ServerConfig::default();
+    hop_config.audit_logging = false;
+    hop_config.forward_allow = vec!["127.0.0.1:*".to_string()];
+    let hop_dest = hop_config.identity.destination_hash();
+    let hop = Server::with_interface(hop_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(hop.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(hop_dest);
+    let hop_client = Client::with_interface(client_config, Arc::new(client_interface), hop_dest)
+        .await
+        .unwrap();
+    hop_client.connect().await.unwrap();
+
+    let mut next_config = ClientConfig::default();
+    next_config.server_destination = hex::encode(target_dest);
+    next_config.server_tcp_address = Some(target_address);
+    let client = connect::through(hop_client, next_config).await.unwrap();
+    client.connect().await.unwrap();
+
+    let response = client
+        .execute_command("echo".to_string(), vec!["relayed".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "relayed");
+    client.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_reconnect_after_connection_loss() {
+    use reticulum_core::NetworkInterface;
+
+    let state_dir = tempfile::tempdir().unwrap();
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.session_state_dir = Some(state_dir.path().to_path_buf());
+    let server_dest = server_config.identity.destination_hash();
+
+    let start = |config: ServerConfig, address| async move {
+        let interface = Arc::new(TcpInterface::bind(address).await.unwrap());
+        let server = Server::with_interface(config, interface.clone())
+            .await
+            .unwrap();
+        (interface, tokio::spawn(server.run()))
+    };
+
+    let (interface, first) = start(server_config.clone(), "127.0.0.1:0".parse().unwrap()).await;
+    let address = interface.local_addr();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.server_tcp_address = Some(address);
+    let client = connect::open(client_config).await.unwrap();
+    client.connect().await.unwrap();
+    let session_id = client.session_id().await.unwrap();
+
+    // The server restarts, dropping the connection under the client
+    first.abort();
+    let _ = first.await;
+    interface.close().await.unwrap();
+    let (_interface, _second) = start(server_config, address).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let response = client
+        .execute_command("echo".to_string(), vec!["reconnected".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&response.stdout).trim(),
+        "reconnected"
+    );
+    assert_eq!(client.session_id().await, Some(session_id));
+}
+
+#[tokio::test]
+async fn test_known_hosts() {
+    use shell_client::{known_hosts, ClientError};
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server_key = server_config.identity.public_key();
+
+    let address = "127.0.0.1:0".parse().unwrap();
+    let interface = Arc::new(TcpInterface::bind(address).await.unwrap());
+    let address = interface.local_addr();
+    let server = Server::with_interface(server_config, interface)
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.server_tcp_address = Some(address);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+
+    // First contact: the key is shown and must be trusted
+    let client = connect::open(client_config.clone()).await.unwrap();
+    client.connect().await.unwrap();
+    assert!(client.host_key_unknown());
+    assert_eq!(
+        client.host_key_fingerprint().await,
+        Some(known_hosts::fingerprint(&server_key))
+    );
+    client.trust_host_key().await.unwrap();
+    client.disconnect().await.unwrap();
+
+    let client = connect::open(client_config.clone()).await.unwrap();
+    client.connect().await.unwrap();
+    assert!(!client.host_key_unknown());
+    client.disconnect().await.unwrap();
+
+    // Another key recorded for the destination fails the connection
+    client_config.known_hosts_path = dir.path().join("impostor");
+    known_hosts::add(&client_config.known_hosts_path, &server_dest, b"other key").unwrap();
+    let client = connect::open(client_config).await.unwrap();
+    match client.connect().await {
+        Err(ClientError::HostKeyChanged(_)) => {}
+        other => panic!("expected a changed key, got {:?}", other),
+    }
+    assert!(client.execute_command("true".to_string(), vec![]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_dynamic_forward() {
+    use shell_client::forward;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // A target that echoes one message
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.socks_allow = vec!["127.0.0.1:*".to_string()];
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_address = proxy.local_addr().unwrap();
+    tokio::spawn(forward::serve_dynamic(Arc::new(client), proxy));
+
+    // SOCKS5: no authentication, then CONNECT to 127.0.0.1:<target_port>
+    let mut stream = TcpStream::connect(proxy_address).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [5, 0]);
+
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[tokio::test]
+async fn test_connection_health() {
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+
+    let health = client.health().await;
+    assert!(!health.connected);
+    assert_eq!(health.session_age, None);
+
+    client.connect().await.unwrap();
+    let rtt = client.ping().await.unwrap();
+
+    let health = client.health().await;
+    assert!(health.connected);
+    assert!(health.session_age.is_some());
+    assert_eq!(
+        health.protocol_version,
+        Some(shell_proto::CURRENT_PROTOCOL_VERSION)
+    );
+    assert!(health.capabilities.contains(&"command-exec".to_string()));
+    assert_eq!(health.last_rtt, Some(rtt));
+    assert_eq!((health.pings, health.pings_lost), (1, 0));
+    assert_eq!(health.loss(), Some(0.0));
+    assert!(health.bytes_sent > 0 && health.bytes_received > 0);
+}
+
+#[tokio::test]
+async fn test_link_benchmark() {
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    // Large echoes come back whole
+    client
+        .echo(vec![0x5A; shell_proto::MAX_CHUNK_SIZE])
+        .await
+        .unwrap();
+    assert!(client
+        .echo(vec![0; shell_proto::MAX_CHUNK_SIZE + 1])
+        .await
+        .is_err());
+
+    let results = shell_client::bench::run(&client, 2, false).await.unwrap();
+    let kinds: Vec<&str> = results.iter().map(|series| series.kind).collect();
+    assert_eq!(kinds, vec!["ping", "echo-small", "echo-large"]);
+    for series in &results {
+        assert_eq!((series.sent, series.lost), (2, 0));
+        assert!(series.avg_ms.is_some());
+    }
+    assert!(results[2].throughput.unwrap() > 0.0);
+}
+
+#[tokio::test]
+async fn test_keepalive() {
+    use shell_client::keepalive;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.keepalive_interval = 1;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    // The server's interval wins over the configured 60 seconds
+    let client = Arc::new(client);
+    let task = keepalive::spawn(&client);
+    sleep(Duration::from_millis(2500)).await;
+
+    let health = client.health().await;
+    assert!(health.connected);
+    assert!(health.pings >= 1, "{} pings", health.pings);
+    assert_eq!(health.pings_lost, 0);
+    assert!(health.rtt.is_some());
+
+    // Requests still get their own replies
+    let response = client.execute_command("whoami".to_string(), vec![]).await.unwrap();
+    assert_eq!(response.exit_code, 0);
+
+    drop(client);
+    tokio::time::timeout(Duration::from_secs(3), task)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_command_stdin() {
+    use shell_client::client::CommandOptions;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest_hex = server_config.identity.destination_hex();
+
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = server_dest_hex;
+    let server_dest = client_config.parse_server_destination().unwrap();
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    // Input written while the command runs reaches it, then end of file
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        for line in ["one\n", "two\n"] {
+            tx.send(line.as_bytes().to_vec()).await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+        }
+    });
+    let response = client
+        .execute_command_piped(
+            "wc".to_string(),
+            vec!["-l".to_string()],
+            &CommandOptions::default(),
+            Some(rx),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.exit_code, 0);
+    assert_eq!(String::from_utf8_lossy(&response.stdout).trim(), "2");
+
+    // Without input, stdin is closed
+    let response = client
+        .execute_command("cat".to_string(), vec![])
+        .await
+        .unwrap();
+    assert_eq!(response.exit_code, 0);
+    assert!(response.stdout.is_empty());
+}
+
+#[tokio::test]
+async fn test_output_delta() {
+    use shell_client::client::CommandOptions;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest_hex = server_config.identity.destination_hex();
+
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = server_dest_hex;
+    let server_dest = client_config.parse_server_destination().unwrap();
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    // The second run comes as a delta against the first; either way the
+    // caller sees the whole output
+    let options = CommandOptions {
+        delta: true,
+        ..Default::default()
+    };
+    let expected: String = (1..=200).map(|i| format!("{}\n", i)).collect();
+    for _ in 0..2 {
+        let response = client
+            .execute_command_with("seq".to_string(), vec!["200".to_string()], &options)
+            .await
+            .unwrap();
+        assert_eq!(response.exit_code, 0);
+        assert_eq!(response.stdout, expected.as_bytes());
+        assert!(response.delta_base.is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_timeouts() {
+    use reticulum_core::{NetworkInterface, Packet};
+    use shell_client::ClientError;
+    use shell_proto::{AcceptMessage, Message, ProtocolCodec, CURRENT_PROTOCOL_VERSION};
+
+    let identity = ServerConfig::default().identity;
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = identity.destination_hex();
+    client_config.connection_timeout = 1;
+    client_config.retry_attempts = 0;
+    // The stand-in server below doesn't encrypt
+    client_config.require_noise = false;
+    let server_dest = client_config.parse_server_destination().unwrap();
+
+    // Nobody answers the CONNECT
+    let (client_interface, _server_interface) = MockInterface::create_pair();
+    let client = Client::with_interface(
+        client_config.clone(),
+        Arc::new(client_interface),
+        server_dest,
+    )
+    .await
+    .unwrap();
+    match client.connect().await {
+        Err(ClientError::Timeout(message)) => assert!(message.contains("connection_timeout")),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(!client.is_connected().await);
+
+    // A server that accepts, then falls silent
+    let (client_interface, server_interface) = MockInterface::create_pair();
+    tokio::spawn(async move {
+        let packet = server_interface.receive().await.unwrap();
+        let accept = Message::Accept(AcceptMessage {
+            protocol_version: CURRENT_PROTOCOL_VERSION,
+            server_identity: identity.public_key(),
+            session_id: [1; 16],
+            capabilities: vec!["command-exec".to_string()],
+            resume_token: None,
+            banner: None,
+            banner_ack_required: false,
+            keepalive_interval: None,
+            noise: None,
+        });
+        let reply = Packet::data(packet.destination, ProtocolCodec::encode(&accept).unwrap());
+        server_interface.send(&reply).await.unwrap();
+        while server_interface.receive().await.is_ok() {}
+    });
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    let start = std::time::Instant::now();
+    assert!(matches!(client.stats().await, Err(ClientError::Timeout(_))));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_retry() {
+    use reticulum_core::{NetworkInterface, Packet};
+    use shell_client::{client::CommandOptions, ClientError};
+    use shell_proto::{
+        AcceptMessage, CommandResponse, CommandStatus, Message, ProtocolCodec, StatsResponse,
+        CURRENT_PROTOCOL_VERSION,
+    };
+    use std::collections::HashSet;
+
+    let identity = ServerConfig::default().identity;
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = identity.destination_hex();
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    client_config.accept_new_host_keys = true;
+    client_config.connection_timeout = 1;
+    client_config.retry_attempts = 2;
+    client_config.retry_backoff_ms = 10;
+    client_config.retry_jitter_ms = 0;
+    // The stand-in server below doesn't encrypt
+    client_config.require_noise = false;
+    let server_dest = client_config.parse_server_destination().unwrap();
+
+    // A server whose link loses the first of every kind of request
+    let (client_interface, server_interface) = MockInterface::create_pair();
+    tokio::spawn(async move {
+        let mut seen = HashSet::new();
+        while let Ok(packet) = server_interface.receive().await {
+            let mut buf = bytes::BytesMut::from(packet.data.as_ref());
+            let reply = match ProtocolCodec::decode(&mut buf).unwrap().unwrap() {
+                Message::Connect(_) if seen.insert("connect".to_string()) => continue,
+                Message::Connect(_) => Message::Accept(AcceptMessage {
+                    protocol_version: CURRENT_PROTOCOL_VERSION,
+                    server_identity: identity.public_key(),
+                    session_id: [1; 16],
+                    capabilities: vec!["command-exec".to_string()],
+                    resume_token: None,
+                    banner: None,
+                    banner_ack_required: false,
+                    keepalive_interval: None,
+                    noise: None,
+                }),
+                Message::StatsRequest(_) if seen.insert("stats".to_string()) => continue,
+                Message::StatsRequest(request) => Message::StatsResponse(StatsResponse {
+                    id: request.id,
+                    commands: 7,
+                    ..StatsResponse::default()
+                }),
+                Message::CommandRequest(request) if seen.insert(request.command.clone()) => {
+                    continue
+                }
+                Message::CommandRequest(request) => Message::CommandResponse(CommandResponse {
+                    id: request.id,
+                    status: CommandStatus::Success,
+                    stdout: b"ran\n".to_vec(),
+                    stderr: vec![],
+                    exit_code: 0,
+                    execution_time_ms: 1,
+                    truncated: false,
+                    total_bytes: 4,
+                    spool_id: None,
+                    delta_base: None,
+                }),
+                _ => continue,
+            };
+            let reply = Packet::data(packet.destination, ProtocolCodec::encode(&reply).unwrap());
+            server_interface.send(&reply).await.unwrap();
+        }
+    });
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+
+    // Connecting and stats are tried again
+    client.connect().await.unwrap();
+    assert_eq!(client.stats().await.unwrap().commands, 7);
+
+    // Commands only if they are safe to repeat
+    let options = CommandOptions {
+        timeout: Some(1),
+        ..CommandOptions::default()
+    };
+    let result = client
+        .execute_command_with("touch".to_string(), vec![], &options)
+        .await;
+    assert!(matches!(result, Err(ClientError::Timeout(_))));
+
+    let options = CommandOptions {
+        idempotent: true,
+        ..options
+    };
+    let response = client
+        .execute_command_with("date".to_string(), vec![], &options)
+        .await
+        .unwrap();
+    assert_eq!(response.stdout, b"ran\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shared_connection() {
+    use shell_client::control::{self, Master};
+    use shell_proto::CommandRequest;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    client_config.control_path = Some(dir.path().join("rsh-%d.sock"));
+    let path = control::socket_path(&client_config).unwrap();
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    // No master yet
+    assert!(Master::connect(&path).await.is_none());
+    let master = tokio::spawn({
+        let path = path.clone();
+        async move { control::serve(Arc::new(client), &path).await }
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let request = |command: &str, args: &[&str], stdin: bool| CommandRequest {
+        id: 1,
+        command: command.to_string(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        env: None,
+        timeout: None,
+        working_dir: None,
+        secrets: vec![],
+        stdin,
+        delta_base: None,
+    };
+
+    // Commands of several processes share the connection
+    let mut streamed = Vec::new();
+    let response = Master::connect(&path)
+        .await
+        .unwrap()
+        .execute(request("echo", &["shared"], false), None, &mut |_, data| {
+            streamed.extend_from_slice(data)
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.exit_code, 0);
+    assert_eq!(response.stdout, b"shared\n");
+    assert_eq!(streamed, b"shared\n");
+
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tx.send(b"piped\n".to_vec()).await.unwrap();
+    drop(tx);
+    let response = Master::connect(&path)
+        .await
+        .unwrap()
+        .execute(request("cat", &[], true), Some(rx), &mut |_, _| {})
+        .await
+        .unwrap();
+    assert_eq!(response.stdout, b"piped\n");
+
+    // The socket goes with the master
+    master.abort();
+    let _ = master.await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_client_audit_log() {
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log_path = dir.path().join("audit.log");
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    client_config.audit_log_path = Some(log_path.clone());
+    client_config.audit_redact = vec!["token=\\S+".to_string()];
+    let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    client.connect().await.unwrap();
+
+    client
+        .execute_command("echo".to_string(), vec!["token=s3cret".to_string()])
+        .await
+        .unwrap();
+    client
+        .execute_command("false".to_string(), vec![])
+        .await
+        .unwrap();
+
+    let contents = std::fs::read_to_string(&log_path).unwrap();
+    assert!(!contents.contains("s3cret"));
+    let records: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["server"], hex::encode(server_dest));
+    assert_eq!(records[0]["command"], "echo [REDACTED]");
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[0]["exit_code"], 0);
+    assert_eq!(records[1]["command"], "false");
+    assert_eq!(records[1]["status"], "failed");
+    assert_eq!(records[1]["exit_code"], 1);
+}
+
+#[tokio::test]
+async fn test_event_handler() {
+    use shell_client::events::Event;
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    let mut client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+        .await
+        .unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    client.set_event_handler(Arc::new(tx));
+    client.connect().await.unwrap();
+
+    let response = client
+        .execute_command("echo".to_string(), vec!["hello".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(response.stdout, b"hello\n");
+
+    let mut stdout = Vec::new();
+    while let Ok(event) = rx.try_recv() {
+        if let Event::Output {
+            stderr: false,
+            data,
+            ..
+        } = event
+        {
+            stdout.extend(data);
+        }
+    }
+    assert_eq!(stdout, b"hello\n");
+}
+
+#[tokio::test]
+async fn test_encrypted_session() {
+    use reticulum_core::{NetworkInterface, Packet};
+    use shell_client::{known_hosts, ClientError};
+    use shell_proto::{AcceptMessage, Message, ProtocolCodec, CURRENT_PROTOCOL_VERSION};
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    server_config.require_noise = true;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut client_config = ClientConfig::default();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.known_hosts_path = dir.path().join("known_hosts");
+    client_config.require_noise = true;
+    client_config.connection_timeout = 5;
+    let client = Client::with_interface(
+        client_config.clone(),
+        Arc::new(client_interface),
+        server_dest,
+    )
+    .await
+    .unwrap();
+    client.connect().await.unwrap();
+    assert!(client.is_encrypted());
+
+    let response = client
+        .execute_command("echo".to_string(), vec!["sealed".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(response.stdout, b"sealed\n");
+
+    // A server that does not encrypt is refused when encryption is required,
+    // and when its key is recorded, as only the handshake proves it holds it
+    let identity = ServerConfig::default().identity;
+    let server_key = identity.public_key();
+    let server_dest = identity.destination_hash();
+    client_config.server_destination = hex::encode(server_dest);
+    client_config.retry_attempts = 0;
+    let mut pinned = client_config.clone();
+    pinned.require_noise = false;
+    pinned.known_hosts_path = dir.path().join("pinned");
+    known_hosts::add(&pinned.known_hosts_path, &server_dest, &server_key).unwrap();
+
+    for (client_config, refusal) in [(client_config, "require_noise"), (pinned, "known hosts")] {
+        let (client_interface, server_interface) = MockInterface::create_pair();
+        let accept = Message::Accept(AcceptMessage {
+            protocol_version: CURRENT_PROTOCOL_VERSION,
+            server_identity: server_key.clone(),
+            session_id: [1; 16],
+            capabilities: vec!["command-exec".to_string()],
+            resume_token: None,
+            banner: None,
+            banner_ack_required: false,
+            keepalive_interval: None,
+            noise: None,
+        });
+        tokio::spawn(async move {
+            let packet = server_interface.receive().await.unwrap();
+            let reply = Packet::data(packet.destination, ProtocolCodec::encode(&accept).unwrap());
+            server_interface.send(&reply).await.unwrap();
+            while server_interface.receive().await.is_ok() {}
+        });
+        let client = Client::with_interface(client_config, Arc::new(client_interface), server_dest)
+            .await
+            .unwrap();
+        match client.connect().await {
+            Err(ClientError::Connection(message)) => assert!(message.contains(refusal)),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        assert!(!client.is_encrypted());
+        assert!(!client.is_connected().await);
+    }
+}
+
+#[tokio::test]
+async fn test_packet_signatures() {
+    use reticulum_core::{Identity, NetworkInterface, Packet};
+    use shell_proto::{
+        packet_signing_payload, ConnectMessage, EchoMessage, Message, ProtocolCodec,
+        CURRENT_PROTOCOL_VERSION, REPLAY_WINDOW,
+    };
+
+    /// Send `message` with `signature` and its sequence number, returning
+    /// the reply if any
+    async fn exchange(
+        interface: &MockInterface,
+        destination: [u8; 32],
+        message: &Message,
+        signature: Option<(Vec<u8>, u64)>,
+    ) -> Option<Message> {
+        let mut packet = Packet::data(destination, ProtocolCodec::encode(message).unwrap());
+        if let Some((signature, sequence)) = signature {
+            packet = packet.with_signature(signature).with_sequence(sequence);
+        }
+        interface.send(&packet).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_millis(500), interface.receive())
+            .await
+            .ok()?
+            .unwrap();
+        let mut buf = bytes::BytesMut::from(reply.data.as_ref());
+        ProtocolCodec::decode(&mut buf).unwrap()
+    }
+
+    let (client_interface, server_interface) = MockInterface::create_pair();
+
+    let mut server_config = ServerConfig::default();
+    server_config.audit_logging = false;
+    let server_dest = server_config.identity.destination_hash();
+    let server = Server::with_interface(server_config, Arc::new(server_interface))
+        .await
+        .unwrap();
+    tokio::spawn(server.run());
+    sleep(Duration::from_millis(100)).await;
+
+    // A client that does not encrypt the session, so signs its packets
+    let identity = Identity::generate();
+    let mut connect = ConnectMessage {
+        protocol_version: CURRENT_PROTOCOL_VERSION,
+        client_identity: identity.public_key(),
+        capabilities: vec![],
+        auth_token: None,
+        timestamp: shell_server::auth::unix_time(),
+        nonce: [7; 16],
+        signature: vec![],
+        resume_token: None,
+        noise: None,
+        server_destination: server_dest,
+    };
+    connect.signature = identity.sign(&connect.signing_payload());
+    let session_id = match exchange(
+        &client_interface,
+        server_dest,
+        &Message::Connect(connect),
+        None,
+    )
+    .await
+    {
+        Some(Message::Accept(accept)) => accept.session_id,
+        other => panic!("expected ACCEPT, got {:?}", other),
+    };
+
+    let echo = Message::Echo(EchoMessage {
+        id: 1,
+        data: b"hello".to_vec(),
+    });
+    let encoded = ProtocolCodec::encode(&echo).unwrap();
+    let sign = |signer: &Identity, session_id: &[u8; 16], sequence| {
+        let payload = packet_signing_payload(session_id, sequence, &encoded);
+        (signer.sign(&payload), sequence)
+    };
+
+    // Unsigned, signed by someone else or for another session: dropped
+    let forged = [
+        None,
+        Some(sign(&Identity::generate(), &session_id, 1)),
+        Some(sign(&identity, &[0; 16], 1)),
+    ];
+    for signature in forged {
+        assert!(exchange(&client_interface, server_dest, &echo, signature)
+            .await
+            .is_none());
+    }
+
+    let signed = Some(sign(&identity, &session_id, 2));
+    match exchange(&client_interface, server_dest, &echo, signed.clone()).await {
+        Some(Message::EchoReply(reply)) => assert_eq!(reply.data, b"hello"),
+        other => panic!("expected an echo, got {:?}", other),
+    }
+
+    // Replayed: dropped
+    assert!(exchange(&client_interface, server_dest, &echo, signed)
+        .await
+        .is_none());
+
+    // Overtaken on the way, but not seen yet: taken
+    let reordered = Some(sign(&identity, &session_id, 1));
+    assert!(exchange(&client_interface, server_dest, &echo, reordered)
+        .await
+        .is_some());
+
+    // Too far behind the highest to tell: dropped
+    let ahead = Some(sign(&identity, &session_id, 2 * REPLAY_WINDOW));
+    assert!(exchange(&client_interface, server_dest, &echo, ahead)
+        .await
+        .is_some());
+    let behind = Some(sign(&identity, &session_id, 3));
+    assert!(exchange(&client_interface, server_dest, &echo, behind)
+        .await
+        .is_none());
+
+    // Every message of a packet is covered by its one signature
+    let mut data = ProtocolCodec::encode(&Message::Echo(EchoMessage {
+        id: 2,
+        data: b"first".to_vec(),
+    }))
+    .unwrap();
+    data.extend(
+        ProtocolCodec::encode(&Message::Echo(EchoMessage {
+            id: 3,
+            data: b"second".to_vec(),
+        }))
+        .unwrap(),
+    );
+    let sequence = 2 * REPLAY_WINDOW + 1;
+    let signature = identity.sign(&packet_signing_payload(&session_id, sequence, &data));
+    let packet = Packet::data(server_dest, data)
+        .with_signature(signature)
+        .with_sequence(sequence);
+    client_interface.send(&packet).await.unwrap();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let reply = tokio::time::timeout(Duration::from_millis(500), client_interface.receive())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = bytes::BytesMut::from(reply.data.as_ref());
+        match ProtocolCodec::decode(&mut buf).unwrap() {
+            Some(Message::EchoReply(reply)) => ids.push(reply.id),
+            other => panic!("expected an echo, got {:?}", other),
+        }
+    }
+    ids.sort();
+    assert_eq!(ids, vec![2, 3]);
+}
@@ -0,0 +1,13 @@
+//! `Packet::decode` on arbitrary bytes: it must not panic, and whatever it
+//! accepts must encode back to the same bytes
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reticulum_core::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode(data) {
+        assert_eq!(packet.encode(), data);
+    }
+});
@@ -0,0 +1,24 @@
+//! `ProtocolCodec::decode` on an arbitrary stream: it must not panic, and
+//! must take each frame it decodes or skips off the front of the buffer
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use shell_proto::{ProtocolCodec, ProtocolError};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    loop {
+        let before = buf.len();
+        match ProtocolCodec::decode(&mut buf) {
+            Ok(Some(message)) => {
+                assert!(buf.len() < before);
+                // What decodes encodes again
+                ProtocolCodec::encode(&message).unwrap();
+            }
+            Ok(None) | Err(ProtocolError::MessageTooLarge { .. }) => break,
+            Err(_) => assert!(buf.len() < before),
+        }
+    }
+});